@@ -369,7 +369,8 @@ async fn run_actor(
             }
             result = app.next_payment_request() => {
                 match result {
-                    Ok(IncomingPaymentRequest::Single(request)) => {
+                    Ok(IncomingPaymentRequest::Single(request))
+                    | Ok(IncomingPaymentRequest::SubscriptionCharge { request, .. }) => {
                         log::info!("Payment request received");
                         let dto = single_request_to_dto(&request);
                         let _ = sse_tx.send(SseEvent::PaymentRequest(dto));
@@ -673,18 +674,12 @@ async fn run_handshake(app: &PortalApp, url: &str) -> Result<(), String> {
     .map_err(|_| "Timed out waiting for auth challenge".to_string())?
     .map_err(|e| format!("Auth challenge error: {}", e))?;
 
-    let session_token = format!(
-        "demo-{}",
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_millis()
-    );
+    // The app presents the current session with the service, if any
     app.reply_auth_challenge(
         challenge,
         AuthResponseStatus::Approved {
             granted_permissions: vec![],
-            session_token,
+            session_token: String::new(),
         },
     )
     .await
@@ -2,9 +2,13 @@ pub mod db;
 pub mod logger;
 pub mod nwc;
 pub mod runtime;
+pub mod subscriptions;
 pub mod wallet;
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use portal_macros::fetch_git_hash;
 
@@ -24,7 +28,7 @@ use portal::{
     conversation::{app::{
         auth::{
             AuthChallengeEvent, AuthChallengeListenerConversation, AuthResponseConversation,
-            KeyHandshakeConversation,
+            AuthSuccessEvent, AuthSuccessListenerConversation, KeyHandshakeConversation,
         },
         payments::{
             PaymentRequestContent, PaymentRequestEvent, PaymentRequestListenerConversation, PaymentStatusSenderConversation, RecurringPaymentStatusSenderConversation
@@ -32,23 +36,36 @@ use portal::{
     }, cashu::{
         CashuDirectReceiverConversation, CashuRequestReceiverConversation,
         CashuResponseSenderConversation,
+    }, certificate::{
+        CertificateDeliveryReceiverConversation, CertificateRequestReceiverConversation,
+        CertificateResponseSenderConversation, RevocationListListenerConversation,
     }, close_subscription::{
         CloseRecurringPaymentConversation, CloseRecurringPaymentReceiverConversation,
-    }, invoice::{InvoiceReceiverConversation, InvoiceRequestConversation, InvoiceSenderConversation}, nip46::{Nip46Request, Nip46RequestListenerConversation, SigningResponseSenderConversation}, profile::{FetchProfileInfoConversation, Profile, SetProfileConversation}, sdk::payments::SinglePaymentRequestSenderConversation},
+    }, invoice::{InvoiceReceiverConversation, InvoiceRequestConversation, InvoiceSenderConversation}, nip46::{Nip46Request, Nip46RequestListenerConversation, SigningResponseSenderConversation}, profile::{FetchProfileInfoConversation, Profile, SetProfileConversation}, sdk::payments::SinglePaymentRequestSenderConversation, subkey::{SubkeyRevocationListenerConversation, SubkeyRevocationPublisherConversation}},
     nostr::nips::nip19::ToBech32,
     nostr_relay_pool::{RelayOptions, RelayPool},
     protocol::{
-        jwt::CustomClaims,
+        identity::Certificate,
+        jwt::{CustomClaims, DecodeOptions},
         key_handshake::KeyHandshakeUrl,
         model::{
             Timestamp,
-            auth::{AuthResponseStatus, SubkeyProof},
+            auth::{AuthResponseStatus, Permission, SubkeyProof},
             bindings::PublicKey,
+            identity::{
+                CertificateDeliveryContentWithKey, CertificateRequestContentWithKey,
+                CertificateResponseContent, CertificateResponseStatus,
+            },
             nip46::{NostrConnectEvent, NostrConnectResponseStatus},
             payment::{
-                CashuDirectContentWithKey, CashuRequestContentWithKey, CashuResponseContent, CashuResponseStatus, CloseRecurringPaymentContent, CloseRecurringPaymentResponse, InvoiceRequestContent, InvoiceRequestContentWithKey, InvoiceResponse, PaymentResponseContent, RecurringPaymentRequestContent, RecurringPaymentResponseContent, SinglePaymentRequestContent
+                CashuDirectContentWithKey, CashuRequestContentWithKey, CashuResponseContent, CashuResponseStatus, CloseRecurringPaymentContent, CloseRecurringPaymentResponse, InvoiceRequestContent, InvoiceRequestContentWithKey, InvoiceResponse, PaymentResponseContent, PaymentStatus, RecurringPaymentRequestContent, RecurringPaymentResponseContent, RecurringPaymentStatus, SinglePaymentRequestContent
             },
         },
+        revocation::{CertificateStatus, RevocationList, RevocationRegistry},
+        subkey::{
+            PrivateSubkeyManager, SubkeyMetadata, SubkeyOperation, SubkeyPermission,
+            SubkeyRevocation,
+        },
     },
     router::{
         MessageRouter, MultiKeyListenerAdapter, MultiKeySenderAdapter, NotificationStream,
@@ -63,6 +80,7 @@ pub use portal_rates;
 use crate::{
     logger::{CallbackLogger, LogCallback, LogLevel},
     runtime::BindingsRuntime,
+    subscriptions::{AuthorizedSubscription, SubscriptionChargeCheck, SubscriptionRegistry},
 };
 
 uniffi::setup_scaffolding!();
@@ -142,6 +160,19 @@ impl Mnemonic {
         })
     }
 
+    /// Derives a named subkey of the main key, see [`Keypair::derive_subkey`]
+    pub fn derive_subkey(
+        &self,
+        name: String,
+        permissions: Vec<SubkeyPermission>,
+        valid_from: Timestamp,
+        expires_at: Timestamp,
+    ) -> Result<Keypair, MnemonicError> {
+        self.get_keypair()?
+            .derive_subkey(name, permissions, valid_from, expires_at)
+            .map_err(|e| MnemonicError::InvalidSubkey(e.to_string()))
+    }
+
     pub fn derive_cashu(&self) -> Vec<u8> {
         let seed = self.inner.to_seed("");
         let xpriv = bip32::Xpriv::new_master(Network::Bitcoin, &seed).expect("Valid seed");
@@ -163,6 +194,9 @@ impl Mnemonic {
 pub enum MnemonicError {
     #[error("Invalid mnemonic")]
     InvalidMnemonic,
+
+    #[error("Invalid subkey: {0}")]
+    InvalidSubkey(String),
 }
 
 impl From<bip39::Error> for MnemonicError {
@@ -225,14 +259,93 @@ impl Keypair {
         Ok(nsec)
     }
 
+    /// Derives a named subkey of this key, valid from `valid_from` to `expires_at`
+    ///
+    /// The returned keypair carries its proof (see [`Keypair::subkey_proof`]), so it can be
+    /// used right away by another device or service.
+    pub fn derive_subkey(
+        &self,
+        name: String,
+        permissions: Vec<SubkeyPermission>,
+        valid_from: Timestamp,
+        expires_at: Timestamp,
+    ) -> Result<Keypair, KeypairError> {
+        self.derive_subkey_with_limits(
+            name,
+            permissions,
+            valid_from,
+            expires_at,
+            SubkeyLimits::default(),
+        )
+    }
+
+    /// Derives a subkey like [`Keypair::derive_subkey`], with a spending cap or a restricted
+    /// set of counterparties
+    pub fn derive_subkey_with_limits(
+        &self,
+        name: String,
+        permissions: Vec<SubkeyPermission>,
+        valid_from: Timestamp,
+        expires_at: Timestamp,
+        limits: SubkeyLimits,
+    ) -> Result<Keypair, KeypairError> {
+        if self.inner.subkey_proof().is_some() {
+            return Err(KeypairError::MainKeyRequired);
+        }
+        if expires_at <= valid_from {
+            return Err(KeypairError::SubkeyError(
+                "expires_at must be after valid_from".to_string(),
+            ));
+        }
+
+        let mut metadata = SubkeyMetadata::new(name, permissions, valid_from, expires_at)
+            .with_allowed_counterparties(
+                limits
+                    .allowed_counterparties
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+            );
+        if let Some(max_payment_msat) = limits.max_payment_msat {
+            metadata = metadata.with_max_payment_msat(max_payment_msat);
+        }
+        let (keys, proof) = self
+            .inner
+            .get_keys()
+            .create_subkey(&metadata)
+            .map_err(|e| KeypairError::SubkeyError(e.to_string()))?
+            .split();
+
+        Ok(Keypair {
+            inner: portal::protocol::LocalKeypair::new(keys, Some(proof)),
+        })
+    }
+
     pub fn issue_jwt(
         &self,
         target_key: PublicKey,
         expires_in_hours: i64,
     ) -> Result<String, KeypairError> {
+        self.issue_jwt_with_options(target_key, expires_in_hours, JwtClaimsOptions::default())
+    }
+
+    /// Issues a JWT with optional claims, bound to the main key when this is a subkey
+    pub fn issue_jwt_with_options(
+        &self,
+        target_key: PublicKey,
+        expires_in_hours: i64,
+        options: JwtClaimsOptions,
+    ) -> Result<String, KeypairError> {
+        let mut claims = CustomClaims::new(target_key);
+        claims.audience = options.audience;
+        claims.issuer = options.issuer;
+        claims.scopes = options.scopes;
+        claims.jti = options.jti;
+        claims.subkey_proof = self.inner.subkey_proof().cloned();
+
         let token = portal::protocol::jwt::encode(
             self.inner.secret_key(),
-            CustomClaims::new(target_key),
+            claims,
             Duration::hours(expires_in_hours),
         )
         .map_err(|e| KeypairError::JwtError(e.to_string()))?;
@@ -244,12 +357,51 @@ impl Keypair {
         pubkey: PublicKey,
         token: &str,
     ) -> Result<portal::protocol::jwt::CustomClaims, KeypairError> {
-        let claims = portal::protocol::jwt::decode(&pubkey.into(), token)
+        self.verify_jwt_with_options(pubkey, token, JwtVerifyOptions::default())
+    }
+
+    /// Verifies a JWT issued by `pubkey` and checks its claims against `options`
+    pub fn verify_jwt_with_options(
+        &self,
+        pubkey: PublicKey,
+        token: &str,
+        options: JwtVerifyOptions,
+    ) -> Result<portal::protocol::jwt::CustomClaims, KeypairError> {
+        let revoked: HashSet<String> = options.revoked_ids.into_iter().collect();
+        let options = DecodeOptions {
+            audience: options.audience,
+            issuer: options.issuer,
+            required_scopes: options.required_scopes,
+            revocation_list: Some(Arc::new(revoked)),
+        };
+
+        let claims = portal::protocol::jwt::decode_with_options(&pubkey.into(), token, &options)
             .map_err(|e| KeypairError::JwtError(e.to_string()))?;
         Ok(claims)
     }
 }
 
+/// Optional claims of a JWT issued with [`Keypair::issue_jwt_with_options`]
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct JwtClaimsOptions {
+    pub audience: Option<String>,
+    pub issuer: Option<String>,
+    pub scopes: Vec<String>,
+    /// Session ID, to revoke the token
+    pub jti: Option<String>,
+}
+
+/// Checks applied by [`Keypair::verify_jwt_with_options`], on top of the signature and expiration
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct JwtVerifyOptions {
+    pub audience: Option<String>,
+    pub issuer: Option<String>,
+    /// Scopes the token must all have
+    pub required_scopes: Vec<String>,
+    /// Tokens with one of these `jti` are rejected
+    pub revoked_ids: Vec<String>,
+}
+
 #[derive(Debug, PartialEq, thiserror::Error, uniffi::Error)]
 pub enum KeypairError {
     #[error("Invalid nsec")]
@@ -257,6 +409,30 @@ pub enum KeypairError {
 
     #[error("JWT error: {0}")]
     JwtError(String),
+
+    #[error("Subkeys can only be derived from a main key")]
+    MainKeyRequired,
+
+    #[error("Subkey error: {0}")]
+    SubkeyError(String),
+}
+
+/// Limits of a subkey, on top of its permissions
+#[derive(Debug, Clone, Default, uniffi::Record)]
+pub struct SubkeyLimits {
+    /// Maximum amount of a single payment, in millisats
+    pub max_payment_msat: Option<u64>,
+    /// Keys the subkey may interact with, any if empty
+    pub allowed_counterparties: Vec<PublicKey>,
+}
+
+/// A subkey derived by the app, see [`PortalApp::create_subkey`]
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct SubkeyInfo {
+    pub subkey: PublicKey,
+    /// Proof binding the subkey to the main key, with its name, permissions and validity
+    pub proof: SubkeyProof,
+    pub revoked_at: Option<Timestamp>,
 }
 
 #[derive(uniffi::Object)]
@@ -266,6 +442,7 @@ pub struct PortalApp {
     runtime: Arc<BindingsRuntime>,
 
     auth_challenge_rx: Mutex<NotificationStream<AuthChallengeEvent>>,
+    auth_success_rx: Mutex<NotificationStream<AuthSuccessEvent>>,
     payment_request_rx: Mutex<NotificationStream<PaymentRequestEvent>>,
     closed_recurring_payment_rx:
         Mutex<NotificationStream<CloseRecurringPaymentResponse>>,
@@ -273,7 +450,19 @@ pub struct PortalApp {
         Mutex<NotificationStream<InvoiceRequestContentWithKey>>,
     cashu_request_rx: Mutex<NotificationStream<CashuRequestContentWithKey>>,
     cashu_direct_rx: Mutex<NotificationStream<CashuDirectContentWithKey>>,
+    certificate_request_rx: Mutex<NotificationStream<CertificateRequestContentWithKey>>,
+    certificate_delivery_rx: Mutex<NotificationStream<CertificateDeliveryContentWithKey>>,
     nip46_rx: Mutex<NotificationStream<Nip46Request>>,
+
+    revocation_registry: Arc<Mutex<RevocationRegistry>>,
+    /// Issuers whose revocation lists are already listened for
+    watched_issuers: Mutex<HashSet<PublicKey>>,
+    subscriptions: Arc<Mutex<SubscriptionRegistry>>,
+    recurring_payment_wallet: Mutex<Option<Arc<dyn RecurringPaymentWallet>>>,
+    /// Latest session opened by each service, by service key
+    sessions: Mutex<HashMap<PublicKey, AuthSuccessEvent>>,
+    /// Subkeys derived with `create_subkey`, by subkey
+    subkeys: Mutex<HashMap<PublicKey, SubkeyInfo>>,
 }
 #[derive(uniffi::Record, Debug)]
 pub struct Bolt11InvoiceData {
@@ -312,6 +501,17 @@ pub fn parse_key_handshake_url(url: &str) -> Result<KeyHandshakeUrl, ParseError>
     Ok(KeyHandshakeUrl::from_str(url)?)
 }
 
+/// Parses a permission string of an auth challenge, e.g. `payments:single<=1000 sats`
+#[uniffi::export]
+pub fn parse_permission(s: &str) -> Result<Permission, ParseError> {
+    Ok(Permission::from_str(s)?)
+}
+
+#[uniffi::export]
+pub fn permission_to_string(permission: Permission) -> String {
+    permission.to_string()
+}
+
 #[uniffi::export]
 pub fn parse_calendar(s: &str) -> Result<portal::protocol::calendar::Calendar, ParseError> {
     use std::str::FromStr;
@@ -328,6 +528,11 @@ impl From<portal::protocol::key_handshake::ParseError> for ParseError {
         ParseError::Inner(error.to_string())
     }
 }
+impl From<portal::protocol::model::auth::PermissionError> for ParseError {
+    fn from(error: portal::protocol::model::auth::PermissionError) -> Self {
+        ParseError::Inner(error.to_string())
+    }
+}
 impl From<portal::protocol::calendar::CalendarError> for ParseError {
     fn from(error: portal::protocol::calendar::CalendarError) -> Self {
         ParseError::Inner(error.to_string())
@@ -364,6 +569,15 @@ pub trait NostrConnectRequestListener: Send + Sync {
     ) -> Result<NostrConnectResponseStatus, CallbackError>;
 }
 
+/// Pays the subscription charges that [`PortalApp`] approves automatically
+#[uniffi::export(with_foreign)]
+#[async_trait::async_trait]
+pub trait RecurringPaymentWallet: Send + Sync {
+    /// Pays the invoice of the charge, returning its preimage if the wallet knows it
+    async fn pay_invoice(&self, request: SinglePaymentRequest)
+    -> Result<Option<String>, CallbackError>;
+}
+
 #[uniffi::export]
 impl PortalApp {
     #[uniffi::constructor]
@@ -422,6 +636,12 @@ impl PortalApp {
                 router.keypair().subkey_proof().cloned(),
             )))
             .await?;
+        let (auth_success_rx, _outcomes): (NotificationStream<AuthSuccessEvent>, _) = router
+            .add_and_subscribe(Box::new(MultiKeyListenerAdapter::new(
+                AuthSuccessListenerConversation::new(router.keypair().public_key()),
+                router.keypair().subkey_proof().cloned(),
+            )))
+            .await?;
         let (payment_request_rx, _outcomes): (NotificationStream<PaymentRequestEvent>, _) =
             router
                 .add_and_subscribe(Box::new(MultiKeyListenerAdapter::new(
@@ -457,6 +677,24 @@ impl PortalApp {
                 router.keypair().subkey_proof().cloned(),
             )))
             .await?;
+        let (certificate_request_rx, _outcomes): (
+            NotificationStream<CertificateRequestContentWithKey>,
+            _,
+        ) = router
+            .add_and_subscribe(Box::new(MultiKeyListenerAdapter::new(
+                CertificateRequestReceiverConversation::new(router.keypair().public_key()),
+                router.keypair().subkey_proof().cloned(),
+            )))
+            .await?;
+        let (certificate_delivery_rx, _outcomes): (
+            NotificationStream<CertificateDeliveryContentWithKey>,
+            _,
+        ) = router
+            .add_and_subscribe(Box::new(MultiKeyListenerAdapter::new(
+                CertificateDeliveryReceiverConversation::new(router.keypair().public_key()),
+                router.keypair().subkey_proof().cloned(),
+            )))
+            .await?;
         let (nip46_rx, _outcomes): (NotificationStream<Nip46Request>, _) = router
             .add_and_subscribe(Box::new(MultiKeyListenerAdapter::new(
                 Nip46RequestListenerConversation::new(router.keypair().public_key()),
@@ -464,18 +702,33 @@ impl PortalApp {
             )))
             .await?;
 
+        // Keep track of the revoked subkeys, so their proofs are rejected
+        router
+            .add_conversation(Box::new(SubkeyRevocationListenerConversation::new(vec![])))
+            .await?;
+
         Ok(Arc::new(Self {
             router,
             relay_pool,
             runtime,
 
             auth_challenge_rx: Mutex::new(auth_challenge_rx),
+            auth_success_rx: Mutex::new(auth_success_rx),
             payment_request_rx: Mutex::new(payment_request_rx),
             closed_recurring_payment_rx: Mutex::new(closed_recurring_payment_rx),
             invoice_request_rx: Mutex::new(invoice_request_rx),
             cashu_request_rx: Mutex::new(cashu_request_rx),
             cashu_direct_rx: Mutex::new(cashu_direct_rx),
+            certificate_request_rx: Mutex::new(certificate_request_rx),
+            certificate_delivery_rx: Mutex::new(certificate_delivery_rx),
             nip46_rx: Mutex::new(nip46_rx),
+
+            revocation_registry: Arc::new(Mutex::new(RevocationRegistry::new())),
+            watched_issuers: Mutex::new(HashSet::new()),
+            subscriptions: Arc::new(Mutex::new(SubscriptionRegistry::new())),
+            recurring_payment_wallet: Mutex::new(None),
+            sessions: Mutex::new(HashMap::new()),
+            subkeys: Mutex::new(HashMap::new()),
         }))
     }
 
@@ -595,13 +848,28 @@ impl PortalApp {
         Ok(auth_challenge)
     }
 
+    /// Replies to an auth challenge
+    ///
+    /// When approving with an empty `session_token`, the token of the current session with the
+    /// service is presented, if any, so the service refreshes it instead of opening a new one.
     pub async fn reply_auth_challenge(
         &self,
         event: AuthChallengeEvent,
-        status: AuthResponseStatus,
+        mut status: AuthResponseStatus,
     ) -> Result<(), AppError> {
         let recipient = event.recipient;
 
+        if let AuthResponseStatus::Approved { .. } = &status {
+            self.authorize_subkey(SubkeyOperation::Auth, event.service_key)?;
+        }
+
+        if let AuthResponseStatus::Approved { session_token, .. } = &mut status
+            && session_token.is_empty()
+            && let Some(token) = self.session_token(event.service_key).await
+        {
+            *session_token = token;
+        }
+
         let conv = AuthResponseConversation::new(
             event,
             self.router.keypair().subkey_proof().cloned(),
@@ -618,45 +886,345 @@ impl PortalApp {
         Ok(())
     }
 
-    pub async fn next_payment_request(&self) -> Result<IncomingPaymentRequest, AppError> {
-        let request = self
-            .payment_request_rx
+    /// Approves an auth challenge, granting some of the permissions it asks for
+    ///
+    /// `granted` can narrow the requested permissions, e.g. grant `payments:single<=100 sats`
+    /// when `payments:single<=1000 sats` was asked for, but not go beyond them.
+    pub async fn approve_auth_challenge(
+        &self,
+        event: AuthChallengeEvent,
+        granted: Vec<Permission>,
+    ) -> Result<(), AppError> {
+        if let Some(permission) = granted
+            .iter()
+            .find(|p| !Permission::is_granted(&event.permissions, p))
+        {
+            return Err(AppError::PermissionNotRequested(permission.to_string()));
+        }
+
+        let status = AuthResponseStatus::Approved {
+            granted_permissions: granted.iter().map(|p| p.to_string()).collect(),
+            session_token: String::new(),
+        };
+        self.reply_auth_challenge(event, status).await
+    }
+
+    /// Waits for the next session opened (or refreshed) by a service after an approved auth
+    /// challenge
+    ///
+    /// The session is kept to be presented to the service on the next auth challenge.
+    pub async fn next_auth_success(&self) -> Result<AuthSuccessEvent, AppError> {
+        let auth_success = self
+            .auth_success_rx
             .lock()
             .await
             .next()
             .await
-            .ok_or(AppError::ListenerDisconnected)?;
-        let request = request.map_err(|e| AppError::ParseError(e.to_string()))?;
+            .ok_or(AppError::ListenerDisconnected)?
+            .map_err(|e| AppError::ParseError(e.to_string()))?;
+        log::debug!("Received auth success: {:?}", auth_success);
 
-        log::debug!("Received payment request: {:?}", request);
+        self.sessions
+            .lock()
+            .await
+            .insert(auth_success.service_key, auth_success.clone());
+        Ok(auth_success)
+    }
 
-        match &request.content {
-            PaymentRequestContent::Single(content) => {
-                Ok(IncomingPaymentRequest::Single(SinglePaymentRequest {
-                    service_key: request.service_key,
-                    recipient: request.recipient,
-                    expires_at: request.expires_at,
-                    content: content.clone(),
-                    event_id: request.event_id.clone(),
-                }))
+    /// The sessions opened by services, to be saved by the app
+    pub async fn sessions(&self) -> Vec<AuthSuccessEvent> {
+        self.sessions.lock().await.values().cloned().collect()
+    }
+
+    /// Loads the sessions saved by the app, e.g. at startup
+    pub async fn restore_sessions(&self, sessions: Vec<AuthSuccessEvent>) {
+        let mut registry = self.sessions.lock().await;
+        for session in sessions {
+            registry.insert(session.service_key, session);
+        }
+    }
+
+    /// Token of the current session with a service, if it hasn't expired
+    pub async fn session_token(&self, service_key: PublicKey) -> Option<String> {
+        let now = Timestamp::now().as_u64();
+        self.sessions
+            .lock()
+            .await
+            .get(&service_key)
+            .filter(|session| session.expires_at > now)
+            .map(|session| session.session_token.clone())
+    }
+
+    /// Derives a named subkey of the app key and keeps track of it (see [`PortalApp::subkeys`])
+    ///
+    /// The app must run with its main key.
+    pub async fn create_subkey(
+        &self,
+        name: String,
+        permissions: Vec<SubkeyPermission>,
+        valid_from: Timestamp,
+        expires_at: Timestamp,
+    ) -> Result<Keypair, AppError> {
+        self.create_subkey_with_limits(
+            name,
+            permissions,
+            valid_from,
+            expires_at,
+            SubkeyLimits::default(),
+        )
+        .await
+    }
+
+    /// Creates a subkey like [`PortalApp::create_subkey`], with a spending cap or a restricted
+    /// set of counterparties
+    pub async fn create_subkey_with_limits(
+        &self,
+        name: String,
+        permissions: Vec<SubkeyPermission>,
+        valid_from: Timestamp,
+        expires_at: Timestamp,
+        limits: SubkeyLimits,
+    ) -> Result<Keypair, AppError> {
+        let main = Keypair {
+            inner: self.router.keypair().clone(),
+        };
+        let subkey = main
+            .derive_subkey_with_limits(name, permissions, valid_from, expires_at, limits)
+            .map_err(|e| match e {
+                KeypairError::MainKeyRequired => AppError::MasterKeyRequired,
+                e => AppError::SubkeyError(e.to_string()),
+            })?;
+
+        let info = SubkeyInfo {
+            subkey: subkey.public_key(),
+            proof: subkey
+                .subkey_proof()
+                .ok_or_else(|| AppError::SubkeyError("Missing subkey proof".to_string()))?,
+            revoked_at: None,
+        };
+        self.subkeys.lock().await.insert(info.subkey, info);
+
+        Ok(subkey)
+    }
+
+    /// The subkeys derived by the app, to be saved by the app
+    ///
+    /// `revoked_at` is also set for the subkeys revoked from another device.
+    pub async fn subkeys(&self) -> Vec<SubkeyInfo> {
+        let main_key = self.router.keypair().public_key();
+        let revoked: HashMap<_, _> = self
+            .router
+            .revoked_subkeys()
+            .list(&main_key)
+            .into_iter()
+            .map(|revocation| (revocation.subkey, revocation.revoked_at))
+            .collect();
+
+        self.subkeys
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .map(|mut info| {
+                info.revoked_at = info.revoked_at.or(revoked.get(&*info.subkey).copied());
+                info
+            })
+            .collect()
+    }
+
+    /// Loads the subkeys saved by the app, e.g. at startup
+    pub async fn restore_subkeys(&self, subkeys: Vec<SubkeyInfo>) {
+        let main_key = self.router.keypair().public_key();
+        let mut registry = self.subkeys.lock().await;
+        for info in subkeys {
+            if let Some(revoked_at) = info.revoked_at {
+                let mut revocation = SubkeyRevocation::new(*info.subkey, None);
+                revocation.revoked_at = revoked_at;
+                self.router.revoked_subkeys().insert(main_key, revocation);
             }
-            PaymentRequestContent::Recurring(content) => {
-                Ok(IncomingPaymentRequest::Recurring(RecurringPaymentRequest {
+            registry.insert(info.subkey, info);
+        }
+    }
+
+    /// Revokes a subkey of the app key, publishing its revocation
+    ///
+    /// Services and apps that saw the revocation reject the proof of the subkey. The app must
+    /// run with its main key.
+    pub async fn revoke_subkey(
+        &self,
+        subkey: PublicKey,
+        reason: Option<String>,
+    ) -> Result<(), AppError> {
+        if self.router.keypair().subkey_proof().is_some() {
+            return Err(AppError::MasterKeyRequired);
+        }
+
+        let main_key = self.router.keypair().public_key();
+        let revocation = SubkeyRevocation::new(*subkey, reason);
+        self.router
+            .revoked_subkeys()
+            .insert(main_key, revocation.clone());
+        if let Some(info) = self.subkeys.lock().await.get_mut(&subkey) {
+            info.revoked_at = Some(revocation.revoked_at);
+        }
+
+        let conv = SubkeyRevocationPublisherConversation::new(vec![revocation]);
+        self.router
+            .add_conversation(Box::new(OneShotSenderAdapter::new_with_user(
+                main_key,
+                vec![],
+                conv,
+            )))
+            .await?;
+        Ok(())
+    }
+
+    /// Waits for the next payment request that needs the user
+    ///
+    /// Charges of a confirmed subscription that are within its authorization are paid
+    /// automatically when a [`RecurringPaymentWallet`] is set, and not returned. The other
+    /// subscription charges are returned as [`IncomingPaymentRequest::SubscriptionCharge`],
+    /// together with the reason they need a review.
+    pub async fn next_payment_request(&self) -> Result<IncomingPaymentRequest, AppError> {
+        loop {
+            let request = self
+                .payment_request_rx
+                .lock()
+                .await
+                .next()
+                .await
+                .ok_or(AppError::ListenerDisconnected)?;
+            let request = request.map_err(|e| AppError::ParseError(e.to_string()))?;
+
+            log::debug!("Received payment request: {:?}", request);
+
+            let request = match &request.content {
+                PaymentRequestContent::Single(content) => SinglePaymentRequest {
                     service_key: request.service_key,
                     recipient: request.recipient,
                     expires_at: request.expires_at,
                     content: content.clone(),
                     event_id: request.event_id.clone(),
-                }))
+                },
+                PaymentRequestContent::Recurring(content) => {
+                    return Ok(IncomingPaymentRequest::Recurring(RecurringPaymentRequest {
+                        service_key: request.service_key,
+                        recipient: request.recipient,
+                        expires_at: request.expires_at,
+                        content: content.clone(),
+                        event_id: request.event_id.clone(),
+                    }));
+                }
+            };
+
+            let Some(subscription_id) = request.content.subscription_id.clone() else {
+                return Ok(IncomingPaymentRequest::Single(request));
+            };
+
+            // Charges the subkey isn't allowed to pay are left to the user
+            let subkey_allowed = self
+                .authorize_subkey(
+                    SubkeyOperation::Payment {
+                        amount_msat: request.content.amount_millisats(),
+                    },
+                    request.service_key,
+                )
+                .is_ok();
+
+            let check = {
+                let mut subscriptions = self.subscriptions.lock().await;
+                let check =
+                    subscriptions.check(&request.service_key, &request.content, Timestamp::now());
+                let wallet = self.recurring_payment_wallet.lock().await.clone();
+                match (&check, wallet) {
+                    (SubscriptionChargeCheck::Authorized { slot }, Some(wallet))
+                        if subkey_allowed =>
+                    {
+                        // Recorded right away, so that the same slot can't be charged twice
+                        // while the invoice is being paid
+                        let previous_slot = subscriptions
+                            .record_payment(&subscription_id, *slot)
+                            .flatten();
+                        Some((wallet, previous_slot))
+                    }
+                    _ => None,
+                }
+                .ok_or(check)
+            };
+
+            match check {
+                Ok((wallet, previous_slot)) => {
+                    log::info!("Paying charge of subscription {subscription_id} automatically");
+                    std::mem::drop(self.runtime.add_task(Self::pay_subscription_charge(
+                        Arc::clone(&self.router),
+                        Arc::clone(&self.subscriptions),
+                        wallet,
+                        request,
+                        previous_slot,
+                    )));
+                }
+                Err(check) => {
+                    log::info!(
+                        "Charge of subscription {subscription_id} needs review: {:?}",
+                        check
+                    );
+                    return Ok(IncomingPaymentRequest::SubscriptionCharge { request, check });
+                }
             }
         }
     }
 
+    /// Sets the wallet used to pay the authorized subscription charges, `None` to review all
+    /// of them manually
+    pub async fn set_recurring_payment_wallet(
+        &self,
+        wallet: Option<Arc<dyn RecurringPaymentWallet>>,
+    ) {
+        *self.recurring_payment_wallet.lock().await = wallet;
+    }
+
+    /// The subscriptions confirmed by the user, to be saved by the app
+    pub async fn authorized_subscriptions(&self) -> Vec<AuthorizedSubscription> {
+        self.subscriptions.lock().await.list()
+    }
+
+    /// Loads the subscriptions saved by the app, e.g. at startup
+    pub async fn restore_authorized_subscriptions(
+        &self,
+        subscriptions: Vec<AuthorizedSubscription>,
+    ) {
+        let mut registry = self.subscriptions.lock().await;
+        for subscription in subscriptions {
+            registry.insert(subscription);
+        }
+    }
+
     pub async fn reply_single_payment_request(
         &self,
         request: SinglePaymentRequest,
         status: PaymentResponseContent,
     ) -> Result<(), AppError> {
+        if let PaymentStatus::Approved | PaymentStatus::Success { .. } = &status.status {
+            self.authorize_subkey(
+                SubkeyOperation::Payment {
+                    amount_msat: request.content.amount_millisats(),
+                },
+                request.service_key,
+            )?;
+        }
+
+        // Count the subscription charges paid manually too
+        if let (PaymentStatus::Success { .. }, Some(subscription_id)) =
+            (&status.status, &request.content.subscription_id)
+        {
+            let mut subscriptions = self.subscriptions.lock().await;
+            if let SubscriptionChargeCheck::Authorized { slot } =
+                subscriptions.check(&request.service_key, &request.content, Timestamp::now())
+            {
+                subscriptions.record_payment(subscription_id, slot);
+            }
+        }
+
         let conv = PaymentStatusSenderConversation::new(
             request.service_key.into(),
             request.recipient.into(),
@@ -679,6 +1247,34 @@ impl PortalApp {
         request: RecurringPaymentRequest,
         status: RecurringPaymentResponseContent,
     ) -> Result<(), AppError> {
+        if let RecurringPaymentStatus::Confirmed { .. } = &status.status {
+            self.authorize_subkey(
+                SubkeyOperation::Payment {
+                    amount_msat: request.content.amount_millisats(),
+                },
+                request.service_key,
+            )?;
+        }
+
+        if let RecurringPaymentStatus::Confirmed {
+            subscription_id,
+            authorized_amount,
+            authorized_currency,
+            authorized_recurrence,
+        } = &status.status
+        {
+            self.subscriptions
+                .lock()
+                .await
+                .insert(AuthorizedSubscription::new(
+                    subscription_id.clone(),
+                    request.service_key,
+                    *authorized_amount,
+                    authorized_currency.clone(),
+                    authorized_recurrence.clone(),
+                ));
+        }
+
         let conv = RecurringPaymentStatusSenderConversation::new(
             request.service_key.into(),
             request.recipient.into(),
@@ -758,6 +1354,11 @@ impl PortalApp {
         service_key: PublicKey,
         subscription_id: String,
     ) -> Result<(), AppError> {
+        self.subscriptions
+            .lock()
+            .await
+            .close(&subscription_id, &service_key);
+
         let content = CloseRecurringPaymentContent {
             subscription_id,
             reason: None,
@@ -787,6 +1388,11 @@ impl PortalApp {
             .ok_or(AppError::ListenerDisconnected)?;
         let response = response.map_err(|e| AppError::ParseError(e.to_string()))?;
         log::debug!("Received closed recurring payment: {:?}", response);
+
+        self.subscriptions
+            .lock()
+            .await
+            .close(&response.content.subscription_id, &response.main_key);
         Ok(response)
     }
 
@@ -823,6 +1429,8 @@ impl PortalApp {
             return Ok(());
         }
 
+        self.authorize_subkey(SubkeyOperation::Nip46, event.nostr_client_pubkey)?;
+
         let nostr_connect_message: NostrConnectMessage = event.message.into();
         let nostr_connect_request = match nostr_connect_message.clone().to_request() {
             Ok(req) => req,
@@ -974,6 +1582,8 @@ impl PortalApp {
         request: portal::protocol::model::payment::InvoiceRequestContentWithKey,
         invoice: MakeInvoiceResponse,
     ) -> Result<(), AppError> {
+        self.authorize_subkey(SubkeyOperation::Invoice, request.main_key)?;
+
         let recipient = request.recipient.into();
         let invoice_response = InvoiceResponse {
             request,
@@ -1046,6 +1656,10 @@ impl PortalApp {
         request: CashuRequestContentWithKey,
         status: CashuResponseStatus,
     ) -> Result<(), AppError> {
+        if let CashuResponseStatus::Success { .. } = &status {
+            self.authorize_subkey(SubkeyOperation::Cashu, request.main_key)?;
+        }
+
         let recipient = request.recipient.into();
         let response = CashuResponseContent { request, status };
         let conv = CashuResponseSenderConversation::new(response);
@@ -1072,6 +1686,216 @@ impl PortalApp {
         Ok(response)
     }
 
+    pub async fn next_certificate_request(
+        &self,
+    ) -> Result<CertificateRequestContentWithKey, AppError> {
+        let request = self
+            .certificate_request_rx
+            .lock()
+            .await
+            .next()
+            .await
+            .ok_or(AppError::ListenerDisconnected)?;
+        let request = request.map_err(|e| AppError::ParseError(e.to_string()))?;
+        log::debug!("Received certificate request: {:?}", request);
+        Ok(request)
+    }
+
+    /// Wait for a certificate issued to the user
+    ///
+    /// The certificate is verified before being returned: it must be signed by its issuer and
+    /// issued to the user's key. `inner.certificate` should be stored by the app and passed back to
+    /// [`PortalApp::reply_certificate_request`].
+    pub async fn next_certificate_delivery(
+        &self,
+    ) -> Result<CertificateDeliveryContentWithKey, AppError> {
+        loop {
+            let delivery = self
+                .certificate_delivery_rx
+                .lock()
+                .await
+                .next()
+                .await
+                .ok_or(AppError::ListenerDisconnected)?;
+            let delivery = delivery.map_err(|e| AppError::ParseError(e.to_string()))?;
+            log::debug!("Received certificate delivery: {:?}", delivery);
+
+            let certificate: Certificate = match serde_json::from_str(&delivery.inner.certificate) {
+                Ok(certificate) => certificate,
+                Err(e) => {
+                    log::warn!("Ignoring invalid certificate: {:?}", e);
+                    continue;
+                }
+            };
+
+            let subject_is_us = certificate.subject == self.router.keypair().public_key()
+                || self
+                    .router
+                    .keypair()
+                    .subkey_proof()
+                    .is_some_and(|p| certificate.subject == *p.main_key);
+            if !subject_is_us {
+                log::warn!("Ignoring certificate issued to another key");
+                continue;
+            }
+
+            let verified = certificate
+                .create_partial(&[])
+                .map_err(|e| e.to_string())
+                .and_then(|partial| partial.verify().map_err(|e| e.to_string()));
+            if let Err(e) = verified {
+                log::warn!("Ignoring certificate that failed verification: {}", e);
+                continue;
+            }
+
+            return Ok(delivery);
+        }
+    }
+
+    /// Reply to a certificate request
+    ///
+    /// `certificates` are the JSON-serialized certificates held by the user. Only the ones matching
+    /// the requested types are included in the response, revealing just the requested fields.
+    ///
+    /// The response holds one certificate per type. Expired certificates and the ones revoked by
+    /// the latest revocation list of their issuer are skipped, and of the remaining ones of a
+    /// type the most recently issued is revealed.
+    ///
+    /// If the service asked for status proofs, the latest revocation list received from each issuer
+    /// (see [`PortalApp::watch_certificate_issuers`]) is attached to the response.
+    pub async fn reply_certificate_request(
+        &self,
+        request: CertificateRequestContentWithKey,
+        certificates: Vec<String>,
+        status: CertificateResponseStatus,
+    ) -> Result<(), AppError> {
+        let require_status_proofs = request.inner.require_status_proofs.unwrap_or(false);
+        let registry = self.revocation_registry.lock().await;
+
+        let mut selected: HashMap<String, Certificate> = HashMap::new();
+        if status == CertificateResponseStatus::Approved {
+            for certificate in certificates {
+                let certificate: Certificate = serde_json::from_str(&certificate)
+                    .map_err(|e| AppError::ParseError(e.to_string()))?;
+                let type_name = certificate.data.type_name().to_string();
+                if !request.inner.requested_types.is_empty()
+                    && !request.inner.requested_types.contains(&type_name)
+                {
+                    continue;
+                }
+
+                if certificate.metadata.expires_at < Timestamp::now() {
+                    log::debug!("Skipping expired '{}' certificate", type_name);
+                    continue;
+                }
+                let revoked = registry
+                    .get(&certificate.metadata.issuer_pubkey)
+                    .is_some_and(|list| {
+                        certificate.create_partial(&[]).is_ok_and(|partial| {
+                            matches!(
+                                list.check(&partial, u64::MAX),
+                                Ok(CertificateStatus::Revoked { .. })
+                            )
+                        })
+                    });
+                if revoked {
+                    log::debug!("Skipping revoked '{}' certificate", type_name);
+                    continue;
+                }
+
+                if selected.get(&type_name).is_some_and(|current| {
+                    current.metadata.issued_at >= certificate.metadata.issued_at
+                }) {
+                    continue;
+                }
+                selected.insert(type_name, certificate);
+            }
+        }
+
+        let mut revealed = HashMap::new();
+        let mut status_proofs = HashMap::new();
+        for (type_name, certificate) in selected {
+            let partial = certificate
+                .create_partial(&request.inner.requested_fields)
+                .map_err(|e| AppError::CertificateError(e.to_string()))?;
+            let partial = serde_json::to_value(&partial)
+                .map_err(|e| AppError::CertificateError(e.to_string()))?;
+            revealed.insert(type_name.clone(), partial);
+
+            if require_status_proofs {
+                match registry.get(&certificate.metadata.issuer_pubkey) {
+                    Some(list) => {
+                        let list = serde_json::to_value(list)
+                            .map_err(|e| AppError::CertificateError(e.to_string()))?;
+                        status_proofs.insert(type_name, list);
+                    }
+                    None => log::warn!(
+                        "No revocation list available for issuer {}",
+                        certificate.metadata.issuer_pubkey
+                    ),
+                }
+            }
+        }
+        drop(registry);
+
+        let recipient = request.recipient.into();
+        let content = CertificateResponseContent {
+            request_id: request.inner.request_id.clone(),
+            status,
+            certificates: revealed,
+            status_proofs: require_status_proofs.then_some(status_proofs),
+        };
+        let conv = CertificateResponseSenderConversation::new(request, content);
+        let _ = self.router
+            .add_conversation(Box::new(OneShotSenderAdapter::new_with_user(
+                recipient,
+                vec![],
+                conv,
+            )))
+            .await?;
+        Ok(())
+    }
+
+    /// Start listening for the revocation lists published by the issuers of the user's certificates
+    ///
+    /// The most recent list of each issuer is kept in memory and used as a status proof when
+    /// replying to certificate requests. Issuers already watched are skipped, so this can be called
+    /// again with all the issuers whenever the user receives a certificate.
+    pub async fn watch_certificate_issuers(&self, issuers: Vec<PublicKey>) -> Result<(), AppError> {
+        let mut watched = self.watched_issuers.lock().await;
+        let issuers: HashSet<PublicKey> = issuers
+            .into_iter()
+            .filter(|issuer| !watched.contains(issuer))
+            .collect();
+        if issuers.is_empty() {
+            return Ok(());
+        }
+
+        let conv = RevocationListListenerConversation::new(
+            issuers.iter().copied().map(Into::into).collect(),
+        );
+        let (mut rx, _outcomes): (NotificationStream<RevocationList>, _) =
+            self.router.add_and_subscribe(Box::new(conv)).await?;
+        watched.extend(issuers);
+        drop(watched);
+
+        let registry = Arc::clone(&self.revocation_registry);
+        std::mem::drop(self.runtime.add_task(async move {
+            while let Some(list) = rx.next().await {
+                match list {
+                    Ok(list) => {
+                        if let Err(e) = registry.lock().await.update(list) {
+                            log::warn!("Invalid revocation list: {:?}", e);
+                        }
+                    }
+                    Err(e) => log::warn!("Failed to parse revocation list: {:?}", e),
+                }
+            }
+        }));
+
+        Ok(())
+    }
+
     pub async fn single_payment_request(
         &self,
         receiver_pubkey: &str,
@@ -1101,6 +1925,68 @@ impl PortalApp {
 }
 
 impl PortalApp {
+    /// Fails if the app runs with a subkey that isn't allowed to perform `operation` with
+    /// `counterparty`
+    fn authorize_subkey(
+        &self,
+        operation: SubkeyOperation,
+        counterparty: PublicKey,
+    ) -> Result<(), AppError> {
+        match self.router.keypair().subkey_proof() {
+            Some(proof) => proof
+                .metadata
+                .authorize(&operation, Some(&*counterparty))
+                .map_err(|e| AppError::SubkeyError(e.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Approve and pay a subscription charge, undoing its record if the payment fails
+    async fn pay_subscription_charge(
+        router: Arc<MessageRouter<Arc<RelayPool>>>,
+        subscriptions: Arc<Mutex<SubscriptionRegistry>>,
+        wallet: Arc<dyn RecurringPaymentWallet>,
+        request: SinglePaymentRequest,
+        previous_slot: Option<Timestamp>,
+    ) -> Result<(), AppError> {
+        let reply = |status: PaymentStatus| {
+            let conv = PaymentStatusSenderConversation::new(
+                request.service_key.into(),
+                request.recipient.into(),
+                PaymentResponseContent {
+                    request_id: request.content.request_id.clone(),
+                    status,
+                },
+            );
+            router.add_conversation(Box::new(OneShotSenderAdapter::new_with_user(
+                request.recipient.into(),
+                vec![],
+                conv,
+            )))
+        };
+
+        reply(PaymentStatus::Approved).await?;
+
+        let status = match wallet.pay_invoice(request.clone()).await {
+            Ok(preimage) => PaymentStatus::Success { preimage },
+            Err(e) => {
+                log::warn!("Failed to pay subscription charge: {e}");
+                if let Some(subscription_id) = &request.content.subscription_id {
+                    subscriptions
+                        .lock()
+                        .await
+                        .undo_payment(subscription_id, previous_slot);
+                }
+                PaymentStatus::Failed {
+                    reason: Some(e.to_string()),
+                }
+            }
+        };
+        reply(status).await?;
+
+        Ok(())
+    }
+
     /// Set up relay status monitoring in a separate task
     fn setup_relay_status_monitoring(
         runtime: Arc<BindingsRuntime>,
@@ -1233,6 +2119,11 @@ pub struct RecurringPaymentRequest {
 pub enum IncomingPaymentRequest {
     Single(SinglePaymentRequest),
     Recurring(RecurringPaymentRequest),
+    /// A charge of a subscription that was not paid automatically
+    SubscriptionCharge {
+        request: SinglePaymentRequest,
+        check: SubscriptionChargeCheck,
+    },
 }
 
 #[derive(Debug, thiserror::Error, uniffi::Error)]
@@ -1282,6 +2173,15 @@ pub enum AppError {
 
     #[error("Error sending single payment request: {0}")]
     RequestSinglePaymentError(String),
+
+    #[error("Certificate error: {0}")]
+    CertificateError(String),
+
+    #[error("Permission not requested by the service: {0}")]
+    PermissionNotRequested(String),
+
+    #[error("Subkey error: {0}")]
+    SubkeyError(String),
 }
 
 impl From<portal::router::ConversationError> for AppError {
@@ -0,0 +1,364 @@
+//! Subscriptions confirmed by the user
+//!
+//! [`crate::PortalApp`] remembers the recurring payments confirmed through
+//! `reply_recurring_payment_request`, so that the charges services send for them can be checked
+//! against what the user authorized: same amount and currency, at most one payment per calendar
+//! slot, and no more than `max_payments` or after `until`.
+
+use std::{collections::HashMap, str::FromStr};
+
+use lightning_invoice::Bolt11Invoice;
+use portal::protocol::model::{
+    Timestamp,
+    bindings::PublicKey,
+    payment::{Amount, Currency, RecurrenceInfo, SinglePaymentRequestContent},
+};
+
+/// How early a charge may come before its due date, to make up for clock differences
+const SLOT_TOLERANCE_SECS: u64 = 10 * 60;
+/// Max due dates skipped to find the one a late charge is for
+const MAX_SKIPPED_SLOTS: usize = 10_000;
+
+/// A recurring payment confirmed by the user
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct AuthorizedSubscription {
+    pub subscription_id: String,
+    /// The service the subscription was confirmed to
+    pub service_key: PublicKey,
+    pub amount: Amount,
+    pub currency: Currency,
+    pub recurrence: RecurrenceInfo,
+    pub payments_made: u32,
+    /// Due date of the last payment made
+    pub last_paid_slot: Option<Timestamp>,
+    /// Closed by the user or by the service
+    pub closed: bool,
+}
+
+impl AuthorizedSubscription {
+    pub fn new(
+        subscription_id: String,
+        service_key: PublicKey,
+        amount: Amount,
+        currency: Currency,
+        recurrence: RecurrenceInfo,
+    ) -> Self {
+        Self {
+            subscription_id,
+            service_key,
+            amount,
+            currency,
+            recurrence,
+            payments_made: 0,
+            last_paid_slot: None,
+            closed: false,
+        }
+    }
+
+    /// Due date of the payment a charge received at `now` is for
+    ///
+    /// A late charge is for the latest due date, the ones before it are skipped.
+    fn due_slot(&self, now: Timestamp) -> Result<Timestamp, SubscriptionChargeCheck> {
+        let calendar = self.recurrence.calendar.get_calendar();
+        let next_slot =
+            |slot: Timestamp| calendar.next_occurrence(Timestamp::new(slot.as_u64() + 1));
+
+        let mut slot = match self.last_paid_slot {
+            None => self.recurrence.first_payment_due,
+            Some(last) => next_slot(last).ok_or(SubscriptionChargeCheck::Expired)?,
+        };
+
+        let limit = now.as_u64() + SLOT_TOLERANCE_SECS;
+        if slot.as_u64() > limit {
+            return Err(SubscriptionChargeCheck::NotDue { next_due: slot });
+        }
+        for _ in 0..MAX_SKIPPED_SLOTS {
+            match next_slot(slot) {
+                Some(next) if next.as_u64() <= limit => slot = next,
+                _ => break,
+            }
+        }
+
+        if self.recurrence.until.is_some_and(|until| slot > until) {
+            return Err(SubscriptionChargeCheck::Expired);
+        }
+        Ok(slot)
+    }
+}
+
+/// Outcome of checking a charge against the subscription it claims to be for
+#[derive(Debug, Clone, PartialEq, uniffi::Enum)]
+pub enum SubscriptionChargeCheck {
+    /// Within the authorization, for the payment due at `slot`
+    Authorized {
+        slot: Timestamp,
+    },
+    /// The subscription was not confirmed on this device
+    UnknownSubscription,
+    /// The charge comes from a service other than the one the subscription was confirmed to
+    WrongService,
+    Closed,
+    AmountMismatch {
+        authorized: Amount,
+        requested: Amount,
+    },
+    CurrencyMismatch {
+        authorized: Currency,
+        requested: Currency,
+    },
+    /// The invoice is not for the requested amount of millisats
+    InvoiceAmountMismatch {
+        invoice_msat: Option<u64>,
+    },
+    /// The payment of the current slot was already made, or the first one is not due yet
+    NotDue {
+        next_due: Timestamp,
+    },
+    NoPaymentsLeft,
+    /// The subscription ended (`until` is past)
+    Expired,
+}
+
+/// Local registry of the subscriptions confirmed by the user
+#[derive(Debug, Default)]
+pub struct SubscriptionRegistry {
+    subscriptions: HashMap<String, AuthorizedSubscription>,
+}
+
+impl SubscriptionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a subscription, replacing the one with the same ID if any
+    pub fn insert(&mut self, subscription: AuthorizedSubscription) {
+        self.subscriptions
+            .insert(subscription.subscription_id.clone(), subscription);
+    }
+
+    pub fn list(&self) -> Vec<AuthorizedSubscription> {
+        self.subscriptions.values().cloned().collect()
+    }
+
+    /// Marks a subscription of `service_key` as closed
+    pub fn close(&mut self, subscription_id: &str, service_key: &PublicKey) {
+        if let Some(subscription) = self.subscriptions.get_mut(subscription_id)
+            && subscription.service_key == *service_key
+        {
+            subscription.closed = true;
+        }
+    }
+
+    /// Checks a charge sent by `service_key` against the subscription it is for
+    pub fn check(
+        &self,
+        service_key: &PublicKey,
+        content: &SinglePaymentRequestContent,
+        now: Timestamp,
+    ) -> SubscriptionChargeCheck {
+        let Some(subscription) = content
+            .subscription_id
+            .as_ref()
+            .and_then(|id| self.subscriptions.get(id))
+        else {
+            return SubscriptionChargeCheck::UnknownSubscription;
+        };
+
+        if subscription.service_key != *service_key {
+            return SubscriptionChargeCheck::WrongService;
+        }
+        if subscription.closed {
+            return SubscriptionChargeCheck::Closed;
+        }
+        if content.amount != subscription.amount {
+            return SubscriptionChargeCheck::AmountMismatch {
+                authorized: subscription.amount,
+                requested: content.amount,
+            };
+        }
+        if content.currency != subscription.currency {
+            return SubscriptionChargeCheck::CurrencyMismatch {
+                authorized: subscription.currency.clone(),
+                requested: content.currency.clone(),
+            };
+        }
+        // Fiat charges are converted with the service's exchange rate, their invoice can only
+        // be checked by the wallet
+        if content.currency == Currency::Millisats {
+            let invoice_msat = Bolt11Invoice::from_str(&content.invoice)
+                .ok()
+                .and_then(|invoice| invoice.amount_milli_satoshis());
+            if invoice_msat != Some(content.amount.as_millisats()) {
+                return SubscriptionChargeCheck::InvoiceAmountMismatch { invoice_msat };
+            }
+        }
+        if subscription
+            .recurrence
+            .max_payments
+            .is_some_and(|max| subscription.payments_made >= max)
+        {
+            return SubscriptionChargeCheck::NoPaymentsLeft;
+        }
+
+        match subscription.due_slot(now) {
+            Ok(slot) => SubscriptionChargeCheck::Authorized { slot },
+            Err(check) => check,
+        }
+    }
+
+    /// Records the payment due at `slot`, returning the previous last paid slot
+    pub fn record_payment(
+        &mut self,
+        subscription_id: &str,
+        slot: Timestamp,
+    ) -> Option<Option<Timestamp>> {
+        let subscription = self.subscriptions.get_mut(subscription_id)?;
+        subscription.payments_made += 1;
+        Some(subscription.last_paid_slot.replace(slot))
+    }
+
+    /// Reverts [`Self::record_payment`], for a payment that failed after all
+    pub fn undo_payment(&mut self, subscription_id: &str, previous_slot: Option<Timestamp>) {
+        if let Some(subscription) = self.subscriptions.get_mut(subscription_id) {
+            subscription.payments_made = subscription.payments_made.saturating_sub(1);
+            subscription.last_paid_slot = previous_slot;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use portal::protocol::calendar::{Calendar, CalendarWrapper};
+
+    const DAY: u64 = 24 * 60 * 60;
+    // 2025-01-01T00:00:00Z
+    const START: u64 = 1_735_689_600;
+
+    fn service() -> PublicKey {
+        PublicKey(nostr::Keys::generate().public_key())
+    }
+
+    fn subscription(service_key: PublicKey, max_payments: Option<u32>) -> AuthorizedSubscription {
+        AuthorizedSubscription::new(
+            "sub".to_string(),
+            service_key,
+            Amount::new(500),
+            Currency::Fiat("EUR".to_string()),
+            RecurrenceInfo {
+                until: None,
+                calendar: CalendarWrapper::new(Calendar::from_str("daily").unwrap()),
+                max_payments,
+                first_payment_due: Timestamp::new(START),
+            },
+        )
+    }
+
+    fn charge(amount: u64) -> SinglePaymentRequestContent {
+        SinglePaymentRequestContent {
+            amount: Amount::new(amount),
+            currency: Currency::Fiat("EUR".to_string()),
+            current_exchange_rate: None,
+            invoice: String::new(),
+            auth_token: None,
+            expires_at: Timestamp::new(START + DAY),
+            subscription_id: Some("sub".to_string()),
+            description: None,
+            request_id: "request".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_charge_slots() {
+        let service_key = service();
+        let mut registry = SubscriptionRegistry::new();
+        registry.insert(subscription(service_key, Some(2)));
+
+        assert_eq!(
+            registry.check(&service_key, &charge(500), Timestamp::new(START - DAY)),
+            SubscriptionChargeCheck::NotDue {
+                next_due: Timestamp::new(START)
+            }
+        );
+
+        let now = Timestamp::new(START + 60);
+        let SubscriptionChargeCheck::Authorized { slot } =
+            registry.check(&service_key, &charge(500), now)
+        else {
+            panic!("charge not authorized");
+        };
+        assert_eq!(slot, Timestamp::new(START));
+        registry.record_payment("sub", slot);
+
+        // Already paid for today
+        assert!(matches!(
+            registry.check(&service_key, &charge(500), now),
+            SubscriptionChargeCheck::NotDue { .. }
+        ));
+
+        // Three days late: only the latest slot is paid
+        let SubscriptionChargeCheck::Authorized { slot } = registry.check(
+            &service_key,
+            &charge(500),
+            Timestamp::new(START + 3 * DAY + 60),
+        ) else {
+            panic!("charge not authorized");
+        };
+        assert_eq!(slot, Timestamp::new(START + 3 * DAY));
+        let previous = registry.record_payment("sub", slot).unwrap();
+
+        assert_eq!(
+            registry.check(&service_key, &charge(500), Timestamp::new(START + 4 * DAY)),
+            SubscriptionChargeCheck::NoPaymentsLeft
+        );
+
+        registry.undo_payment("sub", previous);
+        assert!(matches!(
+            registry.check(&service_key, &charge(500), Timestamp::new(START + 4 * DAY)),
+            SubscriptionChargeCheck::Authorized { .. }
+        ));
+    }
+
+    #[test]
+    fn test_charge_outside_authorization() {
+        let service_key = service();
+        let mut registry = SubscriptionRegistry::new();
+        registry.insert(subscription(service_key, None));
+        let now = Timestamp::new(START);
+
+        assert_eq!(
+            registry.check(&service_key, &charge(600), now),
+            SubscriptionChargeCheck::AmountMismatch {
+                authorized: Amount::new(500),
+                requested: Amount::new(600),
+            }
+        );
+        assert_eq!(
+            registry.check(&service(), &charge(500), now),
+            SubscriptionChargeCheck::WrongService
+        );
+        assert_eq!(
+            registry.check(
+                &service_key,
+                &SinglePaymentRequestContent {
+                    subscription_id: Some("other".to_string()),
+                    ..charge(500)
+                },
+                now
+            ),
+            SubscriptionChargeCheck::UnknownSubscription
+        );
+
+        registry.close("sub", &service());
+        assert!(matches!(
+            registry.check(&service_key, &charge(500), now),
+            SubscriptionChargeCheck::Authorized { .. }
+        ));
+        registry.close("sub", &service_key);
+        assert_eq!(
+            registry.check(&service_key, &charge(500), now),
+            SubscriptionChargeCheck::Closed
+        );
+    }
+}
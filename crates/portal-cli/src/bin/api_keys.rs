@@ -0,0 +1,193 @@
+//! API key management
+//!
+//! Creates, lists and revokes the API keys of a running `portal-rest` daemon, through its
+//! `/api-keys` endpoints.
+//!
+//! The daemon is reached at `PORTAL_REST_URL` (default: `http://localhost:3000`), with the
+//! `auth_token` of its config or an API key with the `admin` scope in `PORTAL_AUTH_TOKEN`.
+//! API keys are per identity: set `PORTAL_REST_URL` to `http://localhost:3000/identities/<name>`
+//! to manage the keys of another identity.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use portal_cli::{CliError, Options};
+use serde_json::{Value, json};
+
+const USAGE: &str = "Usage:
+  api_keys create <name> [options]
+      Create an API key and print it. The key can't be shown again.
+
+      --scope <scope>              Scope of the key: auth, payments:request, wallet:spend,
+                                   cashu, relays or admin (repeatable, at least one)
+      --days <n>                   Validity in days (default: no expiry)
+
+  api_keys list
+      List the API keys, revoked and expired ones included.
+
+  api_keys revoke <key-id>
+      Revoke an API key. It is rejected from then on.";
+
+const DEFAULT_URL: &str = "http://localhost:3000";
+
+struct Daemon {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+impl Daemon {
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value, CliError> {
+        let mut request = self
+            .client
+            .request(method, format!("{}{path}", self.url))
+            .bearer_auth(&self.token);
+        if let Some(body) = body {
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body.to_string());
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        let body: Value = serde_json::from_str(&response.text().await?)
+            .map_err(|_| format!("Unexpected response from the daemon (HTTP {status})"))?;
+        if !status.is_success() {
+            let error = body["error"].as_str().unwrap_or("unknown error");
+            return Err(format!("HTTP {status}: {error}").into());
+        }
+        Ok(body["data"].clone())
+    }
+}
+
+fn parse_create_args(args: &[String]) -> Result<Value, CliError> {
+    let [name, options @ ..] = args else {
+        return Err(USAGE.into());
+    };
+
+    let mut scopes = vec![];
+    let mut expires_at = None;
+
+    let mut options = Options::new(options);
+    while let Some(option) = options.next_option() {
+        match option {
+            // Checked by the daemon
+            "--scope" => scopes.push(options.value()?.clone()),
+            "--days" => {
+                let days: u64 = options.value()?.parse()?;
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                expires_at = Some(now + days * 24 * 60 * 60);
+            }
+            other => return Err(format!("Unknown option: {other}\n\n{USAGE}").into()),
+        }
+    }
+
+    if scopes.is_empty() {
+        return Err(format!("At least one --scope is required\n\n{USAGE}").into());
+    }
+
+    Ok(json!({
+        "name": name,
+        "scopes": scopes,
+        "expires_at": expires_at,
+    }))
+}
+
+async fn create(daemon: &Daemon, request: Value) -> Result<(), CliError> {
+    let created = daemon
+        .request(reqwest::Method::POST, "/api-keys", Some(request))
+        .await?;
+
+    let id = created["api_key"]["id"].as_str().unwrap_or_default();
+    log::info!("Created API key {id}");
+
+    println!("id = {id}");
+    println!("key = {}", created["key"].as_str().unwrap_or_default());
+
+    Ok(())
+}
+
+async fn list(daemon: &Daemon) -> Result<(), CliError> {
+    let response = daemon
+        .request(reqwest::Method::GET, "/api-keys", None)
+        .await?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    for key in response["keys"].as_array().into_iter().flatten() {
+        let state = if !key["revoked_at"].is_null() {
+            "revoked"
+        } else if key["expires_at"]
+            .as_i64()
+            .is_some_and(|expires_at| expires_at <= now)
+        {
+            "expired"
+        } else {
+            "active"
+        };
+        let scopes: Vec<&str> = key["scopes"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect();
+
+        println!(
+            "{}  {}...  {:<8} {}  [{}]",
+            key["id"].as_str().unwrap_or_default(),
+            key["prefix"].as_str().unwrap_or_default(),
+            state,
+            key["name"].as_str().unwrap_or_default(),
+            scopes.join(", "),
+        );
+    }
+
+    Ok(())
+}
+
+async fn revoke(daemon: &Daemon, key_id: &str) -> Result<(), CliError> {
+    daemon
+        .request(
+            reqwest::Method::DELETE,
+            &format!("/api-keys/{key_id}"),
+            None,
+        )
+        .await?;
+    log::info!("Revoked API key {key_id}");
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), CliError> {
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some((command, args)) = args.split_first() else {
+        eprintln!("{USAGE}");
+        return Ok(());
+    };
+
+    let token = std::env::var("PORTAL_AUTH_TOKEN")
+        .map_err(|_| "PORTAL_AUTH_TOKEN must be set to the auth token or an admin API key")?;
+    let daemon = Daemon {
+        client: reqwest::Client::new(),
+        url: std::env::var("PORTAL_REST_URL")
+            .unwrap_or_else(|_| DEFAULT_URL.to_string())
+            .trim_end_matches('/')
+            .to_string(),
+        token,
+    };
+
+    match (command.as_str(), args) {
+        ("create", args) => create(&daemon, parse_create_args(args)?).await?,
+        ("list", []) => list(&daemon).await?,
+        ("revoke", [key_id]) => revoke(&daemon, key_id).await?,
+        _ => eprintln!("{USAGE}"),
+    }
+
+    Ok(())
+}
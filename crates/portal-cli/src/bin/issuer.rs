@@ -0,0 +1,163 @@
+//! Certificate issuer
+//!
+//! Signs identity certificates with the issuer key and exports them as JSON, optionally
+//! delivering them to their subject over Nostr.
+//!
+//! The issuer key is read from the `PORTAL_ISSUER_KEY` environment variable (nsec or hex).
+
+use portal::{
+    nostr::key::{Keys, PublicKey},
+    protocol::{
+        LocalKeypair,
+        identity::{CertificateData, VerificationLevel, VerificationMethod},
+        issuance::CertificateBuilder,
+        predicate::Predicate,
+    },
+};
+use portal_cli::{CliError, Options, default_relays, publish_to_relays};
+use portal_sdk::PortalSDK;
+
+const USAGE: &str = "Usage:
+  issuer pubkey
+      Print the issuer public key
+
+  issuer issue <subject-pubkey> <data.json> [options]
+      Sign a certificate for <subject-pubkey>. <data.json> contains the certificate data,
+      tagged by \"type\" (\"personal\", \"business\" or \"custom\").
+
+      --level <high|medium|low>    Verification level (default: low)
+      --method <method>            Verification method (default: document_upload)
+      --days <n>                   Validity in days (default: 365)
+      --predicate <predicate>      Commit a predicate claim (repeatable): age_over:<n>,
+                                   nationality_in_eu or document_valid
+      --out <file>                 Write the certificate to <file> instead of stdout
+      --deliver                    Send the certificate to the subject over Nostr
+      --relay <url>                Relay used for delivery (repeatable)";
+
+struct IssueArgs {
+    subject: PublicKey,
+    data_path: String,
+    level: VerificationLevel,
+    method: VerificationMethod,
+    days: u64,
+    predicates: Vec<Predicate>,
+    out: Option<String>,
+    deliver: bool,
+    relays: Vec<String>,
+}
+
+fn parse_enum<T: serde::de::DeserializeOwned>(name: &str, value: &str) -> Result<T, CliError> {
+    serde_json::from_value(serde_json::Value::String(value.to_string()))
+        .map_err(|_| format!("Invalid {name}: {value}").into())
+}
+
+fn parse_predicate(value: &str) -> Result<Predicate, CliError> {
+    match value.split_once(':') {
+        Some(("age_over", age)) => Ok(Predicate::AgeOver { age: age.parse()? }),
+        None if value == "nationality_in_eu" => Ok(Predicate::nationality_in_eu()),
+        None if value == "document_valid" => Ok(Predicate::DocumentValid),
+        _ => Err(format!("Invalid predicate: {value}").into()),
+    }
+}
+
+fn parse_issue_args(args: &[String]) -> Result<IssueArgs, CliError> {
+    let [subject, data_path, options @ ..] = args else {
+        return Err(USAGE.into());
+    };
+
+    let mut parsed = IssueArgs {
+        subject: PublicKey::parse(subject)?,
+        data_path: data_path.clone(),
+        level: VerificationLevel::Low,
+        method: VerificationMethod::DocumentUpload,
+        days: 365,
+        predicates: vec![],
+        out: None,
+        deliver: false,
+        relays: vec![],
+    };
+
+    let mut options = Options::new(options);
+    while let Some(option) = options.next_option() {
+        match option {
+            "--level" => parsed.level = parse_enum("verification level", options.value()?)?,
+            "--method" => parsed.method = parse_enum("verification method", options.value()?)?,
+            "--days" => parsed.days = options.value()?.parse()?,
+            "--predicate" => parsed.predicates.push(parse_predicate(options.value()?)?),
+            "--out" => parsed.out = Some(options.value()?.clone()),
+            "--deliver" => parsed.deliver = true,
+            "--relay" => parsed.relays.push(options.value()?.clone()),
+            other => return Err(format!("Unknown option: {other}\n\n{USAGE}").into()),
+        }
+    }
+
+    if parsed.relays.is_empty() {
+        parsed.relays = default_relays();
+    }
+
+    Ok(parsed)
+}
+
+async fn issue(keys: Keys, args: IssueArgs) -> Result<(), CliError> {
+    let data: CertificateData = serde_json::from_str(&std::fs::read_to_string(&args.data_path)?)?;
+
+    let builder = CertificateBuilder::new(keys.public_key(), args.subject)
+        .data(data)
+        .verification(args.level, args.method)
+        .valid_for(args.days * 24 * 60 * 60);
+    let certificate = args
+        .predicates
+        .into_iter()
+        .fold(builder, |builder, predicate| builder.predicate(predicate))
+        .sign(&keys)?;
+
+    let json = serde_json::to_string_pretty(&certificate)?;
+    match &args.out {
+        Some(path) => {
+            std::fs::write(path, &json)?;
+            log::info!("Certificate written to {}", path);
+        }
+        None => println!("{}", json),
+    }
+    log::info!(
+        "Issued certificate {}",
+        certificate.metadata.certificate_id()
+    );
+
+    if args.deliver {
+        let sdk = PortalSDK::new(LocalKeypair::new(keys, None), args.relays).await?;
+
+        publish_to_relays(async {
+            sdk.deliver_certificate(args.subject, vec![], &certificate)
+                .await?;
+            log::info!("Certificate sent to {}", args.subject);
+            Ok::<_, CliError>(())
+        })
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), CliError> {
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some((command, args)) = args.split_first() else {
+        eprintln!("{USAGE}");
+        return Ok(());
+    };
+
+    let key = std::env::var("PORTAL_ISSUER_KEY")
+        .map_err(|_| "PORTAL_ISSUER_KEY must be set to the issuer secret key")?;
+    let keys = Keys::parse(&key)?;
+
+    match command.as_str() {
+        "pubkey" => println!("{}", keys.public_key()),
+        "issue" => issue(keys, parse_issue_args(args)?).await?,
+        _ => eprintln!("{USAGE}"),
+    }
+
+    Ok(())
+}
@@ -226,7 +226,7 @@ async fn auth_challenge_loop(app: Arc<PortalApp>) {
                 let _ = app.fetch_profile(event.service_key).await;
                 let status = AuthResponseStatus::Approved {
                     granted_permissions: vec![],
-                    session_token: String::from("ABC"),
+                    session_token: String::new(),
                 };
                 if let Err(e) = app.reply_auth_challenge(event, status).await {
                     error!("Failed to reply to auth challenge: {:?}", e);
@@ -251,6 +251,14 @@ async fn payment_request_loop(app: Arc<PortalApp>, nwc: Arc<nwc::NWC>) {
                     process_single_payment_request(single_app, single_nwc, request).await;
                 });
             }
+            Ok(IncomingPaymentRequest::SubscriptionCharge { request, check }) => {
+                info!("Received subscription charge ({:?}): {:?}", check, request);
+                let single_app = Arc::clone(&app);
+                let single_nwc = Arc::clone(&nwc);
+                tokio::spawn(async move {
+                    process_single_payment_request(single_app, single_nwc, request).await;
+                });
+            }
             Ok(IncomingPaymentRequest::Recurring(request)) => {
                 info!("Received recurring payment request: {:?}", request);
                 let content = request.content.clone();
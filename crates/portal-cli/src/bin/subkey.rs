@@ -0,0 +1,178 @@
+//! Subkey management
+//!
+//! Derives subkeys of a main key, printing the `[nostr]` settings a `portal-rest` daemon needs
+//! to run with the subkey, and publishes their revocation.
+//!
+//! The main key is read from the `PORTAL_MAIN_KEY` environment variable (nsec or hex).
+
+use portal::{
+    nostr::{
+        key::{Keys, PublicKey},
+        nips::nip19::ToBech32,
+    },
+    protocol::{
+        LocalKeypair,
+        model::Timestamp,
+        subkey::{PrivateSubkeyManager, SubkeyMetadata, SubkeyPermission},
+    },
+};
+use portal_cli::{CliError, Options, default_relays, publish_to_relays};
+use portal_sdk::PortalSDK;
+
+const USAGE: &str = "Usage:
+  subkey create <name> [options]
+      Derive a subkey of the main key and print its private key and the subkey_proof to put
+      in the [nostr] section of the portal-rest config.
+
+      --days <n>                   Validity in days (default: 365)
+      --permission <name>          Permission of the subkey: auth, payment, cashu, invoice,
+                                   nip46 or profile (repeatable, default: auth and payment)
+      --max-payment <msat>         Maximum amount of a single payment
+      --counterparty <pubkey>      Key the subkey may interact with (repeatable, default: any)
+
+  subkey revoke <subkey-pubkey> [options]
+      Publish the revocation of a subkey. Its proof is rejected from then on.
+
+      --reason <text>              Reason of the revocation
+      --relay <url>                Relay to publish to (repeatable)";
+
+struct CreateArgs {
+    name: String,
+    days: u64,
+    permissions: Vec<SubkeyPermission>,
+    max_payment_msat: Option<u64>,
+    counterparties: Vec<PublicKey>,
+}
+
+struct RevokeArgs {
+    subkey: PublicKey,
+    reason: Option<String>,
+    relays: Vec<String>,
+}
+
+fn parse_permission(value: &str) -> Result<SubkeyPermission, CliError> {
+    serde_json::from_value(serde_json::Value::String(value.to_string()))
+        .map_err(|_| format!("Invalid permission: {value}").into())
+}
+
+fn parse_create_args(args: &[String]) -> Result<CreateArgs, CliError> {
+    let [name, options @ ..] = args else {
+        return Err(USAGE.into());
+    };
+
+    let mut parsed = CreateArgs {
+        name: name.clone(),
+        days: 365,
+        permissions: vec![],
+        max_payment_msat: None,
+        counterparties: vec![],
+    };
+
+    let mut options = Options::new(options);
+    while let Some(option) = options.next_option() {
+        match option {
+            "--days" => parsed.days = options.value()?.parse()?,
+            "--permission" => parsed.permissions.push(parse_permission(options.value()?)?),
+            "--max-payment" => parsed.max_payment_msat = Some(options.value()?.parse()?),
+            "--counterparty" => parsed
+                .counterparties
+                .push(PublicKey::parse(options.value()?)?),
+            other => return Err(format!("Unknown option: {other}\n\n{USAGE}").into()),
+        }
+    }
+
+    if parsed.permissions.is_empty() {
+        parsed.permissions = vec![SubkeyPermission::Auth, SubkeyPermission::Payment];
+    }
+
+    Ok(parsed)
+}
+
+fn parse_revoke_args(args: &[String]) -> Result<RevokeArgs, CliError> {
+    let [subkey, options @ ..] = args else {
+        return Err(USAGE.into());
+    };
+
+    let mut parsed = RevokeArgs {
+        subkey: PublicKey::parse(subkey)?,
+        reason: None,
+        relays: vec![],
+    };
+
+    let mut options = Options::new(options);
+    while let Some(option) = options.next_option() {
+        match option {
+            "--reason" => parsed.reason = Some(options.value()?.clone()),
+            "--relay" => parsed.relays.push(options.value()?.clone()),
+            other => return Err(format!("Unknown option: {other}\n\n{USAGE}").into()),
+        }
+    }
+
+    if parsed.relays.is_empty() {
+        parsed.relays = default_relays();
+    }
+
+    Ok(parsed)
+}
+
+fn create(keys: Keys, args: CreateArgs) -> Result<(), CliError> {
+    let mut metadata = SubkeyMetadata::new(
+        args.name,
+        args.permissions,
+        Timestamp::now(),
+        Timestamp::now_plus_seconds(args.days * 24 * 60 * 60),
+    )
+    .with_allowed_counterparties(args.counterparties);
+    if let Some(max_payment_msat) = args.max_payment_msat {
+        metadata = metadata.with_max_payment_msat(max_payment_msat);
+    }
+    let (subkey, proof) = keys.create_subkey(&metadata)?.split();
+    proof.verify(&subkey.public_key())?;
+
+    log::info!(
+        "Derived subkey {} of {}",
+        subkey.public_key(),
+        keys.public_key()
+    );
+
+    // The proof is JSON without single quotes, so it fits in a TOML literal string
+    println!("[nostr]");
+    println!("private_key = \"{}\"", subkey.secret_key().to_bech32()?);
+    println!("subkey_proof = '{}'", serde_json::to_string(&proof)?);
+
+    Ok(())
+}
+
+async fn revoke(keys: Keys, args: RevokeArgs) -> Result<(), CliError> {
+    let sdk = PortalSDK::new(LocalKeypair::new(keys, None), args.relays).await?;
+
+    publish_to_relays(async {
+        sdk.revoke_subkeys(vec![args.subkey], args.reason).await?;
+        log::info!("Revoked subkey {}", args.subkey);
+        Ok::<_, CliError>(())
+    })
+    .await
+}
+
+#[tokio::main]
+async fn main() -> Result<(), CliError> {
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some((command, args)) = args.split_first() else {
+        eprintln!("{USAGE}");
+        return Ok(());
+    };
+
+    let key = std::env::var("PORTAL_MAIN_KEY")
+        .map_err(|_| "PORTAL_MAIN_KEY must be set to the main secret key")?;
+    let keys = Keys::parse(&key)?;
+
+    match command.as_str() {
+        "create" => create(keys, parse_create_args(args)?)?,
+        "revoke" => revoke(keys, parse_revoke_args(args)?).await?,
+        _ => eprintln!("{USAGE}"),
+    }
+
+    Ok(())
+}
@@ -1,4 +1,5 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use app::{
     CallbackError, Keypair, Mnemonic, PortalApp, RelayStatus, RelayStatusListener, RelayUrl,
@@ -21,6 +22,57 @@ impl RelayStatusListener for LogRelayStatusChange {
 
 pub type CliError = Box<dyn std::error::Error>;
 
+/// Relays used by the binaries when no `--relay` is given
+pub const DEFAULT_RELAYS: &[&str] = &["wss://relay.nostr.net", "wss://relay.damus.io"];
+
+pub fn default_relays() -> Vec<String> {
+    DEFAULT_RELAYS.iter().map(|r| r.to_string()).collect()
+}
+
+/// Options of a command, like `--days 30 --deliver`
+pub struct Options<'a> {
+    args: std::slice::Iter<'a, String>,
+    option: &'a str,
+}
+
+impl<'a> Options<'a> {
+    pub fn new(args: &'a [String]) -> Self {
+        Self {
+            args: args.iter(),
+            option: "",
+        }
+    }
+
+    /// The next option, e.g. `--days`
+    pub fn next_option(&mut self) -> Option<&'a str> {
+        self.option = self.args.next()?.as_str();
+        Some(self.option)
+    }
+
+    /// The value of the current option
+    pub fn value(&mut self) -> Result<&'a String, CliError> {
+        self.args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", self.option).into())
+    }
+}
+
+/// Runs `publish` once the relays had some time to connect, then waits for its events to be
+/// broadcasted, so the binary can exit right after.
+pub async fn publish_to_relays<T>(
+    publish: impl Future<Output = Result<T, CliError>>,
+) -> Result<T, CliError> {
+    // Give the relays some time to connect
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let result = publish.await?;
+
+    // Wait for the event to be broadcasted before exiting
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    Ok(result)
+}
+
 pub async fn create_app_instance(
     name: &str,
     mnemonic: &str,
@@ -10,6 +10,7 @@ import {
   AuthResponseStatus,
   SinglePaymentRequestContent,
   SinglePaymentResponse,
+  StreamResponse,
   InvoiceStatus,
   InvoicePaymentRequestContent,
   RecurringPaymentRequestContent,
@@ -21,16 +22,53 @@ import {
   InvoicePaymentResponse,
   PayInvoiceResponse,
   IssueJwtResponse,
+  JwtClaimsOptions,
+  JwtVerifyOptions,
   VerifyJwtResponse,
+  SessionResponse,
+  RevokeSessionRequest,
+  RevokeSessionResponse,
+  IssueCertificateRequest,
+  IssueCertificateResponse,
+  RequestCertificatesRequest,
+  CertificateResponse,
+  CertificateResponseStatus,
+  RevealedCertificate,
   CashuResponseStatus,
   VerificationSessionResponse,
+  ApiKey,
+  CreateApiKeyRequest,
+  CreatedApiKeyResponse,
   WalletInfoResponse,
+  WalletCapabilitiesResponse,
+  WalletPayment,
+  WalletPaymentsResponse,
+  ListWalletPaymentsOptions,
+  CreateWalletInvoiceRequest,
+  WalletInvoiceResponse,
+  MockInvoiceResponse,
+  LedgerResponse,
+  ListLedgerOptions,
+  ExportLedgerOptions,
+  OutboxEvent,
+  OutboxResponse,
+  OutboxRetryResponse,
+  Subscription,
+  SubscriptionStatus,
+  SubscriptionsResponse,
+  WebhookDelivery,
+  WebhookDeliveriesResponse,
+  WebhookDeliveryStatus,
+  WebhookReplayResponse,
   VersionResponse,
   InfoResponse,
   Nip05WellKnownResponse,
   Nip05Profile,
   StreamEvent,
   EventsResponse,
+  LiveEvent,
+  FollowOptions,
+  FollowAllOptions,
   WebhookPayload,
   Timestamp,
 } from './types';
@@ -55,6 +93,7 @@ function isTerminalEvent(event: StreamEvent): boolean {
     case 'recurring_payment_response':
     case 'invoice_response':
     case 'cashu_response':
+    case 'certificate_response':
     case 'error':
       return true;
     case 'payment_status_update': {
@@ -66,6 +105,22 @@ function isTerminalEvent(event: StreamEvent): boolean {
   }
 }
 
+/** Query string of the ledger filters, with the export `format` if set. */
+function ledgerQuery(options: ListLedgerOptions, format?: string): string {
+  const params = new URLSearchParams();
+  if (format !== undefined) params.set('format', format);
+  if (options.main_key !== undefined) params.set('main_key', options.main_key);
+  if (options.subscription_id !== undefined) params.set('subscription_id', options.subscription_id);
+  if (options.kind !== undefined) params.set('kind', options.kind);
+  if (options.status !== undefined) params.set('status', options.status);
+  if (options.from !== undefined) params.set('from', String(options.from));
+  if (options.until !== undefined) params.set('until', String(options.until));
+  if (options.offset !== undefined) params.set('offset', String(options.offset));
+  if (options.limit !== undefined) params.set('limit', String(options.limit));
+  const q = params.toString();
+  return q ? `?${q}` : '';
+}
+
 interface PendingStream {
   resolve: (event: StreamEvent) => void;
   reject: (err: Error) => void;
@@ -87,6 +142,9 @@ interface PendingStream {
 export class PortalClient {
   private baseUrl: string;
   private authToken?: string;
+  private identity?: string;
+  /** Sent with the POST requests, see `withIdempotencyKey()`. */
+  private idempotencyKey?: string;
   private webhookSecret?: string;
   private debugEnabled: boolean;
   private pollingTimer?: ReturnType<typeof setInterval>;
@@ -99,6 +157,7 @@ export class PortalClient {
   constructor(config: ClientConfig) {
     this.baseUrl = config.baseUrl.replace(/\/+$/, '');
     this.authToken = config.authToken;
+    this.identity = config.identity;
     this.webhookSecret = config.webhookSecret;
     this.debugEnabled = config.debug ?? false;
 
@@ -164,6 +223,22 @@ export class PortalClient {
     this.authToken = token;
   }
 
+  /**
+   * A view of this client that sends `key` in the Idempotency-Key header of its POST requests.
+   * Retrying a call through it (e.g. after a timeout) returns the response of the first call,
+   * with the same `stream_id`, instead of sending the user a second request. Use one key per
+   * operation, such as an order ID:
+   *
+   * ```ts
+   * const op = await client.withIdempotencyKey(orderId).requestSinglePayment(...);
+   * ```
+   */
+  public withIdempotencyKey(key: string): PortalClient {
+    const client = Object.create(this) as PortalClient;
+    client.idempotencyKey = key;
+    return client;
+  }
+
   private debug(message: string, data?: unknown): void {
     if (!this.debugEnabled) return;
     if (data !== undefined) {
@@ -184,6 +259,9 @@ export class PortalClient {
     if (this.authToken) {
       h['Authorization'] = `Bearer ${this.authToken}`;
     }
+    if (this.identity) {
+      h['X-Portal-Identity'] = this.identity;
+    }
     if (hasBody) {
       h['Content-Type'] = 'application/json';
     }
@@ -196,9 +274,13 @@ export class PortalClient {
 
     let res: Response;
     try {
+      const headers = this.headers(body !== undefined);
+      if (method === 'POST' && this.idempotencyKey) {
+        headers['Idempotency-Key'] = this.idempotencyKey;
+      }
       res = await fetch(url, {
         method,
-        headers: this.headers(body !== undefined),
+        headers,
         body: body !== undefined ? JSON.stringify(body) : undefined,
       });
     } catch (err) {
@@ -253,7 +335,7 @@ export class PortalClient {
     return this.request<T>('POST', path, body ?? {});
   }
 
-  private del<T>(path: string, body: unknown): Promise<T> {
+  private del<T>(path: string, body?: unknown): Promise<T> {
     return this.request<T>('DELETE', path, body);
   }
 
@@ -496,14 +578,19 @@ export class PortalClient {
     return { url: resp.url, streamId: resp.stream_id, done };
   }
 
-  /** Authenticate a key (NIP-46 style). Returns an async operation. */
+  /**
+   * Authenticate a key (NIP-46 style), optionally asking for permissions
+   * (e.g. `payments:single<=1000 sats`). Returns an async operation.
+   */
   public async authenticateKey(
     mainKey: string,
-    subkeys: string[] = []
+    subkeys: string[] = [],
+    permissions: string[] = []
   ): Promise<AsyncOperation<AuthResponseData>> {
-    const resp = await this.post<{ stream_id: string }>('/authenticate-key', {
+    const resp = await this.post<StreamResponse>('/authenticate-key', {
       main_key: mainKey,
       subkeys,
+      permissions,
     });
     const done = this.registerStream(resp.stream_id).then((event) => ({
       user_key: event.user_key as string,
@@ -511,7 +598,7 @@ export class PortalClient {
       challenge: event.challenge as string,
       status: event.status as AuthResponseStatus,
     }));
-    return { streamId: resp.stream_id, done };
+    return { streamId: resp.stream_id, delivery: resp.delivery, done };
   }
 
   // ---- Payments ----
@@ -536,7 +623,7 @@ export class PortalClient {
     const done = this.registerStream(resp.stream_id).then(
       (event) => event.status as InvoiceStatus
     );
-    return { streamId: resp.stream_id, done };
+    return { streamId: resp.stream_id, delivery: resp.delivery, done };
   }
 
   /**
@@ -556,7 +643,7 @@ export class PortalClient {
     const done = this.registerStream(resp.stream_id).then(
       (event) => event.status as InvoiceStatus
     );
-    return { streamId: resp.stream_id, done };
+    return { streamId: resp.stream_id, delivery: resp.delivery, done };
   }
 
   /** Request a recurring payment. Returns an async operation. */
@@ -565,7 +652,7 @@ export class PortalClient {
     subkeys: string[] = [],
     paymentRequest: RecurringPaymentRequestContent
   ): Promise<AsyncOperation<RecurringPaymentResponseContent>> {
-    const resp = await this.post<{ stream_id: string }>('/payments/recurring', {
+    const resp = await this.post<StreamResponse>('/payments/recurring', {
       main_key: mainKey,
       subkeys,
       payment_request: paymentRequest,
@@ -573,7 +660,7 @@ export class PortalClient {
     const done = this.registerStream(resp.stream_id).then(
       (event) => event.status as RecurringPaymentResponseContent
     );
-    return { streamId: resp.stream_id, done };
+    return { streamId: resp.stream_id, delivery: resp.delivery, done };
   }
 
   /** Close a recurring payment subscription. */
@@ -604,7 +691,7 @@ export class PortalClient {
     subkeys: string[],
     content: RequestInvoiceParams
   ): Promise<AsyncOperation<InvoicePaymentResponse>> {
-    const resp = await this.post<{ stream_id: string }>('/invoices/request', {
+    const resp = await this.post<StreamResponse>('/invoices/request', {
       recipient_key: recipientKey,
       subkeys,
       content,
@@ -613,7 +700,7 @@ export class PortalClient {
       invoice: event.invoice as string,
       payment_hash: (event.payment_hash as string) ?? null,
     }));
-    return { streamId: resp.stream_id, done };
+    return { streamId: resp.stream_id, delivery: resp.delivery, done };
   }
 
   /** Pay a BOLT11 invoice. Returns preimage and fees paid. */
@@ -623,19 +710,63 @@ export class PortalClient {
 
   // ---- JWT ----
 
-  /** Issue a JWT for the given target key. */
-  public async issueJwt(targetKey: string, durationHours: number): Promise<string> {
+  /** Issue a JWT for the given target key, with optional audience, issuer, scopes and jti. */
+  public async issueJwt(
+    targetKey: string,
+    durationHours: number,
+    options: JwtClaimsOptions = {}
+  ): Promise<string> {
     const response = await this.post<IssueJwtResponse>('/jwt/issue', {
+      ...options,
       target_key: targetKey,
       duration_hours: durationHours,
     });
     return response.token;
   }
 
-  /** Verify a JWT and return claims. */
-  public async verifyJwt(pubkey: string, token: string): Promise<{ target_key: string }> {
-    const response = await this.post<VerifyJwtResponse>('/jwt/verify', { pubkey, token });
-    return { target_key: response.target_key };
+  /** Verify a JWT and return claims. Rejects tokens whose jti is a revoked session. */
+  public async verifyJwt(
+    pubkey: string,
+    token: string,
+    options: JwtVerifyOptions = {}
+  ): Promise<VerifyJwtResponse> {
+    return this.post<VerifyJwtResponse>('/jwt/verify', { ...options, pubkey, token });
+  }
+
+  // ---- Sessions ----
+
+  /** Verify a user session token (signature, expiration and revocation). */
+  public async verifySession(token: string): Promise<SessionResponse> {
+    return this.post<SessionResponse>('/sessions/verify', { token });
+  }
+
+  /** Issue a new token for a valid user session. */
+  public async refreshSession(token: string): Promise<SessionResponse> {
+    return this.post<SessionResponse>('/sessions/refresh', { token });
+  }
+
+  /** Revoke a user session by token or ID. */
+  public async revokeSession(request: RevokeSessionRequest): Promise<RevokeSessionResponse> {
+    return this.post<RevokeSessionResponse>('/sessions/revoke', request);
+  }
+
+  // ---- Certificates ----
+
+  /** Issue a signed identity certificate to a user and (by default) deliver it over Nostr. */
+  public async issueCertificate(request: IssueCertificateRequest): Promise<IssueCertificateResponse> {
+    return this.post<IssueCertificateResponse>('/certificates/issue', request);
+  }
+
+  /** Ask a user to reveal certificate fields or predicates. Returns an async operation. */
+  public async requestCertificates(
+    request: RequestCertificatesRequest
+  ): Promise<AsyncOperation<CertificateResponse>> {
+    const resp = await this.post<StreamResponse>('/certificates/request', request);
+    const done = this.registerStream(resp.stream_id).then((event) => ({
+      status: event.status as CertificateResponseStatus,
+      certificates: event.certificates as Record<string, RevealedCertificate>,
+    }));
+    return { streamId: resp.stream_id, delivery: resp.delivery, done };
   }
 
   // ---- Cashu ----
@@ -648,7 +779,7 @@ export class PortalClient {
     unit: string,
     amount: number
   ): Promise<AsyncOperation<CashuResponseStatus>> {
-    const resp = await this.post<{ stream_id: string }>('/cashu/request', {
+    const resp = await this.post<StreamResponse>('/cashu/request', {
       recipient_key: recipientKey,
       subkeys,
       mint_url: mintUrl,
@@ -658,7 +789,7 @@ export class PortalClient {
     const done = this.registerStream(resp.stream_id).then((event) =>
       event.status as CashuResponseStatus
     );
-    return { streamId: resp.stream_id, done };
+    return { streamId: resp.stream_id, delivery: resp.delivery, done };
   }
 
   /** Send Cashu tokens directly to a recipient. */
@@ -714,6 +845,108 @@ export class PortalClient {
     return response.relay;
   }
 
+  // ---- API keys (admin scope) ----
+
+  /** List the API keys, revoked and expired ones included, newest first. */
+  public async listApiKeys(): Promise<ApiKey[]> {
+    const response = await this.get<{ keys: ApiKey[] }>('/api-keys');
+    return response.keys;
+  }
+
+  /** Create an API key. The returned `key` is only shown once. */
+  public async createApiKey(request: CreateApiKeyRequest): Promise<CreatedApiKeyResponse> {
+    return this.post<CreatedApiKeyResponse>('/api-keys', request);
+  }
+
+  /** Get an API key by ID. */
+  public async getApiKey(keyId: string): Promise<ApiKey> {
+    return this.get<ApiKey>(`/api-keys/${encodeURIComponent(keyId)}`);
+  }
+
+  /** Revoke an API key, it is rejected from then on. */
+  public async revokeApiKey(keyId: string): Promise<ApiKey> {
+    return this.del<ApiKey>(`/api-keys/${encodeURIComponent(keyId)}`);
+  }
+
+  // ---- Outbox ----
+
+  /** List the events that some relays didn't accept yet, oldest first. */
+  public async listOutbox(): Promise<OutboxEvent[]> {
+    const response = await this.get<OutboxResponse>('/outbox');
+    return response.events;
+  }
+
+  /** Retry a queued event (or all of them) right away, ignoring the backoff. */
+  public async retryOutbox(eventId?: string): Promise<OutboxRetryResponse> {
+    const path = eventId ? `/outbox/${encodeURIComponent(eventId)}/retry` : '/outbox/retry';
+    return this.post<OutboxRetryResponse>(path);
+  }
+
+  /** Drop a queued event (or all of them) without delivering it. Returns the number of events removed. */
+  public async purgeOutbox(eventId?: string): Promise<number> {
+    const path = eventId ? `/outbox/${encodeURIComponent(eventId)}` : '/outbox';
+    const response = await this.del<{ purged: number }>(path);
+    return response.purged;
+  }
+
+  // ---- Subscriptions ----
+
+  /**
+   * List the subscriptions charged by the server (requires billing to be enabled).
+   * Their lifecycle events are pushed to the stream of the recurring payment request.
+   */
+  public async listSubscriptions(status?: SubscriptionStatus): Promise<Subscription[]> {
+    const q = status !== undefined ? `?status=${status}` : '';
+    const response = await this.get<SubscriptionsResponse>(`/subscriptions${q}`);
+    return response.subscriptions;
+  }
+
+  /** Get a subscription charged by the server. */
+  public async getSubscription(subscriptionId: string): Promise<Subscription> {
+    return this.get<Subscription>(`/subscriptions/${encodeURIComponent(subscriptionId)}`);
+  }
+
+  // ---- Webhook deliveries ----
+
+  /**
+   * List webhook deliveries, most recently updated first.
+   * Without `status`, the ones that are pending or dead-lettered are returned.
+   */
+  public async listWebhookDeliveries(
+    options: { status?: WebhookDeliveryStatus; streamId?: string } = {}
+  ): Promise<WebhookDelivery[]> {
+    const params = new URLSearchParams();
+    if (options.status !== undefined) params.set('status', options.status);
+    if (options.streamId !== undefined) params.set('stream_id', options.streamId);
+    const q = params.toString() ? `?${params.toString()}` : '';
+    const response = await this.get<WebhookDeliveriesResponse>(`/webhooks/deliveries${q}`);
+    return response.deliveries;
+  }
+
+  /** Get the webhook delivery of a stream event, with its attempt log. */
+  public async getWebhookDelivery(streamId: string, index: number): Promise<WebhookDelivery> {
+    return this.get<WebhookDelivery>(`/webhooks/deliveries/${encodeURIComponent(streamId)}/${index}`);
+  }
+
+  /**
+   * Deliver stream events to the webhook again: only `index` if given, otherwise
+   * every event of the stream from `fromIndex` on.
+   */
+  public async replayWebhookDeliveries(
+    streamId: string,
+    options: { index?: number; fromIndex?: number } = {}
+  ): Promise<WebhookReplayResponse> {
+    if (options.index !== undefined) {
+      return this.post<WebhookReplayResponse>(
+        `/webhooks/deliveries/${encodeURIComponent(streamId)}/${options.index}/replay`
+      );
+    }
+    return this.post<WebhookReplayResponse>('/webhooks/deliveries/replay', {
+      stream_id: streamId,
+      from_index: options.fromIndex ?? 0,
+    });
+  }
+
   // ---- Calendar ----
 
   /** Calculate next occurrence for a calendar (e.g. "daily", "monthly"). */
@@ -741,6 +974,81 @@ export class PortalClient {
     return this.get<WalletInfoResponse>('/wallet/info');
   }
 
+  /** What the configured wallet supports (payment history, invoice lookup, ...). */
+  public async getWalletCapabilities(): Promise<WalletCapabilitiesResponse> {
+    return this.get<WalletCapabilitiesResponse>('/wallet/capabilities');
+  }
+
+  /** List the incoming and outgoing payments of the wallet, newest first. */
+  public async listWalletPayments(options: ListWalletPaymentsOptions = {}): Promise<WalletPaymentsResponse> {
+    const params = new URLSearchParams();
+    if (options.direction !== undefined) params.set('direction', options.direction);
+    if (options.from !== undefined) params.set('from', String(options.from));
+    if (options.until !== undefined) params.set('until', String(options.until));
+    if (options.offset !== undefined) params.set('offset', String(options.offset));
+    if (options.limit !== undefined) params.set('limit', String(options.limit));
+    const q = params.toString() ? `?${params.toString()}` : '';
+    return this.get<WalletPaymentsResponse>(`/wallet/payments${q}`);
+  }
+
+  /** Look up a payment of the wallet by payment hash. */
+  public async lookupWalletInvoice(paymentHash: string): Promise<WalletPayment> {
+    return this.get<WalletPayment>(`/wallet/invoices/${encodeURIComponent(paymentHash)}`);
+  }
+
+  /** Create an invoice paid to the wallet, optionally with a description hash or an expiry. */
+  public async createWalletInvoice(request: CreateWalletInvoiceRequest): Promise<WalletInvoiceResponse> {
+    return this.post<WalletInvoiceResponse>('/wallet/invoices', request);
+  }
+
+  /** List the payment ledger, newest first, filtered by user, subscription, kind, status or date. */
+  public async listLedger(options: ListLedgerOptions = {}): Promise<LedgerResponse> {
+    return this.get<LedgerResponse>(`/ledger${ledgerQuery(options)}`);
+  }
+
+  /**
+   * Export the payment ledger for bookkeeping, returning the contents of the CSV (default) or
+   * JSON file. All the matching entries are exported unless `offset` or `limit` are set.
+   */
+  public async exportLedger(options: ExportLedgerOptions = {}): Promise<string> {
+    const url = `${this.baseUrl}/ledger/export${ledgerQuery(options, options.format ?? 'csv')}`;
+    this.debug(`GET ${url}`);
+
+    let res: Response;
+    try {
+      res = await fetch(url, { headers: this.headers(false) });
+    } catch (err) {
+      throw new PortalSDKError(
+        `Network error: ${err instanceof Error ? err.message : String(err)}`,
+        'NETWORK_ERROR',
+        err
+      );
+    }
+    if (!res.ok) {
+      const text = await res.text().catch(() => '');
+      throw new PortalSDKError(`HTTP ${res.status}: ${text}`, 'HTTP_ERROR', undefined, res.status);
+    }
+    return res.text();
+  }
+
+  /**
+   * Mark an invoice of the mock wallet (`ln_backend = "mock"`) as paid.
+   * `invoice` is the BOLT11 invoice or its payment hash.
+   */
+  public async settleMockInvoice(invoice: string): Promise<MockInvoiceResponse> {
+    return this.post<MockInvoiceResponse>('/wallet/mock/settle', { invoice });
+  }
+
+  /** Mark a pending invoice of the mock wallet as expired. */
+  public async expireMockInvoice(invoice: string): Promise<MockInvoiceResponse> {
+    return this.post<MockInvoiceResponse>('/wallet/mock/expire', { invoice });
+  }
+
+  /** Make an invoice fail with `reason` in the mock wallet. */
+  public async failMockInvoice(invoice: string, reason?: string): Promise<MockInvoiceResponse> {
+    return this.post<MockInvoiceResponse>('/wallet/mock/fail', { invoice, reason });
+  }
+
   // ---- Events (low-level) ----
 
   /**
@@ -752,4 +1060,97 @@ export class PortalClient {
     return this.get<EventsResponse>(`/events/${encodeURIComponent(streamId)}${q}`);
   }
 
+  /**
+   * Follow the events of a stream live over Server-Sent Events, replaying those after
+   * `options.after` first. Resolves when `options.signal` is aborted or the server closes
+   * the connection.
+   */
+  public async followEvents(
+    streamId: string,
+    onEvent: (event: StreamEvent) => void,
+    options: FollowOptions = {}
+  ): Promise<void> {
+    const q = options.after !== undefined ? `?after=${options.after}` : '';
+    return this.follow<StreamEvent>(
+      `/events/${encodeURIComponent(streamId)}/live${q}`,
+      onEvent,
+      options.signal
+    );
+  }
+
+  /**
+   * Follow the events of all the streams live over Server-Sent Events, optionally only those
+   * of `options.types`. Only the new events are sent unless `options.after` is set.
+   */
+  public async followAllEvents(
+    onEvent: (event: LiveEvent) => void,
+    options: FollowAllOptions = {}
+  ): Promise<void> {
+    const params = new URLSearchParams();
+    if (options.after !== undefined) params.set('after', String(options.after));
+    if (options.types?.length) params.set('types', options.types.join(','));
+    const q = params.toString() ? `?${params}` : '';
+    return this.follow<LiveEvent>(`/events/live${q}`, onEvent, options.signal);
+  }
+
+  /** Read a Server-Sent Events response, passing the JSON `data` of each event to `onEvent`. */
+  private async follow<T>(
+    path: string,
+    onEvent: (event: T) => void,
+    signal?: AbortSignal
+  ): Promise<void> {
+    const url = `${this.baseUrl}${path}`;
+    this.debug(`GET ${url} (live)`);
+
+    let res: Response;
+    try {
+      res = await fetch(url, {
+        headers: { ...this.headers(false), Accept: 'text/event-stream' },
+        signal,
+      });
+    } catch (err) {
+      if (signal?.aborted) return;
+      throw new PortalSDKError(
+        `Network error: ${err instanceof Error ? err.message : String(err)}`,
+        'NETWORK_ERROR',
+        err
+      );
+    }
+    if (!res.ok || !res.body) {
+      const text = await res.text().catch(() => '');
+      throw new PortalSDKError(`HTTP ${res.status}: ${text}`, 'HTTP_ERROR', undefined, res.status);
+    }
+
+    const reader = res.body.getReader();
+    const decoder = new TextDecoder();
+    let buffer = '';
+    try {
+      for (;;) {
+        const { done, value } = await reader.read();
+        if (done) return;
+        buffer += decoder.decode(value, { stream: true }).replace(/\r\n?/g, '\n');
+
+        // Events are separated by a blank line, keep-alive comments start with ':'
+        let end: number;
+        while ((end = buffer.indexOf('\n\n')) !== -1) {
+          const block = buffer.slice(0, end);
+          buffer = buffer.slice(end + 2);
+          const data = block
+            .split('\n')
+            .filter((line) => line.startsWith('data:'))
+            .map((line) => line.slice(5).replace(/^ /, ''))
+            .join('\n');
+          if (data) onEvent(JSON.parse(data) as T);
+        }
+      }
+    } catch (err) {
+      if (signal?.aborted) return;
+      throw new PortalSDKError(
+        `Network error: ${err instanceof Error ? err.message : String(err)}`,
+        'NETWORK_ERROR',
+        err
+      );
+    }
+  }
+
 }
@@ -38,6 +38,11 @@ export {
   CloseRecurringPaymentRequest,
   InvoiceStatus,
 
+  // Relay delivery
+  StreamResponse,
+  SendOutcome,
+  EventSendResult,
+
   // Profile
   Profile,
 
@@ -50,9 +55,32 @@ export {
   // JWT
   IssueJwtRequest,
   IssueJwtResponse,
+  JwtClaimsOptions,
+  JwtVerifyOptions,
   VerifyJwtRequest,
   VerifyJwtResponse,
 
+  // Sessions
+  SessionResponse,
+  RevokeSessionRequest,
+  RevokeSessionResponse,
+
+  // Certificates
+  Address,
+  PersonCertificateData,
+  BusinessCertificateData,
+  CustomCertificateData,
+  CertificateData,
+  VerificationLevel,
+  VerificationMethod,
+  Predicate,
+  IssueCertificateRequest,
+  IssueCertificateResponse,
+  RequestCertificatesRequest,
+  CertificateResponseStatus,
+  RevealedCertificate,
+  CertificateResponse,
+
   // Cashu
   RequestCashuRequest,
   SendCashuDirectRequest,
@@ -70,14 +98,55 @@ export {
   // Relays
   RelayRequest,
 
+  // Outbox
+  OutboxEvent,
+  OutboxResponse,
+  OutboxRetryResponse,
+
+  // Subscriptions
+  Subscription,
+  SubscriptionStatus,
+  SubscriptionsResponse,
+
+  // Webhook deliveries
+  WebhookDelivery,
+  WebhookDeliveryAttempt,
+  WebhookDeliveryStatus,
+  WebhookDeliveriesResponse,
+  WebhookReplayResponse,
+
   // Calendar
   CalculateNextOccurrenceRequest,
 
   // NIP-05
   Nip05Profile,
 
+  // API keys
+  ApiKeyScope,
+  ApiKey,
+  CreateApiKeyRequest,
+  CreatedApiKeyResponse,
+
   // Wallet
   WalletInfoResponse,
+  WalletCapabilitiesResponse,
+  WalletPaymentDirection,
+  WalletPaymentState,
+  WalletPayment,
+  WalletPaymentsResponse,
+  ListWalletPaymentsOptions,
+  CreateWalletInvoiceRequest,
+  WalletInvoiceResponse,
+  MockInvoiceStatus,
+  MockInvoiceResponse,
+
+  // Payment ledger
+  LedgerKind,
+  LedgerStatus,
+  LedgerEntry,
+  LedgerResponse,
+  ListLedgerOptions,
+  ExportLedgerOptions,
 
   // Version / Info
   VersionResponse,
@@ -87,6 +156,9 @@ export {
   // Events / Polling
   StreamEvent,
   EventsResponse,
+  LiveEvent,
+  FollowOptions,
+  FollowAllOptions,
   NotificationData,
   CloseRecurringPaymentNotification,
 
@@ -51,6 +51,11 @@ export interface ClientConfig {
   baseUrl: string;
   /** Bearer token for authentication. */
   authToken?: string;
+  /**
+   * Identity of the daemon to use, among those configured under `[identities.<name>]`.
+   * Sent in the X-Portal-Identity header. The main identity is used if unset.
+   */
+  identity?: string;
   /**
    * Shared secret for verifying X-Portal-Signature HMAC-SHA256 webhook signatures.
    * Required when using `webhookHandler()`.
@@ -95,6 +100,12 @@ export interface PollOptions {
 export interface AsyncOperation<T = StreamEvent> {
   /** The stream ID for this operation (available immediately after the HTTP call). */
   streamId: string;
+  /**
+   * How the relays took each event of the request. Queued events are retried by the
+   * server; a `relay_delivery` event is pushed on the stream when they reach a relay
+   * or are dropped. Not set for key handshakes, which don't send anything.
+   */
+  delivery?: EventSendResult[];
   /**
    * Resolves with the terminal event when the webhook fires.
    * If webhooks are not configured, use `client.poll(streamId)` instead.
@@ -124,6 +135,8 @@ export interface KeyHandshakeResult {
 export interface AuthenticateKeyRequest {
   main_key: string;
   subkeys: string[];
+  /** e.g. `payments:single<=1000 sats`, `payments:recurring`, `profile:read`, `cashu:request` */
+  permissions?: string[];
 }
 
 export interface AuthResponseStatus {
@@ -165,6 +178,25 @@ export interface SinglePaymentRequestContent {
 
 export interface SinglePaymentResponse {
   stream_id: string;
+  delivery: EventSendResult[];
+}
+
+/** Response of the endpoints that start an async request to a user. */
+export interface StreamResponse {
+  stream_id: string;
+  delivery: EventSendResult[];
+}
+
+// ---- Relay delivery ----
+
+export type SendOutcome =
+  | { status: 'delivered'; relays: string[] }
+  | { status: 'queued' }
+  | { status: 'dropped' };
+
+export interface EventSendResult {
+  event_id: string;
+  outcome: SendOutcome;
 }
 
 export interface RecurringPaymentRequestContent {
@@ -267,7 +299,24 @@ export interface PayInvoiceResponse {
 
 // ---- JWT ----
 
-export interface IssueJwtRequest {
+/** Optional claims of an issued JWT. */
+export interface JwtClaimsOptions {
+  audience?: string;
+  issuer?: string;
+  scopes?: string[];
+  /** Session ID, revoke it with `/sessions/revoke` to reject the token */
+  jti?: string;
+}
+
+/** Checks applied when verifying a JWT, on top of its signature and expiration. */
+export interface JwtVerifyOptions {
+  audience?: string;
+  issuer?: string;
+  /** Scopes the token must all have */
+  required_scopes?: string[];
+}
+
+export interface IssueJwtRequest extends JwtClaimsOptions {
   target_key: string;
   duration_hours: number;
 }
@@ -276,13 +325,156 @@ export interface IssueJwtResponse {
   token: string;
 }
 
-export interface VerifyJwtRequest {
+export interface VerifyJwtRequest extends JwtVerifyOptions {
   pubkey: string;
   token: string;
 }
 
 export interface VerifyJwtResponse {
   target_key: string;
+  audience: string | null;
+  issuer: string | null;
+  scopes: string[];
+  jti: string | null;
+  /** Main key of the issuer, when the token is signed with one of its subkeys */
+  main_key: string | null;
+}
+
+// ---- Sessions ----
+
+/** A user session, opened when the user approves an auth challenge. */
+export interface SessionResponse {
+  token: string;
+  /** Random ID, kept when the session is refreshed */
+  session_id: string;
+  service_key: string;
+  user_key: string;
+  granted_permissions: string[];
+  issued_at: number;
+  expires_at: number;
+}
+
+/** Either the token or the ID of the session to revoke. */
+export interface RevokeSessionRequest {
+  token?: string;
+  session_id?: string;
+}
+
+export interface RevokeSessionResponse {
+  session_id: string;
+}
+
+// ---- Certificates ----
+
+export interface Address {
+  street: string;
+  city: string;
+  state?: string | null;
+  postal_code: string;
+  country: string;
+}
+
+export interface PersonCertificateData {
+  type: 'personal';
+  full_name: string;
+  /** YYYY-MM-DD */
+  date_of_birth: string;
+  /** ISO 3166-1 alpha-2 country code */
+  nationality: string;
+  document_type: string;
+  document_number: string;
+  place_of_birth?: string | null;
+  gender?: string | null;
+  issue_date?: string | null;
+  expiry_date?: string | null;
+  address?: Address | null;
+}
+
+export interface BusinessCertificateData {
+  type: 'business';
+  legal_name: string;
+  trading_name?: string | null;
+  registration_number: string;
+  tax_id?: string | null;
+  jurisdiction: string;
+  /** YYYY-MM-DD */
+  incorporation_date: string;
+  business_type: string;
+  address: Address;
+  contact: { email?: string | null; phone?: string | null };
+  website?: string | null;
+}
+
+export type CustomCertificateData = { type: 'custom' } & Record<string, unknown>;
+
+export type CertificateData =
+  | PersonCertificateData
+  | BusinessCertificateData
+  | CustomCertificateData;
+
+export type VerificationLevel = 'high' | 'medium' | 'low';
+
+export type VerificationMethod =
+  | 'in_person'
+  | 'video_call'
+  | 'document_upload'
+  | 'registry_check'
+  | 'third_party_verification'
+  | { custom: string };
+
+export type Predicate =
+  | { type: 'age_over'; age: number }
+  | { type: 'nationality_in'; region: string; countries: string[] }
+  | { type: 'document_valid' };
+
+export interface IssueCertificateRequest {
+  subject_key: string;
+  subkeys: string[];
+  data: CertificateData;
+  verification_level: VerificationLevel;
+  verification_method: VerificationMethod;
+  expires_at: Timestamp;
+  /** Claims revealable as `predicates.<claim>`, e.g. `predicates.age_over_18` */
+  predicates?: Predicate[];
+  /** Send the certificate to the subject over Nostr. Defaults to true. */
+  deliver?: boolean;
+}
+
+export interface IssueCertificateResponse {
+  certificate_id: string;
+  certificate: Record<string, unknown>;
+  delivered: boolean;
+}
+
+export interface RequestCertificatesRequest {
+  main_key: string;
+  subkeys: string[];
+  requested_types: string[];
+  /** e.g. `nationality` or `predicates.age_over_18` */
+  requested_fields: string[];
+  purpose: string;
+  require_status_proofs?: boolean;
+  /** Issuers to accept certificates from. Without it, check the `issuer` of each certificate. */
+  trusted_issuers?: string[];
+}
+
+export type CertificateResponseStatus =
+  | { status: 'approved' }
+  | { status: 'declined'; reason?: string | null };
+
+export interface RevealedCertificate {
+  issuer: string;
+  subject: string;
+  issued_at: Timestamp;
+  expires_at: Timestamp;
+  fields: Record<string, unknown>;
+  /** `unknown` if the user sent no status proof for this certificate */
+  revocation_status: 'valid' | 'revoked' | 'unknown';
+}
+
+export interface CertificateResponse {
+  status: CertificateResponseStatus;
+  certificates: Record<string, RevealedCertificate>;
 }
 
 // ---- Cashu ----
@@ -328,6 +520,104 @@ export interface RelayRequest {
   relay: string;
 }
 
+// ---- Outbox ----
+
+/** An event that some relays didn't accept yet, waiting for retry. */
+export interface OutboxEvent {
+  event_id: string;
+  kind: number;
+  pending_relays: string[];
+  delivered_relays: string[];
+  attempts: number;
+  created_at: number;
+  last_attempt_at: number;
+  next_attempt_at: number;
+  last_error?: string | null;
+}
+
+export interface OutboxResponse {
+  events: OutboxEvent[];
+}
+
+export interface OutboxRetryResponse {
+  /** Events delivered to all their relays by the retry */
+  delivered: number;
+  /** Events still waiting in the outbox */
+  remaining: number;
+}
+
+// ---- Subscriptions ----
+
+export type SubscriptionStatus = 'active' | 'past_due' | 'completed' | 'cancelled';
+
+/** A recurring payment confirmed by the user, charged by the server when billing is enabled. */
+export interface Subscription {
+  subscription_id: string;
+  /** Stream of the recurring payment request, lifecycle events are pushed to it */
+  stream_id: string;
+  main_key: string;
+  subkeys: string[];
+  amount: number;
+  currency: PaymentCurrency;
+  recurrence: RecurrenceInfo;
+  description?: string | null;
+  auth_token?: string | null;
+  status: SubscriptionStatus;
+  payments_made: number;
+  /** Unix timestamp the payment being collected is due at */
+  next_payment_at: number;
+  /** Unix timestamp of the next charge attempt */
+  next_attempt_at: number;
+  /** Failed attempts at collecting the current payment */
+  failed_attempts: number;
+  pending_charge?: { request_id: string; invoice: string; expires_at: number } | null;
+  last_error?: string | null;
+  created_at: number;
+  updated_at: number;
+}
+
+export interface SubscriptionsResponse {
+  subscriptions: Subscription[];
+}
+
+// ---- Webhook deliveries ----
+
+export type WebhookDeliveryStatus = 'pending' | 'delivered' | 'dead_letter';
+
+/** One POST to the webhook URL. */
+export interface WebhookDeliveryAttempt {
+  attempted_at: number;
+  /** HTTP status returned by the webhook endpoint, if it answered */
+  status_code?: number | null;
+  error?: string | null;
+  duration_ms: number;
+}
+
+/** Webhook delivery of a stream event, retried with backoff until delivered or dead-lettered. */
+export interface WebhookDelivery {
+  stream_id: string;
+  index: number;
+  status: WebhookDeliveryStatus;
+  /** Attempts since the event was pushed or last replayed */
+  attempts: number;
+  next_attempt_at?: number | null;
+  last_error?: string | null;
+  created_at: number;
+  updated_at: number;
+  /** Every attempt made, oldest first. Only returned for a single delivery. */
+  attempt_log?: WebhookDeliveryAttempt[];
+}
+
+export interface WebhookDeliveriesResponse {
+  deliveries: WebhookDelivery[];
+}
+
+export interface WebhookReplayResponse {
+  stream_id: string;
+  /** Indexes of the events scheduled for delivery again */
+  replayed: number[];
+}
+
 // ---- Calendar ----
 
 export interface CalculateNextOccurrenceRequest {
@@ -342,6 +632,37 @@ export interface Nip05Profile {
   relays?: string[];
 }
 
+// ---- API keys ----
+
+export type ApiKeyScope = 'auth' | 'payments:request' | 'wallet:spend' | 'cashu' | 'relays' | 'admin';
+
+/** An API key, without the key itself. Timestamps are Unix timestamps in seconds. */
+export interface ApiKey {
+  id: string;
+  name: string;
+  /** First characters of the key, to recognize it */
+  prefix: string;
+  scopes: ApiKeyScope[];
+  created_at: number;
+  expires_at: number | null;
+  revoked_at: number | null;
+  /** Updated at most once a minute */
+  last_used_at: number | null;
+}
+
+export interface CreateApiKeyRequest {
+  name: string;
+  scopes: ApiKeyScope[];
+  /** Unix timestamp after which the key is rejected, never if not set */
+  expires_at?: number;
+}
+
+export interface CreatedApiKeyResponse {
+  /** The key to use as `authToken`, it can't be shown again */
+  key: string;
+  api_key: ApiKey;
+}
+
 // ---- Wallet ----
 
 export interface WalletInfoResponse {
@@ -349,6 +670,162 @@ export interface WalletInfoResponse {
   balance_msat: number;
 }
 
+/** What the configured wallet supports, see `getWalletCapabilities()`. */
+export interface WalletCapabilitiesResponse {
+  wallet_type: string;
+  list_payments: boolean;
+  lookup_invoice: boolean;
+  invoice_expiry: boolean;
+  description_hash: boolean;
+  /** Whether invoice settlements are pushed by the wallet instead of polled */
+  push_settlements: boolean;
+}
+
+export type WalletPaymentDirection = 'incoming' | 'outgoing';
+
+export type WalletPaymentState = 'pending' | 'settled' | 'expired' | 'failed';
+
+/** A payment of the wallet. Timestamps are Unix timestamps in seconds. */
+export interface WalletPayment {
+  direction: WalletPaymentDirection;
+  state: WalletPaymentState;
+  payment_hash: string;
+  invoice: string | null;
+  /** Only for settled payments */
+  preimage: string | null;
+  amount_msat: number;
+  fees_paid_msat: number;
+  description: string | null;
+  description_hash: string | null;
+  created_at: number;
+  expires_at: number | null;
+  settled_at: number | null;
+}
+
+export interface WalletPaymentsResponse {
+  payments: WalletPayment[];
+  offset: number;
+  limit: number;
+}
+
+export interface ListWalletPaymentsOptions {
+  direction?: WalletPaymentDirection;
+  /** Only the payments created at or after this Unix timestamp */
+  from?: number;
+  /** Only the payments created at or before this Unix timestamp */
+  until?: number;
+  /** At most 10000 */
+  offset?: number;
+  /** 50 by default, at most 500 */
+  limit?: number;
+}
+
+// ---- Payment ledger ----
+
+export type LedgerKind =
+  | 'single_payment'
+  | 'raw_payment'
+  | 'subscription_charge'
+  | 'invoice_payment'
+  | 'cashu_mint'
+  | 'cashu_burn';
+
+/**
+ * `claimed` when the user reports the payment as sent without a preimage matching the invoice.
+ * A final status is only replaced by `paid`, for an invoice paid late.
+ */
+export type LedgerStatus =
+  | 'pending'
+  | 'approved'
+  | 'claimed'
+  | 'paid'
+  | 'rejected'
+  | 'failed'
+  | 'expired';
+
+/** A payment recorded in the ledger. Timestamps are Unix timestamps in seconds. */
+export interface LedgerEntry {
+  /** Request ID for the subscription charges, random otherwise */
+  id: string;
+  kind: LedgerKind;
+  direction: 'incoming' | 'outgoing';
+  status: LedgerStatus;
+  stream_id: string | null;
+  /** User asked to pay */
+  main_key: string | null;
+  subscription_id: string | null;
+  description: string | null;
+  /** Amount as requested, in `currency` (millisats, fiat cents or Cashu unit) */
+  amount: number;
+  /** `Millisats`, a fiat currency code or the unit of a Cashu mint */
+  currency: string;
+  /** Rate the fiat amount was converted with, `time` is a Unix timestamp as a string */
+  exchange_rate: { rate: number; source: string; time: string } | null;
+  amount_msat: number | null;
+  /** Routing fees of `/invoices/pay` */
+  fees_msat: number | null;
+  invoice: string | null;
+  payment_hash: string | null;
+  preimage: string | null;
+  mint_url: string | null;
+  error: string | null;
+  created_at: number;
+  updated_at: number;
+  settled_at: number | null;
+}
+
+export interface LedgerResponse {
+  entries: LedgerEntry[];
+  offset: number;
+  limit: number;
+}
+
+export interface ListLedgerOptions {
+  /** Only the payments requested from this user */
+  main_key?: string;
+  subscription_id?: string;
+  kind?: LedgerKind;
+  status?: LedgerStatus;
+  /** Only the payments created at or after this Unix timestamp */
+  from?: number;
+  /** Only the payments created at or before this Unix timestamp */
+  until?: number;
+  offset?: number;
+  /** 50 by default, at most 500. All the entries are exported if not set */
+  limit?: number;
+}
+
+export interface ExportLedgerOptions extends ListLedgerOptions {
+  /** `csv` by default */
+  format?: 'csv' | 'json';
+}
+
+export interface CreateWalletInvoiceRequest {
+  amount_msat: number;
+  description?: string;
+  /** Hex encoded SHA-256 of the description, committed to instead of `description` */
+  description_hash?: string;
+  /** How long the invoice can be paid for, the wallet's default if not set */
+  expiry_secs?: number;
+}
+
+export interface WalletInvoiceResponse {
+  invoice: string;
+  payment_hash: string;
+  expires_at: number | null;
+}
+
+export type MockInvoiceStatus = 'pending' | 'paid' | 'expired' | 'failed';
+
+/** State of an invoice of the mock wallet, after a test command. */
+export interface MockInvoiceResponse {
+  payment_hash: string;
+  status: MockInvoiceStatus;
+  reason?: string;
+  amount_msat: number;
+  balance_msat: number;
+}
+
 // ---- Version ----
 
 export interface VersionResponse {
@@ -358,6 +835,10 @@ export interface VersionResponse {
 
 export interface InfoResponse {
   public_key: string;
+  /** Name of the identity, null for the main one. */
+  identity: string | null;
+  version: string;
+  git_commit: string;
 }
 
 export interface Nip05WellKnownResponse {
@@ -383,6 +864,25 @@ export interface EventsResponse {
   events: StreamEvent[];
 }
 
+/** An event of any stream, as sent by `GET /events/live`. */
+export interface LiveEvent extends StreamEvent {
+  /** Increases across all the streams, pass it as `after` to resume. */
+  seq: number;
+  stream_id: string;
+}
+
+export interface FollowOptions {
+  /** Only send the events after this index (`followEvents`) or `seq` (`followAllEvents`). */
+  after?: number;
+  /** Stop following the events when aborted. */
+  signal?: AbortSignal;
+}
+
+export interface FollowAllOptions extends FollowOptions {
+  /** Only send the events of these types, e.g. `payment_status_update`. */
+  types?: string[];
+}
+
 // ---- Notification data (event variants) ----
 
 export type NotificationData =
@@ -393,6 +893,13 @@ export type NotificationData =
   | { type: 'recurring_payment_response'; status: RecurringPaymentResponseContent }
   | { type: 'invoice_response'; invoice: string; payment_hash: string }
   | { type: 'cashu_response'; status: CashuResponseStatus }
+  | { type: 'certificate_response'; status: CertificateResponseStatus; certificates: Record<string, RevealedCertificate> }
+  | { type: 'relay_delivery'; event_id: string; outcome: SendOutcome }
+  | { type: 'subscription_charged'; subscription_id: string; payment_number: number; amount: number; currency: PaymentCurrency; due_at: number; preimage: string | null; next_payment_at: number | null }
+  | { type: 'subscription_payment_failed'; subscription_id: string; reason: string; attempt: number; next_retry_at: number | null }
+  | { type: 'subscription_past_due'; subscription_id: string; missed_payment_at: number; next_payment_at: number | null }
+  | { type: 'subscription_completed'; subscription_id: string; payments_made: number }
+  | { type: 'subscription_cancelled'; subscription_id: string; reason: string | null; by_service: boolean }
   | { type: 'error'; reason: string };
 
 export type CloseRecurringPaymentNotification = {
@@ -0,0 +1,281 @@
+use std::str::FromStr;
+
+use rand::RngCore;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::info;
+use uuid::Uuid;
+
+/// Prefix of the API keys, which tells them apart from the `[auth] auth_token`.
+const KEY_PREFIX: &str = "prk_";
+/// Characters of a key kept in clear, so that it can be recognized in the list.
+const VISIBLE_PREFIX_LEN: usize = 12;
+/// `last_used_at` is updated at most this often, in seconds, to avoid a write per request.
+const LAST_USED_RESOLUTION_SECS: i64 = 60;
+
+/// Route group an API key is allowed to call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    /// Key handshakes, authentication, sessions, JWTs, certificates, profiles and NIP-05.
+    #[serde(rename = "auth")]
+    Auth,
+    /// Payment, subscription and invoice requests sent to users, invoices of the wallet and
+    /// its history.
+    #[serde(rename = "payments:request")]
+    PaymentsRequest,
+    /// Paying invoices with the wallet of the daemon.
+    #[serde(rename = "wallet:spend")]
+    WalletSpend,
+    /// Cashu requests, direct sends, minting and burning.
+    #[serde(rename = "cashu")]
+    Cashu,
+    /// Adding and removing relays.
+    #[serde(rename = "relays")]
+    Relays,
+    /// Everything, including the API keys, the outbox, the webhook deliveries, the events of
+    /// all the streams, the payment ledger and the mock wallet.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Auth => "auth",
+            Self::PaymentsRequest => "payments:request",
+            Self::WalletSpend => "wallet:spend",
+            Self::Cashu => "cashu",
+            Self::Relays => "relays",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auth" => Ok(Self::Auth),
+            "payments:request" => Ok(Self::PaymentsRequest),
+            "wallet:spend" => Ok(Self::WalletSpend),
+            "cashu" => Ok(Self::Cashu),
+            "relays" => Ok(Self::Relays),
+            "admin" => Ok(Self::Admin),
+            _ => Err(format!("Invalid scope: {s}")),
+        }
+    }
+}
+
+/// An API key, without the key itself: only its hash is stored.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// First characters of the key, to recognize it.
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+}
+
+impl ApiKey {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        let scopes: String = row.get(3)?;
+        Ok(Self {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            prefix: row.get(2)?,
+            // Only valid scopes are saved
+            scopes: serde_json::from_str(&scopes).unwrap_or_default(),
+            created_at: row.get(4)?,
+            expires_at: row.get(5)?,
+            revoked_at: row.get(6)?,
+            last_used_at: row.get(7)?,
+        })
+    }
+}
+
+const API_KEY_COLUMNS: &str =
+    "id, name, prefix, scopes, created_at, expires_at, revoked_at, last_used_at";
+
+/// Who made an API request, set by the auth middleware.
+#[derive(Debug, Clone)]
+pub enum Caller {
+    /// The `[auth] auth_token`, allowed everything.
+    AuthToken,
+    ApiKey(ApiKey),
+}
+
+impl Caller {
+    /// Whether the caller may call the routes of `scope`. `admin` keys may call all of them.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        match self {
+            Self::AuthToken => true,
+            Self::ApiKey(key) => key.scopes.contains(&Scope::Admin) || key.scopes.contains(&scope),
+        }
+    }
+
+    /// ID of the API key, recorded on the streams it creates.
+    pub fn key_id(&self) -> Option<&str> {
+        match self {
+            Self::AuthToken => None,
+            Self::ApiKey(key) => Some(&key.id),
+        }
+    }
+
+    /// Whether the caller may read the events of a stream created by `created_by`: the
+    /// streams it created, or all of them with the `admin` scope.
+    pub fn can_read_stream(&self, created_by: Option<&str>) -> bool {
+        self.has_scope(Scope::Admin) || (self.key_id().is_some() && self.key_id() == created_by)
+    }
+}
+
+/// SQLite-backed API keys. Only the SHA-256 hash of a key is stored: the key itself is shown
+/// once, when it is created.
+///
+/// Keys are 256-bit random values, so an unsalted hash is enough to protect them.
+pub struct SqliteApiKeyStore {
+    db: Mutex<Connection>,
+}
+
+impl SqliteApiKeyStore {
+    /// Open (or create) the SQLite database at `db_path` and initialize the schema.
+    pub fn new(db_path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open(db_path)?;
+
+        conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS api_keys (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                key_hash TEXT NOT NULL UNIQUE,
+                prefix TEXT NOT NULL,
+                scopes TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER,
+                revoked_at INTEGER,
+                last_used_at INTEGER
+            );",
+        )?;
+
+        info!("API key store opened at {db_path}");
+
+        Ok(Self {
+            db: Mutex::new(conn),
+        })
+    }
+
+    /// Create a key, returning it together with its record.
+    pub async fn create(
+        &self,
+        name: &str,
+        scopes: &[Scope],
+        expires_at: Option<i64>,
+    ) -> anyhow::Result<(String, ApiKey)> {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let key = format!("{KEY_PREFIX}{}", hex::encode(secret));
+
+        let api_key = ApiKey {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            prefix: key[..VISIBLE_PREFIX_LEN].to_string(),
+            scopes: scopes.to_vec(),
+            created_at: chrono::Utc::now().timestamp(),
+            expires_at,
+            revoked_at: None,
+            last_used_at: None,
+        };
+
+        let db = self.db.lock().await;
+        db.execute(
+            "INSERT INTO api_keys (id, name, key_hash, prefix, scopes, created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![
+                api_key.id,
+                api_key.name,
+                hash_key(&key),
+                api_key.prefix,
+                serde_json::to_string(&api_key.scopes)?,
+                api_key.created_at,
+                api_key.expires_at,
+            ],
+        )?;
+
+        Ok((key, api_key))
+    }
+
+    /// All the keys, revoked and expired ones included, newest first.
+    pub async fn list(&self) -> anyhow::Result<Vec<ApiKey>> {
+        let db = self.db.lock().await;
+        let mut stmt = db.prepare(&format!(
+            "SELECT {API_KEY_COLUMNS} FROM api_keys ORDER BY created_at DESC, rowid DESC"
+        ))?;
+        let rows = stmt.query_map([], ApiKey::from_row)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// A key by ID.
+    pub async fn get(&self, id: &str) -> anyhow::Result<Option<ApiKey>> {
+        let db = self.db.lock().await;
+        let key = db
+            .query_row(
+                &format!("SELECT {API_KEY_COLUMNS} FROM api_keys WHERE id = ?1"),
+                rusqlite::params![id],
+                ApiKey::from_row,
+            )
+            .optional()?;
+        Ok(key)
+    }
+
+    /// Revoke a key. Returns `false` if there is no such key.
+    pub async fn revoke(&self, id: &str) -> anyhow::Result<bool> {
+        let db = self.db.lock().await;
+        let updated = db.execute(
+            "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, ?1) WHERE id = ?2",
+            rusqlite::params![chrono::Utc::now().timestamp(), id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// The key matching `key`, if it is neither revoked nor expired.
+    pub async fn authenticate(&self, key: &str) -> anyhow::Result<Option<ApiKey>> {
+        if !key.starts_with(KEY_PREFIX) {
+            return Ok(None);
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let db = self.db.lock().await;
+        let api_key = db
+            .query_row(
+                &format!(
+                    "SELECT {API_KEY_COLUMNS} FROM api_keys
+                     WHERE key_hash = ?1
+                       AND revoked_at IS NULL
+                       AND (expires_at IS NULL OR expires_at > ?2)"
+                ),
+                rusqlite::params![hash_key(key), now],
+                ApiKey::from_row,
+            )
+            .optional()?;
+
+        if let Some(api_key) = &api_key {
+            db.execute(
+                "UPDATE api_keys SET last_used_at = ?1
+                 WHERE id = ?2 AND (last_used_at IS NULL OR last_used_at <= ?3)",
+                rusqlite::params![now, api_key.id, now - LAST_USED_RESOLUTION_SECS],
+            )?;
+        }
+        Ok(api_key)
+    }
+}
+
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
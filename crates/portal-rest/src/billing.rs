@@ -0,0 +1,1039 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use portal::protocol::model::payment::{
+    Amount, Currency, PaymentResponseContent, PaymentStatus, RecurrenceInfo,
+    RecurringPaymentResponseContent, RecurringPaymentStatus, SinglePaymentRequestContent,
+};
+use portal::protocol::model::Timestamp;
+use portal::router::NotificationStream;
+use portal_wallet::PortalWallet;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::config::BillingSettings;
+use crate::events::EventStore;
+use crate::handlers::{parse_subkeys, record_in_ledger, resolve_amount_and_exchange_rate};
+use crate::ledger::{LedgerEntry, LedgerKind, LedgerStatus, LedgerUpdate, PaymentLedger};
+use crate::response::{InvoiceStatus, NotificationData};
+use crate::settlements::SettlementWatcher;
+use crate::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    Active,
+    /// The last payment could not be collected, charging goes on at the next due date
+    PastDue,
+    /// No payment left, because of `max_payments` or `until`
+    Completed,
+    Cancelled,
+}
+
+impl SubscriptionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Active => "active",
+            Self::PastDue => "past_due",
+            Self::Completed => "completed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn is_final(&self) -> bool {
+        matches!(self, Self::Completed | Self::Cancelled)
+    }
+}
+
+/// What the service sent along with a recurring payment request, kept in the stream
+/// metadata until the user replies.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionRequest {
+    pub main_key: String,
+    pub subkeys: Vec<String>,
+    pub description: Option<String>,
+    pub auth_token: Option<String>,
+}
+
+/// A charge sent to the user and waiting for its invoice to be paid.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingCharge {
+    pub request_id: String,
+    pub invoice: String,
+    /// Unix timestamp after which the charge is considered failed
+    pub expires_at: u64,
+}
+
+/// A recurring payment confirmed by the user, charged by the daemon at each due date.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub subscription_id: String,
+    /// Stream of the recurring payment request, the lifecycle events are pushed to it
+    pub stream_id: String,
+    pub main_key: String,
+    pub subkeys: Vec<String>,
+    /// Amount, currency and recurrence authorized by the user
+    pub amount: Amount,
+    pub currency: Currency,
+    pub recurrence: RecurrenceInfo,
+    pub description: Option<String>,
+    pub auth_token: Option<String>,
+    pub status: SubscriptionStatus,
+    pub payments_made: u32,
+    /// Unix timestamp the payment being collected was due at
+    pub next_payment_at: u64,
+    /// Unix timestamp of the next charge attempt
+    pub next_attempt_at: u64,
+    /// Failed attempts at collecting the current payment
+    pub failed_attempts: u32,
+    pub pending_charge: Option<PendingCharge>,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Subscription {
+    fn is_due(&self, now: u64) -> bool {
+        !self.status.is_final() && self.pending_charge.is_none() && self.next_attempt_at <= now
+    }
+
+    /// Due date of the payment after the current one, `None` if the subscription ends first
+    ///
+    /// Due dates missed while the daemon was not running (before `now`) are skipped, so that
+    /// a late start charges the user once instead of once per missed date.
+    fn following_payment(&self, now: u64) -> Option<u64> {
+        if self
+            .recurrence
+            .max_payments
+            .is_some_and(|max| self.payments_made >= max)
+        {
+            return None;
+        }
+
+        let from = (self.next_payment_at + 1).max(now);
+        let next = self
+            .recurrence
+            .calendar
+            .get_calendar()
+            .next_occurrence(Timestamp::new(from))?;
+        match self.recurrence.until {
+            Some(until) if next > until => None,
+            _ => Some(next.as_u64()),
+        }
+    }
+
+    /// Moves on to the next payment, completing the subscription if there is none.
+    fn advance(&mut self, now: u64) -> Option<u64> {
+        self.failed_attempts = 0;
+        let next = self.following_payment(now);
+        match next {
+            Some(next) => {
+                self.next_payment_at = next;
+                self.next_attempt_at = next;
+            }
+            None if !self.status.is_final() => self.status = SubscriptionStatus::Completed,
+            None => {}
+        }
+        next
+    }
+}
+
+/// Subscription billing engine: confirmed recurring payments are saved in SQLite and charged
+/// by [`run_scheduler`] at each due date of their calendar.
+///
+/// Failed charges are retried after the configured dunning delays; when they are used up the
+/// payment is skipped and the subscription is past due. Lifecycle events are pushed to the
+/// stream of the recurring payment request, and so delivered to the webhook too.
+#[derive(Clone)]
+pub struct Billing {
+    db: Arc<Mutex<Connection>>,
+    settings: BillingSettings,
+    /// Subscriptions with a charge being sent or collected
+    charging: Arc<std::sync::Mutex<HashSet<String>>>,
+}
+
+impl Billing {
+    /// Open (or create) the SQLite database at `db_path` and initialize the schema.
+    pub fn new(db_path: &str, settings: BillingSettings) -> anyhow::Result<Self> {
+        let conn = Connection::open(db_path)?;
+
+        conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS subscriptions (
+                subscription_id TEXT PRIMARY KEY,
+                status TEXT NOT NULL,
+                record TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            );",
+        )?;
+
+        info!("Subscription billing enabled");
+
+        Ok(Self {
+            db: Arc::new(Mutex::new(conn)),
+            settings,
+            charging: Arc::new(std::sync::Mutex::new(HashSet::new())),
+        })
+    }
+
+    async fn save(&self, subscription: &mut Subscription) -> anyhow::Result<()> {
+        subscription.updated_at = Timestamp::now().as_u64();
+        let record = serde_json::to_string(subscription)?;
+        let db = self.db.lock().await;
+        db.execute(
+            "INSERT INTO subscriptions (subscription_id, status, record, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(subscription_id) DO UPDATE SET status = ?2, record = ?3, updated_at = ?5",
+            rusqlite::params![
+                subscription.subscription_id,
+                subscription.status.as_str(),
+                record,
+                subscription.created_at as i64,
+                subscription.updated_at as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub async fn get(&self, subscription_id: &str) -> anyhow::Result<Option<Subscription>> {
+        let db = self.db.lock().await;
+        let record = db
+            .query_row(
+                "SELECT record FROM subscriptions WHERE subscription_id = ?1",
+                rusqlite::params![subscription_id],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+
+        Ok(record
+            .map(|record| serde_json::from_str(&record))
+            .transpose()?)
+    }
+
+    /// All the subscriptions, newest first, optionally only those with the given status.
+    pub async fn list(
+        &self,
+        status: Option<SubscriptionStatus>,
+    ) -> anyhow::Result<Vec<Subscription>> {
+        let db = self.db.lock().await;
+        let mut stmt = db.prepare(
+            "SELECT record FROM subscriptions
+             WHERE ?1 IS NULL OR status = ?1
+             ORDER BY created_at DESC",
+        )?;
+        let rows = stmt.query_map(rusqlite::params![status.map(|s| s.as_str())], |row| {
+            row.get::<_, String>(0)
+        })?;
+
+        let mut subscriptions = Vec::new();
+        for row in rows {
+            subscriptions.push(serde_json::from_str(&row?)?);
+        }
+        Ok(subscriptions)
+    }
+
+    async fn open_subscriptions(&self) -> anyhow::Result<Vec<Subscription>> {
+        let mut subscriptions = self.list(Some(SubscriptionStatus::Active)).await?;
+        subscriptions.extend(self.list(Some(SubscriptionStatus::PastDue)).await?);
+        Ok(subscriptions)
+    }
+
+    /// Start billing the subscription confirmed in `response`, if any.
+    pub async fn register(
+        &self,
+        stream_id: &str,
+        request: SubscriptionRequest,
+        response: &RecurringPaymentResponseContent,
+    ) {
+        let RecurringPaymentStatus::Confirmed {
+            subscription_id,
+            authorized_amount,
+            authorized_currency,
+            authorized_recurrence,
+        } = &response.status
+        else {
+            return;
+        };
+
+        match self.get(subscription_id).await {
+            Ok(None) => {}
+            Ok(Some(_)) => {
+                warn!("Subscription {subscription_id} is already registered, ignoring it");
+                return;
+            }
+            Err(e) => {
+                error!("Failed to look up subscription {subscription_id}: {e}");
+                return;
+            }
+        }
+
+        let now = Timestamp::now().as_u64();
+        let first_payment_at = authorized_recurrence.first_payment_due.as_u64();
+        let mut subscription = Subscription {
+            subscription_id: subscription_id.clone(),
+            stream_id: stream_id.to_string(),
+            main_key: request.main_key,
+            subkeys: request.subkeys,
+            amount: *authorized_amount,
+            currency: authorized_currency.clone(),
+            recurrence: authorized_recurrence.clone(),
+            description: request.description,
+            auth_token: request.auth_token,
+            status: SubscriptionStatus::Active,
+            payments_made: 0,
+            next_payment_at: first_payment_at,
+            next_attempt_at: first_payment_at,
+            failed_attempts: 0,
+            pending_charge: None,
+            last_error: None,
+            created_at: now,
+            updated_at: now,
+        };
+
+        match self.save(&mut subscription).await {
+            Ok(()) => info!(
+                "Subscription {subscription_id} registered, first payment due at {first_payment_at}"
+            ),
+            Err(e) => error!("Failed to save subscription {subscription_id}: {e}"),
+        }
+    }
+
+    /// Stop billing a subscription closed by `main_key`, by the user or by us.
+    pub async fn cancel(
+        &self,
+        events: &EventStore,
+        subscription_id: &str,
+        main_key: &str,
+        reason: Option<String>,
+        by_service: bool,
+    ) {
+        let mut subscription = match self.get(subscription_id).await {
+            Ok(Some(subscription)) => subscription,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Failed to look up subscription {subscription_id}: {e}");
+                return;
+            }
+        };
+
+        if subscription.main_key != main_key {
+            warn!("Ignoring close of subscription {subscription_id} by {main_key}, not its subscriber");
+            return;
+        }
+        if subscription.status.is_final() {
+            return;
+        }
+
+        subscription.status = SubscriptionStatus::Cancelled;
+        if let Err(e) = self.save(&mut subscription).await {
+            error!("Failed to cancel subscription {subscription_id}: {e}");
+            return;
+        }
+        info!("Subscription {subscription_id} cancelled");
+
+        events
+            .push(
+                &subscription.stream_id,
+                NotificationData::SubscriptionCancelled {
+                    subscription_id: subscription_id.to_string(),
+                    reason,
+                    by_service,
+                },
+            )
+            .await;
+    }
+
+    /// Record the outcome of the pending charge of a subscription, in the subscription and
+    /// the ledger, and schedule the next one.
+    async fn settle(
+        &self,
+        events: &EventStore,
+        ledger: &PaymentLedger,
+        subscription_id: &str,
+        outcome: Result<Option<String>, String>,
+    ) {
+        // Reload it, the subscription may have been cancelled in the meantime
+        let mut subscription = match self.get(subscription_id).await {
+            Ok(Some(subscription)) => subscription,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Failed to look up subscription {subscription_id}: {e}");
+                return;
+            }
+        };
+        if let Some(charge) = subscription.pending_charge.take() {
+            let update = match &outcome {
+                Ok(preimage) => LedgerUpdate::paid(preimage.clone()),
+                Err(reason) => LedgerUpdate::error(LedgerStatus::Failed, Some(reason.clone())),
+            };
+            if let Err(e) = ledger.update(&charge.request_id, update).await {
+                error!(
+                    "Failed to update the ledger entry of charge {}: {e}",
+                    charge.request_id
+                );
+            }
+        }
+
+        let now = Timestamp::now().as_u64();
+        let cancelled = subscription.status.is_final();
+        let mut notifications = Vec::new();
+        match outcome {
+            Ok(preimage) => {
+                let due_at = subscription.next_payment_at;
+                subscription.payments_made += 1;
+                subscription.last_error = None;
+                if !cancelled {
+                    subscription.status = SubscriptionStatus::Active;
+                }
+                let next_payment_at = if cancelled {
+                    None
+                } else {
+                    subscription.advance(now)
+                };
+
+                info!(
+                    "Subscription {subscription_id} charged (payment {})",
+                    subscription.payments_made
+                );
+                notifications.push(NotificationData::SubscriptionCharged {
+                    subscription_id: subscription_id.to_string(),
+                    payment_number: subscription.payments_made,
+                    amount: subscription.amount.as_u64(),
+                    currency: subscription.currency.clone(),
+                    due_at,
+                    preimage,
+                    next_payment_at,
+                });
+            }
+            Err(reason) if cancelled => {
+                subscription.last_error = Some(reason);
+            }
+            Err(reason) => {
+                subscription.failed_attempts += 1;
+                subscription.last_error = Some(reason.clone());
+
+                let attempt = subscription.failed_attempts;
+                let next_retry_at = self
+                    .settings
+                    .retry_delays_secs
+                    .get(attempt as usize - 1)
+                    .map(|delay| now + delay);
+
+                warn!("Charge {attempt} of subscription {subscription_id} failed: {reason}");
+                notifications.push(NotificationData::SubscriptionPaymentFailed {
+                    subscription_id: subscription_id.to_string(),
+                    reason,
+                    attempt,
+                    next_retry_at,
+                });
+
+                match next_retry_at {
+                    Some(retry_at) => subscription.next_attempt_at = retry_at,
+                    None => {
+                        // Dunning is over, give up on this payment
+                        let missed_payment_at = subscription.next_payment_at;
+                        subscription.status = SubscriptionStatus::PastDue;
+                        let next_payment_at = subscription.advance(now);
+                        notifications.push(NotificationData::SubscriptionPastDue {
+                            subscription_id: subscription_id.to_string(),
+                            missed_payment_at,
+                            next_payment_at,
+                        });
+                    }
+                }
+            }
+        }
+
+        if !cancelled && subscription.status == SubscriptionStatus::Completed {
+            info!("Subscription {subscription_id} completed");
+            notifications.push(NotificationData::SubscriptionCompleted {
+                subscription_id: subscription_id.to_string(),
+                payments_made: subscription.payments_made,
+            });
+        }
+
+        if let Err(e) = self.save(&mut subscription).await {
+            error!("Failed to save subscription {subscription_id}: {e}");
+        }
+        for notification in notifications {
+            events.push(&subscription.stream_id, notification).await;
+        }
+    }
+
+    /// Mark a subscription as being charged, returns false if it already is.
+    fn start_charging(&self, subscription_id: &str) -> bool {
+        self.charging
+            .lock()
+            .unwrap()
+            .insert(subscription_id.to_string())
+    }
+
+    fn stop_charging(&self, subscription_id: &str) {
+        self.charging.lock().unwrap().remove(subscription_id);
+    }
+}
+
+/// Charge the due subscriptions every `check_interval_secs`, until the daemon stops.
+///
+/// Charges that were pending when the daemon stopped are collected first.
+pub async fn run_scheduler(state: AppState) {
+    let (Some(billing), Some(wallet), Some(settlements)) = (
+        state.billing.clone(),
+        state.wallet.clone(),
+        state.settlements.clone(),
+    ) else {
+        return;
+    };
+
+    match billing.open_subscriptions().await {
+        Ok(subscriptions) => {
+            for subscription in subscriptions {
+                let Some(charge) = subscription.pending_charge.clone() else {
+                    continue;
+                };
+                if !billing.start_charging(&subscription.subscription_id) {
+                    continue;
+                }
+                info!(
+                    "Recovering pending charge of subscription {}",
+                    subscription.subscription_id
+                );
+
+                let (state, billing, settlements) =
+                    (state.clone(), billing.clone(), settlements.clone());
+                tokio::spawn(async move {
+                    let outcome = wait_for_payment(&settlements, &charge, None).await;
+                    let subscription_id = subscription.subscription_id;
+                    billing
+                        .settle(&state.events, &state.ledger, &subscription_id, outcome)
+                        .await;
+                    billing.stop_charging(&subscription_id);
+                });
+            }
+        }
+        Err(e) => error!("Failed to load subscriptions: {e}"),
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        billing.settings.check_interval_secs.max(1),
+    ));
+    loop {
+        interval.tick().await;
+
+        let now = Timestamp::now().as_u64();
+        let due = match billing.open_subscriptions().await {
+            Ok(subscriptions) => subscriptions.into_iter().filter(|s| s.is_due(now)),
+            Err(e) => {
+                error!("Failed to load subscriptions: {e}");
+                continue;
+            }
+        };
+
+        for subscription in due {
+            if !billing.start_charging(&subscription.subscription_id) {
+                continue;
+            }
+            tokio::spawn(charge(
+                state.clone(),
+                billing.clone(),
+                wallet.clone(),
+                settlements.clone(),
+                subscription,
+            ));
+        }
+    }
+}
+
+async fn charge(
+    state: AppState,
+    billing: Billing,
+    wallet: Arc<dyn PortalWallet>,
+    settlements: SettlementWatcher,
+    mut subscription: Subscription,
+) {
+    let subscription_id = subscription.subscription_id.clone();
+    info!("Charging subscription {subscription_id}");
+
+    let outcome = match send_charge(&state, &billing, wallet.as_ref(), &mut subscription).await {
+        Ok((charge, notifications)) => {
+            wait_for_payment(&settlements, &charge, Some(notifications)).await
+        }
+        Err(reason) => Err(reason),
+    };
+
+    billing
+        .settle(&state.events, &state.ledger, &subscription_id, outcome)
+        .await;
+    billing.stop_charging(&subscription_id);
+}
+
+/// Send the user a payment request for the current payment of the subscription.
+async fn send_charge(
+    state: &AppState,
+    billing: &Billing,
+    wallet: &dyn PortalWallet,
+    subscription: &mut Subscription,
+) -> Result<(PendingCharge, NotificationStream<PaymentResponseContent>), String> {
+    let main_key = subscription
+        .main_key
+        .parse()
+        .map_err(|e| format!("Invalid main key: {e}"))?;
+    let subkeys =
+        parse_subkeys(&subscription.subkeys).map_err(|e| format!("Invalid subkeys: {e}"))?;
+
+    let (msat_amount, current_exchange_rate) = resolve_amount_and_exchange_rate(
+        subscription.amount,
+        &subscription.currency,
+        state.market_api.clone(),
+    )
+    .await
+    .map_err(|e| format!("Failed to fetch market data: {e}"))?;
+
+    let invoice = wallet
+        .make_invoice(msat_amount.as_millisats(), subscription.description.clone())
+        .await
+        .map_err(|e| format!("Failed to make invoice: {e}"))?;
+
+    let expires_at = Timestamp::now_plus_seconds(billing.settings.payment_timeout_secs);
+    let charge = PendingCharge {
+        request_id: Uuid::new_v4().to_string(),
+        invoice,
+        expires_at: expires_at.as_u64(),
+    };
+
+    // Saved before sending, so that a restart doesn't charge the user again. Checked first
+    // in case the subscription was cancelled while the invoice was made
+    let cancelled = billing
+        .get(&subscription.subscription_id)
+        .await
+        .map_err(|e| format!("Failed to load subscription: {e}"))?
+        .is_none_or(|current| current.status.is_final());
+    if cancelled {
+        return Err("Subscription cancelled".to_string());
+    }
+    subscription.pending_charge = Some(charge.clone());
+    billing
+        .save(subscription)
+        .await
+        .map_err(|e| format!("Failed to save subscription: {e}"))?;
+
+    let payment_request = SinglePaymentRequestContent {
+        amount: subscription.amount,
+        currency: subscription.currency.clone(),
+        current_exchange_rate,
+        invoice: charge.invoice.clone(),
+        auth_token: subscription.auth_token.clone(),
+        expires_at,
+        subscription_id: Some(subscription.subscription_id.clone()),
+        description: subscription.description.clone(),
+        request_id: charge.request_id.clone(),
+    };
+    // The ledger entry of a charge has the ID of its request
+    let mut entry = LedgerEntry::payment_request(
+        LedgerKind::SubscriptionCharge,
+        subscription.main_key.clone(),
+        &payment_request,
+    );
+    entry.id = charge.request_id.clone();
+
+    let notifications = state
+        .sdk
+        .request_single_payment(main_key, subkeys, payment_request)
+        .await
+        .map_err(|e| format!("Failed to request payment: {e}"))?;
+    record_in_ledger(state, &entry).await;
+
+    Ok((charge, notifications))
+}
+
+/// Wait until the invoice of a charge is paid, returning its preimage.
+///
+/// The invoice is watched by the shared [`SettlementWatcher`]. Fails when the charge
+/// expires, or earlier if the user rejects the payment or reports that it failed.
+async fn wait_for_payment(
+    settlements: &SettlementWatcher,
+    charge: &PendingCharge,
+    mut notifications: Option<NotificationStream<PaymentResponseContent>>,
+) -> Result<Option<String>, String> {
+    let mut status = settlements
+        .watch_charge(
+            charge.request_id.clone(),
+            charge.invoice.clone(),
+            Timestamp::new(charge.expires_at),
+        )
+        .await;
+    loop {
+        let response = tokio::select! {
+            status = &mut status => return match status {
+                Ok(InvoiceStatus::Paid { preimage }) => Ok(preimage),
+                Ok(InvoiceStatus::Error { reason }) => Err(reason),
+                Ok(_) => Err("Payment not received in time".to_string()),
+                Err(_) => Err("The settlement watcher stopped".to_string()),
+            },
+            response = next_response(&mut notifications) => response,
+        };
+
+        let reason = match response {
+            Some(Ok(response)) => match response.status {
+                PaymentStatus::Rejected { reason } => {
+                    reason.unwrap_or_else(|| "Rejected by the user".to_string())
+                }
+                PaymentStatus::Failed { reason } => {
+                    reason.unwrap_or_else(|| "Payment failed".to_string())
+                }
+                PaymentStatus::Approved | PaymentStatus::Success { .. } => continue,
+            },
+            Some(Err(e)) => {
+                warn!("Payment notification error: {e}");
+                continue;
+            }
+            None => {
+                notifications = None;
+                continue;
+            }
+        };
+        settlements.unwatch_charge(&charge.request_id);
+        return Err(reason);
+    }
+}
+
+/// The next reply of the user, never returns once they are gone
+async fn next_response(
+    notifications: &mut Option<NotificationStream<PaymentResponseContent>>,
+) -> Option<Result<PaymentResponseContent, serde_json::Error>> {
+    match notifications {
+        Some(stream) => stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use portal::protocol::calendar::{Calendar, CalendarWrapper};
+
+    use crate::config::WebhookSettings;
+    use crate::ledger::LedgerQuery;
+
+    const DAY: u64 = 24 * 60 * 60;
+    /// A midnight UTC
+    const MIDNIGHT: u64 = 1_700_006_400;
+
+    fn subscription(next_payment_at: u64) -> Subscription {
+        Subscription {
+            subscription_id: "subscription".to_string(),
+            stream_id: "stream".to_string(),
+            main_key: "main".to_string(),
+            subkeys: vec![],
+            amount: Amount::new(1000),
+            currency: Currency::Millisats,
+            recurrence: RecurrenceInfo {
+                until: None,
+                calendar: CalendarWrapper::new(Calendar::from_str("daily").unwrap()),
+                max_payments: None,
+                first_payment_due: Timestamp::new(next_payment_at),
+            },
+            description: None,
+            auth_token: None,
+            status: SubscriptionStatus::Active,
+            payments_made: 0,
+            next_payment_at,
+            next_attempt_at: next_payment_at,
+            failed_attempts: 0,
+            pending_charge: None,
+            last_error: None,
+            created_at: next_payment_at,
+            updated_at: next_payment_at,
+        }
+    }
+
+    struct Engine {
+        billing: Billing,
+        events: EventStore,
+        ledger: PaymentLedger,
+    }
+
+    impl Engine {
+        fn new() -> Self {
+            let settings = BillingSettings {
+                enabled: true,
+                retry_delays_secs: vec![60, 600],
+                ..Default::default()
+            };
+            Self {
+                billing: Billing::new(":memory:", settings).unwrap(),
+                events: EventStore::new(":memory:", WebhookSettings::default()).unwrap(),
+                ledger: PaymentLedger::new(":memory:").unwrap(),
+            }
+        }
+
+        /// Save `subscription` with a charge being collected, recorded in the ledger.
+        async fn charging(&self, mut subscription: Subscription) {
+            let request_id = Uuid::new_v4().to_string();
+            let mut entry = LedgerEntry::new(
+                LedgerKind::SubscriptionCharge,
+                crate::ledger::LedgerDirection::Incoming,
+                1000,
+                "Millisats".to_string(),
+            );
+            entry.id = request_id.clone();
+            entry.subscription_id = Some(subscription.subscription_id.clone());
+            self.ledger.record(&entry).await.unwrap();
+
+            subscription.pending_charge = Some(PendingCharge {
+                request_id,
+                invoice: "invoice".to_string(),
+                expires_at: Timestamp::now().as_u64() + 60,
+            });
+            self.billing.save(&mut subscription).await.unwrap();
+        }
+
+        async fn settle(&self, outcome: Result<Option<String>, String>) -> Subscription {
+            self.billing
+                .settle(&self.events, &self.ledger, "subscription", outcome)
+                .await;
+            self.billing.get("subscription").await.unwrap().unwrap()
+        }
+
+        async fn notifications(&self) -> Vec<NotificationData> {
+            self.events
+                .get("stream", None)
+                .await
+                .into_iter()
+                .map(|event| event.data)
+                .collect()
+        }
+
+        async fn ledger_statuses(&self) -> Vec<LedgerStatus> {
+            self.ledger
+                .list(&LedgerQuery::default())
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.status)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_following_payment_skips_missed_dates() {
+        let subscription = subscription(MIDNIGHT);
+
+        assert_eq!(
+            subscription.following_payment(MIDNIGHT),
+            Some(MIDNIGHT + DAY)
+        );
+        // The daemon was down for three days
+        assert_eq!(
+            subscription.following_payment(MIDNIGHT + 3 * DAY + 100),
+            Some(MIDNIGHT + 4 * DAY)
+        );
+    }
+
+    #[test]
+    fn test_following_payment_stops_at_max_payments_and_until() {
+        let mut subscription = subscription(MIDNIGHT);
+        subscription.recurrence.max_payments = Some(2);
+        subscription.payments_made = 1;
+        assert_eq!(
+            subscription.following_payment(MIDNIGHT),
+            Some(MIDNIGHT + DAY)
+        );
+        subscription.payments_made = 2;
+        assert_eq!(subscription.following_payment(MIDNIGHT), None);
+
+        let mut subscription = self::subscription(MIDNIGHT);
+        subscription.recurrence.until = Some(Timestamp::new(MIDNIGHT + DAY));
+        assert_eq!(
+            subscription.following_payment(MIDNIGHT),
+            Some(MIDNIGHT + DAY)
+        );
+        subscription.recurrence.until = Some(Timestamp::new(MIDNIGHT + DAY - 1));
+        assert_eq!(subscription.following_payment(MIDNIGHT), None);
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut subscription = subscription(MIDNIGHT);
+        subscription.failed_attempts = 2;
+        subscription.next_attempt_at = MIDNIGHT + 600;
+
+        assert_eq!(subscription.advance(MIDNIGHT), Some(MIDNIGHT + DAY));
+        assert_eq!(subscription.next_payment_at, MIDNIGHT + DAY);
+        assert_eq!(subscription.next_attempt_at, MIDNIGHT + DAY);
+        assert_eq!(subscription.failed_attempts, 0);
+        assert_eq!(subscription.status, SubscriptionStatus::Active);
+
+        subscription.recurrence.max_payments = Some(1);
+        subscription.payments_made = 1;
+        assert_eq!(subscription.advance(MIDNIGHT + DAY), None);
+        assert_eq!(subscription.next_payment_at, MIDNIGHT + DAY);
+        assert_eq!(subscription.status, SubscriptionStatus::Completed);
+
+        // A cancelled subscription stays cancelled
+        subscription.status = SubscriptionStatus::Cancelled;
+        assert_eq!(subscription.advance(MIDNIGHT + DAY), None);
+        assert_eq!(subscription.status, SubscriptionStatus::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_settle_retries_then_past_due() {
+        let engine = Engine::new();
+        let due_at = Timestamp::now().as_u64() - 10;
+        engine.charging(subscription(due_at)).await;
+
+        let before = Timestamp::now().as_u64();
+        let subscription = engine.settle(Err("No route".to_string())).await;
+        assert_eq!(subscription.failed_attempts, 1);
+        assert!(subscription.next_attempt_at >= before + 60);
+        assert!(subscription.next_attempt_at <= Timestamp::now().as_u64() + 60);
+        assert_eq!(subscription.next_payment_at, due_at);
+        assert_eq!(subscription.status, SubscriptionStatus::Active);
+        assert_eq!(subscription.last_error.as_deref(), Some("No route"));
+        assert!(subscription.pending_charge.is_none());
+
+        engine.charging(subscription).await;
+        let subscription = engine.settle(Err("No route".to_string())).await;
+        assert_eq!(subscription.failed_attempts, 2);
+        assert!(subscription.next_attempt_at >= before + 600);
+
+        // The retries are used up
+        engine.charging(subscription).await;
+        let subscription = engine.settle(Err("No route".to_string())).await;
+        assert_eq!(subscription.status, SubscriptionStatus::PastDue);
+        assert_eq!(subscription.failed_attempts, 0);
+        assert!(subscription.next_payment_at > due_at);
+        assert_eq!(subscription.next_attempt_at, subscription.next_payment_at);
+        assert_eq!(subscription.payments_made, 0);
+
+        let notifications = engine.notifications().await;
+        assert_eq!(notifications.len(), 4);
+        for (notification, expected_attempt) in notifications.iter().zip(1..=3) {
+            assert!(matches!(
+                notification,
+                NotificationData::SubscriptionPaymentFailed { attempt, next_retry_at, .. }
+                    if *attempt == expected_attempt && next_retry_at.is_some() == (expected_attempt < 3)
+            ));
+        }
+        assert!(matches!(
+            &notifications[3],
+            NotificationData::SubscriptionPastDue { missed_payment_at, next_payment_at, .. }
+                if *missed_payment_at == due_at && *next_payment_at == Some(subscription.next_payment_at)
+        ));
+        assert_eq!(
+            engine.ledger_statuses().await,
+            vec![LedgerStatus::Failed; 3]
+        );
+
+        // The next payment makes it active again
+        engine.charging(subscription).await;
+        let subscription = engine.settle(Ok(Some("preimage".to_string()))).await;
+        assert_eq!(subscription.status, SubscriptionStatus::Active);
+        assert_eq!(subscription.payments_made, 1);
+        assert_eq!(subscription.last_error, None);
+    }
+
+    #[tokio::test]
+    async fn test_settle_completes_at_max_payments() {
+        let engine = Engine::new();
+        let due_at = Timestamp::now().as_u64() - 10;
+        let mut subscription = subscription(due_at);
+        subscription.recurrence.max_payments = Some(1);
+        engine.charging(subscription).await;
+
+        let subscription = engine.settle(Ok(Some("preimage".to_string()))).await;
+        assert_eq!(subscription.status, SubscriptionStatus::Completed);
+        assert_eq!(subscription.payments_made, 1);
+
+        let notifications = engine.notifications().await;
+        assert!(matches!(
+            &notifications[..],
+            [
+                NotificationData::SubscriptionCharged {
+                    payment_number: 1,
+                    next_payment_at: None,
+                    ..
+                },
+                NotificationData::SubscriptionCompleted {
+                    payments_made: 1,
+                    ..
+                },
+            ]
+        ));
+        assert_eq!(engine.ledger_statuses().await, vec![LedgerStatus::Paid]);
+    }
+
+    #[tokio::test]
+    async fn test_settle_completes_at_until() {
+        let engine = Engine::new();
+        let due_at = Timestamp::now().as_u64() - 10;
+        let mut subscription = subscription(due_at);
+        subscription.recurrence.until = Some(Timestamp::new(due_at + 1));
+        engine.charging(subscription).await;
+
+        let subscription = engine.settle(Ok(None)).await;
+        assert_eq!(subscription.status, SubscriptionStatus::Completed);
+        assert!(matches!(
+            engine.notifications().await.last(),
+            Some(NotificationData::SubscriptionCompleted { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_during_charge() {
+        let engine = Engine::new();
+        let due_at = Timestamp::now().as_u64() - 10;
+        engine.charging(subscription(due_at)).await;
+
+        // Only the subscriber can cancel it
+        engine
+            .billing
+            .cancel(&engine.events, "subscription", "other", None, false)
+            .await;
+        let subscription = engine.billing.get("subscription").await.unwrap().unwrap();
+        assert_eq!(subscription.status, SubscriptionStatus::Active);
+
+        engine
+            .billing
+            .cancel(&engine.events, "subscription", "main", None, false)
+            .await;
+
+        // The charge sent before was paid anyway
+        let subscription = engine.settle(Ok(Some("preimage".to_string()))).await;
+        assert_eq!(subscription.status, SubscriptionStatus::Cancelled);
+        assert_eq!(subscription.payments_made, 1);
+        assert_eq!(subscription.next_payment_at, due_at);
+        assert_eq!(engine.ledger_statuses().await, vec![LedgerStatus::Paid]);
+
+        // A failed charge isn't retried
+        engine.charging(subscription).await;
+        let subscription = engine.settle(Err("Expired".to_string())).await;
+        assert_eq!(subscription.status, SubscriptionStatus::Cancelled);
+        assert_eq!(subscription.failed_attempts, 0);
+        assert_eq!(subscription.last_error.as_deref(), Some("Expired"));
+
+        let notifications = engine.notifications().await;
+        assert!(matches!(
+            &notifications[..],
+            [
+                NotificationData::SubscriptionCancelled {
+                    by_service: false,
+                    ..
+                },
+                NotificationData::SubscriptionCharged {
+                    next_payment_at: None,
+                    ..
+                },
+            ]
+        ));
+    }
+}
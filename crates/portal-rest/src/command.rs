@@ -1,3 +1,5 @@
+use portal::protocol::identity::{CertificateData, VerificationLevel, VerificationMethod};
+use portal::protocol::predicate::Predicate;
 use portal::protocol::model::payment::{
     Currency, RecurrenceInfo, SinglePaymentRequestContent,
 };
@@ -16,6 +18,9 @@ pub struct KeyHandshakeRequest {
 pub struct AuthenticateKeyRequest {
     pub main_key: String,
     pub subkeys: Vec<String>,
+    /// Permissions to ask the user for, e.g. `payments:single<=1000 sats`
+    #[serde(default)]
+    pub permissions: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -57,12 +62,71 @@ pub struct RequestInvoiceRequest {
 pub struct IssueJwtRequest {
     pub target_key: String,
     pub duration_hours: i64,
+    #[serde(default)]
+    pub audience: Option<String>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Session ID, revoke it with `/sessions/revoke` to reject the token
+    #[serde(default)]
+    pub jti: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct VerifyJwtRequest {
     pub pubkey: String,
     pub token: String,
+    #[serde(default)]
+    pub audience: Option<String>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub required_scopes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionTokenRequest {
+    pub token: String,
+}
+
+/// Either the token or the ID of the session to revoke
+#[derive(Debug, Deserialize)]
+pub struct RevokeSessionRequest {
+    pub token: Option<String>,
+    pub session_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueCertificateRequest {
+    /// Key the certificate is issued to
+    pub subject_key: String,
+    pub subkeys: Vec<String>,
+    /// Certificate data, tagged by `type` (`personal`, `business` or `custom`)
+    pub data: CertificateData,
+    pub verification_level: VerificationLevel,
+    pub verification_method: VerificationMethod,
+    pub expires_at: Timestamp,
+    /// Predicate claims derived from `data` and committed in the certificate
+    #[serde(default)]
+    pub predicates: Vec<Predicate>,
+    /// Send the certificate to the subject over Nostr. Defaults to `true`.
+    pub deliver: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestCertificatesRequest {
+    pub main_key: String,
+    pub subkeys: Vec<String>,
+    /// Certificate types to request (`personal`, `business`, `custom`)
+    pub requested_types: Vec<String>,
+    /// Fields to reveal, e.g. `nationality` or `predicates.age_over_18`
+    pub requested_fields: Vec<String>,
+    pub purpose: String,
+    pub require_status_proofs: Option<bool>,
+    /// Keys of the issuers to accept certificates from. Without it certificates of any issuer
+    /// are returned, and the caller must check their `issuer`.
+    pub trusted_issuers: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -115,6 +179,32 @@ pub struct PayInvoiceRequest {
     pub invoice: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct CreateWalletInvoiceRequest {
+    pub amount_msat: u64,
+    pub description: Option<String>,
+    /// Hex encoded SHA-256 of the description, committed to instead of `description`
+    pub description_hash: Option<String>,
+    /// How long the invoice can be paid for, the wallet's default if not set
+    pub expiry_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MockInvoiceRequest {
+    /// BOLT11 invoice or payment hash
+    pub invoice: String,
+    /// Reason of the failure, for `POST /wallet/mock/fail`
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReplayWebhooksRequest {
+    pub stream_id: String,
+    /// Index of the first event to deliver again, the following ones are replayed too.
+    #[serde(default)]
+    pub from_index: u64,
+}
+
 // ---- Shared param types ----
 
 #[derive(Debug, Deserialize)]
@@ -150,3 +240,12 @@ pub struct RequestInvoiceParams {
     /// Optional request ID. If not provided, a UUID is generated.
     pub request_id: Option<String>,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    /// `auth`, `payments:request`, `wallet:spend`, `cashu`, `relays` or `admin`
+    pub scopes: Vec<String>,
+    /// Unix timestamp after which the key is rejected, never if not set
+    pub expires_at: Option<i64>,
+}
@@ -1,8 +1,12 @@
 use config::{Config, Environment, File};
-use portal_wallet::{BreezSparkWallet, NwcWallet, PortalWallet};
+use portal_wallet::{
+    BreezSparkWallet, ClnWallet, LnbitsWallet, LndWallet, MockWallet, MockWalletConfig, NwcWallet,
+    PortalWallet,
+};
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
-use tracing::info;
+use tracing::{info, warn};
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Settings {
@@ -15,11 +19,41 @@ pub struct Settings {
     #[serde(default)]
     pub database: DatabaseSettings,
     #[serde(default)]
+    pub outbox: OutboxSettings,
+    #[serde(default)]
+    pub billing: BillingSettings,
+    #[serde(default)]
     pub profile: ProfileSettings,
     /// Used when the `task-tracing` feature is off (see `main` tracing init).
     #[cfg_attr(feature = "task-tracing", allow(dead_code))]
     #[serde(default)]
     pub logging: LoggingSettings,
+    /// Other service identities served by the daemon, by name.
+    #[serde(default)]
+    pub identities: BTreeMap<String, IdentitySettings>,
+    /// Name of the identity these settings are for, `None` for the main one.
+    #[serde(skip)]
+    pub identity: Option<String>,
+}
+
+/// A service identity served next to the main one, under `/identities/<name>` or with the
+/// `X-Portal-Identity` header. The settings that are not listed here (auth, outbox,
+/// logging, port) are shared with the main identity.
+#[derive(Deserialize, Debug, Clone)]
+pub struct IdentitySettings {
+    pub nostr: NostrSettings,
+    /// No wallet if unset.
+    #[serde(default)]
+    pub wallet: WalletSettings,
+    #[serde(default)]
+    pub webhook: WebhookSettings,
+    #[serde(default)]
+    pub billing: BillingSettings,
+    #[serde(default)]
+    pub profile: ProfileSettings,
+    /// Database of the identity, `portal-rest-<name>.db` if unset. Its streams, conversations,
+    /// outbox and subscriptions are kept apart from the other identities.
+    pub database: Option<DatabaseSettings>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -76,19 +110,79 @@ pub struct NostrSettings {
 #[derive(Deserialize, Debug, Clone)]
 pub struct AuthSettings {
     pub auth_token: String,
+    /// How long the user sessions opened by `/authenticate-key` and `/sessions/refresh`
+    /// last, in seconds.
+    #[serde(default = "default_session_duration_secs")]
+    pub session_duration_secs: u64,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+fn default_session_duration_secs() -> u64 {
+    portal::protocol::session::DEFAULT_SESSION_DURATION_SECS
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct WalletSettings {
     pub ln_backend: LnBackend,
     pub nwc: Option<NwcSettings>,
     pub breez: Option<BreezSettings>,
+    pub lnd: Option<LndSettings>,
+    pub cln: Option<ClnSettings>,
+    pub lnbits: Option<LnbitsSettings>,
+    pub mock: Option<MockSettings>,
 }
 
-#[derive(Deserialize, Debug, Clone, Default)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct WebhookSettings {
     pub url: Option<String>,
     pub secret: Option<String>,
+    /// Delivery attempts before an event is moved to the dead-letter state.
+    #[serde(default = "default_webhook_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, in seconds. Doubled after every failed attempt.
+    #[serde(default = "default_webhook_retry_base_delay_secs")]
+    pub retry_base_delay_secs: u64,
+    /// Upper bound of the delay between two attempts, in seconds.
+    #[serde(default = "default_webhook_retry_max_delay_secs")]
+    pub retry_max_delay_secs: u64,
+    /// How long to wait for the webhook endpoint to answer, in seconds.
+    #[serde(default = "default_webhook_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl WebhookSettings {
+    /// The configured webhook URL, if any.
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref().filter(|url| !url.is_empty())
+    }
+}
+
+fn default_webhook_max_attempts() -> u32 {
+    10
+}
+
+fn default_webhook_retry_base_delay_secs() -> u64 {
+    10
+}
+
+fn default_webhook_retry_max_delay_secs() -> u64 {
+    60 * 60
+}
+
+fn default_webhook_timeout_secs() -> u64 {
+    10
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            url: None,
+            secret: None,
+            max_attempts: default_webhook_max_attempts(),
+            retry_base_delay_secs: default_webhook_retry_base_delay_secs(),
+            retry_max_delay_secs: default_webhook_retry_max_delay_secs(),
+            timeout_secs: default_webhook_timeout_secs(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -105,11 +199,77 @@ impl Default for DatabaseSettings {
 }
 
 #[derive(Deserialize, Debug, Clone)]
+pub struct OutboxSettings {
+    /// Max events waiting for relay retry. Events that fail to send while the outbox is
+    /// full are dropped. `None` means unbounded.
+    #[serde(default = "default_outbox_max_events")]
+    pub max_events: Option<usize>,
+}
+
+fn default_outbox_max_events() -> Option<usize> {
+    Some(10_000)
+}
+
+impl Default for OutboxSettings {
+    fn default() -> Self {
+        Self {
+            max_events: default_outbox_max_events(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BillingSettings {
+    /// Charge confirmed subscriptions automatically. Off by default, so services that
+    /// already send the subscription payments themselves don't charge their users twice.
+    #[serde(default)]
+    pub enabled: bool,
+    /// How often due subscriptions are looked up, in seconds.
+    #[serde(default = "default_billing_check_interval_secs")]
+    pub check_interval_secs: u64,
+    /// How long the user has to pay a charge, in seconds.
+    #[serde(default = "default_billing_payment_timeout_secs")]
+    pub payment_timeout_secs: u64,
+    /// Delays before retrying a failed charge, in seconds. Once they are used up the
+    /// payment is skipped and the subscription is past due.
+    #[serde(default = "default_billing_retry_delays_secs")]
+    pub retry_delays_secs: Vec<u64>,
+}
+
+fn default_billing_check_interval_secs() -> u64 {
+    60
+}
+
+fn default_billing_payment_timeout_secs() -> u64 {
+    300
+}
+
+fn default_billing_retry_delays_secs() -> Vec<u64> {
+    vec![60 * 60, 6 * 60 * 60, 24 * 60 * 60]
+}
+
+impl Default for BillingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            check_interval_secs: default_billing_check_interval_secs(),
+            payment_timeout_secs: default_billing_payment_timeout_secs(),
+            retry_delays_secs: default_billing_retry_delays_secs(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum LnBackend {
+    #[default]
     None,
     Nwc,
     Breez,
+    Lnd,
+    Cln,
+    Lnbits,
+    Mock,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -126,6 +286,54 @@ pub struct BreezSettings {
     pub mnemonic: String,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct LndSettings {
+    /// URL of the REST API, e.g. https://localhost:8080
+    pub url: String,
+    /// Hex encoded macaroon, or `macaroon_path` to read it from a file
+    pub macaroon: Option<String>,
+    pub macaroon_path: Option<String>,
+    /// `tls.cert` of the node, when it's self-signed
+    pub tls_cert_path: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClnSettings {
+    /// URL of `clnrest`, e.g. https://localhost:3010
+    pub url: String,
+    pub rune: String,
+    /// Certificate of `clnrest`, when it's self-signed
+    pub tls_cert_path: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LnbitsSettings {
+    pub url: String,
+    /// Admin key of the wallet
+    pub api_key: String,
+}
+
+/// In-process wallet that never touches the network, for development and CI
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MockSettings {
+    /// SQLite database of the wallet, relative paths are resolved under ~/.portal-rest/.
+    /// The wallet is kept in memory if unset.
+    pub database_path: Option<String>,
+    #[serde(default)]
+    pub initial_balance_msat: u64,
+    /// Delay added to every wallet call
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Fee of every payment: `fee_msat` plus `fee_ppm` parts per million of the amount
+    #[serde(default)]
+    pub fee_msat: u64,
+    #[serde(default)]
+    pub fee_ppm: u64,
+    /// Settle the invoices on their own after this delay, instead of waiting for
+    /// `POST /wallet/mock/settle`
+    pub settle_after_secs: Option<u64>,
+}
+
 
 impl Settings {
     pub fn load() -> anyhow::Result<Self> {
@@ -161,7 +369,75 @@ impl Settings {
         Ok(settings)
     }
 
+    /// The settings of the identity `name`: the shared settings, with the key, relays, wallet,
+    /// webhook, billing, profile and database of the identity.
+    pub fn for_identity(&self, name: &str) -> Option<Settings> {
+        let identity = self.identities.get(name)?;
+        Some(Settings {
+            nostr: identity.nostr.clone(),
+            wallet: identity.wallet.clone(),
+            webhook: identity.webhook.clone(),
+            billing: identity.billing.clone(),
+            profile: identity.profile.clone(),
+            database: identity
+                .database
+                .clone()
+                .unwrap_or_else(|| DatabaseSettings {
+                    path: format!("portal-rest-{name}.db"),
+                }),
+            identities: BTreeMap::new(),
+            identity: Some(name.to_string()),
+            ..self.clone()
+        })
+    }
+
+    /// Path of a file or directory of this identity (wallet data, caches): under
+    /// ~/.portal-rest/ for the main identity, ~/.portal-rest/identities/<name>/ for the others.
+    pub fn data_path(&self, name: &str) -> anyhow::Result<std::path::PathBuf> {
+        let rest_dir = crate::constants::portal_rest_dir()?;
+        Ok(match &self.identity {
+            Some(identity) => rest_dir.join("identities").join(identity).join(name),
+            None => rest_dir.join(name),
+        })
+    }
+
     pub fn validate(&self) -> anyhow::Result<()> {
+        let mut database_paths = HashSet::from([self.database.path.clone()]);
+        for name in self.identities.keys() {
+            let valid_name = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+            if !valid_name {
+                return Err(anyhow::anyhow!(
+                    "Invalid identity name '{name}': use lowercase letters, digits, '-' and '_'"
+                ));
+            }
+
+            let identity = self.for_identity(name).expect("identity exists");
+            if !database_paths.insert(identity.database.path.clone()) {
+                return Err(anyhow::anyhow!(
+                    "Identity '{name}' must have its own database, {} is already used",
+                    identity.database.path
+                ));
+            }
+            identity
+                .validate()
+                .map_err(|e| anyhow::anyhow!("Identity '{name}': {e}"))?;
+        }
+
+        if self.auth.session_duration_secs == 0 {
+            return Err(anyhow::anyhow!("auth.session_duration_secs must be at least 1"));
+        }
+
+        if self.webhook.max_attempts == 0 {
+            return Err(anyhow::anyhow!("webhook.max_attempts must be at least 1"));
+        }
+
+        if self.billing.enabled && matches!(self.wallet.ln_backend, LnBackend::None) {
+            return Err(anyhow::anyhow!("Billing requires a wallet to issue invoices"));
+        }
+
         match self.wallet.ln_backend {
             LnBackend::None => anyhow::Ok(()),
             LnBackend::Nwc => {
@@ -176,6 +452,31 @@ impl Settings {
                 }
                 anyhow::Ok(())
             }
+            LnBackend::Lnd => {
+                let Some(lnd) = &self.wallet.lnd else {
+                    return Err(anyhow::anyhow!("LND Wallet is not set"));
+                };
+                if lnd.macaroon.is_none() == lnd.macaroon_path.is_none() {
+                    return Err(anyhow::anyhow!(
+                        "Exactly one of wallet.lnd.macaroon and wallet.lnd.macaroon_path must be set"
+                    ));
+                }
+                anyhow::Ok(())
+            }
+            LnBackend::Cln => {
+                if self.wallet.cln.is_none() {
+                    return Err(anyhow::anyhow!("Core Lightning Wallet is not set"));
+                }
+                anyhow::Ok(())
+            }
+            LnBackend::Lnbits => {
+                if self.wallet.lnbits.is_none() {
+                    return Err(anyhow::anyhow!("LNbits Wallet is not set"));
+                }
+                anyhow::Ok(())
+            }
+            // Every mock setting has a default
+            LnBackend::Mock => anyhow::Ok(()),
         }
     }
 
@@ -202,8 +503,8 @@ impl Settings {
                     .breez
                     .as_ref()
                     .ok_or(anyhow::anyhow!("Breez Wallet is not set"))?;
-                let storage_dir = crate::constants::portal_rest_dir()?
-                    .join("breez")
+                let storage_dir = self
+                    .data_path("breez")?
                     .to_str()
                     .ok_or_else(|| anyhow::anyhow!("Invalid portal-rest path"))?
                     .to_string();
@@ -218,6 +519,203 @@ impl Settings {
                 info!("Breez Wallet created");
                 anyhow::Ok(Some(Arc::new(breez)))
             }
+            LnBackend::Lnd => {
+                let settings = self
+                    .wallet
+                    .lnd
+                    .as_ref()
+                    .ok_or(anyhow::anyhow!("LND Wallet is not set"))?;
+                let macaroon = match (&settings.macaroon, &settings.macaroon_path) {
+                    (Some(macaroon), _) => macaroon.clone(),
+                    (None, Some(path)) => hex::encode(std::fs::read(path)?),
+                    (None, None) => return Err(anyhow::anyhow!("LND macaroon is not set")),
+                };
+                let lnd = LndWallet::new(
+                    settings.url.clone(),
+                    macaroon,
+                    read_tls_cert(settings.tls_cert_path.as_deref())?,
+                )?;
+
+                info!("LND Wallet created");
+                anyhow::Ok(Some(Arc::new(lnd)))
+            }
+            LnBackend::Cln => {
+                let settings = self
+                    .wallet
+                    .cln
+                    .as_ref()
+                    .ok_or(anyhow::anyhow!("Core Lightning Wallet is not set"))?;
+                let cln = ClnWallet::new(
+                    settings.url.clone(),
+                    settings.rune.clone(),
+                    read_tls_cert(settings.tls_cert_path.as_deref())?,
+                )?;
+
+                info!("Core Lightning Wallet created");
+                anyhow::Ok(Some(Arc::new(cln)))
+            }
+            LnBackend::Lnbits => {
+                let settings = self
+                    .wallet
+                    .lnbits
+                    .as_ref()
+                    .ok_or(anyhow::anyhow!("LNbits Wallet is not set"))?;
+                let lnbits = LnbitsWallet::new(settings.url.clone(), settings.api_key.clone())?;
+
+                info!("LNbits Wallet created");
+                anyhow::Ok(Some(Arc::new(lnbits)))
+            }
+            LnBackend::Mock => anyhow::Ok(
+                self.build_mock_wallet()?
+                    .map(|mock| mock as Arc<dyn PortalWallet>),
+            ),
         }
     }
+
+    /// The mock wallet, if it is the configured backend. The daemon keeps it next to the
+    /// [`PortalWallet`] to expose its test commands.
+    pub fn build_mock_wallet(&self) -> anyhow::Result<Option<Arc<MockWallet>>> {
+        if !matches!(self.wallet.ln_backend, LnBackend::Mock) {
+            return Ok(None);
+        }
+
+        let settings = self.wallet.mock.clone().unwrap_or_default();
+        let config = MockWalletConfig {
+            initial_balance_msat: settings.initial_balance_msat,
+            latency: std::time::Duration::from_millis(settings.latency_ms),
+            fee_msat: settings.fee_msat,
+            fee_ppm: settings.fee_ppm,
+            settle_after: settings
+                .settle_after_secs
+                .map(std::time::Duration::from_secs),
+            ..Default::default()
+        };
+        let mock = match &settings.database_path {
+            Some(path) if std::path::Path::new(path).is_relative() => {
+                let rest_dir = crate::constants::portal_rest_dir()?;
+                std::fs::create_dir_all(&rest_dir)?;
+                MockWallet::open(rest_dir.join(path), config)?
+            }
+            Some(path) => MockWallet::open(path, config)?,
+            None => MockWallet::new(config)?,
+        };
+
+        warn!("Mock Wallet created: payments are simulated, no real funds are moved");
+        Ok(Some(Arc::new(mock)))
+    }
+}
+
+fn read_tls_cert(path: Option<&str>) -> anyhow::Result<Option<Vec<u8>>> {
+    path.map(|path| {
+        std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read TLS certificate {path}: {e}"))
+    })
+    .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    const SETTINGS: &str = r#"
+        [info]
+        listen_port = 3000
+
+        [nostr]
+        private_key = "main-key"
+        relays = ["wss://relay.nostr.net"]
+
+        [auth]
+        auth_token = "token"
+
+        [wallet]
+        ln_backend = "none"
+    "#;
+
+    /// The settings above, with an identity `shop` and the identities in `identities`.
+    fn settings(identities: &str) -> Settings {
+        Config::builder()
+            .add_source(File::from_str(
+                &format!(
+                    r#"
+                    {SETTINGS}
+                    [identities.shop.nostr]
+                    private_key = "shop-key"
+                    relays = ["wss://relay.getportal.cc"]
+                    {identities}
+                    "#
+                ),
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_identity_settings() {
+        let settings = settings("");
+        settings.validate().unwrap();
+
+        let shop = settings.for_identity("shop").unwrap();
+        assert_eq!(shop.identity.as_deref(), Some("shop"));
+        assert_eq!(shop.nostr.private_key, "shop-key");
+        assert_eq!(shop.database.path, "portal-rest-shop.db");
+        assert_eq!(shop.auth.auth_token, "token");
+        assert!(shop.identities.is_empty());
+        assert!(settings.for_identity("other").is_none());
+    }
+
+    #[test]
+    fn test_invalid_identity_names_are_rejected() {
+        let with_identity = |name: &str| {
+            let mut settings = settings("");
+            let shop = settings.identities.remove("shop").unwrap();
+            settings.identities.insert(name.to_string(), shop);
+            settings
+        };
+
+        for name in ["shop-1", "shop_2"] {
+            with_identity(name).validate().unwrap();
+        }
+        for name in ["Shop", "shop/1", "shop.com", ""] {
+            let err = with_identity(name).validate().unwrap_err();
+            assert!(err.to_string().contains("Invalid identity name"), "{err}");
+        }
+    }
+
+    #[test]
+    fn test_shared_database_is_rejected() {
+        let err = settings(
+            r#"
+            [identities.shop.database]
+            path = "portal-rest.db"
+            "#,
+        )
+        .validate()
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("must have its own database"),
+            "{err}"
+        );
+
+        let err = settings(
+            r#"
+            [identities.blog.nostr]
+            private_key = "blog-key"
+            relays = ["wss://relay.getportal.cc"]
+
+            [identities.blog.database]
+            path = "portal-rest-shop.db"
+            "#,
+        )
+        .validate()
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("must have its own database"),
+            "{err}"
+        );
+    }
 }
@@ -0,0 +1,88 @@
+use std::sync::Mutex;
+
+use portal::router::{ConversationRecord, ConversationStore, ConversationStoreError};
+use rusqlite::Connection;
+use tracing::info;
+
+fn storage_error(e: rusqlite::Error) -> ConversationStoreError {
+    ConversationStoreError::Storage(Box::new(e))
+}
+
+/// SQLite-backed store for the state of in-flight SDK conversations, so that flows like
+/// payment approvals survive a restart of the daemon.
+///
+/// Records are stored as JSON, keyed by their tag (the stream ID of the request). The router
+/// saves and removes them from a thread of its own, so the queries don't block the executor.
+pub struct SqliteConversationStore {
+    db: Mutex<Connection>,
+}
+
+impl SqliteConversationStore {
+    /// Open (or create) the SQLite database at `db_path` and initialize the schema.
+    pub fn new(db_path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open(db_path)?;
+
+        conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS conversations (
+                tag TEXT PRIMARY KEY,
+                conversation_type TEXT NOT NULL,
+                record TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            );",
+        )?;
+
+        info!("Conversation store opened at {db_path}");
+
+        Ok(Self {
+            db: Mutex::new(conn),
+        })
+    }
+}
+
+impl ConversationStore for SqliteConversationStore {
+    fn save(&self, record: ConversationRecord) -> Result<(), ConversationStoreError> {
+        let record_json = serde_json::to_string(&record)?;
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT INTO conversations (tag, conversation_type, record, updated_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(tag) DO UPDATE SET record = ?3, updated_at = ?4",
+            rusqlite::params![
+                record.tag,
+                record.conversation_type,
+                record_json,
+                record.updated_at as i64
+            ],
+        )
+        .map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn remove(&self, tag: &str) -> Result<(), ConversationStoreError> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "DELETE FROM conversations WHERE tag = ?1",
+            rusqlite::params![tag],
+        )
+        .map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn load_all(&self) -> Result<Vec<ConversationRecord>, ConversationStoreError> {
+        let db = self.db.lock().unwrap();
+        let mut stmt = db
+            .prepare("SELECT record FROM conversations ORDER BY updated_at ASC")
+            .map_err(storage_error)?;
+        let rows = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(storage_error)?;
+
+        let mut records = Vec::new();
+        for row in rows {
+            records.push(serde_json::from_str(&row.map_err(storage_error)?)?);
+        }
+        Ok(records)
+    }
+}
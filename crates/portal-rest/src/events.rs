@@ -1,14 +1,24 @@
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use portal::router::{EventSendResult, SendOutcome};
 use reqwest::Client;
-use rusqlite::Connection;
-use tokio::sync::Mutex;
-use tracing::{error, info};
-use uuid::Uuid;
+use rusqlite::{Connection, OptionalExtension};
+use tokio::sync::{broadcast, Mutex};
+use tracing::{debug, error, info, warn};
 
+use crate::billing::SubscriptionRequest;
 use crate::config::WebhookSettings;
-use crate::response::{NotificationData, StreamEvent};
-use crate::webhook;
+use crate::response::{LiveEvent, NotificationData, StreamEvent};
+use crate::webhook::{self, DeliveryAttempt, DeliveryStatus, WebhookDelivery};
+
+/// Max deliveries retried per check, the others wait for the next one.
+const MAX_DUE_DELIVERIES: i64 = 100;
+/// Events buffered for each live subscriber, those lagging behind catch up from the database.
+pub const LIVE_EVENTS_CAPACITY: usize = 1024;
+/// How long the response to a request with an `Idempotency-Key` is kept, in seconds.
+const IDEMPOTENCY_KEY_TTL_SECS: i64 = 24 * 60 * 60;
 
 /// Stream status in the database.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -53,11 +63,37 @@ pub enum StreamMetadata {
         /// Expiry timestamp as Unix seconds.
         expires_at_secs: u64,
     },
+    /// Kept until the user replies, to bill the subscription if they confirm it.
+    RecurringPayment(SubscriptionRequest),
     RecurringPaymentClose,
+    InvoiceRequest {
+        /// Amount the returned invoice must be for, fixed when the request was made.
+        expected_amount_msat: u64,
+    },
+    CertificateRequest {
+        /// The key the revealed certificates must be issued to.
+        main_key: String,
+        /// The issuers the revealed certificates are accepted from, any if `None`.
+        #[serde(default)]
+        trusted_issuers: Option<Vec<String>>,
+    },
     /// Generic stream with no recovery metadata.
     Other,
 }
 
+/// Outcome of claiming an idempotency key, see [`EventStore::claim_idempotency_key`].
+#[derive(Debug)]
+pub enum IdempotencyClaim {
+    /// First request with this key, to be executed.
+    Claimed,
+    /// A request with this key is being executed.
+    InProgress,
+    /// The key was used for a different request.
+    Mismatch,
+    /// The request was executed already, and answered this.
+    Completed { status_code: u16, response: Vec<u8> },
+}
+
 /// Info about an in-flight stream, used for startup recovery.
 #[derive(Debug, Clone)]
 pub struct InFlightStream {
@@ -67,16 +103,23 @@ pub struct InFlightStream {
 }
 
 /// SQLite-backed store for stream events. Events are appended per stream_id and can be
-/// polled by clients via `GET /events/{stream_id}?after={index}`.
+/// polled by clients via `GET /events/{stream_id}?after={index}`, or followed live via
+/// [`EventStore::subscribe`].
 ///
-/// When a webhook URL is configured, events are also delivered via HTTP POST.
+/// When a webhook URL is configured, events are also delivered via HTTP POST. Deliveries are
+/// tracked in the `webhook_deliveries` table and retried with exponential backoff until they
+/// succeed or run out of attempts, in which case they are dead-lettered until replayed.
 ///
-/// Stream IDs and events survive server restarts.
+/// Stream IDs, events and pending deliveries survive server restarts.
 #[derive(Clone)]
 pub struct EventStore {
     db: Arc<Mutex<Connection>>,
     webhook_settings: WebhookSettings,
     http_client: Client,
+    /// Deliveries being attempted, so the same event is never posted twice concurrently
+    delivering: Arc<std::sync::Mutex<HashSet<(String, u64)>>>,
+    /// Events of all the streams, as they are pushed
+    live: broadcast::Sender<LiveEvent>,
 }
 
 impl EventStore {
@@ -106,45 +149,106 @@ impl EventStore {
             );
 
             CREATE INDEX IF NOT EXISTS idx_stream_events_stream_id
-                ON stream_events(stream_id, event_index);",
+                ON stream_events(stream_id, event_index);
+
+            CREATE TABLE IF NOT EXISTS queued_events (
+                event_id TEXT PRIMARY KEY,
+                stream_id TEXT NOT NULL REFERENCES streams(stream_id)
+            );
+
+            CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                stream_id TEXT NOT NULL,
+                event_index INTEGER NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending',
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER,
+                last_error TEXT,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (stream_id, event_index)
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_due
+                ON webhook_deliveries(status, next_attempt_at);
+
+            CREATE TABLE IF NOT EXISTS webhook_attempts (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                stream_id TEXT NOT NULL,
+                event_index INTEGER NOT NULL,
+                attempted_at INTEGER NOT NULL,
+                status_code INTEGER,
+                error TEXT,
+                duration_ms INTEGER NOT NULL
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_webhook_attempts_event
+                ON webhook_attempts(stream_id, event_index);
+
+            CREATE TABLE IF NOT EXISTS idempotency_keys (
+                idempotency_key TEXT NOT NULL,
+                caller TEXT NOT NULL,
+                fingerprint TEXT NOT NULL,
+                status_code INTEGER,
+                response BLOB,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (idempotency_key, caller)
+            );",
         )?;
 
+        // Requests interrupted by a restart never completed, so they may be retried
+        conn.execute("DELETE FROM idempotency_keys WHERE status_code IS NULL", [])?;
+
+        // The API key that created a stream, for databases created before API keys
+        let has_api_key_id = conn
+            .prepare("SELECT api_key_id FROM streams LIMIT 0")
+            .is_ok();
+        if !has_api_key_id {
+            conn.execute_batch("ALTER TABLE streams ADD COLUMN api_key_id TEXT;")?;
+        }
+
         info!("SQLite database opened at {db_path}");
 
+        let http_client = Client::builder()
+            .timeout(Duration::from_secs(webhook_settings.timeout_secs))
+            .build()?;
+
         Ok(Self {
             db: Arc::new(Mutex::new(conn)),
             webhook_settings,
-            http_client: Client::new(),
+            http_client,
+            delivering: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            live: broadcast::channel(LIVE_EVENTS_CAPACITY).0,
         })
     }
 
-    /// Create a new stream entry in the database.
+    /// Whether a webhook URL is configured.
+    pub fn webhooks_enabled(&self) -> bool {
+        self.webhook_settings.url().is_some()
+    }
+
+    /// Create a new stream entry in the database. `api_key_id` is the API key that created it,
+    /// if any.
     pub async fn create_stream(
         &self,
         stream_id: &str,
         stream_type: &str,
         metadata: Option<&StreamMetadata>,
+        api_key_id: Option<&str>,
     ) {
         let now = chrono::Utc::now().timestamp();
         let meta_json = metadata.and_then(|m| serde_json::to_string(m).ok());
         let db = self.db.lock().await;
         if let Err(e) = db.execute(
-            "INSERT INTO streams (stream_id, stream_type, status, created_at, updated_at, metadata)
-             VALUES (?1, ?2, 'in_flight', ?3, ?3, ?4)
+            "INSERT INTO streams
+                (stream_id, stream_type, status, created_at, updated_at, metadata, api_key_id)
+             VALUES (?1, ?2, 'in_flight', ?3, ?3, ?4, ?5)
              ON CONFLICT(stream_id) DO UPDATE SET updated_at = ?3, status = 'in_flight'",
-            rusqlite::params![stream_id, stream_type, now, meta_json],
+            rusqlite::params![stream_id, stream_type, now, meta_json, api_key_id],
         ) {
             error!("Failed to create stream {stream_id}: {e}");
         }
     }
 
-    /// Generate a new UUID stream ID, create the stream, and return the ID.
-    pub async fn new_stream(&self, stream_type: &str, metadata: Option<&StreamMetadata>) -> String {
-        let stream_id = Uuid::new_v4().to_string();
-        self.create_stream(&stream_id, stream_type, metadata).await;
-        stream_id
-    }
-
     /// Push an event to a stream. Returns the assigned event index.
     pub async fn push(&self, stream_id: &str, data: NotificationData) -> u64 {
         let timestamp = chrono::Utc::now().to_rfc3339();
@@ -168,12 +272,25 @@ impl EventStore {
                 )
                 .unwrap_or(0);
 
-            if let Err(e) = db.execute(
+            let inserted = db.execute(
                 "INSERT INTO stream_events (stream_id, event_index, timestamp, data)
                  VALUES (?1, ?2, ?3, ?4)",
                 rusqlite::params![stream_id, next_index, timestamp, data_json],
-            ) {
-                error!("Failed to persist event for stream {stream_id}: {e}");
+            );
+            match inserted {
+                // Sent with the database locked, so the subscribers get the events in order
+                Ok(_) => {
+                    let _ = self.live.send(LiveEvent {
+                        seq: db.last_insert_rowid() as u64,
+                        stream_id: stream_id.to_string(),
+                        event: StreamEvent {
+                            index: next_index,
+                            timestamp,
+                            data: data.clone(),
+                        },
+                    });
+                }
+                Err(e) => error!("Failed to persist event for stream {stream_id}: {e}"),
             }
 
             // Update the stream's updated_at
@@ -183,6 +300,10 @@ impl EventStore {
                 rusqlite::params![now, stream_id],
             );
 
+            if self.webhooks_enabled() {
+                Self::schedule_delivery(&db, stream_id, next_index, now);
+            }
+
             next_index
         };
 
@@ -192,19 +313,464 @@ impl EventStore {
             self.update_stream_status(stream_id, status).await;
         }
 
-        // Fire-and-forget webhook delivery
-        let settings = self.webhook_settings.clone();
-        let http_client = self.http_client.clone();
-        let sid = stream_id.to_string();
-        let data_clone = data;
-        let ts = timestamp.clone();
-        tokio::spawn(async move {
-            webhook::deliver(&http_client, &settings, &sid, &data_clone, index, &ts).await;
-        });
+        // First webhook delivery attempt, failures are retried by `webhook::run_retries`
+        if self.webhooks_enabled() {
+            let events = self.clone();
+            let sid = stream_id.to_string();
+            tokio::spawn(async move { events.deliver_webhook(&sid, index).await });
+        }
 
         index
     }
 
+    /// Mark an event as pending webhook delivery, due immediately. Replayed deliveries start
+    /// over with a full set of attempts.
+    fn schedule_delivery(db: &Connection, stream_id: &str, index: u64, now: i64) {
+        if let Err(e) = db.execute(
+            "INSERT INTO webhook_deliveries
+                (stream_id, event_index, status, attempts, next_attempt_at, created_at, updated_at)
+             VALUES (?1, ?2, 'pending', 0, ?3, ?3, ?3)
+             ON CONFLICT(stream_id, event_index) DO UPDATE
+                SET status = 'pending', attempts = 0, next_attempt_at = ?3, updated_at = ?3",
+            rusqlite::params![stream_id, index as i64, now],
+        ) {
+            error!("Failed to schedule webhook delivery for stream {stream_id}: {e}");
+        }
+    }
+
+    /// Make one attempt to deliver a pending event to the webhook and record its outcome.
+    ///
+    /// Does nothing if the event is not pending or is already being delivered.
+    pub async fn deliver_webhook(&self, stream_id: &str, index: u64) {
+        let key = (stream_id.to_string(), index);
+        if !self.delivering.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+        self.attempt_delivery(stream_id, index).await;
+        self.delivering.lock().unwrap().remove(&key);
+    }
+
+    async fn attempt_delivery(&self, stream_id: &str, index: u64) {
+        let event = {
+            let db = self.db.lock().await;
+            let status: Option<String> = db
+                .query_row(
+                    "SELECT status FROM webhook_deliveries WHERE stream_id = ?1 AND event_index = ?2",
+                    rusqlite::params![stream_id, index as i64],
+                    |row| row.get(0),
+                )
+                .optional()
+                .unwrap_or_else(|e| {
+                    error!("Failed to read webhook delivery for stream {stream_id}: {e}");
+                    None
+                });
+            if status.as_deref() != Some(DeliveryStatus::Pending.as_str()) {
+                return;
+            }
+
+            db.query_row(
+                "SELECT timestamp, data FROM stream_events WHERE stream_id = ?1 AND event_index = ?2",
+                rusqlite::params![stream_id, index as i64],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()
+            .unwrap_or_else(|e| {
+                error!("Failed to read event {index} of stream {stream_id}: {e}");
+                None
+            })
+        };
+
+        let started = Instant::now();
+        let result = match event {
+            Some((timestamp, data_json)) => match serde_json::from_str::<NotificationData>(&data_json) {
+                Ok(data) => {
+                    webhook::deliver(
+                        &self.http_client,
+                        &self.webhook_settings,
+                        stream_id,
+                        &data,
+                        index,
+                        &timestamp,
+                    )
+                    .await
+                }
+                Err(e) => Err(webhook::DeliveryError {
+                    status_code: None,
+                    message: format!("Failed to parse stored event: {e}"),
+                }),
+            },
+            None => Err(webhook::DeliveryError {
+                status_code: None,
+                message: "Event not found".to_string(),
+            }),
+        };
+        let duration_ms = started.elapsed().as_millis() as u64;
+
+        self.record_delivery_attempt(stream_id, index, result, duration_ms)
+            .await;
+    }
+
+    async fn record_delivery_attempt(
+        &self,
+        stream_id: &str,
+        index: u64,
+        result: Result<u16, webhook::DeliveryError>,
+        duration_ms: u64,
+    ) {
+        let now = chrono::Utc::now().timestamp();
+        let (status_code, error) = match &result {
+            Ok(status_code) => (Some(*status_code), None),
+            Err(e) => (e.status_code, Some(e.message.clone())),
+        };
+
+        let db = self.db.lock().await;
+        if let Err(e) = db.execute(
+            "INSERT INTO webhook_attempts
+                (stream_id, event_index, attempted_at, status_code, error, duration_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![stream_id, index as i64, now, status_code, error, duration_ms as i64],
+        ) {
+            error!("Failed to log webhook attempt for stream {stream_id}: {e}");
+        }
+
+        let attempts: u32 = db
+            .query_row(
+                "SELECT attempts FROM webhook_deliveries WHERE stream_id = ?1 AND event_index = ?2",
+                rusqlite::params![stream_id, index as i64],
+                |row| row.get(0),
+            )
+            .unwrap_or(0)
+            + 1;
+
+        let (status, next_attempt_at) = match &error {
+            None => {
+                debug!("Webhook delivered event {index} of stream {stream_id}");
+                (DeliveryStatus::Delivered, None)
+            }
+            Some(error) if attempts >= self.webhook_settings.max_attempts => {
+                error!(
+                    "Webhook delivery of event {index} of stream {stream_id} dead-lettered after {attempts} attempt(s): {error}"
+                );
+                (DeliveryStatus::DeadLetter, None)
+            }
+            Some(error) => {
+                let delay = webhook::retry_delay_secs(&self.webhook_settings, attempts);
+                warn!(
+                    "Webhook delivery of event {index} of stream {stream_id} failed (attempt {attempts}), retrying in {delay}s: {error}"
+                );
+                (DeliveryStatus::Pending, Some(now + delay as i64))
+            }
+        };
+
+        if let Err(e) = db.execute(
+            "UPDATE webhook_deliveries
+             SET status = ?1, attempts = ?2, next_attempt_at = ?3, last_error = ?4, updated_at = ?5
+             WHERE stream_id = ?6 AND event_index = ?7",
+            rusqlite::params![
+                status.as_str(),
+                attempts,
+                next_attempt_at,
+                error,
+                now,
+                stream_id,
+                index as i64
+            ],
+        ) {
+            error!("Failed to update webhook delivery for stream {stream_id}: {e}");
+        }
+    }
+
+    /// Pending deliveries whose backoff has elapsed, oldest first.
+    pub async fn due_webhook_deliveries(&self) -> Vec<(String, u64)> {
+        let now = chrono::Utc::now().timestamp();
+        let db = self.db.lock().await;
+        let mut stmt = match db.prepare(
+            "SELECT stream_id, event_index FROM webhook_deliveries
+             WHERE status = 'pending' AND next_attempt_at <= ?1
+             ORDER BY next_attempt_at ASC
+             LIMIT ?2",
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to prepare due webhook deliveries query: {e}");
+                return vec![];
+            }
+        };
+
+        let rows = stmt.query_map(rusqlite::params![now, MAX_DUE_DELIVERIES], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64))
+        });
+        match rows {
+            Ok(rows) => rows.filter_map(|r| r.ok()).collect(),
+            Err(e) => {
+                error!("Failed to query due webhook deliveries: {e}");
+                vec![]
+            }
+        }
+    }
+
+    /// List webhook deliveries, most recently updated first. Without a status filter, only the
+    /// ones that are not delivered yet are returned.
+    pub async fn list_webhook_deliveries(
+        &self,
+        status: Option<DeliveryStatus>,
+        stream_id: Option<&str>,
+    ) -> Vec<WebhookDelivery> {
+        let db = self.db.lock().await;
+        let mut stmt = match db.prepare(
+            "SELECT stream_id, event_index, status, attempts, next_attempt_at, last_error,
+                    created_at, updated_at
+             FROM webhook_deliveries
+             WHERE ((?1 IS NULL AND status != 'delivered') OR status = ?1)
+               AND (?2 IS NULL OR stream_id = ?2)
+             ORDER BY updated_at DESC",
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to prepare webhook deliveries query: {e}");
+                return vec![];
+            }
+        };
+
+        let rows = stmt.query_map(
+            rusqlite::params![status.map(|s| s.as_str()), stream_id],
+            Self::delivery_from_row,
+        );
+        match rows {
+            Ok(rows) => rows.filter_map(|r| r.ok()).collect(),
+            Err(e) => {
+                error!("Failed to query webhook deliveries: {e}");
+                vec![]
+            }
+        }
+    }
+
+    /// Get the webhook delivery of an event, with the log of its attempts.
+    pub async fn get_webhook_delivery(&self, stream_id: &str, index: u64) -> Option<WebhookDelivery> {
+        let db = self.db.lock().await;
+        let mut delivery = db
+            .query_row(
+                "SELECT stream_id, event_index, status, attempts, next_attempt_at, last_error,
+                        created_at, updated_at
+                 FROM webhook_deliveries
+                 WHERE stream_id = ?1 AND event_index = ?2",
+                rusqlite::params![stream_id, index as i64],
+                Self::delivery_from_row,
+            )
+            .optional()
+            .unwrap_or_else(|e| {
+                error!("Failed to read webhook delivery for stream {stream_id}: {e}");
+                None
+            })?;
+
+        let mut stmt = match db.prepare(
+            "SELECT attempted_at, status_code, error, duration_ms FROM webhook_attempts
+             WHERE stream_id = ?1 AND event_index = ?2
+             ORDER BY id ASC",
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to prepare webhook attempts query: {e}");
+                return Some(delivery);
+            }
+        };
+        let attempts = stmt.query_map(rusqlite::params![stream_id, index as i64], |row| {
+            Ok(DeliveryAttempt {
+                attempted_at: row.get(0)?,
+                status_code: row.get(1)?,
+                error: row.get(2)?,
+                duration_ms: row.get::<_, i64>(3)? as u64,
+            })
+        });
+        match attempts {
+            Ok(attempts) => delivery.attempt_log = attempts.filter_map(|r| r.ok()).collect(),
+            Err(e) => error!("Failed to query webhook attempts for stream {stream_id}: {e}"),
+        }
+
+        Some(delivery)
+    }
+
+    fn delivery_from_row(row: &rusqlite::Row) -> rusqlite::Result<WebhookDelivery> {
+        let status: String = row.get(2)?;
+        Ok(WebhookDelivery {
+            stream_id: row.get(0)?,
+            index: row.get::<_, i64>(1)? as u64,
+            status: DeliveryStatus::from_str(&status).unwrap_or(DeliveryStatus::Pending),
+            attempts: row.get(3)?,
+            next_attempt_at: row.get(4)?,
+            last_error: row.get(5)?,
+            created_at: row.get(6)?,
+            updated_at: row.get(7)?,
+            attempt_log: vec![],
+        })
+    }
+
+    /// Deliver again the events of a stream with an index between `from_index` and `to_index`
+    /// (inclusive), whatever the state of their previous delivery. Returns the replayed
+    /// indexes.
+    pub async fn replay_webhooks(
+        &self,
+        stream_id: &str,
+        from_index: u64,
+        to_index: Option<u64>,
+    ) -> Vec<u64> {
+        let indexes: Vec<u64> = {
+            let now = chrono::Utc::now().timestamp();
+            let db = self.db.lock().await;
+            let mut stmt = match db.prepare(
+                "SELECT event_index FROM stream_events
+                 WHERE stream_id = ?1 AND event_index >= ?2 AND (?3 IS NULL OR event_index <= ?3)
+                 ORDER BY event_index ASC",
+            ) {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("Failed to prepare replay query: {e}");
+                    return vec![];
+                }
+            };
+            let indexes: Vec<u64> = match stmt.query_map(
+                rusqlite::params![stream_id, from_index as i64, to_index.map(|i| i as i64)],
+                |row| row.get::<_, i64>(0),
+            ) {
+                Ok(rows) => rows.filter_map(|r| r.ok()).map(|i| i as u64).collect(),
+                Err(e) => {
+                    error!("Failed to query events to replay for stream {stream_id}: {e}");
+                    return vec![];
+                }
+            };
+
+            for index in &indexes {
+                Self::schedule_delivery(&db, stream_id, *index, now);
+            }
+            indexes
+        };
+
+        if !indexes.is_empty() {
+            info!("Replaying {} webhook delivery(ies) of stream {stream_id}", indexes.len());
+            let events = self.clone();
+            let sid = stream_id.to_string();
+            let replayed = indexes.clone();
+            tokio::spawn(async move {
+                // In order, so receivers get the events of the stream as they were pushed
+                for index in replayed {
+                    events.deliver_webhook(&sid, index).await;
+                }
+            });
+        }
+
+        indexes
+    }
+
+    /// Remember the request events of a stream that no relay accepted, so that their later
+    /// delivery (or drop) can be reported on the stream.
+    pub async fn track_queued_events(&self, stream_id: &str, delivery: &[EventSendResult]) {
+        let queued = delivery
+            .iter()
+            .filter(|result| result.outcome == SendOutcome::Queued);
+
+        let db = self.db.lock().await;
+        for result in queued {
+            if let Err(e) = db.execute(
+                "INSERT OR REPLACE INTO queued_events (event_id, stream_id) VALUES (?1, ?2)",
+                rusqlite::params![result.event_id.to_hex(), stream_id],
+            ) {
+                error!("Failed to track queued event for stream {stream_id}: {e}");
+            }
+        }
+    }
+
+    /// Stop tracking a queued event, returning the stream it belongs to.
+    pub async fn take_queued_event(&self, event_id: &str) -> Option<String> {
+        let db = self.db.lock().await;
+        let stream_id: String = db
+            .query_row(
+                "SELECT stream_id FROM queued_events WHERE event_id = ?1",
+                rusqlite::params![event_id],
+                |row| row.get(0),
+            )
+            .ok()?;
+
+        if let Err(e) = db.execute(
+            "DELETE FROM queued_events WHERE event_id = ?1",
+            rusqlite::params![event_id],
+        ) {
+            error!("Failed to untrack queued event {event_id}: {e}");
+        }
+
+        Some(stream_id)
+    }
+
+    /// Claim an idempotency key of `caller` (an API key ID, or empty for the auth token) for a
+    /// request with `fingerprint`. Keys expire after a day.
+    pub async fn claim_idempotency_key(
+        &self,
+        key: &str,
+        caller: &str,
+        fingerprint: &str,
+    ) -> anyhow::Result<IdempotencyClaim> {
+        let now = chrono::Utc::now().timestamp();
+        let db = self.db.lock().await;
+        db.execute(
+            "DELETE FROM idempotency_keys WHERE created_at < ?1",
+            rusqlite::params![now - IDEMPOTENCY_KEY_TTL_SECS],
+        )?;
+
+        let inserted = db.execute(
+            "INSERT INTO idempotency_keys (idempotency_key, caller, fingerprint, created_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(idempotency_key, caller) DO NOTHING",
+            rusqlite::params![key, caller, fingerprint, now],
+        )?;
+        if inserted > 0 {
+            return Ok(IdempotencyClaim::Claimed);
+        }
+
+        let (stored_fingerprint, status_code, response): (String, Option<u16>, Option<Vec<u8>>) =
+            db.query_row(
+                "SELECT fingerprint, status_code, response FROM idempotency_keys
+                 WHERE idempotency_key = ?1 AND caller = ?2",
+                rusqlite::params![key, caller],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?;
+        Ok(match (status_code, response) {
+            _ if stored_fingerprint != fingerprint => IdempotencyClaim::Mismatch,
+            (Some(status_code), Some(response)) => IdempotencyClaim::Completed {
+                status_code,
+                response,
+            },
+            _ => IdempotencyClaim::InProgress,
+        })
+    }
+
+    /// Save the response to the request of a claimed idempotency key, returned to its retries.
+    pub async fn complete_idempotency_key(
+        &self,
+        key: &str,
+        caller: &str,
+        status_code: u16,
+        response: &[u8],
+    ) {
+        let db = self.db.lock().await;
+        if let Err(e) = db.execute(
+            "UPDATE idempotency_keys SET status_code = ?1, response = ?2
+             WHERE idempotency_key = ?3 AND caller = ?4",
+            rusqlite::params![status_code, response, key, caller],
+        ) {
+            error!("Failed to save the response of idempotency key {key}: {e}");
+        }
+    }
+
+    /// Release a claimed idempotency key, so that the request can be retried.
+    pub async fn release_idempotency_key(&self, key: &str, caller: &str) {
+        let db = self.db.lock().await;
+        if let Err(e) = db.execute(
+            "DELETE FROM idempotency_keys WHERE idempotency_key = ?1 AND caller = ?2",
+            rusqlite::params![key, caller],
+        ) {
+            error!("Failed to release idempotency key {key}: {e}");
+        }
+    }
+
     /// Get events for a stream, optionally filtering to those with index > after.
     pub async fn get(&self, stream_id: &str, after: Option<u64>) -> Vec<StreamEvent> {
         let db = self.db.lock().await;
@@ -263,6 +829,71 @@ impl EventStore {
             .collect()
     }
 
+    /// Subscribe to the events of all the streams, as they are pushed. Events missed by a
+    /// lagging receiver can be read back with [`EventStore::get_all`].
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveEvent> {
+        self.live.subscribe()
+    }
+
+    /// Get up to `limit` events of all the streams with a sequence number > after, oldest first.
+    pub async fn get_all(&self, after: u64, limit: u64) -> Vec<LiveEvent> {
+        let db = self.db.lock().await;
+        let mut stmt = match db.prepare(
+            "SELECT id, stream_id, event_index, timestamp, data FROM stream_events
+             WHERE id > ?1
+             ORDER BY id ASC
+             LIMIT ?2",
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to prepare get all events query: {e}");
+                return vec![];
+            }
+        };
+
+        let rows = stmt.query_map(rusqlite::params![after as i64, limit as i64], |row| {
+            Ok((
+                row.get::<_, u64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, u64>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+            ))
+        });
+        match rows {
+            Ok(rows) => rows
+                .filter_map(|r| {
+                    let (seq, stream_id, index, timestamp, data_json) = r.ok()?;
+                    let data: NotificationData = serde_json::from_str(&data_json).ok()?;
+                    Some(LiveEvent {
+                        seq,
+                        stream_id,
+                        event: StreamEvent {
+                            index,
+                            timestamp,
+                            data,
+                        },
+                    })
+                })
+                .collect(),
+            Err(e) => {
+                error!("Failed to query all events: {e}");
+                vec![]
+            }
+        }
+    }
+
+    /// Sequence number of the last event pushed to any stream, 0 if there is none.
+    pub async fn last_seq(&self) -> u64 {
+        let db = self.db.lock().await;
+        db.query_row(
+            "SELECT COALESCE(MAX(id), 0) FROM stream_events",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap_or(0)
+    }
+
     /// Check if a stream exists.
     pub async fn exists(&self, stream_id: &str) -> bool {
         let db = self.db.lock().await;
@@ -274,6 +905,21 @@ impl EventStore {
         .is_ok()
     }
 
+    /// The API key that created a stream, `None` if it was created with the auth token or by
+    /// the daemon.
+    pub async fn created_by(&self, stream_id: &str) -> Option<String> {
+        let db = self.db.lock().await;
+        db.query_row(
+            "SELECT api_key_id FROM streams WHERE stream_id = ?1",
+            rusqlite::params![stream_id],
+            |row| row.get(0),
+        )
+        .optional()
+        .ok()
+        .flatten()
+        .flatten()
+    }
+
     /// Update the status of a stream.
     pub async fn update_stream_status(&self, stream_id: &str, status: StreamStatus) {
         let now = chrono::Utc::now().timestamp();
@@ -346,8 +992,251 @@ impl EventStore {
             NotificationData::RecurringPaymentResponse { .. } => Some(StreamStatus::Completed),
             NotificationData::Error { .. } => Some(StreamStatus::Failed),
             // Key handshake and recurring close events don't have a terminal state
-            // they just keep streaming until the connection ends
+            // they just keep streaming until the connection ends. Subscription events are
+            // pushed to recurring payment streams, which are already completed
             _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::future::Future;
+
+    use axum::http::StatusCode;
+
+    fn store() -> EventStore {
+        EventStore::new(":memory:", WebhookSettings::default()).unwrap()
+    }
+
+    /// A webhook endpoint answering `status`, returning its URL and the indexes of the events
+    /// it received
+    fn webhook_endpoint(status: StatusCode) -> (String, Arc<std::sync::Mutex<Vec<u64>>>) {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::post({
+                let received = received.clone();
+                move |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                    received
+                        .lock()
+                        .unwrap()
+                        .push(body["index"].as_u64().unwrap());
+                    status
+                }
+            }),
+        );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        (url, received)
+    }
+
+    fn webhook_store(url: String, max_attempts: u32) -> EventStore {
+        let settings = WebhookSettings {
+            url: Some(url),
+            max_attempts,
+            retry_base_delay_secs: 0,
+            ..Default::default()
+        };
+        EventStore::new(":memory:", settings).unwrap()
+    }
+
+    /// Polls `condition` for up to 5 seconds
+    async fn wait_until<F, Fut>(mut condition: F) -> bool
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        for _ in 0..500 {
+            if condition().await {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    fn error_event() -> NotificationData {
+        NotificationData::Error {
+            reason: "test".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_claim_and_replay() {
+        let store = store();
+
+        let claim = store
+            .claim_idempotency_key("key", "", "request")
+            .await
+            .unwrap();
+        assert!(matches!(claim, IdempotencyClaim::Claimed));
+
+        // A retry while the request runs
+        let claim = store
+            .claim_idempotency_key("key", "", "request")
+            .await
+            .unwrap();
+        assert!(matches!(claim, IdempotencyClaim::InProgress));
+
+        store
+            .complete_idempotency_key("key", "", 200, b"{\"success\":true}")
+            .await;
+        let claim = store
+            .claim_idempotency_key("key", "", "request")
+            .await
+            .unwrap();
+        match claim {
+            IdempotencyClaim::Completed {
+                status_code,
+                response,
+            } => {
+                assert_eq!(status_code, 200);
+                assert_eq!(response, b"{\"success\":true}");
+            }
+            claim => panic!("expected the saved response, got {claim:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_mismatch() {
+        let store = store();
+
+        store
+            .claim_idempotency_key("key", "", "request")
+            .await
+            .unwrap();
+        let claim = store
+            .claim_idempotency_key("key", "", "other")
+            .await
+            .unwrap();
+        assert!(matches!(claim, IdempotencyClaim::Mismatch));
+
+        store.complete_idempotency_key("key", "", 200, b"{}").await;
+        let claim = store
+            .claim_idempotency_key("key", "", "other")
+            .await
+            .unwrap();
+        assert!(matches!(claim, IdempotencyClaim::Mismatch));
+
+        // Keys are per caller
+        let claim = store
+            .claim_idempotency_key("key", "api-key", "other")
+            .await
+            .unwrap();
+        assert!(matches!(claim, IdempotencyClaim::Claimed));
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_release() {
+        let store = store();
+
+        store
+            .claim_idempotency_key("key", "", "request")
+            .await
+            .unwrap();
+        store.release_idempotency_key("key", "").await;
+
+        let claim = store
+            .claim_idempotency_key("key", "", "request")
+            .await
+            .unwrap();
+        assert!(matches!(claim, IdempotencyClaim::Claimed));
+    }
+
+    #[tokio::test]
+    async fn test_webhook_delivery_is_dead_lettered_after_max_attempts() {
+        let (url, received) = webhook_endpoint(StatusCode::INTERNAL_SERVER_ERROR);
+        let store = &webhook_store(url, 3);
+
+        let index = store.push("stream", error_event()).await;
+
+        // Retries are due right away, the way `webhook::run_retries` would make them
+        let dead_lettered = wait_until(|| async move {
+            store.deliver_webhook("stream", index).await;
+            let delivery = store.get_webhook_delivery("stream", index).await.unwrap();
+            delivery.status == DeliveryStatus::DeadLetter
+        })
+        .await;
+        assert!(dead_lettered);
+
+        let delivery = store.get_webhook_delivery("stream", index).await.unwrap();
+        assert_eq!(delivery.attempts, 3);
+        assert_eq!(delivery.next_attempt_at, None);
+        assert_eq!(
+            delivery.last_error.as_deref(),
+            Some("Webhook returned HTTP 500 Internal Server Error")
+        );
+        assert_eq!(delivery.attempt_log.len(), 3);
+        assert!(delivery
+            .attempt_log
+            .iter()
+            .all(|attempt| attempt.status_code == Some(500)));
+        assert_eq!(*received.lock().unwrap(), vec![index; 3]);
+
+        // Dead-lettered deliveries are not retried anymore
+        assert!(store.due_webhook_deliveries().await.is_empty());
+        store.deliver_webhook("stream", index).await;
+        assert_eq!(received.lock().unwrap().len(), 3);
+        assert_eq!(
+            store
+                .list_webhook_deliveries(Some(DeliveryStatus::DeadLetter), None)
+                .await
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_webhook_replay_from_index() {
+        let (url, received) = webhook_endpoint(StatusCode::OK);
+        let received = &*received;
+        let store = &webhook_store(url, 3);
+
+        for _ in 0..3 {
+            store.push("stream", error_event()).await;
+        }
+        let delivered = wait_until(|| async move {
+            store
+                .list_webhook_deliveries(Some(DeliveryStatus::Delivered), Some("stream"))
+                .await
+                .len()
+                == 3
+        })
+        .await;
+        assert!(delivered);
+        received.lock().unwrap().clear();
+
+        // The events from index 1 are delivered again, in order
+        assert_eq!(store.replay_webhooks("stream", 1, None).await, vec![1, 2]);
+        let replayed = wait_until(|| async move { received.lock().unwrap().len() == 2 }).await;
+        assert!(replayed);
+        assert_eq!(*received.lock().unwrap(), vec![1, 2]);
+
+        let redelivered = wait_until(|| async move {
+            let delivery = store.get_webhook_delivery("stream", 2).await.unwrap();
+            delivery.status == DeliveryStatus::Delivered
+        })
+        .await;
+        assert!(redelivered);
+        // Attempts start over when replayed, the log keeps them all
+        let delivery = store.get_webhook_delivery("stream", 2).await.unwrap();
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.attempt_log.len(), 2);
+        let delivery = store.get_webhook_delivery("stream", 0).await.unwrap();
+        assert_eq!(delivery.attempt_log.len(), 1);
+
+        // Bounded on both ends
+        assert_eq!(store.replay_webhooks("stream", 0, Some(0)).await, vec![0]);
+        assert!(store.replay_webhooks("stream", 3, None).await.is_empty());
+        assert!(store.replay_webhooks("other", 0, None).await.is_empty());
+    }
+}
@@ -2,8 +2,10 @@ use lightning_invoice::Bolt11Invoice;
 use std::str::FromStr;
 use std::sync::Arc;
 
-use axum::extract::{Path, Query, State};
-use axum::http::StatusCode;
+use axum::extract::ws::WebSocketUpgrade;
+use axum::extract::{Extension, Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::Json;
 use cdk::amount::SplitTarget;
 use cdk::mint_url::MintUrl;
@@ -13,25 +15,48 @@ use cdk_sqlite::wallet::memory;
 use chrono::Duration;
 #[allow(unused_imports)]
 use futures::StreamExt;
+use portal::nostr::event::EventId;
 use portal::nostr::key::PublicKey;
-use portal::nostr_relay_pool::RelayOptions;
+use portal::nostr_relay_pool::{RelayOptions, RelayPool};
+use portal::conversation::sdk::auth::{AuthResponseEvent, KeyHandshakeEvent};
 use portal::protocol::calendar::Calendar;
-use portal::protocol::jwt::CustomClaims;
+use portal::protocol::identity::RevealError;
+use portal::protocol::issuance::IssuanceError;
+use portal::protocol::jwt::{CustomClaims, DecodeOptions};
+use portal::protocol::model::auth::Permission;
+use portal::protocol::model::identity::{CertificateRequestContent, CertificateResponseContent};
 use portal::protocol::model::payment::{
-    Amount, CashuDirectContent, CashuRequestContent, Currency, ExchangeRate,
-    InvoiceRequestContent, PaymentStatus, RecurringPaymentRequestContent,
-    SinglePaymentRequestContent,
+    Amount, CashuDirectContent, CashuRequestContent, CashuResponseContent, Currency,
+    ExchangeRate, InvoiceRequestContent, InvoiceResponse, PaymentResponseContent, PaymentStatus,
+    RecurringPaymentRequestContent, RecurringPaymentResponseContent, SinglePaymentRequestContent,
 };
 use portal::protocol::model::Timestamp;
+use portal::protocol::revocation::CertificateStatus;
+use portal::router::{EventSendResult, NotificationStream};
 use portal::utils::fetch_nip05_profile as portal_fetch_nip05;
+use portal_sdk::PortalSDKError;
+use portal_wallet::{
+    InvoiceRequest, MockInvoiceStatus, MockWallet, PaymentDirection, PaymentsQuery,
+    PortalWalletError,
+};
 use rand::RngCore;
 use serde::Deserialize;
+use tokio::sync::broadcast;
 use tracing::{debug, error, warn};
 use uuid::Uuid;
 
+use crate::api_keys::{ApiKey, Caller, Scope};
+use crate::billing::{Billing, SubscriptionRequest, SubscriptionStatus};
 use crate::command::*;
-use crate::events::StreamMetadata;
+use crate::events::{EventStore, StreamMetadata};
+use crate::ledger::{
+    self, LedgerDirection, LedgerEntry, LedgerKind, LedgerQuery, LedgerStatus, LedgerUpdate,
+    PaymentLedger,
+};
+use crate::live;
 use crate::response::*;
+use crate::settlements::SettlementWatcher;
+use crate::webhook::{DeliveryStatus, WebhookDelivery};
 use crate::AppState;
 
 // ---- Helper types ----
@@ -62,17 +87,32 @@ fn not_found(msg: impl Into<String>) -> (StatusCode, Json<ApiResponse<()>>) {
     err(StatusCode::NOT_FOUND, msg)
 }
 
+/// `403` for requests the user didn't grant the permission for, `500` for other SDK errors.
+fn request_error(context: &str, e: PortalSDKError) -> (StatusCode, Json<ApiResponse<()>>) {
+    match e {
+        PortalSDKError::PermissionDenied(_) => err(StatusCode::FORBIDDEN, format!("{context}: {e}")),
+        e => internal_error(format!("{context}: {e}")),
+    }
+}
+
+/// Record a payment in the ledger. A failure is only logged: the payment went through.
+pub async fn record_in_ledger(state: &AppState, entry: &LedgerEntry) {
+    if let Err(e) = state.ledger.record(entry).await {
+        error!("Failed to record ledger entry {}: {e}", entry.id);
+    }
+}
+
 fn hex_to_pubkey(hex: &str) -> Result<PublicKey, String> {
     hex.parse::<PublicKey>().map_err(|e| e.to_string())
 }
 
-fn parse_subkeys(subkeys: &[String]) -> Result<Vec<PublicKey>, String> {
+pub fn parse_subkeys(subkeys: &[String]) -> Result<Vec<PublicKey>, String> {
     subkeys.iter().map(|s| hex_to_pubkey(s)).collect()
 }
 
 /// Resolve amount and exchange rate: for Millisats returns (amount, None);
 /// for Fiat fetches market data and returns (amount_msat, Some(ExchangeRate)).
-async fn resolve_amount_and_exchange_rate(
+pub async fn resolve_amount_and_exchange_rate(
     amount: Amount,
     currency: &Currency,
     market_api: Arc<portal_rates::MarketAPI>,
@@ -119,41 +159,320 @@ async fn get_cashu_wallet(
     Ok(wallet)
 }
 
-// ---- Shared helpers ----
+// ---- Conversation forwarding ----
+//
+// The SDK conversations behind these streams are saved in the conversation store, so the
+// same functions are used to forward their notifications after a restart.
+
+/// Create the stream of a request sent to the user, remembering which of its events are
+/// queued so that [`forward_delivery_updates`] can report on them.
+async fn create_request_stream(
+    events: &EventStore,
+    stream_id: &str,
+    stream_type: &str,
+    metadata: Option<&StreamMetadata>,
+    delivery: &[EventSendResult],
+    api_key_id: Option<&str>,
+) {
+    events
+        .create_stream(stream_id, stream_type, metadata, api_key_id)
+        .await;
+    events.track_queued_events(stream_id, delivery).await;
+}
+
+/// Push the delivery updates of queued request events to the streams they belong to.
+pub async fn forward_delivery_updates(
+    events: EventStore,
+    mut updates: broadcast::Receiver<EventSendResult>,
+) {
+    loop {
+        let update = match updates.recv().await {
+            Ok(update) => update,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Missed {skipped} relay delivery update(s)");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        let event_id = update.event_id.to_hex();
+        if let Some(stream_id) = events.take_queued_event(&event_id).await {
+            events
+                .push(
+                    &stream_id,
+                    NotificationData::RelayDelivery {
+                        event_id,
+                        outcome: update.outcome,
+                    },
+                )
+                .await;
+        }
+    }
+}
+
+/// Wait for the first notification of a conversation and push it to the stream.
+pub async fn forward_first_notification<T, F>(
+    events: EventStore,
+    stream_id: String,
+    mut stream: NotificationStream<T>,
+    to_notification: F,
+) where
+    T: serde::Serialize,
+    F: FnOnce(Result<T, PortalSDKError>) -> NotificationData,
+{
+    let result = next_notification(&mut stream).await;
+    events.push(&stream_id, to_notification(result)).await;
+}
+
+async fn next_notification<T>(stream: &mut NotificationStream<T>) -> Result<T, PortalSDKError>
+where
+    T: serde::Serialize,
+{
+    match stream.next().await {
+        Some(Ok(notification)) => Ok(notification),
+        Some(Err(e)) => Err(PortalSDKError::Deserialization(e)),
+        None => Err(PortalSDKError::Timeout),
+    }
+}
 
-/// Poll a Lightning invoice until it is paid, times out, or errors.
-/// Pushes a `PaymentStatusUpdate` notification to the event store when done.
-pub async fn monitor_invoice_until_paid(
-    wallet: Arc<dyn portal_wallet::PortalWallet>,
-    events: crate::events::EventStore,
+/// Wait for the auth response, open the user session if approved and push the response to
+/// the stream.
+///
+/// The permissions granted by the user are saved, so they are still enforced after a restart.
+pub async fn forward_authentication(
+    state: AppState,
     stream_id: String,
-    invoice: String,
-    expires_at: portal::protocol::model::Timestamp,
+    mut stream: NotificationStream<AuthResponseEvent>,
 ) {
-    let notification = loop {
-        if portal::protocol::model::Timestamp::now() > expires_at {
-            break NotificationData::PaymentStatusUpdate {
-                status: InvoiceStatus::Timeout,
-            };
+    let result = match next_notification(&mut stream).await {
+        Ok(event) => {
+            state
+                .sdk
+                .complete_authentication(event, state.session_duration())
+                .await
         }
-        match wallet.is_invoice_paid(invoice.clone()).await {
-            Ok((true, preimage)) => {
-                break NotificationData::PaymentStatusUpdate {
-                    status: InvoiceStatus::Paid { preimage },
-                };
+        Err(e) => Err(e),
+    };
+
+    if let Ok(event) = &result {
+        if let Some(granted) = state.sdk.granted_permissions(&event.user_key) {
+            if let Err(e) = state
+                .sessions
+                .save_granted_permissions(&event.user_key.to_string(), &granted)
+                .await
+            {
+                error!("Failed to save the permissions granted by {}: {e}", event.user_key);
             }
-            Ok((false, _)) => {
-                tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+        }
+    }
+
+    state
+        .events
+        .push(&stream_id, authenticate_key_notification(result))
+        .await;
+}
+
+pub async fn forward_key_handshakes(
+    events: EventStore,
+    relay_pool: Arc<RelayPool>,
+    stream_id: String,
+    mut stream: NotificationStream<KeyHandshakeEvent>,
+) {
+    while let Some(Ok(event)) = stream.next().await {
+        debug!("Got key handshake event: {:?}", event);
+
+        let preferred_relays = event.relays.clone();
+        for relay in &preferred_relays {
+            match relay_pool.add_relay(relay, RelayOptions::default()).await {
+                Ok(false) => continue,
+                Err(e) => {
+                    warn!("Failed to add relay {relay}: {e}");
+                    continue;
+                }
+                _ => {}
             }
-            Err(e) => {
-                tracing::error!("Failed to check invoice for stream {stream_id}: {e}");
-                break NotificationData::PaymentStatusUpdate {
-                    status: InvoiceStatus::Error { reason: e.to_string() },
+            if let Err(e) = relay_pool.connect_relay(relay).await {
+                warn!("Failed to connect to relay {relay}: {e}");
+                continue;
+            }
+        }
+
+        events
+            .push(
+                &stream_id,
+                NotificationData::KeyHandshake {
+                    main_key: event.main_key.to_string(),
+                    preferred_relays,
+                },
+            )
+            .await;
+    }
+    debug!("Key handshake stream ended for {stream_id}");
+}
+
+/// Push the payment status updates sent by the user until a final one, recording them in the
+/// ledger.
+///
+/// If `monitor` is set, the invoice is watched after the first non-final update.
+pub async fn forward_payment_statuses(
+    events: EventStore,
+    ledger: PaymentLedger,
+    stream_id: String,
+    mut notifications: NotificationStream<PaymentResponseContent>,
+    mut monitor: Option<(SettlementWatcher, String, Timestamp)>,
+) {
+    while let Some(notification) = notifications.next().await {
+        match notification {
+            Ok(response) => {
+                let is_final = response.status.is_final();
+                let status = match response.status {
+                    PaymentStatus::Failed { reason } => InvoiceStatus::UserFailed { reason },
+                    PaymentStatus::Rejected { reason } => InvoiceStatus::UserRejected { reason },
+                    PaymentStatus::Success { preimage } => InvoiceStatus::UserSuccess { preimage },
+                    PaymentStatus::Approved => InvoiceStatus::UserApproved,
                 };
+                if let Err(e) = ledger
+                    .update_stream(&stream_id, LedgerUpdate::from_invoice_status(&status))
+                    .await
+                {
+                    error!("Failed to update the ledger entry of stream {stream_id}: {e}");
+                }
+                events
+                    .push(&stream_id, NotificationData::PaymentStatusUpdate { status })
+                    .await;
+
+                if is_final {
+                    return;
+                }
+
+                // Start invoice monitoring
+                if let Some((watcher, invoice, expires_at)) = monitor.take() {
+                    watcher.watch(stream_id.clone(), invoice, expires_at).await;
+                }
+            }
+            Err(e) => {
+                error!("Payment notification error: {e}");
+            }
+        }
+    }
+}
+
+pub fn authenticate_key_notification(
+    result: Result<AuthResponseEvent, PortalSDKError>,
+) -> NotificationData {
+    match result {
+        Ok(event) => NotificationData::AuthenticateKey {
+            user_key: event.user_key.to_string(),
+            recipient: event.recipient.to_string(),
+            challenge: event.challenge,
+            status: event.status,
+        },
+        Err(e) => NotificationData::Error {
+            reason: format!("Failed to authenticate key: {e}"),
+        },
+    }
+}
+
+pub fn recurring_payment_notification(
+    result: Result<RecurringPaymentResponseContent, PortalSDKError>,
+) -> NotificationData {
+    match result {
+        Ok(status) => NotificationData::RecurringPaymentResponse { status },
+        Err(e) => NotificationData::Error {
+            reason: format!("Failed to request recurring payment: {e}"),
+        },
+    }
+}
+
+/// Like [`forward_first_notification`] for a recurring payment request, also handing the
+/// subscription to the billing engine if the user confirmed it.
+pub async fn forward_recurring_payment(
+    events: EventStore,
+    billing: Option<Billing>,
+    stream_id: String,
+    request: SubscriptionRequest,
+    mut stream: NotificationStream<RecurringPaymentResponseContent>,
+) {
+    let result = next_notification(&mut stream).await;
+    if let (Some(billing), Ok(response)) = (&billing, &result) {
+        billing.register(&stream_id, request, response).await;
+    }
+    events
+        .push(&stream_id, recurring_payment_notification(result))
+        .await;
+}
+
+/// Check that the invoice returned by the recipient is for the requested amount.
+pub fn invoice_notification(
+    expected_msat: u64,
+    result: Result<InvoiceResponse, PortalSDKError>,
+) -> NotificationData {
+    let resp = match result {
+        Ok(resp) => resp,
+        Err(e) => {
+            return NotificationData::Error {
+                reason: format!("Failed to request invoice: {e}"),
+            }
+        }
+    };
+
+    let invoice_amount_msat = match extract_invoice_amount_msat(&resp.invoice) {
+        Ok(Some(amt)) => amt,
+        Ok(None) => {
+            return NotificationData::Error {
+                reason: "Invoice has no amount (zero-amount invoice not allowed)".to_string(),
+            }
+        }
+        Err(e) => {
+            return NotificationData::Error {
+                reason: format!("Invalid invoice: {e}"),
             }
         }
     };
-    events.push(&stream_id, notification).await;
+
+    let amount_diff = (invoice_amount_msat as i128 - expected_msat as i128).abs();
+    if amount_diff > 1 {
+        return NotificationData::Error {
+            reason: format!(
+                "Invoice amount mismatch: got {invoice_amount_msat} msat, expected {expected_msat} msat (diff: {amount_diff} msat)"
+            ),
+        };
+    }
+
+    NotificationData::InvoiceResponse {
+        invoice: resp.invoice,
+        payment_hash: resp.payment_hash.unwrap_or_default(),
+    }
+}
+
+pub fn cashu_notification(result: Result<CashuResponseContent, PortalSDKError>) -> NotificationData {
+    match result {
+        Ok(r) => NotificationData::CashuResponse { status: r.status },
+        Err(e) => NotificationData::Error {
+            reason: format!("Failed to request cashu: {e}"),
+        },
+    }
+}
+
+pub fn certificate_notification(
+    main_key: PublicKey,
+    trusted_issuers: Option<Vec<PublicKey>>,
+    result: Result<CertificateResponseContent, PortalSDKError>,
+) -> NotificationData {
+    match result {
+        Ok(response) => {
+            match verify_certificate_response(main_key, trusted_issuers.as_deref(), &response) {
+                Ok(certificates) => NotificationData::CertificateResponse {
+                    status: response.status,
+                    certificates,
+                },
+                Err(reason) => NotificationData::Error { reason },
+            }
+        }
+        Err(e) => NotificationData::Error {
+            reason: format!("Failed to request certificates: {e}"),
+        },
+    }
 }
 
 // ---- Route handlers ----
@@ -174,6 +493,7 @@ pub async fn info(
 ) -> ApiResult<InfoResponse> {
     Ok(ok(InfoResponse {
         public_key: state.public_key.clone(),
+        identity: state.settings.identity.clone(),
         version: crate::APP_VERSION,
         git_commit: crate::GIT_COMMIT,
     }))
@@ -207,56 +527,31 @@ pub async fn well_known_nostr_json(
 // POST /key-handshake
 pub async fn new_key_handshake_url(
     State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
     Json(req): Json<KeyHandshakeRequest>,
 ) -> ApiResult<KeyHandshakeUrlResponse> {
+    let stream_id = Uuid::new_v4().to_string();
     let (url, notification_stream) = state
         .sdk
-        .new_key_handshake_url(req.static_token, req.no_request)
+        .new_key_handshake_url_resumable(req.static_token, req.no_request, stream_id.clone())
         .await
         .map_err(|e| internal_error(format!("Failed to create key handshake URL: {e}")))?;
 
     let metadata = StreamMetadata::KeyHandshake {
         url: url.to_string(),
     };
-    let stream_id = state.events.new_stream("key_handshake", Some(&metadata)).await;
+    state
+        .events
+        .create_stream(&stream_id, "key_handshake", Some(&metadata), caller.key_id())
+        .await;
 
     // Spawn background task to collect notifications
-    let events = state.events.clone();
-    let relay_pool = state.sdk.relay_pool();
-    let sid = stream_id.clone();
-    tokio::spawn(async move {
-        let mut stream = notification_stream;
-        while let Some(Ok(event)) = stream.next().await {
-            debug!("Got key handshake event: {:?}", event);
-
-            let preferred_relays = event.relays.clone();
-            for relay in &preferred_relays {
-                match relay_pool.add_relay(relay, RelayOptions::default()).await {
-                    Ok(false) => continue,
-                    Err(e) => {
-                        warn!("Failed to add relay {relay}: {e}");
-                        continue;
-                    }
-                    _ => {}
-                }
-                if let Err(e) = relay_pool.connect_relay(relay).await {
-                    warn!("Failed to connect to relay {relay}: {e}");
-                    continue;
-                }
-            }
-
-            events
-                .push(
-                    &sid,
-                    NotificationData::KeyHandshake {
-                        main_key: event.main_key.to_string(),
-                        preferred_relays,
-                    },
-                )
-                .await;
-        }
-        debug!("Key handshake stream ended for {sid}");
-    });
+    tokio::spawn(forward_key_handshakes(
+        state.events.clone(),
+        state.sdk.relay_pool(),
+        stream_id.clone(),
+        notification_stream,
+    ));
 
     Ok(created(KeyHandshakeUrlResponse {
         url: url.to_string(),
@@ -267,50 +562,44 @@ pub async fn new_key_handshake_url(
 // POST /authenticate-key
 pub async fn authenticate_key(
     State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
     Json(req): Json<AuthenticateKeyRequest>,
 ) -> ApiResult<StreamResponse> {
     let main_key = hex_to_pubkey(&req.main_key).map_err(|e| bad_request(format!("Invalid main key: {e}")))?;
     let subkeys = parse_subkeys(&req.subkeys).map_err(|e| bad_request(format!("Invalid subkeys: {e}")))?;
+    let permissions = Permission::parse_all(&req.permissions)
+        .map_err(|e| bad_request(format!("Invalid permissions: {e}")))?;
 
-    let stream_id = state.events.new_stream("authenticate_key", None).await;
+    let stream_id = Uuid::new_v4().to_string();
+    let (notifications, delivery) = state
+        .sdk
+        .authenticate_key_resumable(main_key, subkeys, permissions, stream_id.clone())
+        .await
+        .map_err(|e| internal_error(format!("Failed to authenticate key: {e}")))?;
+
+    create_request_stream(
+        &state.events,
+        &stream_id,
+        "authenticate_key",
+        None,
+        &delivery,
+        caller.key_id(),
+    )
+    .await;
 
-    let sdk = state.sdk.clone();
-    let events = state.events.clone();
-    let sid = stream_id.clone();
-    tokio::spawn(async move {
-        match sdk.authenticate_key(main_key, subkeys).await {
-            Ok(event) => {
-                events
-                    .push(
-                        &sid,
-                        NotificationData::AuthenticateKey {
-                            user_key: event.user_key.to_string(),
-                            recipient: event.recipient.to_string(),
-                            challenge: event.challenge,
-                            status: event.status,
-                        },
-                    )
-                    .await;
-            }
-            Err(e) => {
-                events
-                    .push(
-                        &sid,
-                        NotificationData::Error {
-                            reason: format!("Failed to authenticate key: {e}"),
-                        },
-                    )
-                    .await;
-            }
-        }
-    });
+    tokio::spawn(forward_authentication(
+        state.clone(),
+        stream_id.clone(),
+        notifications,
+    ));
 
-    Ok(created(StreamResponse { stream_id }))
+    Ok(created(StreamResponse { stream_id, delivery }))
 }
 
 // POST /payments/recurring
 pub async fn request_recurring_payment(
     State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
     Json(req): Json<RequestRecurringPaymentRequest>,
 ) -> ApiResult<StreamResponse> {
     let main_key = hex_to_pubkey(&req.main_key).map_err(|e| bad_request(format!("Invalid main key: {e}")))?;
@@ -324,6 +613,12 @@ pub async fn request_recurring_payment(
     .await
     .map_err(|e| internal_error(format!("Failed to fetch market data: {e}")))?;
 
+    let subscription_request = SubscriptionRequest {
+        main_key: main_key.to_string(),
+        subkeys: subkeys.iter().map(|k| k.to_string()).collect(),
+        description: req.payment_request.description.clone(),
+        auth_token: req.payment_request.auth_token.clone(),
+    };
     let payment_request = RecurringPaymentRequestContent {
         description: req.payment_request.description,
         amount: Amount::new(req.payment_request.amount),
@@ -335,48 +630,45 @@ pub async fn request_recurring_payment(
         current_exchange_rate,
     };
 
-    let stream_id = state.events.new_stream("recurring_payment", None).await;
+    let stream_id = Uuid::new_v4().to_string();
+    let (notifications, delivery) = state
+        .sdk
+        .request_recurring_payment_resumable(main_key, subkeys, payment_request, stream_id.clone())
+        .await
+        .map_err(|e| request_error("Failed to request recurring payment", e))?;
+
+    let metadata = StreamMetadata::RecurringPayment(subscription_request.clone());
+    create_request_stream(
+        &state.events,
+        &stream_id,
+        "recurring_payment",
+        Some(&metadata),
+        &delivery,
+        caller.key_id(),
+    )
+    .await;
 
-    let sdk = state.sdk.clone();
-    let events = state.events.clone();
-    let sid = stream_id.clone();
-    tokio::spawn(async move {
-        match sdk
-            .request_recurring_payment(main_key, subkeys, payment_request)
-            .await
-        {
-            Ok(status) => {
-                events
-                    .push(
-                        &sid,
-                        NotificationData::RecurringPaymentResponse { status },
-                    )
-                    .await;
-            }
-            Err(e) => {
-                events
-                    .push(
-                        &sid,
-                        NotificationData::Error {
-                            reason: format!("Failed to request recurring payment: {e}"),
-                        },
-                    )
-                    .await;
-            }
-        }
-    });
+    tokio::spawn(forward_recurring_payment(
+        state.events.clone(),
+        state.billing.clone(),
+        stream_id.clone(),
+        subscription_request,
+        notifications,
+    ));
 
-    Ok(created(StreamResponse { stream_id }))
+    Ok(created(StreamResponse { stream_id, delivery }))
 }
 
 // POST /payments/single
 pub async fn request_single_payment(
     State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
     Json(req): Json<RequestSinglePaymentRequest>,
 ) -> ApiResult<SinglePaymentResponse> {
-    let wallet = state
+    let (wallet, settlements) = state
         .wallet
         .as_ref()
+        .zip(state.settlements.as_ref())
         .ok_or_else(|| bad_request("Backend wallet not available: set NWC_URL or BREEZ_MNEMONIC"))?;
 
     let main_key = hex_to_pubkey(&req.main_key).map_err(|e| bad_request(format!("Invalid main key: {e}")))?;
@@ -410,123 +702,89 @@ pub async fn request_single_payment(
         description: Some(req.payment_request.description),
     };
 
-    let mut notifications = state
+    let stream_id = Uuid::new_v4().to_string();
+    let mut entry = LedgerEntry::payment_request(
+        LedgerKind::SinglePayment,
+        main_key.to_string(),
+        &payment_request,
+    );
+    entry.stream_id = Some(stream_id.clone());
+
+    let (notifications, delivery) = state
         .sdk
-        .request_single_payment(main_key, subkeys, payment_request)
+        .request_single_payment_resumable(main_key, subkeys, payment_request, stream_id.clone())
         .await
-        .map_err(|e| internal_error(format!("Failed to request single payment: {e}")))?;
+        .map_err(|e| request_error("Failed to request single payment", e))?;
+    record_in_ledger(&state, &entry).await;
 
     let metadata = StreamMetadata::SinglePayment {
         invoice: invoice.clone(),
         expires_at_secs: expires_at.as_u64(),
     };
-    let stream_id = state.events.new_stream("single_payment", Some(&metadata)).await;
-
-    let events = state.events.clone();
-    let sid = stream_id.clone();
-    let wallet_clone = wallet.clone();
-    let invoice_clone = invoice;
-
-    tokio::spawn(async move {
-        let monitor_started = std::sync::atomic::AtomicBool::new(false);
-        while let Some(notification) = notifications.next().await {
-            match notification {
-                Ok(status) => {
-                    let notif_data = match &status.status {
-                        PaymentStatus::Failed { reason } => NotificationData::PaymentStatusUpdate {
-                            status: InvoiceStatus::UserFailed {
-                                reason: reason.clone(),
-                            },
-                        },
-                        PaymentStatus::Rejected { reason } => NotificationData::PaymentStatusUpdate {
-                            status: InvoiceStatus::UserRejected {
-                                reason: reason.clone(),
-                            },
-                        },
-                        PaymentStatus::Success { preimage } => NotificationData::PaymentStatusUpdate {
-                            status: InvoiceStatus::UserSuccess {
-                                preimage: preimage.clone(),
-                            },
-                        },
-                        PaymentStatus::Approved => NotificationData::PaymentStatusUpdate {
-                            status: InvoiceStatus::UserApproved,
-                        },
-                    };
-
-                    events.push(&sid, notif_data).await;
-
-                    if status.status.is_final() {
-                        return;
-                    }
-
-                    if monitor_started.swap(true, std::sync::atomic::Ordering::SeqCst) {
-                        continue;
-                    }
-
-                    // Start invoice monitoring
-                    let events2 = events.clone();
-                    let sid2 = sid.clone();
-                    let wallet2 = wallet_clone.clone();
-                    let inv2 = invoice_clone.clone();
-                    tokio::spawn(monitor_invoice_until_paid(wallet2, events2, sid2, inv2, expires_at));
-                }
-                Err(e) => {
-                    error!("Payment notification error: {e}");
-                }
-            }
-        }
-    });
+    create_request_stream(
+        &state.events,
+        &stream_id,
+        "single_payment",
+        Some(&metadata),
+        &delivery,
+        caller.key_id(),
+    )
+    .await;
 
-    Ok(created(SinglePaymentResponse { stream_id }))
+    tokio::spawn(forward_payment_statuses(
+        state.events.clone(),
+        state.ledger.clone(),
+        stream_id.clone(),
+        notifications,
+        Some((settlements.clone(), invoice, expires_at)),
+    ));
+
+    Ok(created(SinglePaymentResponse { stream_id, delivery }))
 }
 
 // POST /payments/raw
 pub async fn request_payment_raw(
     State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
     Json(req): Json<RequestPaymentRawRequest>,
 ) -> ApiResult<SinglePaymentResponse> {
     let main_key = hex_to_pubkey(&req.main_key).map_err(|e| bad_request(format!("Invalid main key: {e}")))?;
     let subkeys = parse_subkeys(&req.subkeys).map_err(|e| bad_request(format!("Invalid subkeys: {e}")))?;
 
-    let mut notifications = state
+    let stream_id = Uuid::new_v4().to_string();
+    let mut entry = LedgerEntry::payment_request(
+        LedgerKind::RawPayment,
+        main_key.to_string(),
+        &req.payment_request,
+    );
+    entry.stream_id = Some(stream_id.clone());
+
+    let (notifications, delivery) = state
         .sdk
-        .request_single_payment(main_key, subkeys, req.payment_request)
-        .await
-        .map_err(|e| internal_error(format!("Failed to request payment: {e}")))?;
-
-    let stream_id = state.events.new_stream("raw_payment", None).await;
-
-    let events = state.events.clone();
-    let sid = stream_id.clone();
-
-    tokio::spawn(async move {
-        while let Some(notification) = notifications.next().await {
-            match notification {
-                Ok(status) => {
-                    let notif_data = match status.status {
-                        PaymentStatus::Failed { reason } => NotificationData::PaymentStatusUpdate {
-                            status: InvoiceStatus::UserFailed { reason },
-                        },
-                        PaymentStatus::Rejected { reason } => NotificationData::PaymentStatusUpdate {
-                            status: InvoiceStatus::UserRejected { reason },
-                        },
-                        PaymentStatus::Success { preimage } => NotificationData::PaymentStatusUpdate {
-                            status: InvoiceStatus::UserSuccess { preimage },
-                        },
-                        PaymentStatus::Approved => NotificationData::PaymentStatusUpdate {
-                            status: InvoiceStatus::UserApproved,
-                        },
-                    };
-                    events.push(&sid, notif_data).await;
-                }
-                Err(e) => {
-                    error!("Payment notification error: {e}");
-                }
-            }
-        }
-    });
+        .request_single_payment_resumable(main_key, subkeys, req.payment_request, stream_id.clone())
+        .await
+        .map_err(|e| request_error("Failed to request payment", e))?;
+    record_in_ledger(&state, &entry).await;
+
+    create_request_stream(
+        &state.events,
+        &stream_id,
+        "raw_payment",
+        None,
+        &delivery,
+        caller.key_id(),
+    )
+    .await;
 
-    Ok(created(SinglePaymentResponse { stream_id }))
+    tokio::spawn(forward_payment_statuses(
+        state.events.clone(),
+        state.ledger.clone(),
+        stream_id.clone(),
+        notifications,
+        None,
+    ));
+
+    Ok(created(SinglePaymentResponse { stream_id, delivery }))
 }
 
 // GET /profile/:main_key
@@ -555,18 +813,73 @@ pub async fn close_recurring_payment(
 
     state
         .sdk
-        .close_recurring_payment(main_key, subkeys, req.subscription_id)
+        .close_recurring_payment(main_key, subkeys, req.subscription_id.clone())
         .await
         .map_err(|e| internal_error(format!("Failed to close recurring payment: {e}")))?;
 
+    if let Some(billing) = &state.billing {
+        billing
+            .cancel(
+                &state.events,
+                &req.subscription_id,
+                &main_key.to_string(),
+                None,
+                true,
+            )
+            .await;
+    }
+
     Ok(ok(CloseRecurringPaymentResponse {
         message: "Recurring payment closed".to_string(),
     }))
 }
 
+// GET /subscriptions
+#[derive(Deserialize)]
+pub struct ListSubscriptionsQuery {
+    pub status: Option<SubscriptionStatus>,
+}
+
+pub async fn list_subscriptions(
+    State(state): State<AppState>,
+    Query(query): Query<ListSubscriptionsQuery>,
+) -> ApiResult<SubscriptionsResponse> {
+    let billing = state
+        .billing
+        .as_ref()
+        .ok_or_else(|| bad_request("Billing is not enabled"))?;
+
+    let subscriptions = billing
+        .list(query.status)
+        .await
+        .map_err(|e| internal_error(format!("Failed to list subscriptions: {e}")))?;
+
+    Ok(ok(SubscriptionsResponse { subscriptions }))
+}
+
+// GET /subscriptions/:subscription_id
+pub async fn get_subscription(
+    State(state): State<AppState>,
+    Path(subscription_id): Path<String>,
+) -> ApiResult<crate::billing::Subscription> {
+    let billing = state
+        .billing
+        .as_ref()
+        .ok_or_else(|| bad_request("Billing is not enabled"))?;
+
+    let subscription = billing
+        .get(&subscription_id)
+        .await
+        .map_err(|e| internal_error(format!("Failed to load subscription: {e}")))?
+        .ok_or_else(|| not_found(format!("Subscription {subscription_id} not found")))?;
+
+    Ok(ok(subscription))
+}
+
 // POST /invoices/request
 pub async fn request_invoice(
     State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
     Json(req): Json<RequestInvoiceRequest>,
 ) -> ApiResult<StreamResponse> {
     let recipient_key = hex_to_pubkey(&req.recipient_key).map_err(|e| bad_request(format!("Invalid recipient key: {e}")))?;
@@ -593,94 +906,35 @@ pub async fn request_invoice(
         refund_invoice: req.content.refund_invoice.clone(),
     };
 
-    let stream_id = state.events.new_stream("invoice_request", None).await;
-
-    let sdk = state.sdk.clone();
-    let events = state.events.clone();
-    let sid = stream_id.clone();
-    tokio::spawn(async move {
-        match sdk
-            .request_invoice(recipient_key, subkeys, sdk_content)
-            .await
-        {
-            Ok(Some(resp)) => {
-                // Validate amount
-                let invoice_amount_msat = match extract_invoice_amount_msat(&resp.invoice) {
-                    Ok(Some(amt)) => amt,
-                    Ok(None) => {
-                        events
-                            .push(
-                                &sid,
-                                NotificationData::Error {
-                                    reason: "Invoice has no amount (zero-amount invoice not allowed)".to_string(),
-                                },
-                            )
-                            .await;
-                        return;
-                    }
-                    Err(e) => {
-                        events
-                            .push(
-                                &sid,
-                                NotificationData::Error {
-                                    reason: format!("Invalid invoice: {e}"),
-                                },
-                            )
-                            .await;
-                        return;
-                    }
-                };
-
-                let expected_msat = expected_amount_msat.as_millisats();
-                let amount_diff = (invoice_amount_msat as i128 - expected_msat as i128).abs();
-                if amount_diff > 1 {
-                    events
-                        .push(
-                            &sid,
-                            NotificationData::Error {
-                                reason: format!(
-                                    "Invoice amount mismatch: got {invoice_amount_msat} msat, expected {expected_msat} msat (diff: {amount_diff} msat)"
-                                ),
-                            },
-                        )
-                        .await;
-                    return;
-                }
+    let expected_msat = expected_amount_msat.as_millisats();
+    let metadata = StreamMetadata::InvoiceRequest {
+        expected_amount_msat: expected_msat,
+    };
+    let stream_id = Uuid::new_v4().to_string();
+    let (notifications, delivery) = state
+        .sdk
+        .request_invoice_resumable(recipient_key, subkeys, sdk_content, stream_id.clone())
+        .await
+        .map_err(|e| internal_error(format!("Failed to request invoice: {e}")))?;
+
+    create_request_stream(
+        &state.events,
+        &stream_id,
+        "invoice_request",
+        Some(&metadata),
+        &delivery,
+        caller.key_id(),
+    )
+    .await;
 
-                events
-                    .push(
-                        &sid,
-                        NotificationData::InvoiceResponse {
-                            invoice: resp.invoice,
-                            payment_hash: resp.payment_hash.unwrap_or_default(),
-                        },
-                    )
-                    .await;
-            }
-            Ok(None) => {
-                events
-                    .push(
-                        &sid,
-                        NotificationData::Error {
-                            reason: "Recipient did not reply with an invoice".to_string(),
-                        },
-                    )
-                    .await;
-            }
-            Err(e) => {
-                events
-                    .push(
-                        &sid,
-                        NotificationData::Error {
-                            reason: format!("Failed to request invoice: {e}"),
-                        },
-                    )
-                    .await;
-            }
-        }
-    });
+    tokio::spawn(forward_first_notification(
+        state.events.clone(),
+        stream_id.clone(),
+        notifications,
+        move |result| invoice_notification(expected_msat, result),
+    ));
 
-    Ok(created(StreamResponse { stream_id }))
+    Ok(created(StreamResponse { stream_id, delivery }))
 }
 
 // POST /jwt/issue
@@ -690,9 +944,15 @@ pub async fn issue_jwt(
 ) -> ApiResult<IssueJwtResponse> {
     let target_key = hex_to_pubkey(&req.target_key).map_err(|e| bad_request(format!("Invalid target_key: {e}")))?;
 
+    let mut claims = CustomClaims::new(target_key.into());
+    claims.audience = req.audience;
+    claims.issuer = req.issuer;
+    claims.scopes = req.scopes;
+    claims.jti = req.jti;
+
     let token = state
         .sdk
-        .issue_jwt(CustomClaims::new(target_key.into()), Duration::hours(req.duration_hours))
+        .issue_jwt(claims, Duration::hours(req.duration_hours))
         .map_err(|e| internal_error(format!("Failed to issue JWT: {e}")))?;
 
     Ok(ok(IssueJwtResponse { token }))
@@ -705,95 +965,339 @@ pub async fn verify_jwt(
 ) -> ApiResult<VerifyJwtResponse> {
     let pubkey = hex_to_pubkey(&req.pubkey).map_err(|e| bad_request(format!("Invalid pubkey: {e}")))?;
 
+    let options = DecodeOptions {
+        audience: req.audience,
+        issuer: req.issuer,
+        required_scopes: req.required_scopes,
+        revocation_list: None,
+    };
     let claims = state
         .sdk
-        .verify_jwt(pubkey, &req.token)
+        .verify_jwt_with_options(pubkey, &req.token, options)
         .map_err(|e| bad_request(format!("Failed to verify JWT: {e}")))?;
 
-    Ok(ok(VerifyJwtResponse {
-        target_key: claims.target_key.to_string(),
-    }))
+    Ok(ok(claims.into()))
 }
 
-// POST /cashu/request
-pub async fn request_cashu(
+// POST /sessions/verify
+pub async fn verify_session(
     State(state): State<AppState>,
-    Json(req): Json<RequestCashuRequest>,
-) -> ApiResult<StreamResponse> {
-    let recipient_key = hex_to_pubkey(&req.recipient_key).map_err(|e| bad_request(format!("Invalid recipient key: {e}")))?;
-    let subkeys = parse_subkeys(&req.subkeys).map_err(|e| bad_request(format!("Invalid subkeys: {e}")))?;
+    Json(req): Json<SessionTokenRequest>,
+) -> ApiResult<SessionResponse> {
+    let session = state
+        .sdk
+        .verify_session(&req.token)
+        .map_err(|e| bad_request(format!("Invalid session: {e}")))?;
 
-    let expires_at = Timestamp::now_plus_seconds(300);
-    let content = CashuRequestContent {
-        mint_url: req.mint_url,
-        unit: req.unit,
-        amount: req.amount,
-        request_id: Uuid::new_v4().to_string(),
-        expires_at,
-    };
+    Ok(ok(session.into()))
+}
 
-    let stream_id = state.events.new_stream("cashu_request", None).await;
+// POST /sessions/refresh
+pub async fn refresh_session(
+    State(state): State<AppState>,
+    Json(req): Json<SessionTokenRequest>,
+) -> ApiResult<SessionResponse> {
+    let session = state
+        .sdk
+        .refresh_session(&req.token, state.session_duration())
+        .map_err(|e| bad_request(format!("Failed to refresh session: {e}")))?;
 
-    let sdk = state.sdk.clone();
-    let events = state.events.clone();
-    let sid = stream_id.clone();
-    tokio::spawn(async move {
-        match sdk.request_cashu(recipient_key, subkeys, content).await {
-            Ok(Some(r)) => {
-                events
-                    .push(&sid, NotificationData::CashuResponse { status: r.status })
-                    .await;
-            }
-            Ok(None) => {
-                events
-                    .push(
-                        &sid,
-                        NotificationData::Error {
-                            reason: "No response from recipient".to_string(),
-                        },
-                    )
-                    .await;
-            }
-            Err(e) => {
-                events
-                    .push(
-                        &sid,
-                        NotificationData::Error {
-                            reason: format!("Failed to request cashu: {e}"),
-                        },
-                    )
-                    .await;
-            }
+    Ok(ok(session.into()))
+}
+
+// POST /sessions/revoke
+pub async fn revoke_session(
+    State(state): State<AppState>,
+    Json(req): Json<RevokeSessionRequest>,
+) -> ApiResult<RevokeSessionResponse> {
+    let session_id = match (req.session_id, req.token) {
+        (Some(session_id), _) => session_id,
+        (None, Some(token)) => {
+            state
+                .sdk
+                .verify_session(&token)
+                .map_err(|e| bad_request(format!("Invalid session: {e}")))?
+                .claims
+                .session_id
         }
-    });
+        (None, None) => return Err(bad_request("Either token or session_id is required")),
+    };
+
+    // Tokens are issued for at most the session duration, so none of the session is valid
+    // after that
+    let expires_at = Timestamp::now().as_u64() + state.settings.auth.session_duration_secs;
+    state
+        .sessions
+        .revoke(&session_id, expires_at)
+        .await
+        .map_err(|e| internal_error(format!("Failed to revoke session: {e}")))?;
+    state
+        .sdk
+        .revoke_session(session_id.clone(), Timestamp::new(expires_at));
 
-    Ok(created(StreamResponse { stream_id }))
+    Ok(ok(RevokeSessionResponse { session_id }))
 }
 
-// POST /cashu/send-direct
-pub async fn send_cashu_direct(
+// POST /certificates/issue
+pub async fn issue_certificate(
     State(state): State<AppState>,
-    Json(req): Json<SendCashuDirectRequest>,
-) -> ApiResult<SendCashuDirectResponse> {
-    let main_key = hex_to_pubkey(&req.main_key).map_err(|e| bad_request(format!("Invalid main key: {e}")))?;
+    Json(req): Json<IssueCertificateRequest>,
+) -> ApiResult<IssueCertificateResponse> {
+    let subject_key = hex_to_pubkey(&req.subject_key).map_err(|e| bad_request(format!("Invalid subject key: {e}")))?;
     let subkeys = parse_subkeys(&req.subkeys).map_err(|e| bad_request(format!("Invalid subkeys: {e}")))?;
 
-    state
+    let builder = state
         .sdk
-        .send_cashu_direct(main_key, subkeys, CashuDirectContent { token: req.token })
-        .await
-        .map_err(|e| internal_error(format!("Failed to send cashu direct: {e}")))?;
+        .certificate_builder(subject_key)
+        .data(req.data)
+        .verification(req.verification_level, req.verification_method)
+        .expires_at(req.expires_at);
+    let builder = req
+        .predicates
+        .into_iter()
+        .fold(builder, |builder, predicate| builder.predicate(predicate));
+
+    let certificate = state.sdk.issue_certificate(builder).map_err(|e| match e {
+        portal_sdk::PortalSDKError::Issuance(
+            e @ (IssuanceError::Missing(_)
+            | IssuanceError::InvalidField { .. }
+            | IssuanceError::InvalidValidity
+            | IssuanceError::Predicate(_)
+            | IssuanceError::Reveal(RevealError::InvalidField)),
+        ) => bad_request(format!("Invalid certificate: {e}")),
+        portal_sdk::PortalSDKError::MasterKeyRequired => err(
+            StatusCode::CONFLICT,
+            "Certificates must be signed with the master key, this identity runs with a subkey",
+        ),
+        e => internal_error(format!("Failed to issue certificate: {e}")),
+    })?;
+
+    let deliver = req.deliver.unwrap_or(true);
+    if deliver {
+        state
+            .sdk
+            .deliver_certificate(subject_key, subkeys, &certificate)
+            .await
+            .map_err(|e| internal_error(format!("Failed to deliver certificate: {e}")))?;
+    }
 
-    Ok(ok(SendCashuDirectResponse {
-        message: "Cashu direct sent".to_string(),
+    let certificate_json = serde_json::to_value(&certificate)
+        .map_err(|e| internal_error(format!("Failed to serialize certificate: {e}")))?;
+
+    Ok(created(IssueCertificateResponse {
+        certificate_id: certificate.metadata.certificate_id(),
+        certificate: certificate_json,
+        delivered: deliver,
     }))
 }
 
-// POST /cashu/mint
-pub async fn mint_cashu(
-    State(_state): State<AppState>,
+/// Maximum age of the revocation lists accepted as status proofs
+const STATUS_PROOF_MAX_AGE_SECS: u64 = 60 * 60 * 24;
+
+/// Verify the certificates revealed by a user, dropping the invalid ones and, if
+/// `trusted_issuers` is set, the ones from other issuers.
+fn verify_certificate_response(
+    main_key: PublicKey,
+    trusted_issuers: Option<&[PublicKey]>,
+    response: &CertificateResponseContent,
+) -> Result<std::collections::HashMap<String, RevealedCertificate>, String> {
+    let statuses = match response.status_proofs {
+        Some(_) => Some(
+            response
+                .check_status_proofs(STATUS_PROOF_MAX_AGE_SECS)
+                .map_err(|e| format!("Invalid status proofs: {e}"))?,
+        ),
+        None => None,
+    };
+
+    let mut certificates = std::collections::HashMap::new();
+    for (certificate_type, partial) in response
+        .partial_certificates()
+        .map_err(|e| format!("Invalid certificates: {e}"))?
+    {
+        if partial.subject != main_key {
+            warn!("Ignoring '{certificate_type}' certificate issued to another key");
+            continue;
+        }
+        if trusted_issuers.is_some_and(|trusted| !trusted.contains(&partial.metadata.issuer_pubkey))
+        {
+            warn!(
+                "Ignoring '{certificate_type}' certificate from untrusted issuer {}",
+                partial.metadata.issuer_pubkey
+            );
+            continue;
+        }
+        if partial.metadata.expires_at < Timestamp::now() {
+            warn!("Ignoring expired '{certificate_type}' certificate");
+            continue;
+        }
+        let fields = match partial.verify() {
+            Ok(fields) => fields,
+            Err(e) => {
+                warn!("Ignoring invalid '{certificate_type}' certificate: {e}");
+                continue;
+            }
+        };
+
+        let revocation_status = statuses
+            .as_ref()
+            .and_then(|s| s.get(&certificate_type))
+            .map_or("unknown", |status| match status {
+                CertificateStatus::Valid => "valid",
+                CertificateStatus::Revoked { .. } => "revoked",
+            })
+            .to_string();
+
+        certificates.insert(
+            certificate_type,
+            RevealedCertificate {
+                issuer: partial.metadata.issuer_pubkey.to_string(),
+                subject: partial.subject.to_string(),
+                issued_at: partial.metadata.issued_at,
+                expires_at: partial.metadata.expires_at,
+                fields,
+                revocation_status,
+            },
+        );
+    }
+
+    Ok(certificates)
+}
+
+// POST /certificates/request
+pub async fn request_certificates(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<RequestCertificatesRequest>,
+) -> ApiResult<StreamResponse> {
+    let main_key = hex_to_pubkey(&req.main_key).map_err(|e| bad_request(format!("Invalid main key: {e}")))?;
+    let subkeys = parse_subkeys(&req.subkeys).map_err(|e| bad_request(format!("Invalid subkeys: {e}")))?;
+    let trusted_issuers = req
+        .trusted_issuers
+        .as_deref()
+        .map(parse_subkeys)
+        .transpose()
+        .map_err(|e| bad_request(format!("Invalid trusted issuers: {e}")))?;
+
+    let content = CertificateRequestContent {
+        request_id: Uuid::new_v4().to_string(),
+        requested_types: req.requested_types,
+        requested_fields: req.requested_fields,
+        purpose: req.purpose,
+        require_status_proofs: req.require_status_proofs,
+        expires_at: Timestamp::now_plus_seconds(300),
+    };
+
+    let metadata = StreamMetadata::CertificateRequest {
+        main_key: main_key.to_string(),
+        trusted_issuers: req.trusted_issuers,
+    };
+    let stream_id = Uuid::new_v4().to_string();
+    let (notifications, delivery) = state
+        .sdk
+        .request_certificates_resumable(main_key, subkeys, content, stream_id.clone())
+        .await
+        .map_err(|e| internal_error(format!("Failed to request certificates: {e}")))?;
+
+    create_request_stream(
+        &state.events,
+        &stream_id,
+        "certificate_request",
+        Some(&metadata),
+        &delivery,
+        caller.key_id(),
+    )
+    .await;
+
+    tokio::spawn(forward_first_notification(
+        state.events.clone(),
+        stream_id.clone(),
+        notifications,
+        move |result| certificate_notification(main_key, trusted_issuers, result),
+    ));
+
+    Ok(created(StreamResponse { stream_id, delivery }))
+}
+
+// POST /cashu/request
+pub async fn request_cashu(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Json(req): Json<RequestCashuRequest>,
+) -> ApiResult<StreamResponse> {
+    let recipient_key = hex_to_pubkey(&req.recipient_key).map_err(|e| bad_request(format!("Invalid recipient key: {e}")))?;
+    let subkeys = parse_subkeys(&req.subkeys).map_err(|e| bad_request(format!("Invalid subkeys: {e}")))?;
+
+    let expires_at = Timestamp::now_plus_seconds(300);
+    let content = CashuRequestContent {
+        mint_url: req.mint_url,
+        unit: req.unit,
+        amount: req.amount,
+        request_id: Uuid::new_v4().to_string(),
+        expires_at,
+    };
+
+    let stream_id = Uuid::new_v4().to_string();
+    let (notifications, delivery) = state
+        .sdk
+        .request_cashu_resumable(recipient_key, subkeys, content, stream_id.clone())
+        .await
+        .map_err(|e| request_error("Failed to request cashu", e))?;
+
+    create_request_stream(
+        &state.events,
+        &stream_id,
+        "cashu_request",
+        None,
+        &delivery,
+        caller.key_id(),
+    )
+    .await;
+
+    tokio::spawn(forward_first_notification(
+        state.events.clone(),
+        stream_id.clone(),
+        notifications,
+        cashu_notification,
+    ));
+
+    Ok(created(StreamResponse { stream_id, delivery }))
+}
+
+// POST /cashu/send-direct
+pub async fn send_cashu_direct(
+    State(state): State<AppState>,
+    Json(req): Json<SendCashuDirectRequest>,
+) -> ApiResult<SendCashuDirectResponse> {
+    let main_key = hex_to_pubkey(&req.main_key).map_err(|e| bad_request(format!("Invalid main key: {e}")))?;
+    let subkeys = parse_subkeys(&req.subkeys).map_err(|e| bad_request(format!("Invalid subkeys: {e}")))?;
+
+    state
+        .sdk
+        .send_cashu_direct(main_key, subkeys, CashuDirectContent { token: req.token })
+        .await
+        .map_err(|e| internal_error(format!("Failed to send cashu direct: {e}")))?;
+
+    Ok(ok(SendCashuDirectResponse {
+        message: "Cashu direct sent".to_string(),
+    }))
+}
+
+// POST /cashu/mint
+pub async fn mint_cashu(
+    State(state): State<AppState>,
     Json(req): Json<MintCashuRequest>,
 ) -> ApiResult<CashuMintResponse> {
+    let mut entry = LedgerEntry::new(
+        LedgerKind::CashuMint,
+        LedgerDirection::Outgoing,
+        req.amount,
+        req.unit.clone(),
+    );
+    entry.amount_msat = ledger::cashu_amount_msat(req.amount, &req.unit);
+    entry.description = req.description.clone();
+    entry.mint_url = Some(req.mint_url.clone());
+
     let mint_url = MintUrl::from_str(&req.mint_url).map_err(|e| bad_request(format!("Invalid mint URL: {e}")))?;
     let currency_unit = CurrencyUnit::from_str(&req.unit).map_err(|e| bad_request(format!("Invalid unit: {e}")))?;
 
@@ -820,6 +1324,7 @@ pub async fn mint_cashu(
         .send(prepared_send, None)
         .await
         .map_err(|e| internal_error(format!("Failed to send token: {e}")))?;
+    record_in_ledger(&state, &entry.paid()).await;
 
     Ok(ok(CashuMintResponse {
         token: token.to_string(),
@@ -828,7 +1333,7 @@ pub async fn mint_cashu(
 
 // POST /cashu/burn
 pub async fn burn_cashu(
-    State(_state): State<AppState>,
+    State(state): State<AppState>,
     Json(req): Json<BurnCashuRequest>,
 ) -> ApiResult<CashuBurnResponse> {
     let mint_url = MintUrl::from_str(&req.mint_url).map_err(|e| bad_request(format!("Invalid mint URL: {e}")))?;
@@ -843,9 +1348,18 @@ pub async fn burn_cashu(
         .await
         .map_err(|e| internal_error(format!("Failed to receive token: {e}")))?;
 
-    Ok(ok(CashuBurnResponse {
-        amount: receive.into(),
-    }))
+    let amount: u64 = receive.into();
+    let mut entry = LedgerEntry::new(
+        LedgerKind::CashuBurn,
+        LedgerDirection::Incoming,
+        amount,
+        req.unit.clone(),
+    );
+    entry.amount_msat = ledger::cashu_amount_msat(amount, &req.unit);
+    entry.mint_url = Some(req.mint_url);
+    record_in_ledger(&state, &entry.paid()).await;
+
+    Ok(ok(CashuBurnResponse { amount }))
 }
 
 // POST /relays
@@ -876,6 +1390,179 @@ pub async fn remove_relay(
     Ok(ok(RelayResponse { relay: req.relay }))
 }
 
+// ---- Outbox ----
+
+fn parse_event_id(event_id: &str) -> Result<EventId, (StatusCode, Json<ApiResponse<()>>)> {
+    EventId::parse(event_id).map_err(|e| bad_request(format!("Invalid event id: {e}")))
+}
+
+async fn outbox_events(state: &AppState) -> Result<Vec<OutboxEvent>, (StatusCode, Json<ApiResponse<()>>)> {
+    let entries = state
+        .sdk
+        .list_outbox()
+        .await
+        .map_err(|e| internal_error(format!("Failed to read the outbox: {e:?}")))?;
+    Ok(entries.into_iter().map(OutboxEvent::from).collect())
+}
+
+/// Retries `event_id` (or all queued events) and reports what is left in the outbox.
+async fn retry_outbox_events(
+    state: &AppState,
+    event_id: Option<EventId>,
+) -> ApiResult<OutboxRetryResponse> {
+    let delivered = state
+        .sdk
+        .retry_outbox(event_id)
+        .await
+        .map_err(|e| internal_error(format!("Failed to retry queued events: {e:?}")))?;
+    let remaining = outbox_events(state).await?.len();
+
+    Ok(ok(OutboxRetryResponse { delivered, remaining }))
+}
+
+// GET /outbox
+pub async fn list_outbox(State(state): State<AppState>) -> ApiResult<OutboxResponse> {
+    let events = outbox_events(&state).await?;
+    Ok(ok(OutboxResponse { events }))
+}
+
+// POST /outbox/retry
+pub async fn retry_outbox(State(state): State<AppState>) -> ApiResult<OutboxRetryResponse> {
+    retry_outbox_events(&state, None).await
+}
+
+// POST /outbox/:event_id/retry
+pub async fn retry_outbox_event(
+    State(state): State<AppState>,
+    Path(event_id): Path<String>,
+) -> ApiResult<OutboxRetryResponse> {
+    let event_id = parse_event_id(&event_id)?;
+    if !outbox_events(&state)
+        .await?
+        .iter()
+        .any(|event| event.event_id == event_id.to_hex())
+    {
+        return Err(not_found(format!("Event '{event_id}' is not queued")));
+    }
+
+    retry_outbox_events(&state, Some(event_id)).await
+}
+
+// DELETE /outbox
+pub async fn purge_outbox(State(state): State<AppState>) -> ApiResult<OutboxPurgeResponse> {
+    let purged = state
+        .sdk
+        .purge_outbox(None)
+        .await
+        .map_err(|e| internal_error(format!("Failed to purge the outbox: {e:?}")))?;
+
+    Ok(ok(OutboxPurgeResponse { purged }))
+}
+
+// DELETE /outbox/:event_id
+pub async fn purge_outbox_event(
+    State(state): State<AppState>,
+    Path(event_id): Path<String>,
+) -> ApiResult<OutboxPurgeResponse> {
+    let event_id = parse_event_id(&event_id)?;
+    let purged = state
+        .sdk
+        .purge_outbox(Some(event_id))
+        .await
+        .map_err(|e| internal_error(format!("Failed to purge event {event_id}: {e:?}")))?;
+    if purged == 0 {
+        return Err(not_found(format!("Event '{event_id}' is not queued")));
+    }
+
+    Ok(ok(OutboxPurgeResponse { purged }))
+}
+
+// ---- Webhooks ----
+
+fn require_webhook(state: &AppState) -> Result<(), (StatusCode, Json<ApiResponse<()>>)> {
+    if !state.events.webhooks_enabled() {
+        return Err(bad_request("No webhook URL configured"));
+    }
+    Ok(())
+}
+
+// GET /webhooks/deliveries
+#[derive(Deserialize)]
+pub struct WebhookDeliveriesQuery {
+    /// Defaults to the deliveries that are pending or dead-lettered
+    pub status: Option<DeliveryStatus>,
+    pub stream_id: Option<String>,
+}
+
+pub async fn list_webhook_deliveries(
+    State(state): State<AppState>,
+    Query(query): Query<WebhookDeliveriesQuery>,
+) -> ApiResult<WebhookDeliveriesResponse> {
+    let deliveries = state
+        .events
+        .list_webhook_deliveries(query.status, query.stream_id.as_deref())
+        .await;
+
+    Ok(ok(WebhookDeliveriesResponse { deliveries }))
+}
+
+// GET /webhooks/deliveries/:stream_id/:index
+pub async fn get_webhook_delivery(
+    State(state): State<AppState>,
+    Path((stream_id, index)): Path<(String, u64)>,
+) -> ApiResult<WebhookDelivery> {
+    let delivery = state
+        .events
+        .get_webhook_delivery(&stream_id, index)
+        .await
+        .ok_or_else(|| {
+            not_found(format!("No webhook delivery for event {index} of stream '{stream_id}'"))
+        })?;
+
+    Ok(ok(delivery))
+}
+
+// POST /webhooks/deliveries/:stream_id/:index/replay
+pub async fn replay_webhook_delivery(
+    State(state): State<AppState>,
+    Path((stream_id, index)): Path<(String, u64)>,
+) -> ApiResult<WebhookReplayResponse> {
+    require_webhook(&state)?;
+
+    let replayed = state
+        .events
+        .replay_webhooks(&stream_id, index, Some(index))
+        .await;
+    if replayed.is_empty() {
+        return Err(not_found(format!(
+            "Event {index} of stream '{stream_id}' not found"
+        )));
+    }
+
+    Ok(ok(WebhookReplayResponse { stream_id, replayed }))
+}
+
+// POST /webhooks/deliveries/replay
+pub async fn replay_webhook_deliveries(
+    State(state): State<AppState>,
+    Json(req): Json<ReplayWebhooksRequest>,
+) -> ApiResult<WebhookReplayResponse> {
+    require_webhook(&state)?;
+    if !state.events.exists(&req.stream_id).await {
+        return Err(not_found(format!("Stream '{}' not found", req.stream_id)));
+    }
+
+    let replayed = state
+        .events
+        .replay_webhooks(&req.stream_id, req.from_index, None)
+        .await;
+
+    Ok(ok(WebhookReplayResponse {
+        stream_id: req.stream_id,
+        replayed,
+    }))
+}
+
 // POST /calendar/next-occurrence
 pub async fn calculate_next_occurrence(
     State(_state): State<AppState>,
@@ -899,10 +1586,32 @@ pub async fn pay_invoice(
         .as_ref()
         .ok_or_else(|| bad_request("Backend wallet not available: set NWC_URL or BREEZ_MNEMONIC"))?;
 
-    let (preimage, fees_paid_msat) = wallet
-        .pay_invoice(req.invoice)
-        .await
-        .map_err(|e| internal_error(format!("Failed to pay invoice: {e}")))?;
+    let amount_msat = extract_invoice_amount_msat(&req.invoice).ok().flatten();
+    let mut entry = LedgerEntry::new(
+        LedgerKind::InvoicePayment,
+        LedgerDirection::Outgoing,
+        amount_msat.unwrap_or(0),
+        ledger::currency_code(&Currency::Millisats),
+    );
+    entry.amount_msat = amount_msat;
+    entry.payment_hash = ledger::payment_hash(&req.invoice);
+    entry.invoice = Some(req.invoice.clone());
+    record_in_ledger(&state, &entry).await;
+
+    let result = wallet.pay_invoice(req.invoice).await;
+    let update = match &result {
+        Ok((preimage, fees_paid_msat)) => LedgerUpdate {
+            fees_msat: Some(*fees_paid_msat),
+            ..LedgerUpdate::paid(Some(preimage.clone()))
+        },
+        Err(e) => LedgerUpdate::error(LedgerStatus::Failed, Some(e.to_string())),
+    };
+    if let Err(e) = state.ledger.update(&entry.id, update).await {
+        error!("Failed to update ledger entry {}: {e}", entry.id);
+    }
+
+    let (preimage, fees_paid_msat) =
+        result.map_err(|e| internal_error(format!("Failed to pay invoice: {e}")))?;
 
     Ok(ok(PayInvoiceResponse {
         preimage,
@@ -910,6 +1619,88 @@ pub async fn pay_invoice(
     }))
 }
 
+// ---- Mock wallet ----
+//
+// Test commands of the mock wallet (`ln_backend = "mock"`), deciding what happens to the
+// invoices it issued since nobody can pay them.
+
+fn mock_wallet(state: &AppState) -> Result<&Arc<MockWallet>, (StatusCode, Json<ApiResponse<()>>)> {
+    state
+        .mock_wallet
+        .as_ref()
+        .ok_or_else(|| bad_request("The mock wallet is not configured"))
+}
+
+fn mock_wallet_error(e: PortalWalletError) -> (StatusCode, Json<ApiResponse<()>>) {
+    match e {
+        PortalWalletError::InvoiceNotFound(_) => not_found(e.to_string()),
+        PortalWalletError::DatabaseError(_) => internal_error(e.to_string()),
+        e => bad_request(e.to_string()),
+    }
+}
+
+async fn mock_invoice_response(
+    wallet: &MockWallet,
+    invoice: &str,
+) -> ApiResult<MockInvoiceResponse> {
+    let invoice = wallet
+        .invoice(invoice)
+        .map_err(mock_wallet_error)?
+        .ok_or_else(|| not_found("Invoice not found"))?;
+    let balance_msat = wallet.get_balance().await.map_err(mock_wallet_error)?;
+
+    let (status, reason) = match invoice.status {
+        MockInvoiceStatus::Pending => ("pending", None),
+        MockInvoiceStatus::Paid => ("paid", None),
+        MockInvoiceStatus::Expired => ("expired", None),
+        MockInvoiceStatus::Failed { reason } => ("failed", Some(reason)),
+    };
+    Ok(ok(MockInvoiceResponse {
+        payment_hash: invoice.payment_hash,
+        status: status.to_string(),
+        reason,
+        amount_msat: invoice.amount_msat,
+        balance_msat,
+    }))
+}
+
+// POST /wallet/mock/settle
+pub async fn settle_mock_invoice(
+    State(state): State<AppState>,
+    Json(req): Json<MockInvoiceRequest>,
+) -> ApiResult<MockInvoiceResponse> {
+    let wallet = mock_wallet(&state)?;
+    wallet
+        .settle_invoice(&req.invoice)
+        .map_err(mock_wallet_error)?;
+    mock_invoice_response(wallet, &req.invoice).await
+}
+
+// POST /wallet/mock/expire
+pub async fn expire_mock_invoice(
+    State(state): State<AppState>,
+    Json(req): Json<MockInvoiceRequest>,
+) -> ApiResult<MockInvoiceResponse> {
+    let wallet = mock_wallet(&state)?;
+    wallet
+        .expire_invoice(&req.invoice)
+        .map_err(mock_wallet_error)?;
+    mock_invoice_response(wallet, &req.invoice).await
+}
+
+// POST /wallet/mock/fail
+pub async fn fail_mock_invoice(
+    State(state): State<AppState>,
+    Json(req): Json<MockInvoiceRequest>,
+) -> ApiResult<MockInvoiceResponse> {
+    let wallet = mock_wallet(&state)?;
+    let reason = req.reason.as_deref().unwrap_or("Payment failed");
+    wallet
+        .fail_invoice(&req.invoice, reason)
+        .map_err(mock_wallet_error)?;
+    mock_invoice_response(wallet, &req.invoice).await
+}
+
 // GET /nip05/:nip05
 pub async fn fetch_nip05_profile(
     State(_state): State<AppState>,
@@ -936,36 +1727,536 @@ pub async fn get_wallet_info(
         .await
         .map_err(|e| internal_error(format!("Failed to get balance: {e}")))?;
 
-    let wallet_type = match &state.settings.wallet.ln_backend {
+    Ok(ok(WalletInfoResponse {
+        wallet_type: wallet_type(&state),
+        balance_msat,
+    }))
+}
+
+fn wallet_type(state: &AppState) -> String {
+    match &state.settings.wallet.ln_backend {
         crate::config::LnBackend::None => "none",
         crate::config::LnBackend::Nwc => "nwc",
         crate::config::LnBackend::Breez => "breez",
+        crate::config::LnBackend::Lnd => "lnd",
+        crate::config::LnBackend::Cln => "cln",
+        crate::config::LnBackend::Lnbits => "lnbits",
+        crate::config::LnBackend::Mock => "mock",
     }
-    .to_string();
+    .to_string()
+}
 
-    Ok(ok(WalletInfoResponse {
-        wallet_type,
-        balance_msat,
+fn wallet_error(context: &str, e: PortalWalletError) -> (StatusCode, Json<ApiResponse<()>>) {
+    match e {
+        PortalWalletError::InvoiceNotFound(_) => not_found(e.to_string()),
+        PortalWalletError::Unsupported(_) => err(StatusCode::NOT_IMPLEMENTED, e.to_string()),
+        PortalWalletError::InvalidInvoice(_) => bad_request(e.to_string()),
+        e => internal_error(format!("{context}: {e}")),
+    }
+}
+
+/// Payment and description hashes are hex encoded SHA-256 hashes
+fn is_sha256_hex(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Page size of `GET /wallet/payments`, unless asked otherwise
+const WALLET_PAYMENTS_LIMIT: u64 = 50;
+const WALLET_PAYMENTS_MAX_LIMIT: u64 = 500;
+/// Backends fetch every payment before the page, so deep pages are refused
+const WALLET_PAYMENTS_MAX_OFFSET: u64 = 10_000;
+
+// GET /wallet/capabilities
+pub async fn get_wallet_capabilities(
+    State(state): State<AppState>,
+) -> ApiResult<WalletCapabilitiesResponse> {
+    let wallet = state
+        .wallet
+        .as_ref()
+        .ok_or_else(|| bad_request("No wallet configured"))?;
+
+    let capabilities = wallet
+        .capabilities()
+        .await
+        .map_err(|e| wallet_error("Failed to get wallet capabilities", e))?;
+
+    Ok(ok(WalletCapabilitiesResponse {
+        wallet_type: wallet_type(&state),
+        list_payments: capabilities.list_payments,
+        lookup_invoice: capabilities.lookup_invoice,
+        invoice_expiry: capabilities.invoice_expiry,
+        description_hash: capabilities.description_hash,
+        push_settlements: capabilities.push_settlements,
     }))
 }
 
+// GET /wallet/payments
+#[derive(Deserialize)]
+pub struct WalletPaymentsQuery {
+    /// `incoming` or `outgoing`, both if not set
+    pub direction: Option<String>,
+    /// Unix timestamps bounding the creation time of the payments
+    pub from: Option<u64>,
+    pub until: Option<u64>,
+    pub offset: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+pub async fn list_wallet_payments(
+    State(state): State<AppState>,
+    Query(query): Query<WalletPaymentsQuery>,
+) -> ApiResult<WalletPaymentsResponse> {
+    let wallet = state
+        .wallet
+        .as_ref()
+        .ok_or_else(|| bad_request("No wallet configured"))?;
+
+    let direction = match query.direction.as_deref() {
+        None => None,
+        Some("incoming") => Some(PaymentDirection::Incoming),
+        Some("outgoing") => Some(PaymentDirection::Outgoing),
+        Some(other) => {
+            return Err(bad_request(format!(
+                "Invalid direction '{other}': expected 'incoming' or 'outgoing'"
+            )))
+        }
+    };
+    let offset = query.offset.unwrap_or(0);
+    if offset > WALLET_PAYMENTS_MAX_OFFSET {
+        return Err(bad_request(format!(
+            "Invalid offset {offset}: at most {WALLET_PAYMENTS_MAX_OFFSET}"
+        )));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(WALLET_PAYMENTS_LIMIT)
+        .min(WALLET_PAYMENTS_MAX_LIMIT);
+
+    let payments = wallet
+        .list_payments(PaymentsQuery {
+            direction,
+            from: query.from,
+            until: query.until,
+            offset,
+            limit,
+        })
+        .await
+        .map_err(|e| wallet_error("Failed to list payments", e))?;
+
+    Ok(ok(WalletPaymentsResponse {
+        payments: payments
+            .into_iter()
+            .map(WalletPaymentResponse::from)
+            .collect(),
+        offset,
+        limit,
+    }))
+}
+
+// GET /wallet/invoices/:payment_hash
+pub async fn lookup_wallet_invoice(
+    State(state): State<AppState>,
+    Path(payment_hash): Path<String>,
+) -> ApiResult<WalletPaymentResponse> {
+    let wallet = state
+        .wallet
+        .as_ref()
+        .ok_or_else(|| bad_request("No wallet configured"))?;
+
+    if !is_sha256_hex(&payment_hash) {
+        return Err(bad_request("Invalid payment hash"));
+    }
+
+    let payment = wallet
+        .lookup_invoice(&payment_hash.to_lowercase())
+        .await
+        .map_err(|e| wallet_error("Failed to look up invoice", e))?;
+
+    Ok(ok(WalletPaymentResponse::from(payment)))
+}
+
+// POST /wallet/invoices
+pub async fn create_wallet_invoice(
+    State(state): State<AppState>,
+    Json(req): Json<CreateWalletInvoiceRequest>,
+) -> ApiResult<WalletInvoiceResponse> {
+    let wallet = state
+        .wallet
+        .as_ref()
+        .ok_or_else(|| bad_request("No wallet configured"))?;
+
+    if let Some(hash) = &req.description_hash {
+        if !is_sha256_hex(hash) {
+            return Err(bad_request("Invalid description hash"));
+        }
+    }
+
+    let invoice = wallet
+        .create_invoice(InvoiceRequest {
+            amount_msat: req.amount_msat,
+            description: req.description,
+            description_hash: req.description_hash,
+            expiry_secs: req.expiry_secs,
+        })
+        .await
+        .map_err(|e| wallet_error("Failed to create invoice", e))?;
+
+    let parsed = Bolt11Invoice::from_str(&invoice)
+        .map_err(|e| internal_error(format!("The wallet returned an invalid invoice: {e}")))?;
+
+    Ok(created(WalletInvoiceResponse {
+        payment_hash: parsed.payment_hash().to_string(),
+        expires_at: parsed.expires_at().map(|expires_at| expires_at.as_secs()),
+        invoice,
+    }))
+}
+
+// GET /api-keys
+pub async fn list_api_keys(State(state): State<AppState>) -> ApiResult<ApiKeysResponse> {
+    let keys = state
+        .api_keys
+        .list()
+        .await
+        .map_err(|e| internal_error(format!("Failed to list API keys: {e}")))?;
+
+    Ok(ok(ApiKeysResponse { keys }))
+}
+
+// POST /api-keys
+pub async fn create_api_key(
+    State(state): State<AppState>,
+    Json(req): Json<CreateApiKeyRequest>,
+) -> ApiResult<CreatedApiKeyResponse> {
+    if req.name.trim().is_empty() {
+        return Err(bad_request("The name of the key can't be empty"));
+    }
+    let scopes = req
+        .scopes
+        .iter()
+        .map(|scope| scope.parse::<Scope>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(bad_request)?;
+    if scopes.is_empty() {
+        return Err(bad_request("At least one scope is required"));
+    }
+    if req
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= chrono::Utc::now().timestamp())
+    {
+        return Err(bad_request("expires_at must be in the future"));
+    }
+
+    let (key, api_key) = state
+        .api_keys
+        .create(req.name.trim(), &scopes, req.expires_at)
+        .await
+        .map_err(|e| internal_error(format!("Failed to create API key: {e}")))?;
+
+    Ok(created(CreatedApiKeyResponse { key, api_key }))
+}
+
+// GET /api-keys/:key_id
+pub async fn get_api_key(
+    State(state): State<AppState>,
+    Path(key_id): Path<String>,
+) -> ApiResult<ApiKey> {
+    match state.api_keys.get(&key_id).await {
+        Ok(Some(api_key)) => Ok(ok(api_key)),
+        Ok(None) => Err(not_found(format!("API key '{key_id}' not found"))),
+        Err(e) => Err(internal_error(format!("Failed to get API key: {e}"))),
+    }
+}
+
+// DELETE /api-keys/:key_id
+pub async fn revoke_api_key(
+    State(state): State<AppState>,
+    Path(key_id): Path<String>,
+) -> ApiResult<ApiKey> {
+    let revoked = state
+        .api_keys
+        .revoke(&key_id)
+        .await
+        .map_err(|e| internal_error(format!("Failed to revoke API key: {e}")))?;
+    if !revoked {
+        return Err(not_found(format!("API key '{key_id}' not found")));
+    }
+
+    match state.api_keys.get(&key_id).await {
+        Ok(Some(api_key)) => Ok(ok(api_key)),
+        Ok(None) => Err(not_found(format!("API key '{key_id}' not found"))),
+        Err(e) => Err(internal_error(format!("Failed to get API key: {e}"))),
+    }
+}
+
 // GET /events/:stream_id
 #[derive(Deserialize)]
 pub struct EventsQuery {
     pub after: Option<u64>,
 }
 
+/// API keys only see the streams they created, unless they have the `admin` scope.
+async fn check_stream_access(
+    state: &AppState,
+    caller: &Caller,
+    stream_id: &str,
+) -> Result<(), (StatusCode, Json<ApiResponse<()>>)> {
+    let readable = state.events.exists(stream_id).await
+        && caller.can_read_stream(state.events.created_by(stream_id).await.as_deref());
+    if !readable {
+        return Err(not_found(format!("Stream '{stream_id}' not found")));
+    }
+    Ok(())
+}
+
 pub async fn get_events(
     State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
     Path(stream_id): Path<String>,
     Query(query): Query<EventsQuery>,
 ) -> ApiResult<EventsResponse> {
-    if !state.events.exists(&stream_id).await {
-        return Err(not_found(format!("Stream '{stream_id}' not found")));
-    }
+    check_stream_access(&state, &caller, &stream_id).await?;
 
     let events = state.events.get(&stream_id, query.after).await;
 
     Ok(ok(EventsResponse { stream_id, events }))
 }
 
+// GET /events/:stream_id/live
+pub async fn follow_events(
+    State(state): State<AppState>,
+    Extension(caller): Extension<Caller>,
+    Path(stream_id): Path<String>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+    ws: Option<WebSocketUpgrade>,
+) -> Result<Response, (StatusCode, Json<ApiResponse<()>>)> {
+    check_stream_access(&state, &caller, &stream_id).await?;
+
+    // `EventSource` reconnects to the same URL, resuming after the last event it received
+    let after = live::last_event_id(&headers).or(query.after);
+    let events = live::follow_stream(state.events.clone(), stream_id, after);
+
+    Ok(live::respond(ws, events, |event| event.index))
+}
+
+// GET /events/live
+#[derive(Deserialize)]
+pub struct LiveEventsQuery {
+    /// Replay the events with a greater `seq`, only the new events are sent otherwise
+    pub after: Option<u64>,
+    /// Comma separated notification types, e.g. `payment_status_update,authenticate_key`
+    pub types: Option<String>,
+}
+
+pub async fn follow_all_events(
+    State(state): State<AppState>,
+    Query(query): Query<LiveEventsQuery>,
+    headers: HeaderMap,
+    ws: Option<WebSocketUpgrade>,
+) -> Response {
+    let after = live::last_event_id(&headers).or(query.after);
+    let types = query.types.map(|types| {
+        types
+            .split(',')
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect()
+    });
+    let events = live::follow_all(state.events.clone(), after, types).await;
+
+    live::respond(ws, events, |event| event.seq)
+}
+
+// ---- Payment ledger ----
+
+/// Page size of `GET /ledger`, unless asked otherwise
+const LEDGER_LIMIT: u64 = 50;
+const LEDGER_MAX_LIMIT: u64 = 500;
+
+// GET /ledger
+#[derive(Deserialize)]
+pub struct LedgerFilters {
+    pub main_key: Option<String>,
+    pub subscription_id: Option<String>,
+    pub kind: Option<LedgerKind>,
+    pub status: Option<LedgerStatus>,
+    /// Unix timestamps bounding the creation time of the entries
+    pub from: Option<u64>,
+    pub until: Option<u64>,
+    pub offset: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+impl LedgerFilters {
+    fn into_query(self) -> Result<LedgerQuery, (StatusCode, Json<ApiResponse<()>>)> {
+        let main_key = self
+            .main_key
+            .map(|key| hex_to_pubkey(&key).map(|key| key.to_string()))
+            .transpose()
+            .map_err(|e| bad_request(format!("Invalid main key: {e}")))?;
+
+        Ok(LedgerQuery {
+            main_key,
+            subscription_id: self.subscription_id,
+            kind: self.kind,
+            status: self.status,
+            from: self.from,
+            until: self.until,
+            offset: self.offset.unwrap_or(0),
+            limit: self.limit,
+        })
+    }
+}
+
+pub async fn list_ledger(
+    State(state): State<AppState>,
+    Query(filters): Query<LedgerFilters>,
+) -> ApiResult<LedgerResponse> {
+    let mut query = filters.into_query()?;
+    let limit = query.limit.unwrap_or(LEDGER_LIMIT).min(LEDGER_MAX_LIMIT);
+    query.limit = Some(limit);
+
+    let entries = state
+        .ledger
+        .list(&query)
+        .await
+        .map_err(|e| internal_error(format!("Failed to list ledger entries: {e}")))?;
+
+    Ok(ok(LedgerResponse {
+        entries,
+        offset: query.offset,
+        limit,
+    }))
+}
+
+// GET /ledger/export
+#[derive(Deserialize)]
+pub struct LedgerExportQuery {
+    /// `csv` (default) or `json`
+    pub format: Option<String>,
+}
+
+/// All the entries matching the filters of `GET /ledger`, unpaginated unless `offset` or
+/// `limit` are set, as a CSV or JSON file.
+pub async fn export_ledger(
+    State(state): State<AppState>,
+    Query(filters): Query<LedgerFilters>,
+    Query(export): Query<LedgerExportQuery>,
+) -> Result<Response, (StatusCode, Json<ApiResponse<()>>)> {
+    let format = export.format.unwrap_or_else(|| "csv".to_string());
+    if format != "csv" && format != "json" {
+        return Err(bad_request(format!(
+            "Invalid format '{format}': expected 'csv' or 'json'"
+        )));
+    }
+
+    let query = filters.into_query()?;
+    let entries = state
+        .ledger
+        .list(&query)
+        .await
+        .map_err(|e| internal_error(format!("Failed to export ledger entries: {e}")))?;
+
+    let (content_type, body) = if format == "csv" {
+        let mut csv = format!("{}\r\n", ledger::CSV_HEADER);
+        for entry in &entries {
+            csv.push_str(&entry.csv_record());
+            csv.push_str("\r\n");
+        }
+        ("text/csv; charset=utf-8", csv)
+    } else {
+        let json = serde_json::to_string(&entries)
+            .map_err(|e| internal_error(format!("Failed to serialize ledger entries: {e}")))?;
+        ("application/json", json)
+    };
+    let disposition = format!("attachment; filename=\"ledger.{format}\"");
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use portal::router::SendOutcome;
+
+    use crate::config::WebhookSettings;
+
+    fn delivered() -> SendOutcome {
+        SendOutcome::Delivered {
+            relays: vec!["wss://relay.example".to_string()],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delivery_updates_are_pushed_to_their_stream() {
+        let events = EventStore::new(":memory:", WebhookSettings::default()).unwrap();
+        let queued = EventId::from_byte_array([1; 32]);
+        let dropped = EventId::from_byte_array([2; 32]);
+        let sent = EventId::from_byte_array([3; 32]);
+        let unknown = EventId::from_byte_array([4; 32]);
+
+        events
+            .track_queued_events(
+                "stream",
+                &[
+                    EventSendResult {
+                        event_id: queued,
+                        outcome: SendOutcome::Queued,
+                    },
+                    EventSendResult {
+                        event_id: dropped,
+                        outcome: SendOutcome::Queued,
+                    },
+                    EventSendResult {
+                        event_id: sent,
+                        outcome: delivered(),
+                    },
+                ],
+            )
+            .await;
+
+        let (updates, receiver) = broadcast::channel(16);
+        let forwarder = tokio::spawn(forward_delivery_updates(events.clone(), receiver));
+        for (event_id, outcome) in [
+            (queued, delivered()),
+            (dropped, SendOutcome::Dropped),
+            // Not queued, or not sent for a stream
+            (sent, delivered()),
+            (unknown, delivered()),
+        ] {
+            updates.send(EventSendResult { event_id, outcome }).unwrap();
+        }
+        // The forwarder stops once it went through all the updates
+        drop(updates);
+        forwarder.await.unwrap();
+
+        let pushed: Vec<(String, SendOutcome)> = events
+            .get("stream", None)
+            .await
+            .into_iter()
+            .map(|event| match event.data {
+                NotificationData::RelayDelivery { event_id, outcome } => (event_id, outcome),
+                data => panic!("unexpected event {data:?}"),
+            })
+            .collect();
+        assert_eq!(
+            pushed,
+            vec![
+                (queued.to_hex(), delivered()),
+                (dropped.to_hex(), SendOutcome::Dropped),
+            ]
+        );
+
+        // Each event is only reported once
+        assert_eq!(events.take_queued_event(&queued.to_hex()).await, None);
+        assert_eq!(events.take_queued_event(&dropped.to_hex()).await, None);
+    }
+}
@@ -0,0 +1,846 @@
+//! Payment ledger, for bookkeeping.
+//!
+//! Every payment the daemon requests, pays or collects gets an entry, from the request to its
+//! final status: who was asked to pay, how much and in which currency, at which exchange
+//! rate, and the preimage proving it settled.
+
+use std::sync::Arc;
+
+use portal::protocol::model::payment::{Currency, ExchangeRate, SinglePaymentRequestContent};
+use portal::protocol::model::Timestamp;
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::response::InvoiceStatus;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerKind {
+    /// `POST /payments/single`
+    SinglePayment,
+    /// `POST /payments/raw`
+    RawPayment,
+    /// Payment of a subscription collected by the billing engine
+    SubscriptionCharge,
+    /// `POST /invoices/pay`
+    InvoicePayment,
+    /// `POST /cashu/mint`
+    CashuMint,
+    /// `POST /cashu/burn`
+    CashuBurn,
+}
+
+impl LedgerKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::SinglePayment => "single_payment",
+            Self::RawPayment => "raw_payment",
+            Self::SubscriptionCharge => "subscription_charge",
+            Self::InvoicePayment => "invoice_payment",
+            Self::CashuMint => "cashu_mint",
+            Self::CashuBurn => "cashu_burn",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerDirection {
+    /// Paid to the service
+    Incoming,
+    /// Paid by the service
+    Outgoing,
+}
+
+impl LedgerDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Incoming => "incoming",
+            Self::Outgoing => "outgoing",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerStatus {
+    /// Requested, waiting for the user or the wallet
+    Pending,
+    /// Approved by the user, not paid yet
+    Approved,
+    /// Reported as paid by the user, without a preimage matching the invoice
+    Claimed,
+    Paid,
+    Rejected,
+    Failed,
+    /// Not paid before the request expired
+    Expired,
+}
+
+impl LedgerStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Approved => "approved",
+            Self::Claimed => "claimed",
+            Self::Paid => "paid",
+            Self::Rejected => "rejected",
+            Self::Failed => "failed",
+            Self::Expired => "expired",
+        }
+    }
+}
+
+const LEDGER_COLUMNS: &str = "id, kind, direction, status, stream_id, main_key, subscription_id,
+    description, amount, currency, exchange_rate, amount_msat, fees_msat, invoice, payment_hash,
+    preimage, mint_url, error, created_at, updated_at, settled_at";
+
+/// Columns of the CSV export, in the order of [`LedgerEntry::csv_record`].
+pub const CSV_HEADER: &str = "id,kind,direction,status,created_at,settled_at,main_key,\
+subscription_id,description,amount,currency,exchange_rate,exchange_rate_source,\
+exchange_rate_time,amount_msat,fees_msat,invoice,payment_hash,preimage,mint_url,error";
+
+/// A payment recorded in the ledger.
+#[derive(Debug, Clone, Serialize)]
+pub struct LedgerEntry {
+    pub id: String,
+    pub kind: LedgerKind,
+    pub direction: LedgerDirection,
+    pub status: LedgerStatus,
+    /// Stream the payment status updates are pushed to, if any
+    pub stream_id: Option<String>,
+    /// User asked to pay
+    pub main_key: Option<String>,
+    pub subscription_id: Option<String>,
+    pub description: Option<String>,
+    /// Amount as requested, in `currency` (millisats, fiat cents or Cashu unit)
+    pub amount: u64,
+    /// `Millisats`, a fiat currency code or the unit of a Cashu mint
+    pub currency: String,
+    /// Rate the fiat amount was converted with
+    pub exchange_rate: Option<ExchangeRate>,
+    /// Amount in millisats, if it's known
+    pub amount_msat: Option<u64>,
+    /// Routing fees paid on outgoing payments
+    pub fees_msat: Option<u64>,
+    pub invoice: Option<String>,
+    pub payment_hash: Option<String>,
+    pub preimage: Option<String>,
+    pub mint_url: Option<String>,
+    /// Why the payment failed or was rejected
+    pub error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub settled_at: Option<u64>,
+}
+
+impl LedgerEntry {
+    pub fn new(
+        kind: LedgerKind,
+        direction: LedgerDirection,
+        amount: u64,
+        currency: String,
+    ) -> Self {
+        let now = Timestamp::now().as_u64();
+        Self {
+            id: Uuid::new_v4().to_string(),
+            kind,
+            direction,
+            status: LedgerStatus::Pending,
+            stream_id: None,
+            main_key: None,
+            subscription_id: None,
+            description: None,
+            amount,
+            currency,
+            exchange_rate: None,
+            amount_msat: None,
+            fees_msat: None,
+            invoice: None,
+            payment_hash: None,
+            preimage: None,
+            mint_url: None,
+            error: None,
+            created_at: now,
+            updated_at: now,
+            settled_at: None,
+        }
+    }
+
+    /// A payment requested from `main_key`, to be paid to the service.
+    pub fn payment_request(
+        kind: LedgerKind,
+        main_key: String,
+        request: &SinglePaymentRequestContent,
+    ) -> Self {
+        let mut entry = Self::new(
+            kind,
+            LedgerDirection::Incoming,
+            request.amount.as_u64(),
+            currency_code(&request.currency),
+        );
+        entry.main_key = Some(main_key);
+        entry.subscription_id = request.subscription_id.clone();
+        entry.description = request.description.clone();
+        entry.exchange_rate = request.current_exchange_rate.clone();
+        entry.amount_msat = request.amount_millisats();
+        entry.invoice = Some(request.invoice.clone());
+        entry.payment_hash = payment_hash(&request.invoice);
+        entry
+    }
+
+    /// Mark the entry as paid when it's recorded, for the payments that settle right away.
+    pub fn paid(mut self) -> Self {
+        self.status = LedgerStatus::Paid;
+        self.settled_at = Some(self.created_at);
+        self
+    }
+
+    /// The entry as a CSV line, dates in RFC 3339.
+    pub fn csv_record(&self) -> String {
+        let rate = self.exchange_rate.as_ref();
+        let fields = [
+            self.id.clone(),
+            self.kind.as_str().to_string(),
+            self.direction.as_str().to_string(),
+            self.status.as_str().to_string(),
+            rfc3339(Some(self.created_at)),
+            rfc3339(self.settled_at),
+            self.main_key.clone().unwrap_or_default(),
+            self.subscription_id.clone().unwrap_or_default(),
+            self.description.clone().unwrap_or_default(),
+            self.amount.to_string(),
+            self.currency.clone(),
+            rate.map(|rate| rate.rate.to_string()).unwrap_or_default(),
+            rate.map(|rate| rate.source.clone()).unwrap_or_default(),
+            rfc3339(rate.map(|rate| rate.time.as_u64())),
+            self.amount_msat
+                .map(|msat| msat.to_string())
+                .unwrap_or_default(),
+            self.fees_msat
+                .map(|msat| msat.to_string())
+                .unwrap_or_default(),
+            self.invoice.clone().unwrap_or_default(),
+            self.payment_hash.clone().unwrap_or_default(),
+            self.preimage.clone().unwrap_or_default(),
+            self.mint_url.clone().unwrap_or_default(),
+            self.error.clone().unwrap_or_default(),
+        ];
+        fields
+            .iter()
+            .map(|field| csv_field(field))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        let kind: String = row.get(1)?;
+        let direction: String = row.get(2)?;
+        let status: String = row.get(3)?;
+        let exchange_rate: Option<String> = row.get(10)?;
+        Ok(Self {
+            id: row.get(0)?,
+            // Only valid values are saved
+            kind: serde_json::from_value(kind.into()).map_err(from_json_error)?,
+            direction: serde_json::from_value(direction.into()).map_err(from_json_error)?,
+            status: serde_json::from_value(status.into()).map_err(from_json_error)?,
+            stream_id: row.get(4)?,
+            main_key: row.get(5)?,
+            subscription_id: row.get(6)?,
+            description: row.get(7)?,
+            amount: row.get::<_, i64>(8)? as u64,
+            currency: row.get(9)?,
+            exchange_rate: exchange_rate.and_then(|rate| serde_json::from_str(&rate).ok()),
+            amount_msat: row.get::<_, Option<i64>>(11)?.map(|msat| msat as u64),
+            fees_msat: row.get::<_, Option<i64>>(12)?.map(|msat| msat as u64),
+            invoice: row.get(13)?,
+            payment_hash: row.get(14)?,
+            preimage: row.get(15)?,
+            mint_url: row.get(16)?,
+            error: row.get(17)?,
+            created_at: row.get::<_, i64>(18)? as u64,
+            updated_at: row.get::<_, i64>(19)? as u64,
+            settled_at: row.get::<_, Option<i64>>(20)?.map(|at| at as u64),
+        })
+    }
+}
+
+/// New status of a ledger entry.
+#[derive(Debug, Clone)]
+pub struct LedgerUpdate {
+    pub status: LedgerStatus,
+    pub preimage: Option<String>,
+    pub fees_msat: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl LedgerUpdate {
+    pub fn status(status: LedgerStatus) -> Self {
+        Self {
+            status,
+            preimage: None,
+            fees_msat: None,
+            error: None,
+        }
+    }
+
+    pub fn paid(preimage: Option<String>) -> Self {
+        Self {
+            preimage,
+            ..Self::status(LedgerStatus::Paid)
+        }
+    }
+
+    /// The user reports the payment as sent. Only settles the entry if `preimage` is the
+    /// preimage of its invoice: the app of the user could claim anything.
+    pub fn claimed(preimage: Option<String>) -> Self {
+        Self {
+            preimage,
+            ..Self::status(LedgerStatus::Claimed)
+        }
+    }
+
+    pub fn error(status: LedgerStatus, reason: Option<String>) -> Self {
+        Self {
+            error: reason,
+            ..Self::status(status)
+        }
+    }
+
+    /// The update matching a status pushed to a payment stream.
+    pub fn from_invoice_status(status: &InvoiceStatus) -> Self {
+        match status {
+            InvoiceStatus::Paid { preimage } => Self::paid(preimage.clone()),
+            InvoiceStatus::UserSuccess { preimage } => Self::claimed(preimage.clone()),
+            InvoiceStatus::Timeout => Self::status(LedgerStatus::Expired),
+            InvoiceStatus::Error { reason } => {
+                Self::error(LedgerStatus::Failed, Some(reason.clone()))
+            }
+            InvoiceStatus::UserApproved => Self::status(LedgerStatus::Approved),
+            InvoiceStatus::UserFailed { reason } => {
+                Self::error(LedgerStatus::Failed, reason.clone())
+            }
+            InvoiceStatus::UserRejected { reason } => {
+                Self::error(LedgerStatus::Rejected, reason.clone())
+            }
+        }
+    }
+}
+
+/// Filters of [`PaymentLedger::list`].
+#[derive(Debug, Clone, Default)]
+pub struct LedgerQuery {
+    pub main_key: Option<String>,
+    pub subscription_id: Option<String>,
+    pub kind: Option<LedgerKind>,
+    pub status: Option<LedgerStatus>,
+    /// Unix timestamps bounding the creation time of the entries
+    pub from: Option<u64>,
+    pub until: Option<u64>,
+    pub offset: u64,
+    /// All the matching entries if not set
+    pub limit: Option<u64>,
+}
+
+/// SQLite-backed payment ledger.
+///
+/// Entries are created when a payment is requested or made, and updated as its status
+/// changes. A final status is only replaced by `paid`: an invoice paid after its request
+/// expired was still paid. `claimed` isn't final, the wallet may still see the invoice paid.
+#[derive(Clone)]
+pub struct PaymentLedger {
+    db: Arc<Mutex<Connection>>,
+}
+
+impl PaymentLedger {
+    /// Open (or create) the SQLite database at `db_path` and initialize the schema.
+    pub fn new(db_path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open(db_path)?;
+
+        conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS payment_ledger (
+                id TEXT PRIMARY KEY,
+                kind TEXT NOT NULL,
+                direction TEXT NOT NULL,
+                status TEXT NOT NULL,
+                stream_id TEXT,
+                main_key TEXT,
+                subscription_id TEXT,
+                description TEXT,
+                amount INTEGER NOT NULL,
+                currency TEXT NOT NULL,
+                exchange_rate TEXT,
+                amount_msat INTEGER,
+                fees_msat INTEGER,
+                invoice TEXT,
+                payment_hash TEXT,
+                preimage TEXT,
+                mint_url TEXT,
+                error TEXT,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                settled_at INTEGER
+            );
+            CREATE INDEX IF NOT EXISTS idx_payment_ledger_created_at
+                ON payment_ledger(created_at);
+            CREATE INDEX IF NOT EXISTS idx_payment_ledger_main_key
+                ON payment_ledger(main_key);
+            CREATE INDEX IF NOT EXISTS idx_payment_ledger_stream_id
+                ON payment_ledger(stream_id);
+            CREATE INDEX IF NOT EXISTS idx_payment_ledger_subscription_id
+                ON payment_ledger(subscription_id);",
+        )?;
+
+        info!("Payment ledger opened at {db_path}");
+
+        Ok(Self {
+            db: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    pub async fn record(&self, entry: &LedgerEntry) -> anyhow::Result<()> {
+        let exchange_rate = entry
+            .exchange_rate
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+
+        let db = self.db.lock().await;
+        db.execute(
+            &format!(
+                "INSERT INTO payment_ledger ({LEDGER_COLUMNS})
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16,
+                         ?17, ?18, ?19, ?20, ?21)"
+            ),
+            rusqlite::params![
+                entry.id,
+                entry.kind.as_str(),
+                entry.direction.as_str(),
+                entry.status.as_str(),
+                entry.stream_id,
+                entry.main_key,
+                entry.subscription_id,
+                entry.description,
+                entry.amount as i64,
+                entry.currency,
+                exchange_rate,
+                entry.amount_msat.map(|msat| msat as i64),
+                entry.fees_msat.map(|msat| msat as i64),
+                entry.invoice,
+                entry.payment_hash,
+                entry.preimage,
+                entry.mint_url,
+                entry.error,
+                entry.created_at as i64,
+                entry.updated_at as i64,
+                entry.settled_at.map(|at| at as i64),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Update the entry with the given ID.
+    pub async fn update(&self, id: &str, update: LedgerUpdate) -> anyhow::Result<()> {
+        self.update_where("id", id, update).await
+    }
+
+    /// Update the entry of a payment stream.
+    pub async fn update_stream(&self, stream_id: &str, update: LedgerUpdate) -> anyhow::Result<()> {
+        self.update_where("stream_id", stream_id, update).await
+    }
+
+    async fn update_where(
+        &self,
+        column: &str,
+        value: &str,
+        mut update: LedgerUpdate,
+    ) -> anyhow::Result<()> {
+        let db = self.db.lock().await;
+
+        if update.status == LedgerStatus::Claimed {
+            let payment_hash: Option<String> = db
+                .query_row(
+                    &format!("SELECT payment_hash FROM payment_ledger WHERE {column} = ?1"),
+                    rusqlite::params![value],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .flatten();
+            let proven = update
+                .preimage
+                .as_deref()
+                .zip(payment_hash.as_deref())
+                .is_some_and(|(preimage, hash)| preimage_matches(preimage, hash));
+            if proven {
+                update.status = LedgerStatus::Paid;
+            } else {
+                update.preimage = None;
+            }
+        }
+
+        db.execute(
+            &format!(
+                "UPDATE payment_ledger SET
+                     status = ?1,
+                     preimage = COALESCE(?2, preimage),
+                     fees_msat = COALESCE(?3, fees_msat),
+                     error = ?4,
+                     updated_at = ?5,
+                     settled_at = CASE WHEN ?1 = 'paid' THEN ?5 ELSE settled_at END
+                 WHERE {column} = ?6
+                   AND status != 'paid'
+                   AND (status IN ('pending', 'approved', 'claimed') OR ?1 = 'paid')"
+            ),
+            rusqlite::params![
+                update.status.as_str(),
+                update.preimage,
+                update.fees_msat.map(|msat| msat as i64),
+                update.error,
+                Timestamp::now().as_u64() as i64,
+                value,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The entries matching `query`, newest first.
+    pub async fn list(&self, query: &LedgerQuery) -> anyhow::Result<Vec<LedgerEntry>> {
+        let db = self.db.lock().await;
+        let mut stmt = db.prepare(&format!(
+            "SELECT {LEDGER_COLUMNS} FROM payment_ledger
+             WHERE (?1 IS NULL OR main_key = ?1)
+               AND (?2 IS NULL OR subscription_id = ?2)
+               AND (?3 IS NULL OR kind = ?3)
+               AND (?4 IS NULL OR status = ?4)
+               AND (?5 IS NULL OR created_at >= ?5)
+               AND (?6 IS NULL OR created_at <= ?6)
+             ORDER BY created_at DESC, rowid DESC
+             LIMIT ?7 OFFSET ?8"
+        ))?;
+        let rows = stmt.query_map(
+            rusqlite::params![
+                query.main_key,
+                query.subscription_id,
+                query.kind.map(|kind| kind.as_str()),
+                query.status.map(|status| status.as_str()),
+                query.from.map(|from| from as i64),
+                query.until.map(|until| until as i64),
+                // A negative limit is no limit
+                query.limit.map_or(-1, |limit| limit as i64),
+                query.offset as i64,
+            ],
+            LedgerEntry::from_row,
+        )?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+}
+
+/// How a currency is written in the ledger: `Millisats` or the fiat currency code, like in
+/// the payment requests.
+pub fn currency_code(currency: &Currency) -> String {
+    match currency {
+        Currency::Millisats => "Millisats".to_string(),
+        Currency::Fiat(code) => code.clone(),
+    }
+}
+
+/// Amount in millisats of an amount in a Cashu unit, for the units that are bitcoin.
+pub fn cashu_amount_msat(amount: u64, unit: &str) -> Option<u64> {
+    match unit {
+        "sat" => Some(amount * 1000),
+        "msat" => Some(amount),
+        _ => None,
+    }
+}
+
+/// Payment hash of a BOLT11 invoice, if it can be parsed.
+pub fn payment_hash(invoice: &str) -> Option<String> {
+    invoice
+        .parse::<lightning_invoice::Bolt11Invoice>()
+        .ok()
+        .map(|invoice| invoice.payment_hash().to_string())
+}
+
+/// Whether `preimage` hashes to `payment_hash`, both hex encoded.
+fn preimage_matches(preimage: &str, payment_hash: &str) -> bool {
+    hex::decode(preimage).is_ok_and(|preimage| {
+        hex::encode(Sha256::digest(preimage)).eq_ignore_ascii_case(payment_hash)
+    })
+}
+
+fn from_json_error(e: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+}
+
+fn rfc3339(timestamp: Option<u64>) -> String {
+    timestamp
+        .and_then(|timestamp| chrono::DateTime::from_timestamp(timestamp as i64, 0))
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_default()
+}
+
+/// Quote a CSV field if needed (RFC 4180).
+///
+/// A field starting like a formula is prefixed with `'`, so spreadsheets don't evaluate
+/// descriptions and errors coming from users.
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{field}")
+    } else {
+        field.to_string()
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PREIMAGE: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+
+    fn ledger() -> PaymentLedger {
+        PaymentLedger::new(":memory:").unwrap()
+    }
+
+    fn invoice_entry(stream_id: &str) -> LedgerEntry {
+        let mut entry = LedgerEntry::new(
+            LedgerKind::SinglePayment,
+            LedgerDirection::Incoming,
+            1000,
+            "Millisats".to_string(),
+        );
+        entry.stream_id = Some(stream_id.to_string());
+        entry.payment_hash = Some(hex::encode(Sha256::digest(hex::decode(PREIMAGE).unwrap())));
+        entry
+    }
+
+    async fn get(ledger: &PaymentLedger, id: &str) -> LedgerEntry {
+        ledger
+            .list(&LedgerQuery::default())
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|entry| entry.id == id)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_update_state_transitions() {
+        let ledger = ledger();
+        let entry = invoice_entry("stream");
+        ledger.record(&entry).await.unwrap();
+
+        ledger
+            .update_stream(
+                "stream",
+                LedgerUpdate::from_invoice_status(&InvoiceStatus::UserApproved),
+            )
+            .await
+            .unwrap();
+        assert_eq!(get(&ledger, &entry.id).await.status, LedgerStatus::Approved);
+
+        ledger
+            .update_stream(
+                "stream",
+                LedgerUpdate::from_invoice_status(&InvoiceStatus::Timeout),
+            )
+            .await
+            .unwrap();
+        assert_eq!(get(&ledger, &entry.id).await.status, LedgerStatus::Expired);
+
+        // A final status is only replaced by `paid`
+        ledger
+            .update_stream("stream", LedgerUpdate::status(LedgerStatus::Approved))
+            .await
+            .unwrap();
+        assert_eq!(get(&ledger, &entry.id).await.status, LedgerStatus::Expired);
+
+        ledger
+            .update(&entry.id, LedgerUpdate::paid(Some(PREIMAGE.to_string())))
+            .await
+            .unwrap();
+        let paid = get(&ledger, &entry.id).await;
+        assert_eq!(paid.status, LedgerStatus::Paid);
+        assert_eq!(paid.preimage.as_deref(), Some(PREIMAGE));
+        assert!(paid.settled_at.is_some());
+
+        // And `paid` is never replaced
+        ledger
+            .update(
+                &entry.id,
+                LedgerUpdate::error(LedgerStatus::Failed, Some("late".to_string())),
+            )
+            .await
+            .unwrap();
+        let paid = get(&ledger, &entry.id).await;
+        assert_eq!(paid.status, LedgerStatus::Paid);
+        assert_eq!(paid.error, None);
+    }
+
+    #[tokio::test]
+    async fn test_user_success_with_matching_preimage_is_paid() {
+        let ledger = ledger();
+        let entry = invoice_entry("stream");
+        ledger.record(&entry).await.unwrap();
+
+        let status = InvoiceStatus::UserSuccess {
+            preimage: Some(PREIMAGE.to_string()),
+        };
+        ledger
+            .update_stream("stream", LedgerUpdate::from_invoice_status(&status))
+            .await
+            .unwrap();
+
+        let paid = get(&ledger, &entry.id).await;
+        assert_eq!(paid.status, LedgerStatus::Paid);
+        assert_eq!(paid.preimage.as_deref(), Some(PREIMAGE));
+    }
+
+    #[tokio::test]
+    async fn test_user_success_without_matching_preimage_is_claimed() {
+        let ledger = ledger();
+        let entry = invoice_entry("stream");
+        ledger.record(&entry).await.unwrap();
+
+        for preimage in [None, Some("not hex".to_string()), Some("02".repeat(32))] {
+            let status = InvoiceStatus::UserSuccess { preimage };
+            ledger
+                .update_stream("stream", LedgerUpdate::from_invoice_status(&status))
+                .await
+                .unwrap();
+
+            let claimed = get(&ledger, &entry.id).await;
+            assert_eq!(claimed.status, LedgerStatus::Claimed);
+            assert_eq!(claimed.preimage, None);
+            assert_eq!(claimed.settled_at, None);
+        }
+
+        // The wallet can still see the invoice paid
+        let status = InvoiceStatus::Paid {
+            preimage: Some(PREIMAGE.to_string()),
+        };
+        ledger
+            .update_stream("stream", LedgerUpdate::from_invoice_status(&status))
+            .await
+            .unwrap();
+        assert_eq!(get(&ledger, &entry.id).await.status, LedgerStatus::Paid);
+    }
+
+    #[tokio::test]
+    async fn test_list_filters_and_pagination() {
+        let ledger = ledger();
+        let mut ids = Vec::new();
+        for i in 0..5u64 {
+            let mut entry = invoice_entry(&format!("stream-{i}"));
+            entry.main_key = Some(if i % 2 == 0 { "alice" } else { "bob" }.to_string());
+            entry.created_at = 1000 + i;
+            if i == 4 {
+                entry.kind = LedgerKind::SubscriptionCharge;
+                entry.subscription_id = Some("subscription".to_string());
+                entry = entry.paid();
+            }
+            ledger.record(&entry).await.unwrap();
+            ids.push(entry.id);
+        }
+        let list = |query: LedgerQuery| {
+            let ledger = ledger.clone();
+            async move {
+                ledger
+                    .list(&query)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|entry| entry.id)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        // Newest first
+        let all = list(LedgerQuery::default()).await;
+        assert_eq!(all, ids.iter().rev().cloned().collect::<Vec<_>>());
+
+        let alice = list(LedgerQuery {
+            main_key: Some("alice".to_string()),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(alice, vec![ids[4].clone(), ids[2].clone(), ids[0].clone()]);
+
+        let charges = list(LedgerQuery {
+            kind: Some(LedgerKind::SubscriptionCharge),
+            subscription_id: Some("subscription".to_string()),
+            status: Some(LedgerStatus::Paid),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(charges, vec![ids[4].clone()]);
+
+        let range = list(LedgerQuery {
+            from: Some(1001),
+            until: Some(1003),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(range, vec![ids[3].clone(), ids[2].clone(), ids[1].clone()]);
+
+        let page = list(LedgerQuery {
+            offset: 1,
+            limit: Some(2),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(page, vec![ids[3].clone(), ids[2].clone()]);
+
+        let past_the_end = list(LedgerQuery {
+            offset: 5,
+            ..Default::default()
+        })
+        .await;
+        assert!(past_the_end.is_empty());
+    }
+
+    #[test]
+    fn test_csv_field_escaping() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn test_csv_field_formula_injection() {
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1"), "'+1");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("\tcmd"), "'\tcmd");
+        assert_eq!(csv_field("\rcmd"), "\"'\rcmd\"");
+        assert_eq!(csv_field("a=b"), "a=b");
+    }
+
+    #[test]
+    fn test_csv_record_matches_header() {
+        let mut entry = invoice_entry("stream");
+        entry.description = Some("=1+1, please".to_string());
+        let record = entry.csv_record();
+        assert!(record.contains("\"'=1+1, please\""));
+        assert_eq!(
+            record
+                .replace("\"'=1+1, please\"", "description")
+                .split(',')
+                .count(),
+            CSV_HEADER.split(',').count()
+        );
+    }
+}
@@ -0,0 +1,375 @@
+//! Live event streaming, over Server-Sent Events or a WebSocket.
+//!
+//! The events stored before the client connected are replayed from the [`EventStore`], then
+//! the new ones are pushed as they come. A client that falls behind catches up from the
+//! database, so no event is skipped or sent twice.
+
+use std::collections::{HashSet, VecDeque};
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::debug;
+
+use crate::events::EventStore;
+use crate::response::{LiveEvent, StreamEvent};
+
+/// Events read from the database at once when replaying all the streams.
+const REPLAY_BATCH: u64 = 500;
+
+/// The `Last-Event-ID` header sent by `EventSource` when it reconnects.
+pub fn last_event_id(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Follow the events of a stream with an index > `after`.
+pub fn follow_stream(
+    events: EventStore,
+    stream_id: String,
+    after: Option<u64>,
+) -> impl Stream<Item = StreamEvent> {
+    // Subscribed before replaying, so the events pushed meanwhile aren't missed
+    let follower = StreamFollower {
+        receiver: events.subscribe(),
+        events,
+        stream_id,
+        after,
+        pending: VecDeque::new(),
+        replay: true,
+    };
+    futures::stream::unfold(follower, |mut follower| async move {
+        let event = follower.next().await?;
+        Some((event, follower))
+    })
+}
+
+/// Follow the events of all the streams, those with a sequence number > `after` or only the
+/// new ones. `types` filters the events by notification type.
+pub async fn follow_all(
+    events: EventStore,
+    after: Option<u64>,
+    types: Option<HashSet<String>>,
+) -> impl Stream<Item = LiveEvent> {
+    let receiver = events.subscribe();
+    let (after, catching_up) = match after {
+        Some(after) => (after, true),
+        None => (events.last_seq().await, false),
+    };
+    let follower = AllFollower {
+        events,
+        receiver,
+        types,
+        after,
+        pending: VecDeque::new(),
+        catching_up,
+    };
+    futures::stream::unfold(follower, |mut follower| async move {
+        let event = follower.next().await?;
+        Some((event, follower))
+    })
+}
+
+struct StreamFollower {
+    events: EventStore,
+    receiver: broadcast::Receiver<LiveEvent>,
+    stream_id: String,
+    /// Index of the last event sent
+    after: Option<u64>,
+    pending: VecDeque<StreamEvent>,
+    /// Whether the events after `after` must be read from the database
+    replay: bool,
+}
+
+impl StreamFollower {
+    async fn next(&mut self) -> Option<StreamEvent> {
+        loop {
+            if self.replay {
+                self.replay = false;
+                self.pending = self.events.get(&self.stream_id, self.after).await.into();
+            }
+            if let Some(event) = self.pending.pop_front() {
+                self.after = Some(event.index);
+                return Some(event);
+            }
+
+            match self.receiver.recv().await {
+                Ok(live) => {
+                    let new = self.after.map_or(true, |after| live.event.index > after);
+                    if live.stream_id == self.stream_id && new {
+                        self.pending.push_back(live.event);
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!(
+                        "Live subscriber of stream {} lagged by {skipped} events",
+                        self.stream_id
+                    );
+                    self.replay = true;
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+struct AllFollower {
+    events: EventStore,
+    receiver: broadcast::Receiver<LiveEvent>,
+    types: Option<HashSet<String>>,
+    /// Sequence number of the last event seen, sent or filtered out
+    after: u64,
+    pending: VecDeque<LiveEvent>,
+    /// Whether the events after `after` must be read from the database
+    catching_up: bool,
+}
+
+impl AllFollower {
+    fn accepts(&self, event: &LiveEvent) -> bool {
+        self.types
+            .as_ref()
+            .map_or(true, |types| types.contains(&event.event.data.type_name()))
+    }
+
+    async fn next(&mut self) -> Option<LiveEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+
+            if self.catching_up {
+                let batch = self.events.get_all(self.after, REPLAY_BATCH).await;
+                self.catching_up = batch.len() as u64 == REPLAY_BATCH;
+                if let Some(last) = batch.last() {
+                    self.after = last.seq;
+                }
+                let accepted: Vec<_> = batch.into_iter().filter(|e| self.accepts(e)).collect();
+                self.pending.extend(accepted);
+                continue;
+            }
+
+            match self.receiver.recv().await {
+                Ok(event) if event.seq > self.after => {
+                    self.after = event.seq;
+                    if self.accepts(&event) {
+                        self.pending.push_back(event);
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("Live subscriber of all streams lagged by {skipped} events");
+                    self.catching_up = true;
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Send the events over a WebSocket if the client asked to upgrade, as Server-Sent Events
+/// otherwise. `id` is the ID of an event, sent back by `EventSource` in `Last-Event-ID` when
+/// it reconnects.
+pub fn respond<T, S>(ws: Option<WebSocketUpgrade>, events: S, id: fn(&T) -> u64) -> Response
+where
+    T: Serialize + Send + 'static,
+    S: Stream<Item = T> + Send + 'static,
+{
+    match ws {
+        Some(ws) => ws.on_upgrade(move |socket| forward(socket, events)),
+        None => {
+            let events = events.map(move |event| {
+                Event::default()
+                    .id(id(&event).to_string())
+                    .json_data(&event)
+            });
+            Sse::new(events)
+                .keep_alive(KeepAlive::default())
+                .into_response()
+        }
+    }
+}
+
+/// Send the events as JSON text messages, until the client closes the socket.
+async fn forward<T: Serialize>(mut socket: WebSocket, events: impl Stream<Item = T>) {
+    let mut events = Box::pin(events);
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else { break };
+                let text = match serde_json::to_string(&event) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        debug!("Failed to serialize live event: {e}");
+                        continue;
+                    }
+                };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use portal::router::SendOutcome;
+
+    use super::*;
+    use crate::config::WebhookSettings;
+    use crate::events::LIVE_EVENTS_CAPACITY;
+    use crate::response::NotificationData;
+
+    /// More events than a live subscriber buffers, so it lags
+    const LAGGING_EVENTS: usize = LIVE_EVENTS_CAPACITY + 100;
+
+    fn store() -> EventStore {
+        EventStore::new(":memory:", WebhookSettings::default()).unwrap()
+    }
+
+    fn relay_delivery() -> NotificationData {
+        NotificationData::RelayDelivery {
+            event_id: "event".to_string(),
+            outcome: SendOutcome::Queued,
+        }
+    }
+
+    fn error() -> NotificationData {
+        NotificationData::Error {
+            reason: "test".to_string(),
+        }
+    }
+
+    /// The next item of `stream`, `None` if it doesn't come right away
+    async fn next_now<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+        tokio::time::timeout(Duration::from_millis(100), stream.next())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn take<S: Stream + Unpin>(stream: &mut S, n: usize) -> Vec<S::Item> {
+        let mut items = Vec::with_capacity(n);
+        for _ in 0..n {
+            items.push(next_now(stream).await.expect("missing event"));
+        }
+        items
+    }
+
+    #[tokio::test]
+    async fn test_stream_follower_replays_then_follows() {
+        let store = store();
+        store.push("stream", relay_delivery()).await;
+        store.push("other", relay_delivery()).await;
+        store.push("stream", relay_delivery()).await;
+
+        let mut follower = Box::pin(follow_stream(store.clone(), "stream".to_string(), None));
+        // Pushed before the follower reads the database: both replayed and received live
+        store.push("stream", relay_delivery()).await;
+
+        let events = take(&mut follower, 3).await;
+        let indexes: Vec<u64> = events.iter().map(|event| event.index).collect();
+        assert_eq!(indexes, vec![0, 1, 2]);
+
+        store.push("other", relay_delivery()).await;
+        store.push("stream", relay_delivery()).await;
+        assert_eq!(next_now(&mut follower).await.unwrap().index, 3);
+        assert!(next_now(&mut follower).await.is_none());
+
+        // Resumed after the last event seen
+        let mut follower = Box::pin(follow_stream(store.clone(), "stream".to_string(), Some(1)));
+        let events = take(&mut follower, 2).await;
+        let indexes: Vec<u64> = events.iter().map(|event| event.index).collect();
+        assert_eq!(indexes, vec![2, 3]);
+        assert!(next_now(&mut follower).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_follower_catches_up_after_lagging() {
+        let store = store();
+        store.push("stream", relay_delivery()).await;
+
+        let mut follower = Box::pin(follow_stream(store.clone(), "stream".to_string(), None));
+        assert_eq!(next_now(&mut follower).await.unwrap().index, 0);
+
+        for _ in 0..LAGGING_EVENTS / 2 {
+            store.push("stream", relay_delivery()).await;
+            store.push("other", relay_delivery()).await;
+        }
+
+        let events = take(&mut follower, LAGGING_EVENTS / 2).await;
+        let indexes: Vec<u64> = events.iter().map(|event| event.index).collect();
+        let expected: Vec<u64> = (1..=(LAGGING_EVENTS / 2) as u64).collect();
+        assert_eq!(indexes, expected);
+        assert!(next_now(&mut follower).await.is_none());
+
+        store.push("stream", relay_delivery()).await;
+        assert_eq!(
+            next_now(&mut follower).await.unwrap().index,
+            (LAGGING_EVENTS / 2) as u64 + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_all_follower_catches_up_in_order() {
+        let store = store();
+        for stream_id in ["a", "b", "a"] {
+            store.push(stream_id, relay_delivery()).await;
+        }
+
+        let mut follower = Box::pin(follow_all(store.clone(), Some(0), None).await);
+        // Pushed while the follower catches up, then enough for it to lag, over several
+        // batches read from the database
+        store.push("b", relay_delivery()).await;
+        for i in 0..LAGGING_EVENTS {
+            let stream_id = if i % 2 == 0 { "a" } else { "b" };
+            store.push(stream_id, relay_delivery()).await;
+        }
+
+        let total = 4 + LAGGING_EVENTS;
+        let events = take(&mut follower, total).await;
+        let seqs: Vec<u64> = events.iter().map(|event| event.seq).collect();
+        let expected: Vec<u64> = (1..=total as u64).collect();
+        assert_eq!(seqs, expected);
+        assert!(next_now(&mut follower).await.is_none());
+
+        store.push("c", relay_delivery()).await;
+        let event = next_now(&mut follower).await.unwrap();
+        assert_eq!(event.seq, total as u64 + 1);
+        assert_eq!(event.stream_id, "c");
+    }
+
+    #[tokio::test]
+    async fn test_all_follower_filters_new_events_by_type() {
+        let store = store();
+        store.push("old", relay_delivery()).await;
+
+        let types = HashSet::from(["relay_delivery".to_string()]);
+        let mut follower = Box::pin(follow_all(store.clone(), None, Some(types)).await);
+        for _ in 0..LAGGING_EVENTS / 2 {
+            store.push("a", relay_delivery()).await;
+            store.push("b", error()).await;
+        }
+
+        // Only the new relay deliveries, the ones before lagging included
+        let events = take(&mut follower, LAGGING_EVENTS / 2).await;
+        assert!(events.iter().all(|event| event.stream_id == "a"));
+        let indexes: Vec<u64> = events.iter().map(|event| event.event.index).collect();
+        let expected: Vec<u64> = (0..(LAGGING_EVENTS / 2) as u64).collect();
+        assert_eq!(indexes, expected);
+        assert!(next_now(&mut follower).await.is_none());
+    }
+}
@@ -1,38 +1,65 @@
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::str::FromStr;
 use std::sync::Arc;
 
 use axum::{
-    extract::State,
-    http::{Request, StatusCode},
+    body::{boxed, Body, Bytes, Full},
+    extract::{FromRequest, State},
+    http::{header, request, HeaderValue, Method, Request, StatusCode},
     middleware::{self, Next},
-    response::Response,
+    response::{IntoResponse, Response},
     routing::{delete, get, post},
     Json, Router,
 };
+use portal::protocol::model::auth::SubkeyProof;
 use portal::protocol::LocalKeypair;
+use portal::router::{ResumedConversation, RouterStorage};
 use portal_sdk::PortalSDK;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::trace::TraceLayer;
 use tracing::{info, warn, error};
 #[cfg(not(feature = "task-tracing"))]
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod api_keys;
+mod billing;
 mod command;
 mod config;
 mod constants;
+mod conversations;
 mod events;
 mod handlers;
+mod ledger;
+mod live;
+mod outbox;
 mod response;
+mod sessions;
+mod settlements;
 mod webhook;
 
 // Re-export the portal types that we need
 pub use portal::nostr::key::PublicKey;
 
-use portal_wallet::PortalWallet;
+use portal_wallet::{MockWallet, PortalWallet};
 use portal_macros::fetch_git_hash;
 
+use crate::api_keys::{Caller, Scope};
+use crate::events::{EventStore, IdempotencyClaim};
+
+/// Header naming the identity a request is for, instead of the `/identities/<name>` prefix.
+const IDENTITY_HEADER: &str = "X-Portal-Identity";
+
+/// Header making a POST safe to retry: the retries of a request get its response back
+/// instead of being executed again.
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+/// Set on the responses returned to the retries of a request with an idempotency key.
+const IDEMPOTENT_REPLAYED_HEADER: &str = "Idempotent-Replayed";
+/// Longest accepted idempotency key.
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
 /// Build-time version from Cargo.toml (used for Docker image tagging and runtime /version endpoint).
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 /// Git commit hash at build time (from portal_macros::fetch_git_hash! or PORTAL_GIT_HASH env).
@@ -44,6 +71,21 @@ enum ApiError {
     #[error("Authentication failed: {0}")]
     AuthenticationError(String),
 
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Unprocessable entity: {0}")]
+    UnprocessableEntity(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
     #[error("SDK error: {0}")]
     SdkError(#[from] portal_sdk::PortalSDKError),
 
@@ -55,6 +97,11 @@ impl From<ApiError> for (StatusCode, Json<ErrorResponse>) {
     fn from(error: ApiError) -> Self {
         let status = match &error {
             ApiError::AuthenticationError(_) => StatusCode::UNAUTHORIZED,
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::UnprocessableEntity(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
             ApiError::SdkError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             ApiError::AnyhowError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
@@ -74,8 +121,23 @@ pub struct AppState {
     public_key: String,
     settings: config::Settings,
     wallet: Option<Arc<dyn PortalWallet>>,
+    /// Same wallet as `wallet` when the mock backend is used, for its test commands
+    mock_wallet: Option<Arc<MockWallet>>,
+    /// Watches the invoices of the payment streams, if there's a wallet
+    settlements: Option<settlements::SettlementWatcher>,
     market_api: Arc<portal_rates::MarketAPI>,
     events: events::EventStore,
+    billing: Option<billing::Billing>,
+    ledger: ledger::PaymentLedger,
+    sessions: Arc<sessions::SqliteSessionStore>,
+    api_keys: Arc<api_keys::SqliteApiKeyStore>,
+}
+
+impl AppState {
+    /// How long the user sessions opened by the daemon last
+    fn session_duration(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.settings.auth.session_duration_secs as i64)
+    }
 }
 
 #[derive(Serialize)]
@@ -85,7 +147,7 @@ struct ErrorResponse {
 
 async fn auth_middleware<B>(
     State(state): State<AppState>,
-    req: Request<B>,
+    mut req: Request<B>,
     next: Next<B>,
 ) -> std::result::Result<Response, (StatusCode, Json<ErrorResponse>)> {
     let auth_header = req
@@ -105,57 +167,306 @@ async fn auth_middleware<B>(
     // Constant-time comparison to prevent timing attacks
     use subtle::ConstantTimeEq;
     let valid = token.as_bytes().ct_eq(state.settings.auth.auth_token.as_bytes());
-    if !bool::from(valid) {
-        return Err(ApiError::AuthenticationError("Invalid token".to_string()).into());
+    let caller = if bool::from(valid) {
+        Caller::AuthToken
+    } else {
+        match state.api_keys.authenticate(token).await {
+            Ok(Some(api_key)) => Caller::ApiKey(api_key),
+            Ok(None) => {
+                return Err(ApiError::AuthenticationError("Invalid token".to_string()).into())
+            }
+            Err(e) => return Err(ApiError::AnyhowError(e).into()),
+        }
+    };
+
+    req.extensions_mut().insert(caller);
+    Ok(next.run(req).await)
+}
+
+/// Reject the requests of API keys without `scope`.
+async fn require_scope<B>(
+    State(scope): State<Scope>,
+    req: Request<B>,
+    next: Next<B>,
+) -> std::result::Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    // Set by `auth_middleware`, which runs first
+    let allowed = req
+        .extensions()
+        .get::<Caller>()
+        .is_some_and(|caller| caller.has_scope(scope));
+    if !allowed {
+        return Err(ApiError::Forbidden(format!(
+            "The API key doesn't have the '{}' scope",
+            scope.as_str()
+        ))
+        .into());
     }
 
     Ok(next.run(req).await)
 }
 
+/// Execute a POST with an `Idempotency-Key` header only once. Its retries, with the same key
+/// and request, get the saved response back. A key is per caller, and its request is only
+/// saved if it didn't fail with a server error, so that it can be retried.
+async fn idempotency_middleware(
+    State(state): State<AppState>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> std::result::Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let Some(key) = req.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(next.run(req).await);
+    };
+    if req.method() != Method::POST {
+        return Ok(next.run(req).await);
+    }
+
+    let key = key
+        .to_str()
+        .ok()
+        .filter(|key| !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN)
+        .ok_or_else(|| -> (StatusCode, Json<ErrorResponse>) {
+            ApiError::BadRequest(format!(
+                "{IDEMPOTENCY_KEY_HEADER} must be 1 to {MAX_IDEMPOTENCY_KEY_LEN} visible ASCII characters"
+            ))
+            .into()
+        })?
+        .to_string();
+    // Set by `auth_middleware`, which runs first
+    let caller = req
+        .extensions()
+        .get::<Caller>()
+        .and_then(|caller| caller.key_id())
+        .unwrap_or_default()
+        .to_string();
+
+    let (parts, body) = req.into_parts();
+    let body = Bytes::from_request(Request::new(body), &()).await.map_err(
+        |e| -> (StatusCode, Json<ErrorResponse>) { ApiError::BadRequest(e.to_string()).into() },
+    )?;
+    let fingerprint = request_fingerprint(&parts, &body);
+
+    match state
+        .events
+        .claim_idempotency_key(&key, &caller, &fingerprint)
+        .await
+        .map_err(|e| -> (StatusCode, Json<ErrorResponse>) { ApiError::AnyhowError(e).into() })?
+    {
+        IdempotencyClaim::Claimed => {}
+        IdempotencyClaim::Completed {
+            status_code,
+            response,
+        } => {
+            let status = StatusCode::from_u16(status_code).unwrap_or(StatusCode::OK);
+            let mut response = (status, response).into_response();
+            let headers = response.headers_mut();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            headers.insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+            return Ok(response);
+        }
+        IdempotencyClaim::InProgress => {
+            return Err(ApiError::Conflict(format!(
+                "A request with this {IDEMPOTENCY_KEY_HEADER} is in progress"
+            ))
+            .into());
+        }
+        IdempotencyClaim::Mismatch => {
+            return Err(ApiError::UnprocessableEntity(format!(
+                "This {IDEMPOTENCY_KEY_HEADER} was used for a different request"
+            ))
+            .into());
+        }
+    }
+
+    let claim = IdempotencyClaimGuard::new(state.events.clone(), key, caller);
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    let (parts, body) = response.into_parts();
+    let body = match Bytes::from_request(Request::new(body), &()).await {
+        Ok(body) => body,
+        Err(e) => {
+            claim.release().await;
+            return Err(
+                ApiError::AnyhowError(anyhow::anyhow!("Failed to read the response: {e}")).into(),
+            );
+        }
+    };
+
+    if parts.status.is_server_error() {
+        claim.release().await;
+    } else {
+        claim.complete(parts.status.as_u16(), &body).await;
+    }
+    Ok(Response::from_parts(parts, boxed(Full::from(body))))
+}
+
+/// A claimed idempotency key, released if its request never completes: when the client
+/// disconnects, the future of the request is dropped before it's answered, and its retries
+/// would otherwise get `409` until the key expires.
+struct IdempotencyClaimGuard {
+    events: EventStore,
+    key: String,
+    caller: String,
+    /// Whether the key was completed or released already
+    done: bool,
+}
+
+impl IdempotencyClaimGuard {
+    fn new(events: EventStore, key: String, caller: String) -> Self {
+        Self {
+            events,
+            key,
+            caller,
+            done: false,
+        }
+    }
+
+    /// Save the response returned to the retries.
+    async fn complete(mut self, status_code: u16, response: &[u8]) {
+        self.events
+            .complete_idempotency_key(&self.key, &self.caller, status_code, response)
+            .await;
+        self.done = true;
+    }
+
+    /// Release the key, so that the request can be retried.
+    async fn release(mut self) {
+        self.events
+            .release_idempotency_key(&self.key, &self.caller)
+            .await;
+        self.done = true;
+    }
+}
+
+impl Drop for IdempotencyClaimGuard {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let events = self.events.clone();
+        let key = std::mem::take(&mut self.key);
+        let caller = std::mem::take(&mut self.caller);
+        tokio::spawn(async move {
+            events.release_idempotency_key(&key, &caller).await;
+        });
+    }
+}
+
+/// Hash of the method, path, query and body of a request, to tell a retry from another
+/// request reusing its idempotency key.
+fn request_fingerprint(parts: &request::Parts, body: &[u8]) -> String {
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+
+    let mut hasher = Sha256::new();
+    hasher.update(parts.method.as_str().as_bytes());
+    hasher.update(b" ");
+    hasher.update(path.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Send the requests with an `X-Portal-Identity` header to the routes of that identity, by
+/// prefixing their path with `/identities/<name>`. Requests already prefixed are left alone.
+async fn route_identity<B>(
+    State(identities): State<Arc<HashSet<String>>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> std::result::Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let Some(header) = req.headers().get(IDENTITY_HEADER) else {
+        return Ok(next.run(req).await);
+    };
+    let name = header.to_str().unwrap_or_default().trim().to_string();
+    if !identities.contains(&name) {
+        return Err(ApiError::NotFound(format!("Unknown identity '{name}'")).into());
+    }
+
+    if !req.uri().path().starts_with("/identities/") {
+        let path_and_query = req
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        *req.uri_mut() = format!("/identities/{name}{path_and_query}")
+            .parse()
+            .map_err(|e| -> (StatusCode, Json<ErrorResponse>) {
+                ApiError::AnyhowError(anyhow::anyhow!("Invalid request path: {e}")).into()
+            })?;
+    }
+
+    Ok(next.run(req).await)
+}
+
+/// Mark a stream that can't be recovered as failed, together with its ledger entry if it's a
+/// payment stream.
+async fn fail_stream(state: &AppState, stream_id: &str) {
+    state
+        .events
+        .update_stream_status(stream_id, events::StreamStatus::Failed)
+        .await;
+
+    let update = ledger::LedgerUpdate::error(
+        ledger::LedgerStatus::Failed,
+        Some("The stream could not be recovered after a restart".to_string()),
+    );
+    if let Err(e) = state.ledger.update_stream(stream_id, update).await {
+        error!("Failed to update the ledger entry of stream {stream_id}: {e}");
+    }
+}
+
 /// Resume any in-flight streams that survived a server restart.
 async fn recover_in_flight_streams(state: &AppState) {
     let in_flight = state.events.get_in_flight_streams().await;
     if !in_flight.is_empty() {
         info!("Recovering {} in-flight stream(s) from database", in_flight.len());
     }
+
+    // SDK conversations are saved with the ID of the stream they feed as tag
+    let mut conversations: HashMap<String, ResumedConversation> =
+        match state.sdk.resume_conversations().await {
+            Ok(resumed) => resumed.into_iter().map(|c| (c.tag.clone(), c)).collect(),
+            Err(e) => {
+                error!("Failed to resume SDK conversations: {e}");
+                HashMap::new()
+            }
+        };
+
     for stream in in_flight {
         match stream.stream_type.as_str() {
             "single_payment" => {
+                // Keep forwarding the status updates sent by the user, the invoice itself
+                // is monitored below
+                if let Some(conversation) = conversations.remove(&stream.stream_id) {
+                    tokio::spawn(handlers::forward_payment_statuses(
+                        state.events.clone(),
+                        state.ledger.clone(),
+                        stream.stream_id.clone(),
+                        conversation.into_stream(),
+                        None,
+                    ));
+                }
+
                 if let Some(events::StreamMetadata::SinglePayment { invoice, expires_at_secs }) =
                     stream.metadata
                 {
-                    if let Some(wallet) = state.wallet.clone() {
-                        let events_store = state.events.clone();
+                    if let Some(settlements) = &state.settlements {
                         let sid = stream.stream_id.clone();
                         info!("Recovering single_payment stream {sid}");
 
                         let expires_at = portal::protocol::model::Timestamp::new(expires_at_secs);
-                        tokio::spawn(handlers::monitor_invoice_until_paid(
-                            wallet,
-                            events_store,
-                            sid,
-                            invoice,
-                            expires_at,
-                        ));
+                        settlements.watch(sid, invoice, expires_at).await;
                     } else {
                         warn!("Cannot recover single_payment stream {} — no wallet configured", stream.stream_id);
-                        state
-                            .events
-                            .update_stream_status(
-                                &stream.stream_id,
-                                events::StreamStatus::Failed,
-                            )
-                            .await;
+                        fail_stream(state, &stream.stream_id).await;
                     }
                 } else {
                     warn!("Cannot recover single_payment stream {} — missing metadata", stream.stream_id);
-                    state
-                        .events
-                        .update_stream_status(
-                            &stream.stream_id,
-                            events::StreamStatus::Failed,
-                        )
-                        .await;
+                    fail_stream(state, &stream.stream_id).await;
                 }
             }
             "recurring_payment_close" => {
@@ -163,33 +474,131 @@ async fn recover_in_flight_streams(state: &AppState) {
                 // there's nothing to "recover" here. Also avoid noisy warnings on restart.
             }
             "key_handshake" | "authenticate_key" | "recurring_payment"
-            | "invoice_request" | "cashu_request" | "raw_payment" => {
-                // These streams rely on ephemeral SDK conversation state and
-                // cannot be resumed after restart. Mark as failed.
-                warn!(
-                    "Cannot recover {} stream {} — marking as failed",
-                    stream.stream_type, stream.stream_id
-                );
-                state
-                    .events
-                    .update_stream_status(
-                        &stream.stream_id,
-                        events::StreamStatus::Failed,
-                    )
-                    .await;
+            | "invoice_request" | "cashu_request" | "raw_payment"
+            | "certificate_request" => {
+                let resumed = match conversations.remove(&stream.stream_id) {
+                    Some(conversation) => resume_conversation_stream(state, &stream, conversation),
+                    None => Err("conversation expired or was not saved".to_string()),
+                };
+                match resumed {
+                    Ok(()) => info!("Resumed {} stream {}", stream.stream_type, stream.stream_id),
+                    Err(reason) => {
+                        warn!(
+                            "Cannot recover {} stream {} ({reason}) — marking as failed",
+                            stream.stream_type, stream.stream_id
+                        );
+                        fail_stream(state, &stream.stream_id).await;
+                    }
+                }
             }
             other => {
                 warn!("Unknown stream type '{other}' for stream {} — marking as failed", stream.stream_id);
-                state
-                    .events
-                    .update_stream_status(
-                        &stream.stream_id,
-                        events::StreamStatus::Failed,
-                    )
-                    .await;
+                fail_stream(state, &stream.stream_id).await;
             }
         }
     }
+
+    for tag in conversations.keys() {
+        warn!("Resumed conversation {tag} has no in-flight stream, ignoring it");
+    }
+}
+
+/// Forward the notifications of a conversation restored by the SDK to its stream.
+fn resume_conversation_stream(
+    state: &AppState,
+    stream: &events::InFlightStream,
+    conversation: ResumedConversation,
+) -> Result<(), String> {
+    let events = state.events.clone();
+    let sid = stream.stream_id.clone();
+
+    match (stream.stream_type.as_str(), &stream.metadata) {
+        ("key_handshake", _) => {
+            tokio::spawn(handlers::forward_key_handshakes(
+                events,
+                state.sdk.relay_pool(),
+                sid,
+                conversation.into_stream(),
+            ));
+        }
+        ("authenticate_key", _) => {
+            tokio::spawn(handlers::forward_authentication(
+                state.clone(),
+                sid,
+                conversation.into_stream(),
+            ));
+        }
+        ("recurring_payment", Some(events::StreamMetadata::RecurringPayment(request))) => {
+            tokio::spawn(handlers::forward_recurring_payment(
+                events,
+                state.billing.clone(),
+                sid,
+                request.clone(),
+                conversation.into_stream(),
+            ));
+        }
+        ("recurring_payment", _) => {
+            tokio::spawn(handlers::forward_first_notification(
+                events,
+                sid,
+                conversation.into_stream(),
+                handlers::recurring_payment_notification,
+            ));
+        }
+        ("cashu_request", _) => {
+            tokio::spawn(handlers::forward_first_notification(
+                events,
+                sid,
+                conversation.into_stream(),
+                handlers::cashu_notification,
+            ));
+        }
+        ("raw_payment", _) => {
+            tokio::spawn(handlers::forward_payment_statuses(
+                events,
+                state.ledger.clone(),
+                sid,
+                conversation.into_stream(),
+                None,
+            ));
+        }
+        (
+            "invoice_request",
+            Some(events::StreamMetadata::InvoiceRequest {
+                expected_amount_msat,
+            }),
+        ) => {
+            let expected_msat = *expected_amount_msat;
+            tokio::spawn(handlers::forward_first_notification(
+                events,
+                sid,
+                conversation.into_stream(),
+                move |result| handlers::invoice_notification(expected_msat, result),
+            ));
+        }
+        (
+            "certificate_request",
+            Some(events::StreamMetadata::CertificateRequest {
+                main_key,
+                trusted_issuers,
+            }),
+        ) => {
+            let main_key = PublicKey::from_str(main_key).map_err(|e| e.to_string())?;
+            let trusted_issuers = trusted_issuers
+                .as_deref()
+                .map(handlers::parse_subkeys)
+                .transpose()?;
+            tokio::spawn(handlers::forward_first_notification(
+                events,
+                sid,
+                conversation.into_stream(),
+                move |result| handlers::certificate_notification(main_key, trusted_issuers, result),
+            ));
+        }
+        _ => return Err("missing metadata".to_string()),
+    }
+
+    Ok(())
 }
 
 /// Start recurring long-lived background listeners and apply profile config.
@@ -199,16 +608,30 @@ async fn setup_background_listeners(state: &AppState) {
         let stream_id = "recurring-payment-close";
         state
             .events
-            .create_stream(stream_id, "recurring_payment_close", None)
+            .create_stream(stream_id, "recurring_payment_close", None, None)
             .await;
         match state.sdk.listen_closed_recurring_payment().await {
             Ok(notification_stream) => {
                 info!("Started recurring payment close listener (stream {stream_id})");
                 let events_store = state.events.clone();
+                let billing = state.billing.clone();
                 let sid = stream_id.to_string();
                 tokio::spawn(async move {
                     let mut stream = notification_stream;
                     while let Some(Ok(event)) = stream.next().await {
+                        // Stop charging the subscriptions closed by their user
+                        if let Some(billing) = &billing {
+                            billing
+                                .cancel(
+                                    &events_store,
+                                    &event.content.subscription_id,
+                                    &event.main_key.to_string(),
+                                    event.content.reason.clone(),
+                                    event.content.by_service,
+                                )
+                                .await;
+                        }
+
                         events_store
                             .push(
                                 &sid,
@@ -247,8 +670,7 @@ async fn setup_background_listeners(state: &AppState) {
     if let Some(ref nip05) = state.settings.profile.nip05 {
         // Only register with getportal.cc domain
         if nip05.ends_with("@getportal.cc") {
-            let registered_file = constants::portal_rest_dir()
-                .map(|d| d.join("nip05.registered"));
+            let registered_file = state.settings.data_path("nip05.registered");
 
             let already_registered = match &registered_file {
                 Ok(path) => tokio::fs::read_to_string(path)
@@ -267,6 +689,9 @@ async fn setup_background_listeners(state: &AppState) {
                     Ok(true) => {
                         info!("NIP-05 '{nip05}' registered with profile service");
                         if let Ok(path) = &registered_file {
+                            if let Some(dir) = path.parent() {
+                                let _ = tokio::fs::create_dir_all(dir).await;
+                            }
                             if let Err(e) = tokio::fs::write(path, nip05).await {
                                 warn!("Could not write NIP-05 registration cache file: {e}");
                             }
@@ -288,41 +713,127 @@ fn build_router(state: AppState) -> Router {
         .route("/version", get(handlers::version))
         .route("/well-known/nostr.json", get(handlers::well_known_nostr_json));
 
-    // Authenticated REST API routes
-    let api = Router::new()
-        .route("/info", get(handlers::info))
+    // Authenticated REST API routes, grouped by the scope an API key needs to call them
+    let auth = Router::new()
         // Key handshake & auth
         .route("/key-handshake", post(handlers::new_key_handshake_url))
         .route("/authenticate-key", post(handlers::authenticate_key))
+        // Sessions
+        .route("/sessions/verify", post(handlers::verify_session))
+        .route("/sessions/refresh", post(handlers::refresh_session))
+        .route("/sessions/revoke", post(handlers::revoke_session))
+        // Profiles
+        .route("/profile/:main_key", get(handlers::fetch_profile))
+        // JWT
+        .route("/jwt/issue", post(handlers::issue_jwt))
+        .route("/jwt/verify", post(handlers::verify_jwt))
+        // Certificates
+        .route("/certificates/issue", post(handlers::issue_certificate))
+        .route("/certificates/request", post(handlers::request_certificates))
+        // NIP-05
+        .route("/nip05/:nip05", get(handlers::fetch_nip05_profile))
+        .route_layer(middleware::from_fn_with_state(Scope::Auth, require_scope));
+
+    let payments = Router::new()
         // Payments
         .route("/payments/single", post(handlers::request_single_payment))
         .route("/payments/raw", post(handlers::request_payment_raw))
         .route("/payments/recurring", post(handlers::request_recurring_payment))
         .route("/payments/recurring/close", post(handlers::close_recurring_payment))
-        // Profiles
-        .route("/profile/:main_key", get(handlers::fetch_profile))
+        // Subscriptions
+        .route("/subscriptions", get(handlers::list_subscriptions))
+        .route("/subscriptions/:subscription_id", get(handlers::get_subscription))
         // Invoices
         .route("/invoices/request", post(handlers::request_invoice))
+        // Wallet
+        .route("/wallet/info", get(handlers::get_wallet_info))
+        .route(
+            "/wallet/capabilities",
+            get(handlers::get_wallet_capabilities),
+        )
+        .route("/wallet/payments", get(handlers::list_wallet_payments))
+        .route("/wallet/invoices", post(handlers::create_wallet_invoice))
+        .route(
+            "/wallet/invoices/:payment_hash",
+            get(handlers::lookup_wallet_invoice),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            Scope::PaymentsRequest,
+            require_scope,
+        ));
+
+    let wallet_spend = Router::new()
         .route("/invoices/pay", post(handlers::pay_invoice))
-        // JWT
-        .route("/jwt/issue", post(handlers::issue_jwt))
-        .route("/jwt/verify", post(handlers::verify_jwt))
-        // Cashu
+        .route_layer(middleware::from_fn_with_state(
+            Scope::WalletSpend,
+            require_scope,
+        ));
+
+    let cashu = Router::new()
         .route("/cashu/request", post(handlers::request_cashu))
         .route("/cashu/send-direct", post(handlers::send_cashu_direct))
         .route("/cashu/mint", post(handlers::mint_cashu))
         .route("/cashu/burn", post(handlers::burn_cashu))
-        // Relays
+        .route_layer(middleware::from_fn_with_state(Scope::Cashu, require_scope));
+
+    let relays = Router::new()
         .route("/relays", post(handlers::add_relay))
         .route("/relays", delete(handlers::remove_relay))
+        .route_layer(middleware::from_fn_with_state(Scope::Relays, require_scope));
+
+    let admin = Router::new()
+        // API keys
+        .route("/api-keys", get(handlers::list_api_keys))
+        .route("/api-keys", post(handlers::create_api_key))
+        .route("/api-keys/:key_id", get(handlers::get_api_key))
+        .route("/api-keys/:key_id", delete(handlers::revoke_api_key))
+        // Outbox
+        .route("/outbox", get(handlers::list_outbox))
+        .route("/outbox", delete(handlers::purge_outbox))
+        .route("/outbox/retry", post(handlers::retry_outbox))
+        .route("/outbox/:event_id/retry", post(handlers::retry_outbox_event))
+        .route("/outbox/:event_id", delete(handlers::purge_outbox_event))
+        // Webhooks
+        .route("/webhooks/deliveries", get(handlers::list_webhook_deliveries))
+        .route("/webhooks/deliveries/replay", post(handlers::replay_webhook_deliveries))
+        .route(
+            "/webhooks/deliveries/:stream_id/:index",
+            get(handlers::get_webhook_delivery),
+        )
+        .route(
+            "/webhooks/deliveries/:stream_id/:index/replay",
+            post(handlers::replay_webhook_delivery),
+        )
+        // Mock wallet
+        .route("/wallet/mock/settle", post(handlers::settle_mock_invoice))
+        .route("/wallet/mock/expire", post(handlers::expire_mock_invoice))
+        .route("/wallet/mock/fail", post(handlers::fail_mock_invoice))
+        // Events of all the streams
+        .route("/events/live", get(handlers::follow_all_events))
+        // Ledger, with the payments of every stream
+        .route("/ledger", get(handlers::list_ledger))
+        .route("/ledger/export", get(handlers::export_ledger))
+        .route_layer(middleware::from_fn_with_state(Scope::Admin, require_scope));
+
+    // Any key, the events of a stream are checked against the key that created it
+    let api = Router::new()
+        .route("/info", get(handlers::info))
         // Calendar
         .route("/calendar/next-occurrence", post(handlers::calculate_next_occurrence))
-        // NIP-05
-        .route("/nip05/:nip05", get(handlers::fetch_nip05_profile))
-        // Wallet
-        .route("/wallet/info", get(handlers::get_wallet_info))
-        // Event polling
+        // Event polling and live streaming
         .route("/events/:stream_id", get(handlers::get_events))
+        .route("/events/:stream_id/live", get(handlers::follow_events))
+        .merge(auth)
+        .merge(payments)
+        .merge(wallet_spend)
+        .merge(cashu)
+        .merge(relays)
+        .merge(admin)
+        // After `auth_middleware`, for the caller
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            idempotency_middleware,
+        ))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             auth_middleware,
@@ -341,6 +852,166 @@ fn build_router(state: AppState) -> Router {
         .with_state(state)
 }
 
+/// Resolve a database path, relative paths being relative to ~/.portal-rest/.
+fn resolve_db_path(path: &str) -> anyhow::Result<String> {
+    if std::path::Path::new(path).is_relative() {
+        let rest_dir = constants::portal_rest_dir()?;
+        std::fs::create_dir_all(&rest_dir)?;
+        Ok(rest_dir
+            .join(path)
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid database path"))?
+            .to_string())
+    } else {
+        Ok(path.to_string())
+    }
+}
+
+/// Set up the SDK, wallet and stores of an identity, from its settings.
+async fn build_state(
+    config: config::Settings,
+    market_api: Arc<portal_rates::MarketAPI>,
+) -> anyhow::Result<AppState> {
+    let keys = portal::nostr::key::Keys::from_str(&config.nostr.private_key)?;
+
+    // Initialize keypair from environment
+    let subkey_proof = config
+        .nostr
+        .subkey_proof
+        .as_deref()
+        .map(serde_json::from_str::<SubkeyProof>)
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Failed to parse subkey proof: {e}"))?;
+    if let Some(proof) = &subkey_proof {
+        proof
+            .verify(&keys.public_key())
+            .map_err(|e| anyhow::anyhow!("Invalid subkey proof for the configured key: {e}"))?;
+        info!("Running as a subkey of {}", proof.main_key.to_hex());
+    }
+    let keypair = LocalKeypair::new(keys, subkey_proof);
+
+    let public_key = keypair.public_key().to_string();
+    match &config.identity {
+        Some(name) => info!("Identity {name} running with keypair: {public_key}"),
+        None => info!("Running with keypair: {}", public_key),
+    }
+
+    let db_path = resolve_db_path(&config.database.path)?;
+
+    // Initialize SDK, saving in-flight conversations and undelivered events in the database
+    // so they survive restarts
+    let storage = RouterStorage {
+        conversations: Arc::new(conversations::SqliteConversationStore::new(&db_path)?),
+        outbox: Arc::new(outbox::SqliteOutbox::new(
+            &db_path,
+            config.outbox.max_events,
+        )?),
+    };
+    let sdk = PortalSDK::new_with_storage(keypair, config.nostr.relays.clone(), storage).await?;
+
+    // Revoked user sessions stay revoked across restarts
+    let sessions = sessions::SqliteSessionStore::new(&db_path)?;
+    for (session_id, expires_at) in sessions.load_revoked().await? {
+        sdk.revoke_session(
+            session_id,
+            portal::protocol::model::Timestamp::new(expires_at),
+        );
+    }
+    for (user_key, permissions) in sessions.load_granted_permissions().await? {
+        sdk.set_granted_permissions(user_key, permissions);
+    }
+
+    // Initialize the wallet
+    let mock_wallet = config.build_mock_wallet()?;
+    let wallet = match &mock_wallet {
+        Some(mock) => Some(mock.clone() as Arc<dyn PortalWallet>),
+        None => config.build_wallet().await?,
+    };
+
+    // Create event store with SQLite persistence
+    let event_store = events::EventStore::new(&db_path, config.webhook.clone())?;
+
+    // Confirmed subscriptions are charged by the daemon if billing is enabled
+    let billing = if config.billing.enabled {
+        Some(billing::Billing::new(&db_path, config.billing.clone())?)
+    } else {
+        None
+    };
+
+    // Requested, paid and collected payments are recorded for bookkeeping
+    let ledger = ledger::PaymentLedger::new(&db_path)?;
+
+    // A key only calls the routes of the identity it was created with
+    let api_keys = api_keys::SqliteApiKeyStore::new(&db_path)?;
+
+    // A single watcher fans the wallet's settlements out to the payment streams
+    let settlements = wallet.clone().map(|wallet| {
+        settlements::SettlementWatcher::new(wallet, event_store.clone(), ledger.clone())
+    });
+
+    Ok(AppState {
+        sdk: Arc::new(sdk),
+        public_key,
+        settings: config,
+        wallet,
+        mock_wallet,
+        settlements,
+        market_api,
+        events: event_store,
+        billing,
+        ledger,
+        sessions: Arc::new(sessions),
+        api_keys: Arc::new(api_keys),
+    })
+}
+
+/// Start the background tasks of an identity and resume its in-flight streams.
+async fn start_background_tasks(state: &AppState) {
+    // Report on their streams when queued request events reach a relay (or are dropped)
+    tokio::spawn(handlers::forward_delivery_updates(
+        state.events.clone(),
+        state.sdk.delivery_updates(),
+    ));
+
+    if let Some(settlements) = state.settlements.clone() {
+        tokio::spawn(settlements.run());
+    }
+    recover_in_flight_streams(state).await;
+    setup_background_listeners(state).await;
+    tokio::spawn(billing::run_scheduler(state.clone()));
+    tokio::spawn(webhook::run_retries(state.events.clone()));
+}
+
+/// Serve the main identity at the root and each other identity under `/identities/<name>`.
+fn build_app(state: AppState, identities: Vec<AppState>) -> Router {
+    let identities = identities
+        .into_iter()
+        .map(|identity| {
+            let name = identity.settings.identity.clone().expect("named identity");
+            (name, build_router(identity))
+        })
+        .collect();
+    route_identities(build_router(state), identities)
+}
+
+/// Nest the routers of the identities under `/identities/<name>`, and route the requests with
+/// an `X-Portal-Identity` header to them.
+fn route_identities(mut app: Router, identities: Vec<(String, Router)>) -> Router {
+    let mut names = HashSet::new();
+    for (name, router) in identities {
+        app = app.nest(&format!("/identities/{name}"), router);
+        names.insert(name);
+    }
+
+    // Middleware added to `app` would run after routing, too late to rewrite the path
+    Router::new()
+        .fallback_service(app)
+        .layer(middleware::from_fn_with_state(
+            Arc::new(names),
+            route_identity,
+        ))
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let config = config::Settings::load()?;
@@ -369,59 +1040,36 @@ async fn main() -> anyhow::Result<()> {
     // Settings validation
     config.validate()?;
 
-    let keys = portal::nostr::key::Keys::from_str(&config.nostr.private_key)?;
-
-    // Initialize keypair from environment
-    let keypair = LocalKeypair::new(
-        keys,
-        config
-            .nostr
-            .subkey_proof
-            .clone()
-            .map(|s| serde_json::from_str(&s).expect("Failed to parse subkey proof")),
-    );
-
-    let public_key = keypair.public_key().to_string();
-    info!("Running with keypair: {}", public_key);
-
-    // Initialize SDK
-    let sdk = PortalSDK::new(keypair, config.nostr.relays.clone()).await?;
-
-    // Initialize the wallet
-    let wallet = config.build_wallet().await?;
-
+    let market_api = portal_rates::MarketAPI::new().expect("Failed to create market API");
     let listen_port = config.info.listen_port;
 
-    // Resolve database path (relative paths are relative to ~/.portal-rest/)
-    let db_path = if std::path::Path::new(&config.database.path).is_relative() {
-        let rest_dir = constants::portal_rest_dir()?;
-        std::fs::create_dir_all(&rest_dir)?;
-        rest_dir
-            .join(&config.database.path)
-            .to_str()
-            .ok_or_else(|| anyhow::anyhow!("Invalid database path"))?
-            .to_string()
-    } else {
-        config.database.path.clone()
-    };
-
-    // Create event store with SQLite persistence
-    let event_store = events::EventStore::new(&db_path, config.webhook.clone())?;
+    let identities: Vec<_> = config
+        .identities
+        .keys()
+        .map(|name| config.for_identity(name).expect("identity exists"))
+        .collect();
+    let state = build_state(config, market_api.clone()).await?;
 
-    // Create app state
-    let state = AppState {
-        sdk: Arc::new(sdk),
-        public_key,
-        settings: config,
-        wallet,
-        market_api: portal_rates::MarketAPI::new().expect("Failed to create market API"),
-        events: event_store,
-    };
+    // Each identity has its own SDK, wallet, webhook and database
+    let mut public_keys = HashMap::from([(state.public_key.clone(), "main".to_string())]);
+    let mut identity_states = Vec::new();
+    for settings in identities {
+        let name = settings.identity.clone().expect("named identity");
+        let identity = build_state(settings, market_api.clone()).await?;
+        if let Some(other) = public_keys.insert(identity.public_key.clone(), name.clone()) {
+            return Err(anyhow::anyhow!(
+                "Identity '{name}' has the same key as the {other} identity"
+            ));
+        }
+        identity_states.push(identity);
+    }
 
-    recover_in_flight_streams(&state).await;
-    setup_background_listeners(&state).await;
+    start_background_tasks(&state).await;
+    for identity in &identity_states {
+        start_background_tasks(identity).await;
+    }
 
-    let app = build_router(state);
+    let app = build_app(state, identity_states);
 
     // Start server
     let addr = SocketAddr::from(([0, 0, 0, 0], listen_port));
@@ -433,3 +1081,139 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::RawQuery;
+
+    fn event_store() -> EventStore {
+        EventStore::new(":memory:", config::WebhookSettings::default()).unwrap()
+    }
+
+    async fn claim(events: &EventStore, fingerprint: &str) -> IdempotencyClaim {
+        events
+            .claim_idempotency_key("key", "", fingerprint)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dropped_idempotency_claim_is_released() {
+        let events = event_store();
+        assert!(matches!(
+            claim(&events, "request").await,
+            IdempotencyClaim::Claimed
+        ));
+
+        // The request future is dropped, like when the client disconnects
+        let request = async {
+            let _claim = IdempotencyClaimGuard::new(events.clone(), "key".into(), "".into());
+            std::future::pending::<()>().await;
+        };
+        let _ = tokio::time::timeout(std::time::Duration::from_millis(10), request).await;
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(matches!(
+            claim(&events, "request").await,
+            IdempotencyClaim::Claimed
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_completed_idempotency_claim_is_kept() {
+        let events = event_store();
+        assert!(matches!(
+            claim(&events, "request").await,
+            IdempotencyClaim::Claimed
+        ));
+
+        IdempotencyClaimGuard::new(events.clone(), "key".into(), "".into())
+            .complete(201, b"{}")
+            .await;
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(matches!(
+            claim(&events, "request").await,
+            IdempotencyClaim::Completed {
+                status_code: 201,
+                ..
+            }
+        ));
+    }
+
+    /// Serve a main identity and a `shop` identity whose `/info` routes answer with their
+    /// name and query string.
+    fn serve_identities() -> String {
+        fn info(name: &'static str) -> Router {
+            Router::new().route(
+                "/info",
+                get(move |RawQuery(query): RawQuery| async move {
+                    format!("{name} {}", query.unwrap_or_default())
+                }),
+            )
+        }
+
+        let app = route_identities(info("main"), vec![("shop".to_string(), info("shop"))]);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        url
+    }
+
+    async fn get_info(url: &str, identity: Option<&str>) -> (StatusCode, String) {
+        let mut request = reqwest::Client::new().get(url);
+        if let Some(identity) = identity {
+            request = request.header(IDENTITY_HEADER, identity);
+        }
+        let response = request.send().await.unwrap();
+        let status = StatusCode::from_u16(response.status().as_u16()).unwrap();
+        (status, response.text().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_identity_header_routes_to_the_identity() {
+        let url = serve_identities();
+
+        assert_eq!(
+            get_info(&format!("{url}/info?a=1"), None).await,
+            (StatusCode::OK, "main a=1".to_string())
+        );
+        assert_eq!(
+            get_info(&format!("{url}/info?a=1"), Some("shop")).await,
+            (StatusCode::OK, "shop a=1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_identity_is_not_found() {
+        let url = serve_identities();
+
+        let (status, body) = get_info(&format!("{url}/info"), Some("other")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.contains("Unknown identity 'other'"));
+    }
+
+    #[tokio::test]
+    async fn test_identity_prefixed_requests_are_left_alone() {
+        let url = serve_identities();
+
+        assert_eq!(
+            get_info(&format!("{url}/identities/shop/info"), None).await,
+            (StatusCode::OK, "shop ".to_string())
+        );
+        // Not rewritten to /identities/shop/identities/shop/info
+        assert_eq!(
+            get_info(&format!("{url}/identities/shop/info"), Some("shop")).await,
+            (StatusCode::OK, "shop ".to_string())
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Mutex;
+
+use portal::nostr::event::EventId;
+use portal::router::{EventOutbox, OutboxEntry, OutboxError};
+use rusqlite::{Connection, OptionalExtension};
+use tracing::info;
+
+fn storage_error(e: rusqlite::Error) -> OutboxError {
+    OutboxError::Storage(Box::new(e))
+}
+
+/// SQLite-backed outbox for the events the relays didn't accept yet, so they are still
+/// delivered if the daemon restarts in the meantime.
+///
+/// Entries are stored as JSON, keyed by event ID.
+pub struct SqliteOutbox {
+    db: Mutex<Connection>,
+    capacity: Option<usize>,
+}
+
+impl SqliteOutbox {
+    /// Open (or create) the SQLite database at `db_path` and initialize the schema.
+    pub fn new(db_path: &str, capacity: Option<usize>) -> anyhow::Result<Self> {
+        let conn = Connection::open(db_path)?;
+
+        conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS outbox (
+                event_id TEXT PRIMARY KEY,
+                entry TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );",
+        )?;
+
+        let queued: i64 = conn.query_row("SELECT COUNT(*) FROM outbox", [], |row| row.get(0))?;
+        info!("Outbox opened at {db_path} ({queued} queued event(s))");
+
+        Ok(Self {
+            db: Mutex::new(conn),
+            capacity,
+        })
+    }
+}
+
+impl EventOutbox for SqliteOutbox {
+    fn save(&self, entry: OutboxEntry) -> Result<(), OutboxError> {
+        let entry_json = serde_json::to_string(&entry)?;
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT INTO outbox (event_id, entry, created_at)
+             VALUES (?1, ?2, ?3)
+             ON CONFLICT(event_id) DO UPDATE SET entry = ?2",
+            rusqlite::params![
+                entry.event_id().to_hex(),
+                entry_json,
+                entry.created_at as i64
+            ],
+        )
+        .map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn get(&self, event_id: &EventId) -> Result<Option<OutboxEntry>, OutboxError> {
+        let db = self.db.lock().unwrap();
+        let entry = db
+            .query_row(
+                "SELECT entry FROM outbox WHERE event_id = ?1",
+                rusqlite::params![event_id.to_hex()],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()
+            .map_err(storage_error)?;
+
+        Ok(entry
+            .map(|entry| serde_json::from_str(&entry))
+            .transpose()?)
+    }
+
+    fn remove(&self, event_id: &EventId) -> Result<(), OutboxError> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "DELETE FROM outbox WHERE event_id = ?1",
+            rusqlite::params![event_id.to_hex()],
+        )
+        .map_err(storage_error)?;
+        Ok(())
+    }
+
+    fn load_all(&self) -> Result<Vec<OutboxEntry>, OutboxError> {
+        let db = self.db.lock().unwrap();
+        let mut stmt = db
+            .prepare("SELECT entry FROM outbox ORDER BY created_at ASC")
+            .map_err(storage_error)?;
+        let rows = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(storage_error)?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            entries.push(serde_json::from_str(&row.map_err(storage_error)?)?);
+        }
+        Ok(entries)
+    }
+
+    fn count(&self) -> Result<usize, OutboxError> {
+        let db = self.db.lock().unwrap();
+        let count: i64 = db
+            .query_row("SELECT COUNT(*) FROM outbox", [], |row| row.get(0))
+            .map_err(storage_error)?;
+        Ok(count as usize)
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+}
@@ -1,13 +1,23 @@
 use nostr::nips::nip05::Nip05Profile;
 
 use portal::conversation::profile::Profile;
+use portal::protocol::jwt::CustomClaims;
 use portal::protocol::model::auth::AuthResponseStatus;
+use portal::protocol::model::identity::CertificateResponseStatus;
 use portal::protocol::model::payment::{
-    CashuResponseStatus, RecurringPaymentResponseContent,
+    CashuResponseStatus, Currency, RecurringPaymentResponseContent,
 };
 use portal::protocol::model::Timestamp;
+use portal::protocol::session::Session;
+use portal::router::{EventSendResult, OutboxEntry, SendOutcome};
+use portal_wallet::{PaymentDirection, PaymentState, WalletPayment};
 use serde::{Deserialize, Serialize};
 
+use crate::api_keys::ApiKey;
+use crate::billing::Subscription;
+use crate::ledger::LedgerEntry;
+use crate::webhook::WebhookDelivery;
+
 /// Generic API response wrapper used for all REST endpoints.
 #[derive(Debug, Serialize)]
 pub struct ApiResponse<T: Serialize> {
@@ -50,11 +60,15 @@ pub struct KeyHandshakeUrlResponse {
 #[derive(Debug, Serialize)]
 pub struct StreamResponse {
     pub stream_id: String,
+    /// How the relays took each event of the request
+    pub delivery: Vec<EventSendResult>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct SinglePaymentResponse {
     pub stream_id: String,
+    /// How the relays took each event of the request
+    pub delivery: Vec<EventSendResult>,
 }
 
 #[derive(Debug, Serialize)]
@@ -77,9 +91,65 @@ pub struct IssueJwtResponse {
 #[derive(Debug, Serialize)]
 pub struct VerifyJwtResponse {
     pub target_key: String,
+    pub audience: Option<String>,
+    pub issuer: Option<String>,
+    pub scopes: Vec<String>,
+    pub jti: Option<String>,
+    /// Main key of the issuer, when the token is signed with one of its subkeys
+    pub main_key: Option<String>,
 }
 
+impl From<CustomClaims> for VerifyJwtResponse {
+    fn from(claims: CustomClaims) -> Self {
+        Self {
+            target_key: claims.target_key.to_string(),
+            main_key: claims.subkey_proof.map(|proof| proof.main_key.to_hex()),
+            audience: claims.audience,
+            issuer: claims.issuer,
+            scopes: claims.scopes,
+            jti: claims.jti,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    pub token: String,
+    pub session_id: String,
+    pub service_key: String,
+    pub user_key: String,
+    pub granted_permissions: Vec<String>,
+    pub issued_at: u64,
+    pub expires_at: u64,
+}
 
+impl From<Session> for SessionResponse {
+    fn from(session: Session) -> Self {
+        Self {
+            token: session.token,
+            session_id: session.claims.session_id,
+            service_key: session.claims.service_key.to_string(),
+            user_key: session.claims.user_key.to_string(),
+            granted_permissions: session.claims.granted_permissions,
+            issued_at: session.issued_at.as_u64(),
+            expires_at: session.expires_at.as_u64(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevokeSessionResponse {
+    pub session_id: String,
+}
+
+
+
+#[derive(Debug, Serialize)]
+pub struct IssueCertificateResponse {
+    pub certificate_id: String,
+    pub certificate: serde_json::Value,
+    pub delivered: bool,
+}
 
 #[derive(Debug, Serialize)]
 pub struct SendCashuDirectResponse {
@@ -123,6 +193,97 @@ pub struct WalletInfoResponse {
     pub balance_msat: u64,
 }
 
+#[derive(Debug, Serialize)]
+pub struct WalletCapabilitiesResponse {
+    pub wallet_type: String,
+    pub list_payments: bool,
+    pub lookup_invoice: bool,
+    pub invoice_expiry: bool,
+    pub description_hash: bool,
+    /// Whether invoice settlements are pushed by the wallet instead of polled
+    pub push_settlements: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WalletPaymentResponse {
+    /// `incoming` or `outgoing`
+    pub direction: String,
+    /// `pending`, `settled`, `expired` or `failed`
+    pub state: String,
+    pub payment_hash: String,
+    pub invoice: Option<String>,
+    pub preimage: Option<String>,
+    pub amount_msat: u64,
+    pub fees_paid_msat: u64,
+    pub description: Option<String>,
+    pub description_hash: Option<String>,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub settled_at: Option<u64>,
+}
+
+impl From<WalletPayment> for WalletPaymentResponse {
+    fn from(payment: WalletPayment) -> Self {
+        let direction = match payment.direction {
+            PaymentDirection::Incoming => "incoming",
+            PaymentDirection::Outgoing => "outgoing",
+        };
+        let state = match payment.state {
+            PaymentState::Pending => "pending",
+            PaymentState::Settled => "settled",
+            PaymentState::Expired => "expired",
+            PaymentState::Failed => "failed",
+        };
+
+        Self {
+            direction: direction.to_string(),
+            state: state.to_string(),
+            payment_hash: payment.payment_hash,
+            invoice: payment.invoice,
+            preimage: payment.preimage,
+            amount_msat: payment.amount_msat,
+            fees_paid_msat: payment.fees_paid_msat,
+            description: payment.description,
+            description_hash: payment.description_hash,
+            created_at: payment.created_at,
+            expires_at: payment.expires_at,
+            settled_at: payment.settled_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WalletPaymentsResponse {
+    pub payments: Vec<WalletPaymentResponse>,
+    pub offset: u64,
+    pub limit: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LedgerResponse {
+    pub entries: Vec<LedgerEntry>,
+    pub offset: u64,
+    pub limit: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WalletInvoiceResponse {
+    pub invoice: String,
+    pub payment_hash: String,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MockInvoiceResponse {
+    pub payment_hash: String,
+    /// `pending`, `paid`, `expired` or `failed`
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub amount_msat: u64,
+    pub balance_msat: u64,
+}
+
 #[derive(Debug, Serialize)]
 pub struct VersionResponse {
     pub version: &'static str,
@@ -132,10 +293,65 @@ pub struct VersionResponse {
 #[derive(Debug, Serialize)]
 pub struct InfoResponse {
     pub public_key: String,
+    /// Name of the identity that answered, `None` for the main one
+    pub identity: Option<String>,
     pub version: &'static str,
     pub git_commit: &'static str,
 }
 
+/// An event that some relays didn't accept yet, waiting in the outbox for retry.
+#[derive(Debug, Serialize)]
+pub struct OutboxEvent {
+    pub event_id: String,
+    pub kind: u16,
+    pub pending_relays: Vec<String>,
+    pub delivered_relays: Vec<String>,
+    pub attempts: u32,
+    pub created_at: u64,
+    pub last_attempt_at: u64,
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+}
+
+impl From<OutboxEntry> for OutboxEvent {
+    fn from(entry: OutboxEntry) -> Self {
+        let mut pending_relays: Vec<String> = entry.pending_relays.into_iter().collect();
+        pending_relays.sort();
+        let mut delivered_relays: Vec<String> = entry.delivered_relays.into_iter().collect();
+        delivered_relays.sort();
+
+        Self {
+            event_id: entry.event.id.to_hex(),
+            kind: entry.event.kind.as_u16(),
+            pending_relays,
+            delivered_relays,
+            attempts: entry.attempts,
+            created_at: entry.created_at,
+            last_attempt_at: entry.last_attempt_at,
+            next_attempt_at: entry.next_attempt_at,
+            last_error: entry.last_error,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutboxResponse {
+    pub events: Vec<OutboxEvent>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutboxRetryResponse {
+    /// Events delivered to all their relays by the retry
+    pub delivered: usize,
+    /// Events still waiting in the outbox
+    pub remaining: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutboxPurgeResponse {
+    pub purged: usize,
+}
+
 /// NIP-05 `.well-known/nostr.json` content.
 #[derive(Debug, Serialize)]
 pub struct Nip05WellKnownResponse {
@@ -156,6 +372,16 @@ pub struct StreamEvent {
     pub data: NotificationData,
 }
 
+/// An event of any stream, as sent by `GET /events/live`.
+#[derive(Debug, Clone, Serialize)]
+pub struct LiveEvent {
+    /// Monotonically increasing across all the streams.
+    pub seq: u64,
+    pub stream_id: String,
+    #[serde(flatten)]
+    pub event: StreamEvent,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum NotificationData {
@@ -188,11 +414,82 @@ pub enum NotificationData {
     CashuResponse {
         status: CashuResponseStatus,
     },
+    CertificateResponse {
+        status: CertificateResponseStatus,
+        /// Verified certificates, keyed by certificate type
+        certificates: std::collections::HashMap<String, RevealedCertificate>,
+    },
+    /// A payment of a subscription was collected by the billing engine
+    SubscriptionCharged {
+        subscription_id: String,
+        /// Number of payments collected so far, including this one
+        payment_number: u32,
+        amount: u64,
+        currency: Currency,
+        /// Unix timestamp the payment was due at
+        due_at: u64,
+        preimage: Option<String>,
+        /// `None` if this was the last payment
+        next_payment_at: Option<u64>,
+    },
+    /// A charge of a subscription failed
+    SubscriptionPaymentFailed {
+        subscription_id: String,
+        reason: String,
+        /// Failed attempts at collecting this payment
+        attempt: u32,
+        /// `None` if the charge won't be retried
+        next_retry_at: Option<u64>,
+    },
+    /// All the retries of a payment failed, it is skipped
+    SubscriptionPastDue {
+        subscription_id: String,
+        missed_payment_at: u64,
+        next_payment_at: Option<u64>,
+    },
+    /// The last payment of a subscription was due
+    SubscriptionCompleted {
+        subscription_id: String,
+        payments_made: u32,
+    },
+    SubscriptionCancelled {
+        subscription_id: String,
+        reason: Option<String>,
+        by_service: bool,
+    },
+    /// A request event that no relay accepted was delivered later, or dropped
+    RelayDelivery {
+        event_id: String,
+        outcome: SendOutcome,
+    },
     Error {
         reason: String,
     },
 }
 
+impl NotificationData {
+    /// The `type` of the notification, as serialized.
+    pub fn type_name(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|value| value.get("type")?.as_str().map(str::to_string))
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevealedCertificate {
+    pub issuer: String,
+    pub subject: String,
+    pub issued_at: Timestamp,
+    pub expires_at: Timestamp,
+    /// The fields revealed by the user, including `predicates` claims
+    pub fields: serde_json::Value,
+    /// `valid` or `revoked` according to the status proofs of the user, `unknown` if they
+    /// didn't send any for this certificate
+    pub revocation_status: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "status", rename_all = "snake_case")]
 pub enum InvoiceStatus {
@@ -205,9 +502,38 @@ pub enum InvoiceStatus {
     UserRejected { reason: Option<String> },
 }
 
+#[derive(Debug, Serialize)]
+pub struct SubscriptionsResponse {
+    pub subscriptions: Vec<Subscription>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookDeliveriesResponse {
+    pub deliveries: Vec<WebhookDelivery>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookReplayResponse {
+    pub stream_id: String,
+    /// Indexes of the events scheduled for delivery again
+    pub replayed: Vec<u64>,
+}
+
 /// Events polling response.
 #[derive(Debug, Serialize)]
 pub struct EventsResponse {
     pub stream_id: String,
     pub events: Vec<StreamEvent>,
 }
+
+#[derive(Debug, Serialize)]
+pub struct ApiKeysResponse {
+    pub keys: Vec<ApiKey>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKeyResponse {
+    /// The key to send as Bearer token, only shown now
+    pub key: String,
+    pub api_key: ApiKey,
+}
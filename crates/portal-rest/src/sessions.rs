@@ -0,0 +1,109 @@
+use portal::nostr::key::PublicKey;
+use portal::protocol::model::auth::Permission;
+use rusqlite::Connection;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// SQLite-backed list of the revoked user sessions and of the permissions granted by the
+/// users, loaded into the SDK at startup so that they survive a restart of the daemon.
+///
+/// Revoked sessions are kept until every token of the session has expired.
+pub struct SqliteSessionStore {
+    db: Mutex<Connection>,
+}
+
+impl SqliteSessionStore {
+    /// Open (or create) the SQLite database at `db_path` and initialize the schema.
+    pub fn new(db_path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open(db_path)?;
+
+        conn.execute_batch("PRAGMA journal_mode=WAL;")?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS revoked_sessions (
+                session_id TEXT PRIMARY KEY,
+                revoked_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS granted_permissions (
+                user_key TEXT PRIMARY KEY,
+                permissions TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            );",
+        )?;
+
+        info!("Session store opened at {db_path}");
+
+        Ok(Self {
+            db: Mutex::new(conn),
+        })
+    }
+
+    /// Record a revoked session, until `expires_at` (unix seconds).
+    pub async fn revoke(&self, session_id: &str, expires_at: u64) -> anyhow::Result<()> {
+        let db = self.db.lock().await;
+        db.execute(
+            "INSERT INTO revoked_sessions (session_id, revoked_at, expires_at)
+             VALUES (?1, ?2, ?3)
+             ON CONFLICT(session_id) DO UPDATE SET expires_at = MAX(expires_at, ?3)",
+            rusqlite::params![
+                session_id,
+                chrono::Utc::now().timestamp(),
+                expires_at as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The sessions still revoked, with the time their last token expires, dropping the ones
+    /// whose tokens have all expired.
+    pub async fn load_revoked(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        let db = self.db.lock().await;
+        db.execute(
+            "DELETE FROM revoked_sessions WHERE expires_at < ?1",
+            rusqlite::params![chrono::Utc::now().timestamp()],
+        )?;
+
+        let mut stmt = db.prepare("SELECT session_id, expires_at FROM revoked_sessions")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Save the permissions a user granted, replacing the previous ones.
+    pub async fn save_granted_permissions(
+        &self,
+        user_key: &str,
+        permissions: &[Permission],
+    ) -> anyhow::Result<()> {
+        let permissions = serde_json::to_string(permissions)?;
+        let db = self.db.lock().await;
+        db.execute(
+            "INSERT INTO granted_permissions (user_key, permissions, updated_at)
+             VALUES (?1, ?2, ?3)
+             ON CONFLICT(user_key) DO UPDATE SET permissions = ?2, updated_at = ?3",
+            rusqlite::params![user_key, permissions, chrono::Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    /// The permissions granted by each user.
+    pub async fn load_granted_permissions(
+        &self,
+    ) -> anyhow::Result<Vec<(PublicKey, Vec<Permission>)>> {
+        let db = self.db.lock().await;
+        let mut stmt = db.prepare("SELECT user_key, permissions FROM granted_permissions")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut granted = Vec::new();
+        for row in rows {
+            let (user_key, permissions) = row?;
+            match (user_key.parse(), serde_json::from_str(&permissions)) {
+                (Ok(user_key), Ok(permissions)) => granted.push((user_key, permissions)),
+                _ => warn!("Ignoring invalid granted permissions of {user_key}"),
+            }
+        }
+        Ok(granted)
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lightning_invoice::Bolt11Invoice;
+use portal::protocol::model::Timestamp;
+use portal_wallet::{PortalWallet, Settlement};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::oneshot;
+use tracing::{error, info, warn};
+
+use crate::events::EventStore;
+use crate::ledger::{LedgerUpdate, PaymentLedger};
+use crate::response::{InvoiceStatus, NotificationData};
+
+/// How often the invoices are checked when the wallet doesn't push settlements
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the invoices are checked anyway when the wallet pushes settlements, in case
+/// one was missed
+const FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Settlements remembered for the invoices watched after they were paid
+const RECENT_SETTLEMENTS: usize = 1024;
+
+struct WatchedInvoice {
+    invoice: String,
+    payment_hash: Option<String>,
+    expires_at: Timestamp,
+    waiter: Waiter,
+}
+
+/// Who gets the final status of a watched invoice
+enum Waiter {
+    /// Pushed to the stream and recorded in the ledger
+    Stream,
+    /// Sent to the subscription charge waiting for it, the billing engine updates the ledger
+    Charge(oneshot::Sender<InvoiceStatus>),
+}
+
+/// Watches the invoices of all the payment streams and subscription charges until they are
+/// paid or expire.
+///
+/// Settlements pushed by the wallet ([`PortalWallet::subscribe_settlements`]) are fanned out
+/// to the streams waiting for them. The invoices are only polled with `is_invoice_paid` as a
+/// fallback, or every second if the wallet can't push settlements.
+///
+/// The final status of each stream invoice is recorded in the payment ledger too.
+#[derive(Clone)]
+pub struct SettlementWatcher {
+    wallet: Arc<dyn PortalWallet>,
+    events: EventStore,
+    ledger: PaymentLedger,
+    /// By stream ID, or request ID for the subscription charges
+    watched: Arc<Mutex<HashMap<String, WatchedInvoice>>>,
+    recent: Arc<Mutex<VecDeque<Settlement>>>,
+}
+
+impl SettlementWatcher {
+    pub fn new(wallet: Arc<dyn PortalWallet>, events: EventStore, ledger: PaymentLedger) -> Self {
+        Self {
+            wallet,
+            events,
+            ledger,
+            watched: Arc::new(Mutex::new(HashMap::new())),
+            recent: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    /// Watch the invoice of a stream, pushing a `PaymentStatusUpdate` once it is paid or
+    /// when the request expires.
+    pub async fn watch(&self, stream_id: String, invoice: String, expires_at: Timestamp) {
+        self.start(stream_id, invoice, expires_at, Waiter::Stream)
+            .await;
+    }
+
+    /// Watch the invoice of a subscription charge, the returned receiver gets its final
+    /// status: `Paid`, `Timeout` once `expires_at` is past, or `Error`.
+    pub async fn watch_charge(
+        &self,
+        request_id: String,
+        invoice: String,
+        expires_at: Timestamp,
+    ) -> oneshot::Receiver<InvoiceStatus> {
+        let (sender, receiver) = oneshot::channel();
+        self.start(request_id, invoice, expires_at, Waiter::Charge(sender))
+            .await;
+        receiver
+    }
+
+    /// Stop watching the invoice of a subscription charge given up before it was paid
+    pub fn unwatch_charge(&self, request_id: &str) {
+        self.watched.lock().unwrap().remove(request_id);
+    }
+
+    async fn start(&self, id: String, invoice: String, expires_at: Timestamp, waiter: Waiter) {
+        let payment_hash = Bolt11Invoice::from_str(&invoice)
+            .ok()
+            .map(|invoice| invoice.payment_hash().to_string());
+        let watched = WatchedInvoice {
+            invoice,
+            payment_hash,
+            expires_at,
+            waiter,
+        };
+
+        // The user may have paid before telling us
+        let settled = self
+            .recent
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|settlement| matches(&watched, settlement))
+            .cloned();
+        self.watched.lock().unwrap().insert(id.clone(), watched);
+        if let Some(settlement) = settled {
+            self.paid(&id, settlement.preimage).await;
+        }
+    }
+
+    /// Run the watcher, for the lifetime of the daemon
+    pub async fn run(self) {
+        let mut settlements = match self.wallet.subscribe_settlements().await {
+            Ok(Some(settlements)) => {
+                info!("The wallet pushes settlements, invoices are polled every {FALLBACK_POLL_INTERVAL:?} as a fallback");
+                Some(settlements)
+            }
+            Ok(None) => {
+                info!("The wallet doesn't push settlements, invoices are polled every {POLL_INTERVAL:?}");
+                None
+            }
+            Err(e) => {
+                warn!("Failed to subscribe to the wallet settlements, invoices are polled every {POLL_INTERVAL:?}: {e}");
+                None
+            }
+        };
+
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        let mut last_poll = Instant::now();
+        loop {
+            tokio::select! {
+                settlement = next_settlement(&mut settlements) => match settlement {
+                    Ok(settlement) => self.settle(settlement).await,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Missed {skipped} settlements, polling the invoices");
+                        self.poll().await;
+                        last_poll = Instant::now();
+                    }
+                    Err(RecvError::Closed) => {
+                        warn!("The wallet stopped pushing settlements, polling the invoices every {POLL_INTERVAL:?}");
+                        settlements = None;
+                    }
+                },
+                _ = ticker.tick() => {
+                    self.expire().await;
+                    if settlements.is_none() || last_poll.elapsed() >= FALLBACK_POLL_INTERVAL {
+                        self.poll().await;
+                        last_poll = Instant::now();
+                    }
+                }
+            }
+        }
+    }
+
+    async fn settle(&self, settlement: Settlement) {
+        let ids: Vec<String> = self
+            .watched
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, watched)| matches(watched, &settlement))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in ids {
+            self.paid(&id, settlement.preimage.clone()).await;
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_SETTLEMENTS {
+            recent.pop_front();
+        }
+        recent.push_back(settlement);
+    }
+
+    async fn poll(&self) {
+        let invoices: Vec<(String, String)> = self
+            .watched
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, watched)| (id.clone(), watched.invoice.clone()))
+            .collect();
+
+        for (id, invoice) in invoices {
+            match self.wallet.is_invoice_paid(invoice).await {
+                Ok((true, preimage)) => self.paid(&id, preimage).await,
+                Ok((false, _)) => {}
+                Err(e) => {
+                    error!("Failed to check invoice {id}: {e}");
+                    self.finish(
+                        &id,
+                        InvoiceStatus::Error {
+                            reason: e.to_string(),
+                        },
+                    )
+                    .await;
+                }
+            }
+        }
+    }
+
+    async fn expire(&self) {
+        let now = Timestamp::now();
+        let expired: Vec<String> = self
+            .watched
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, watched)| now > watched.expires_at)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            self.finish(&id, InvoiceStatus::Timeout).await;
+        }
+    }
+
+    async fn paid(&self, id: &str, preimage: Option<String>) {
+        self.finish(id, InvoiceStatus::Paid { preimage }).await;
+    }
+
+    /// Stop watching an invoice, handing its final status to its waiter
+    async fn finish(&self, id: &str, status: InvoiceStatus) {
+        // A settlement pushed while the invoice was being polled finishes it only once
+        let Some(watched) = self.watched.lock().unwrap().remove(id) else {
+            return;
+        };
+        match watched.waiter {
+            Waiter::Stream => self.push_status(id, status).await,
+            Waiter::Charge(sender) => {
+                // The charge may have been given up meanwhile
+                let _ = sender.send(status);
+            }
+        }
+    }
+
+    async fn push_status(&self, stream_id: &str, status: InvoiceStatus) {
+        if let Err(e) = self
+            .ledger
+            .update_stream(stream_id, LedgerUpdate::from_invoice_status(&status))
+            .await
+        {
+            error!("Failed to update the ledger entry of stream {stream_id}: {e}");
+        }
+        self.events
+            .push(stream_id, NotificationData::PaymentStatusUpdate { status })
+            .await;
+    }
+}
+
+fn matches(watched: &WatchedInvoice, settlement: &Settlement) -> bool {
+    watched.payment_hash.as_deref() == Some(settlement.payment_hash.as_str())
+        || settlement.invoice.as_deref() == Some(watched.invoice.as_str())
+}
+
+async fn next_settlement(
+    settlements: &mut Option<broadcast::Receiver<Settlement>>,
+) -> Result<Settlement, RecvError> {
+    match settlements {
+        Some(settlements) => settlements.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use portal_wallet::{MockWallet, MockWalletConfig};
+
+    use crate::config::WebhookSettings;
+
+    /// A watcher of a mock wallet, running
+    async fn watcher() -> (SettlementWatcher, Arc<MockWallet>) {
+        let wallet = Arc::new(MockWallet::new(MockWalletConfig::default()).unwrap());
+        let watcher = SettlementWatcher::new(
+            wallet.clone(),
+            EventStore::new(":memory:", WebhookSettings::default()).unwrap(),
+            PaymentLedger::new(":memory:").unwrap(),
+        );
+        tokio::spawn(watcher.clone().run());
+        // Let it subscribe to the settlements
+        tokio::task::yield_now().await;
+        (watcher, wallet)
+    }
+
+    #[tokio::test]
+    async fn test_charge_is_paid_by_a_pushed_settlement() {
+        let (watcher, wallet) = watcher().await;
+        let invoice = wallet.make_invoice(1000, None).await.unwrap();
+
+        let status = watcher
+            .watch_charge(
+                "request".to_string(),
+                invoice.clone(),
+                Timestamp::now_plus_seconds(3600),
+            )
+            .await;
+        wallet.settle_invoice(&invoice).unwrap();
+
+        let status = tokio::time::timeout(Duration::from_secs(5), status)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(status, InvoiceStatus::Paid { preimage: Some(_) }));
+        assert!(watcher.watched.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_charge_times_out_when_it_expires() {
+        let (watcher, wallet) = watcher().await;
+        let invoice = wallet.make_invoice(1000, None).await.unwrap();
+
+        let status = watcher
+            .watch_charge(
+                "request".to_string(),
+                invoice,
+                Timestamp::new(Timestamp::now().as_u64() - 1),
+            )
+            .await;
+
+        let status = tokio::time::timeout(Duration::from_secs(5), status)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(status, InvoiceStatus::Timeout));
+    }
+}
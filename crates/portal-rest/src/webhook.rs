@@ -1,19 +1,103 @@
+use std::time::Duration;
+
 use hmac::{Hmac, Mac};
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use sha2::Sha256;
-use tracing::{debug, error};
+use tracing::{debug, info};
 
 use crate::config::WebhookSettings;
+use crate::events::EventStore;
 use crate::response::NotificationData;
 
 type HmacSha256 = Hmac<Sha256>;
 
-/// Delivers a webhook notification if a webhook URL is configured.
+/// How often the deliveries due for retry are looked up.
+const RETRY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Delivery state of a stream event to the webhook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Not delivered yet, retried at `next_attempt_at`.
+    Pending,
+    Delivered,
+    /// Every attempt failed. Not retried anymore until replayed.
+    DeadLetter,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Delivered => "delivered",
+            Self::DeadLetter => "dead_letter",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "pending" => Some(Self::Pending),
+            "delivered" => Some(Self::Delivered),
+            "dead_letter" => Some(Self::DeadLetter),
+            _ => None,
+        }
+    }
+}
+
+/// The webhook delivery of a stream event.
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookDelivery {
+    pub stream_id: String,
+    pub index: u64,
+    pub status: DeliveryStatus,
+    /// Attempts since the event was pushed or last replayed.
+    pub attempts: u32,
+    /// Unix timestamp of the next attempt, only set while pending.
+    pub next_attempt_at: Option<i64>,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    /// Every attempt made, oldest first. Only filled when a single delivery is fetched.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attempt_log: Vec<DeliveryAttempt>,
+}
+
+/// One POST to the webhook URL.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryAttempt {
+    pub attempted_at: i64,
+    /// HTTP status returned by the endpoint, if it answered.
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// Why a delivery attempt failed.
+#[derive(Debug)]
+pub struct DeliveryError {
+    pub status_code: Option<u16>,
+    pub message: String,
+}
+
+/// Delay before the next attempt after `attempts` failed ones, doubled every time and capped
+/// at `retry_max_delay_secs`.
+pub fn retry_delay_secs(settings: &WebhookSettings, attempts: u32) -> u64 {
+    settings
+        .retry_base_delay_secs
+        .saturating_mul(1 << attempts.saturating_sub(1).min(30))
+        .min(settings.retry_max_delay_secs)
+}
+
+/// Makes one attempt to deliver a webhook notification, returning the HTTP status on success.
 ///
 /// The payload is JSON-serialised `NotificationData` wrapped in an envelope with the `stream_id`.
 /// If a `webhook_secret` is configured, the raw JSON body is signed with HMAC-SHA256 and the
 /// hex-encoded signature is sent in the `X-Portal-Signature` header.
 ///
+/// Retries are up to the caller, see [`EventStore::deliver_webhook`]: the same event is always
+/// sent with the same `index` and `timestamp`, so receivers can deduplicate.
+///
 /// The `client` should be a shared `reqwest::Client` — do not create one per call.
 pub async fn deliver(
     client: &Client,
@@ -22,12 +106,16 @@ pub async fn deliver(
     data: &NotificationData,
     index: u64,
     timestamp: &str,
-) {
-    let url = match &settings.url {
-        Some(u) if !u.is_empty() => u.clone(),
-        _ => return, // No webhook configured
+) -> Result<u16, DeliveryError> {
+    let failure = |status_code: Option<u16>, message: String| DeliveryError {
+        status_code,
+        message,
     };
 
+    let url = settings
+        .url()
+        .ok_or_else(|| failure(None, "No webhook URL configured".to_string()))?;
+
     #[derive(serde::Serialize)]
     struct Envelope<'a> {
         stream_id: &'a str,
@@ -38,16 +126,11 @@ pub async fn deliver(
     }
 
     let envelope = Envelope { stream_id, data, index, timestamp };
-    let body = match serde_json::to_string(&envelope) {
-        Ok(b) => b,
-        Err(e) => {
-            error!("Failed to serialise webhook payload: {e}");
-            return;
-        }
-    };
+    let body = serde_json::to_string(&envelope)
+        .map_err(|e| failure(None, format!("Failed to serialise webhook payload: {e}")))?;
 
     let mut req = client
-        .post(&url)
+        .post(url)
         .header("Content-Type", "application/json");
 
     // Sign with HMAC-SHA256 if secret is set
@@ -64,16 +147,68 @@ pub async fn deliver(
     debug!("Delivering webhook to {url} for stream {stream_id}");
 
     match req.body(body).send().await {
-        Ok(resp) => {
-            if !resp.status().is_success() {
-                error!(
-                    "Webhook delivery to {url} returned HTTP {}",
-                    resp.status()
-                );
-            }
+        Ok(resp) if resp.status().is_success() => Ok(resp.status().as_u16()),
+        Ok(resp) => Err(failure(
+            Some(resp.status().as_u16()),
+            format!("Webhook returned HTTP {}", resp.status()),
+        )),
+        Err(e) => Err(failure(None, format!("Webhook request failed: {e}"))),
+    }
+}
+
+/// Retries the webhook deliveries that are due, including the ones left pending by a
+/// previous run of the daemon.
+pub async fn run_retries(events: EventStore) {
+    if !events.webhooks_enabled() {
+        return;
+    }
+    info!("Webhook delivery retries started");
+
+    let mut interval = tokio::time::interval(RETRY_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        for (stream_id, index) in events.due_webhook_deliveries().await {
+            let events = events.clone();
+            tokio::spawn(async move { events.deliver_webhook(&stream_id, index).await });
         }
-        Err(e) => {
-            error!("Webhook delivery to {url} failed: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(base: u64, max: u64) -> WebhookSettings {
+        WebhookSettings {
+            retry_base_delay_secs: base,
+            retry_max_delay_secs: max,
+            ..Default::default()
         }
     }
+
+    #[test]
+    fn test_retry_delay_doubles_up_to_the_cap() {
+        let settings = settings(10, 60);
+
+        assert_eq!(retry_delay_secs(&settings, 0), 10);
+        assert_eq!(retry_delay_secs(&settings, 1), 10);
+        assert_eq!(retry_delay_secs(&settings, 2), 20);
+        assert_eq!(retry_delay_secs(&settings, 3), 40);
+        assert_eq!(retry_delay_secs(&settings, 4), 60);
+        assert_eq!(retry_delay_secs(&settings, u32::MAX), 60);
+    }
+
+    #[test]
+    fn test_retry_delay_does_not_overflow() {
+        // The multiplier stops growing at 2^30
+        let uncapped = settings(1, u64::MAX);
+        assert_eq!(retry_delay_secs(&uncapped, 31), 1 << 30);
+        assert_eq!(retry_delay_secs(&uncapped, 100), 1 << 30);
+        assert_eq!(retry_delay_secs(&uncapped, u32::MAX), 1 << 30);
+
+        // And the delay saturates instead of wrapping
+        let long = settings(u64::MAX / 2, u64::MAX);
+        assert_eq!(retry_delay_secs(&long, 3), u64::MAX);
+    }
 }
@@ -1,18 +1,39 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 
 use chrono::Duration;
 use portal::{
     conversation::cashu::{CashuDirectSenderConversation, CashuRequestSenderConversation},
+    conversation::certificate::{
+        CertificateDeliverySenderConversation, CertificateRequestSenderConversation,
+        RevocationListListenerConversation, RevocationListPublisherConversation,
+    },
     conversation::close_subscription::{
         CloseRecurringPaymentConversation, CloseRecurringPaymentReceiverConversation,
     },
     conversation::invoice::InvoiceRequestConversation,
-    nostr::key::PublicKey,
+    conversation::subkey::{
+        SubkeyRevocationListenerConversation, SubkeyRevocationPublisherConversation,
+    },
+    nostr::{event::EventId, key::PublicKey},
     nostr_relay_pool::{RelayOptions, RelayPool},
     conversation::profile::{FetchProfileInfoConversation, Profile, SetProfileConversation},
     protocol::{
         LocalKeypair,
+        identity::Certificate,
+        issuance::{CertificateBuilder, IssuanceError},
+        jwt::DecodeOptions,
         key_handshake::KeyHandshakeUrl,
+        model::Timestamp,
+        model::auth::{AuthResponseStatus, AuthSuccessContent, Permission},
+        model::identity::{
+            CertificateDeliveryContent, CertificateRequestContent, CertificateResponseContent,
+        },
+        revocation::RevocationList,
+        session::{DEFAULT_SESSION_DURATION_SECS, Session, SessionClaims},
+        subkey::SubkeyRevocation,
         model::payment::{
             CashuDirectContent, CashuRequestContent, CashuResponseContent,
             CloseRecurringPaymentContent, CloseRecurringPaymentResponse, InvoiceRequestContent,
@@ -21,13 +42,16 @@ use portal::{
         },
     },
     router::{
-        ConversationError, MessageRouter, MessageRouterActorError, MultiKeyListenerAdapter,
-        MultiKeySenderAdapter, NotificationStream, adapters::one_shot::OneShotSenderAdapter,
+        ConversationError, ConversationRegistry, EventSendResult, MessageRouter,
+        MessageRouterActorError, MultiKeyListenerAdapter, MultiKeySenderAdapter,
+        NotificationStream, OutboxEntry, PersistentConversation, ResumedConversation,
+        RouterStorage,
+        adapters::{ConversationWithNotification, one_shot::OneShotSenderAdapter},
     },
     conversation::sdk::{
         auth::{
-            AuthChallengeSenderConversation, AuthResponseEvent, KeyHandshakeEvent,
-            KeyHandshakeReceiverConversation,
+            AuthChallengeSenderConversation, AuthResponseEvent, AuthSuccessSenderConversation,
+            KeyHandshakeEvent, KeyHandshakeReceiverConversation,
         },
         payments::{
             RecurringPaymentRequestSenderConversation, SinglePaymentRequestSenderConversation,
@@ -35,17 +59,44 @@ use portal::{
     },
     utils::verify_nip05,
 };
-use tokio::task::JoinHandle;
+use tokio::{sync::broadcast, task::JoinHandle};
 
 pub struct PortalSDK {
     router: Arc<MessageRouter<Arc<RelayPool>>>,
     prefererred_relays: Vec<String>,
     relay_pool: Arc<RelayPool>,
+    /// IDs of the sessions revoked by the service, also checked against the `jti` of the JWTs,
+    /// with the time the last token of each session expires
+    revoked_sessions: Arc<RwLock<HashMap<String, Timestamp>>>,
+    /// Permissions granted by the users that were asked for some
+    granted_permissions: RwLock<HashMap<PublicKey, Vec<Permission>>>,
     _listener: JoinHandle<Result<(), MessageRouterActorError>>,
 }
 
+/// Conversation types started by the SDK that can be resumed after a restart
+fn conversation_registry() -> ConversationRegistry {
+    ConversationRegistry::new()
+        .register_listener::<KeyHandshakeReceiverConversation>()
+        .register_sender::<AuthChallengeSenderConversation>()
+        .register_sender::<RecurringPaymentRequestSenderConversation>()
+        .register_sender::<SinglePaymentRequestSenderConversation>()
+        .register_sender::<InvoiceRequestConversation>()
+        .register_sender::<CashuRequestSenderConversation>()
+        .register_sender::<CertificateRequestSenderConversation>()
+}
+
 impl PortalSDK {
     pub async fn new(keypair: LocalKeypair, relays: Vec<String>) -> Result<Self, PortalSDKError> {
+        Self::new_with_storage(keypair, relays, RouterStorage::default()).await
+    }
+
+    /// Creates an SDK instance that saves the state of resumable conversations and the
+    /// events waiting for relay delivery in `storage`
+    pub async fn new_with_storage(
+        keypair: LocalKeypair,
+        relays: Vec<String>,
+        storage: RouterStorage,
+    ) -> Result<Self, PortalSDKError> {
         let relay_pool = RelayPool::new();
         for relay in &relays {
             relay_pool.add_relay(relay, RelayOptions::default()).await?;
@@ -53,7 +104,11 @@ impl PortalSDK {
         relay_pool.connect().await;
         let relay_pool = Arc::new(relay_pool);
 
-        let router = Arc::new(MessageRouter::new(Arc::clone(&relay_pool), keypair.clone()));
+        let router = Arc::new(MessageRouter::new_with_storage(
+            Arc::clone(&relay_pool),
+            keypair.clone(),
+            storage,
+        ));
 
         for relay in &relays {
             router.add_relay(relay.clone(), false).await?;
@@ -62,18 +117,77 @@ impl PortalSDK {
         let _router = Arc::clone(&router);
         let _listener = tokio::spawn(async move { _router.listen().await });
 
+        // Keep track of the revoked subkeys, so their proofs are rejected
+        router
+            .add_conversation(Box::new(SubkeyRevocationListenerConversation::new(vec![])))
+            .await?;
+
         Ok(Self {
             router,
             relay_pool,
             prefererred_relays: relays,
+            revoked_sessions: Arc::new(RwLock::new(HashMap::new())),
+            granted_permissions: RwLock::new(HashMap::new()),
             _listener,
         })
     }
 
+    /// Adds a conversation and subscribes to its notifications
+    ///
+    /// If `persist_as` is set the conversation is saved in the conversation store under that
+    /// tag, and can be picked up again with [`PortalSDK::resume_conversations`].
+    async fn subscribe<C>(
+        &self,
+        conversation: C,
+        persist_as: Option<String>,
+    ) -> Result<(NotificationStream<C::Notification>, Vec<EventSendResult>), PortalSDKError>
+    where
+        C: ConversationWithNotification + PersistentConversation + Send + Sync + 'static,
+    {
+        Ok(match persist_as {
+            Some(tag) => {
+                self.router
+                    .add_persistent_and_subscribe(Box::new(conversation), tag)
+                    .await?
+            }
+            None => self.router.add_and_subscribe(Box::new(conversation)).await?,
+        })
+    }
+
+    /// Restores the resumable conversations that were in progress before a restart
+    ///
+    /// Each conversation is returned with the tag it was started with, its notifications
+    /// can be read with [`ResumedConversation::into_stream`].
+    pub async fn resume_conversations(&self) -> Result<Vec<ResumedConversation>, PortalSDKError> {
+        Ok(self
+            .router
+            .resume_conversations(conversation_registry())
+            .await?)
+    }
+
     pub async fn new_key_handshake_url(
         &self,
         static_token: Option<String>,
         no_request: Option<bool>,
+    ) -> Result<(KeyHandshakeUrl, NotificationStream<KeyHandshakeEvent>), PortalSDKError> {
+        self.key_handshake(static_token, no_request, None).await
+    }
+
+    /// Like [`PortalSDK::new_key_handshake_url`], but the conversation is saved under `tag`
+    pub async fn new_key_handshake_url_resumable(
+        &self,
+        static_token: Option<String>,
+        no_request: Option<bool>,
+        tag: String,
+    ) -> Result<(KeyHandshakeUrl, NotificationStream<KeyHandshakeEvent>), PortalSDKError> {
+        self.key_handshake(static_token, no_request, Some(tag)).await
+    }
+
+    async fn key_handshake(
+        &self,
+        static_token: Option<String>,
+        no_request: Option<bool>,
+        persist_as: Option<String>,
     ) -> Result<(KeyHandshakeUrl, NotificationStream<KeyHandshakeEvent>), PortalSDKError> {
         let token = static_token.unwrap_or_else(|| {
             format!(
@@ -90,11 +204,10 @@ impl PortalSDK {
             token.clone(),
         );
         let (event, _outcomes) = self
-            .router
-            .add_and_subscribe(Box::new(MultiKeyListenerAdapter::new(
-                inner,
-                self.router.keypair().subkey_proof().cloned(),
-            )))
+            .subscribe(
+                MultiKeyListenerAdapter::new(inner, self.router.keypair().subkey_proof().cloned()),
+                persist_as,
+            )
             .await?;
 
         let (main_key, subkey) = if let Some(subkey_proof) = self.router.keypair().subkey_proof() {
@@ -117,67 +230,307 @@ impl PortalSDK {
         Ok((url, event))
     }
 
-    pub async fn authenticate_key(
+    fn auth_challenge(
         &self,
         main_key: PublicKey,
         subkeys: Vec<PublicKey>,
-    ) -> Result<AuthResponseEvent, PortalSDKError> {
+        permissions: Vec<Permission>,
+    ) -> MultiKeySenderAdapter<AuthChallengeSenderConversation> {
         let conv = AuthChallengeSenderConversation::new(
             self.router.keypair().public_key(),
             self.router.keypair().subkey_proof().cloned(),
+            permissions,
         );
+        MultiKeySenderAdapter::new_with_user(main_key, subkeys, conv)
+    }
+
+    /// Sends an auth challenge and waits for the response
+    ///
+    /// When the user approves, a session of [`DEFAULT_SESSION_DURATION_SECS`] is opened (or the
+    /// one presented by the user refreshed), see [`PortalSDK::complete_authentication`].
+    pub async fn authenticate_key(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+    ) -> Result<AuthResponseEvent, PortalSDKError> {
+        self.authenticate_key_with_permissions(main_key, subkeys, vec![])
+            .await
+    }
 
+    /// Like [`PortalSDK::authenticate_key`], also asking the user for `permissions`
+    ///
+    /// Once the user answered, the requests that need a permission they didn't grant fail
+    /// with [`PortalSDKError::PermissionDenied`].
+    pub async fn authenticate_key_with_permissions(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+        permissions: Vec<Permission>,
+    ) -> Result<AuthResponseEvent, PortalSDKError> {
         let (mut event, _outcomes) = self
-            .router
-            .add_and_subscribe(Box::new(MultiKeySenderAdapter::new_with_user(
-                main_key, subkeys, conv,
+            .subscribe(self.auth_challenge(main_key, subkeys, permissions), None)
+            .await?;
+        let event = event.next().await.ok_or(PortalSDKError::Timeout)??;
+        self.complete_authentication(
+            event,
+            Duration::seconds(DEFAULT_SESSION_DURATION_SECS as i64),
+        )
+        .await
+    }
+
+    /// Like [`PortalSDK::authenticate_key_with_permissions`], but the conversation is saved
+    /// under `tag`
+    ///
+    /// The response must be passed to [`PortalSDK::complete_authentication`]. Also returns the
+    /// delivery outcome of the request events, see [`PortalSDK::delivery_updates`] for what
+    /// happens to the queued ones.
+    pub async fn authenticate_key_resumable(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+        permissions: Vec<Permission>,
+        tag: String,
+    ) -> Result<(NotificationStream<AuthResponseEvent>, Vec<EventSendResult>), PortalSDKError> {
+        self.subscribe(
+            self.auth_challenge(main_key, subkeys, permissions),
+            Some(tag),
+        )
+        .await
+    }
+
+    /// Opens the session of an approved auth response and sends its token to the user
+    ///
+    /// If the user presented the token of a valid session it is refreshed instead, keeping its
+    /// ID. The session token of the returned event is the one sent to the user. If the challenge
+    /// asked for permissions, the granted ones are enforced on the next requests to the user.
+    /// Declined responses are returned unchanged.
+    pub async fn complete_authentication(
+        &self,
+        mut event: AuthResponseEvent,
+        duration: Duration,
+    ) -> Result<AuthResponseEvent, PortalSDKError> {
+        let AuthResponseStatus::Approved {
+            granted_permissions,
+            session_token,
+        } = &mut event.status
+        else {
+            return Ok(event);
+        };
+
+        if !event.required_permissions.is_empty() {
+            let granted = Permission::parse_all(granted_permissions)
+                .map_err(|e| PortalSDKError::ProtocolError(e.to_string()))?;
+            self.set_granted_permissions(event.user_key, granted);
+        }
+
+        let keypair = self.router.keypair();
+        let presented = Some(session_token.as_str())
+            .filter(|token| !token.is_empty())
+            .and_then(|token| self.verify_session(token).ok())
+            .filter(|session| session.claims.user_key == event.user_key);
+        let claims = match presented {
+            Some(session) => SessionClaims {
+                granted_permissions: granted_permissions.clone(),
+                ..session.claims
+            },
+            None => {
+                let service_key = keypair
+                    .subkey_proof()
+                    .map(|proof| *proof.main_key)
+                    .unwrap_or_else(|| keypair.public_key());
+                SessionClaims::new(service_key, event.user_key, granted_permissions.clone())
+            }
+        };
+        let session = Session::issue(keypair.secret_key(), claims, duration)?;
+        *session_token = session.token.clone();
+
+        let content = AuthSuccessContent {
+            challenge: event.challenge.clone(),
+            session_token: session.token,
+            expires_at: session.expires_at,
+            subkey_proof: keypair.subkey_proof().cloned(),
+        };
+        let subkeys = if event.recipient != event.user_key {
+            vec![event.recipient]
+        } else {
+            vec![]
+        };
+        self.router
+            .add_conversation(Box::new(MultiKeySenderAdapter::new_with_user(
+                event.user_key,
+                subkeys,
+                AuthSuccessSenderConversation::new(content),
             )))
             .await?;
-        Ok(event.next().await.ok_or(PortalSDKError::Timeout)??)
+
+        Ok(event)
     }
 
-    pub async fn request_recurring_payment(
+    /// Verifies a session token issued by this service, rejecting the revoked sessions
+    pub fn verify_session(&self, token: &str) -> Result<Session, PortalSDKError> {
+        let session = Session::verify(&self.router.keypair().public_key(), token)?;
+        if self.is_session_revoked(&session.claims.session_id) {
+            return Err(PortalSDKError::SessionRevoked);
+        }
+        Ok(session)
+    }
+
+    /// Issues a new token for a valid session, valid for `duration` from now
+    pub fn refresh_session(
+        &self,
+        token: &str,
+        duration: Duration,
+    ) -> Result<Session, PortalSDKError> {
+        let session = self.verify_session(token)?;
+        Ok(session.refresh(self.router.keypair().secret_key(), duration)?)
+    }
+
+    /// Revokes a session, its tokens are rejected from now on
+    ///
+    /// The session is forgotten after `expires_at`, when its last token expires: its tokens
+    /// are rejected anyway by then. The sessions forgotten are pruned on each revocation.
+    pub fn revoke_session(&self, session_id: String, expires_at: Timestamp) {
+        let now = Timestamp::now();
+        let mut revoked = self.revoked_sessions.write().unwrap();
+        revoked.retain(|_, expires_at| *expires_at >= now);
+
+        let expires_at = revoked
+            .get(&session_id)
+            .map_or(expires_at, |revoked| expires_at.max(*revoked));
+        revoked.insert(session_id, expires_at);
+    }
+
+    pub fn is_session_revoked(&self, session_id: &str) -> bool {
+        self.revoked_sessions
+            .read()
+            .unwrap()
+            .contains_key(session_id)
+    }
+
+    /// The permissions granted by a user, `None` if they were never asked for any
+    pub fn granted_permissions(&self, user: &PublicKey) -> Option<Vec<Permission>> {
+        self.granted_permissions.read().unwrap().get(user).cloned()
+    }
+
+    /// Sets the permissions granted by a user, e.g. to restore them after a restart
+    pub fn set_granted_permissions(&self, user: PublicKey, permissions: Vec<Permission>) {
+        self.granted_permissions
+            .write()
+            .unwrap()
+            .insert(user, permissions);
+    }
+
+    /// Fails if `user` was asked for permissions and didn't grant `permission`
+    ///
+    /// Users that were never asked for permissions are not restricted.
+    pub fn check_permission(
+        &self,
+        user: &PublicKey,
+        permission: &Permission,
+    ) -> Result<(), PortalSDKError> {
+        match self.granted_permissions.read().unwrap().get(user) {
+            Some(granted) if !Permission::is_granted(granted, permission) => {
+                Err(PortalSDKError::PermissionDenied(permission.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn recurring_payment_request(
         &self,
         main_key: PublicKey,
         subkeys: Vec<PublicKey>,
         payment_request: RecurringPaymentRequestContent,
-    ) -> Result<RecurringPaymentResponseContent, PortalSDKError> {
+    ) -> Result<MultiKeySenderAdapter<RecurringPaymentRequestSenderConversation>, PortalSDKError>
+    {
+        self.check_permission(&main_key, &Permission::RecurringPayment)?;
+
         let conv = RecurringPaymentRequestSenderConversation::new(
             self.router.keypair().public_key(),
             self.router.keypair().subkey_proof().cloned(),
             payment_request,
         )
         .map_err(PortalSDKError::ProtocolError)?;
+        Ok(MultiKeySenderAdapter::new_with_user(main_key, subkeys, conv))
+    }
 
-        let (mut event, _outcomes) = self
-            .router
-            .add_and_subscribe(Box::new(MultiKeySenderAdapter::new_with_user(
-                main_key, subkeys, conv,
-            )))
-            .await?;
+    pub async fn request_recurring_payment(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+        payment_request: RecurringPaymentRequestContent,
+    ) -> Result<RecurringPaymentResponseContent, PortalSDKError> {
+        let conv = self.recurring_payment_request(main_key, subkeys, payment_request)?;
+        let (mut event, _outcomes) = self.subscribe(conv, None).await?;
         Ok(event.next().await.ok_or(PortalSDKError::Timeout)??)
     }
 
-    pub async fn request_single_payment(
+    /// Like [`PortalSDK::request_recurring_payment`], but the conversation is saved under `tag`
+    ///
+    /// Also returns the delivery outcome of the request events, see
+    /// [`PortalSDK::delivery_updates`] for what happens to the queued ones.
+    pub async fn request_recurring_payment_resumable(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+        payment_request: RecurringPaymentRequestContent,
+        tag: String,
+    ) -> Result<(NotificationStream<RecurringPaymentResponseContent>, Vec<EventSendResult>), PortalSDKError> {
+        let conv = self.recurring_payment_request(main_key, subkeys, payment_request)?;
+        self.subscribe(conv, Some(tag)).await
+    }
+
+    fn single_payment_request(
         &self,
         main_key: PublicKey,
         subkeys: Vec<PublicKey>,
         payment_request: SinglePaymentRequestContent,
-    ) -> Result<NotificationStream<PaymentResponseContent>, PortalSDKError> {
+    ) -> Result<MultiKeySenderAdapter<SinglePaymentRequestSenderConversation>, PortalSDKError> {
+        // Charges of a subscription are covered by the recurring payment permission
+        let permission = if payment_request.subscription_id.is_some() {
+            Permission::RecurringPayment
+        } else {
+            Permission::SinglePayment {
+                max_sats: payment_request
+                    .amount_millisats()
+                    .map(|msat| msat.div_ceil(1000)),
+            }
+        };
+        self.check_permission(&main_key, &permission)?;
+
         let conv = SinglePaymentRequestSenderConversation::new(
             self.router.keypair().public_key(),
             self.router.keypair().subkey_proof().cloned(),
             payment_request,
         )
         .map_err(PortalSDKError::ProtocolError)?;
+        Ok(MultiKeySenderAdapter::new_with_user(main_key, subkeys, conv))
+    }
 
-        let (event, _outcomes) = self
-            .router
-            .add_and_subscribe(Box::new(MultiKeySenderAdapter::new_with_user(
-                main_key, subkeys, conv,
-            )))
-            .await?;
-        Ok(event)
+    pub async fn request_single_payment(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+        payment_request: SinglePaymentRequestContent,
+    ) -> Result<NotificationStream<PaymentResponseContent>, PortalSDKError> {
+        let conv = self.single_payment_request(main_key, subkeys, payment_request)?;
+        let (stream, _outcomes) = self.subscribe(conv, None).await?;
+        Ok(stream)
+    }
+
+    /// Like [`PortalSDK::request_single_payment`], but the conversation is saved under `tag`
+    ///
+    /// Also returns the delivery outcome of the request events, see
+    /// [`PortalSDK::delivery_updates`] for what happens to the queued ones.
+    pub async fn request_single_payment_resumable(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+        payment_request: SinglePaymentRequestContent,
+        tag: String,
+    ) -> Result<(NotificationStream<PaymentResponseContent>, Vec<EventSendResult>), PortalSDKError> {
+        let conv = self.single_payment_request(main_key, subkeys, payment_request)?;
+        self.subscribe(conv, Some(tag)).await
     }
 
     pub async fn fetch_profile(
@@ -255,22 +608,43 @@ impl PortalSDK {
         Ok(())
     }
 
-    pub async fn request_invoice(
+    fn invoice_request(
         &self,
         recipient: PublicKey,
         subkeys: Vec<PublicKey>,
         content: InvoiceRequestContent,
-    ) -> Result<Option<InvoiceResponse>, PortalSDKError> {
+    ) -> MultiKeySenderAdapter<InvoiceRequestConversation> {
         let conv = InvoiceRequestConversation::new(
             self.router.keypair().public_key(),
             self.router.keypair().subkey_proof().cloned(),
             content,
         );
+        MultiKeySenderAdapter::new_with_user(recipient, subkeys, conv)
+    }
+
+    /// Like [`PortalSDK::request_invoice`], but the conversation is saved under `tag`
+    ///
+    /// Also returns the delivery outcome of the request events, see
+    /// [`PortalSDK::delivery_updates`] for what happens to the queued ones.
+    pub async fn request_invoice_resumable(
+        &self,
+        recipient: PublicKey,
+        subkeys: Vec<PublicKey>,
+        content: InvoiceRequestContent,
+        tag: String,
+    ) -> Result<(NotificationStream<InvoiceResponse>, Vec<EventSendResult>), PortalSDKError> {
+        self.subscribe(self.invoice_request(recipient, subkeys, content), Some(tag))
+            .await
+    }
+
+    pub async fn request_invoice(
+        &self,
+        recipient: PublicKey,
+        subkeys: Vec<PublicKey>,
+        content: InvoiceRequestContent,
+    ) -> Result<Option<InvoiceResponse>, PortalSDKError> {
         let (mut rx, _outcomes) = self
-            .router
-            .add_and_subscribe(Box::new(MultiKeySenderAdapter::new_with_user(
-                recipient, subkeys, conv,
-            )))
+            .subscribe(self.invoice_request(recipient, subkeys, content), None)
             .await?;
 
         if let Ok(invoice_response) = rx.next().await.ok_or(PortalSDKError::Timeout)? {
@@ -280,11 +654,19 @@ impl PortalSDK {
         Ok(None)
     }
 
+    /// Signs a JWT with the key of the service
+    ///
+    /// When the service runs with a subkey, its proof is added to the claims so the token
+    /// verifies against the main key.
     pub fn issue_jwt(
         &self,
-        claims: portal::protocol::jwt::CustomClaims,
+        mut claims: portal::protocol::jwt::CustomClaims,
         duration: Duration,
     ) -> Result<String, PortalSDKError> {
+        if claims.subkey_proof.is_none() {
+            claims.subkey_proof = self.router.keypair().subkey_proof().cloned();
+        }
+
         let token =
             portal::protocol::jwt::encode(self.router.keypair().secret_key(), claims, duration)
                 .map_err(PortalSDKError::JwtError)?;
@@ -296,27 +678,67 @@ impl PortalSDK {
         public_key: PublicKey,
         token: &str,
     ) -> Result<portal::protocol::jwt::CustomClaims, PortalSDKError> {
-        let claims =
-            portal::protocol::jwt::decode(&public_key, token).map_err(PortalSDKError::JwtError)?;
+        self.verify_jwt_with_options(public_key, token, DecodeOptions::default())
+    }
+
+    /// Verifies a JWT and checks its claims against `options`
+    ///
+    /// Tokens whose `jti` is a revoked session (see [`PortalSDK::revoke_session`]) are rejected,
+    /// unless `options` brings its own revocation list.
+    pub fn verify_jwt_with_options(
+        &self,
+        public_key: PublicKey,
+        token: &str,
+        mut options: DecodeOptions,
+    ) -> Result<portal::protocol::jwt::CustomClaims, PortalSDKError> {
+        if options.revocation_list.is_none() {
+            options = options.revocation_list(self.revoked_sessions.clone());
+        }
+
+        let claims = portal::protocol::jwt::decode_with_options(&public_key, token, &options)
+            .map_err(PortalSDKError::JwtError)?;
         Ok(claims)
     }
 
-    pub async fn request_cashu(
+    fn cashu_request(
         &self,
         main_key: PublicKey,
         subkeys: Vec<PublicKey>,
         content: CashuRequestContent,
-    ) -> Result<Option<CashuResponseContent>, PortalSDKError> {
+    ) -> Result<MultiKeySenderAdapter<CashuRequestSenderConversation>, PortalSDKError> {
+        self.check_permission(&main_key, &Permission::CashuRequest)?;
+
         let conv = CashuRequestSenderConversation::new(
             self.router.keypair().public_key(),
             self.router.keypair().subkey_proof().cloned(),
             content,
         );
-        let (mut rx, _outcomes): (NotificationStream<CashuResponseContent>, _) = self
-            .router
-            .add_and_subscribe(Box::new(MultiKeySenderAdapter::new_with_user(
-                main_key, subkeys, conv,
-            )))
+        Ok(MultiKeySenderAdapter::new_with_user(main_key, subkeys, conv))
+    }
+
+    /// Like [`PortalSDK::request_cashu`], but the conversation is saved under `tag`
+    ///
+    /// Also returns the delivery outcome of the request events, see
+    /// [`PortalSDK::delivery_updates`] for what happens to the queued ones.
+    pub async fn request_cashu_resumable(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+        content: CashuRequestContent,
+        tag: String,
+    ) -> Result<(NotificationStream<CashuResponseContent>, Vec<EventSendResult>), PortalSDKError> {
+        self.subscribe(self.cashu_request(main_key, subkeys, content)?, Some(tag))
+            .await
+    }
+
+    pub async fn request_cashu(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+        content: CashuRequestContent,
+    ) -> Result<Option<CashuResponseContent>, PortalSDKError> {
+        let (mut rx, _outcomes) = self
+            .subscribe(self.cashu_request(main_key, subkeys, content)?, None)
             .await?;
 
         if let Ok(cashu_response) = rx.next().await.ok_or(PortalSDKError::Timeout)? {
@@ -325,6 +747,159 @@ impl PortalSDK {
         Ok(None)
     }
 
+    fn certificate_request(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+        content: CertificateRequestContent,
+    ) -> MultiKeySenderAdapter<CertificateRequestSenderConversation> {
+        let conv = CertificateRequestSenderConversation::new(
+            self.router.keypair().public_key(),
+            self.router.keypair().subkey_proof().cloned(),
+            content,
+        );
+        MultiKeySenderAdapter::new_with_user(main_key, subkeys, conv)
+    }
+
+    /// Like [`PortalSDK::request_certificates`], but the conversation is saved under `tag`
+    ///
+    /// Also returns the delivery outcome of the request events, see
+    /// [`PortalSDK::delivery_updates`] for what happens to the queued ones.
+    pub async fn request_certificates_resumable(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+        content: CertificateRequestContent,
+        tag: String,
+    ) -> Result<(NotificationStream<CertificateResponseContent>, Vec<EventSendResult>), PortalSDKError> {
+        self.subscribe(self.certificate_request(main_key, subkeys, content), Some(tag))
+            .await
+    }
+
+    pub async fn request_certificates(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+        content: CertificateRequestContent,
+    ) -> Result<CertificateResponseContent, PortalSDKError> {
+        let (mut rx, _outcomes) = self
+            .subscribe(self.certificate_request(main_key, subkeys, content), None)
+            .await?;
+
+        Ok(rx.next().await.ok_or(PortalSDKError::Timeout)??)
+    }
+
+    /// Returns a [`CertificateBuilder`] with this service as the issuer
+    pub fn certificate_builder(&self, subject: PublicKey) -> CertificateBuilder {
+        CertificateBuilder::new(self.router.keypair().public_key(), subject)
+    }
+
+    /// Validate and sign a certificate built with [`PortalSDK::certificate_builder`]
+    pub fn issue_certificate(
+        &self,
+        builder: CertificateBuilder,
+    ) -> Result<Certificate, PortalSDKError> {
+        if self.router.keypair().subkey_proof().is_some() {
+            return Err(PortalSDKError::MasterKeyRequired);
+        }
+
+        Ok(builder.sign(self.router.keypair().get_keys())?)
+    }
+
+    /// Send an issued certificate to its subject
+    pub async fn deliver_certificate(
+        &self,
+        main_key: PublicKey,
+        subkeys: Vec<PublicKey>,
+        certificate: &Certificate,
+    ) -> Result<(), PortalSDKError> {
+        let content = CertificateDeliveryContent {
+            certificate: serde_json::to_string(certificate)?,
+        };
+        let conv = CertificateDeliverySenderConversation::new(content);
+        self.router
+            .add_conversation(Box::new(MultiKeySenderAdapter::new_with_user(
+                main_key, subkeys, conv,
+            )))
+            .await?;
+        Ok(())
+    }
+
+    /// Sign and publish a revocation list for the certificates issued by this service
+    pub async fn publish_revocation_list(
+        &self,
+        mut list: RevocationList,
+    ) -> Result<(), PortalSDKError> {
+        if self.router.keypair().subkey_proof().is_some() {
+            return Err(PortalSDKError::MasterKeyRequired);
+        }
+
+        if list.signature.is_empty() {
+            list.sign(self.router.keypair().get_keys())
+                .map_err(|e| PortalSDKError::ProtocolError(e.to_string()))?;
+        }
+
+        let conv = RevocationListPublisherConversation::new(list);
+        self.router
+            .add_conversation(Box::new(OneShotSenderAdapter::new_with_user(
+                self.router.keypair().public_key(),
+                vec![],
+                conv,
+            )))
+            .await?;
+        Ok(())
+    }
+
+    /// Revokes subkeys of the service key, publishing their revocation
+    ///
+    /// Proofs of the revoked subkeys are rejected by everyone who saw the revocation. Requires
+    /// the SDK to run with the main key.
+    pub async fn revoke_subkeys(
+        &self,
+        subkeys: Vec<PublicKey>,
+        reason: Option<String>,
+    ) -> Result<(), PortalSDKError> {
+        if self.router.keypair().subkey_proof().is_some() {
+            return Err(PortalSDKError::MasterKeyRequired);
+        }
+
+        let main_key = self.router.keypair().public_key();
+        let revocations: Vec<_> = subkeys
+            .into_iter()
+            .map(|subkey| SubkeyRevocation::new(subkey, reason.clone()))
+            .collect();
+        for revocation in &revocations {
+            self.router
+                .revoked_subkeys()
+                .insert(main_key, revocation.clone());
+        }
+
+        let conv = SubkeyRevocationPublisherConversation::new(revocations);
+        self.router
+            .add_conversation(Box::new(OneShotSenderAdapter::new_with_user(
+                main_key,
+                vec![],
+                conv,
+            )))
+            .await?;
+        Ok(())
+    }
+
+    /// The revoked subkeys of `main_key` seen so far
+    pub fn revoked_subkeys(&self, main_key: &PublicKey) -> Vec<SubkeyRevocation> {
+        self.router.revoked_subkeys().list(main_key)
+    }
+
+    /// Listen for the revocation lists published by the given certificate issuers
+    pub async fn listen_revocation_lists(
+        &self,
+        issuers: Vec<PublicKey>,
+    ) -> Result<NotificationStream<RevocationList>, PortalSDKError> {
+        let conv = RevocationListListenerConversation::new(issuers);
+        let (event, _outcomes) = self.router.add_and_subscribe(Box::new(conv)).await?;
+        Ok(event)
+    }
+
     pub async fn send_cashu_direct(
         &self,
         main_key: PublicKey,
@@ -355,6 +930,37 @@ impl PortalSDK {
         Ok(())
     }
 
+    /// Subscribes to the delivery updates of queued events
+    ///
+    /// An update is sent when an event that no relay accepted is delivered to its first relay
+    /// ([`SendOutcome::Delivered`]), or when it's purged from the outbox before that
+    /// ([`SendOutcome::Dropped`]).
+    ///
+    /// [`SendOutcome::Delivered`]: portal::router::SendOutcome::Delivered
+    /// [`SendOutcome::Dropped`]: portal::router::SendOutcome::Dropped
+    pub fn delivery_updates(&self) -> broadcast::Receiver<EventSendResult> {
+        self.router.delivery_updates()
+    }
+
+    /// Lists the events that were not delivered to all their relays yet, oldest first
+    pub async fn list_outbox(&self) -> Result<Vec<OutboxEntry>, PortalSDKError> {
+        Ok(self.router.list_outbox().await?)
+    }
+
+    /// Retries delivering a queued event (or all of them if `event_id` is `None`) right away
+    ///
+    /// Returns the number of events that are now delivered to all their relays.
+    pub async fn retry_outbox(&self, event_id: Option<EventId>) -> Result<usize, PortalSDKError> {
+        Ok(self.router.retry_outbox(event_id).await?)
+    }
+
+    /// Drops a queued event (or all of them if `event_id` is `None`) without delivering it
+    ///
+    /// Returns the number of events removed.
+    pub async fn purge_outbox(&self, event_id: Option<EventId>) -> Result<usize, PortalSDKError> {
+        Ok(self.router.purge_outbox(event_id).await?)
+    }
+
     pub fn relay_pool(&self) -> Arc<RelayPool> {
         self.relay_pool.clone()
     }
@@ -380,9 +986,18 @@ pub enum PortalSDKError {
     #[error("Master key required")]
     MasterKeyRequired,
 
+    #[error("Session revoked")]
+    SessionRevoked,
+
+    #[error("Permission not granted by the user: {0}")]
+    PermissionDenied(String),
+
     #[error("JWT error: {0}")]
     JwtError(#[from] portal::protocol::jwt::JwtError),
 
+    #[error("Issuance error: {0}")]
+    Issuance(#[from] IssuanceError),
+
     #[error("Protocol error: {0}")]
     ProtocolError(String),
 }
@@ -1,18 +1,53 @@
 
 use axum::async_trait;
 use breez_sdk_spark::{
-    BreezSdk, ConnectRequest, GetInfoRequest, ListPaymentsRequest, Network, PaymentDetails, PaymentStatus, PaymentType,
-    PrepareSendPaymentRequest, ReceivePaymentMethod, ReceivePaymentRequest, SendPaymentMethod,
+    BreezSdk, ConnectRequest, EventListener, GetInfoRequest, ListPaymentsRequest, Network, Payment, PaymentDetails, PaymentStatus, PaymentType,
+    PrepareSendPaymentRequest, ReceivePaymentMethod, ReceivePaymentRequest, SdkEvent, SendPaymentMethod,
     SendPaymentRequest, Seed, connect, default_config
 };
+use tokio::sync::broadcast;
 
 use tracing::info;
 
-use crate::{PortalWallet, PortalWalletError, Result};
+use crate::{
+    InvoiceRequest, PaymentDirection, PaymentState, PaymentsQuery, PortalWallet, PortalWalletError,
+    Result, SETTLEMENTS_CAPACITY, Settlement, WalletCapabilities, WalletPayment, max_fee_msat,
+};
+
+/// Expiry of the invoices created by the wallet, unless asked otherwise
+const INVOICE_EXPIRY_SECS: u32 = 3600;
 
 /// Breez Spark Wallet implementation
 pub struct BreezSparkWallet {
     sdk: BreezSdk,
+    /// Kept to hand out new receivers, the sender lives in the SDK event listener
+    settlements: broadcast::Receiver<Settlement>,
+}
+
+/// Forwards the received Lightning payments reported by the SDK
+struct SettlementListener {
+    sender: broadcast::Sender<Settlement>,
+}
+
+#[async_trait]
+impl EventListener for SettlementListener {
+    async fn on_event(&self, event: SdkEvent) {
+        if let SdkEvent::PaymentSucceeded { payment } = event
+            && payment.payment_type == PaymentType::Receive
+            && let Some(PaymentDetails::Lightning {
+                invoice,
+                preimage,
+                payment_hash,
+                ..
+            }) = payment.details
+        {
+            let _ = self.sender.send(Settlement {
+                payment_hash,
+                invoice: Some(invoice),
+                preimage,
+            });
+        }
+    }
 }
 
 impl BreezSparkWallet {
@@ -32,23 +67,78 @@ impl BreezSparkWallet {
         })
         .await?;
 
-        Ok(Self { sdk })
+        let (sender, settlements) = broadcast::channel(SETTLEMENTS_CAPACITY);
+        sdk.add_event_listener(Box::new(SettlementListener { sender }))
+            .await;
+
+        Ok(Self { sdk, settlements })
+    }
+}
+
+/// Payments that aren't Lightning payments (like Spark transfers) have no invoice, their
+/// payment ID stands for the payment hash
+fn wallet_payment(payment: Payment) -> WalletPayment {
+    let direction = match payment.payment_type {
+        PaymentType::Receive => PaymentDirection::Incoming,
+        PaymentType::Send => PaymentDirection::Outgoing,
+    };
+    let state = match payment.status {
+        PaymentStatus::Completed => PaymentState::Settled,
+        PaymentStatus::Pending => PaymentState::Pending,
+        PaymentStatus::Failed => PaymentState::Failed,
+    };
+
+    let mut wallet_payment = match &payment.details {
+        Some(PaymentDetails::Lightning {
+            invoice,
+            payment_hash,
+            preimage,
+            description,
+            ..
+        }) => {
+            let mut wallet_payment = WalletPayment::from_invoice(direction, invoice)
+                .unwrap_or_else(|_| WalletPayment {
+                    invoice: Some(invoice.clone()),
+                    ..WalletPayment::new(direction, payment_hash.clone(), payment.timestamp)
+                });
+            wallet_payment.preimage = preimage.clone();
+            if description.is_some() {
+                wallet_payment.description = description.clone();
+            }
+            wallet_payment
+        }
+        _ => WalletPayment::new(direction, payment.id.clone(), payment.timestamp),
+    };
+
+    wallet_payment.state = state;
+    wallet_payment.amount_msat = payment.amount as u64 * 1000;
+    wallet_payment.fees_paid_msat = payment.fees as u64 * 1000;
+    wallet_payment.created_at = payment.timestamp;
+    if state == PaymentState::Settled {
+        wallet_payment.settled_at = Some(payment.timestamp);
     }
+    wallet_payment.check_expiry()
 }
 
 #[async_trait]
 impl PortalWallet for BreezSparkWallet {
-    async fn make_invoice(&self, amount_msat: u64, description: Option<String>) -> Result<String> {
-        let description = description.unwrap_or("Portal invoice".into());
-        // Optionally set the expiry duration in seconds
-        let optional_expiry_secs = Some(3600_u32);
+    async fn create_invoice(&self, request: InvoiceRequest) -> Result<String> {
+        if request.description_hash.is_some() {
+            return Err(PortalWalletError::Unsupported("description hashes".into()));
+        }
+
+        let description = request.description.unwrap_or("Portal invoice".into());
+        let expiry_secs = request
+            .expiry_secs
+            .map(|expiry_secs| u32::try_from(expiry_secs).unwrap_or(u32::MAX))
+            .unwrap_or(INVOICE_EXPIRY_SECS);
         let receive_response = self
             .sdk
             .receive_payment(ReceivePaymentRequest {
                 payment_method: ReceivePaymentMethod::Bolt11Invoice {
                     description,
-                    amount_sats: Some(amount_msat / 1000),
-                    expiry_secs: optional_expiry_secs,
+                    amount_sats: Some(request.amount_msat / 1000),
+                    expiry_secs: Some(expiry_secs),
                 },
             })
             .await?;
@@ -69,13 +159,7 @@ impl PortalWallet for BreezSparkWallet {
 
         let mut fee_sats = 0;
 
-        // Check fee acceptability before sending.
-        // Policy:
-        //   - Below 500 sats: accept any fee (small payments are exempt).
-        //   - 500 sats or above: reject if fees exceed max(1% of amount, 1000 sats).
-        //   - Zero-amount invoices: reject if fees exceed 1000 sats.
-        // This guards against unexpectedly high fees in programmatic payments where
-        // there is no interactive user to approve.
+        // Check fee acceptability before sending, see `max_fee_msat`
         if let SendPaymentMethod::Bolt11Invoice {
             invoice_details,
             spark_transfer_fee_sats,
@@ -88,37 +172,18 @@ impl PortalWallet for BreezSparkWallet {
             info!("Lightning fees: {lightning_fee_sats} sats");
             info!("Spark transfer fees: {spark_fee} sats");
 
-            // Use invoice amount (in msats) to compute a percentage-based cap.
-            if let Some(amount_msat) = invoice_details.amount_msat {
-                let amount_sats = amount_msat / 1000;
-
-                if amount_sats < 500 {
-                    // Small payments (below 500 sats): accept any fee.
-                    info!("Total fees: {fee_sats} sats (small payment {amount_sats} sats — fee check skipped)");
-                } else {
-                    // 500 sats or above: max acceptable fee = max(1% of amount, 1000 sats).
-                    let one_percent = amount_sats / 100;
-                    let max_fee = std::cmp::max(one_percent, 1000);
-
-                    info!("Total fees: {fee_sats} sats (max allowed: {max_fee} sats, amount: {amount_sats} sats)");
+            match max_fee_msat(invoice_details.amount_msat) {
+                Some(max_fee_msat) => {
+                    let max_fee = max_fee_msat / 1000;
+                    info!("Total fees: {fee_sats} sats (max allowed: {max_fee} sats)");
 
                     if fee_sats > max_fee {
                         return Err(PortalWalletError::FeeTooHigh(format!(
-                            "{fee_sats} sats exceeds maximum of {max_fee} sats \
-                             (payment amount: {amount_sats} sats)"
+                            "{fee_sats} sats exceeds maximum of {max_fee} sats"
                         )));
                     }
                 }
-            } else {
-                // No amount in invoice (zero-amount invoice); apply absolute cap of 1000 sats.
-                info!("Total fees: {fee_sats} sats (max allowed: 1000 sats, zero-amount invoice)");
-
-                if fee_sats > 1000 {
-                    return Err(PortalWalletError::FeeTooHigh(format!(
-                        "{fee_sats} sats exceeds absolute maximum of 1000 sats \
-                         (zero-amount invoice)"
-                    )));
-                }
+                None => info!("Total fees: {fee_sats} sats (small payment — fee check skipped)"),
             }
         }
 
@@ -204,4 +269,78 @@ impl PortalWallet for BreezSparkWallet {
         .await?;
         Ok(balance.balance_sats * 1000)
     }
+
+    async fn subscribe_settlements(&self) -> Result<Option<broadcast::Receiver<Settlement>>> {
+        Ok(Some(self.settlements.resubscribe()))
+    }
+
+    async fn list_payments(&self, query: PaymentsQuery) -> Result<Vec<WalletPayment>> {
+        let type_filter = query.direction.map(|direction| match direction {
+            PaymentDirection::Incoming => vec![PaymentType::Receive],
+            PaymentDirection::Outgoing => vec![PaymentType::Send],
+        });
+        let response = self
+            .sdk
+            .list_payments(ListPaymentsRequest {
+                type_filter,
+                status_filter: None,
+                asset_filter: None,
+                from_timestamp: query.from,
+                to_timestamp: query.until,
+                limit: query.limit.try_into().ok(),
+                offset: query.offset.try_into().ok(),
+                sort_ascending: Some(false),
+                payment_details_filter: None,
+            })
+            .await?;
+
+        Ok(response.payments.into_iter().map(wallet_payment).collect())
+    }
+
+    async fn lookup_invoice(&self, payment_hash: &str) -> Result<WalletPayment> {
+        let batch_size = 100;
+        let mut offset = 0;
+
+        loop {
+            let response = self
+                .sdk
+                .list_payments(ListPaymentsRequest {
+                    type_filter: None,
+                    status_filter: None,
+                    asset_filter: None,
+                    from_timestamp: None,
+                    to_timestamp: None,
+                    limit: Some(batch_size),
+                    offset: Some(offset),
+                    sort_ascending: Some(false),
+                    payment_details_filter: None,
+                })
+                .await?;
+
+            let count = response.payments.len();
+            if let Some(payment) = response.payments.into_iter().find(|payment| {
+                matches!(
+                    &payment.details,
+                    Some(PaymentDetails::Lightning { payment_hash: hash, .. }) if hash == payment_hash
+                )
+            }) {
+                return Ok(wallet_payment(payment));
+            }
+
+            if count < batch_size as usize {
+                return Err(PortalWalletError::InvoiceNotFound(payment_hash.to_string()));
+            }
+            offset += batch_size;
+        }
+    }
+
+    async fn capabilities(&self) -> Result<WalletCapabilities> {
+        Ok(WalletCapabilities {
+            list_payments: true,
+            lookup_invoice: true,
+            invoice_expiry: true,
+            description_hash: false,
+            push_settlements: true,
+        })
+    }
 }
@@ -0,0 +1,346 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    PaymentDirection, PaymentState, PaymentsQuery, PortalWallet, PortalWalletError, Result,
+    WalletCapabilities, WalletPayment, max_fee_msat,
+    rest::{RestClient, parse_invoice},
+};
+
+/// Expiry of the invoices created by the wallet unless asked otherwise, in seconds
+const INVOICE_EXPIRY_SECS: u64 = 3600;
+
+/// Core Lightning wallet, over the `clnrest` API (port 3010 by default)
+///
+/// Authenticates with a rune allowed to call `invoice`, `listinvoices`, `listfunds` and `pay`.
+pub struct ClnWallet {
+    client: RestClient,
+    /// Makes the invoice labels unique, together with the creation time
+    label_counter: AtomicU64,
+}
+
+impl ClnWallet {
+    /// `tls_cert_pem` is the certificate of `clnrest` (`clnrest-certs`), needed unless it is
+    /// behind a certificate trusted by the system
+    pub fn new(url: String, rune: String, tls_cert_pem: Option<Vec<u8>>) -> Result<Self> {
+        Ok(Self {
+            client: RestClient::new(url, ("Rune", rune), tls_cert_pem)?,
+            label_counter: AtomicU64::new(0),
+        })
+    }
+
+    fn next_label(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let counter = self.label_counter.fetch_add(1, Ordering::Relaxed);
+        format!("portal-{now}-{counter}")
+    }
+}
+
+#[derive(Serialize)]
+struct InvoiceRequest {
+    amount_msat: u64,
+    label: String,
+    description: String,
+    expiry: u64,
+}
+
+#[derive(Deserialize)]
+struct InvoiceResponse {
+    bolt11: String,
+}
+
+#[derive(Serialize, Default)]
+struct ListInvoicesRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    invstring: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payment_hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListInvoicesResponse {
+    invoices: Vec<Invoice>,
+}
+
+#[derive(Deserialize)]
+struct Invoice {
+    status: String,
+    payment_hash: String,
+    /// BOLT12 invoices have none
+    bolt11: Option<String>,
+    payment_preimage: Option<String>,
+    amount_received_msat: Option<u64>,
+    paid_at: Option<u64>,
+}
+
+#[derive(Serialize, Default)]
+struct ListPaysRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payment_hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ListPaysResponse {
+    pays: Vec<Pay>,
+}
+
+#[derive(Deserialize)]
+struct Pay {
+    status: String,
+    payment_hash: String,
+    bolt11: Option<String>,
+    preimage: Option<String>,
+    amount_msat: Option<u64>,
+    amount_sent_msat: Option<u64>,
+    created_at: u64,
+    completed_at: Option<u64>,
+}
+
+impl Invoice {
+    fn into_wallet_payment(self) -> WalletPayment {
+        let mut payment = self
+            .bolt11
+            .as_deref()
+            .and_then(|bolt11| WalletPayment::from_invoice(PaymentDirection::Incoming, bolt11).ok())
+            .unwrap_or_else(|| {
+                WalletPayment::new(PaymentDirection::Incoming, self.payment_hash, 0)
+            });
+
+        match self.status.as_str() {
+            "paid" => {
+                payment.state = PaymentState::Settled;
+                payment.preimage = self.payment_preimage;
+                payment.amount_msat = self.amount_received_msat.unwrap_or(payment.amount_msat);
+                payment.settled_at = self.paid_at;
+            }
+            "expired" => payment.state = PaymentState::Expired,
+            _ => {}
+        }
+        payment
+    }
+}
+
+impl Pay {
+    fn into_wallet_payment(self) -> WalletPayment {
+        let mut payment = self
+            .bolt11
+            .as_deref()
+            .and_then(|bolt11| WalletPayment::from_invoice(PaymentDirection::Outgoing, bolt11).ok())
+            .unwrap_or_else(|| {
+                WalletPayment::new(
+                    PaymentDirection::Outgoing,
+                    self.payment_hash,
+                    self.created_at,
+                )
+            });
+
+        payment.state = match self.status.as_str() {
+            "complete" => PaymentState::Settled,
+            "failed" => PaymentState::Failed,
+            _ => PaymentState::Pending,
+        };
+        if payment.state == PaymentState::Settled {
+            payment.preimage = self.preimage;
+            payment.settled_at = self.completed_at;
+        }
+        if let Some(amount_msat) = self.amount_msat {
+            payment.amount_msat = amount_msat;
+            payment.fees_paid_msat = self
+                .amount_sent_msat
+                .map(|sent| sent.saturating_sub(amount_msat))
+                .unwrap_or(0);
+        }
+        payment.created_at = self.created_at;
+        payment
+    }
+}
+
+#[derive(Serialize)]
+struct Empty {}
+
+#[derive(Deserialize)]
+struct ListFundsResponse {
+    channels: Vec<Channel>,
+}
+
+#[derive(Deserialize)]
+struct Channel {
+    state: String,
+    our_amount_msat: u64,
+}
+
+#[derive(Serialize)]
+struct PayRequest {
+    bolt11: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maxfee: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct PayResponse {
+    status: String,
+    payment_preimage: String,
+    amount_msat: u64,
+    amount_sent_msat: u64,
+}
+
+#[async_trait]
+impl PortalWallet for ClnWallet {
+    /// Core Lightning can only commit to the hash of the description it is given, arbitrary
+    /// description hashes are not supported
+    async fn create_invoice(&self, request: crate::InvoiceRequest) -> Result<String> {
+        if request.description_hash.is_some() {
+            return Err(PortalWalletError::Unsupported("description hashes".into()));
+        }
+
+        let response: InvoiceResponse = self
+            .client
+            .post(
+                "/v1/invoice",
+                &InvoiceRequest {
+                    amount_msat: request.amount_msat,
+                    label: self.next_label(),
+                    description: request.description.unwrap_or_default(),
+                    expiry: request.expiry_secs.unwrap_or(INVOICE_EXPIRY_SECS),
+                },
+            )
+            .await?;
+
+        Ok(response.bolt11)
+    }
+
+    async fn is_invoice_paid(&self, invoice: String) -> Result<(bool, Option<String>)> {
+        let response: ListInvoicesResponse = self
+            .client
+            .post(
+                "/v1/listinvoices",
+                &ListInvoicesRequest {
+                    invstring: Some(invoice),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        match response.invoices.into_iter().next() {
+            Some(invoice) if invoice.status == "paid" => Ok((true, invoice.payment_preimage)),
+            _ => Ok((false, None)),
+        }
+    }
+
+    async fn get_balance(&self) -> Result<u64> {
+        let response: ListFundsResponse = self.client.post("/v1/listfunds", &Empty {}).await?;
+
+        // Only the channels that can be used to pay right away
+        let balance = response
+            .channels
+            .iter()
+            .filter(|channel| channel.state == "CHANNELD_NORMAL")
+            .map(|channel| channel.our_amount_msat)
+            .sum();
+        Ok(balance)
+    }
+
+    async fn pay_invoice(&self, invoice: String) -> Result<(String, u64)> {
+        let amount_msat = parse_invoice(&invoice)?.amount_milli_satoshis();
+        let response: PayResponse = self
+            .client
+            .post(
+                "/v1/pay",
+                &PayRequest {
+                    bolt11: invoice,
+                    maxfee: max_fee_msat(amount_msat),
+                },
+            )
+            .await?;
+
+        if response.status != "complete" {
+            return Err(PortalWalletError::PaymentFailed(format!(
+                "Payment is {}",
+                response.status
+            )));
+        }
+
+        let fees_paid_msat = response
+            .amount_sent_msat
+            .saturating_sub(response.amount_msat);
+        Ok((response.payment_preimage, fees_paid_msat))
+    }
+
+    /// `listinvoices` and `listpays` are read in full and paginated here
+    async fn list_payments(&self, query: PaymentsQuery) -> Result<Vec<WalletPayment>> {
+        let mut payments = Vec::new();
+
+        if query.direction != Some(PaymentDirection::Outgoing) {
+            let response: ListInvoicesResponse = self
+                .client
+                .post("/v1/listinvoices", &ListInvoicesRequest::default())
+                .await?;
+            payments.extend(
+                response
+                    .invoices
+                    .into_iter()
+                    .map(Invoice::into_wallet_payment),
+            );
+        }
+
+        if query.direction != Some(PaymentDirection::Incoming) {
+            let response: ListPaysResponse = self
+                .client
+                .post("/v1/listpays", &ListPaysRequest::default())
+                .await?;
+            payments.extend(response.pays.into_iter().map(Pay::into_wallet_payment));
+        }
+
+        Ok(query.apply(payments))
+    }
+
+    async fn lookup_invoice(&self, payment_hash: &str) -> Result<WalletPayment> {
+        let response: ListInvoicesResponse = self
+            .client
+            .post(
+                "/v1/listinvoices",
+                &ListInvoicesRequest {
+                    payment_hash: Some(payment_hash.to_string()),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        if let Some(invoice) = response.invoices.into_iter().next() {
+            return Ok(invoice.into_wallet_payment());
+        }
+
+        let response: ListPaysResponse = self
+            .client
+            .post(
+                "/v1/listpays",
+                &ListPaysRequest {
+                    payment_hash: Some(payment_hash.to_string()),
+                },
+            )
+            .await?;
+        response
+            .pays
+            .into_iter()
+            .next()
+            .map(Pay::into_wallet_payment)
+            .ok_or_else(|| PortalWalletError::InvoiceNotFound(payment_hash.to_string()))
+    }
+
+    async fn capabilities(&self) -> Result<WalletCapabilities> {
+        Ok(WalletCapabilities {
+            list_payments: true,
+            lookup_invoice: true,
+            invoice_expiry: true,
+            description_hash: false,
+            push_settlements: false,
+        })
+    }
+}
@@ -1,21 +1,263 @@
 mod breez;
+mod cln;
+mod lnbits;
+mod lnd;
+mod mock;
 mod nwc;
+mod rest;
+
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use axum::async_trait;
+use lightning_invoice::Bolt11InvoiceDescriptionRef;
+use tokio::sync::broadcast;
 
 pub use breez::BreezSparkWallet;
+pub use cln::ClnWallet;
+pub use lnbits::LnbitsWallet;
+pub use lnd::LndWallet;
+pub use mock::{MockInvoice, MockInvoiceStatus, MockWallet, MockWalletConfig};
 pub use nwc::NwcWallet;
 
 /// Portal Wallet trait
 #[async_trait]
 pub trait PortalWallet: Send + Sync {
     /// Create an invoice for the given amount (in millisatoshis).
-    async fn make_invoice(&self, amount_msat: u64, description: Option<String>) -> Result<String>;
+    async fn make_invoice(&self, amount_msat: u64, description: Option<String>) -> Result<String> {
+        self.create_invoice(InvoiceRequest {
+            amount_msat,
+            description,
+            ..Default::default()
+        })
+        .await
+    }
+    /// Create an invoice with a description hash or an expiry, when the backend supports them
+    /// (see [`WalletCapabilities`]).
+    async fn create_invoice(&self, request: InvoiceRequest) -> Result<String>;
     async fn is_invoice_paid(&self, invoice: String) -> Result<(bool, Option<String>)>;
     /// Get balance (msat)
     async fn get_balance(&self) -> Result<u64>;
     /// Pay invoice, returns (preimage, fees_paid_msat)
     async fn pay_invoice(&self, invoice: String) -> Result<(String, u64)>;
+    /// Subscribe to the incoming payments settled from now on, pushed by the wallet.
+    ///
+    /// `None` if the wallet can't push them: `is_invoice_paid` has to be polled instead.
+    async fn subscribe_settlements(&self) -> Result<Option<broadcast::Receiver<Settlement>>> {
+        Ok(None)
+    }
+    /// Incoming and outgoing payments of the wallet, newest first
+    async fn list_payments(&self, _query: PaymentsQuery) -> Result<Vec<WalletPayment>> {
+        Err(PortalWalletError::Unsupported("listing payments".into()))
+    }
+    /// Look up a payment of the wallet by payment hash
+    ///
+    /// [`PortalWalletError::InvoiceNotFound`] if the wallet doesn't know it.
+    async fn lookup_invoice(&self, _payment_hash: &str) -> Result<WalletPayment> {
+        Err(PortalWalletError::Unsupported("looking up invoices".into()))
+    }
+    /// What the wallet supports on top of paying and creating invoices
+    async fn capabilities(&self) -> Result<WalletCapabilities>;
+}
+
+/// An invoice to create with [`PortalWallet::create_invoice`]
+#[derive(Debug, Clone, Default)]
+pub struct InvoiceRequest {
+    pub amount_msat: u64,
+    pub description: Option<String>,
+    /// Hex encoded SHA-256 of a description committed to instead of `description`, e.g. for
+    /// LNURL-pay
+    pub description_hash: Option<String>,
+    /// How long the invoice can be paid for, the backend's default if `None`
+    pub expiry_secs: Option<u64>,
+}
+
+/// Direction of a [`WalletPayment`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentDirection {
+    /// Paid to the wallet
+    Incoming,
+    /// Paid by the wallet
+    Outgoing,
+}
+
+/// State of a [`WalletPayment`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentState {
+    Pending,
+    Settled,
+    Expired,
+    Failed,
+}
+
+/// A payment of the wallet, as reported by [`PortalWallet::list_payments`] and
+/// [`PortalWallet::lookup_invoice`]
+///
+/// Timestamps are in seconds since the Unix epoch. The fields a backend doesn't report are
+/// taken from the invoice when possible.
+#[derive(Debug, Clone)]
+pub struct WalletPayment {
+    pub direction: PaymentDirection,
+    pub state: PaymentState,
+    pub payment_hash: String,
+    pub invoice: Option<String>,
+    pub preimage: Option<String>,
+    pub amount_msat: u64,
+    pub fees_paid_msat: u64,
+    pub description: Option<String>,
+    pub description_hash: Option<String>,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub settled_at: Option<u64>,
+}
+
+impl WalletPayment {
+    /// A pending payment, for the backends that don't report its invoice
+    pub(crate) fn new(direction: PaymentDirection, payment_hash: String, created_at: u64) -> Self {
+        Self {
+            direction,
+            state: PaymentState::Pending,
+            payment_hash,
+            invoice: None,
+            preimage: None,
+            amount_msat: 0,
+            fees_paid_msat: 0,
+            description: None,
+            description_hash: None,
+            created_at,
+            expires_at: None,
+            settled_at: None,
+        }
+    }
+
+    /// A pending payment with the details of a BOLT11 invoice
+    pub(crate) fn from_invoice(direction: PaymentDirection, invoice: &str) -> Result<Self> {
+        let parsed = rest::parse_invoice(invoice)?;
+        let (description, description_hash) = match parsed.description() {
+            Bolt11InvoiceDescriptionRef::Direct(description) => {
+                (Some(description.to_string()), None)
+            }
+            Bolt11InvoiceDescriptionRef::Hash(hash) => (None, Some(hash.0.to_string())),
+        };
+
+        Ok(Self {
+            direction,
+            state: PaymentState::Pending,
+            payment_hash: parsed.payment_hash().to_string(),
+            invoice: Some(invoice.to_string()),
+            preimage: None,
+            amount_msat: parsed.amount_milli_satoshis().unwrap_or(0),
+            fees_paid_msat: 0,
+            description,
+            description_hash,
+            created_at: parsed.duration_since_epoch().as_secs(),
+            expires_at: parsed.expires_at().map(|expires_at| expires_at.as_secs()),
+            settled_at: None,
+        })
+    }
+
+    /// Pending payments past their expiry are reported as expired
+    pub(crate) fn check_expiry(mut self) -> Self {
+        if self.state == PaymentState::Pending
+            && self.expires_at.is_some_and(|expires_at| now() > expires_at)
+        {
+            self.state = PaymentState::Expired;
+        }
+        self
+    }
+}
+
+/// Filters and page of [`PortalWallet::list_payments`]
+#[derive(Debug, Clone)]
+pub struct PaymentsQuery {
+    /// Both directions if `None`
+    pub direction: Option<PaymentDirection>,
+    /// Only the payments created at or after this time
+    pub from: Option<u64>,
+    /// Only the payments created at or before this time
+    pub until: Option<u64>,
+    /// Number of matching payments to skip, newest first
+    pub offset: u64,
+    pub limit: u64,
+}
+
+impl Default for PaymentsQuery {
+    fn default() -> Self {
+        Self {
+            direction: None,
+            from: None,
+            until: None,
+            offset: 0,
+            limit: 100,
+        }
+    }
+}
+
+impl PaymentsQuery {
+    pub(crate) fn matches(&self, payment: &WalletPayment) -> bool {
+        self.direction
+            .is_none_or(|direction| direction == payment.direction)
+            && self.from.is_none_or(|from| payment.created_at >= from)
+            && self.until.is_none_or(|until| payment.created_at <= until)
+    }
+
+    /// Filter, sort and paginate the payments of a backend that can't do it itself
+    pub(crate) fn apply(&self, mut payments: Vec<WalletPayment>) -> Vec<WalletPayment> {
+        payments.retain(|payment| self.matches(payment));
+        payments.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        payments
+            .into_iter()
+            .skip(self.offset as usize)
+            .take(self.limit as usize)
+            .collect()
+    }
+}
+
+/// What a wallet supports, see [`PortalWallet::capabilities`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletCapabilities {
+    /// [`PortalWallet::list_payments`]
+    pub list_payments: bool,
+    /// [`PortalWallet::lookup_invoice`]
+    pub lookup_invoice: bool,
+    /// [`InvoiceRequest::expiry_secs`]
+    pub invoice_expiry: bool,
+    /// [`InvoiceRequest::description_hash`]
+    pub description_hash: bool,
+    /// [`PortalWallet::subscribe_settlements`]
+    pub push_settlements: bool,
+}
+
+/// An incoming payment settled in the wallet
+#[derive(Debug, Clone)]
+pub struct Settlement {
+    pub payment_hash: String,
+    pub invoice: Option<String>,
+    pub preimage: Option<String>,
+}
+
+/// Settlements buffered for each subscriber of [`PortalWallet::subscribe_settlements`]
+pub(crate) const SETTLEMENTS_CAPACITY: usize = 1024;
+
+/// Maximum fee accepted to pay an invoice of `amount_msat` (in millisatoshis)
+///
+/// Payments happen without an interactive user to approve the fees, so:
+///   - below 500 sats, any fee is accepted (`None`)
+///   - from 500 sats, fees are capped at max(1% of the amount, 1000 sats)
+///   - zero-amount invoices are capped at 1000 sats
+pub fn max_fee_msat(amount_msat: Option<u64>) -> Option<u64> {
+    match amount_msat {
+        Some(amount_msat) if amount_msat / 1000 < 500 => None,
+        Some(amount_msat) => Some(std::cmp::max(amount_msat / 1000 / 100, 1000) * 1000),
+        None => Some(1000 * 1000),
+    }
+}
+
+/// Current time, in seconds since the Unix epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
 }
 
 /// Result type for Portal Wallet operations
@@ -32,6 +274,24 @@ pub enum PortalWalletError {
     BreezError(breez_sdk_spark::SdkError),
     #[error("Fee too high: {0}")]
     FeeTooHigh(String),
+    #[error("HTTP error: {0}")]
+    HttpError(reqwest::Error),
+    #[error("Backend error ({status}): {message}")]
+    Backend { status: u16, message: String },
+    #[error("Invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("Invalid invoice: {0}")]
+    InvalidInvoice(String),
+    #[error("Payment failed: {0}")]
+    PaymentFailed(String),
+    #[error("Invoice not found: {0}")]
+    InvoiceNotFound(String),
+    #[error("Invoice expired: {0}")]
+    InvoiceExpired(String),
+    #[error("Database error: {0}")]
+    DatabaseError(rusqlite::Error),
+    #[error("Not supported by the wallet: {0}")]
+    Unsupported(String),
 }
 
 impl From<portal::nostr::nips::nip47::Error> for PortalWalletError {
@@ -51,3 +311,21 @@ impl From<breez_sdk_spark::SdkError> for PortalWalletError {
         PortalWalletError::BreezError(error)
     }
 }
+
+impl From<reqwest::Error> for PortalWalletError {
+    fn from(error: reqwest::Error) -> Self {
+        PortalWalletError::HttpError(error)
+    }
+}
+
+impl From<serde_json::Error> for PortalWalletError {
+    fn from(error: serde_json::Error) -> Self {
+        PortalWalletError::InvalidResponse(error.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for PortalWalletError {
+    fn from(error: rusqlite::Error) -> Self {
+        PortalWalletError::DatabaseError(error)
+    }
+}
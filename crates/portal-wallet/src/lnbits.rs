@@ -0,0 +1,242 @@
+use axum::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    InvoiceRequest, PaymentDirection, PaymentState, PaymentsQuery, PortalWallet, PortalWalletError,
+    Result, WalletCapabilities, WalletPayment,
+    rest::{RestClient, parse_invoice},
+};
+
+/// Expiry of the invoices created by the wallet unless asked otherwise, in seconds
+const INVOICE_EXPIRY_SECS: u64 = 3600;
+/// Payments read at once when listing them
+const LIST_PAGE_SIZE: u64 = 100;
+
+/// LNbits wallet, over the LNbits API
+///
+/// Authenticates with the admin key of the wallet, the invoice key can't pay invoices. LNbits
+/// applies its own fee reserve to the payments.
+pub struct LnbitsWallet {
+    client: RestClient,
+}
+
+impl LnbitsWallet {
+    pub fn new(url: String, admin_key: String) -> Result<Self> {
+        Ok(Self {
+            client: RestClient::new(url, ("X-Api-Key", admin_key), None)?,
+        })
+    }
+
+    async fn payment(&self, payment_hash: &str) -> Result<Payment> {
+        self.client
+            .get(&format!("/api/v1/payments/{payment_hash}"))
+            .await
+    }
+}
+
+#[derive(Serialize)]
+struct CreateInvoiceRequest {
+    out: bool,
+    /// In sats
+    amount: u64,
+    memo: String,
+    expiry: u64,
+    /// Hex
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description_hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CreateInvoiceResponse {
+    /// Renamed `bolt11` by LNbits 1.0, which still sends both
+    #[serde(alias = "bolt11")]
+    payment_request: String,
+}
+
+#[derive(Serialize)]
+struct PayInvoiceRequest {
+    out: bool,
+    bolt11: String,
+}
+
+#[derive(Deserialize)]
+struct PayInvoiceResponse {
+    payment_hash: String,
+}
+
+#[derive(Deserialize)]
+struct Payment {
+    paid: bool,
+    preimage: Option<String>,
+    details: Option<PaymentDetails>,
+}
+
+/// A payment of the wallet, as listed by `/api/v1/payments`
+#[derive(Deserialize)]
+struct PaymentDetails {
+    #[serde(default)]
+    payment_hash: String,
+    #[serde(default)]
+    bolt11: String,
+    preimage: Option<String>,
+    /// In msat, negative for outgoing payments
+    #[serde(default)]
+    amount: i64,
+    /// In msat, negative for outgoing payments
+    #[serde(default)]
+    fee: i64,
+    /// `success`, `pending` or `failed` since LNbits 1.0, only `pending` before
+    status: Option<String>,
+    #[serde(default)]
+    pending: bool,
+}
+
+impl PaymentDetails {
+    fn into_wallet_payment(self) -> WalletPayment {
+        let direction = if self.amount < 0 {
+            PaymentDirection::Outgoing
+        } else {
+            PaymentDirection::Incoming
+        };
+        let mut payment = WalletPayment::from_invoice(direction, &self.bolt11)
+            .unwrap_or_else(|_| WalletPayment::new(direction, self.payment_hash, 0));
+
+        payment.state = match (self.status.as_deref(), self.pending) {
+            (Some("success"), _) | (None, false) => PaymentState::Settled,
+            (Some("failed"), _) => PaymentState::Failed,
+            _ => PaymentState::Pending,
+        };
+        if payment.state == PaymentState::Settled {
+            payment.preimage = self.preimage;
+        }
+        payment.amount_msat = self.amount.unsigned_abs();
+        payment.fees_paid_msat = self.fee.unsigned_abs();
+        payment.check_expiry()
+    }
+}
+
+#[derive(Deserialize)]
+struct Wallet {
+    /// In msat
+    balance: u64,
+}
+
+#[async_trait]
+impl PortalWallet for LnbitsWallet {
+    async fn create_invoice(&self, request: InvoiceRequest) -> Result<String> {
+        let response: CreateInvoiceResponse = self
+            .client
+            .post(
+                "/api/v1/payments",
+                &CreateInvoiceRequest {
+                    out: false,
+                    amount: request.amount_msat / 1000,
+                    memo: request.description.unwrap_or_default(),
+                    expiry: request.expiry_secs.unwrap_or(INVOICE_EXPIRY_SECS),
+                    description_hash: request.description_hash,
+                },
+            )
+            .await?;
+
+        Ok(response.payment_request)
+    }
+
+    async fn is_invoice_paid(&self, invoice: String) -> Result<(bool, Option<String>)> {
+        let payment_hash = parse_invoice(&invoice)?.payment_hash().to_string();
+        let payment = self.payment(&payment_hash).await?;
+
+        if !payment.paid {
+            return Ok((false, None));
+        }
+        Ok((true, payment.preimage))
+    }
+
+    async fn get_balance(&self) -> Result<u64> {
+        let wallet: Wallet = self.client.get("/api/v1/wallet").await?;
+        Ok(wallet.balance)
+    }
+
+    async fn pay_invoice(&self, invoice: String) -> Result<(String, u64)> {
+        let response: PayInvoiceResponse = self
+            .client
+            .post(
+                "/api/v1/payments",
+                &PayInvoiceRequest {
+                    out: true,
+                    bolt11: invoice,
+                },
+            )
+            .await?;
+
+        // The payment call only returns once the payment is done
+        let payment = self.payment(&response.payment_hash).await?;
+        if !payment.paid {
+            return Err(PortalWalletError::PaymentFailed(format!(
+                "Payment {} is not settled",
+                response.payment_hash
+            )));
+        }
+
+        let fees_paid_msat = payment
+            .details
+            .map(|details| details.fee.unsigned_abs())
+            .unwrap_or(0);
+        Ok((payment.preimage.unwrap_or_default(), fees_paid_msat))
+    }
+
+    /// LNbits pages the payments of both directions together, so pages are read until the
+    /// requested one is complete
+    async fn list_payments(&self, query: PaymentsQuery) -> Result<Vec<WalletPayment>> {
+        let count = query.offset.saturating_add(query.limit) as usize;
+        let mut payments = Vec::new();
+        let mut offset = 0;
+
+        loop {
+            let page: Vec<PaymentDetails> = self
+                .client
+                .get(&format!(
+                    "/api/v1/payments?sortby=time&direction=desc&limit={LIST_PAGE_SIZE}&offset={offset}"
+                ))
+                .await?;
+            let page_size = page.len();
+            let mut past_range = false;
+            for payment in page.into_iter().map(PaymentDetails::into_wallet_payment) {
+                past_range |= query.from.is_some_and(|from| payment.created_at < from);
+                if query.matches(&payment) {
+                    payments.push(payment);
+                }
+            }
+
+            if payments.len() >= count || past_range || page_size < LIST_PAGE_SIZE as usize {
+                break;
+            }
+            offset += LIST_PAGE_SIZE;
+        }
+
+        Ok(query.apply(payments))
+    }
+
+    async fn lookup_invoice(&self, payment_hash: &str) -> Result<WalletPayment> {
+        let payment = self.payment(payment_hash).await.map_err(|e| match e {
+            PortalWalletError::Backend { status: 404, .. } => {
+                PortalWalletError::InvoiceNotFound(payment_hash.to_string())
+            }
+            e => e,
+        })?;
+
+        let details = payment.details.ok_or_else(|| {
+            PortalWalletError::InvalidResponse(format!("No details for payment {payment_hash}"))
+        })?;
+        Ok(details.into_wallet_payment())
+    }
+
+    async fn capabilities(&self) -> Result<WalletCapabilities> {
+        Ok(WalletCapabilities {
+            list_payments: true,
+            lookup_invoice: true,
+            invoice_expiry: true,
+            description_hash: true,
+            push_settlements: false,
+        })
+    }
+}
@@ -0,0 +1,360 @@
+use axum::async_trait;
+use base64::Engine;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    InvoiceRequest, PaymentDirection, PaymentState, PaymentsQuery, PortalWallet, PortalWalletError,
+    Result, WalletCapabilities, WalletPayment, max_fee_msat,
+    rest::{RestClient, parse_invoice},
+};
+
+/// Expiry of the invoices created by the wallet unless asked otherwise, in seconds
+const INVOICE_EXPIRY_SECS: u64 = 3600;
+
+/// LND wallet, over the node's REST API (`restlisten`, port 8080 by default)
+///
+/// Authenticates with a macaroon allowed to create and read invoices, read the channel balance
+/// and send payments (e.g. `admin.macaroon`).
+pub struct LndWallet {
+    client: RestClient,
+}
+
+impl LndWallet {
+    /// `macaroon_hex` is the hex encoded macaroon; `tls_cert_pem` the node's `tls.cert`, needed
+    /// unless the REST API is behind a certificate trusted by the system
+    pub fn new(url: String, macaroon_hex: String, tls_cert_pem: Option<Vec<u8>>) -> Result<Self> {
+        let client = RestClient::new(url, ("Grpc-Metadata-macaroon", macaroon_hex), tls_cert_pem)?;
+        Ok(Self { client })
+    }
+}
+
+// LND serializes 64-bit integers as strings and bytes as base64
+
+fn u64_string<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<u64, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    value.parse().map_err(serde::de::Error::custom)
+}
+
+fn base64_to_hex(value: &str) -> Result<String> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .map_err(|e| PortalWalletError::InvalidResponse(format!("Invalid base64: {e}")))?;
+    Ok(hex::encode(bytes))
+}
+
+fn hex_to_base64(value: &str) -> Result<String> {
+    let bytes = hex::decode(value)
+        .map_err(|e| PortalWalletError::InvalidInvoice(format!("Invalid hex: {e}")))?;
+    Ok(base64::engine::general_purpose::STANDARD.encode(bytes))
+}
+
+#[derive(Serialize)]
+struct AddInvoiceRequest {
+    value_msat: String,
+    memo: String,
+    expiry: String,
+    /// Base64
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description_hash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AddInvoiceResponse {
+    payment_request: String,
+}
+
+#[derive(Deserialize)]
+struct Invoice {
+    #[serde(default)]
+    state: String,
+    #[serde(default)]
+    r_preimage: String,
+    /// Base64, like the preimage
+    #[serde(default)]
+    r_hash: String,
+    /// Empty for keysend and AMP invoices
+    #[serde(default)]
+    payment_request: String,
+    #[serde(default, deserialize_with = "u64_string")]
+    value_msat: u64,
+    #[serde(default, deserialize_with = "u64_string")]
+    amt_paid_msat: u64,
+    #[serde(default, deserialize_with = "u64_string")]
+    creation_date: u64,
+    #[serde(default, deserialize_with = "u64_string")]
+    settle_date: u64,
+}
+
+#[derive(Deserialize)]
+struct ListInvoicesResponse {
+    #[serde(default)]
+    invoices: Vec<Invoice>,
+}
+
+#[derive(Deserialize)]
+struct ListPaymentsResponse {
+    #[serde(default)]
+    payments: Vec<Payment>,
+}
+
+#[derive(Deserialize)]
+struct Payment {
+    payment_hash: String,
+    #[serde(default)]
+    payment_request: String,
+    /// Hex, unlike the invoices
+    #[serde(default)]
+    payment_preimage: String,
+    #[serde(default, deserialize_with = "u64_string")]
+    value_msat: u64,
+    #[serde(default, deserialize_with = "u64_string")]
+    fee_msat: u64,
+    #[serde(default, deserialize_with = "u64_string")]
+    creation_time_ns: u64,
+    #[serde(default)]
+    status: String,
+}
+
+impl Invoice {
+    fn into_wallet_payment(self) -> Result<WalletPayment> {
+        let mut payment =
+            match WalletPayment::from_invoice(PaymentDirection::Incoming, &self.payment_request) {
+                Ok(payment) => payment,
+                Err(_) => {
+                    let payment_hash = base64_to_hex(&self.r_hash)?;
+                    let mut payment = WalletPayment::new(
+                        PaymentDirection::Incoming,
+                        payment_hash,
+                        self.creation_date,
+                    );
+                    payment.amount_msat = self.value_msat;
+                    payment
+                }
+            };
+        payment.created_at = self.creation_date;
+        match self.state.as_str() {
+            "SETTLED" => {
+                payment.state = PaymentState::Settled;
+                payment.preimage = Some(base64_to_hex(&self.r_preimage)?);
+                payment.amount_msat = self.amt_paid_msat;
+                payment.settled_at = Some(self.settle_date);
+            }
+            "CANCELED" => payment.state = PaymentState::Failed,
+            _ => {}
+        }
+        Ok(payment.check_expiry())
+    }
+}
+
+impl Payment {
+    fn into_wallet_payment(self) -> WalletPayment {
+        let created_at = self.creation_time_ns / 1_000_000_000;
+        let mut payment =
+            WalletPayment::from_invoice(PaymentDirection::Outgoing, &self.payment_request)
+                .unwrap_or_else(|_| {
+                    WalletPayment::new(PaymentDirection::Outgoing, self.payment_hash, created_at)
+                });
+
+        // Payments in flight stay pending past the invoice expiry
+        payment.state = match self.status.as_str() {
+            "SUCCEEDED" => PaymentState::Settled,
+            "FAILED" => PaymentState::Failed,
+            _ => PaymentState::Pending,
+        };
+        if payment.state == PaymentState::Settled {
+            payment.preimage = Some(self.payment_preimage);
+        }
+        payment.amount_msat = self.value_msat;
+        payment.fees_paid_msat = self.fee_msat;
+        payment.created_at = created_at;
+        payment
+    }
+}
+
+/// `creation_date_start` / `creation_date_end` parameters of the list calls
+fn date_range(query: &PaymentsQuery) -> String {
+    let mut params = String::new();
+    if let Some(from) = query.from {
+        params.push_str(&format!("&creation_date_start={from}"));
+    }
+    if let Some(until) = query.until {
+        params.push_str(&format!("&creation_date_end={until}"));
+    }
+    params
+}
+
+#[derive(Deserialize)]
+struct ChannelBalanceResponse {
+    local_balance: Amount,
+}
+
+#[derive(Deserialize)]
+struct Amount {
+    #[serde(deserialize_with = "u64_string")]
+    msat: u64,
+}
+
+#[derive(Serialize)]
+struct SendPaymentRequest {
+    payment_request: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fee_limit: Option<FeeLimit>,
+}
+
+#[derive(Serialize)]
+struct FeeLimit {
+    fixed_msat: String,
+}
+
+#[derive(Deserialize)]
+struct SendPaymentResponse {
+    #[serde(default)]
+    payment_error: String,
+    #[serde(default)]
+    payment_preimage: String,
+    payment_route: Option<Route>,
+}
+
+#[derive(Deserialize)]
+struct Route {
+    #[serde(default, deserialize_with = "u64_string")]
+    total_fees_msat: u64,
+}
+
+#[async_trait]
+impl PortalWallet for LndWallet {
+    async fn create_invoice(&self, request: InvoiceRequest) -> Result<String> {
+        let description_hash = request
+            .description_hash
+            .as_deref()
+            .map(hex_to_base64)
+            .transpose()?;
+        let response: AddInvoiceResponse = self
+            .client
+            .post(
+                "/v1/invoices",
+                &AddInvoiceRequest {
+                    value_msat: request.amount_msat.to_string(),
+                    memo: request.description.unwrap_or_default(),
+                    expiry: request
+                        .expiry_secs
+                        .unwrap_or(INVOICE_EXPIRY_SECS)
+                        .to_string(),
+                    description_hash,
+                },
+            )
+            .await?;
+
+        Ok(response.payment_request)
+    }
+
+    async fn is_invoice_paid(&self, invoice: String) -> Result<(bool, Option<String>)> {
+        let payment_hash = parse_invoice(&invoice)?.payment_hash().to_string();
+        let invoice: Invoice = self
+            .client
+            .get(&format!("/v1/invoice/{payment_hash}"))
+            .await?;
+
+        if invoice.state != "SETTLED" {
+            return Ok((false, None));
+        }
+        Ok((true, Some(base64_to_hex(&invoice.r_preimage)?)))
+    }
+
+    async fn get_balance(&self) -> Result<u64> {
+        let response: ChannelBalanceResponse = self.client.get("/v1/balance/channels").await?;
+        Ok(response.local_balance.msat)
+    }
+
+    async fn pay_invoice(&self, invoice: String) -> Result<(String, u64)> {
+        let amount_msat = parse_invoice(&invoice)?.amount_milli_satoshis();
+        let fee_limit = max_fee_msat(amount_msat).map(|max_fee_msat| FeeLimit {
+            fixed_msat: max_fee_msat.to_string(),
+        });
+
+        let response: SendPaymentResponse = self
+            .client
+            .post(
+                "/v1/channels/transactions",
+                &SendPaymentRequest {
+                    payment_request: invoice,
+                    fee_limit,
+                },
+            )
+            .await?;
+
+        if !response.payment_error.is_empty() {
+            return Err(PortalWalletError::PaymentFailed(response.payment_error));
+        }
+
+        let preimage = base64_to_hex(&response.payment_preimage)?;
+        let fees_paid_msat = response
+            .payment_route
+            .map(|route| route.total_fees_msat)
+            .unwrap_or(0);
+        Ok((preimage, fees_paid_msat))
+    }
+
+    /// The invoices and the payments are listed separately, enough of both are fetched to merge
+    /// the page
+    async fn list_payments(&self, query: PaymentsQuery) -> Result<Vec<WalletPayment>> {
+        let count = query.offset.saturating_add(query.limit);
+        let mut payments = Vec::new();
+
+        if query.direction != Some(PaymentDirection::Outgoing) {
+            let response: ListInvoicesResponse = self
+                .client
+                .get(&format!(
+                    "/v1/invoices?reversed=true&num_max_invoices={count}{}",
+                    date_range(&query)
+                ))
+                .await?;
+            for invoice in response.invoices {
+                payments.push(invoice.into_wallet_payment()?);
+            }
+        }
+
+        if query.direction != Some(PaymentDirection::Incoming) {
+            let response: ListPaymentsResponse = self
+                .client
+                .get(&format!(
+                    "/v1/payments?reversed=true&include_incomplete=true&max_payments={count}{}",
+                    date_range(&query)
+                ))
+                .await?;
+            payments.extend(
+                response
+                    .payments
+                    .into_iter()
+                    .map(Payment::into_wallet_payment),
+            );
+        }
+
+        Ok(query.apply(payments))
+    }
+
+    /// Only the invoices of the node, LND can't look up a payment by hash over REST
+    async fn lookup_invoice(&self, payment_hash: &str) -> Result<WalletPayment> {
+        let invoice: Invoice = self
+            .client
+            .get(&format!("/v1/invoice/{payment_hash}"))
+            .await
+            .map_err(|e| match e {
+                PortalWalletError::Backend { status: 404, .. } => {
+                    PortalWalletError::InvoiceNotFound(payment_hash.to_string())
+                }
+                e => e,
+            })?;
+        invoice.into_wallet_payment()
+    }
+
+    async fn capabilities(&self) -> Result<WalletCapabilities> {
+        Ok(WalletCapabilities {
+            list_payments: true,
+            lookup_invoice: true,
+            invoice_expiry: true,
+            description_hash: true,
+            push_settlements: false,
+        })
+    }
+}
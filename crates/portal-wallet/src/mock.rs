@@ -0,0 +1,637 @@
+use std::{
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::async_trait;
+use bitcoin::{
+    hashes::{Hash, sha256},
+    secp256k1::{PublicKey, Secp256k1, SecretKey},
+};
+use lightning_invoice::{Currency, InvoiceBuilder};
+use lightning_types::payment::PaymentSecret;
+use rusqlite::{Connection, OptionalExtension, params};
+use tokio::sync::broadcast;
+use tracing::info;
+
+use crate::{
+    InvoiceRequest, PaymentDirection, PaymentState, PaymentsQuery, PortalWallet, PortalWalletError,
+    Result, SETTLEMENTS_CAPACITY, Settlement, WalletCapabilities, WalletPayment, max_fee_msat, now,
+    rest::parse_invoice,
+};
+
+/// Settings of a [`MockWallet`]
+#[derive(Debug, Clone)]
+pub struct MockWalletConfig {
+    /// Balance of a new wallet (msat)
+    pub initial_balance_msat: u64,
+    /// Delay added to every call, to simulate a remote node
+    pub latency: Duration,
+    /// Fixed fee of every payment (msat)
+    pub fee_msat: u64,
+    /// Proportional fee of every payment, in parts per million of the amount
+    pub fee_ppm: u64,
+    /// Expiry of the invoices created by the wallet
+    pub invoice_expiry: Duration,
+    /// Settle the invoices created by the wallet on their own after this delay, instead of
+    /// waiting for [`MockWallet::settle_invoice`]
+    pub settle_after: Option<Duration>,
+    /// Network of the invoices created by the wallet
+    pub currency: Currency,
+}
+
+impl Default for MockWalletConfig {
+    fn default() -> Self {
+        Self {
+            initial_balance_msat: 0,
+            latency: Duration::ZERO,
+            fee_msat: 0,
+            fee_ppm: 0,
+            invoice_expiry: Duration::from_secs(3600),
+            settle_after: None,
+            currency: Currency::Regtest,
+        }
+    }
+}
+
+/// State of a [`MockInvoice`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockInvoiceStatus {
+    Pending,
+    Paid,
+    Expired,
+    Failed { reason: String },
+}
+
+/// An invoice known to a [`MockWallet`]
+#[derive(Debug, Clone)]
+pub struct MockInvoice {
+    pub payment_hash: String,
+    pub invoice: String,
+    pub direction: PaymentDirection,
+    pub amount_msat: u64,
+    pub fee_msat: u64,
+    /// Only known for the incoming invoices, made up for the outgoing ones
+    pub preimage: Option<String>,
+    pub status: MockInvoiceStatus,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub settled_at: Option<u64>,
+}
+
+impl From<MockInvoice> for WalletPayment {
+    fn from(invoice: MockInvoice) -> Self {
+        let mut payment = WalletPayment::from_invoice(invoice.direction, &invoice.invoice)
+            .expect("Invoices are parsed before being stored");
+
+        payment.state = match invoice.status {
+            MockInvoiceStatus::Pending => PaymentState::Pending,
+            MockInvoiceStatus::Paid => PaymentState::Settled,
+            MockInvoiceStatus::Expired => PaymentState::Expired,
+            MockInvoiceStatus::Failed { .. } => PaymentState::Failed,
+        };
+        // The preimage of a pending invoice would let anyone claim it
+        if payment.state == PaymentState::Settled {
+            payment.preimage = invoice.preimage;
+        }
+        payment.amount_msat = invoice.amount_msat;
+        payment.fees_paid_msat = invoice.fee_msat;
+        payment.created_at = invoice.created_at;
+        payment.expires_at = Some(invoice.expires_at);
+        payment.settled_at = invoice.settled_at;
+        payment.check_expiry()
+    }
+}
+
+/// In-process wallet for development and tests: no node and no network involved
+///
+/// Invoices are real BOLT11 invoices signed with a key of the wallet, so anything that parses
+/// them keeps working, but nobody can pay them over Lightning. Instead, tests decide what
+/// happens to them with [`MockWallet::settle_invoice`], [`MockWallet::expire_invoice`] and
+/// [`MockWallet::fail_invoice`], or let them settle on their own with
+/// [`MockWalletConfig::settle_after`].
+///
+/// Paying an invoice of the wallet itself settles it; other invoices are just recorded as
+/// paid, with a made up preimage. The balance and invoices are kept in SQLite, in memory
+/// ([`MockWallet::new`]) or in a file ([`MockWallet::open`]). Settled invoices are pushed to
+/// [`PortalWallet::subscribe_settlements`], like a real node would.
+pub struct MockWallet {
+    db: Arc<Mutex<Connection>>,
+    config: MockWalletConfig,
+    node_key: SecretKey,
+    settlements: broadcast::Sender<Settlement>,
+}
+
+impl MockWallet {
+    /// A wallet kept in memory, starting with [`MockWalletConfig::initial_balance_msat`]
+    pub fn new(config: MockWalletConfig) -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?, config)
+    }
+
+    /// A wallet kept in the SQLite database at `path`. The initial balance only applies when
+    /// the database is created.
+    pub fn open(path: impl AsRef<Path>, config: MockWalletConfig) -> Result<Self> {
+        let path = path.as_ref();
+        let wallet = Self::with_connection(Connection::open(path)?, config)?;
+        info!("Mock wallet opened at {}", path.display());
+        Ok(wallet)
+    }
+
+    fn with_connection(conn: Connection, config: MockWalletConfig) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS mock_wallet (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                balance_msat INTEGER NOT NULL,
+                node_key TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS mock_wallet_invoices (
+                payment_hash TEXT NOT NULL,
+                direction TEXT NOT NULL,
+                invoice TEXT NOT NULL,
+                amount_msat INTEGER NOT NULL,
+                fee_msat INTEGER NOT NULL DEFAULT 0,
+                preimage TEXT,
+                status TEXT NOT NULL,
+                failure TEXT,
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                settled_at INTEGER,
+                PRIMARY KEY (payment_hash, direction)
+            );",
+        )?;
+
+        // The node key is kept with the balance, so the invoices of a reopened wallet keep
+        // the same payee
+        let node_key = SecretKey::from_slice(&rand::random::<[u8; 32]>())
+            .expect("32 random bytes are a valid secret key");
+        conn.execute(
+            "INSERT OR IGNORE INTO mock_wallet (id, balance_msat, node_key) VALUES (0, ?1, ?2)",
+            params![
+                config.initial_balance_msat as i64,
+                hex::encode(node_key.secret_bytes())
+            ],
+        )?;
+        let node_key: String =
+            conn.query_row("SELECT node_key FROM mock_wallet WHERE id = 0", [], |row| {
+                row.get(0)
+            })?;
+        let node_key = hex::decode(node_key)
+            .ok()
+            .and_then(|bytes| SecretKey::from_slice(&bytes).ok())
+            .ok_or_else(|| PortalWalletError::InvalidResponse("Invalid mock node key".into()))?;
+
+        Ok(Self {
+            db: Arc::new(Mutex::new(conn)),
+            config,
+            node_key,
+            settlements: broadcast::channel(SETTLEMENTS_CAPACITY).0,
+        })
+    }
+
+    /// Public key the invoices of the wallet are signed with
+    pub fn node_id(&self) -> PublicKey {
+        self.node_key.public_key(&Secp256k1::new())
+    }
+
+    /// Mark an invoice of the wallet as paid, crediting its amount
+    ///
+    /// `invoice` is the BOLT11 invoice or its payment hash.
+    pub fn settle_invoice(&self, invoice: &str) -> Result<()> {
+        let db = self.db.lock().unwrap();
+        let found = Self::find(&db, invoice, PaymentDirection::Incoming)?;
+        Self::settle(&db, &self.settlements, &found)
+    }
+
+    /// Mark a pending invoice of the wallet as expired: it can't be paid anymore
+    pub fn expire_invoice(&self, invoice: &str) -> Result<()> {
+        let db = self.db.lock().unwrap();
+        let found = Self::find(&db, invoice, PaymentDirection::Incoming)?;
+        if found.status != MockInvoiceStatus::Pending {
+            return Err(PortalWalletError::PaymentFailed(format!(
+                "Invoice {} is {:?}",
+                found.payment_hash, found.status
+            )));
+        }
+        Self::set_status(
+            &db,
+            &found.payment_hash,
+            PaymentDirection::Incoming,
+            "expired",
+            None,
+        )?;
+        Ok(())
+    }
+
+    /// Make an invoice fail with `reason`
+    ///
+    /// An invoice of the wallet reports the failure when checked; any other invoice (which
+    /// must then be given as BOLT11) fails when the wallet tries to pay it.
+    pub fn fail_invoice(&self, invoice: &str, reason: &str) -> Result<()> {
+        let db = self.db.lock().unwrap();
+        match Self::find(&db, invoice, PaymentDirection::Incoming) {
+            Ok(found) => Self::set_status(
+                &db,
+                &found.payment_hash,
+                PaymentDirection::Incoming,
+                "failed",
+                Some(reason),
+            ),
+            Err(PortalWalletError::InvoiceNotFound(_)) => {
+                let parsed = parse_invoice(invoice)?;
+                db.execute(
+                    "INSERT OR REPLACE INTO mock_wallet_invoices
+                     (payment_hash, direction, invoice, amount_msat, status, failure, created_at, expires_at)
+                     VALUES (?1, 'outgoing', ?2, ?3, 'failed', ?4, ?5, ?6)",
+                    params![
+                        parsed.payment_hash().to_string(),
+                        invoice,
+                        parsed.amount_milli_satoshis().unwrap_or(0) as i64,
+                        reason,
+                        now() as i64,
+                        (parsed.duration_since_epoch() + parsed.expiry_time()).as_secs() as i64,
+                    ],
+                )?;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The invoice of the wallet with this BOLT11 or payment hash, the incoming one if the
+    /// wallet paid itself
+    pub fn invoice(&self, invoice: &str) -> Result<Option<MockInvoice>> {
+        let db = self.db.lock().unwrap();
+        for direction in [PaymentDirection::Incoming, PaymentDirection::Outgoing] {
+            match Self::find(&db, invoice, direction) {
+                Ok(found) => return Ok(Some(found)),
+                Err(PortalWalletError::InvoiceNotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+
+    async fn simulate_latency(&self) {
+        if !self.config.latency.is_zero() {
+            tokio::time::sleep(self.config.latency).await;
+        }
+    }
+
+    fn fee_for(&self, amount_msat: u64) -> u64 {
+        self.config.fee_msat + amount_msat * self.config.fee_ppm / 1_000_000
+    }
+
+    fn find(db: &Connection, invoice: &str, direction: PaymentDirection) -> Result<MockInvoice> {
+        // Payment hashes are 64 hex characters, anything else should be an invoice
+        let payment_hash = if invoice.len() == 64 && invoice.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            invoice.to_lowercase()
+        } else {
+            parse_invoice(invoice)?.payment_hash().to_string()
+        };
+
+        db.query_row(
+            &format!("SELECT {INVOICE_COLUMNS} FROM mock_wallet_invoices WHERE payment_hash = ?1 AND direction = ?2"),
+            params![payment_hash, direction_name(direction)],
+            row_to_invoice,
+        )
+        .optional()?
+        .ok_or(PortalWalletError::InvoiceNotFound(payment_hash))
+    }
+
+    fn settle(
+        db: &Connection,
+        settlements: &broadcast::Sender<Settlement>,
+        found: &MockInvoice,
+    ) -> Result<()> {
+        match &found.status {
+            MockInvoiceStatus::Pending => {}
+            MockInvoiceStatus::Paid => return Ok(()),
+            status => {
+                return Err(PortalWalletError::PaymentFailed(format!(
+                    "Invoice {} is {status:?}",
+                    found.payment_hash
+                )));
+            }
+        }
+
+        db.execute(
+            "UPDATE mock_wallet_invoices SET status = 'paid', settled_at = ?1
+             WHERE payment_hash = ?2 AND direction = 'incoming'",
+            params![now() as i64, found.payment_hash],
+        )?;
+        db.execute(
+            "UPDATE mock_wallet SET balance_msat = balance_msat + ?1 WHERE id = 0",
+            params![found.amount_msat as i64],
+        )?;
+        info!(
+            "Mock wallet: settled invoice {} ({} msat)",
+            found.payment_hash, found.amount_msat
+        );
+
+        // Nobody listening is fine
+        let _ = settlements.send(Settlement {
+            payment_hash: found.payment_hash.clone(),
+            invoice: Some(found.invoice.clone()),
+            preimage: found.preimage.clone(),
+        });
+        Ok(())
+    }
+
+    fn set_status(
+        db: &Connection,
+        payment_hash: &str,
+        direction: PaymentDirection,
+        status: &str,
+        failure: Option<&str>,
+    ) -> Result<()> {
+        db.execute(
+            "UPDATE mock_wallet_invoices SET status = ?1, failure = ?2
+             WHERE payment_hash = ?3 AND direction = ?4",
+            params![status, failure, payment_hash, direction_name(direction)],
+        )?;
+        Ok(())
+    }
+
+    fn balance(db: &Connection) -> Result<u64> {
+        let balance: i64 = db.query_row(
+            "SELECT balance_msat FROM mock_wallet WHERE id = 0",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(balance as u64)
+    }
+}
+
+const INVOICE_COLUMNS: &str = "payment_hash, direction, invoice, amount_msat, fee_msat, preimage, status, failure, created_at, expires_at, settled_at";
+
+fn row_to_invoice(row: &rusqlite::Row) -> rusqlite::Result<MockInvoice> {
+    let direction = match row.get::<_, String>(1)?.as_str() {
+        "outgoing" => PaymentDirection::Outgoing,
+        _ => PaymentDirection::Incoming,
+    };
+    let status = match row.get::<_, String>(6)?.as_str() {
+        "paid" => MockInvoiceStatus::Paid,
+        "expired" => MockInvoiceStatus::Expired,
+        "failed" => MockInvoiceStatus::Failed {
+            reason: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+        },
+        _ => MockInvoiceStatus::Pending,
+    };
+    Ok(MockInvoice {
+        payment_hash: row.get(0)?,
+        invoice: row.get(2)?,
+        direction,
+        amount_msat: row.get::<_, i64>(3)? as u64,
+        fee_msat: row.get::<_, i64>(4)? as u64,
+        preimage: row.get(5)?,
+        status,
+        created_at: row.get::<_, i64>(8)? as u64,
+        expires_at: row.get::<_, i64>(9)? as u64,
+        settled_at: row
+            .get::<_, Option<i64>>(10)?
+            .map(|settled_at| settled_at as u64),
+    })
+}
+
+fn direction_name(direction: PaymentDirection) -> &'static str {
+    match direction {
+        PaymentDirection::Incoming => "incoming",
+        PaymentDirection::Outgoing => "outgoing",
+    }
+}
+
+#[async_trait]
+impl PortalWallet for MockWallet {
+    async fn create_invoice(&self, request: InvoiceRequest) -> Result<String> {
+        self.simulate_latency().await;
+
+        let amount_msat = request.amount_msat;
+        let expiry = request
+            .expiry_secs
+            .map(Duration::from_secs)
+            .unwrap_or(self.config.invoice_expiry);
+        let builder = InvoiceBuilder::new(self.config.currency.clone());
+        let builder = match request.description_hash {
+            Some(hash) => builder.description_hash(sha256::Hash::from_str(&hash).map_err(|e| {
+                PortalWalletError::InvalidInvoice(format!("Invalid description hash: {e}"))
+            })?),
+            None => builder.description(request.description.unwrap_or_default()),
+        };
+
+        let preimage = rand::random::<[u8; 32]>();
+        let payment_hash = sha256::Hash::hash(&preimage);
+        let invoice = builder
+            .payment_hash(payment_hash)
+            .payment_secret(PaymentSecret(rand::random()))
+            .current_timestamp()
+            .min_final_cltv_expiry_delta(144)
+            .expiry_time(expiry)
+            .amount_milli_satoshis(amount_msat)
+            .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &self.node_key))
+            .map_err(|e| PortalWalletError::InvalidInvoice(e.to_string()))?
+            .to_string();
+
+        let created_at = now();
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT INTO mock_wallet_invoices
+             (payment_hash, direction, invoice, amount_msat, preimage, status, created_at, expires_at)
+             VALUES (?1, 'incoming', ?2, ?3, ?4, 'pending', ?5, ?6)",
+            params![
+                payment_hash.to_string(),
+                invoice,
+                amount_msat as i64,
+                hex::encode(preimage),
+                created_at as i64,
+                (created_at + expiry.as_secs()) as i64,
+            ],
+        )?;
+        drop(db);
+
+        if let Some(delay) = self.config.settle_after {
+            let db = self.db.clone();
+            let settlements = self.settlements.clone();
+            let payment_hash = payment_hash.to_string();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let db = db.lock().unwrap();
+                // Invoices expired or failed in the meantime stay so
+                if let Ok(found) = Self::find(&db, &payment_hash, PaymentDirection::Incoming)
+                    && found.status == MockInvoiceStatus::Pending
+                {
+                    let _ = Self::settle(&db, &settlements, &found);
+                }
+            });
+        }
+
+        Ok(invoice)
+    }
+
+    async fn is_invoice_paid(&self, invoice: String) -> Result<(bool, Option<String>)> {
+        self.simulate_latency().await;
+
+        let found = Self::find(
+            &self.db.lock().unwrap(),
+            &invoice,
+            PaymentDirection::Incoming,
+        )?;
+        match found.status {
+            MockInvoiceStatus::Paid => Ok((true, found.preimage)),
+            MockInvoiceStatus::Failed { reason } => Err(PortalWalletError::PaymentFailed(reason)),
+            MockInvoiceStatus::Expired => {
+                Err(PortalWalletError::InvoiceExpired(found.payment_hash))
+            }
+            MockInvoiceStatus::Pending if now() > found.expires_at => {
+                self.expire_invoice(&found.payment_hash)?;
+                Err(PortalWalletError::InvoiceExpired(found.payment_hash))
+            }
+            MockInvoiceStatus::Pending => match self.config.settle_after {
+                Some(delay) if now() >= found.created_at + delay.as_secs() => {
+                    self.settle_invoice(&found.payment_hash)?;
+                    Ok((true, found.preimage))
+                }
+                _ => Ok((false, None)),
+            },
+        }
+    }
+
+    async fn get_balance(&self) -> Result<u64> {
+        self.simulate_latency().await;
+        Self::balance(&self.db.lock().unwrap())
+    }
+
+    async fn subscribe_settlements(&self) -> Result<Option<broadcast::Receiver<Settlement>>> {
+        Ok(Some(self.settlements.subscribe()))
+    }
+
+    async fn list_payments(&self, query: PaymentsQuery) -> Result<Vec<WalletPayment>> {
+        self.simulate_latency().await;
+
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(&format!(
+            "SELECT {INVOICE_COLUMNS} FROM mock_wallet_invoices
+             WHERE (?1 IS NULL OR direction = ?1)
+               AND (?2 IS NULL OR created_at >= ?2)
+               AND (?3 IS NULL OR created_at <= ?3)
+             ORDER BY created_at DESC, rowid DESC
+             LIMIT ?4 OFFSET ?5"
+        ))?;
+        let invoices = statement
+            .query_map(
+                params![
+                    query.direction.map(direction_name),
+                    query.from.map(|from| from as i64),
+                    query.until.map(|until| until as i64),
+                    query.limit as i64,
+                    query.offset as i64,
+                ],
+                row_to_invoice,
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(invoices.into_iter().map(WalletPayment::from).collect())
+    }
+
+    async fn lookup_invoice(&self, payment_hash: &str) -> Result<WalletPayment> {
+        self.simulate_latency().await;
+
+        self.invoice(payment_hash)?
+            .map(WalletPayment::from)
+            .ok_or_else(|| PortalWalletError::InvoiceNotFound(payment_hash.to_string()))
+    }
+
+    async fn capabilities(&self) -> Result<WalletCapabilities> {
+        Ok(WalletCapabilities {
+            list_payments: true,
+            lookup_invoice: true,
+            invoice_expiry: true,
+            description_hash: true,
+            push_settlements: true,
+        })
+    }
+
+    async fn pay_invoice(&self, invoice: String) -> Result<(String, u64)> {
+        self.simulate_latency().await;
+
+        let parsed = parse_invoice(&invoice)?;
+        let payment_hash = parsed.payment_hash().to_string();
+        let amount_msat = parsed.amount_milli_satoshis().ok_or_else(|| {
+            PortalWalletError::InvalidInvoice("Zero-amount invoices are not supported".into())
+        })?;
+        if parsed.is_expired() {
+            return Err(PortalWalletError::InvoiceExpired(payment_hash));
+        }
+
+        let fee_msat = self.fee_for(amount_msat);
+        if let Some(max_fee_msat) = max_fee_msat(Some(amount_msat))
+            && fee_msat > max_fee_msat
+        {
+            return Err(PortalWalletError::FeeTooHigh(format!(
+                "{fee_msat} msat exceeds maximum of {max_fee_msat} msat"
+            )));
+        }
+
+        let db = self.db.lock().unwrap();
+        match Self::find(&db, &invoice, PaymentDirection::Outgoing) {
+            Ok(found) => {
+                return Err(PortalWalletError::PaymentFailed(match found.status {
+                    MockInvoiceStatus::Failed { reason } => reason,
+                    _ => format!("Invoice {payment_hash} was already paid"),
+                }));
+            }
+            Err(PortalWalletError::InvoiceNotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        if Self::balance(&db)? < amount_msat + fee_msat {
+            return Err(PortalWalletError::PaymentFailed(
+                "Insufficient balance".to_string(),
+            ));
+        }
+
+        // Paying one of our invoices settles it, otherwise the preimage can't be known
+        let preimage = match Self::find(&db, &invoice, PaymentDirection::Incoming) {
+            Ok(own) if own.status != MockInvoiceStatus::Pending => {
+                return Err(PortalWalletError::PaymentFailed(format!(
+                    "Invoice {payment_hash} is {:?}",
+                    own.status
+                )));
+            }
+            Ok(own) => {
+                Self::settle(&db, &self.settlements, &own)?;
+                own.preimage.unwrap_or_default()
+            }
+            Err(PortalWalletError::InvoiceNotFound(_)) => {
+                sha256::Hash::hash(payment_hash.as_bytes()).to_string()
+            }
+            Err(e) => return Err(e),
+        };
+
+        db.execute(
+            "UPDATE mock_wallet SET balance_msat = balance_msat - ?1 WHERE id = 0",
+            params![(amount_msat + fee_msat) as i64],
+        )?;
+        db.execute(
+            "INSERT INTO mock_wallet_invoices
+             (payment_hash, direction, invoice, amount_msat, fee_msat, preimage, status, created_at, expires_at, settled_at)
+             VALUES (?1, 'outgoing', ?2, ?3, ?4, ?5, 'paid', ?6, ?7, ?6)",
+            params![
+                payment_hash,
+                invoice,
+                amount_msat as i64,
+                fee_msat as i64,
+                preimage,
+                now() as i64,
+                (parsed.duration_since_epoch() + parsed.expiry_time()).as_secs() as i64,
+            ],
+        )?;
+        info!(
+            "Mock wallet: paid invoice {payment_hash} ({amount_msat} msat, {fee_msat} msat fees)"
+        );
+
+        Ok((preimage, fee_msat))
+    }
+}
@@ -2,32 +2,164 @@ use std::sync::Arc;
 
 use axum::async_trait;
 use nwc::NWC;
+use portal::nostr::Timestamp;
+use portal::nostr::nips::nip47::{
+    ListTransactionsRequest, LookupInvoiceRequest, MakeInvoiceRequest, Notification,
+    PayInvoiceRequest, TransactionType,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::sync::{OnceCell, broadcast};
+use tracing::warn;
 
-use crate::{PortalWallet, Result};
+use crate::{
+    InvoiceRequest, PaymentDirection, PaymentState, PaymentsQuery, PortalWallet, Result,
+    SETTLEMENTS_CAPACITY, Settlement, WalletCapabilities, WalletPayment,
+};
 
 /// NWC Wallet implementation
 pub struct NwcWallet {
     nwc: Arc<NWC>,
+    /// Kept to hand out new receivers, the sender lives in the notification handler
+    settlements: OnceCell<broadcast::Receiver<Settlement>>,
 }
 
 impl NwcWallet {
     pub fn new(nwc_url: String) -> Result<Self> {
         Ok(Self {
             nwc: Arc::new(NWC::new(nwc_url.parse()?)),
+            settlements: OnceCell::new(),
         })
     }
+
+    /// Subscribe to the NIP-47 notifications of the wallet service and forward the
+    /// `payment_received` ones, until the service stops sending them
+    async fn start_notifications(&self) -> Result<broadcast::Receiver<Settlement>> {
+        self.nwc.subscribe_to_notifications().await?;
+
+        let (sender, receiver) = broadcast::channel(SETTLEMENTS_CAPACITY);
+        let nwc = self.nwc.clone();
+        tokio::spawn(async move {
+            let result = nwc
+                .handle_notifications(|notification| {
+                    let sender = sender.clone();
+                    async move {
+                        if let Some(settlement) = payment_received(&notification) {
+                            let _ = sender.send(settlement);
+                        }
+                        Ok(false)
+                    }
+                })
+                .await;
+
+            // Dropping the sender closes the subscriptions, falling back to polling
+            if let Err(e) = result {
+                warn!("NWC notifications stopped: {e}");
+            }
+        });
+
+        Ok(receiver)
+    }
+}
+
+// The NIP-47 types are read through their wire format, which has the optional fields (like
+// the transaction `state`) that not every version of the types has
+
+/// A NIP-47 notification, as sent on the wire
+#[derive(Deserialize)]
+struct WireNotification {
+    notification_type: String,
+    notification: WireTransaction,
+}
+
+/// A NIP-47 transaction, as sent on the wire
+#[derive(Deserialize)]
+struct WireTransaction {
+    #[serde(rename = "type")]
+    transaction_type: Option<String>,
+    state: Option<String>,
+    invoice: Option<String>,
+    description: Option<String>,
+    description_hash: Option<String>,
+    preimage: Option<String>,
+    payment_hash: String,
+    #[serde(default)]
+    amount: u64,
+    #[serde(default)]
+    fees_paid: u64,
+    #[serde(default)]
+    created_at: u64,
+    expires_at: Option<u64>,
+    settled_at: Option<u64>,
+}
+
+/// The NIP-47 `get_info` response, as sent on the wire
+#[derive(Deserialize)]
+struct WireInfo {
+    #[serde(default)]
+    methods: Vec<String>,
+    #[serde(default)]
+    notifications: Vec<String>,
+}
+
+fn from_wire<T: Serialize, W: DeserializeOwned>(value: &T) -> Result<W> {
+    Ok(serde_json::from_value(serde_json::to_value(value)?)?)
+}
+
+fn payment_received(notification: &Notification) -> Option<Settlement> {
+    let notification: WireNotification = from_wire(notification).ok()?;
+    if notification.notification_type != "payment_received" {
+        return None;
+    }
+
+    Some(Settlement {
+        payment_hash: notification.notification.payment_hash,
+        invoice: notification.notification.invoice,
+        preimage: notification.notification.preimage,
+    })
+}
+
+fn wallet_payment(transaction: WireTransaction) -> WalletPayment {
+    let direction = match transaction.transaction_type.as_deref() {
+        Some("outgoing") => PaymentDirection::Outgoing,
+        _ => PaymentDirection::Incoming,
+    };
+    // Wallet services without `state` only tell whether the transaction settled
+    let state = match transaction.state.as_deref() {
+        Some("settled") => PaymentState::Settled,
+        Some("expired") => PaymentState::Expired,
+        Some("failed") => PaymentState::Failed,
+        Some(_) => PaymentState::Pending,
+        None if transaction.settled_at.is_some() => PaymentState::Settled,
+        None => PaymentState::Pending,
+    };
+
+    WalletPayment {
+        direction,
+        state,
+        payment_hash: transaction.payment_hash,
+        invoice: transaction.invoice,
+        preimage: transaction.preimage,
+        amount_msat: transaction.amount,
+        fees_paid_msat: transaction.fees_paid,
+        description: transaction.description,
+        description_hash: transaction.description_hash,
+        created_at: transaction.created_at,
+        expires_at: transaction.expires_at,
+        settled_at: transaction.settled_at,
+    }
+    .check_expiry()
 }
 
 #[async_trait]
 impl PortalWallet for NwcWallet {
-    async fn make_invoice(&self, amount_msat: u64, description: Option<String>) -> Result<String> {
+    async fn create_invoice(&self, request: InvoiceRequest) -> Result<String> {
         let payment_response = self
             .nwc
-            .make_invoice(portal::nostr::nips::nip47::MakeInvoiceRequest {
-                amount: amount_msat,
-                description,
-                description_hash: None,
-                expiry: None,
+            .make_invoice(MakeInvoiceRequest {
+                amount: request.amount_msat,
+                description: request.description,
+                description_hash: request.description_hash,
+                expiry: request.expiry_secs,
             })
             .await?;
 
@@ -37,7 +169,7 @@ impl PortalWallet for NwcWallet {
     async fn pay_invoice(&self, invoice: String) -> Result<(String, u64)> {
         let response = self
             .nwc
-            .pay_invoice(portal::nostr::nips::nip47::PayInvoiceRequest::new(invoice))
+            .pay_invoice(PayInvoiceRequest::new(invoice))
             .await?;
 
         // NIP-47 returns fees_paid in millisats
@@ -48,7 +180,7 @@ impl PortalWallet for NwcWallet {
     async fn is_invoice_paid(&self, invoice: String) -> Result<(bool, Option<String>)> {
         let invoice = self
             .nwc
-            .lookup_invoice(portal::nostr::nips::nip47::LookupInvoiceRequest {
+            .lookup_invoice(LookupInvoiceRequest {
                 invoice: Some(invoice),
                 payment_hash: None,
             })
@@ -61,4 +193,62 @@ impl PortalWallet for NwcWallet {
         let balance = self.nwc.get_balance().await?;
         Ok(balance)
     }
+
+    /// Backed by the NIP-47 `payment_received` notifications, which not every wallet
+    /// service supports: an error means the invoices have to be polled
+    async fn subscribe_settlements(&self) -> Result<Option<broadcast::Receiver<Settlement>>> {
+        let receiver = self
+            .settlements
+            .get_or_try_init(|| self.start_notifications())
+            .await?;
+        Ok(Some(receiver.resubscribe()))
+    }
+
+    async fn list_payments(&self, query: PaymentsQuery) -> Result<Vec<WalletPayment>> {
+        let transactions = self
+            .nwc
+            .list_transactions(ListTransactionsRequest {
+                from: query.from.map(Timestamp::from),
+                until: query.until.map(Timestamp::from),
+                limit: Some(query.limit),
+                offset: Some(query.offset),
+                unpaid: Some(true),
+                transaction_type: query.direction.map(|direction| match direction {
+                    PaymentDirection::Incoming => TransactionType::Incoming,
+                    PaymentDirection::Outgoing => TransactionType::Outgoing,
+                }),
+            })
+            .await?;
+
+        transactions
+            .iter()
+            .map(|transaction| from_wire(transaction).map(wallet_payment))
+            .collect()
+    }
+
+    async fn lookup_invoice(&self, payment_hash: &str) -> Result<WalletPayment> {
+        let transaction = self
+            .nwc
+            .lookup_invoice(LookupInvoiceRequest {
+                invoice: None,
+                payment_hash: Some(payment_hash.to_string()),
+            })
+            .await?;
+
+        Ok(wallet_payment(from_wire(&transaction)?))
+    }
+
+    /// The methods and notifications announced by the wallet service
+    async fn capabilities(&self) -> Result<WalletCapabilities> {
+        let info: WireInfo = from_wire(&self.nwc.get_info().await?)?;
+        let supports = |method: &str| info.methods.iter().any(|m| m == method);
+
+        Ok(WalletCapabilities {
+            list_payments: supports("list_transactions"),
+            lookup_invoice: supports("lookup_invoice"),
+            invoice_expiry: supports("make_invoice"),
+            description_hash: supports("make_invoice"),
+            push_settlements: info.notifications.iter().any(|n| n == "payment_received"),
+        })
+    }
 }
@@ -0,0 +1,91 @@
+use std::str::FromStr;
+
+use lightning_invoice::Bolt11Invoice;
+use reqwest::{Client, RequestBuilder};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{PortalWalletError, Result};
+
+/// JSON client for the REST APIs of the node backends
+///
+/// Every request carries the backend's authentication header. Non-2xx responses are turned into
+/// [`PortalWalletError::Backend`] with the message found in the error body.
+pub(crate) struct RestClient {
+    client: Client,
+    base_url: String,
+    auth_header: (&'static str, String),
+}
+
+impl RestClient {
+    /// `tls_cert_pem` is trusted on top of the system roots, for nodes with a self-signed
+    /// certificate (like LND's `tls.cert`)
+    pub fn new(
+        base_url: String,
+        auth_header: (&'static str, String),
+        tls_cert_pem: Option<Vec<u8>>,
+    ) -> Result<Self> {
+        let mut builder = Client::builder();
+        if let Some(pem) = tls_cert_pem {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            auth_header,
+        })
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        self.send(self.client.get(self.url(path))).await
+    }
+
+    pub async fn post<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T> {
+        let body = serde_json::to_string(body)?;
+        let request = self
+            .client
+            .post(self.url(path))
+            .header("Content-Type", "application/json")
+            .body(body);
+        self.send(request).await
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T> {
+        let (name, value) = &self.auth_header;
+        let response = request.header(*name, value).send().await?;
+        let status = response.status();
+        let body = response.text().await?;
+
+        if !status.is_success() {
+            return Err(PortalWalletError::Backend {
+                status: status.as_u16(),
+                message: error_message(&body),
+            });
+        }
+
+        serde_json::from_str(&body)
+            .map_err(|e| PortalWalletError::InvalidResponse(format!("{e}: {body}")))
+    }
+}
+
+/// The message of an error body: `message` (LND, Core Lightning), `detail` (LNbits) or `error`,
+/// the raw body otherwise
+fn error_message(body: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|value| {
+            ["message", "detail", "error"]
+                .iter()
+                .find_map(|key| value.get(key)?.as_str().map(str::to_string))
+        })
+        .unwrap_or_else(|| body.to_string())
+}
+
+/// Parses a BOLT11 invoice, to look it up by payment hash or bound the fees paid for it
+pub(crate) fn parse_invoice(invoice: &str) -> Result<Bolt11Invoice> {
+    Bolt11Invoice::from_str(invoice).map_err(|e| PortalWalletError::InvalidInvoice(e.to_string()))
+}
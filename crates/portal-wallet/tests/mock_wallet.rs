@@ -0,0 +1,268 @@
+//! The in-process mock wallet
+
+use std::time::Duration;
+
+use bitcoin::hashes::{Hash, sha256};
+use lightning_invoice::Bolt11Invoice;
+use portal_wallet::{
+    InvoiceRequest, MockInvoiceStatus, MockWallet, MockWalletConfig, PaymentDirection,
+    PaymentState, PaymentsQuery, PortalWallet, PortalWalletError,
+};
+
+fn wallet(config: MockWalletConfig) -> MockWallet {
+    MockWallet::new(config).unwrap()
+}
+
+#[tokio::test]
+async fn test_settle_invoice() {
+    let wallet = wallet(MockWalletConfig::default());
+    let invoice = wallet
+        .make_invoice(21_000, Some("coffee".to_string()))
+        .await
+        .unwrap();
+
+    // A real invoice, signed by the wallet
+    let parsed: Bolt11Invoice = invoice.parse().unwrap();
+    assert_eq!(parsed.amount_milli_satoshis(), Some(21_000));
+    assert_eq!(parsed.recover_payee_pub_key(), wallet.node_id());
+
+    let (paid, _) = wallet.is_invoice_paid(invoice.clone()).await.unwrap();
+    assert!(!paid);
+
+    wallet.settle_invoice(&invoice).unwrap();
+    let (paid, preimage) = wallet.is_invoice_paid(invoice.clone()).await.unwrap();
+    assert!(paid);
+    let preimage = hex::decode(preimage.unwrap()).unwrap();
+    assert_eq!(sha256::Hash::hash(&preimage), *parsed.payment_hash());
+    assert_eq!(wallet.get_balance().await.unwrap(), 21_000);
+
+    // Settling again doesn't credit the amount twice
+    wallet
+        .settle_invoice(&parsed.payment_hash().to_string())
+        .unwrap();
+    assert_eq!(wallet.get_balance().await.unwrap(), 21_000);
+}
+
+#[tokio::test]
+async fn test_expire_and_fail_invoice() {
+    let wallet = wallet(MockWalletConfig::default());
+
+    let expired = wallet.make_invoice(1_000, None).await.unwrap();
+    wallet.expire_invoice(&expired).unwrap();
+    assert!(matches!(
+        wallet.is_invoice_paid(expired.clone()).await,
+        Err(PortalWalletError::InvoiceExpired(_))
+    ));
+    assert!(wallet.settle_invoice(&expired).is_err());
+
+    let failed = wallet.make_invoice(1_000, None).await.unwrap();
+    wallet.fail_invoice(&failed, "route not found").unwrap();
+    match wallet.is_invoice_paid(failed.clone()).await {
+        Err(PortalWalletError::PaymentFailed(reason)) => assert_eq!(reason, "route not found"),
+        other => panic!("Unexpected result: {other:?}"),
+    }
+    assert_eq!(
+        wallet.invoice(&failed).unwrap().unwrap().status,
+        MockInvoiceStatus::Failed {
+            reason: "route not found".to_string()
+        }
+    );
+    assert_eq!(wallet.get_balance().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_pay_invoice() {
+    let payee = wallet(MockWalletConfig::default());
+    let payer = wallet(MockWalletConfig {
+        initial_balance_msat: 1_000_000,
+        fee_msat: 1_000,
+        fee_ppm: 10_000,
+        ..Default::default()
+    });
+
+    // Invoices of another wallet are recorded as paid, fees included
+    let invoice = payee.make_invoice(100_000, None).await.unwrap();
+    let (_, fees_paid_msat) = payer.pay_invoice(invoice.clone()).await.unwrap();
+    assert_eq!(fees_paid_msat, 2_000);
+    assert_eq!(payer.get_balance().await.unwrap(), 898_000);
+    assert!(payer.pay_invoice(invoice).await.is_err());
+
+    // Paying an invoice of the wallet itself settles it, only the fees are lost
+    let own = payer.make_invoice(100_000, None).await.unwrap();
+    payer.pay_invoice(own.clone()).await.unwrap();
+    assert!(payer.is_invoice_paid(own).await.unwrap().0);
+    assert_eq!(payer.get_balance().await.unwrap(), 896_000);
+
+    let too_much = payee.make_invoice(2_000_000, None).await.unwrap();
+    assert!(matches!(
+        payer.pay_invoice(too_much).await,
+        Err(PortalWalletError::PaymentFailed(_))
+    ));
+
+    let failing = payee.make_invoice(1_000, None).await.unwrap();
+    payer.fail_invoice(&failing, "no route").unwrap();
+    assert!(matches!(
+        payer.pay_invoice(failing).await,
+        Err(PortalWalletError::PaymentFailed(reason)) if reason == "no route"
+    ));
+}
+
+#[tokio::test]
+async fn test_fee_cap() {
+    let payee = wallet(MockWalletConfig::default());
+    let payer = wallet(MockWalletConfig {
+        initial_balance_msat: 10_000_000,
+        fee_ppm: 100_000,
+        ..Default::default()
+    });
+
+    // Fees are capped at max(1%, 1000 sats): 10% of 1000 sats is within the cap...
+    let invoice = payee.make_invoice(1_000_000, None).await.unwrap();
+    assert!(payer.pay_invoice(invoice).await.is_ok());
+
+    // ...but not on 20000 sats
+    let invoice = payee.make_invoice(20_000_000, None).await.unwrap();
+    assert!(matches!(
+        payer.pay_invoice(invoice).await,
+        Err(PortalWalletError::FeeTooHigh(_))
+    ));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_latency() {
+    let wallet = wallet(MockWalletConfig {
+        latency: Duration::from_millis(500),
+        ..Default::default()
+    });
+
+    let start = tokio::time::Instant::now();
+    wallet.get_balance().await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn test_settle_after() {
+    let wallet = wallet(MockWalletConfig {
+        settle_after: Some(Duration::ZERO),
+        ..Default::default()
+    });
+
+    let invoice = wallet.make_invoice(5_000, None).await.unwrap();
+    assert!(wallet.is_invoice_paid(invoice).await.unwrap().0);
+    assert_eq!(wallet.get_balance().await.unwrap(), 5_000);
+}
+
+#[tokio::test]
+async fn test_sqlite_storage() {
+    let path = std::env::temp_dir().join(format!("portal-mock-wallet-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = MockWalletConfig {
+        initial_balance_msat: 50_000,
+        ..Default::default()
+    };
+
+    let invoice = {
+        let wallet = MockWallet::open(&path, config.clone()).unwrap();
+        let invoice = wallet.make_invoice(10_000, None).await.unwrap();
+        wallet.settle_invoice(&invoice).unwrap();
+        invoice
+    };
+
+    // The balance, invoices and node key survive reopening the wallet
+    let wallet = MockWallet::open(&path, config).unwrap();
+    assert_eq!(wallet.get_balance().await.unwrap(), 60_000);
+    assert!(wallet.is_invoice_paid(invoice.clone()).await.unwrap().0);
+    let parsed: Bolt11Invoice = invoice.parse().unwrap();
+    assert_eq!(parsed.recover_payee_pub_key(), wallet.node_id());
+
+    drop(wallet);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_settlements_are_pushed() {
+    let wallet = wallet(MockWalletConfig::default());
+    let mut settlements = wallet.subscribe_settlements().await.unwrap().unwrap();
+
+    let invoice = wallet.make_invoice(2_000, None).await.unwrap();
+    wallet.settle_invoice(&invoice).unwrap();
+
+    let settlement = settlements.recv().await.unwrap();
+    let parsed: Bolt11Invoice = invoice.parse().unwrap();
+    assert_eq!(settlement.payment_hash, parsed.payment_hash().to_string());
+    assert_eq!(settlement.invoice.as_deref(), Some(invoice.as_str()));
+    assert!(settlement.preimage.is_some());
+}
+
+#[tokio::test]
+async fn test_list_payments() {
+    let payee = wallet(MockWalletConfig::default());
+    let payer = wallet(MockWalletConfig {
+        initial_balance_msat: 1_000_000,
+        fee_msat: 1_000,
+        ..Default::default()
+    });
+
+    let first = payer.make_invoice(1_000, None).await.unwrap();
+    let second = payer.make_invoice(2_000, None).await.unwrap();
+    payer.settle_invoice(&first).unwrap();
+    let outgoing = payee.make_invoice(10_000, None).await.unwrap();
+    payer.pay_invoice(outgoing.clone()).await.unwrap();
+
+    // Newest first
+    let payments = payer.list_payments(PaymentsQuery::default()).await.unwrap();
+    let invoices: Vec<_> = payments
+        .iter()
+        .map(|p| p.invoice.clone().unwrap())
+        .collect();
+    assert_eq!(invoices, [outgoing.clone(), second.clone(), first.clone()]);
+    assert_eq!(payments[0].direction, PaymentDirection::Outgoing);
+    assert_eq!(payments[0].fees_paid_msat, 1_000);
+    assert_eq!(payments[1].state, PaymentState::Pending);
+    assert_eq!(payments[1].preimage, None);
+    assert_eq!(payments[2].state, PaymentState::Settled);
+    assert!(payments[2].settled_at.is_some());
+
+    let page = payer
+        .list_payments(PaymentsQuery {
+            direction: Some(PaymentDirection::Incoming),
+            offset: 1,
+            limit: 10,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].invoice.as_deref(), Some(first.as_str()));
+}
+
+#[tokio::test]
+async fn test_lookup_invoice() {
+    let wallet = wallet(MockWalletConfig::default());
+    let description_hash = sha256::Hash::hash(b"a long description").to_string();
+    let invoice = wallet
+        .create_invoice(InvoiceRequest {
+            amount_msat: 3_000,
+            description_hash: Some(description_hash.clone()),
+            expiry_secs: Some(60),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let parsed: Bolt11Invoice = invoice.parse().unwrap();
+    assert_eq!(parsed.expiry_time(), Duration::from_secs(60));
+
+    let payment = wallet
+        .lookup_invoice(&parsed.payment_hash().to_string())
+        .await
+        .unwrap();
+    assert_eq!(payment.amount_msat, 3_000);
+    assert_eq!(payment.description_hash, Some(description_hash));
+    assert_eq!(payment.expires_at, Some(payment.created_at + 60));
+
+    assert!(matches!(
+        wallet.lookup_invoice(&"00".repeat(32)).await,
+        Err(PortalWalletError::InvoiceNotFound(_))
+    ));
+    assert!(wallet.capabilities().await.unwrap().description_hash);
+}
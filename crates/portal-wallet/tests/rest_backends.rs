@@ -0,0 +1,492 @@
+//! The REST wallet backends against mock LND, Core Lightning and LNbits servers
+
+use std::net::TcpListener;
+
+use axum::{
+    Json, Router,
+    extract::Path,
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+};
+use base64::Engine;
+use bitcoin::{
+    hashes::{Hash, sha256},
+    secp256k1::{Secp256k1, SecretKey},
+};
+use lightning_invoice::{Currency, InvoiceBuilder};
+use lightning_types::payment::PaymentSecret;
+use portal_wallet::{
+    ClnWallet, InvoiceRequest, LnbitsWallet, LndWallet, PaymentDirection, PaymentState,
+    PaymentsQuery, PortalWallet, PortalWalletError, max_fee_msat,
+};
+use serde_json::{Value, json};
+
+const PREIMAGE: [u8; 32] = [7; 32];
+
+type Reply = Result<Json<Value>, (StatusCode, Json<Value>)>;
+
+/// A signed invoice paying to [`PREIMAGE`]'s hash
+fn invoice(amount_msat: Option<u64>) -> String {
+    let key = SecretKey::from_slice(&[42; 32]).unwrap();
+    let mut builder = InvoiceBuilder::new(Currency::Bitcoin)
+        .description("test".to_string())
+        .payment_hash(sha256::Hash::hash(&PREIMAGE))
+        .payment_secret(PaymentSecret([1; 32]))
+        .current_timestamp()
+        .min_final_cltv_expiry_delta(144);
+    if let Some(amount_msat) = amount_msat {
+        builder = builder.amount_milli_satoshis(amount_msat);
+    }
+    builder
+        .build_signed(|hash| Secp256k1::new().sign_ecdsa_recoverable(hash, &key))
+        .unwrap()
+        .to_string()
+}
+
+fn payment_hash() -> String {
+    sha256::Hash::hash(&PREIMAGE).to_string()
+}
+
+fn incoming() -> PaymentsQuery {
+    PaymentsQuery {
+        direction: Some(PaymentDirection::Incoming),
+        ..Default::default()
+    }
+}
+
+/// Rejects the requests without the expected authentication header, like the nodes do
+fn check_auth(
+    headers: &HeaderMap,
+    name: &str,
+    value: &str,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    match headers.get(name) {
+        Some(header) if header == value => Ok(()),
+        _ => Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({ "message": "permission denied" })),
+        )),
+    }
+}
+
+/// Serves `app` on a random local port, returning its base URL
+fn serve(app: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(app.into_make_service());
+    tokio::spawn(server);
+    url
+}
+
+fn lnd_invoice(preimage: &str) -> Value {
+    json!({
+        "state": "SETTLED",
+        "r_preimage": preimage,
+        "payment_request": invoice(Some(21_000)),
+        "amt_paid_msat": "21000",
+        "creation_date": "1700000000",
+        "settle_date": "1700000060",
+    })
+}
+
+/// A keysend payment received, without a payment request
+fn lnd_keysend_invoice(preimage: &str) -> Value {
+    json!({
+        "state": "SETTLED",
+        "r_preimage": preimage,
+        "r_hash": base64::engine::general_purpose::STANDARD.encode([9; 32]),
+        "payment_request": "",
+        "is_keysend": true,
+        "value_msat": "5000",
+        "amt_paid_msat": "5000",
+        "creation_date": "1699999000",
+        "settle_date": "1699999000",
+    })
+}
+
+fn lnd_server() -> String {
+    const MACAROON: &str = "0201036c6e64";
+    let preimage = base64::engine::general_purpose::STANDARD.encode(PREIMAGE);
+
+    let app = Router::new()
+        .route(
+            "/v1/invoices",
+            post(|headers: HeaderMap, Json(body): Json<Value>| async move {
+                check_auth(&headers, "Grpc-Metadata-macaroon", MACAROON)?;
+                assert_eq!(body["memo"], "coffee");
+                let amount_msat = body["value_msat"].as_str().unwrap().parse().unwrap();
+                Reply::Ok(Json(
+                    json!({ "payment_request": invoice(Some(amount_msat)) }),
+                ))
+            })
+            .get({
+                let preimage = preimage.clone();
+                |headers: HeaderMap| async move {
+                    check_auth(&headers, "Grpc-Metadata-macaroon", MACAROON)?;
+                    Reply::Ok(Json(json!({
+                        "invoices": [lnd_invoice(&preimage), lnd_keysend_invoice(&preimage)]
+                    })))
+                }
+            }),
+        )
+        .route(
+            "/v1/invoice/:hash",
+            get({
+                let preimage = preimage.clone();
+                |headers: HeaderMap, Path(hash): Path<String>| async move {
+                    check_auth(&headers, "Grpc-Metadata-macaroon", MACAROON)?;
+                    if hash != payment_hash() {
+                        return Err((
+                            StatusCode::NOT_FOUND,
+                            Json(json!({ "message": "unable to locate invoice" })),
+                        ));
+                    }
+                    Reply::Ok(Json(lnd_invoice(&preimage)))
+                }
+            }),
+        )
+        .route(
+            "/v1/payments",
+            get(|headers: HeaderMap| async move {
+                check_auth(&headers, "Grpc-Metadata-macaroon", MACAROON)?;
+                Reply::Ok(Json(json!({
+                    "payments": [{
+                        "payment_hash": payment_hash(),
+                        "payment_request": invoice(Some(100_000)),
+                        "payment_preimage": hex::encode(PREIMAGE),
+                        "value_msat": "100000",
+                        "fee_msat": "2000",
+                        "creation_time_ns": "1700000100000000000",
+                        "status": "SUCCEEDED",
+                    }]
+                })))
+            }),
+        )
+        .route(
+            "/v1/balance/channels",
+            get(|headers: HeaderMap| async move {
+                check_auth(&headers, "Grpc-Metadata-macaroon", MACAROON)?;
+                Reply::Ok(Json(
+                    json!({ "local_balance": { "sat": "21", "msat": "21000" } }),
+                ))
+            }),
+        )
+        .route(
+            "/v1/channels/transactions",
+            post(|headers: HeaderMap, Json(body): Json<Value>| async move {
+                check_auth(&headers, "Grpc-Metadata-macaroon", MACAROON)?;
+                assert_eq!(body["fee_limit"]["fixed_msat"], "1000000");
+                Reply::Ok(Json(json!({
+                    "payment_error": "",
+                    "payment_preimage": preimage,
+                    "payment_route": { "total_fees_msat": "2000" },
+                })))
+            }),
+        );
+
+    serve(app)
+}
+
+fn cln_server() -> String {
+    const RUNE: &str = "test-rune";
+
+    let app = Router::new()
+        .route(
+            "/v1/invoice",
+            post(|headers: HeaderMap, Json(body): Json<Value>| async move {
+                check_auth(&headers, "Rune", RUNE)?;
+                assert!(body["label"].as_str().unwrap().starts_with("portal-"));
+                Reply::Ok(Json(
+                    json!({ "bolt11": invoice(body["amount_msat"].as_u64()) }),
+                ))
+            }),
+        )
+        .route(
+            "/v1/listinvoices",
+            post(|headers: HeaderMap| async move {
+                check_auth(&headers, "Rune", RUNE)?;
+                Reply::Ok(Json(json!({
+                    "invoices": [{
+                        "status": "paid",
+                        "payment_hash": payment_hash(),
+                        "bolt11": invoice(Some(100_000)),
+                        "payment_preimage": hex::encode(PREIMAGE),
+                        "amount_received_msat": 100_000,
+                        "paid_at": 1_700_000_060,
+                    }]
+                })))
+            }),
+        )
+        .route(
+            "/v1/listpays",
+            post(|headers: HeaderMap| async move {
+                check_auth(&headers, "Rune", RUNE)?;
+                Reply::Ok(Json(json!({
+                    "pays": [{
+                        "status": "complete",
+                        "payment_hash": payment_hash(),
+                        "bolt11": invoice(Some(100_000)),
+                        "preimage": hex::encode(PREIMAGE),
+                        "amount_msat": 100_000,
+                        "amount_sent_msat": 100_500,
+                        "created_at": 1_700_000_100,
+                        "completed_at": 1_700_000_101,
+                    }]
+                })))
+            }),
+        )
+        .route(
+            "/v1/listfunds",
+            post(|headers: HeaderMap| async move {
+                check_auth(&headers, "Rune", RUNE)?;
+                Reply::Ok(Json(json!({
+                    "outputs": [],
+                    "channels": [
+                        { "state": "CHANNELD_NORMAL", "our_amount_msat": 15000 },
+                        { "state": "ONCHAIN", "our_amount_msat": 5000 },
+                    ]
+                })))
+            }),
+        )
+        .route(
+            "/v1/pay",
+            post(|headers: HeaderMap, Json(body): Json<Value>| async move {
+                check_auth(&headers, "Rune", RUNE)?;
+                assert!(body.get("maxfee").is_none());
+                Reply::Ok(Json(json!({
+                    "status": "complete",
+                    "payment_preimage": hex::encode(PREIMAGE),
+                    "amount_msat": 100000,
+                    "amount_sent_msat": 100500,
+                })))
+            }),
+        );
+
+    serve(app)
+}
+
+/// A settled LNbits payment, outgoing if `amount_msat` is negative
+fn lnbits_payment(amount_msat: i64, fee_msat: i64) -> Value {
+    json!({
+        "payment_hash": payment_hash(),
+        "bolt11": invoice(Some(amount_msat.unsigned_abs())),
+        "preimage": hex::encode(PREIMAGE),
+        "amount": amount_msat,
+        "fee": fee_msat,
+        "status": "success",
+        "pending": false,
+    })
+}
+
+fn lnbits_server() -> String {
+    const ADMIN_KEY: &str = "admin-key";
+
+    let app = Router::new()
+        .route(
+            "/api/v1/payments",
+            post(|headers: HeaderMap, Json(body): Json<Value>| async move {
+                check_auth(&headers, "X-Api-Key", ADMIN_KEY)?;
+                if body["out"] == true {
+                    return Reply::Ok(Json(json!({ "payment_hash": payment_hash() })));
+                }
+                let amount_msat = body["amount"].as_u64().unwrap() * 1000;
+                Reply::Ok(Json(json!({
+                    "payment_hash": payment_hash(),
+                    "bolt11": invoice(Some(amount_msat)),
+                })))
+            })
+            .get(|headers: HeaderMap| async move {
+                check_auth(&headers, "X-Api-Key", ADMIN_KEY)?;
+                Reply::Ok(Json(json!([
+                    lnbits_payment(-100_000, -3000),
+                    lnbits_payment(5000, 0)
+                ])))
+            }),
+        )
+        .route(
+            "/api/v1/payments/:hash",
+            get(|headers: HeaderMap, Path(hash): Path<String>| async move {
+                check_auth(&headers, "X-Api-Key", ADMIN_KEY)?;
+                assert_eq!(hash, payment_hash());
+                Reply::Ok(Json(json!({
+                    "paid": true,
+                    "preimage": hex::encode(PREIMAGE),
+                    "details": lnbits_payment(-100_000, -3000),
+                })))
+            }),
+        )
+        .route(
+            "/api/v1/wallet",
+            get(|headers: HeaderMap| async move {
+                check_auth(&headers, "X-Api-Key", ADMIN_KEY)?;
+                Reply::Ok(Json(json!({ "name": "test", "balance": 42000 })))
+            }),
+        );
+
+    serve(app)
+}
+
+#[tokio::test]
+async fn test_lnd_wallet() {
+    let url = lnd_server();
+    let wallet = LndWallet::new(url.clone(), "0201036c6e64".to_string(), None).unwrap();
+
+    let invoice = wallet
+        .make_invoice(21_000, Some("coffee".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(
+        invoice
+            .parse::<lightning_invoice::Bolt11Invoice>()
+            .unwrap()
+            .amount_milli_satoshis(),
+        Some(21_000)
+    );
+
+    let (paid, preimage) = wallet.is_invoice_paid(invoice).await.unwrap();
+    assert!(paid);
+    assert_eq!(preimage, Some(hex::encode(PREIMAGE)));
+
+    assert_eq!(wallet.get_balance().await.unwrap(), 21_000);
+
+    // Zero-amount invoices are paid with the default fee cap
+    let (preimage, fees) = wallet.pay_invoice(invoice(None)).await.unwrap();
+    assert_eq!(preimage, hex::encode(PREIMAGE));
+    assert_eq!(fees, 2000);
+
+    // Invoices and payments are merged, newest first
+    let payments = wallet
+        .list_payments(PaymentsQuery::default())
+        .await
+        .unwrap();
+    assert_eq!(payments.len(), 3);
+    assert_eq!(payments[0].direction, PaymentDirection::Outgoing);
+    assert_eq!(payments[0].created_at, 1_700_000_100);
+    assert_eq!(payments[0].fees_paid_msat, 2000);
+    assert_eq!(payments[1].direction, PaymentDirection::Incoming);
+    assert_eq!(payments[1].state, PaymentState::Settled);
+    assert_eq!(payments[1].settled_at, Some(1_700_000_060));
+    assert_eq!(wallet.list_payments(incoming()).await.unwrap().len(), 2);
+
+    // Keysend invoices have no payment request
+    assert_eq!(payments[2].payment_hash, hex::encode([9; 32]));
+    assert_eq!(payments[2].invoice, None);
+    assert_eq!(payments[2].amount_msat, 5000);
+    assert_eq!(payments[2].state, PaymentState::Settled);
+
+    let past_the_end = PaymentsQuery {
+        offset: u64::MAX,
+        ..Default::default()
+    };
+    assert!(wallet.list_payments(past_the_end).await.unwrap().is_empty());
+
+    let payment = wallet.lookup_invoice(&payment_hash()).await.unwrap();
+    assert_eq!(payment.amount_msat, 21_000);
+    assert_eq!(payment.preimage, Some(hex::encode(PREIMAGE)));
+    assert_eq!(payment.description.as_deref(), Some("test"));
+    assert!(matches!(
+        wallet.lookup_invoice(&"00".repeat(32)).await,
+        Err(PortalWalletError::InvoiceNotFound(_))
+    ));
+
+    let wrong_macaroon = LndWallet::new(url, "00".to_string(), None).unwrap();
+    match wrong_macaroon.get_balance().await {
+        Err(PortalWalletError::Backend { status, message }) => {
+            assert_eq!(status, 401);
+            assert_eq!(message, "permission denied");
+        }
+        other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn test_cln_wallet() {
+    let wallet = ClnWallet::new(cln_server(), "test-rune".to_string(), None).unwrap();
+
+    let invoice = wallet.make_invoice(100_000, None).await.unwrap();
+
+    let (paid, preimage) = wallet.is_invoice_paid(invoice.clone()).await.unwrap();
+    assert!(paid);
+    assert_eq!(preimage, Some(hex::encode(PREIMAGE)));
+
+    // Only the channels in CHANNELD_NORMAL count
+    assert_eq!(wallet.get_balance().await.unwrap(), 15_000);
+
+    // Below 500 sats, no fee cap is sent
+    let (preimage, fees) = wallet.pay_invoice(invoice).await.unwrap();
+    assert_eq!(preimage, hex::encode(PREIMAGE));
+    assert_eq!(fees, 500);
+
+    let payments = wallet
+        .list_payments(PaymentsQuery::default())
+        .await
+        .unwrap();
+    assert_eq!(payments.len(), 2);
+    let outgoing = payments
+        .iter()
+        .find(|payment| payment.direction == PaymentDirection::Outgoing)
+        .unwrap();
+    assert_eq!(outgoing.state, PaymentState::Settled);
+    assert_eq!(outgoing.fees_paid_msat, 500);
+    assert_eq!(outgoing.settled_at, Some(1_700_000_101));
+    assert_eq!(wallet.list_payments(incoming()).await.unwrap().len(), 1);
+
+    let payment = wallet.lookup_invoice(&payment_hash()).await.unwrap();
+    assert_eq!(payment.direction, PaymentDirection::Incoming);
+    assert_eq!(payment.settled_at, Some(1_700_000_060));
+
+    // Core Lightning can't take an arbitrary description hash
+    let request = InvoiceRequest {
+        amount_msat: 1000,
+        description_hash: Some(payment_hash()),
+        ..Default::default()
+    };
+    assert!(matches!(
+        wallet.create_invoice(request).await,
+        Err(PortalWalletError::Unsupported(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_lnbits_wallet() {
+    let url = lnbits_server();
+    let wallet = LnbitsWallet::new(url.clone(), "admin-key".to_string()).unwrap();
+
+    let invoice = wallet.make_invoice(5_000, None).await.unwrap();
+
+    let (paid, preimage) = wallet.is_invoice_paid(invoice.clone()).await.unwrap();
+    assert!(paid);
+    assert_eq!(preimage, Some(hex::encode(PREIMAGE)));
+
+    assert_eq!(wallet.get_balance().await.unwrap(), 42_000);
+
+    let (preimage, fees) = wallet.pay_invoice(invoice).await.unwrap();
+    assert_eq!(preimage, hex::encode(PREIMAGE));
+    assert_eq!(fees, 3000);
+
+    // The direction is told by the sign of the amount
+    let payments = wallet.list_payments(incoming()).await.unwrap();
+    assert_eq!(payments.len(), 1);
+    assert_eq!(payments[0].amount_msat, 5000);
+
+    let payment = wallet.lookup_invoice(&payment_hash()).await.unwrap();
+    assert_eq!(payment.direction, PaymentDirection::Outgoing);
+    assert_eq!(payment.amount_msat, 100_000);
+    assert_eq!(payment.fees_paid_msat, 3000);
+
+    let invoice_key = LnbitsWallet::new(url, "invoice-key".to_string()).unwrap();
+    assert!(matches!(
+        invoice_key.get_balance().await,
+        Err(PortalWalletError::Backend { status: 401, .. })
+    ));
+}
+
+#[test]
+fn test_max_fee() {
+    assert_eq!(max_fee_msat(Some(499_000)), None);
+    assert_eq!(max_fee_msat(Some(500_000)), Some(1_000_000));
+    assert_eq!(max_fee_msat(Some(200_000_000)), Some(2_000_000));
+    assert_eq!(max_fee_msat(None), Some(1_000_000));
+}
@@ -80,7 +80,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let status = if approve.trim().to_lowercase() == "y" {
             AuthResponseStatus::Approved {
                 granted_permissions: vec![],
-                session_token: String::from("ABC"),
+                session_token: String::new(),
             }
         } else {
             AuthResponseStatus::Declined {
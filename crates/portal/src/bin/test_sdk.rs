@@ -115,6 +115,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let conv = AuthChallengeSenderConversation::new(
         router.keypair().public_key(),
         router.keypair().subkey_proof().cloned(),
+        vec![],
     );
     let (id, _outcomes) = router
         .add_conversation(Box::new(MultiKeySenderAdapter::new_with_user(
@@ -8,12 +8,14 @@ use crate::{
         key_handshake::KeyHandshakeUrl,
         model::{
             auth::{
-                AuthChallengeContent, AuthResponseContent, AuthResponseStatus, ClientInfo,
-                KeyHandshakeContent, SubkeyProof,
+                AuthChallengeContent, AuthResponseContent, AuthResponseStatus, AuthSuccessContent,
+                ClientInfo, KeyHandshakeContent, Permission, SubkeyProof,
             },
             bindings,
-            event_kinds::{AUTH_CHALLENGE, AUTH_RESPONSE, KEY_HANDSHAKE},
+            event_kinds::{AUTH_CHALLENGE, AUTH_RESPONSE, AUTH_SUCCESS, KEY_HANDSHAKE},
         },
+        session::Session,
+        subkey::SubkeyOperation,
     },
     router::{
         ConversationError, MultiKeyListener, MultiKeyListenerAdapter, Response,
@@ -61,6 +63,11 @@ impl OneShotSender for KeyHandshakeConversation {
     }
 }
 
+/// The main key of the user, which the subkeys of services must be allowed to interact with
+fn user_main_key(local_key: PublicKey, subkey_proof: Option<&SubkeyProof>) -> PublicKey {
+    subkey_proof.map_or(local_key, |p| p.main_key.into())
+}
+
 pub struct AuthChallengeListenerConversation {
     local_key: PublicKey,
 }
@@ -79,6 +86,9 @@ pub struct AuthChallengeEvent {
     pub challenge: String,
     pub expires_at: u64,
     pub required_permissions: Vec<String>,
+    /// The valid `required_permissions`, to show to the user
+    #[serde(default)]
+    pub permissions: Vec<Permission>,
     pub event_id: String,
 }
 
@@ -101,7 +111,7 @@ impl MultiKeyListener for AuthChallengeListenerConversation {
     }
 
     fn on_message(
-        _state: &mut crate::router::MultiKeyListenerAdapter<Self>,
+        state: &mut crate::router::MultiKeyListenerAdapter<Self>,
         event: &crate::router::CleartextEvent,
         content: &Self::Message,
     ) -> Result<Response, Self::Error> {
@@ -117,7 +127,13 @@ impl MultiKeyListener for AuthChallengeListenerConversation {
         }
 
         let service_key = if let Some(subkey_proof) = &content.subkey_proof {
-            if let Err(e) = subkey_proof.verify(&event.pubkey) {
+            let user_key = user_main_key(state.local_key, state.subkey_proof.as_ref());
+            if let Err(e) = subkey_proof.authorize(
+                &event.pubkey,
+                &state.revoked_subkeys,
+                &SubkeyOperation::Auth,
+                Some(&user_key),
+            ) {
                 log::warn!("Ignoring request with invalid subkey proof: {}", e);
                 return Ok(Response::default());
             }
@@ -127,12 +143,25 @@ impl MultiKeyListener for AuthChallengeListenerConversation {
             event.pubkey.into()
         };
 
+        let permissions = content
+            .required_permissions
+            .iter()
+            .filter_map(|p| match p.parse::<Permission>() {
+                Ok(p) => Some(p),
+                Err(e) => {
+                    log::warn!("Ignoring required permission: {}", e);
+                    None
+                }
+            })
+            .collect();
+
         let response = Response::new().notify(AuthChallengeEvent {
             service_key,
             recipient: event.pubkey.into(),
             challenge: content.challenge.clone(),
             expires_at: content.expires_at.as_u64(),
             required_permissions: content.required_permissions.clone(),
+            permissions,
             event_id: event.id.to_string(),
         });
 
@@ -193,3 +222,100 @@ impl OneShotSender for AuthResponseConversation {
         Ok(response)
     }
 }
+
+pub struct AuthSuccessListenerConversation {
+    local_key: PublicKey,
+}
+
+impl AuthSuccessListenerConversation {
+    pub fn new(local_key: PublicKey) -> Self {
+        Self { local_key }
+    }
+}
+
+/// A session opened (or refreshed) by a service after an approved auth challenge
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "bindings", derive(uniffi::Record))]
+pub struct AuthSuccessEvent {
+    pub service_key: bindings::PublicKey,
+    pub challenge: String,
+    pub session_id: String,
+    pub session_token: String,
+    pub granted_permissions: Vec<String>,
+    pub expires_at: u64,
+}
+
+impl MultiKeyListener for AuthSuccessListenerConversation {
+    const VALIDITY_SECONDS: Option<u64> = None;
+
+    type Error = ConversationError;
+    type Message = AuthSuccessContent;
+
+    fn init(state: &crate::router::MultiKeyListenerAdapter<Self>) -> Result<Response, Self::Error> {
+        let mut filter = Filter::new()
+            .kinds(vec![Kind::from(AUTH_SUCCESS)])
+            .pubkey(state.local_key);
+
+        if let Some(subkey_proof) = &state.subkey_proof {
+            filter = filter.pubkey(subkey_proof.main_key.into());
+        }
+
+        Ok(Response::new().filter(filter))
+    }
+
+    fn on_message(
+        state: &mut crate::router::MultiKeyListenerAdapter<Self>,
+        event: &crate::router::CleartextEvent,
+        content: &Self::Message,
+    ) -> Result<Response, Self::Error> {
+        let service_key = if let Some(subkey_proof) = &content.subkey_proof {
+            let user_key = user_main_key(state.local_key, state.subkey_proof.as_ref());
+            if let Err(e) = subkey_proof.authorize(
+                &event.pubkey,
+                &state.revoked_subkeys,
+                &SubkeyOperation::Auth,
+                Some(&user_key),
+            ) {
+                log::warn!("Ignoring auth success with invalid subkey proof: {}", e);
+                return Ok(Response::default());
+            }
+
+            *subkey_proof.main_key
+        } else {
+            event.pubkey
+        };
+
+        // The token is signed with the key the service sends its events with
+        let session = match Session::verify(&event.pubkey, &content.session_token) {
+            Ok(session) => session,
+            Err(e) => {
+                log::warn!("Ignoring auth success with invalid session token: {}", e);
+                return Ok(Response::default());
+            }
+        };
+
+        let user_key = session.claims.user_key;
+        let for_us = user_key == state.local_key
+            || state
+                .subkey_proof
+                .as_ref()
+                .is_some_and(|p| user_key == *p.main_key);
+        if session.claims.service_key != service_key || !for_us {
+            log::warn!("Ignoring session token issued for other keys");
+            return Ok(Response::default());
+        }
+
+        Ok(Response::new().notify(AuthSuccessEvent {
+            service_key: service_key.into(),
+            challenge: content.challenge.clone(),
+            session_id: session.claims.session_id,
+            session_token: session.token,
+            granted_permissions: session.claims.granted_permissions,
+            expires_at: session.expires_at.as_u64(),
+        }))
+    }
+}
+
+impl ConversationWithNotification for MultiKeyListenerAdapter<AuthSuccessListenerConversation> {
+    type Notification = AuthSuccessEvent;
+}
@@ -79,7 +79,7 @@ impl MultiKeyListener for PaymentRequestListenerConversation {
         }
 
         let service_key = if let Some(subkey_proof) = state.subkey_proof.clone() {
-            if let Err(e) = subkey_proof.verify(&event.pubkey) {
+            if let Err(e) = subkey_proof.verify_with(&event.pubkey, &state.revoked_subkeys) {
                 log::warn!("Ignoring request with invalid subkey proof: {}", e);
                 return Ok(Response::default());
             }
@@ -6,18 +6,23 @@ use nostr::{
     key::PublicKey,
 };
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
-    protocol::model::{
-        auth::SubkeyProof,
-        event_kinds::{CASHU_DIRECT, CASHU_REQUEST, CASHU_RESPONSE},
-        payment::{
-            CashuDirectContent, CashuDirectContentWithKey, CashuRequestContent,
-            CashuRequestContentWithKey, CashuResponseContent,
+    protocol::{
+        model::{
+            auth::SubkeyProof,
+            event_kinds::{CASHU_DIRECT, CASHU_REQUEST, CASHU_RESPONSE},
+            payment::{
+                CashuDirectContent, CashuDirectContentWithKey, CashuRequestContent,
+                CashuRequestContentWithKey, CashuResponseContent,
+            },
         },
+        subkey::{SubkeyError, SubkeyOperation},
     },
     router::{
         ConversationError, MultiKeyListener, MultiKeyListenerAdapter, MultiKeySender,
-        MultiKeySenderAdapter, Response,
+        MultiKeySenderAdapter, PersistentState, Response,
         adapters::{ConversationWithNotification, one_shot::OneShotSender},
     },
 };
@@ -25,7 +30,7 @@ use crate::{
 /// Sender conversation to request a Cashu token.
 ///
 /// Notifies the receiver with a [`CashuResponseContent`] event.
-#[derive(derive_new::new)]
+#[derive(derive_new::new, Serialize, Deserialize)]
 pub struct CashuRequestSenderConversation {
     local_key: PublicKey,
     subkey_proof: Option<SubkeyProof>,
@@ -81,6 +86,19 @@ impl MultiKeySender for CashuRequestSenderConversation {
         }
     }
 
+    fn authorize_subkey(
+        state: &crate::router::MultiKeySenderAdapter<Self>,
+        proof: &SubkeyProof,
+    ) -> Result<(), SubkeyError> {
+        let service_key = state
+            .subkey_proof
+            .as_ref()
+            .map_or(state.local_key, |p| p.main_key.into());
+        proof
+            .metadata
+            .authorize(&SubkeyOperation::Cashu, Some(&service_key))
+    }
+
     fn on_message(
         state: &mut crate::router::MultiKeySenderAdapter<Self>,
         _event: &crate::router::CleartextEvent,
@@ -98,6 +116,10 @@ impl ConversationWithNotification for MultiKeySenderAdapter<CashuRequestSenderCo
     type Notification = CashuResponseContent;
 }
 
+impl PersistentState for CashuRequestSenderConversation {
+    const CONVERSATION_TYPE: &'static str = "cashu_request_sender";
+}
+
 /// Receiver conversation to receive a [`CashuRequestContent`].
 ///
 /// Notifies the sender with a [`CashuRequestContentWithKey`] event.
@@ -130,7 +152,10 @@ impl MultiKeyListener for CashuRequestReceiverConversation {
         message: &Self::Message,
     ) -> Result<Response, Self::Error> {
         let sender_key = if let Some(subkey_proof) = state.subkey_proof.clone() {
-            if subkey_proof.verify(&event.pubkey).is_err() {
+            if subkey_proof
+                .verify_with(&event.pubkey, &state.revoked_subkeys)
+                .is_err()
+            {
                 return Ok(Response::default());
             }
 
@@ -0,0 +1,394 @@
+use std::{collections::HashSet, ops::Deref};
+
+use nostr::{
+    event::{EventId, Kind, Tag},
+    filter::Filter,
+    key::PublicKey,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    protocol::{
+        model::{
+            auth::SubkeyProof,
+            event_kinds::{
+                CERTIFICATE_DELIVERY, CERTIFICATE_REQUEST, CERTIFICATE_RESPONSE,
+                CERTIFICATE_REVOCATION,
+            },
+            identity::{
+                CertificateDeliveryContent, CertificateDeliveryContentWithKey,
+                CertificateRequestContent, CertificateRequestContentWithKey,
+                CertificateResponseContent,
+            },
+        },
+        revocation::RevocationList,
+    },
+    router::{
+        Conversation, ConversationError, ConversationMessage, MultiKeyListener,
+        MultiKeyListenerAdapter, MultiKeySender, MultiKeySenderAdapter, PersistentState, Response,
+        adapters::{ConversationWithNotification, one_shot::OneShotSender},
+    },
+};
+
+/// Sender conversation to ask a user to reveal fields from their certificates.
+///
+/// Notifies the receiver with a [`CertificateResponseContent`] event.
+#[derive(derive_new::new, Serialize, Deserialize)]
+pub struct CertificateRequestSenderConversation {
+    local_key: PublicKey,
+    subkey_proof: Option<SubkeyProof>,
+
+    content: CertificateRequestContent,
+}
+
+impl MultiKeySender for CertificateRequestSenderConversation {
+    const VALIDITY_SECONDS: Option<u64> = Some(60 * 5);
+
+    type Error = ConversationError;
+    type Message = CertificateResponseContent;
+
+    fn get_filter(
+        state: &crate::router::MultiKeySenderAdapter<Self>,
+    ) -> Result<Filter, Self::Error> {
+        let mut filter = Filter::new()
+            .kinds(vec![Kind::Custom(CERTIFICATE_RESPONSE)])
+            .authors(state.subkeys.iter().chain([&state.user]).cloned())
+            .pubkey(state.local_key);
+
+        if let Some(subkey_proof) = &state.subkey_proof {
+            filter = filter.pubkey(subkey_proof.main_key.into());
+        }
+
+        Ok(filter)
+    }
+
+    fn build_initial_message(
+        state: &mut crate::router::MultiKeySenderAdapter<Self>,
+        new_key: Option<PublicKey>,
+    ) -> Result<Response, Self::Error> {
+        let tags = state
+            .subkeys
+            .iter()
+            .chain([&state.user])
+            .map(|k| Tag::public_key(*k))
+            .collect();
+
+        if let Some(new_key) = new_key {
+            Ok(Response::new().subscribe_to_subkey_proofs().reply_to(
+                new_key,
+                Kind::Custom(CERTIFICATE_REQUEST),
+                tags,
+                state.content.clone(),
+            ))
+        } else {
+            Ok(Response::new().subscribe_to_subkey_proofs().reply_all(
+                Kind::Custom(CERTIFICATE_REQUEST),
+                tags,
+                state.content.clone(),
+            ))
+        }
+    }
+
+    fn on_message(
+        state: &mut crate::router::MultiKeySenderAdapter<Self>,
+        _event: &crate::router::CleartextEvent,
+        message: &Self::Message,
+    ) -> Result<Response, Self::Error> {
+        if message.request_id == state.content.request_id {
+            Ok(Response::new().notify(message.clone()).finish())
+        } else {
+            Ok(Response::default())
+        }
+    }
+}
+
+impl ConversationWithNotification for MultiKeySenderAdapter<CertificateRequestSenderConversation> {
+    type Notification = CertificateResponseContent;
+}
+
+impl PersistentState for CertificateRequestSenderConversation {
+    const CONVERSATION_TYPE: &'static str = "certificate_request_sender";
+}
+
+/// Receiver conversation to receive a [`CertificateRequestContent`].
+///
+/// Notifies the receiver with a [`CertificateRequestContentWithKey`] event.
+#[derive(derive_new::new)]
+pub struct CertificateRequestReceiverConversation {
+    local_key: PublicKey,
+}
+
+impl MultiKeyListener for CertificateRequestReceiverConversation {
+    const VALIDITY_SECONDS: Option<u64> = None;
+
+    type Error = ConversationError;
+    type Message = CertificateRequestContent;
+
+    fn init(state: &crate::router::MultiKeyListenerAdapter<Self>) -> Result<Response, Self::Error> {
+        let mut filter = Filter::new()
+            .kinds(vec![Kind::Custom(CERTIFICATE_REQUEST)])
+            .pubkey(state.local_key);
+
+        if let Some(subkey_proof) = &state.subkey_proof {
+            filter = filter.pubkey(subkey_proof.main_key.into());
+        }
+
+        Ok(Response::new().filter(filter))
+    }
+
+    fn on_message(
+        state: &mut crate::router::MultiKeyListenerAdapter<Self>,
+        event: &crate::router::CleartextEvent,
+        message: &Self::Message,
+    ) -> Result<Response, Self::Error> {
+        if message.expires_at.as_u64() < nostr::Timestamp::now().as_u64() {
+            log::warn!("Ignoring expired certificate request");
+            return Ok(Response::default());
+        }
+
+        let sender_key = if let Some(subkey_proof) = state.subkey_proof.clone() {
+            if subkey_proof
+                .verify_with(&event.pubkey, &state.revoked_subkeys)
+                .is_err()
+            {
+                return Ok(Response::default());
+            }
+
+            subkey_proof.main_key
+        } else {
+            event.pubkey.into()
+        };
+
+        let res = CertificateRequestContentWithKey {
+            inner: message.clone(),
+            main_key: sender_key,
+            recipient: event.pubkey.into(),
+        };
+
+        Ok(Response::new().notify(res))
+    }
+}
+
+impl ConversationWithNotification
+    for MultiKeyListenerAdapter<CertificateRequestReceiverConversation>
+{
+    type Notification = CertificateRequestContentWithKey;
+}
+
+/// Sender conversation to reply to a certificate request.
+#[derive(derive_new::new)]
+pub struct CertificateResponseSenderConversation {
+    request: CertificateRequestContentWithKey,
+    content: CertificateResponseContent,
+}
+
+impl OneShotSender for CertificateResponseSenderConversation {
+    type Error = ConversationError;
+
+    fn send(
+        state: &mut crate::router::adapters::one_shot::OneShotSenderAdapter<Self>,
+    ) -> Result<Response, Self::Error> {
+        let mut keys = HashSet::new();
+        keys.insert(state.request.recipient);
+        keys.insert(state.request.main_key);
+
+        let tags = keys.iter().map(|k| Tag::public_key(*k.deref())).collect();
+        let response = Response::new()
+            .reply_to(
+                state.request.recipient.into(),
+                Kind::from(CERTIFICATE_RESPONSE),
+                tags,
+                state.content.clone(),
+            )
+            .finish();
+
+        Ok(response)
+    }
+}
+
+/// Sender conversation to deliver a newly issued certificate to its subject.
+#[derive(derive_new::new)]
+pub struct CertificateDeliverySenderConversation {
+    content: CertificateDeliveryContent,
+}
+
+impl MultiKeySender for CertificateDeliverySenderConversation {
+    const VALIDITY_SECONDS: Option<u64> = Some(60 * 5);
+
+    type Error = ConversationError;
+    type Message = ();
+
+    fn get_filter(
+        _state: &crate::router::MultiKeySenderAdapter<Self>,
+    ) -> Result<Filter, Self::Error> {
+        // Empty filter that will not match any events
+        Ok(Filter::new().id(EventId::all_zeros()))
+    }
+
+    fn build_initial_message(
+        state: &mut crate::router::MultiKeySenderAdapter<Self>,
+        new_key: Option<PublicKey>,
+    ) -> Result<Response, Self::Error> {
+        let tags = state
+            .subkeys
+            .iter()
+            .chain([&state.user])
+            .map(|k| Tag::public_key(*k))
+            .collect();
+
+        if let Some(new_key) = new_key {
+            Ok(Response::new().subscribe_to_subkey_proofs().reply_to(
+                new_key,
+                Kind::Custom(CERTIFICATE_DELIVERY),
+                tags,
+                state.content.clone(),
+            ))
+        } else {
+            Ok(Response::new().subscribe_to_subkey_proofs().reply_all(
+                Kind::Custom(CERTIFICATE_DELIVERY),
+                tags,
+                state.content.clone(),
+            ))
+        }
+    }
+
+    fn on_message(
+        _state: &mut crate::router::MultiKeySenderAdapter<Self>,
+        _event: &crate::router::CleartextEvent,
+        _message: &Self::Message,
+    ) -> Result<Response, Self::Error> {
+        Ok(Response::default())
+    }
+}
+
+/// Receiver conversation to receive certificates issued to the user.
+///
+/// Notifies the receiver with a [`CertificateDeliveryContentWithKey`] event.
+#[derive(derive_new::new)]
+pub struct CertificateDeliveryReceiverConversation {
+    local_key: PublicKey,
+}
+
+impl MultiKeyListener for CertificateDeliveryReceiverConversation {
+    const VALIDITY_SECONDS: Option<u64> = None;
+
+    type Error = ConversationError;
+    type Message = CertificateDeliveryContent;
+
+    fn init(state: &crate::router::MultiKeyListenerAdapter<Self>) -> Result<Response, Self::Error> {
+        let mut filter = Filter::new()
+            .kinds(vec![Kind::from(CERTIFICATE_DELIVERY)])
+            .pubkey(state.local_key);
+
+        if let Some(subkey_proof) = &state.subkey_proof {
+            filter = filter.pubkey(subkey_proof.main_key.into());
+        }
+
+        Ok(Response::new().filter(filter))
+    }
+
+    fn on_message(
+        state: &mut crate::router::MultiKeyListenerAdapter<Self>,
+        event: &crate::router::CleartextEvent,
+        message: &Self::Message,
+    ) -> Result<Response, Self::Error> {
+        let main_key = match &state.subkey_proof {
+            Some(subkey_proof) => subkey_proof.main_key,
+            None => event.pubkey.into(),
+        };
+
+        let res = CertificateDeliveryContentWithKey {
+            inner: message.clone(),
+            main_key,
+            recipient: event.pubkey.into(),
+        };
+
+        Ok(Response::new().notify(res))
+    }
+}
+
+impl ConversationWithNotification
+    for MultiKeyListenerAdapter<CertificateDeliveryReceiverConversation>
+{
+    type Notification = CertificateDeliveryContentWithKey;
+}
+
+/// Publishes a signed [`RevocationList`] for everyone to see.
+#[derive(derive_new::new)]
+pub struct RevocationListPublisherConversation {
+    list: RevocationList,
+}
+
+impl OneShotSender for RevocationListPublisherConversation {
+    type Error = ConversationError;
+
+    fn send(
+        state: &mut crate::router::adapters::one_shot::OneShotSenderAdapter<Self>,
+    ) -> Result<Response, Self::Error> {
+        Ok(Response::new()
+            .broadcast_unencrypted(
+                Kind::from(CERTIFICATE_REVOCATION),
+                Default::default(),
+                state.list.clone(),
+            )
+            .finish())
+    }
+}
+
+/// Listens for the revocation lists published by a set of issuers.
+///
+/// Notifies every validly signed [`RevocationList`]. It never finishes on its own.
+pub struct RevocationListListenerConversation {
+    issuers: Vec<PublicKey>,
+}
+
+impl RevocationListListenerConversation {
+    pub fn new(issuers: Vec<PublicKey>) -> Self {
+        Self { issuers }
+    }
+}
+
+impl Conversation for RevocationListListenerConversation {
+    fn init(&mut self) -> Result<Response, ConversationError> {
+        Ok(Response::new().filter(
+            Filter::new()
+                .kind(Kind::from(CERTIFICATE_REVOCATION))
+                .authors(self.issuers.iter().cloned()),
+        ))
+    }
+
+    fn on_message(&mut self, message: ConversationMessage) -> Result<Response, ConversationError> {
+        let ConversationMessage::Cleartext(event) = message else {
+            return Ok(Response::default());
+        };
+
+        let list: RevocationList = match serde_json::from_value(event.content) {
+            Ok(list) => list,
+            Err(e) => {
+                log::warn!("Ignoring invalid revocation list: {:?}", e);
+                return Ok(Response::default());
+            }
+        };
+
+        if list.issuer_pubkey != event.pubkey || !self.issuers.contains(&list.issuer_pubkey) {
+            log::warn!("Ignoring revocation list not published by its issuer");
+            return Ok(Response::default());
+        }
+
+        if let Err(e) = list.verify() {
+            log::warn!("Ignoring revocation list with invalid signature: {:?}", e);
+            return Ok(Response::default());
+        }
+
+        Ok(Response::new().notify(list))
+    }
+
+    fn is_expired(&self) -> bool {
+        false
+    }
+}
+
+impl ConversationWithNotification for RevocationListListenerConversation {
+    type Notification = RevocationList;
+}
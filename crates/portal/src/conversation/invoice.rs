@@ -3,21 +3,25 @@ use std::{collections::HashSet, ops::Deref};
 use nostr::{Tag, event::Kind, filter::Filter, key::PublicKey};
 
 use derive_new::new;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    protocol::model::{
-        auth::SubkeyProof,
-        event_kinds::{INVOICE_REQUEST, INVOICE_RESPONSE},
-        payment::{InvoiceRequestContent, InvoiceRequestContentWithKey, InvoiceResponse},
+    protocol::{
+        model::{
+            auth::SubkeyProof,
+            event_kinds::{INVOICE_REQUEST, INVOICE_RESPONSE},
+            payment::{InvoiceRequestContent, InvoiceRequestContentWithKey, InvoiceResponse},
+        },
+        subkey::{SubkeyError, SubkeyOperation},
     },
     router::{
         ConversationError, MultiKeyListener, MultiKeyListenerAdapter, MultiKeySender,
-        MultiKeySenderAdapter, Response,
+        MultiKeySenderAdapter, PersistentState, Response,
         adapters::{ConversationWithNotification, one_shot::OneShotSender},
     },
 };
 
-#[derive(new)]
+#[derive(new, Serialize, Deserialize)]
 pub struct InvoiceRequestConversation {
     local_key: PublicKey,
     subkey_proof: Option<SubkeyProof>,
@@ -73,6 +77,19 @@ impl MultiKeySender for InvoiceRequestConversation {
         }
     }
 
+    fn authorize_subkey(
+        state: &crate::router::MultiKeySenderAdapter<Self>,
+        proof: &SubkeyProof,
+    ) -> Result<(), SubkeyError> {
+        let service_key = state
+            .subkey_proof
+            .as_ref()
+            .map_or(state.local_key, |p| p.main_key.into());
+        proof
+            .metadata
+            .authorize(&SubkeyOperation::Invoice, Some(&service_key))
+    }
+
     fn on_message(
         state: &mut crate::router::MultiKeySenderAdapter<Self>,
         _event: &crate::router::CleartextEvent,
@@ -90,6 +107,10 @@ impl ConversationWithNotification for MultiKeySenderAdapter<InvoiceRequestConver
     type Notification = InvoiceResponse;
 }
 
+impl PersistentState for InvoiceRequestConversation {
+    const CONVERSATION_TYPE: &'static str = "invoice_request_sender";
+}
+
 #[derive(new)]
 pub struct InvoiceReceiverConversation {
     local_key: PublicKey,
@@ -119,7 +140,10 @@ impl MultiKeyListener for InvoiceReceiverConversation {
         message: &Self::Message,
     ) -> Result<Response, Self::Error> {
         let sender_key = if let Some(subkey_proof) = state.subkey_proof.clone() {
-            if subkey_proof.verify(&event.pubkey).is_err() {
+            if subkey_proof
+                .verify_with(&event.pubkey, &state.revoked_subkeys)
+                .is_err()
+            {
                 return Ok(Response::default());
             }
 
@@ -3,7 +3,9 @@ pub mod sdk;
 
 
 pub mod cashu;
+pub mod certificate;
 pub mod close_subscription;
 pub mod invoice;
 pub mod nip46;
-pub mod profile;
\ No newline at end of file
+pub mod profile;
+pub mod subkey;
\ No newline at end of file
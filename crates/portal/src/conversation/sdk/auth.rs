@@ -1,27 +1,31 @@
 use nostr::{
     Filter,
-    event::{Kind, Tag},
+    event::{EventId, Kind, Tag},
     key::PublicKey,
 };
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    protocol::model::{
-        Timestamp,
-        auth::{
-            AuthChallengeContent, AuthResponseContent, AuthResponseStatus, KeyHandshakeContent,
-            SubkeyProof,
+    protocol::{
+        model::{
+            Timestamp,
+            auth::{
+                AuthChallengeContent, AuthResponseContent, AuthResponseStatus, AuthSuccessContent,
+                KeyHandshakeContent, Permission, SubkeyProof,
+            },
+            event_kinds::*,
         },
-        event_kinds::*,
+        session::Session,
+        subkey::{SubkeyError, SubkeyOperation},
     },
     router::{
         ConversationError, MultiKeyListener, MultiKeyListenerAdapter, MultiKeySender,
-        MultiKeySenderAdapter, Response, adapters::ConversationWithNotification,
+        MultiKeySenderAdapter, PersistentState, Response, adapters::ConversationWithNotification,
     },
     utils::random_string,
 };
 
-#[derive(derive_new::new)]
+#[derive(derive_new::new, Serialize, Deserialize)]
 pub struct KeyHandshakeReceiverConversation {
     local_key: PublicKey,
     token: String,
@@ -74,19 +78,31 @@ impl ConversationWithNotification for MultiKeyListenerAdapter<KeyHandshakeReceiv
     type Notification = KeyHandshakeEvent;
 }
 
+impl PersistentState for KeyHandshakeReceiverConversation {
+    const CONVERSATION_TYPE: &'static str = "key_handshake_receiver";
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct AuthChallengeSenderConversation {
     local_key: PublicKey,
     subkey_proof: Option<SubkeyProof>,
 
     challenge: String,
+    #[serde(default)]
+    required_permissions: Vec<Permission>,
 }
 
 impl AuthChallengeSenderConversation {
-    pub fn new(local_key: PublicKey, subkey_proof: Option<SubkeyProof>) -> Self {
+    pub fn new(
+        local_key: PublicKey,
+        subkey_proof: Option<SubkeyProof>,
+        required_permissions: Vec<Permission>,
+    ) -> Self {
         Self {
             local_key,
             subkey_proof,
             challenge: random_string(32),
+            required_permissions,
         }
     }
 }
@@ -96,7 +112,11 @@ pub struct AuthResponseEvent {
     pub user_key: PublicKey,
     pub recipient: PublicKey,
     pub challenge: String,
+    /// Only contains granted permissions that were required, see [`Permission::covers`]
     pub status: AuthResponseStatus,
+    /// Permissions asked for in the challenge
+    #[serde(default)]
+    pub required_permissions: Vec<Permission>,
 }
 
 impl MultiKeySender for AuthChallengeSenderConversation {
@@ -127,7 +147,11 @@ impl MultiKeySender for AuthChallengeSenderConversation {
         let content = AuthChallengeContent {
             challenge: state.challenge.clone(),
             expires_at: Timestamp::now_plus_seconds(60 * 5), // TODO: should take it from state.expires_at
-            required_permissions: vec![],
+            required_permissions: state
+                .required_permissions
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
             subkey_proof: state.subkey_proof.clone(),
         };
 
@@ -154,6 +178,19 @@ impl MultiKeySender for AuthChallengeSenderConversation {
         }
     }
 
+    fn authorize_subkey(
+        state: &crate::router::MultiKeySenderAdapter<Self>,
+        proof: &SubkeyProof,
+    ) -> Result<(), SubkeyError> {
+        let service_key = state
+            .subkey_proof
+            .as_ref()
+            .map_or(state.local_key, |p| p.main_key.into());
+        proof
+            .metadata
+            .authorize(&SubkeyOperation::Auth, Some(&service_key))
+    }
+
     fn on_message(
         state: &mut crate::router::MultiKeySenderAdapter<Self>,
         event: &crate::router::CleartextEvent,
@@ -168,10 +205,14 @@ impl MultiKeySender for AuthChallengeSenderConversation {
         }
 
         let user_key = if let Some(subkey_proof) = &message.subkey_proof {
-            if let Err(e) = subkey_proof.verify(&event.pubkey) {
+            if let Err(e) = subkey_proof.verify_with(&event.pubkey, &state.revoked_subkeys) {
                 log::warn!("Ignoring response with invalid subkey proof: {}", e);
                 return Ok(Response::default());
             }
+            if let Err(e) = Self::authorize_subkey(state, subkey_proof) {
+                log::warn!("Ignoring response from a subkey not allowed to: {}", e);
+                return Ok(Response::default());
+            }
 
             let main_key = subkey_proof.main_key.into();
             state.user = main_key;
@@ -182,12 +223,52 @@ impl MultiKeySender for AuthChallengeSenderConversation {
             event.pubkey
         };
 
+        let status = match &message.status {
+            AuthResponseStatus::Approved {
+                granted_permissions,
+                session_token,
+            } => {
+                // The user can't grant more than what was asked for
+                let granted_permissions = granted_permissions
+                    .iter()
+                    .filter_map(|p| match p.parse::<Permission>() {
+                        Ok(p) if Permission::is_granted(&state.required_permissions, &p) => {
+                            Some(p.to_string())
+                        }
+                        _ => {
+                            log::warn!("Ignoring permission {} granted by {}", p, user_key);
+                            None
+                        }
+                    })
+                    .collect();
+
+                // Only a valid session of this user can be refreshed, other tokens are dropped
+                let valid = !session_token.is_empty()
+                    && Session::verify(&state.local_key, session_token)
+                        .is_ok_and(|session| session.claims.user_key == user_key);
+                if !session_token.is_empty() && !valid {
+                    log::warn!("Ignoring invalid session token presented by {}", user_key);
+                }
+
+                AuthResponseStatus::Approved {
+                    granted_permissions,
+                    session_token: if valid {
+                        session_token.clone()
+                    } else {
+                        String::new()
+                    },
+                }
+            }
+            status => status.clone(),
+        };
+
         Ok(Response::new()
             .notify(AuthResponseEvent {
                 user_key,
                 recipient: event.pubkey,
                 challenge: message.challenge.clone(),
-                status: message.status.clone(),
+                status,
+                required_permissions: state.required_permissions.clone(),
             })
             .finish())
     }
@@ -196,3 +277,62 @@ impl MultiKeySender for AuthChallengeSenderConversation {
 impl ConversationWithNotification for MultiKeySenderAdapter<AuthChallengeSenderConversation> {
     type Notification = AuthResponseEvent;
 }
+
+impl PersistentState for AuthChallengeSenderConversation {
+    const CONVERSATION_TYPE: &'static str = "auth_challenge_sender";
+}
+
+/// Sender conversation to deliver the session token of an approved auth challenge.
+#[derive(derive_new::new)]
+pub struct AuthSuccessSenderConversation {
+    content: AuthSuccessContent,
+}
+
+impl MultiKeySender for AuthSuccessSenderConversation {
+    const VALIDITY_SECONDS: Option<u64> = Some(60 * 5);
+
+    type Error = ConversationError;
+    type Message = ();
+
+    fn get_filter(
+        _state: &crate::router::MultiKeySenderAdapter<Self>,
+    ) -> Result<Filter, Self::Error> {
+        // Empty filter that will not match any events
+        Ok(Filter::new().id(EventId::all_zeros()))
+    }
+
+    fn build_initial_message(
+        state: &mut crate::router::MultiKeySenderAdapter<Self>,
+        new_key: Option<PublicKey>,
+    ) -> Result<Response, Self::Error> {
+        let tags = state
+            .subkeys
+            .iter()
+            .chain([&state.user])
+            .map(|k| Tag::public_key(*k))
+            .collect();
+
+        if let Some(new_key) = new_key {
+            Ok(Response::new().subscribe_to_subkey_proofs().reply_to(
+                new_key,
+                Kind::from(AUTH_SUCCESS),
+                tags,
+                state.content.clone(),
+            ))
+        } else {
+            Ok(Response::new().subscribe_to_subkey_proofs().reply_all(
+                Kind::from(AUTH_SUCCESS),
+                tags,
+                state.content.clone(),
+            ))
+        }
+    }
+
+    fn on_message(
+        _state: &mut crate::router::MultiKeySenderAdapter<Self>,
+        _event: &crate::router::CleartextEvent,
+        _message: &Self::Message,
+    ) -> Result<Response, Self::Error> {
+        Ok(Response::default())
+    }
+}
@@ -8,17 +8,20 @@ use crate::{
             RecurringPaymentResponseContent, SinglePaymentRequestContent,
         },
     },
+    protocol::subkey::{SubkeyError, SubkeyOperation},
     router::{
-        ConversationError, MultiKeySender, MultiKeySenderAdapter, Response,
+        ConversationError, MultiKeySender, MultiKeySenderAdapter, PersistentState, Response,
         adapters::ConversationWithNotification,
     },
 };
+use serde::{Deserialize, Serialize};
 use nostr::{
     Filter,
     event::{Kind, Tag},
     key::PublicKey,
 };
 
+#[derive(Serialize, Deserialize)]
 pub struct RecurringPaymentRequestSenderConversation {
     local_key: PublicKey,
     subkey_proof: Option<SubkeyProof>,
@@ -92,6 +95,20 @@ impl MultiKeySender for RecurringPaymentRequestSenderConversation {
         }
     }
 
+    fn authorize_subkey(
+        state: &crate::router::MultiKeySenderAdapter<Self>,
+        proof: &SubkeyProof,
+    ) -> Result<(), SubkeyError> {
+        let service_key = state
+            .subkey_proof
+            .as_ref()
+            .map_or(state.local_key, |p| p.main_key.into());
+        let operation = SubkeyOperation::Payment {
+            amount_msat: state.payment_request.amount_millisats(),
+        };
+        proof.metadata.authorize(&operation, Some(&service_key))
+    }
+
     fn on_message(
         state: &mut crate::router::MultiKeySenderAdapter<Self>,
         _event: &crate::router::CleartextEvent,
@@ -111,6 +128,11 @@ impl ConversationWithNotification
     type Notification = RecurringPaymentResponseContent;
 }
 
+impl PersistentState for RecurringPaymentRequestSenderConversation {
+    const CONVERSATION_TYPE: &'static str = "recurring_payment_request_sender";
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct SinglePaymentRequestSenderConversation {
     local_key: PublicKey,
     subkey_proof: Option<SubkeyProof>,
@@ -189,6 +211,20 @@ impl MultiKeySender for SinglePaymentRequestSenderConversation {
         }
     }
 
+    fn authorize_subkey(
+        state: &crate::router::MultiKeySenderAdapter<Self>,
+        proof: &SubkeyProof,
+    ) -> Result<(), SubkeyError> {
+        let service_key = state
+            .subkey_proof
+            .as_ref()
+            .map_or(state.local_key, |p| p.main_key.into());
+        let operation = SubkeyOperation::Payment {
+            amount_msat: state.payment_request.amount_millisats(),
+        };
+        proof.metadata.authorize(&operation, Some(&service_key))
+    }
+
     fn on_message(
         state: &mut crate::router::MultiKeySenderAdapter<Self>,
         _event: &crate::router::CleartextEvent,
@@ -221,3 +257,7 @@ impl ConversationWithNotification
 {
     type Notification = PaymentResponseContent;
 }
+
+impl PersistentState for SinglePaymentRequestSenderConversation {
+    const CONVERSATION_TYPE: &'static str = "single_payment_request_sender";
+}
@@ -0,0 +1,125 @@
+//! Subkey revocation
+//!
+//! A main key revokes its subkeys by publishing a `SUBKEY_REVOCATION` event per subkey, tagged
+//! with the subkey in both the `d` and `p` tags. The listener records every revocation it sees in
+//! the [`RevokedSubkeys`] of its router, which the conversations of the router check with
+//! [`crate::protocol::model::auth::SubkeyProof::verify_with`].
+
+use std::sync::Arc;
+
+use nostr::{
+    event::{Kind, Tag},
+    filter::Filter,
+    key::PublicKey,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    protocol::{
+        model::event_kinds::SUBKEY_REVOCATION,
+        subkey::{RevokedSubkeys, SubkeyRevocation},
+    },
+    router::{
+        Conversation, ConversationError, ConversationMessage, Response,
+        adapters::{ConversationWithNotification, one_shot::OneShotSender},
+    },
+};
+
+/// Publishes the revocation of subkeys of the local key, which must be the main key
+#[derive(derive_new::new)]
+pub struct SubkeyRevocationPublisherConversation {
+    revocations: Vec<SubkeyRevocation>,
+}
+
+impl OneShotSender for SubkeyRevocationPublisherConversation {
+    type Error = ConversationError;
+
+    fn send(
+        state: &mut crate::router::adapters::one_shot::OneShotSenderAdapter<Self>,
+    ) -> Result<Response, Self::Error> {
+        let response = state
+            .revocations
+            .iter()
+            .fold(Response::new(), |response, revocation| {
+                let tags = vec![
+                    Tag::identifier(revocation.subkey.to_hex()),
+                    Tag::public_key(revocation.subkey),
+                ]
+                .into_iter()
+                .collect();
+                response.broadcast_unencrypted(Kind::from(SUBKEY_REVOCATION), tags, revocation)
+            });
+
+        Ok(response.finish())
+    }
+}
+
+/// A subkey revoked by its main key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubkeyRevocationEvent {
+    pub main_key: PublicKey,
+    pub revocation: SubkeyRevocation,
+}
+
+/// Listens for subkey revocations, recording them in the [`RevokedSubkeys`] of its router
+///
+/// Listens for the revocations of all the main keys if `main_keys` is empty. It never finishes
+/// on its own.
+pub struct SubkeyRevocationListenerConversation {
+    main_keys: Vec<PublicKey>,
+    revoked: Arc<RevokedSubkeys>,
+}
+
+impl SubkeyRevocationListenerConversation {
+    pub fn new(main_keys: Vec<PublicKey>) -> Self {
+        Self {
+            main_keys,
+            revoked: Arc::default(),
+        }
+    }
+}
+
+impl Conversation for SubkeyRevocationListenerConversation {
+    fn init(&mut self) -> Result<Response, ConversationError> {
+        let mut filter = Filter::new().kind(Kind::from(SUBKEY_REVOCATION));
+        if !self.main_keys.is_empty() {
+            filter = filter.authors(self.main_keys.iter().cloned());
+        }
+
+        Ok(Response::new().filter(filter))
+    }
+
+    fn on_message(&mut self, message: ConversationMessage) -> Result<Response, ConversationError> {
+        let ConversationMessage::Cleartext(event) = message else {
+            return Ok(Response::default());
+        };
+
+        let revocation: SubkeyRevocation = match serde_json::from_value(event.content) {
+            Ok(revocation) => revocation,
+            Err(e) => {
+                log::warn!("Ignoring invalid subkey revocation: {:?}", e);
+                return Ok(Response::default());
+            }
+        };
+
+        log::debug!("Subkey {} revoked by {}", revocation.subkey, event.pubkey);
+        self.revoked.insert(event.pubkey, revocation.clone());
+
+        Ok(Response::new().notify(SubkeyRevocationEvent {
+            main_key: event.pubkey,
+            revocation,
+        }))
+    }
+
+    fn is_expired(&self) -> bool {
+        false
+    }
+
+    fn set_revoked_subkeys(&mut self, revoked: Arc<RevokedSubkeys>) {
+        self.revoked = revoked;
+    }
+}
+
+impl ConversationWithNotification for SubkeyRevocationListenerConversation {
+    type Notification = SubkeyRevocationEvent;
+}
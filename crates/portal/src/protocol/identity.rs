@@ -58,6 +58,11 @@ pub struct Certificate {
     pub version: u32,
     pub subject: nostr::PublicKey,
     pub data: CertificateData,
+    /// Claims derived from `data` by the issuer, committed under the `predicates.` prefix.
+    ///
+    /// See [`crate::protocol::predicate`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub predicates: BTreeMap<String, serde_json::Value>,
     pub metadata: CertificateMetadata,
     pub signature: String,
 }
@@ -92,6 +97,17 @@ pub enum CertificateData {
     },
 }
 
+impl CertificateData {
+    /// Returns the type name used to tag this kind of certificate data
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            CertificateData::Person(_) => "personal",
+            CertificateData::Business(_) => "business",
+            CertificateData::Custom { .. } => "custom",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SaltSequence {
     pub salt_size: usize,
@@ -269,6 +285,9 @@ impl MerkleProofNode {
     }
 }
 
+/// Prefix of the fields holding the predicate claims of a certificate
+pub const PREDICATES_PREFIX: &str = "predicates";
+
 impl Certificate {
     /// Creates a new certificate with the correct merkle root computed from its fields
     pub fn new(
@@ -277,12 +296,25 @@ impl Certificate {
         data: CertificateData,
         metadata: CertificateMetadata,
         signature: String,
+    ) -> Result<Self, RevealError> {
+        Self::new_with_predicates(version, subject, data, BTreeMap::new(), metadata, signature)
+    }
+
+    /// Creates a new certificate that also commits to the given predicate claims
+    pub fn new_with_predicates(
+        version: u32,
+        subject: nostr::PublicKey,
+        data: CertificateData,
+        predicates: BTreeMap<String, serde_json::Value>,
+        metadata: CertificateMetadata,
+        signature: String,
     ) -> Result<Self, RevealError> {
         // Create a temporary certificate to compute the merkle root
         let temp = Self {
             version,
             subject,
             data,
+            predicates,
             metadata,
             signature,
         };
@@ -303,6 +335,7 @@ impl Certificate {
             version: temp.version,
             subject: temp.subject,
             data: temp.data,
+            predicates: temp.predicates,
             metadata,
             signature: temp.signature,
         })
@@ -317,6 +350,14 @@ impl Certificate {
         let mut fields = BTreeMap::new();
         flatten_json("", &value, &mut fields)?;
 
+        // Predicates are committed alongside the data fields, so that they can be revealed
+        // on their own
+        if !self.predicates.is_empty() {
+            let predicates =
+                serde_json::Value::Object(self.predicates.clone().into_iter().collect());
+            flatten_json(PREDICATES_PREFIX, &predicates, &mut fields)?;
+        }
+
         Ok(PreparedCertificate {
             version: self.version,
             fields,
@@ -364,6 +405,25 @@ impl Certificate {
 
         Ok(())
     }
+
+    /// Creates a partial certificate that only reveals the specified fields.
+    ///
+    /// Fields that don't exist in the certificate are ignored.
+    pub fn create_partial(
+        &self,
+        reveal_fields: &[String],
+    ) -> Result<PartialCertificate, RevealError> {
+        let prepared = self.prepare_for_revealing()?;
+        let merkle_proof = prepared.create_proof(&self.metadata.salt_sequence, reveal_fields)?;
+
+        Ok(PartialCertificate {
+            version: self.version,
+            subject: self.subject,
+            metadata: self.metadata.clone(),
+            signature: self.signature.clone(),
+            merkle_proof,
+        })
+    }
 }
 
 impl PartialCertificate {
@@ -708,6 +768,13 @@ pub struct CertificateMetadata {
     pub merkle_root: MerkleRoot,
 }
 
+impl CertificateMetadata {
+    /// Returns the identifier of the certificate, used to reference it in revocation lists
+    pub fn certificate_id(&self) -> String {
+        hex::encode(self.merkle_root.as_bytes())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PersonData {
     pub full_name: String,
@@ -1153,6 +1220,7 @@ mod tests {
             version: 1,
             subject,
             data: CertificateData::Custom { data: custom_data },
+            predicates: BTreeMap::new(),
             metadata: CertificateMetadata {
                 issuer_pubkey: issuer,
                 issued_at: Timestamp::new(1234567890),
@@ -1270,6 +1338,7 @@ mod tests {
             version: 1,
             subject,
             data: CertificateData::Custom { data: nested_value },
+            predicates: BTreeMap::new(),
             metadata: CertificateMetadata {
                 issuer_pubkey: issuer,
                 issued_at: Timestamp::new(1234567890),
@@ -1325,6 +1394,7 @@ mod tests {
             version: 1,
             subject,
             data: CertificateData::Custom { data: custom_data },
+            predicates: BTreeMap::new(),
             metadata: CertificateMetadata {
                 issuer_pubkey: issuer,
                 issued_at: Timestamp::new(1234567890),
@@ -1445,6 +1515,7 @@ mod tests {
             version: 1,
             subject,
             data: CertificateData::Custom { data: custom_data },
+            predicates: BTreeMap::new(),
             metadata: CertificateMetadata {
                 issuer_pubkey: issuer,
                 issued_at: Timestamp::new(1234567890),
@@ -1756,4 +1827,22 @@ mod tests {
             Err(VerifyError::InvalidMerkleRoot)
         ));
     }
+
+    #[test]
+    fn test_create_partial() {
+        let mut cert = create_test_person_certificate();
+        let issuer_key = nostr::Keys::generate();
+        cert.metadata.issuer_pubkey = issuer_key.public_key();
+        cert.sign(&issuer_key).expect("Failed to sign certificate");
+
+        let partial = cert
+            .create_partial(&["nationality".to_string(), "missing".to_string()])
+            .unwrap();
+        let result = partial.verify().unwrap();
+
+        assert_eq!(cert.data.type_name(), "personal");
+        assert_eq!(result.get("nationality").unwrap().as_str().unwrap(), "US");
+        assert!(result.get("full_name").is_none());
+        assert!(result.get("missing").is_none());
+    }
 }
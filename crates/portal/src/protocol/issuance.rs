@@ -0,0 +1,410 @@
+//! Certificate issuance
+//!
+//! [`CertificateBuilder`] is the recommended way to mint an identity certificate: it validates the
+//! certificate data against its schema, generates a fresh salt for every field and computes the
+//! merkle root before signing.
+
+use std::collections::BTreeMap;
+
+use rand::RngCore;
+
+use crate::protocol::{
+    identity::{
+        Address, BusinessData, Certificate, CertificateData, CertificateMetadata, MerkleRoot,
+        PREDICATES_PREFIX, PersonData, RevealError, SaltSequence, SignError, VerificationLevel,
+        VerificationMethod,
+    },
+    model::Timestamp,
+    predicate::{Predicate, PredicateError},
+};
+
+/// Size in bytes of each salt generated by the builder
+pub const SALT_SIZE: usize = 32;
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Debug, thiserror::Error)]
+pub enum IssuanceError {
+    #[error("Missing required value: {0}")]
+    Missing(&'static str),
+
+    #[error("Invalid field '{field}': {reason}")]
+    InvalidField { field: String, reason: String },
+
+    #[error("Certificate expires before it is issued")]
+    InvalidValidity,
+
+    #[error("Predicate error: {0}")]
+    Predicate(#[from] PredicateError),
+
+    #[error("Reveal error: {0}")]
+    Reveal(#[from] RevealError),
+
+    #[error("Sign error: {0}")]
+    Sign(#[from] SignError),
+}
+
+fn invalid(field: &str, reason: impl Into<String>) -> IssuanceError {
+    IssuanceError::InvalidField {
+        field: field.to_string(),
+        reason: reason.into(),
+    }
+}
+
+fn require_non_empty(field: &str, value: &str) -> Result<(), IssuanceError> {
+    if value.trim().is_empty() {
+        return Err(invalid(field, "must not be empty"));
+    }
+    Ok(())
+}
+
+fn require_date(field: &str, value: &str) -> Result<chrono::NaiveDate, IssuanceError> {
+    chrono::NaiveDate::parse_from_str(value, DATE_FORMAT)
+        .map_err(|_| invalid(field, "must be a date in the YYYY-MM-DD format"))
+}
+
+fn require_country_code(field: &str, value: &str) -> Result<(), IssuanceError> {
+    if value.len() != 2 || !value.chars().all(|c| c.is_ascii_uppercase()) {
+        return Err(invalid(field, "must be an ISO 3166-1 alpha-2 country code"));
+    }
+    Ok(())
+}
+
+fn validate_address(prefix: &str, address: &Address) -> Result<(), IssuanceError> {
+    require_non_empty(&format!("{prefix}.street"), &address.street)?;
+    require_non_empty(&format!("{prefix}.city"), &address.city)?;
+    require_non_empty(&format!("{prefix}.postal_code"), &address.postal_code)?;
+    require_country_code(&format!("{prefix}.country"), &address.country)?;
+    Ok(())
+}
+
+impl PersonData {
+    /// Checks that the data follows the `personal` certificate schema
+    pub fn validate(&self) -> Result<(), IssuanceError> {
+        require_non_empty("full_name", &self.full_name)?;
+        let date_of_birth = require_date("date_of_birth", &self.date_of_birth)?;
+        if date_of_birth > chrono::Utc::now().date_naive() {
+            return Err(invalid("date_of_birth", "must not be in the future"));
+        }
+        require_country_code("nationality", &self.nationality)?;
+        require_non_empty("document_type", &self.document_type)?;
+        require_non_empty("document_number", &self.document_number)?;
+
+        let issue_date = self
+            .issue_date
+            .as_deref()
+            .map(|d| require_date("issue_date", d))
+            .transpose()?;
+        let expiry_date = self
+            .expiry_date
+            .as_deref()
+            .map(|d| require_date("expiry_date", d))
+            .transpose()?;
+        if let (Some(issue_date), Some(expiry_date)) = (issue_date, expiry_date)
+            && expiry_date < issue_date
+        {
+            return Err(invalid("expiry_date", "must not be before the issue date"));
+        }
+
+        if let Some(address) = &self.address {
+            validate_address("address", address)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl BusinessData {
+    /// Checks that the data follows the `business` certificate schema
+    pub fn validate(&self) -> Result<(), IssuanceError> {
+        require_non_empty("legal_name", &self.legal_name)?;
+        require_non_empty("registration_number", &self.registration_number)?;
+        require_non_empty("jurisdiction", &self.jurisdiction)?;
+        require_date("incorporation_date", &self.incorporation_date)?;
+        require_non_empty("business_type", &self.business_type)?;
+        validate_address("address", &self.address)?;
+
+        if let Some(email) = &self.contact.email
+            && !email.contains('@')
+        {
+            return Err(invalid("contact.email", "must be a valid email address"));
+        }
+
+        Ok(())
+    }
+}
+
+impl CertificateData {
+    /// Checks that the data follows the schema of its certificate type
+    pub fn validate(&self) -> Result<(), IssuanceError> {
+        match self {
+            CertificateData::Person(data) => data.validate(),
+            CertificateData::Business(data) => data.validate(),
+            CertificateData::Custom { data } => {
+                let Some(object) = data.as_object() else {
+                    return Err(invalid("data", "custom data must be a JSON object"));
+                };
+                if object.is_empty() {
+                    return Err(invalid("data", "must contain at least one field"));
+                }
+                // The "type" key is used to tag the certificate type
+                for reserved in ["type", PREDICATES_PREFIX] {
+                    if object.contains_key(reserved) {
+                        return Err(invalid(reserved, "reserved field name"));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Builder for new certificates
+///
+/// ```ignore
+/// let certificate = CertificateBuilder::new(issuer_keys.public_key(), subject)
+///     .person(person_data)
+///     .verification(VerificationLevel::High, VerificationMethod::DocumentUpload)
+///     .valid_for(60 * 60 * 24 * 365)
+///     .sign(&issuer_keys)?;
+/// ```
+pub struct CertificateBuilder {
+    issuer: nostr::PublicKey,
+    subject: nostr::PublicKey,
+    data: Option<CertificateData>,
+    predicates: Vec<Predicate>,
+    issued_at: Option<Timestamp>,
+    expires_at: Option<Timestamp>,
+    verification_level: Option<VerificationLevel>,
+    verification_method: Option<VerificationMethod>,
+}
+
+impl CertificateBuilder {
+    pub fn new(issuer: nostr::PublicKey, subject: nostr::PublicKey) -> Self {
+        Self {
+            issuer,
+            subject,
+            data: None,
+            predicates: Vec::new(),
+            issued_at: None,
+            expires_at: None,
+            verification_level: None,
+            verification_method: None,
+        }
+    }
+
+    pub fn data(mut self, data: CertificateData) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    pub fn person(self, data: PersonData) -> Self {
+        self.data(CertificateData::Person(data))
+    }
+
+    pub fn business(self, data: BusinessData) -> Self {
+        self.data(CertificateData::Business(data))
+    }
+
+    pub fn custom(self, data: serde_json::Value) -> Self {
+        self.data(CertificateData::Custom { data })
+    }
+
+    /// Adds a predicate claim, derived from the data when the certificate is built
+    pub fn predicate(mut self, predicate: Predicate) -> Self {
+        self.predicates.push(predicate);
+        self
+    }
+
+    /// Sets the issue time. Defaults to the time the certificate is built.
+    pub fn issued_at(mut self, issued_at: Timestamp) -> Self {
+        self.issued_at = Some(issued_at);
+        self
+    }
+
+    pub fn expires_at(mut self, expires_at: Timestamp) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    /// Makes the certificate expire `seconds` from now
+    pub fn valid_for(self, seconds: u64) -> Self {
+        self.expires_at(Timestamp::now_plus_seconds(seconds))
+    }
+
+    pub fn verification(mut self, level: VerificationLevel, method: VerificationMethod) -> Self {
+        self.verification_level = Some(level);
+        self.verification_method = Some(method);
+        self
+    }
+
+    /// Validates the data and builds an unsigned certificate
+    pub fn build(self) -> Result<Certificate, IssuanceError> {
+        let data = self.data.ok_or(IssuanceError::Missing("data"))?;
+        data.validate()?;
+
+        let issued_at = self.issued_at.unwrap_or_else(Timestamp::now);
+        let expires_at = self
+            .expires_at
+            .ok_or(IssuanceError::Missing("expires_at"))?;
+        if expires_at <= issued_at {
+            return Err(IssuanceError::InvalidValidity);
+        }
+
+        let verification_level = self
+            .verification_level
+            .ok_or(IssuanceError::Missing("verification_level"))?;
+        let verification_method = self
+            .verification_method
+            .ok_or(IssuanceError::Missing("verification_method"))?;
+
+        let mut predicates = BTreeMap::new();
+        for predicate in &self.predicates {
+            let value = predicate.evaluate(&data, issued_at, expires_at)?;
+            predicates.insert(predicate.claim_name(), serde_json::Value::Bool(value));
+        }
+
+        let mut metadata = CertificateMetadata {
+            issuer_pubkey: self.issuer,
+            issued_at,
+            expires_at,
+            verification_level,
+            verification_method,
+            salt_sequence: SaltSequence::new(SALT_SIZE, vec![]),
+            merkle_root: MerkleRoot::new([0u8; 32]),
+        };
+
+        // Generate one salt for each revealable field
+        let num_fields = Certificate {
+            version: 1,
+            subject: self.subject,
+            data: data.clone(),
+            predicates: predicates.clone(),
+            metadata: metadata.clone(),
+            signature: String::new(),
+        }
+        .prepare_for_revealing()?
+        .fields
+        .len();
+
+        let mut salts = vec![0u8; SALT_SIZE * num_fields];
+        rand::thread_rng().fill_bytes(&mut salts);
+        metadata.salt_sequence = SaltSequence::new(SALT_SIZE, salts);
+
+        Ok(Certificate::new_with_predicates(
+            1,
+            self.subject,
+            data,
+            predicates,
+            metadata,
+            String::new(),
+        )?)
+    }
+
+    /// Builds the certificate and signs it with the issuer key
+    pub fn sign(self, issuer_key: &nostr::Keys) -> Result<Certificate, IssuanceError> {
+        let mut certificate = self.build()?;
+        certificate.sign(issuer_key)?;
+        Ok(certificate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn person_data() -> PersonData {
+        PersonData {
+            full_name: "John Doe".to_string(),
+            date_of_birth: "1990-01-01".to_string(),
+            nationality: "US".to_string(),
+            document_type: "passport".to_string(),
+            document_number: "123456789".to_string(),
+            place_of_birth: None,
+            gender: None,
+            issue_date: Some("2020-01-01".to_string()),
+            expiry_date: Some("2030-01-01".to_string()),
+            address: None,
+        }
+    }
+
+    #[test]
+    fn test_build_and_verify() {
+        let issuer = nostr::Keys::generate();
+        let subject = nostr::Keys::generate().public_key();
+
+        let certificate = CertificateBuilder::new(issuer.public_key(), subject)
+            .person(person_data())
+            .verification(VerificationLevel::High, VerificationMethod::DocumentUpload)
+            .valid_for(3600)
+            .sign(&issuer)
+            .unwrap();
+
+        let partial = certificate
+            .create_partial(&["nationality".to_string()])
+            .unwrap();
+        let revealed = partial.verify().unwrap();
+        assert_eq!(revealed["nationality"], "US");
+        assert!(revealed.get("full_name").is_none());
+    }
+
+    #[test]
+    fn test_schema_validation() {
+        let issuer = nostr::Keys::generate();
+        let subject = nostr::Keys::generate().public_key();
+        let builder = || {
+            CertificateBuilder::new(issuer.public_key(), subject)
+                .verification(VerificationLevel::Low, VerificationMethod::RegistryCheck)
+                .valid_for(3600)
+        };
+
+        let mut data = person_data();
+        data.nationality = "usa".to_string();
+        assert!(matches!(
+            builder().person(data).build(),
+            Err(IssuanceError::InvalidField { field, .. }) if field == "nationality"
+        ));
+
+        let mut data = person_data();
+        data.expiry_date = Some("2019-01-01".to_string());
+        assert!(builder().person(data).build().is_err());
+
+        let mut data = person_data();
+        data.date_of_birth = "01/01/1990".to_string();
+        assert!(builder().person(data).build().is_err());
+
+        assert!(
+            builder()
+                .custom(serde_json::json!(["not", "an", "object"]))
+                .build()
+                .is_err()
+        );
+        assert!(
+            builder()
+                .custom(serde_json::json!({ "type": "override" }))
+                .build()
+                .is_err()
+        );
+        assert!(
+            builder()
+                .custom(serde_json::json!({ "member": true }))
+                .build()
+                .is_ok()
+        );
+
+        assert!(matches!(
+            CertificateBuilder::new(issuer.public_key(), subject)
+                .person(person_data())
+                .valid_for(3600)
+                .build(),
+            Err(IssuanceError::Missing("verification_level"))
+        ));
+        assert!(matches!(
+            builder()
+                .person(person_data())
+                .expires_at(Timestamp::new(1000))
+                .build(),
+            Err(IssuanceError::InvalidValidity)
+        ));
+    }
+}
@@ -1,13 +1,16 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
 use jwt_compact::TimeOptions;
 use jwt_compact::{UntrustedToken, alg::Es256k};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
 use chrono::{Duration, Utc};
 use jwt_compact::prelude::*;
 use secp256k1::{PublicKey, SecretKey, XOnlyPublicKey};
 use thiserror::Error;
 
-use crate::protocol::model::bindings;
+use crate::protocol::model::{Timestamp, auth::SubkeyProof, bindings};
 
 #[derive(Debug, Error)]
 pub enum JwtError {
@@ -34,13 +37,74 @@ pub enum JwtError {
 
     #[error("Invalid token format")]
     InvalidTokenFormat,
+
+    #[error("Token audience doesn't match")]
+    InvalidAudience,
+
+    #[error("Token issuer doesn't match")]
+    InvalidIssuer,
+
+    #[error("Token is missing the scope {0}")]
+    MissingScope(String),
+
+    #[error("Token has been revoked")]
+    TokenRevoked,
+
+    #[error("Invalid subkey proof: {0}")]
+    InvalidSubkeyProof(String),
 }
 
 /// Custom claims encoded in the token.
-#[derive(uniffi::Object, Debug, PartialEq, Serialize, Deserialize)]
+///
+/// All the fields except `target_key` are optional, so tokens issued before they were added
+/// still decode.
+#[derive(uniffi::Object, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CustomClaims {
     pub target_key: nostr::key::PublicKey,
-    // other fields...
+    /// Who the token is meant for, e.g. the API it grants access to
+    #[serde(rename = "aud", default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<String>,
+    #[serde(rename = "iss", default, skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    /// ID of the session the token belongs to, used to revoke it (see [`TokenRevocationList`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    /// Set when the token is signed with a subkey, binds it to the main key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subkey_proof: Option<SubkeyProof>,
+}
+
+impl CustomClaims {
+    pub fn with_audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    pub fn with_scopes(mut self, scopes: Vec<String>) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    pub fn with_jti(mut self, jti: impl Into<String>) -> Self {
+        self.jti = Some(jti.into());
+        self
+    }
+
+    pub fn with_subkey_proof(mut self, subkey_proof: SubkeyProof) -> Self {
+        self.subkey_proof = Some(subkey_proof);
+        self
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
 }
 
 #[uniffi::export]
@@ -49,22 +113,198 @@ impl CustomClaims {
     pub fn new(target_key: bindings::PublicKey) -> Self {
         Self {
             target_key: target_key.into(),
+            audience: None,
+            issuer: None,
+            scopes: Vec::new(),
+            jti: None,
+            subkey_proof: None,
         }
     }
+
+    pub fn target_key(&self) -> bindings::PublicKey {
+        self.target_key.into()
+    }
+
+    pub fn audience(&self) -> Option<String> {
+        self.audience.clone()
+    }
+
+    pub fn issuer(&self) -> Option<String> {
+        self.issuer.clone()
+    }
+
+    pub fn scopes(&self) -> Vec<String> {
+        self.scopes.clone()
+    }
+
+    pub fn jti(&self) -> Option<String> {
+        self.jti.clone()
+    }
+
+    /// Main key the token was issued for, when it's signed with a subkey
+    pub fn main_key(&self) -> Option<bindings::PublicKey> {
+        self.subkey_proof.as_ref().map(|proof| proof.main_key)
+    }
+}
+
+/// List of revoked token IDs (`jti`), consulted while decoding tokens
+pub trait TokenRevocationList: Send + Sync {
+    fn is_revoked(&self, jti: &str) -> bool;
+}
+
+impl TokenRevocationList for HashSet<String> {
+    fn is_revoked(&self, jti: &str) -> bool {
+        self.contains(jti)
+    }
+}
+
+impl TokenRevocationList for RwLock<HashSet<String>> {
+    fn is_revoked(&self, jti: &str) -> bool {
+        self.read().unwrap().contains(jti)
+    }
+}
+
+/// Revoked token IDs, each with the time its last token expires
+impl TokenRevocationList for RwLock<HashMap<String, Timestamp>> {
+    fn is_revoked(&self, jti: &str) -> bool {
+        self.read().unwrap().contains_key(jti)
+    }
+}
+
+/// Checks applied by [`decode_with_options`] on top of the signature and validity period
+#[derive(Clone, Default)]
+pub struct DecodeOptions {
+    /// Required `aud` claim
+    pub audience: Option<String>,
+    /// Required `iss` claim
+    pub issuer: Option<String>,
+    /// Scopes the token must all have
+    pub required_scopes: Vec<String>,
+    /// Tokens whose `jti` is in the list are rejected
+    pub revocation_list: Option<Arc<dyn TokenRevocationList>>,
+}
+
+impl DecodeOptions {
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    pub fn issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    pub fn required_scopes(mut self, scopes: Vec<String>) -> Self {
+        self.required_scopes = scopes;
+        self
+    }
+
+    pub fn revocation_list(mut self, list: Arc<dyn TokenRevocationList>) -> Self {
+        self.revocation_list = Some(list);
+        self
+    }
 }
 
 pub fn encode(
     secret_key: &nostr::key::SecretKey,
     claims: CustomClaims,
     duration: Duration,
+) -> Result<String, JwtError> {
+    encode_claims(secret_key, claims, duration)
+}
+
+pub fn decode(public_key: &nostr::key::PublicKey, token: &str) -> Result<CustomClaims, JwtError> {
+    decode_with_options(public_key, token, &DecodeOptions::default())
+}
+
+/// Verifies a token issued by `public_key` and checks its claims against `options`
+///
+/// Tokens signed with a subkey of `public_key` are accepted when they carry a valid
+/// `subkey_proof` for the signing key, which is read from the `kid` header.
+pub fn decode_with_options(
+    public_key: &nostr::key::PublicKey,
+    token: &str,
+    options: &DecodeOptions,
+) -> Result<CustomClaims, JwtError> {
+    let untrusted =
+        UntrustedToken::new(&token).map_err(|e| JwtError::TokenParsing(e.to_string()))?;
+    let signing_key = match &untrusted.header().key_id {
+        Some(key_id) => nostr::key::PublicKey::parse(key_id)
+            .map_err(|e| JwtError::InvalidPublicKey(e.to_string()))?,
+        None => *public_key,
+    };
+
+    let claims = decode_claims::<CustomClaims>(&signing_key, token)?;
+
+    let now = Utc::now();
+    if claims.expiration.is_some_and(|expiration| expiration < now) {
+        return Err(JwtError::TokenExpired);
+    }
+    if claims.not_before.is_some_and(|not_before| not_before > now) {
+        return Err(JwtError::TokenNotYetValid);
+    }
+
+    let claims = claims.custom;
+    match &claims.subkey_proof {
+        Some(proof) => {
+            if *proof.main_key != *public_key {
+                return Err(JwtError::InvalidSubkeyProof(
+                    "issued for another main key".to_string(),
+                ));
+            }
+            proof
+                .verify(&signing_key)
+                .map_err(|e| JwtError::InvalidSubkeyProof(e.to_string()))?;
+        }
+        None if signing_key != *public_key => {
+            return Err(JwtError::InvalidSubkeyProof(
+                "token signed with another key".to_string(),
+            ));
+        }
+        None => {}
+    }
+
+    if let Some(audience) = &options.audience
+        && claims.audience.as_ref() != Some(audience)
+    {
+        return Err(JwtError::InvalidAudience);
+    }
+    if let Some(issuer) = &options.issuer
+        && claims.issuer.as_ref() != Some(issuer)
+    {
+        return Err(JwtError::InvalidIssuer);
+    }
+    if let Some(scope) = options
+        .required_scopes
+        .iter()
+        .find(|scope| !claims.has_scope(scope))
+    {
+        return Err(JwtError::MissingScope(scope.clone()));
+    }
+    if let (Some(list), Some(jti)) = (&options.revocation_list, &claims.jti)
+        && list.is_revoked(jti)
+    {
+        return Err(JwtError::TokenRevoked);
+    }
+
+    Ok(claims)
+}
+
+/// Signs a token carrying any custom claims, valid for `duration`
+pub fn encode_claims<T: Serialize>(
+    secret_key: &nostr::key::SecretKey,
+    claims: T,
+    duration: Duration,
 ) -> Result<String, JwtError> {
     let es256k: Es256k = Es256k::default();
 
     // Choose time-related options for token creation / validation.
     let time_options = TimeOptions::default();
-    // Create a symmetric HMAC key, which will be used both to create and verify tokens.
-    // Create a token.
-    let header = Header::empty(); /*.with_key_id("my-key");*/
+    // The signing key goes in `kid`, so tokens signed with a subkey can be checked against the
+    // main key
+    let signing_key = nostr::Keys::new(secret_key.clone()).public_key();
+    let header = Header::empty().with_key_id(signing_key.to_hex());
     let claims = Claims::new(claims)
         .set_duration_and_issuance(&time_options, duration)
         .set_not_before(Utc::now());
@@ -90,7 +330,13 @@ pub fn encode(
     Ok(token_string)
 }
 
-pub fn decode(public_key: &nostr::key::PublicKey, token: &str) -> Result<CustomClaims, JwtError> {
+/// Verifies the signature of a token, returning all of its claims
+///
+/// The time claims (`exp`, `nbf`) are returned as they are, it's up to the caller to check them.
+pub fn decode_claims<T: DeserializeOwned>(
+    public_key: &nostr::key::PublicKey,
+    token: &str,
+) -> Result<Claims<T>, JwtError> {
     let es256k: Es256k = Es256k::default();
 
     let token = UntrustedToken::new(&token).map_err(|e| JwtError::TokenParsing(e.to_string()))?;
@@ -100,10 +346,113 @@ pub fn decode(public_key: &nostr::key::PublicKey, token: &str) -> Result<CustomC
     let public_key = PublicKey::from_x_only_public_key(x_public_key, secp256k1::Parity::Even);
 
     let verified = es256k
-        .validator::<CustomClaims>(&public_key)
+        .validator::<T>(&public_key)
         .validate(&token)
         .map_err(|e| JwtError::TokenVerification(e.to_string()))?;
 
-    let custom_claims = verified.into_parts().1.custom;
-    Ok(custom_claims)
+    Ok(verified.into_parts().1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nostr::key::Keys;
+
+    use crate::protocol::{
+        model::{Nonce, Timestamp},
+        subkey::{PrivateSubkeyManager, SubkeyMetadata},
+    };
+
+    fn claims(target: &Keys) -> CustomClaims {
+        CustomClaims::new(target.public_key().into())
+    }
+
+    #[test]
+    fn test_claims_options() {
+        let service = Keys::generate();
+        let user = Keys::generate();
+        let claims = claims(&user)
+            .with_audience("api.example.com")
+            .with_issuer("portal")
+            .with_scopes(vec!["read".into(), "write".into()])
+            .with_jti("session-1");
+        let token = encode(service.secret_key(), claims.clone(), Duration::hours(1)).unwrap();
+
+        let options = DecodeOptions::default()
+            .audience("api.example.com")
+            .issuer("portal")
+            .required_scopes(vec!["read".into()]);
+        assert_eq!(
+            decode_with_options(&service.public_key(), &token, &options).unwrap(),
+            claims
+        );
+
+        assert!(matches!(
+            decode_with_options(
+                &service.public_key(),
+                &token,
+                &DecodeOptions::default().audience("other")
+            ),
+            Err(JwtError::InvalidAudience)
+        ));
+        assert!(matches!(
+            decode_with_options(
+                &service.public_key(),
+                &token,
+                &DecodeOptions::default().required_scopes(vec!["admin".into()])
+            ),
+            Err(JwtError::MissingScope(_))
+        ));
+
+        let revoked = Arc::new(HashSet::from(["session-1".to_string()]));
+        assert!(matches!(
+            decode_with_options(
+                &service.public_key(),
+                &token,
+                &DecodeOptions::default().revocation_list(revoked)
+            ),
+            Err(JwtError::TokenRevoked)
+        ));
+
+        let expired = encode(service.secret_key(), claims, Duration::seconds(-60)).unwrap();
+        assert!(matches!(
+            decode(&service.public_key(), &expired),
+            Err(JwtError::TokenExpired)
+        ));
+    }
+
+    #[test]
+    fn test_subkey_binding() {
+        let main = Keys::generate();
+        let metadata = SubkeyMetadata {
+            name: "api".to_string(),
+            nonce: Nonce::new([0u8; 32]),
+            valid_from: Timestamp::new(0),
+            expires_at: Timestamp::new(u64::MAX),
+            permissions: vec![],
+            version: 2,
+            max_payment_msat: None,
+            allowed_counterparties: vec![],
+        };
+        let (subkey, proof) = main.create_subkey(&metadata).unwrap().split();
+        let user = Keys::generate();
+
+        let token = encode(
+            subkey.secret_key(),
+            claims(&user).with_subkey_proof(proof),
+            Duration::hours(1),
+        )
+        .unwrap();
+        let decoded = decode(&main.public_key(), &token).unwrap();
+        assert_eq!(decoded.main_key(), Some(main.public_key().into()));
+        assert!(decode(&Keys::generate().public_key(), &token).is_err());
+
+        // Signed with the subkey, without a proof
+        let token = encode(subkey.secret_key(), claims(&user), Duration::hours(1)).unwrap();
+        assert!(matches!(
+            decode(&main.public_key(), &token),
+            Err(JwtError::InvalidSubkeyProof(_))
+        ));
+    }
 }
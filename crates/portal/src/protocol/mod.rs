@@ -4,9 +4,13 @@ use model::auth::SubkeyProof;
 
 pub mod calendar;
 pub mod identity;
+pub mod issuance;
 pub mod jwt;
 pub mod key_handshake;
 pub mod model;
+pub mod predicate;
+pub mod revocation;
+pub mod session;
 pub mod subkey;
 
 #[cfg_attr(feature = "bindings", derive(uniffi::Object))]
@@ -43,6 +43,7 @@ pub mod event_kinds {
     pub const CERTIFICATE_REVOCATION: u16 = 29003;
     pub const CERTIFICATE_VERIFY_REQUEST: u16 = 29004;
     pub const CERTIFICATE_VERIFY_RESPONSE: u16 = 29005;
+    pub const CERTIFICATE_DELIVERY: u16 = 29006;
 
     // Cashu events (29500-29999)
     pub const CASHU_REQUEST: u16 = 29500;
@@ -51,9 +52,10 @@ pub mod event_kinds {
 
     // Control events (30000-30999)
     pub const SUBKEY_PROOF: u16 = 30000;
+    pub const SUBKEY_REVOCATION: u16 = 30001;
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Nonce([u8; 32]);
 
 impl Nonce {
@@ -155,7 +157,11 @@ impl<'de> Deserialize<'de> for Timestamp {
 }
 
 pub mod auth {
-    use crate::protocol::subkey::{PublicSubkeyVerifier, SubkeyError, SubkeyMetadata};
+    use std::{fmt, str::FromStr};
+
+    use crate::protocol::subkey::{
+        PublicSubkeyVerifier, RevokedSubkeys, SubkeyError, SubkeyMetadata, SubkeyOperation,
+    };
 
     use super::*;
 
@@ -189,7 +195,7 @@ pub mod auth {
         pub subkey_proof: Option<SubkeyProof>,
     }
 
-    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
     #[cfg_attr(feature = "bindings", derive(uniffi::Record))]
     pub struct SubkeyProof {
         pub main_key: PublicKey,
@@ -197,8 +203,40 @@ pub mod auth {
     }
 
     impl SubkeyProof {
+        /// Verifies that `subkey` is derived from the main key and is within its validity period
+        ///
+        /// Revocations are not checked, see [`SubkeyProof::verify_with`].
         pub fn verify(&self, subkey: &nostr::PublicKey) -> Result<(), SubkeyError> {
-            self.main_key.verify_subkey(subkey, &self.metadata)
+            self.main_key.verify_subkey(subkey, &self.metadata)?;
+            self.metadata.check_validity(Timestamp::now())?;
+            Ok(())
+        }
+
+        /// Verifies the proof like [`SubkeyProof::verify`], then that `subkey` isn't in `revoked`
+        pub fn verify_with(
+            &self,
+            subkey: &nostr::PublicKey,
+            revoked: &RevokedSubkeys,
+        ) -> Result<(), SubkeyError> {
+            self.verify(subkey)?;
+
+            if revoked.is_revoked(&self.main_key, subkey) {
+                return Err(SubkeyError::Revoked);
+            }
+            Ok(())
+        }
+
+        /// Verifies the proof like [`SubkeyProof::verify_with`], then that the subkey is allowed
+        /// to perform `operation` with `counterparty`
+        pub fn authorize(
+            &self,
+            subkey: &nostr::PublicKey,
+            revoked: &RevokedSubkeys,
+            operation: &SubkeyOperation,
+            counterparty: Option<&nostr::PublicKey>,
+        ) -> Result<(), SubkeyError> {
+            self.verify_with(subkey, revoked)?;
+            self.metadata.authorize(operation, counterparty)
         }
     }
 
@@ -214,29 +252,250 @@ pub mod auth {
     pub enum AuthResponseStatus {
         Approved {
             granted_permissions: Vec<String>,
+            /// Token of the current session with the service (from its last `AUTH_SUCCESS`), empty
+            /// if there's none. The service refreshes it or opens a new session.
             session_token: String,
         },
         Declined {
             reason: Option<String>,
         },
     }
+
+    /// Sent by the service after an approved auth challenge
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct AuthSuccessContent {
+        pub challenge: String,
+        /// See [`crate::protocol::session`]
+        pub session_token: String,
+        pub expires_at: Timestamp,
+        pub subkey_proof: Option<SubkeyProof>,
+    }
+
+    /// A permission a service asks for in an auth challenge
+    ///
+    /// Permissions are sent as strings in `required_permissions` / `granted_permissions`:
+    /// `payments:single` (optionally capped, e.g. `payments:single<=1000 sats`),
+    /// `payments:recurring`, `profile:read` and `cashu:request`.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    #[cfg_attr(feature = "bindings", derive(uniffi::Enum))]
+    pub enum Permission {
+        /// Single payment requests, of at most `max_sats` each if set
+        SinglePayment {
+            max_sats: Option<u64>,
+        },
+        /// Recurring payment requests, and the charges of the subscriptions
+        RecurringPayment,
+        ProfileRead,
+        CashuRequest,
+    }
+
+    #[derive(Debug, Clone, PartialEq, thiserror::Error)]
+    pub enum PermissionError {
+        #[error("Unknown permission: {0}")]
+        Unknown(String),
+
+        #[error("Invalid amount limit: {0}")]
+        InvalidLimit(String),
+    }
+
+    impl Permission {
+        /// Whether this permission, once granted, allows what `other` asks for
+        pub fn covers(&self, other: &Permission) -> bool {
+            match (self, other) {
+                (Self::SinglePayment { max_sats: None }, Self::SinglePayment { .. }) => true,
+                (
+                    Self::SinglePayment {
+                        max_sats: Some(max),
+                    },
+                    Self::SinglePayment {
+                        max_sats: Some(requested),
+                    },
+                ) => requested <= max,
+                (Self::SinglePayment { .. }, Self::SinglePayment { max_sats: None }) => false,
+                (granted, other) => granted == other,
+            }
+        }
+
+        /// Whether one of the `granted` permissions allows `permission`
+        pub fn is_granted(granted: &[Permission], permission: &Permission) -> bool {
+            granted.iter().any(|granted| granted.covers(permission))
+        }
+
+        /// Parses a list of permissions, failing on the first invalid one
+        pub fn parse_all<S: AsRef<str>>(permissions: &[S]) -> Result<Vec<Self>, PermissionError> {
+            permissions.iter().map(|p| p.as_ref().parse()).collect()
+        }
+    }
+
+    impl FromStr for Permission {
+        type Err = PermissionError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let s = s.trim();
+            match s {
+                "payments:recurring" => return Ok(Self::RecurringPayment),
+                "profile:read" => return Ok(Self::ProfileRead),
+                "cashu:request" => return Ok(Self::CashuRequest),
+                _ => {}
+            }
+
+            let Some(limit) = s.strip_prefix("payments:single") else {
+                return Err(PermissionError::Unknown(s.to_string()));
+            };
+            let limit = limit.trim();
+            if limit.is_empty() {
+                return Ok(Self::SinglePayment { max_sats: None });
+            }
+
+            let max_sats = limit
+                .strip_prefix("<=")
+                .map(|amount| amount.trim())
+                .and_then(|amount| {
+                    amount
+                        .strip_suffix("sats")
+                        .or_else(|| amount.strip_suffix("sat"))
+                })
+                .and_then(|amount| amount.trim().parse::<u64>().ok())
+                .filter(|amount| *amount > 0)
+                .ok_or_else(|| PermissionError::InvalidLimit(limit.to_string()))?;
+
+            Ok(Self::SinglePayment {
+                max_sats: Some(max_sats),
+            })
+        }
+    }
+
+    impl fmt::Display for Permission {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::SinglePayment { max_sats: None } => write!(f, "payments:single"),
+                Self::SinglePayment {
+                    max_sats: Some(max_sats),
+                } => write!(f, "payments:single<={} sats", max_sats),
+                Self::RecurringPayment => write!(f, "payments:recurring"),
+                Self::ProfileRead => write!(f, "profile:read"),
+                Self::CashuRequest => write!(f, "cashu:request"),
+            }
+        }
+    }
+
+    impl Serialize for Permission {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: serde::Serializer,
+        {
+            serializer.serialize_str(&self.to_string())
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Permission {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            use serde::de::Error;
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(Error::custom)
+        }
+    }
 }
 
 pub mod identity {
+    use std::collections::HashMap;
+
+    use crate::protocol::{
+        identity::PartialCertificate,
+        revocation::{CertificateStatus, RevocationList, StatusError},
+    };
+
     use super::*;
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[cfg_attr(feature = "bindings", derive(uniffi::Record))]
     pub struct CertificateRequestContent {
+        pub request_id: String,
         pub requested_types: Vec<String>,
         pub requested_fields: Vec<String>,
         pub purpose: String,
         pub require_status_proofs: Option<bool>,
+        pub expires_at: Timestamp,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[cfg_attr(feature = "bindings", derive(uniffi::Record))]
+    pub struct CertificateRequestContentWithKey {
+        pub inner: CertificateRequestContent,
+        pub main_key: PublicKey,
+        pub recipient: PublicKey,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct CertificateResponseContent {
-        pub certificates: std::collections::HashMap<String, serde_json::Value>,
-        pub status_proofs: Option<std::collections::HashMap<String, serde_json::Value>>,
+        pub request_id: String,
+        pub status: CertificateResponseStatus,
+        pub certificates: HashMap<String, serde_json::Value>,
+        pub status_proofs: Option<HashMap<String, serde_json::Value>>,
+    }
+
+    impl CertificateResponseContent {
+        /// Parses the revealed certificates, keyed by certificate type.
+        ///
+        /// The certificates are not verified, call [`PartialCertificate::verify`] on each of them.
+        pub fn partial_certificates(
+            &self,
+        ) -> Result<HashMap<String, PartialCertificate>, serde_json::Error> {
+            self.certificates
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), serde_json::from_value(v.clone())?)))
+                .collect()
+        }
+
+        /// Checks every revealed certificate against the status proof attached for it.
+        ///
+        /// Status proofs are the issuer-signed [`RevocationList`]s, keyed by the same certificate type.
+        /// They must have been issued in the last `max_age_seconds`.
+        pub fn check_status_proofs(
+            &self,
+            max_age_seconds: u64,
+        ) -> Result<HashMap<String, CertificateStatus>, StatusError> {
+            let status_proofs = self.status_proofs.clone().unwrap_or_default();
+
+            let mut statuses = HashMap::new();
+            for (certificate_type, certificate) in self.partial_certificates()? {
+                let proof = status_proofs
+                    .get(&certificate_type)
+                    .ok_or_else(|| StatusError::MissingStatusProof(certificate_type.clone()))?;
+                let list: RevocationList = serde_json::from_value(proof.clone())?;
+
+                statuses.insert(certificate_type, list.check(&certificate, max_age_seconds)?);
+            }
+
+            Ok(statuses)
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+    #[cfg_attr(feature = "bindings", derive(uniffi::Enum))]
+    #[serde(rename_all = "snake_case", tag = "status")]
+    pub enum CertificateResponseStatus {
+        Approved,
+        Declined { reason: Option<String> },
+    }
+
+    /// A newly issued certificate sent by the issuer to its subject
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[cfg_attr(feature = "bindings", derive(uniffi::Record))]
+    pub struct CertificateDeliveryContent {
+        /// JSON-serialized [`Certificate`](crate::protocol::identity::Certificate)
+        pub certificate: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[cfg_attr(feature = "bindings", derive(uniffi::Record))]
+    pub struct CertificateDeliveryContentWithKey {
+        pub inner: CertificateDeliveryContent,
+        pub main_key: PublicKey,
+        pub recipient: PublicKey,
     }
 }
 
@@ -300,6 +559,36 @@ pub mod payment {
         pub request_id: String,
     }
 
+    impl SinglePaymentRequestContent {
+        /// The amount in millisats, converted with `current_exchange_rate` for fiat amounts
+        ///
+        /// `None` for a fiat amount without exchange rate.
+        pub fn amount_millisats(&self) -> Option<u64> {
+            amount_millisats(
+                &self.amount,
+                &self.currency,
+                self.current_exchange_rate.as_ref(),
+            )
+        }
+    }
+
+    fn amount_millisats(
+        amount: &Amount,
+        currency: &Currency,
+        exchange_rate: Option<&ExchangeRate>,
+    ) -> Option<u64> {
+        match currency {
+            Currency::Millisats => Some(amount.as_millisats()),
+            Currency::Fiat(_) => {
+                let rate = exchange_rate?;
+                if rate.rate <= 0.0 {
+                    return None;
+                }
+                Some((amount.as_fiat_major() / rate.rate * 100_000_000_000.0) as u64)
+            }
+        }
+    }
+
     #[derive(Debug, Clone, Serialize, Deserialize)]
     #[cfg_attr(feature = "bindings", derive(uniffi::Record))]
     #[serde(rename_all = "snake_case")]
@@ -348,6 +637,18 @@ pub mod payment {
         pub request_id: String,
     }
 
+    impl RecurringPaymentRequestContent {
+        /// The amount of each payment in millisats, like
+        /// [`SinglePaymentRequestContent::amount_millisats`]
+        pub fn amount_millisats(&self) -> Option<u64> {
+            amount_millisats(
+                &self.amount,
+                &self.currency,
+                self.current_exchange_rate.as_ref(),
+            )
+        }
+    }
+
     #[derive(Debug, Clone, Serialize, Deserialize)]
     #[cfg_attr(feature = "bindings", derive(uniffi::Record))]
     #[serde(rename_all = "snake_case")]
@@ -673,3 +974,45 @@ fn test_serialization() {
     let c2: payment::Currency = serde_json::from_str(&s).unwrap();
     assert_eq!(c, c2);
 }
+
+#[cfg(test)]
+#[test]
+fn test_permissions() {
+    use auth::Permission;
+
+    let permissions = Permission::parse_all(&[
+        "payments:single<=1000 sats",
+        "payments:recurring",
+        "profile:read",
+        "cashu:request",
+    ])
+    .unwrap();
+    assert_eq!(
+        permissions[0],
+        Permission::SinglePayment {
+            max_sats: Some(1000)
+        }
+    );
+    assert_eq!(permissions[0].to_string(), "payments:single<=1000 sats");
+
+    assert!(Permission::is_granted(
+        &permissions,
+        &Permission::SinglePayment {
+            max_sats: Some(500)
+        }
+    ));
+    assert!(!Permission::is_granted(
+        &permissions,
+        &Permission::SinglePayment {
+            max_sats: Some(2000)
+        }
+    ));
+    assert!(!Permission::is_granted(
+        &permissions,
+        &Permission::SinglePayment { max_sats: None }
+    ));
+
+    assert!("payments:single<=0 sats".parse::<Permission>().is_err());
+    assert!("payments:single<=lots".parse::<Permission>().is_err());
+    assert!("wallet:drain".parse::<Permission>().is_err());
+}
@@ -0,0 +1,317 @@
+//! Predicate claims over certificate fields
+//!
+//! A predicate is a yes/no claim that the issuer derives from the certificate data when issuing
+//! it, like "the subject is over 18". The result is stored in [`Certificate::predicates`] and
+//! committed in the merkle tree as a field of its own (e.g. `predicates.age_over_18`), so the
+//! holder can reveal it without revealing the fields it was derived from.
+//!
+//! [`Certificate::predicates`]: crate::protocol::identity::Certificate::predicates
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{
+    identity::{CertificateData, PREDICATES_PREFIX},
+    model::Timestamp,
+};
+
+/// Member states of the European Union, as ISO 3166-1 alpha-2 codes
+pub const EU_COUNTRIES: &[&str] = &[
+    "AT", "BE", "BG", "HR", "CY", "CZ", "DK", "EE", "FI", "FR", "DE", "GR", "HU", "IE", "IT", "LV",
+    "LT", "LU", "MT", "NL", "PL", "PT", "RO", "SK", "SI", "ES", "SE",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Predicate {
+    /// The subject was at least `age` years old when the certificate was issued
+    AgeOver { age: u8 },
+    /// The nationality of the subject is one of `countries`, identified as `region`
+    NationalityIn {
+        region: Region,
+        countries: Vec<String>,
+    },
+    /// The identity document doesn't expire before the certificate does
+    DocumentValid,
+}
+
+/// Name of the region of a [`Predicate::NationalityIn`] claim, e.g. `eu`
+///
+/// It becomes part of the claim name and of the `predicates.<claim>` field path, so only
+/// lowercase letters, digits and `_` are allowed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Region(String);
+
+impl Region {
+    pub fn new(region: impl Into<String>) -> Result<Self, PredicateError> {
+        let region = region.into();
+        let valid = !region.is_empty()
+            && region
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid {
+            return Err(PredicateError::InvalidRegion(region));
+        }
+        Ok(Region(region))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for Region {
+    type Error = PredicateError;
+
+    fn try_from(region: String) -> Result<Self, Self::Error> {
+        Region::new(region)
+    }
+}
+
+impl From<Region> for String {
+    fn from(region: Region) -> Self {
+        region.0
+    }
+}
+
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PredicateError {
+    #[error("Predicate '{0}' can't be derived from '{1}' certificates")]
+    NotApplicable(String, &'static str),
+
+    #[error("Invalid date in field '{0}'")]
+    InvalidDate(&'static str),
+
+    #[error("Invalid region '{0}': use lowercase letters, digits and '_'")]
+    InvalidRegion(String),
+}
+
+fn parse_date(field: &'static str, value: &str) -> Result<chrono::NaiveDate, PredicateError> {
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| PredicateError::InvalidDate(field))
+}
+
+fn timestamp_to_date(timestamp: Timestamp) -> Result<chrono::NaiveDate, PredicateError> {
+    chrono::DateTime::from_timestamp(timestamp.as_u64() as i64, 0)
+        .map(|dt| dt.date_naive())
+        .ok_or(PredicateError::InvalidDate("metadata"))
+}
+
+impl Predicate {
+    pub fn nationality_in_eu() -> Self {
+        Predicate::NationalityIn {
+            region: Region("eu".to_string()),
+            countries: EU_COUNTRIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /// Name of the claim in [`Certificate::predicates`](crate::protocol::identity::Certificate::predicates)
+    pub fn claim_name(&self) -> String {
+        match self {
+            Predicate::AgeOver { age } => format!("age_over_{}", age),
+            Predicate::NationalityIn { region, .. } => format!("nationality_in_{}", region),
+            Predicate::DocumentValid => "document_valid".to_string(),
+        }
+    }
+
+    /// Name of the field to request in order to reveal this claim
+    pub fn field_name(&self) -> String {
+        format!("{}.{}", PREDICATES_PREFIX, self.claim_name())
+    }
+
+    /// Derives the value of the claim from the certificate data
+    pub fn evaluate(
+        &self,
+        data: &CertificateData,
+        issued_at: Timestamp,
+        expires_at: Timestamp,
+    ) -> Result<bool, PredicateError> {
+        let CertificateData::Person(person) = data else {
+            return Err(PredicateError::NotApplicable(
+                self.claim_name(),
+                data.type_name(),
+            ));
+        };
+
+        match self {
+            Predicate::AgeOver { age } => {
+                let date_of_birth = parse_date("date_of_birth", &person.date_of_birth)?;
+                let years = timestamp_to_date(issued_at)?
+                    .years_since(date_of_birth)
+                    .unwrap_or(0);
+                Ok(years >= *age as u32)
+            }
+            Predicate::NationalityIn { countries, .. } => {
+                Ok(countries.iter().any(|c| c == &person.nationality))
+            }
+            Predicate::DocumentValid => match &person.expiry_date {
+                Some(expiry_date) => {
+                    Ok(parse_date("expiry_date", expiry_date)? >= timestamp_to_date(expires_at)?)
+                }
+                None => Ok(true),
+            },
+        }
+    }
+
+    /// Reads the claim from the JSON returned by [`PartialCertificate::verify`]
+    ///
+    /// Returns `None` if the claim was not revealed.
+    ///
+    /// [`PartialCertificate::verify`]: crate::protocol::identity::PartialCertificate::verify
+    pub fn revealed_value(&self, revealed: &serde_json::Value) -> Option<bool> {
+        revealed
+            .get(PREDICATES_PREFIX)?
+            .get(self.claim_name())?
+            .as_bool()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{
+        identity::{PersonData, VerificationLevel, VerificationMethod},
+        issuance::CertificateBuilder,
+    };
+
+    fn person_data(date_of_birth: &str, nationality: &str) -> PersonData {
+        PersonData {
+            full_name: "John Doe".to_string(),
+            date_of_birth: date_of_birth.to_string(),
+            nationality: nationality.to_string(),
+            document_type: "passport".to_string(),
+            document_number: "123456789".to_string(),
+            place_of_birth: None,
+            gender: None,
+            issue_date: None,
+            expiry_date: Some("2030-01-01".to_string()),
+            address: None,
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        // 2024-06-01
+        let issued_at = Timestamp::new(1717200000);
+        // 2029-01-01
+        let expires_at = Timestamp::new(1861920000);
+
+        let data = CertificateData::Person(person_data("2006-06-01", "IT"));
+        assert!(
+            Predicate::AgeOver { age: 18 }
+                .evaluate(&data, issued_at, expires_at)
+                .unwrap()
+        );
+        assert!(
+            !Predicate::AgeOver { age: 21 }
+                .evaluate(&data, issued_at, expires_at)
+                .unwrap()
+        );
+
+        let data = CertificateData::Person(person_data("2006-06-02", "IT"));
+        assert!(
+            !Predicate::AgeOver { age: 18 }
+                .evaluate(&data, issued_at, expires_at)
+                .unwrap()
+        );
+        assert!(
+            Predicate::nationality_in_eu()
+                .evaluate(&data, issued_at, expires_at)
+                .unwrap()
+        );
+        assert!(
+            Predicate::DocumentValid
+                .evaluate(&data, issued_at, expires_at)
+                .unwrap()
+        );
+        // 2031-01-01
+        assert!(
+            !Predicate::DocumentValid
+                .evaluate(&data, issued_at, Timestamp::new(1924992000))
+                .unwrap()
+        );
+
+        let data = CertificateData::Person(person_data("1990-01-01", "US"));
+        assert!(
+            !Predicate::nationality_in_eu()
+                .evaluate(&data, issued_at, expires_at)
+                .unwrap()
+        );
+
+        let data = CertificateData::Custom {
+            data: serde_json::json!({ "member": true }),
+        };
+        assert!(matches!(
+            Predicate::DocumentValid.evaluate(&data, issued_at, expires_at),
+            Err(PredicateError::NotApplicable(..))
+        ));
+    }
+
+    #[test]
+    fn test_reveal_predicate_only() {
+        let issuer = nostr::Keys::generate();
+        let subject = nostr::Keys::generate().public_key();
+        let over_18 = Predicate::AgeOver { age: 18 };
+
+        let certificate = CertificateBuilder::new(issuer.public_key(), subject)
+            .person(person_data("1990-01-01", "IT"))
+            .predicate(over_18.clone())
+            .predicate(Predicate::nationality_in_eu())
+            .verification(VerificationLevel::High, VerificationMethod::DocumentUpload)
+            .valid_for(3600)
+            .sign(&issuer)
+            .unwrap();
+        assert_eq!(certificate.predicates.len(), 2);
+
+        let partial = certificate.create_partial(&[over_18.field_name()]).unwrap();
+        let revealed = partial.verify().unwrap();
+
+        assert_eq!(over_18.revealed_value(&revealed), Some(true));
+        assert_eq!(
+            Predicate::nationality_in_eu().revealed_value(&revealed),
+            None
+        );
+        assert!(revealed.get("date_of_birth").is_none());
+    }
+
+    #[test]
+    fn test_region_is_validated() {
+        assert_eq!(
+            Predicate::NationalityIn {
+                region: Region::new("north_america").unwrap(),
+                countries: vec!["US".to_string(), "CA".to_string()],
+            }
+            .field_name(),
+            "predicates.nationality_in_north_america"
+        );
+
+        for region in ["", "EU", "eu.age_over_18", "eu/x", "eu x"] {
+            assert!(matches!(
+                Region::new(region),
+                Err(PredicateError::InvalidRegion(_))
+            ));
+        }
+
+        let predicate: Predicate = serde_json::from_value(serde_json::json!({
+            "type": "nationality_in",
+            "region": "eu",
+            "countries": ["IT"],
+        }))
+        .unwrap();
+        assert_eq!(predicate.claim_name(), "nationality_in_eu");
+        assert!(
+            serde_json::from_value::<Predicate>(serde_json::json!({
+                "type": "nationality_in",
+                "region": "eu.age_over_18",
+                "countries": ["IT"],
+            }))
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! Certificate revocation lists and status proofs
+//!
+//! An issuer keeps a signed [`RevocationList`] of the certificates it revoked, identified by their
+//! merkle root, and publishes it under the `CERTIFICATE_REVOCATION` event kind. The same signed list
+//! doubles as a status proof: a holder attaches the latest list of the issuer when presenting a
+//! certificate, and the verifier checks that it is fresh and doesn't contain the certificate.
+
+use std::collections::{BTreeMap, HashMap};
+
+use nostr::secp256k1::Secp256k1;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{
+    identity::{PartialCertificate, SignError, VerifyError},
+    model::Timestamp,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RevocationEntry {
+    pub revoked_at: Timestamp,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevocationList {
+    pub version: u32,
+    pub issuer_pubkey: nostr::PublicKey,
+    pub sequence: u64,
+    pub issued_at: Timestamp,
+    /// Revoked certificates, keyed by certificate id
+    pub revoked: BTreeMap<String, RevocationEntry>,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedRevocationData {
+    pub version: u32,
+    pub issuer_pubkey: nostr::PublicKey,
+    pub sequence: u64,
+    pub issued_at: Timestamp,
+    pub revoked: BTreeMap<String, RevocationEntry>,
+}
+
+impl RevocationList {
+    /// Creates a new, empty and unsigned revocation list
+    pub fn new(issuer_pubkey: nostr::PublicKey) -> Self {
+        Self {
+            version: 1,
+            issuer_pubkey,
+            sequence: 0,
+            issued_at: Timestamp::now(),
+            revoked: BTreeMap::new(),
+            signature: String::new(),
+        }
+    }
+
+    /// Adds a certificate to the list. The list must be signed again afterwards.
+    pub fn revoke(&mut self, certificate_id: String, reason: Option<String>) {
+        self.revoked.insert(
+            certificate_id,
+            RevocationEntry {
+                revoked_at: Timestamp::now(),
+                reason,
+            },
+        );
+        self.refresh();
+    }
+
+    /// Bumps the sequence number and the issue time, clearing the signature.
+    ///
+    /// Issuers should periodically refresh and re-publish their list so that holders always have
+    /// a recent status proof to present.
+    pub fn refresh(&mut self) {
+        self.sequence += 1;
+        self.issued_at = Timestamp::now();
+        self.signature.clear();
+    }
+
+    pub fn get_signed_data(&self) -> SignedRevocationData {
+        SignedRevocationData {
+            version: self.version,
+            issuer_pubkey: self.issuer_pubkey,
+            sequence: self.sequence,
+            issued_at: self.issued_at,
+            revoked: self.revoked.clone(),
+        }
+    }
+
+    fn message(&self) -> Result<nostr::secp256k1::Message, VerifyError> {
+        use sha2::{Digest, Sha256};
+
+        let data = serde_json::to_string(&self.get_signed_data())?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(data.as_bytes());
+        Ok(nostr::secp256k1::Message::from_digest_slice(
+            &hasher.finalize(),
+        )?)
+    }
+
+    pub fn sign(&mut self, issuer_key: &nostr::Keys) -> Result<(), SignError> {
+        if self.issuer_pubkey != issuer_key.public_key() {
+            return Err(SignError::InvalidKey);
+        }
+
+        if !self.signature.is_empty() {
+            return Err(SignError::AlreadySigned);
+        }
+
+        let message = self.message().map_err(|_| SignError::SigningFailed)?;
+        let signature = issuer_key.key_pair(&Secp256k1::new()).sign_schnorr(message);
+        self.signature = hex::encode(signature.serialize());
+
+        Ok(())
+    }
+
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let message = self.message()?;
+        let signature = nostr::secp256k1::schnorr::Signature::from_slice(
+            &hex::decode(&self.signature).map_err(|_| VerifyError::InvalidSignature)?,
+        )
+        .map_err(VerifyError::Secp256k1)?;
+
+        Secp256k1::new()
+            .verify_schnorr(&signature, &message, &self.issuer_pubkey.xonly()?)
+            .map_err(|_| VerifyError::InvalidSignature)
+    }
+
+    /// Checks the status of a certificate against this list.
+    ///
+    /// The list must be signed by the issuer of the certificate and must have been issued in the
+    /// last `max_age_seconds`, so that it proves the status at presentation time.
+    pub fn check(
+        &self,
+        certificate: &PartialCertificate,
+        max_age_seconds: u64,
+    ) -> Result<CertificateStatus, StatusError> {
+        if self.issuer_pubkey != certificate.metadata.issuer_pubkey {
+            return Err(StatusError::WrongIssuer);
+        }
+
+        self.verify()?;
+
+        if self.issued_at.as_u64().saturating_add(max_age_seconds) < Timestamp::now().as_u64() {
+            return Err(StatusError::Stale(self.issued_at));
+        }
+
+        match self.revoked.get(&certificate.metadata.certificate_id()) {
+            Some(entry) => Ok(CertificateStatus::Revoked {
+                revoked_at: entry.revoked_at,
+                reason: entry.reason.clone(),
+            }),
+            None => Ok(CertificateStatus::Valid),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertificateStatus {
+    Valid,
+    Revoked {
+        revoked_at: Timestamp,
+        reason: Option<String>,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StatusError {
+    #[error("Revocation list was not signed by the certificate issuer")]
+    WrongIssuer,
+
+    #[error("Revocation list is too old (issued at {})", .0.as_u64())]
+    Stale(Timestamp),
+
+    #[error("No revocation list available for issuer {0}")]
+    MissingRevocationList(nostr::PublicKey),
+
+    #[error("Missing status proof for certificate '{0}'")]
+    MissingStatusProof(String),
+
+    #[error("Verification error: {0}")]
+    Verify(#[from] VerifyError),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+/// Keeps the most recent revocation list of each issuer
+#[derive(Debug, Clone, Default)]
+pub struct RevocationRegistry {
+    lists: HashMap<nostr::PublicKey, RevocationList>,
+}
+
+impl RevocationRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores a list if it's validly signed and newer than the one we have.
+    ///
+    /// Returns `true` if the list replaced the previous one.
+    pub fn update(&mut self, list: RevocationList) -> Result<bool, VerifyError> {
+        list.verify()?;
+
+        if let Some(existing) = self.lists.get(&list.issuer_pubkey)
+            && existing.sequence >= list.sequence
+        {
+            return Ok(false);
+        }
+
+        self.lists.insert(list.issuer_pubkey, list);
+        Ok(true)
+    }
+
+    pub fn get(&self, issuer: &nostr::PublicKey) -> Option<&RevocationList> {
+        self.lists.get(issuer)
+    }
+
+    pub fn check(
+        &self,
+        certificate: &PartialCertificate,
+        max_age_seconds: u64,
+    ) -> Result<CertificateStatus, StatusError> {
+        let issuer = certificate.metadata.issuer_pubkey;
+        self.get(&issuer)
+            .ok_or(StatusError::MissingRevocationList(issuer))?
+            .check(certificate, max_age_seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::identity::{
+        Certificate, CertificateData, CertificateMetadata, MerkleRoot, SaltSequence,
+        VerificationLevel, VerificationMethod,
+    };
+
+    fn create_signed_certificate(issuer: &nostr::Keys) -> Certificate {
+        let mut cert = Certificate::new(
+            1,
+            nostr::Keys::generate().public_key(),
+            CertificateData::Custom {
+                data: serde_json::json!({ "member": true }),
+            },
+            CertificateMetadata {
+                issuer_pubkey: issuer.public_key(),
+                issued_at: Timestamp::new(1234567890),
+                expires_at: Timestamp::new(9876543210),
+                verification_level: VerificationLevel::Low,
+                verification_method: VerificationMethod::RegistryCheck,
+                salt_sequence: SaltSequence::new(32, vec![7u8; 32 * 4]),
+                merkle_root: MerkleRoot::new([0u8; 32]),
+            },
+            "".to_string(),
+        )
+        .unwrap();
+        cert.sign(issuer).unwrap();
+        cert
+    }
+
+    #[test]
+    fn test_revocation_list_status() {
+        let issuer = nostr::Keys::generate();
+        let cert = create_signed_certificate(&issuer);
+        let partial = cert.create_partial(&[]).unwrap();
+
+        let mut list = RevocationList::new(issuer.public_key());
+        list.sign(&issuer).unwrap();
+        assert_eq!(list.check(&partial, 60).unwrap(), CertificateStatus::Valid);
+
+        list.revoke(
+            cert.metadata.certificate_id(),
+            Some("compromised".to_string()),
+        );
+        assert!(matches!(
+            list.check(&partial, 60),
+            Err(StatusError::Verify(_))
+        ));
+
+        list.sign(&issuer).unwrap();
+        assert!(matches!(
+            list.check(&partial, 60).unwrap(),
+            CertificateStatus::Revoked { .. }
+        ));
+
+        let mut tampered = list.clone();
+        tampered.revoked.clear();
+        assert!(tampered.check(&partial, 60).is_err());
+
+        let mut stale = RevocationList::new(issuer.public_key());
+        stale.issued_at = Timestamp::new(1000);
+        stale.sign(&issuer).unwrap();
+        assert!(matches!(
+            stale.check(&partial, 60),
+            Err(StatusError::Stale(_))
+        ));
+    }
+
+    #[test]
+    fn test_revocation_registry() {
+        let issuer = nostr::Keys::generate();
+        let other = nostr::Keys::generate();
+        let cert = create_signed_certificate(&issuer);
+        let partial = cert.create_partial(&[]).unwrap();
+
+        let mut registry = RevocationRegistry::new();
+        assert!(matches!(
+            registry.check(&partial, 60),
+            Err(StatusError::MissingRevocationList(_))
+        ));
+
+        let mut old = RevocationList::new(issuer.public_key());
+        old.sign(&issuer).unwrap();
+        let mut new = old.clone();
+        new.revoke(cert.metadata.certificate_id(), None);
+        new.sign(&issuer).unwrap();
+
+        assert!(registry.update(new).unwrap());
+        assert!(!registry.update(old).unwrap());
+        assert!(matches!(
+            registry.check(&partial, 60).unwrap(),
+            CertificateStatus::Revoked { .. }
+        ));
+
+        let mut forged = RevocationList::new(issuer.public_key());
+        forged.sequence = 100;
+        assert!(forged.sign(&other).is_err());
+        forged.signature = hex::encode([1u8; 64]);
+        assert!(registry.update(forged).is_err());
+    }
+}
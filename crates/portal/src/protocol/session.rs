@@ -0,0 +1,157 @@
+//! Session tokens
+//!
+//! When a user approves an auth challenge, the service mints a session token: a JWT (see
+//! [`super::jwt`]) signed with its key and carrying [`SessionClaims`]. The token is delivered to
+//! the user in an `AUTH_SUCCESS` event. The app presents it again when it answers later auth
+//! challenges from the same service, so the service can refresh the session instead of opening
+//! a new one.
+
+use chrono::{DateTime, Duration, Utc};
+use nostr::key::{PublicKey, SecretKey};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    protocol::{
+        jwt::{self, JwtError},
+        model::Timestamp,
+    },
+    utils::random_string,
+};
+
+/// How long sessions last unless the service picks another duration
+pub const DEFAULT_SESSION_DURATION_SECS: u64 = 24 * 60 * 60;
+
+/// Claims of a session token
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionClaims {
+    /// Random ID, kept when the session is refreshed
+    pub session_id: String,
+    /// Main key of the service that opened the session
+    pub service_key: PublicKey,
+    /// Main key of the authenticated user
+    pub user_key: PublicKey,
+    pub granted_permissions: Vec<String>,
+}
+
+impl SessionClaims {
+    /// Claims of a new session
+    pub fn new(
+        service_key: PublicKey,
+        user_key: PublicKey,
+        granted_permissions: Vec<String>,
+    ) -> Self {
+        Self {
+            session_id: random_string(32),
+            service_key,
+            user_key,
+            granted_permissions,
+        }
+    }
+}
+
+/// A verified session token
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub token: String,
+    pub claims: SessionClaims,
+    pub issued_at: Timestamp,
+    pub expires_at: Timestamp,
+}
+
+impl Session {
+    /// Signs a session token with the key of the service
+    pub fn issue(
+        secret_key: &SecretKey,
+        claims: SessionClaims,
+        duration: Duration,
+    ) -> Result<Self, JwtError> {
+        let issued_at = Timestamp::now();
+        let expires_at = Timestamp::new(issued_at.as_u64() + duration.num_seconds().max(0) as u64);
+        let token = jwt::encode_claims(secret_key, claims.clone(), duration)?;
+
+        Ok(Self {
+            token,
+            claims,
+            issued_at,
+            expires_at,
+        })
+    }
+
+    /// Verifies a session token signed by `signing_key`
+    ///
+    /// `signing_key` is the key the service signs its events with, which is a subkey of
+    /// `claims.service_key` for services running with a subkey.
+    pub fn verify(signing_key: &PublicKey, token: &str) -> Result<Self, JwtError> {
+        let claims = jwt::decode_claims::<SessionClaims>(signing_key, token)?;
+
+        let now = Utc::now();
+        let expiration = claims.expiration.ok_or(JwtError::InvalidTokenFormat)?;
+        if expiration < now {
+            return Err(JwtError::TokenExpired);
+        }
+        if claims.not_before.is_some_and(|not_before| not_before > now) {
+            return Err(JwtError::TokenNotYetValid);
+        }
+
+        let to_timestamp = |time: DateTime<Utc>| Timestamp::new(time.timestamp().max(0) as u64);
+        Ok(Self {
+            token: token.to_string(),
+            issued_at: claims
+                .issued_at
+                .map(to_timestamp)
+                .unwrap_or_else(|| Timestamp::new(0)),
+            expires_at: to_timestamp(expiration),
+            claims: claims.custom,
+        })
+    }
+
+    /// Issues a new token for the same session, valid for `duration` from now
+    pub fn refresh(&self, secret_key: &SecretKey, duration: Duration) -> Result<Self, JwtError> {
+        Self::issue(secret_key, self.claims.clone(), duration)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at < Timestamp::now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nostr::key::Keys;
+
+    #[test]
+    fn test_issue_and_verify() {
+        let service = Keys::generate();
+        let user = Keys::generate().public_key();
+        let claims = SessionClaims::new(service.public_key(), user, vec!["profile:read".into()]);
+
+        let session =
+            Session::issue(service.secret_key(), claims.clone(), Duration::hours(1)).unwrap();
+        let verified = Session::verify(&service.public_key(), &session.token).unwrap();
+        assert_eq!(verified.claims, claims);
+        assert!(!verified.is_expired());
+
+        assert!(Session::verify(&Keys::generate().public_key(), &session.token).is_err());
+
+        let refreshed = verified
+            .refresh(service.secret_key(), Duration::hours(2))
+            .unwrap();
+        assert_eq!(refreshed.claims.session_id, claims.session_id);
+        assert!(refreshed.expires_at > session.expires_at);
+    }
+
+    #[test]
+    fn test_expired_session() {
+        let service = Keys::generate();
+        let claims =
+            SessionClaims::new(service.public_key(), Keys::generate().public_key(), vec![]);
+
+        let session = Session::issue(service.secret_key(), claims, Duration::seconds(-60)).unwrap();
+        assert!(matches!(
+            Session::verify(&service.public_key(), &session.token),
+            Err(JwtError::TokenExpired)
+        ));
+    }
+}
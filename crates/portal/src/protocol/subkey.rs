@@ -5,10 +5,18 @@ use nostr::{
 };
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
 use std::ops::Deref;
+use std::sync::RwLock;
 
 use crate::protocol::model::{Nonce, Timestamp};
 
+#[cfg(feature = "bindings")]
+use crate::protocol::model::bindings::PublicKey as CounterpartyKey;
+#[cfg(not(feature = "bindings"))]
+use nostr::PublicKey as CounterpartyKey;
+
 use super::model::auth::SubkeyProof;
 
 /// A subkey with its associated metadata
@@ -51,6 +59,20 @@ impl Subkey {
     }
 }
 
+/// Version of the [`SubkeyMetadata`] created by [`SubkeyMetadata::new`] when it fits the original
+/// encoding: [`SubkeyPermission::Auth`] and [`SubkeyPermission::Payment`] only, without limits
+pub const SUBKEY_METADATA_VERSION: u8 = 1;
+
+/// Version of the [`SubkeyMetadata`] with the extended permissions, the spending cap and the
+/// allowed counterparties
+///
+/// Version 2 was already used with the original encoding, see
+/// [`SubkeyMetadata::canonical_bytes`].
+pub const SUBKEY_METADATA_VERSION_EXTENDED: u8 = 3;
+
+/// Tolerated clock difference when checking the validity period of a subkey
+pub const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
 /// Trait for managing private subkeys
 pub trait PrivateSubkeyManager {
     /// Creates a new subkey with the given metadata
@@ -82,21 +104,139 @@ pub trait PublicSubkeyVerifier {
 ///
 /// See [`SubkeyMetadata::canonical_bytes`] for the exact wire format and [`SubkeyMetadata::get_tweak`]
 /// for the tagged hash construction.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[cfg_attr(feature = "bindings", derive(uniffi::Record))]
 pub struct SubkeyMetadata {
     pub name: String,
     pub nonce: Nonce,
     pub valid_from: Timestamp,
     pub expires_at: Timestamp,
+    /// Operations the subkey is allowed to perform. An empty list allows all of them, like the
+    /// subkeys created before permissions were enforced.
     pub permissions: Vec<SubkeyPermission>,
     pub version: u8,
+    /// Maximum amount of a single payment, in millisats (version 3)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_payment_msat: Option<u64>,
+    /// Keys the subkey may interact with, any if empty (version 3)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_counterparties: Vec<CounterpartyKey>,
 }
 
 impl SubkeyMetadata {
+    /// Metadata of a new subkey, with a random nonce
+    ///
+    /// The version is [`SUBKEY_METADATA_VERSION`] when the permissions fit it, so that older
+    /// verifiers accept the subkey, [`SUBKEY_METADATA_VERSION_EXTENDED`] otherwise.
+    pub fn new(
+        name: String,
+        permissions: Vec<SubkeyPermission>,
+        valid_from: Timestamp,
+        expires_at: Timestamp,
+    ) -> Self {
+        let mut metadata = Self {
+            name,
+            nonce: Nonce::new(rand::random()),
+            valid_from,
+            expires_at,
+            permissions,
+            version: SUBKEY_METADATA_VERSION,
+            max_payment_msat: None,
+            allowed_counterparties: vec![],
+        };
+        metadata.update_version();
+        metadata
+    }
+
+    /// Limits every payment of the subkey to `max_payment_msat`
+    pub fn with_max_payment_msat(mut self, max_payment_msat: u64) -> Self {
+        self.max_payment_msat = Some(max_payment_msat);
+        self.update_version();
+        self
+    }
+
+    /// Restricts the subkey to interacting with `counterparties`
+    pub fn with_allowed_counterparties(mut self, counterparties: Vec<PublicKey>) -> Self {
+        self.allowed_counterparties = counterparties.into_iter().map(Into::into).collect();
+        self.update_version();
+        self
+    }
+
+    fn update_version(&mut self) {
+        self.version = if self.is_extended() {
+            SUBKEY_METADATA_VERSION_EXTENDED
+        } else {
+            SUBKEY_METADATA_VERSION
+        };
+    }
+
+    /// Whether the metadata uses anything that the original encoding can't encode
+    fn is_extended(&self) -> bool {
+        self.max_payment_msat.is_some()
+            || !self.allowed_counterparties.is_empty()
+            || self.permissions.iter().any(|p| p.legacy_bit().is_none())
+    }
+
+    /// Whether the subkey has `permission`
+    pub fn allows(&self, permission: &SubkeyPermission) -> bool {
+        self.permissions.is_empty() || self.permissions.contains(permission)
+    }
+
+    /// Fails if the subkey isn't allowed to perform `operation`, or to perform it with
+    /// `counterparty`
+    pub fn authorize(
+        &self,
+        operation: &SubkeyOperation,
+        counterparty: Option<&PublicKey>,
+    ) -> Result<(), SubkeyError> {
+        let permission = operation.permission();
+        if !self.allows(&permission) {
+            return Err(SubkeyError::PermissionDenied(permission));
+        }
+
+        if let SubkeyOperation::Payment { amount_msat } = operation
+            && let Some(max_payment_msat) = self.max_payment_msat
+        {
+            match amount_msat {
+                Some(amount_msat) if *amount_msat <= max_payment_msat => {}
+                Some(amount_msat) => {
+                    return Err(SubkeyError::PaymentLimitExceeded {
+                        amount_msat: *amount_msat,
+                        max_payment_msat,
+                    });
+                }
+                // Fiat amounts without an exchange rate can't be checked against the limit
+                None => return Err(SubkeyError::UnknownPaymentAmount),
+            }
+        }
+
+        if let Some(counterparty) = counterparty
+            && !self.allowed_counterparties.is_empty()
+            && !self
+                .allowed_counterparties
+                .iter()
+                .any(|k| PublicKey::from(*k) == *counterparty)
+        {
+            return Err(SubkeyError::CounterpartyNotAllowed(*counterparty));
+        }
+
+        Ok(())
+    }
+
+    /// Fails if the subkey is not valid at `time`, give or take [`MAX_CLOCK_SKEW_SECS`]
+    pub fn check_validity(&self, time: Timestamp) -> Result<(), SubkeyError> {
+        if time.as_u64().saturating_add(MAX_CLOCK_SKEW_SECS) < self.valid_from.as_u64() {
+            return Err(SubkeyError::NotYetValid);
+        }
+        if self.expires_at.as_u64().saturating_add(MAX_CLOCK_SKEW_SECS) < time.as_u64() {
+            return Err(SubkeyError::Expired);
+        }
+        Ok(())
+    }
+
     /// Produces a deterministic, cross-language binary encoding of the metadata.
     ///
-    /// Layout (all integers little-endian):
+    /// Layout of versions 1 and 2 (all integers little-endian):
     /// ```text
     /// version        (1 byte,  u8)
     /// name_len       (2 bytes, u16 LE)
@@ -107,9 +247,22 @@ impl SubkeyMetadata {
     /// permissions    (1 byte bitmask: Auth=0x01, Payment=0x02)
     /// ```
     ///
+    /// Version 3 replaces the permissions byte with:
+    /// ```text
+    /// permissions       (2 bytes, u16 LE bitmask: Auth=0x01, Payment=0x02, Cashu=0x04,
+    ///                    Invoice=0x08, Nip46=0x10, Profile=0x20)
+    /// has_max_payment   (1 byte, 0 or 1)
+    /// max_payment_msat  (8 bytes, u64 LE, only if has_max_payment is 1)
+    /// counterparty_len  (2 bytes, u16 LE)
+    /// counterparties    (counterparty_len × 32 bytes, x-only public keys in order)
+    /// ```
+    ///
     /// This encoding is used as the message in [`get_tweak`] and must be reproduced
     /// identically by any verifier (mobile app, server) regardless of language or platform.
-    pub fn canonical_bytes(&self) -> Vec<u8> {
+    ///
+    /// Fails with [`SubkeyError::UnsupportedVersion`] if the metadata can't be encoded with its
+    /// version.
+    pub fn canonical_bytes(&self) -> Result<Vec<u8>, SubkeyError> {
         let mut buf = Vec::new();
         buf.push(self.version);
         let name_bytes = self.name.as_bytes();
@@ -118,15 +271,46 @@ impl SubkeyMetadata {
         buf.extend_from_slice(self.nonce.as_bytes());
         buf.extend_from_slice(&self.valid_from.as_u64().to_le_bytes());
         buf.extend_from_slice(&self.expires_at.as_u64().to_le_bytes());
-        let mut perms: u8 = 0;
-        for p in &self.permissions {
-            match p {
-                SubkeyPermission::Auth => perms |= 0x01,
-                SubkeyPermission::Payment => perms |= 0x02,
+
+        match self.version {
+            // Versions before 3 all used the original layout, including the version 2 metadata
+            // created by earlier releases
+            0..SUBKEY_METADATA_VERSION_EXTENDED => {
+                if self.is_extended() {
+                    return Err(SubkeyError::UnsupportedVersion(self.version));
+                }
+
+                let perms = self
+                    .permissions
+                    .iter()
+                    .filter_map(SubkeyPermission::legacy_bit)
+                    .fold(0u8, |perms, bit| perms | bit);
+                buf.push(perms);
+            }
+            SUBKEY_METADATA_VERSION_EXTENDED => {
+                let perms = self
+                    .permissions
+                    .iter()
+                    .fold(0u16, |perms, p| perms | p.bit());
+                buf.extend_from_slice(&perms.to_le_bytes());
+
+                match self.max_payment_msat {
+                    Some(max_payment_msat) => {
+                        buf.push(1);
+                        buf.extend_from_slice(&max_payment_msat.to_le_bytes());
+                    }
+                    None => buf.push(0),
+                }
+
+                buf.extend_from_slice(&(self.allowed_counterparties.len() as u16).to_le_bytes());
+                for counterparty in &self.allowed_counterparties {
+                    buf.extend_from_slice(&PublicKey::from(*counterparty).to_bytes());
+                }
             }
+            version => return Err(SubkeyError::UnsupportedVersion(version)),
         }
-        buf.push(perms);
-        buf
+
+        Ok(buf)
     }
 
     /// Computes the key tweak using BIP340-style tagged hashing (same construction as BIP341 Taproot).
@@ -152,7 +336,7 @@ impl SubkeyMetadata {
         let mut hasher = Sha256::new();
         hasher.update(tag_hash);
         hasher.update(tag_hash);
-        hasher.update(self.canonical_bytes());
+        hasher.update(self.canonical_bytes()?);
         let hash: [u8; 32] = hasher.finalize().into();
         Scalar::from_be_bytes(hash).map_err(|_| SubkeyError::InvalidMetadata)
     }
@@ -164,6 +348,79 @@ impl SubkeyMetadata {
 pub enum SubkeyPermission {
     Auth,
     Payment,
+    /// Cashu token requests and transfers (version 3)
+    Cashu,
+    /// Invoice requests (version 3)
+    Invoice,
+    /// NIP-46 remote signing (version 3)
+    Nip46,
+    /// Profile updates (version 3)
+    Profile,
+}
+
+impl SubkeyPermission {
+    /// Bit of the permission in the version 3 bitmask
+    fn bit(&self) -> u16 {
+        match self {
+            SubkeyPermission::Auth => 0x01,
+            SubkeyPermission::Payment => 0x02,
+            SubkeyPermission::Cashu => 0x04,
+            SubkeyPermission::Invoice => 0x08,
+            SubkeyPermission::Nip46 => 0x10,
+            SubkeyPermission::Profile => 0x20,
+        }
+    }
+
+    /// Bit of the permission in the original bitmask, if it existed back then
+    fn legacy_bit(&self) -> Option<u8> {
+        match self {
+            SubkeyPermission::Auth => Some(0x01),
+            SubkeyPermission::Payment => Some(0x02),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SubkeyPermission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SubkeyPermission::Auth => "auth",
+            SubkeyPermission::Payment => "payment",
+            SubkeyPermission::Cashu => "cashu",
+            SubkeyPermission::Invoice => "invoice",
+            SubkeyPermission::Nip46 => "nip46",
+            SubkeyPermission::Profile => "profile",
+        };
+        f.write_str(name)
+    }
+}
+
+/// An operation performed with a subkey, checked by [`SubkeyMetadata::authorize`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubkeyOperation {
+    Auth,
+    /// A payment of `amount_msat`, `None` if the amount isn't known in millisats
+    Payment {
+        amount_msat: Option<u64>,
+    },
+    Cashu,
+    Invoice,
+    Nip46,
+    Profile,
+}
+
+impl SubkeyOperation {
+    /// The permission required to perform the operation
+    pub fn permission(&self) -> SubkeyPermission {
+        match self {
+            SubkeyOperation::Auth => SubkeyPermission::Auth,
+            SubkeyOperation::Payment { .. } => SubkeyPermission::Payment,
+            SubkeyOperation::Cashu => SubkeyPermission::Cashu,
+            SubkeyOperation::Invoice => SubkeyPermission::Invoice,
+            SubkeyOperation::Nip46 => SubkeyPermission::Nip46,
+            SubkeyOperation::Profile => SubkeyPermission::Profile,
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -176,6 +433,100 @@ pub enum SubkeyError {
 
     #[error("Key error: {0}")]
     Key(#[from] nostr::key::Error),
+
+    #[error("Subkey is not valid yet")]
+    NotYetValid,
+
+    #[error("Subkey has expired")]
+    Expired,
+
+    #[error("Subkey has been revoked by its main key")]
+    Revoked,
+
+    #[error("Unsupported subkey metadata version: {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Subkey is missing the {0} permission")]
+    PermissionDenied(SubkeyPermission),
+
+    #[error("Payment of {amount_msat} msat exceeds the subkey limit of {max_payment_msat} msat")]
+    PaymentLimitExceeded {
+        amount_msat: u64,
+        max_payment_msat: u64,
+    },
+
+    #[error("Payment amount can't be checked against the subkey limit")]
+    UnknownPaymentAmount,
+
+    #[error("Subkey is not allowed to interact with {0}")]
+    CounterpartyNotAllowed(PublicKey),
+}
+
+/// Content of a `SUBKEY_REVOCATION` event
+///
+/// The event is published by the main key, whose signature on the event authenticates the
+/// revocation. Revocations are permanent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubkeyRevocation {
+    pub subkey: PublicKey,
+    pub revoked_at: Timestamp,
+    pub reason: Option<String>,
+}
+
+impl SubkeyRevocation {
+    pub fn new(subkey: PublicKey, reason: Option<String>) -> Self {
+        Self {
+            subkey,
+            revoked_at: Timestamp::now(),
+            reason,
+        }
+    }
+}
+
+/// Subkeys revoked by their main key
+///
+/// Each router owns a registry ([`crate::router::MessageRouter::revoked_subkeys`]), filled with
+/// the revocations seen on its relays and handed to its conversations, which check proofs with
+/// [`SubkeyProof::verify_with`]. Proofs of revoked subkeys are rejected in all the conversations
+/// of the router, including when they switch to a new subkey.
+#[derive(Debug, Default)]
+pub struct RevokedSubkeys {
+    revoked: RwLock<HashMap<PublicKey, HashMap<PublicKey, SubkeyRevocation>>>,
+}
+
+impl RevokedSubkeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a revocation published by `main_key`
+    pub fn insert(&self, main_key: PublicKey, revocation: SubkeyRevocation) {
+        self.revoked
+            .write()
+            .unwrap()
+            .entry(main_key)
+            .or_default()
+            .entry(revocation.subkey)
+            .or_insert(revocation);
+    }
+
+    pub fn is_revoked(&self, main_key: &PublicKey, subkey: &PublicKey) -> bool {
+        self.revoked
+            .read()
+            .unwrap()
+            .get(main_key)
+            .is_some_and(|revoked| revoked.contains_key(subkey))
+    }
+
+    /// The subkeys of `main_key` revoked so far
+    pub fn list(&self, main_key: &PublicKey) -> Vec<SubkeyRevocation> {
+        self.revoked
+            .read()
+            .unwrap()
+            .get(main_key)
+            .map(|revoked| revoked.values().cloned().collect())
+            .unwrap_or_default()
+    }
 }
 
 impl PrivateSubkeyManager for Keys {
@@ -248,6 +599,8 @@ mod tests {
             expires_at: Timestamp::new(expires_at),
             permissions,
             version: 2,
+            max_payment_msat: None,
+            allowed_counterparties: vec![],
         }
     }
 
@@ -438,4 +791,145 @@ mod tests {
                 .unwrap();
         }
     }
+
+    #[test]
+    fn test_subkey_validity_period() {
+        let now = Timestamp::now().as_u64();
+        let metadata = create_test_metadata(
+            "validity",
+            now - 3600,
+            now + 3600,
+            vec![SubkeyPermission::Auth],
+        );
+        metadata.check_validity(Timestamp::new(now)).unwrap();
+        assert!(matches!(
+            metadata.check_validity(Timestamp::new(now + 2 * 3600)),
+            Err(SubkeyError::Expired)
+        ));
+        assert!(matches!(
+            metadata.check_validity(Timestamp::new(now - 2 * 3600)),
+            Err(SubkeyError::NotYetValid)
+        ));
+    }
+
+    #[test]
+    fn test_revoked_subkey_proof_is_rejected() {
+        let main_key = Keys::generate();
+        let metadata = SubkeyMetadata::new(
+            "revoked".to_string(),
+            vec![SubkeyPermission::Auth],
+            Timestamp::now(),
+            Timestamp::now_plus_seconds(3600),
+        );
+        let (subkey, proof) = main_key.create_subkey(&metadata).unwrap().split();
+        let revoked = RevokedSubkeys::new();
+        proof.verify_with(&subkey.public_key(), &revoked).unwrap();
+
+        revoked.insert(
+            main_key.public_key(),
+            SubkeyRevocation::new(subkey.public_key(), Some("leaked".to_string())),
+        );
+        assert!(matches!(
+            proof.verify_with(&subkey.public_key(), &revoked),
+            Err(SubkeyError::Revoked)
+        ));
+        assert_eq!(revoked.list(&main_key.public_key()).len(), 1);
+
+        // Revocations seen by another registry don't apply
+        proof
+            .verify_with(&subkey.public_key(), &RevokedSubkeys::new())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_version_1_encoding_is_unchanged() {
+        let mut metadata = create_test_metadata(
+            "v1",
+            1,
+            2,
+            vec![SubkeyPermission::Auth, SubkeyPermission::Payment],
+        );
+        metadata.version = SUBKEY_METADATA_VERSION;
+
+        let mut expected = vec![1, 2, 0];
+        expected.extend_from_slice(b"v1");
+        expected.extend_from_slice(&[0u8; 32]);
+        expected.extend_from_slice(&1u64.to_le_bytes());
+        expected.extend_from_slice(&2u64.to_le_bytes());
+        expected.push(0x03);
+        assert_eq!(metadata.canonical_bytes().unwrap(), expected);
+
+        // Version 2 metadata of earlier releases keeps the same layout
+        metadata.version = 2;
+        expected[0] = 2;
+        assert_eq!(metadata.canonical_bytes().unwrap(), expected);
+
+        // Extended permissions can't be encoded with the original layout
+        metadata.permissions.push(SubkeyPermission::Cashu);
+        assert!(matches!(
+            metadata.canonical_bytes(),
+            Err(SubkeyError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn test_extended_permissions() {
+        let main_key = Keys::generate();
+        let service = Keys::generate().public_key();
+
+        let legacy = SubkeyMetadata::new(
+            "legacy".to_string(),
+            vec![SubkeyPermission::Auth],
+            Timestamp::new(0),
+            Timestamp::new(u64::MAX),
+        );
+        assert_eq!(legacy.version, SUBKEY_METADATA_VERSION);
+
+        let metadata = SubkeyMetadata::new(
+            "wallet".to_string(),
+            vec![SubkeyPermission::Payment, SubkeyPermission::Cashu],
+            Timestamp::new(0),
+            Timestamp::new(u64::MAX),
+        )
+        .with_max_payment_msat(10_000)
+        .with_allowed_counterparties(vec![service]);
+        assert_eq!(metadata.version, SUBKEY_METADATA_VERSION_EXTENDED);
+
+        let subkey = main_key.create_subkey(&metadata).unwrap();
+        main_key
+            .public_key()
+            .verify_subkey(&subkey.public_key(), &metadata)
+            .unwrap();
+
+        // Changing the limits changes the subkey
+        let raised = metadata.clone().with_max_payment_msat(20_000);
+        assert!(
+            main_key
+                .public_key()
+                .verify_subkey(&subkey.public_key(), &raised)
+                .is_err()
+        );
+
+        let payment = |amount_msat| SubkeyOperation::Payment { amount_msat };
+        metadata
+            .authorize(&payment(Some(10_000)), Some(&service))
+            .unwrap();
+        metadata.authorize(&SubkeyOperation::Cashu, None).unwrap();
+        assert!(matches!(
+            metadata.authorize(&payment(Some(10_001)), Some(&service)),
+            Err(SubkeyError::PaymentLimitExceeded { .. })
+        ));
+        assert!(matches!(
+            metadata.authorize(&payment(None), Some(&service)),
+            Err(SubkeyError::UnknownPaymentAmount)
+        ));
+        assert!(matches!(
+            metadata.authorize(&SubkeyOperation::Nip46, Some(&service)),
+            Err(SubkeyError::PermissionDenied(SubkeyPermission::Nip46))
+        ));
+        assert!(matches!(
+            metadata.authorize(&payment(Some(1)), Some(&main_key.public_key())),
+            Err(SubkeyError::CounterpartyNotAllowed(_))
+        ));
+    }
 }
@@ -1,47 +1,74 @@
 use std::{
-    collections::{HashMap, HashSet}, str::FromStr, sync::Arc
+    collections::{HashMap, HashSet, VecDeque}, str::FromStr, sync::Arc
 };
 
 use nostr::{
-    event::{Event, EventBuilder, Kind},
+    event::{Event, EventBuilder, EventId, Kind},
     filter::{Filter, MatchEventOptions},
     message::{RelayMessage, SubscriptionId},
     nips::nip44,
     types::RelayUrl,
 };
 use nostr_relay_pool::RelayPoolNotification;
-use serde::{Serialize, de::DeserializeOwned};
-use tokio::sync::{mpsc, oneshot};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio_stream::StreamExt;
 
 use crate::{
-    protocol::{LocalKeypair, model::event_kinds::SUBKEY_PROOF},
+    protocol::{LocalKeypair, model::{Timestamp, event_kinds::SUBKEY_PROOF}, subkey::RevokedSubkeys},
     router::{
-        CleartextEvent, Conversation, ConversationError, ConversationMessage, NotificationStream, PortalConversationId, PortalSubscriptionId, Response, channel::Channel
+        CleartextEvent, Conversation, ConversationError, ConversationMessage, NotificationStream, PortalConversationId, PortalSubscriptionId, Response, channel::Channel,
+        multiplexer::{SubscriptionMultiplexer, SubscriptionUpdate},
+        outbox::{EventOutbox, InMemoryOutbox, OutboxEntry},
+        store::{ConversationRecord, ConversationRegistry, ConversationStore, ConversationStoreWriter, InMemoryConversationStore, PersistentConversationBox, ResumedConversation},
     },
 };
 
-/// Max events waiting for relay retry; avoids unbounded memory if a relay stays down.
-const MAX_PENDING_RELAY_EVENTS: usize = 512;
+/// Capacity of the delivery updates channel; slow subscribers miss the oldest updates.
+const DELIVERY_UPDATES_CAPACITY: usize = 1024;
+
+/// Event IDs remembered by each conversation. The same event can arrive on a shared subscription
+/// and on a backfill, or again when a shared subscription is updated, it must not be dispatched
+/// twice.
+const MAX_SEEN_EVENTS: usize = 256;
 
 /// Outcome of attempting to send an event to relays.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
 pub enum SendOutcome {
     /// At least one relay accepted the send; `relays` lists those URLs (from the pool send result).
     Delivered { relays: Vec<String> },
     /// No relay was available; the event has been queued for retry.
     Queued,
-    /// The pending queue was full and the event was dropped.
+    /// The outbox was full (or could not be written) and the event was dropped.
     Dropped,
 }
 
 /// Result of sending a single Nostr event, pairing the event ID with its delivery outcome.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EventSendResult {
     pub event_id: nostr::EventId,
     pub outcome: SendOutcome,
 }
 
+/// Storage backends of the router
+#[derive(Clone)]
+pub struct RouterStorage {
+    /// Where persistent conversations are saved
+    pub conversations: Arc<dyn ConversationStore>,
+    /// Where events that could not be delivered to every relay wait for retry
+    pub outbox: Arc<dyn EventOutbox>,
+}
+
+impl Default for RouterStorage {
+    fn default() -> Self {
+        Self {
+            conversations: Arc::new(InMemoryConversationStore::new()),
+            outbox: Arc::new(InMemoryOutbox::new()),
+        }
+    }
+}
+
 type AddAndSubscribeResponseTx = oneshot::Sender<
     Result<(NotificationStream<serde_json::Value>, Vec<EventSendResult>), ConversationError>,
 >;
@@ -76,6 +103,20 @@ pub enum MessageRouterActorMessage {
         oneshot::Sender<Result<NotificationStream<serde_json::Value>, ConversationError>>,
     ),
     AddAndSubscribe(ConversationBox, AddAndSubscribeResponseTx),
+    AddPersistentAndSubscribe(PersistentConversationBox, String, AddAndSubscribeResponseTx),
+    ResumeConversations(
+        ConversationRegistry,
+        oneshot::Sender<Result<Vec<ResumedConversation>, ConversationError>>,
+    ),
+    ListOutbox(oneshot::Sender<Result<Vec<OutboxEntry>, ConversationError>>),
+    RetryOutbox(
+        Option<nostr::EventId>,
+        oneshot::Sender<Result<usize, ConversationError>>,
+    ),
+    PurgeOutbox(
+        Option<nostr::EventId>,
+        oneshot::Sender<Result<usize, ConversationError>>,
+    ),
     Ping(oneshot::Sender<()>),
 
     /// This is used to handle relay pool notifications.
@@ -90,6 +131,8 @@ where
     channel: Arc<C>,
     keypair: LocalKeypair,
     sender: mpsc::Sender<MessageRouterActorMessage>,
+    delivery_updates: broadcast::Sender<EventSendResult>,
+    revoked_subkeys: Arc<RevokedSubkeys>,
 }
 
 impl<C> MessageRouterActor<C>
@@ -98,14 +141,28 @@ where
     C::Error: From<nostr::types::url::Error>,
 {
     pub fn new(channel: C, keypair: LocalKeypair) -> Self {
+        Self::new_with_storage(channel, keypair, RouterStorage::default())
+    }
+
+    /// Creates a router that keeps persistent conversations and queued events in `storage`
+    pub fn new_with_storage(channel: C, keypair: LocalKeypair, storage: RouterStorage) -> Self {
         let keypair_clone = keypair.clone();
         let channel = Arc::new(channel);
 
         let (tx, mut rx) = mpsc::channel(4096);
+        let (delivery_updates, _) = broadcast::channel(DELIVERY_UPDATES_CAPACITY);
+        let revoked_subkeys = Arc::new(RevokedSubkeys::new());
 
         let channel_clone = Arc::clone(&channel);
+        let delivery_updates_clone = delivery_updates.clone();
+        let revoked_subkeys_clone = Arc::clone(&revoked_subkeys);
         tokio::spawn(async move {
-            let mut state = MessageRouterActorState::new(keypair_clone);
+            let mut state = MessageRouterActorState::new(
+                keypair_clone,
+                storage,
+                delivery_updates_clone,
+                revoked_subkeys_clone,
+            );
             while let Some(message) = rx.recv().await {
                 match message {
                     MessageRouterActorMessage::AddRelay(
@@ -175,6 +232,49 @@ where
                             log::error!("Failed to send AddAndSubscribe response: {:?}", e);
                         }
                     }
+                    MessageRouterActorMessage::AddPersistentAndSubscribe(
+                        conversation,
+                        tag,
+                        response_tx,
+                    ) => {
+                        let result = state
+                            .add_persistent_and_subscribe::<_, serde_json::Value>(
+                                &channel_clone,
+                                conversation,
+                                tag,
+                            )
+                            .await;
+                        if let Err(e) = response_tx.send(result) {
+                            log::error!(
+                                "Failed to send AddPersistentAndSubscribe response: {:?}",
+                                e
+                            );
+                        }
+                    }
+                    MessageRouterActorMessage::ResumeConversations(registry, response_tx) => {
+                        let result = state.resume_conversations(&channel_clone, &registry).await;
+                        if let Err(e) = response_tx.send(result) {
+                            log::error!("Failed to send ResumeConversations response: {:?}", e);
+                        }
+                    }
+                    MessageRouterActorMessage::ListOutbox(response_tx) => {
+                        let result = state.list_outbox();
+                        if let Err(e) = response_tx.send(result) {
+                            log::error!("Failed to send ListOutbox response: {:?}", e);
+                        }
+                    }
+                    MessageRouterActorMessage::RetryOutbox(event_id, response_tx) => {
+                        let result = state.retry_outbox(&channel_clone, event_id).await;
+                        if let Err(e) = response_tx.send(result) {
+                            log::error!("Failed to send RetryOutbox response: {:?}", e);
+                        }
+                    }
+                    MessageRouterActorMessage::PurgeOutbox(event_id, response_tx) => {
+                        let result = state.purge_outbox(event_id);
+                        if let Err(e) = response_tx.send(result) {
+                            log::error!("Failed to send PurgeOutbox response: {:?}", e);
+                        }
+                    }
                     MessageRouterActorMessage::Ping(response_tx) => {
                         let _ = response_tx.send(());
                     }
@@ -196,6 +296,8 @@ where
             channel: Arc::clone(&channel),
             keypair,
             sender: tx,
+            delivery_updates,
+            revoked_subkeys,
         }
     }
 
@@ -221,6 +323,24 @@ where
         &self.keypair
     }
 
+    /// The subkeys revoked so far, as seen by the conversations of this router
+    ///
+    /// The revocation listener records the revocations it receives here, and so should the
+    /// owner of the router for the subkeys it revokes itself.
+    pub fn revoked_subkeys(&self) -> &Arc<RevokedSubkeys> {
+        &self.revoked_subkeys
+    }
+
+    /// Subscribes to the delivery updates of queued events.
+    ///
+    /// An update is sent when an event that was queued because no relay accepted it is
+    /// delivered to its first relay ([`SendOutcome::Delivered`]), or when it's purged from the
+    /// outbox ([`SendOutcome::Dropped`]). Events that are delivered to some of their relays
+    /// right away don't get updates.
+    pub fn delivery_updates(&self) -> broadcast::Receiver<EventSendResult> {
+        self.delivery_updates.subscribe()
+    }
+
     pub async fn listen(&self) -> Result<(), MessageRouterActorError> {
         while let Ok(notification) = self.channel.receive().await {
             // Send notification directly without oneshot channel
@@ -381,6 +501,86 @@ where
         Ok((NotificationStream::new(typed_stream), outcomes))
     }
 
+    /// Adds a persistent conversation and subscribes to its notifications.
+    ///
+    /// The conversation is saved in the conversation store under `tag` until it finishes or
+    /// expires, so it can be picked up again with [`Self::resume_conversations`] after a restart.
+    pub async fn add_persistent_and_subscribe<T: DeserializeOwned + Serialize>(
+        &self,
+        conversation: PersistentConversationBox,
+        tag: String,
+    ) -> Result<(NotificationStream<T>, Vec<EventSendResult>), MessageRouterActorError> {
+        let (tx, rx) = oneshot::channel();
+        self.send_message(MessageRouterActorMessage::AddPersistentAndSubscribe(
+            conversation,
+            tag,
+            tx,
+        ))
+        .await?;
+        let (raw_stream, outcomes) = rx
+            .await
+            .map_err(MessageRouterActorError::Receiver)?
+            .map_err(MessageRouterActorError::Conversation)?;
+        let NotificationStream { stream } = raw_stream;
+        let typed_stream =
+            stream.map(|result| result.and_then(|value| serde_json::from_value(value)));
+        Ok((NotificationStream::new(typed_stream), outcomes))
+    }
+
+    /// Restores the conversations saved in the conversation store and re-subscribes them.
+    ///
+    /// Conversations whose type is not in `registry`, or that expired in the meantime, are
+    /// dropped from the store.
+    pub async fn resume_conversations(
+        &self,
+        registry: ConversationRegistry,
+    ) -> Result<Vec<ResumedConversation>, MessageRouterActorError> {
+        let (tx, rx) = oneshot::channel();
+        self.send_message(MessageRouterActorMessage::ResumeConversations(registry, tx))
+            .await?;
+        let result = rx.await.map_err(MessageRouterActorError::Receiver)?;
+        result.map_err(MessageRouterActorError::Conversation)
+    }
+
+    /// Lists the events waiting in the outbox, oldest first.
+    pub async fn list_outbox(&self) -> Result<Vec<OutboxEntry>, MessageRouterActorError> {
+        let (tx, rx) = oneshot::channel();
+        self.send_message(MessageRouterActorMessage::ListOutbox(tx))
+            .await?;
+        let result = rx.await.map_err(MessageRouterActorError::Receiver)?;
+        result.map_err(MessageRouterActorError::Conversation)
+    }
+
+    /// Retries the queued event `event_id` (or every queued event if `None`) right away,
+    /// ignoring its backoff.
+    ///
+    /// Returns the number of events that are now delivered to all their relays.
+    pub async fn retry_outbox(
+        &self,
+        event_id: Option<nostr::EventId>,
+    ) -> Result<usize, MessageRouterActorError> {
+        let (tx, rx) = oneshot::channel();
+        self.send_message(MessageRouterActorMessage::RetryOutbox(event_id, tx))
+            .await?;
+        let result = rx.await.map_err(MessageRouterActorError::Receiver)?;
+        result.map_err(MessageRouterActorError::Conversation)
+    }
+
+    /// Removes the queued event `event_id` (or every queued event if `None`) from the outbox
+    /// without delivering it.
+    ///
+    /// Returns the number of events removed.
+    pub async fn purge_outbox(
+        &self,
+        event_id: Option<nostr::EventId>,
+    ) -> Result<usize, MessageRouterActorError> {
+        let (tx, rx) = oneshot::channel();
+        self.send_message(MessageRouterActorMessage::PurgeOutbox(event_id, tx))
+            .await?;
+        let result = rx.await.map_err(MessageRouterActorError::Receiver)?;
+        result.map_err(MessageRouterActorError::Conversation)
+    }
+
     /// Adds a conversation and subscribes to its notifications in a single operation (raw Value).
     async fn add_and_subscribe_raw(
         &self,
@@ -398,18 +598,44 @@ pub struct MessageRouterActorState {
     keypair: LocalKeypair,
     /// All conversation states
     conversations: HashMap<PortalConversationId, ConversationState>,
-    /// Events queued while relays are disconnected. Each entry is:
-    /// (event, optional target-relay subset). Ordering is preserved.
-    pending_events: Vec<(Event, Option<HashSet<String>>)>,
+    /// Relay subscriptions shared by the conversations
+    subscriptions: SubscriptionMultiplexer,
+    /// Events that were not accepted by all their relays yet
+    outbox: Arc<dyn EventOutbox>,
+    /// Earliest time an event in the outbox is due for retry, `None` if the outbox is empty.
+    /// Avoids reading the outbox on every relay notification.
+    next_outbox_attempt: Option<u64>,
+    /// Where delivery updates of queued events are sent
+    delivery_updates: broadcast::Sender<EventSendResult>,
+    /// Storage for persistent conversations, read when resuming them
+    store: Arc<dyn ConversationStore>,
+    /// Saves and removes persistent conversations without blocking the router
+    store_writer: ConversationStoreWriter,
+    /// Handed to every conversation, see [`Conversation::set_revoked_subkeys`]
+    revoked_subkeys: Arc<RevokedSubkeys>,
 }
 
 impl MessageRouterActorState {
-    pub fn new(keypair: LocalKeypair) -> Self {
-        Self {
+    pub fn new(
+        keypair: LocalKeypair,
+        storage: RouterStorage,
+        delivery_updates: broadcast::Sender<EventSendResult>,
+        revoked_subkeys: Arc<RevokedSubkeys>,
+    ) -> Self {
+        let mut state = Self {
             keypair,
             conversations: HashMap::new(),
-            pending_events: Vec::new(),
-        }
+            subscriptions: SubscriptionMultiplexer::new(),
+            outbox: storage.outbox,
+            next_outbox_attempt: None,
+            delivery_updates,
+            store_writer: ConversationStoreWriter::new(storage.conversations.clone()),
+            store: storage.conversations,
+            revoked_subkeys,
+        };
+        // Events left over from a previous run are retried on the first relay notification
+        state.refresh_next_outbox_attempt();
+        state
     }
 
     pub async fn add_relay<C: Channel>(
@@ -423,43 +649,15 @@ impl MessageRouterActorState {
     {
         // Subscribe existing global conversations to new relay
         if subscribe_existing_conversations {
-            // First, collect all global conversations and their aliases
-            let mut global_conversations = Vec::new();
-            let mut aliases_to_subscribe = Vec::new();
-
-            for (conversation_id, conv_state) in self.conversations.iter() {
-                if conv_state.is_global() {
-                    if let Some(filter) = conv_state.filter.as_ref() {
-                        global_conversations.push((conversation_id.clone(), filter.clone(), conv_state.subscription_id.clone()));
-                    }
-
-                    // Collect aliases
-                    for alias in conv_state.aliases() {
-                        if let Some(alias_state) = self.conversations.get(alias)
-                            && let Some(filter) = &alias_state.filter {
-                                aliases_to_subscribe.push((alias, filter.clone(), conv_state.subscription_id.clone()));
-                            }
-                    }
-                }
-            }
-
-            // Subscribe to the new relay
-            for (conversation_id, filter, subscription_id) in global_conversations {
-                log::trace!("Subscribing {} to new relay = {:?}", conversation_id, url);
+            for (subscription_id, filter) in self.subscriptions.global_subscriptions() {
+                log::trace!("Subscribing {} to new relay = {:?}", subscription_id, url);
                 channel
-                    .subscribe_to(vec![url.clone()], subscription_id.clone(), filter)
+                    .subscribe_to(vec![url.clone()], subscription_id, filter)
                     .await
                     .map_err(|e| ConversationError::Inner(Box::new(e)))?;
             }
 
-            for (_, filter, subscription_id) in aliases_to_subscribe {
-                channel
-                    .subscribe_to(vec![url.clone()], subscription_id.clone(), filter)
-                    .await
-                    .map_err(|e| ConversationError::Inner(Box::new(e)))?;
-            }
-
-            // Increment EOSE counters for all global conversations
+            // Global conversations still waiting for their stored events wait for the new relay too
             for (conversation_id, conv_state) in self.conversations.iter_mut() {
                 if conv_state.is_global() {
                     conv_state.increment_eose();
@@ -482,6 +680,8 @@ impl MessageRouterActorState {
     where
         C::Error: From<nostr::types::url::Error>,
     {
+        self.subscriptions.remove_relay(&url);
+
         let mut conversations_to_cleanup = Vec::new();
 
         // Find conversations that are affected by this relay removal
@@ -535,22 +735,22 @@ impl MessageRouterActorState {
     {
         // Remove conversation state
         if let Some(conv_state) = self.conversations.remove(conversation) {
-            // Remove filters from relays
-            channel
-                .unsubscribe(conv_state.subscription_id.clone())
-                .await
-                .map_err(|e| ConversationError::Inner(Box::new(e)))?;
+            if let InnerConversationState::Persistent { tag, .. } = &conv_state.conversation {
+                self.store_writer.remove(tag.clone());
+            }
+
+            // Remove filters from the shared subscriptions
+            let mut updates = self.subscriptions.remove(conversation);
 
             // Remove aliases
             for alias in conv_state.aliases() {
-                channel
-                    .unsubscribe(conv_state.subscription_id.clone())
-                    .await
-                    .map_err(|e| ConversationError::Inner(Box::new(e)))?;
+                updates.extend(self.subscriptions.remove(alias));
 
                 // Also remove the alias conversation state
                 self.conversations.remove(alias);
             }
+
+            self.apply_subscription_updates(channel, updates).await?;
         }
 
         Ok(())
@@ -567,9 +767,86 @@ impl MessageRouterActorState {
             .map_err(|e| ConversationError::Inner(Box::new(e)))?;
 
         self.conversations.clear();
+        self.subscriptions.clear();
         Ok(())
     }
 
+    /// Adds the filter of a conversation to the shared subscriptions and resets its EOSE counter
+    /// to the relays of its backfill
+    async fn subscribe_conversation<C: Channel>(
+        &mut self,
+        channel: &Arc<C>,
+        id: &PortalConversationId,
+        filter: Filter,
+    ) -> Result<(), ConversationError>
+    where
+        C::Error: From<nostr::types::url::Error>,
+    {
+        let relays = self.get_relays_by_conversation(id)?;
+        let (subscription_id, updates) =
+            self.subscriptions
+                .insert(id.clone(), filter.clone(), relays.as_ref());
+        log::trace!("Conversation {} joined subscription {}", id, subscription_id);
+
+        let num_relays = self.apply_subscription_updates(channel, updates).await?;
+        if let Some(conv_state) = self.conversations.get_mut(id) {
+            conv_state.filter = Some(filter);
+            conv_state.set_eose_counter(num_relays);
+        }
+
+        Ok(())
+    }
+
+    /// Sends the changes of the shared subscriptions to the relays.
+    ///
+    /// Returns the number of relays the last subscription was sent to, the backfill of the
+    /// conversation when coming from [`SubscriptionMultiplexer::insert`].
+    async fn apply_subscription_updates<C: Channel>(
+        &self,
+        channel: &Arc<C>,
+        updates: Vec<SubscriptionUpdate>,
+    ) -> Result<usize, ConversationError>
+    where
+        C::Error: From<nostr::types::url::Error>,
+    {
+        let mut num_relays = 0;
+        for update in updates {
+            match update {
+                SubscriptionUpdate::Subscribe {
+                    id,
+                    filter,
+                    relays: Some(relays),
+                } => {
+                    log::trace!("Subscribing {} to relays = {:?}", id, relays);
+                    num_relays = relays.len();
+                    channel
+                        .subscribe_to(relays, id, filter)
+                        .await
+                        .map_err(|e| ConversationError::Inner(Box::new(e)))?;
+                }
+                SubscriptionUpdate::Subscribe {
+                    id,
+                    filter,
+                    relays: None,
+                } => {
+                    log::trace!("Subscribing {} to all relays", id);
+                    num_relays = channel
+                        .subscribe(id, filter)
+                        .await
+                        .map_err(|e| ConversationError::Inner(Box::new(e)))?;
+                }
+                SubscriptionUpdate::Unsubscribe { id } => {
+                    log::trace!("Closing subscription {}", id);
+                    channel
+                        .unsubscribe(id)
+                        .await
+                        .map_err(|e| ConversationError::Inner(Box::new(e)))?;
+                }
+            }
+        }
+        Ok(num_relays)
+    }
+
     async fn handle_relay_pool_notification<C: Channel>(
         &mut self,
         channel: &Arc<C>,
@@ -593,7 +870,8 @@ impl MessageRouterActorState {
         #[allow(clippy::large_enum_variant)]
         enum LocalEvent {
             Message(Event),
-            EndOfStoredEvents,
+            /// The conversations that received the EOSE of all their relays
+            EndOfStoredEvents(Vec<PortalConversationId>),
         }
         log::trace!("Notification = {:?}", notification);
 
@@ -633,23 +911,24 @@ impl MessageRouterActorState {
                     }
                 };
 
-                let remaining = if let Some((_, conv_state)) = self.conversations.iter_mut().find(|(_, conv_state)| conv_state.subscription_id == portal_subscription_id) {
-                    let remaining = conv_state.decrement_eose();
-                    if remaining == Some(0) {
-                        conv_state.clear_eose();
-                    }
-                    remaining
-                } else {
-                    None
+                // Only the backfill of a conversation tells it the stored events were all sent,
+                // shared subscriptions are for live events
+                let Some(member) = self.subscriptions.backfill_conversation(&portal_subscription_id) else {
+                    return Ok(());
                 };
-
-                log::trace!("{:?} EOSE left for {}", remaining, portal_subscription_id);
-
-                if remaining == Some(0) {
-                    (subscription_id.into_owned(), LocalEvent::EndOfStoredEvents)
-                } else {
+                let Some(conv_state) = self.conversations.get_mut(&member) else {
+                    return Ok(());
+                };
+                let remaining = conv_state.decrement_eose();
+                log::trace!("{:?} EOSE left for {}", remaining, member);
+                if remaining != Some(0) {
                     return Ok(());
                 }
+                conv_state.clear_eose();
+
+                let updates = self.subscriptions.end_backfill(&portal_subscription_id).into_iter().collect();
+                self.apply_subscription_updates(channel, updates).await?;
+                (subscription_id.into_owned(), LocalEvent::EndOfStoredEvents(vec![member]))
             }
             _ => return Ok(()),
         };
@@ -687,48 +966,56 @@ impl MessageRouterActorState {
                     ConversationMessage::Encrypted(event.clone())
                 }
             }
-            LocalEvent::EndOfStoredEvents => ConversationMessage::EndOfStoredEvents,
+            LocalEvent::EndOfStoredEvents(_) => ConversationMessage::EndOfStoredEvents,
         };
 
-        let subscription_id = match PortalSubscriptionId::from_str(subscription_id.as_str()) {
-            Ok(id) => id,
-            Err(e) => {
-                log::warn!("Invalid subscription ID format: {:?}: {}", e, subscription_id);
-                return Ok(());
-            }
-        };
+        // Events can still arrive on a subscription closed in the meantime
+        if let LocalEvent::Message(_) = &event
+            && let Ok(subscription_id) = PortalSubscriptionId::from_str(subscription_id.as_str())
+            && !self.subscriptions.contains(&subscription_id)
+        {
+            log::debug!("Closing unknown subscription {}", subscription_id);
+            channel
+                .unsubscribe(subscription_id)
+                .await
+                .map_err(|e| ConversationError::Inner(Box::new(e)))?;
+        }
 
-        self.dispatch_event(channel, subscription_id.clone(), message.clone())
-            .await?;
+        // The stored events of a backfill are only for the conversation that asked for them
+        let backfill_of = PortalSubscriptionId::from_str(subscription_id.as_str())
+            .ok()
+            .and_then(|id| self.subscriptions.backfill_conversation(&id));
 
         let mut to_cleanup = vec![];
-        let mut other_conversations = vec![];
-
-        // Check if there are other potential conversations to dispatch to
-        for (id, conv_state) in self.conversations.iter() {
-            if conv_state.subscription_id == subscription_id {
-                continue;
-            }
+        let mut targets = match &event {
+            LocalEvent::Message(_) => vec![],
+            LocalEvent::EndOfStoredEvents(conversations) => conversations.clone(),
+        };
 
+        for (id, conv_state) in self.conversations.iter_mut() {
             if conv_state.conversation.is_expired() {
                 to_cleanup.push(id.clone());
                 continue;
             }
 
+            // A shared subscription matches the events of all of its conversations, so events
+            // are dispatched to the conversations whose own filter matches them
             if let LocalEvent::Message(event) = &event
+                && backfill_of.as_ref().is_none_or(|member| member == id)
                 && let Some(filter) = &conv_state.filter
-                    && filter.match_event(event, MatchEventOptions::default()) {
-                        other_conversations.push(conv_state.subscription_id.clone());
-                    }
+                && filter.match_event(event, MatchEventOptions::default())
+                && conv_state.mark_seen(event.id)
+            {
+                targets.push(id.clone());
+            }
         }
 
         for id in to_cleanup {
             self.cleanup_conversation(channel, &id).await?;
         }
 
-        for id in other_conversations {
-            self.dispatch_event(channel, id, message.clone())
-                .await?;
+        for id in targets {
+            self.dispatch_event(channel, &id, message.clone()).await?;
         }
         Ok(())
     }
@@ -736,42 +1023,30 @@ impl MessageRouterActorState {
     async fn dispatch_event<C: Channel>(
         &mut self,
         channel: &Arc<C>,
-        subscription_id: PortalSubscriptionId,
+        conversation_id: &PortalConversationId,
         message: ConversationMessage,
     ) -> Result<(), ConversationError>
     where
         C::Error: From<nostr::types::url::Error>,
     {
-        log::debug!("Dispatching event to subscription: {}", subscription_id);
-
+        log::debug!("Dispatching event to conversation: {}", conversation_id);
 
-        let (response, conversation_id) = match self.conversations.iter_mut().find(|(_, conv_state)| conv_state.subscription_id == subscription_id) {
-            Some((conversation_id, conv_state)) => {
-                log::debug!("Found conversation, processing message");
-                
-                let response = match conv_state.conversation.on_message(message) {
-                    Ok(response) => response,
-                    Err(e) => {
-                        log::warn!("Error in conversation id {}: {:?}", conversation_id, e);
-                        Response::new().finish()
-                    }
-                };
-
-                (response, conversation_id.clone())
-            }
-            None => {
-                log::warn!("No conversation found for subscription id: {}", subscription_id);
-                channel
-                    .unsubscribe(subscription_id.clone())
-                    .await
-                    .map_err(|e| ConversationError::Inner(Box::new(e)))?;
+        // The conversation may have been cleaned up by a previous dispatch
+        let Some(conv_state) = self.conversations.get_mut(conversation_id) else {
+            log::debug!("Conversation {} not found, skipping event", conversation_id);
+            return Ok(());
+        };
 
-                return Ok(());
+        let response = match conv_state.conversation.on_message(message) {
+            Ok(response) => response,
+            Err(e) => {
+                log::warn!("Error in conversation id {}: {:?}", conversation_id, e);
+                Response::new().finish()
             }
         };
 
         log::debug!("Processing response for conversation: {}", conversation_id);
-        let outcomes = self.process_response(channel, &conversation_id, response, subscription_id)
+        let outcomes = self.process_response(channel, conversation_id, response)
             .await?;
         if outcomes.iter().any(|r| r.outcome == SendOutcome::Queued) {
             log::warn!("One or more events for conversation {} were queued (no relay connected)", conversation_id);
@@ -785,7 +1060,6 @@ impl MessageRouterActorState {
         channel: &Arc<C>,
         id: &PortalConversationId,
         response: Response,
-        subscription_id: PortalSubscriptionId,
     ) -> Result<Vec<EventSendResult>, ConversationError>
     where
         C::Error: From<nostr::types::url::Error>,
@@ -801,27 +1075,8 @@ impl MessageRouterActorState {
                 response.filter
             );
 
-            let num_relays = if let Some(selected_relays) = selected_relays_optional.clone() {
-                let num_relays = selected_relays.len();
-                log::trace!("Subscribing to relays = {:?}", selected_relays);
-                channel
-                    .subscribe_to(selected_relays, subscription_id.clone(), response.filter.clone())
-                    .await
-                    .map_err(|e| ConversationError::Inner(Box::new(e)))?;
-
-                num_relays
-            } else {
-                log::trace!("Subscribing to all relays");
-                channel
-                    .subscribe(subscription_id.clone(), response.filter.clone())
-                    .await
-                    .map_err(|e| ConversationError::Inner(Box::new(e)))?
-            };
-
-            if let Some(conv_state) = self.conversations.get_mut(id) {
-                conv_state.filter = Some(response.filter.clone());
-                conv_state.set_eose_counter(num_relays);
-            }
+            self.subscribe_conversation(channel, id, response.filter.clone())
+                .await?;
         }
 
         let mut events_to_broadcast = vec![];
@@ -883,25 +1138,12 @@ impl MessageRouterActorState {
                 .kinds(vec![Kind::Custom(SUBKEY_PROOF)])
                 .events(events_to_broadcast.iter().map(|e| e.id));
 
-            let subscription_id = PortalSubscriptionId::generate();
             // Create a new ConversationState for the alias
             self.conversations.insert(
                 alias.clone(),
-                ConversationState::new_alias(alias.clone(), filter.clone(), subscription_id.clone()),
+                ConversationState::new_alias(alias.clone(), selected_relays_optional.clone()),
             );
-
-            if let Some(selected_relays) = selected_relays_optional.clone() {
-                channel
-                    .subscribe_to(selected_relays, subscription_id.clone(), filter)
-                    .await
-                    .map_err(|e| ConversationError::Inner(Box::new(e)))?;
-            } else {
-                // Subscribe to subkey proofs to all
-                channel
-                    .subscribe(subscription_id.clone(), filter)
-                    .await
-                    .map_err(|e| ConversationError::Inner(Box::new(e)))?;
-            }
+            self.subscribe_conversation(channel, &alias, filter).await?;
         }
 
         // check if Response has selected relays
@@ -924,11 +1166,49 @@ impl MessageRouterActorState {
         if response.finished {
             log::info!("Conversation {} finished, cleaning up", id);
             self.cleanup_conversation(channel, id).await?;
+        } else {
+            self.persist_conversation(id);
         }
 
         Ok(outcomes)
     }
 
+    /// Saves the current state of a persistent conversation in the store, in the background.
+    fn persist_conversation(&self, id: &PortalConversationId) {
+        let Some(conv_state) = self.conversations.get(id) else {
+            return;
+        };
+        let InnerConversationState::Persistent { conversation, tag } = &conv_state.conversation
+        else {
+            return;
+        };
+
+        let state = match conversation.snapshot() {
+            Ok(state) => state,
+            Err(e) => {
+                log::error!("Failed to serialize conversation {}: {:?}", tag, e);
+                return;
+            }
+        };
+        let alias_filters = conv_state
+            .aliases()
+            .iter()
+            .filter_map(|alias| self.conversations.get(alias)?.filter.clone())
+            .collect();
+
+        let record = ConversationRecord {
+            tag: tag.clone(),
+            conversation_type: conversation.conversation_type().to_string(),
+            state,
+            filter: conv_state.filter.clone(),
+            alias_filters,
+            relays: (!conv_state.is_global())
+                .then(|| conv_state.relay_urls().iter().cloned().collect()),
+            updated_at: ConversationRecord::now(),
+        };
+        self.store_writer.save(record);
+    }
+
     async fn queue_event<C: Channel>(
         &mut self,
         channel: &Arc<C>,
@@ -969,18 +1249,35 @@ impl MessageRouterActorState {
             failed
         );
 
-        // Queue only for the failed relays (surgical retry), but keep memory bounded.
-        if self.pending_events.len() >= MAX_PENDING_RELAY_EVENTS {
+        // Queue only for the failed relays (surgical retry), but keep the outbox bounded.
+        let full = match (self.outbox.capacity(), self.outbox.count()) {
+            (Some(capacity), Ok(count)) => count >= capacity,
+            (None, Ok(_)) => false,
+            (_, Err(e)) => {
+                log::error!("Failed to read the outbox: {:?}", e);
+                true
+            }
+        };
+        if full {
             log::error!(
-                "pending relay event queue is full (max {}), dropping retry for event {:?}",
-                MAX_PENDING_RELAY_EVENTS,
+                "Outbox is full (max {:?}), dropping retry for event {:?}",
+                self.outbox.capacity(),
                 event.id
             );
             return Ok(EventSendResult { event_id, outcome: SendOutcome::Dropped });
         }
 
         let all_failed = failed.len() >= all_targeted;
-        self.pending_events.push((event, Some(failed)));
+        let entry = OutboxEntry::new(event, failed, succeeded.clone());
+        let next_attempt_at = entry.next_attempt_at;
+        if let Err(e) = self.outbox.save(entry) {
+            log::error!("Failed to queue event {:?} in the outbox: {:?}", event_id, e);
+            return Ok(EventSendResult { event_id, outcome: SendOutcome::Dropped });
+        }
+        self.next_outbox_attempt = Some(
+            self.next_outbox_attempt
+                .map_or(next_attempt_at, |next| next.min(next_attempt_at)),
+        );
 
         if all_failed {
             Ok(EventSendResult { event_id, outcome: SendOutcome::Queued })
@@ -992,105 +1289,210 @@ impl MessageRouterActorState {
         }
     }
 
-    /// Attempt to flush any events that were queued while the relay was disconnected.
+    /// Attempt to flush the events in the outbox whose backoff has elapsed.
+    ///
+    /// No fail-fast: processes every due event independently and only removes those
+    /// that were accepted by all their relays, so a single stuck event won't block the rest.
     ///
-    /// No fail-fast: processes every pending event independently and only removes those
-    /// that succeed, so a single stuck event won't block the rest.
+    /// Relay-aware: if `relay_url` is `Some`, events that are not pending on that relay
+    /// are skipped (left in the outbox for a later notification from the right relay).
+    /// If `relay_url` is `None`, all due events are attempted.
     ///
-    /// Relay-aware: if `relay_url` is `Some`, events whose target-relay set does *not*
-    /// include that relay are skipped (left in queue for later notification from
-    /// the correct relay). If `relay_url` is `None`, all pending events are attempted.
+    /// Returns the number of events that were fully delivered.
     async fn flush_pending_events<C: Channel>(
         &mut self,
         channel: &Arc<C>,
         relay_url: Option<&RelayUrl>,
-    ) where
+    ) -> usize
+    where
         C::Error: From<nostr::types::url::Error>,
     {
-        if self.pending_events.is_empty() {
-            return;
+        let now = Timestamp::now().as_u64();
+        match self.next_outbox_attempt {
+            Some(next) if next <= now => {}
+            _ => return 0,
         }
 
-        log::debug!("Flushing {} pending event(s)", self.pending_events.len());
+        let entries = match self.outbox.load_all() {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!("Failed to read the outbox: {:?}", e);
+                return 0;
+            }
+        };
+
+        log::debug!("Flushing outbox ({} queued event(s))", entries.len());
 
         // Pre-compute the string form once so we can compare against HashSet<String>.
         let relay_url_str = relay_url.map(|u| u.to_string());
-        let pending = std::mem::take(&mut self.pending_events);
-        let before = pending.len();
-        let mut still_pending = Vec::new();
-
-        for (event, target_relays) in pending {
-            // Decide whether this event is relevant for the notifying relay.
-            let should_try = match (&target_relays, relay_url_str.as_deref()) {
-                // No target constraint -> always try broadcast.
-                (None, _) => true,
-                // Target constraint but no relay hint -> conservative: try anyway.
-                (Some(_), None) => true,
-                // Target constraint + relay hint -> only if relay is in the target set.
-                (Some(relays), Some(url_str)) => relays.contains(url_str),
-            };
-
-            if !should_try {
-                // This relay isn't relevant; leave event in queue.
-                still_pending.push((event, target_relays));
-                continue;
-            }
-
-            let result = if let Some(ref relays) = target_relays {
-                channel
-                    .broadcast_to(relays.clone(), event.clone())
+        let mut flushed = 0;
+        let mut next_attempt: Option<u64> = None;
+
+        for mut entry in entries {
+            // Decide whether this event is due and relevant for the notifying relay.
+            let should_try = entry.is_due(now)
+                && relay_url_str
+                    .as_deref()
+                    .is_none_or(|url| entry.pending_relays.contains(url));
+
+            if should_try {
+                let was_queued = entry.delivered_relays.is_empty();
+                let result = channel
+                    .broadcast_to(entry.pending_relays.clone(), entry.event.clone())
                     .await
-                    .map_err(|e| ConversationError::Inner(Box::new(e)))
-            } else {
-                channel.broadcast(event.clone()).await.map_err(|e| ConversationError::Inner(Box::new(e)))
-            };
+                    .map_err(|e| e.to_string());
+                entry.record_attempt(result);
 
-            match result {
-                Ok((failed, _succeeded)) if failed.is_empty() => { /* success, event dropped */ }
-                Ok((failed, _)) => {
-                    log::warn!("Still failed on relay(s) {:?}, keeping queued", failed);
-                    still_pending.push((event, Some(failed)));
+                if was_queued && !entry.delivered_relays.is_empty() {
+                    let mut relays: Vec<String> = entry.delivered_relays.iter().cloned().collect();
+                    relays.sort();
+                    self.send_delivery_update(entry.event_id(), SendOutcome::Delivered { relays });
                 }
-                Err(e) => {
-                    log::warn!("Flush error for event {:?}: {:?}, keeping queued", event.id, e);
-                    still_pending.push((event, target_relays));
+
+                if entry.is_delivered() {
+                    log::debug!(
+                        "Event {:?} delivered after {} attempt(s)",
+                        entry.event_id(),
+                        entry.attempts
+                    );
+                    match self.outbox.remove(&entry.event_id()) {
+                        Ok(()) => flushed += 1,
+                        Err(e) => log::error!(
+                            "Failed to remove event {:?} from the outbox: {:?}",
+                            entry.event_id(),
+                            e
+                        ),
+                    }
+                    continue;
+                }
+
+                log::warn!(
+                    "Event {:?} still pending on relay(s) {:?}, retrying in {}s",
+                    entry.event_id(),
+                    entry.pending_relays,
+                    entry.next_attempt_at.saturating_sub(now)
+                );
+                if let Err(e) = self.outbox.save(entry.clone()) {
+                    log::error!(
+                        "Failed to update event {:?} in the outbox: {:?}",
+                        entry.event_id(),
+                        e
+                    );
                 }
             }
+
+            next_attempt = Some(
+                next_attempt.map_or(entry.next_attempt_at, |next| next.min(entry.next_attempt_at)),
+            );
         }
 
-        let flushed = before - still_pending.len();
-        self.pending_events = still_pending;
+        self.next_outbox_attempt = next_attempt;
 
         if flushed > 0 {
-            log::debug!(
-                "Flushed {flushed} pending event(s), {} still queued",
-                self.pending_events.len()
-            );
+            log::debug!("Flushed {flushed} queued event(s)");
+        }
+        flushed
+    }
+
+    fn send_delivery_update(&self, event_id: nostr::EventId, outcome: SendOutcome) {
+        // Fails only if nobody is subscribed
+        let _ = self
+            .delivery_updates
+            .send(EventSendResult { event_id, outcome });
+    }
+
+    fn refresh_next_outbox_attempt(&mut self) {
+        self.next_outbox_attempt = match self.outbox.load_all() {
+            Ok(entries) => entries.iter().map(|entry| entry.next_attempt_at).min(),
+            Err(e) => {
+                log::error!("Failed to read the outbox: {:?}", e);
+                None
+            }
+        };
+    }
+
+    pub fn list_outbox(&self) -> Result<Vec<OutboxEntry>, ConversationError> {
+        Ok(self.outbox.load_all()?)
+    }
+
+    /// Clears the backoff of the selected outbox events and flushes them.
+    pub async fn retry_outbox<C: Channel>(
+        &mut self,
+        channel: &Arc<C>,
+        event_id: Option<nostr::EventId>,
+    ) -> Result<usize, ConversationError>
+    where
+        C::Error: From<nostr::types::url::Error>,
+    {
+        let entries = match event_id {
+            Some(event_id) => self.outbox.get(&event_id)?.into_iter().collect(),
+            None => self.outbox.load_all()?,
+        };
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        for mut entry in entries {
+            entry.reset_backoff();
+            self.outbox.save(entry)?;
+        }
+
+        self.refresh_next_outbox_attempt();
+        Ok(self.flush_pending_events(channel, None).await)
+    }
+
+    /// Removes the selected events from the outbox.
+    pub fn purge_outbox(
+        &mut self,
+        event_id: Option<nostr::EventId>,
+    ) -> Result<usize, ConversationError> {
+        let event_ids = match event_id {
+            Some(event_id) => self
+                .outbox
+                .get(&event_id)?
+                .map(|entry| entry.event_id())
+                .into_iter()
+                .collect(),
+            None => self
+                .outbox
+                .load_all()?
+                .iter()
+                .map(OutboxEntry::event_id)
+                .collect::<Vec<_>>(),
+        };
+
+        for event_id in &event_ids {
+            self.outbox.remove(event_id)?;
+            log::info!("Purged event {:?} from the outbox", event_id);
+            self.send_delivery_update(*event_id, SendOutcome::Dropped);
         }
+
+        self.refresh_next_outbox_attempt();
+        Ok(event_ids.len())
     }
 
     fn internal_add_with_id(
         &mut self,
         id: &PortalConversationId,
-        subscription_id: PortalSubscriptionId,
-        mut conversation: ConversationBox,
+        mut conversation: InnerConversationState,
         relays: Option<Vec<String>>,
         subscriber: Option<mpsc::Sender<serde_json::Value>>,
     ) -> Result<Response, ConversationError> {
+        conversation.set_revoked_subkeys(Arc::clone(&self.revoked_subkeys));
         let response = conversation.init()?;
 
         let conv_state = if let Some(relays) = relays {
             // Create conversation with specific relays
             let relay_set: HashSet<String> = relays.into_iter().collect();
             let mut conv_state =
-                ConversationState::new_with_relays(id.clone(), conversation, relay_set, subscription_id.clone());
+                ConversationState::new_with_relays(id.clone(), conversation, relay_set);
             if let Some(subscriber) = subscriber {
                 conv_state.add_subscriber(subscriber);
             }
             conv_state
         } else {
             // Create global conversation (subscribed to all relays)
-            let mut conv_state = ConversationState::new(id.clone(), conversation, subscription_id.clone());
+            let mut conv_state = ConversationState::new(id.clone(), conversation);
             if let Some(subscriber) = subscriber {
                 conv_state.add_subscriber(subscriber);
             }
@@ -1122,9 +1524,8 @@ impl MessageRouterActorState {
     {
         let conversation_id = PortalConversationId::new_conversation();
 
-        let subscription_id = PortalSubscriptionId::generate();
-        let response = self.internal_add_with_id(&conversation_id, subscription_id.clone(), conversation, None, None)?;
-        let outcomes = self.process_response(channel, &conversation_id, response, subscription_id)
+        let response = self.internal_add_with_id(&conversation_id, InnerConversationState::Standard(conversation), None, None)?;
+        let outcomes = self.process_response(channel, &conversation_id, response)
             .await?;
 
         Ok((conversation_id, outcomes))
@@ -1141,10 +1542,9 @@ impl MessageRouterActorState {
     {
         let conversation_id = PortalConversationId::new_conversation();
 
-        let subscription_id = PortalSubscriptionId::generate();
         let response =
-            self.internal_add_with_id(&conversation_id, subscription_id.clone(), conversation, Some(relays), None)?;
-        let outcomes = self.process_response(channel, &conversation_id, response, subscription_id)
+            self.internal_add_with_id(&conversation_id, InnerConversationState::Standard(conversation), Some(relays), None)?;
+        let outcomes = self.process_response(channel, &conversation_id, response)
             .await?;
 
         Ok((conversation_id, outcomes))
@@ -1213,13 +1613,136 @@ impl MessageRouterActorState {
         let rx = NotificationStream::new(rx);
 
         // Now add the conversation
-        let subscription_id = PortalSubscriptionId::generate();
-        let response = self.internal_add_with_id(&conversation_id, subscription_id.clone(), conversation, None, Some(tx))?;
-        let outcomes = self.process_response(channel, &conversation_id, response, subscription_id)
+        let response = self.internal_add_with_id(&conversation_id, InnerConversationState::Standard(conversation), None, Some(tx))?;
+        let outcomes = self.process_response(channel, &conversation_id, response)
             .await?;
 
         Ok((rx, outcomes))
     }
+
+    /// Adds a persistent conversation and subscribes to its notifications.
+    ///
+    /// Works like [`Self::add_and_subscribe`], but the conversation is also saved in the
+    /// conversation store under `tag` every time it processes a message.
+    pub async fn add_persistent_and_subscribe<C: Channel, T: DeserializeOwned + Serialize>(
+        &mut self,
+        channel: &Arc<C>,
+        conversation: PersistentConversationBox,
+        tag: String,
+    ) -> Result<(NotificationStream<T>, Vec<EventSendResult>), ConversationError>
+    where
+        C::Error: From<nostr::types::url::Error>,
+    {
+        let conversation_id = PortalConversationId::new_conversation();
+
+        let (tx, rx) = mpsc::channel(8);
+
+        let rx = tokio_stream::wrappers::ReceiverStream::new(rx);
+        let rx = rx.map(|content| serde_json::from_value(content));
+        let rx = NotificationStream::new(rx);
+
+        let conversation = InnerConversationState::Persistent { conversation, tag };
+        let response = self.internal_add_with_id(&conversation_id, conversation, None, Some(tx))?;
+        let outcomes = self.process_response(channel, &conversation_id, response)
+            .await?;
+
+        Ok((rx, outcomes))
+    }
+
+    /// Restores the conversations saved in the store.
+    ///
+    /// Restored conversations are not initialized again, so their initial messages are not
+    /// re-sent: the router only re-subscribes the filters they had before the restart.
+    pub async fn resume_conversations<C: Channel>(
+        &mut self,
+        channel: &Arc<C>,
+        registry: &ConversationRegistry,
+    ) -> Result<Vec<ResumedConversation>, ConversationError>
+    where
+        C::Error: From<nostr::types::url::Error>,
+    {
+        let mut resumed = Vec::new();
+
+        for record in self.store.load_all()? {
+            let mut conversation = match registry.restore(&record) {
+                Some(Ok(conversation)) if !conversation.is_expired() => conversation,
+                Some(Ok(_)) => {
+                    log::info!("Conversation {} expired while offline", record.tag);
+                    self.store.remove(&record.tag)?;
+                    continue;
+                }
+                Some(Err(e)) => {
+                    log::warn!("Failed to restore conversation {}: {:?}", record.tag, e);
+                    self.store.remove(&record.tag)?;
+                    continue;
+                }
+                None => {
+                    log::warn!(
+                        "Unknown conversation type '{}' for {}",
+                        record.conversation_type,
+                        record.tag
+                    );
+                    self.store.remove(&record.tag)?;
+                    continue;
+                }
+            };
+
+            conversation.set_revoked_subkeys(Arc::clone(&self.revoked_subkeys));
+
+            let conversation_id = PortalConversationId::new_conversation();
+            let conversation = InnerConversationState::Persistent {
+                conversation,
+                tag: record.tag.clone(),
+            };
+            let mut conv_state = match &record.relays {
+                Some(relays) => ConversationState::new_with_relays(
+                    conversation_id.clone(),
+                    conversation,
+                    relays.iter().cloned().collect(),
+                ),
+                None => ConversationState::new(conversation_id.clone(), conversation),
+            };
+
+            let (tx, rx) = mpsc::channel(8);
+            conv_state.add_subscriber(tx);
+            self.conversations.insert(conversation_id.clone(), conv_state);
+
+            let selected_relays = self.get_relays_by_conversation(&conversation_id)?;
+
+            if let Some(filter) = record.filter {
+                self.subscribe_conversation(channel, &conversation_id, filter)
+                    .await?;
+            }
+
+            for filter in record.alias_filters {
+                let alias = PortalConversationId::new_conversation_alias(
+                    conversation_id.id(),
+                    rand::random::<u64>(),
+                );
+                if let Some(conv_state) = self.conversations.get_mut(&conversation_id) {
+                    conv_state.add_alias(alias.clone());
+                }
+
+                self.conversations.insert(
+                    alias.clone(),
+                    ConversationState::new_alias(alias.clone(), selected_relays.clone()),
+                );
+                self.subscribe_conversation(channel, &alias, filter).await?;
+            }
+
+            log::info!("Resumed conversation {} as {}", record.tag, conversation_id);
+
+            let stream =
+                tokio_stream::wrappers::ReceiverStream::new(rx).map(Ok::<_, serde_json::Error>);
+            resumed.push(ResumedConversation {
+                tag: record.tag,
+                conversation_type: record.conversation_type,
+                stream: NotificationStream::new(stream),
+            });
+        }
+
+        Ok(resumed)
+    }
 }
 
 /// Encapsulates all state related to a single conversation.
@@ -1241,20 +1764,36 @@ struct ConversationState {
     relay_urls: HashSet<String>,
     /// Whether this conversation is subscribed to all relays (global)
     is_global: bool,
-    /// The subscription ID for this conversation
-    subscription_id: PortalSubscriptionId,
+    /// IDs of the last events dispatched to this conversation
+    seen_events: VecDeque<EventId>,
 }
 
 #[derive(Debug)]
 enum InnerConversationState {
     Standard(ConversationBox),
+    /// A conversation saved in the store under `tag`
+    Persistent {
+        conversation: PersistentConversationBox,
+        tag: String,
+    },
     Alias,
 }
 
 impl InnerConversationState {
+    fn init(&mut self) -> Result<Response, ConversationError> {
+        match self {
+            InnerConversationState::Standard(conversation) => conversation.init(),
+            InnerConversationState::Persistent { conversation, .. } => conversation.init(),
+            InnerConversationState::Alias => Ok(Response::default()),
+        }
+    }
+
     fn on_message(&mut self, message: ConversationMessage) -> Result<Response, ConversationError> {
         match self {
             InnerConversationState::Standard(conversation) => conversation.on_message(message),
+            InnerConversationState::Persistent { conversation, .. } => {
+                conversation.on_message(message)
+            }
             InnerConversationState::Alias => Ok(Response::default()),
         }
     }
@@ -1262,56 +1801,69 @@ impl InnerConversationState {
     fn is_expired(&self) -> bool {
         match self {
             InnerConversationState::Standard(conversation) => conversation.is_expired(),
+            InnerConversationState::Persistent { conversation, .. } => conversation.is_expired(),
             InnerConversationState::Alias => false,
         }
     }
+
+    fn set_revoked_subkeys(&mut self, revoked: Arc<RevokedSubkeys>) {
+        match self {
+            InnerConversationState::Standard(conversation) => {
+                conversation.set_revoked_subkeys(revoked)
+            }
+            InnerConversationState::Persistent { conversation, .. } => {
+                conversation.set_revoked_subkeys(revoked)
+            }
+            InnerConversationState::Alias => {}
+        }
+    }
 }
 
 impl ConversationState {
-    fn new(id: PortalConversationId, conversation: ConversationBox, subscription_id: PortalSubscriptionId) -> Self {
+    fn new(id: PortalConversationId, conversation: InnerConversationState) -> Self {
         Self {
             id,
-            conversation: InnerConversationState::Standard(conversation),
+            conversation,
             aliases: Vec::new(),
             filter: None,
             subscribers: Vec::new(),
             end_of_stored_events: None,
             relay_urls: HashSet::new(),
             is_global: true, // Default to global subscription
-            subscription_id,
+            seen_events: VecDeque::new(),
         }
     }
 
     fn new_with_relays(
         id: PortalConversationId,
-        conversation: ConversationBox,
+        conversation: InnerConversationState,
         relay_urls: HashSet<String>,
-        subscription_id: PortalSubscriptionId,
     ) -> Self {
         Self {
             id,
-            conversation: InnerConversationState::Standard(conversation),
+            conversation,
             aliases: Vec::new(),
             filter: None,
             subscribers: Vec::new(),
             end_of_stored_events: None,
             relay_urls,
             is_global: false,
-            subscription_id,
+            seen_events: VecDeque::new(),
         }
     }
 
-    fn new_alias(id: PortalConversationId, filter: Filter, subscription_id: PortalSubscriptionId) -> Self {
+    /// Creates the state of an alias, on the same relays as its conversation
+    fn new_alias(id: PortalConversationId, relay_urls: Option<HashSet<String>>) -> Self {
         Self {
             id,
             conversation: InnerConversationState::Alias,
             aliases: Vec::new(),
-            filter: Some(filter),
+            filter: None,
             subscribers: Vec::new(),
             end_of_stored_events: None,
-            relay_urls: HashSet::new(),
-            is_global: true, // Aliases default to global
-            subscription_id,
+            is_global: relay_urls.is_none(),
+            relay_urls: relay_urls.unwrap_or_default(),
+            seen_events: VecDeque::new(),
         }
     }
 
@@ -1347,6 +1899,18 @@ impl ConversationState {
         self.end_of_stored_events = None;
     }
 
+    /// Remember that an event was dispatched, returns `false` if it already was
+    fn mark_seen(&mut self, event_id: EventId) -> bool {
+        if self.seen_events.contains(&event_id) {
+            return false;
+        }
+        if self.seen_events.len() >= MAX_SEEN_EVENTS {
+            self.seen_events.pop_front();
+        }
+        self.seen_events.push_back(event_id);
+        true
+    }
+
     /// Add an alias for subkey proof subscriptions
     fn add_alias(&mut self, alias: PortalConversationId) {
         // Prevent duplicate aliases
@@ -1408,3 +1972,313 @@ impl std::fmt::Debug for ConversationBox {
         f.debug_struct("Conversation").finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use nostr::{key::Keys, types::TryIntoUrl};
+
+    use crate::router::multiplexer::LIVE_SINCE_MARGIN_SECS;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Request {
+        Subscribe(PortalSubscriptionId, Filter),
+        Unsubscribe(PortalSubscriptionId),
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    enum RecordingChannelError {
+        #[error("URL error: {0}")]
+        Url(#[from] nostr::types::url::Error),
+    }
+
+    /// A channel with one relay, recording the subscriptions sent to it
+    #[derive(Default)]
+    struct RecordingChannel {
+        requests: Mutex<Vec<Request>>,
+    }
+
+    impl RecordingChannel {
+        fn take_requests(&self) -> Vec<Request> {
+            std::mem::take(&mut *self.requests.lock().unwrap())
+        }
+    }
+
+    impl Channel for RecordingChannel {
+        type Error = RecordingChannelError;
+
+        async fn subscribe(
+            &self,
+            id: PortalSubscriptionId,
+            filter: Filter,
+        ) -> Result<usize, Self::Error> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push(Request::Subscribe(id, filter));
+            Ok(1)
+        }
+
+        async fn subscribe_to<I, U>(
+            &self,
+            _urls: I,
+            id: PortalSubscriptionId,
+            filter: Filter,
+        ) -> Result<(), Self::Error>
+        where
+            <I as IntoIterator>::IntoIter: Send,
+            I: IntoIterator<Item = U> + Send,
+            U: TryIntoUrl,
+            Self::Error: From<<U as TryIntoUrl>::Err>,
+        {
+            self.requests
+                .lock()
+                .unwrap()
+                .push(Request::Subscribe(id, filter));
+            Ok(())
+        }
+
+        async fn unsubscribe(&self, id: PortalSubscriptionId) -> Result<(), Self::Error> {
+            self.requests.lock().unwrap().push(Request::Unsubscribe(id));
+            Ok(())
+        }
+
+        async fn broadcast(
+            &self,
+            _event: Event,
+        ) -> Result<(HashSet<String>, Vec<String>), Self::Error> {
+            Ok((HashSet::new(), vec!["wss://relay.example".to_string()]))
+        }
+
+        async fn broadcast_to<I, U>(
+            &self,
+            urls: I,
+            _event: Event,
+        ) -> Result<(HashSet<String>, Vec<String>), Self::Error>
+        where
+            <I as IntoIterator>::IntoIter: Send,
+            I: IntoIterator<Item = U> + Send,
+            U: TryIntoUrl,
+            Self::Error: From<<U as TryIntoUrl>::Err>,
+        {
+            let urls = urls
+                .into_iter()
+                .map(|url| Ok::<_, Self::Error>(url.try_into_url()?.to_string()))
+                .collect::<Result<_, _>>()?;
+            Ok((HashSet::new(), urls))
+        }
+
+        async fn receive(&self) -> Result<RelayPoolNotification, Self::Error> {
+            std::future::pending().await
+        }
+
+        async fn shutdown(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    /// Waits for the events of `filter`, recording the messages it receives
+    struct Recorder {
+        filter: Filter,
+        received: Arc<Mutex<Vec<ConversationMessage>>>,
+    }
+
+    impl Recorder {
+        fn new(filter: Filter) -> (Box<Self>, Arc<Mutex<Vec<ConversationMessage>>>) {
+            let received = Arc::new(Mutex::new(Vec::new()));
+            (
+                Box::new(Self {
+                    filter,
+                    received: received.clone(),
+                }),
+                received,
+            )
+        }
+    }
+
+    impl Conversation for Recorder {
+        fn init(&mut self) -> Result<Response, ConversationError> {
+            Ok(Response::new().filter(self.filter.clone()))
+        }
+
+        fn on_message(
+            &mut self,
+            message: ConversationMessage,
+        ) -> Result<Response, ConversationError> {
+            self.received.lock().unwrap().push(message);
+            Ok(Response::new())
+        }
+
+        fn is_expired(&self) -> bool {
+            false
+        }
+    }
+
+    fn relay_url() -> RelayUrl {
+        RelayUrl::parse("wss://relay.example").unwrap()
+    }
+
+    fn event_notification(
+        subscription_id: &PortalSubscriptionId,
+        event: &Event,
+    ) -> RelayPoolNotification {
+        RelayPoolNotification::Event {
+            relay_url: relay_url(),
+            subscription_id: SubscriptionId::new(subscription_id.to_string()),
+            event: Box::new(event.clone()),
+        }
+    }
+
+    fn eose_notification(subscription_id: &PortalSubscriptionId) -> RelayPoolNotification {
+        RelayPoolNotification::Message {
+            relay_url: relay_url(),
+            message: RelayMessage::eose(SubscriptionId::new(subscription_id.to_string())),
+        }
+    }
+
+    fn event_ids(received: &Mutex<Vec<ConversationMessage>>) -> Vec<EventId> {
+        received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|message| match message {
+                ConversationMessage::Cleartext(event) => Some(event.id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn eose_count(received: &Mutex<Vec<ConversationMessage>>) -> usize {
+        received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|message| matches!(message, ConversationMessage::EndOfStoredEvents))
+            .count()
+    }
+
+    fn subscriptions(requests: &[Request]) -> Vec<(PortalSubscriptionId, Filter)> {
+        requests
+            .iter()
+            .filter_map(|request| match request {
+                Request::Subscribe(id, filter) => Some((id.clone(), filter.clone())),
+                Request::Unsubscribe(_) => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_joining_a_shared_subscription_does_not_replay_stored_events() {
+        let channel = Arc::new(RecordingChannel::default());
+        let (delivery_updates, _) = broadcast::channel(DELIVERY_UPDATES_CAPACITY);
+        let mut state = MessageRouterActorState::new(
+            LocalKeypair::new(Keys::generate(), None),
+            RouterStorage::default(),
+            delivery_updates,
+            Arc::new(RevokedSubkeys::new()),
+        );
+
+        let service = Keys::generate().public_key();
+        let first_user = Keys::generate();
+        let second_user = Keys::generate();
+        let filter = |user: &Keys| {
+            Filter::new()
+                .kind(Kind::Custom(27001))
+                .author(user.public_key())
+                .pubkey(service)
+        };
+        let event = |user: &Keys| {
+            EventBuilder::new(Kind::Custom(27001), "{}")
+                .tag(nostr::event::Tag::public_key(service))
+                .sign_with_keys(user)
+                .unwrap()
+        };
+
+        // The first conversation gets its stored events and EOSE from its backfill
+        let (first, first_received) = Recorder::new(filter(&first_user));
+        state.add_conversation(&channel, first).await.unwrap();
+        let requests = subscriptions(&channel.take_requests());
+        let [(shared, _), (first_backfill, backfill_filter)] = requests.as_slice() else {
+            panic!("unexpected subscriptions {requests:?}");
+        };
+        assert_eq!(backfill_filter, &filter(&first_user));
+
+        let stored = event(&first_user);
+        state
+            .handle_relay_pool_notification(&channel, event_notification(first_backfill, &stored))
+            .await
+            .unwrap();
+        state
+            .handle_relay_pool_notification(&channel, eose_notification(first_backfill))
+            .await
+            .unwrap();
+        assert_eq!(event_ids(&first_received), vec![stored.id]);
+        assert_eq!(eose_count(&first_received), 1);
+        assert_eq!(
+            channel.take_requests(),
+            vec![Request::Unsubscribe(first_backfill.clone())]
+        );
+
+        // A second conversation joins: the merged filter is sent for live events only, and the
+        // stored events are fetched for the new conversation alone
+        let (second, second_received) = Recorder::new(filter(&second_user));
+        state.add_conversation(&channel, second).await.unwrap();
+        let requests = subscriptions(&channel.take_requests());
+        let [(resent, merged), (second_backfill, backfill_filter)] = requests.as_slice() else {
+            panic!("unexpected subscriptions {requests:?}");
+        };
+        assert_eq!(resent, shared);
+        assert_eq!(
+            merged.authors.as_ref().map(|authors| authors.len()),
+            Some(2)
+        );
+        let since = merged
+            .since
+            .expect("the merged filter must not ask for stored events");
+        assert!(since.as_u64() + LIVE_SINCE_MARGIN_SECS >= nostr::types::Timestamp::now().as_u64());
+        assert_eq!(backfill_filter, &filter(&second_user));
+
+        // The backfill of the second conversation also returns the event of the first one,
+        // it is not dispatched to it again
+        let second_stored = event(&second_user);
+        for stored in [&stored, &second_stored] {
+            state
+                .handle_relay_pool_notification(
+                    &channel,
+                    event_notification(second_backfill, stored),
+                )
+                .await
+                .unwrap();
+        }
+        state
+            .handle_relay_pool_notification(&channel, eose_notification(shared))
+            .await
+            .unwrap();
+        state
+            .handle_relay_pool_notification(&channel, eose_notification(second_backfill))
+            .await
+            .unwrap();
+
+        assert_eq!(event_ids(&first_received), vec![stored.id]);
+        assert_eq!(eose_count(&first_received), 1);
+        assert_eq!(event_ids(&second_received), vec![second_stored.id]);
+        assert_eq!(eose_count(&second_received), 1);
+
+        // Live events still reach both conversations
+        let live = [event(&first_user), event(&second_user)];
+        for event in &live {
+            state
+                .handle_relay_pool_notification(&channel, event_notification(shared, event))
+                .await
+                .unwrap();
+        }
+        assert_eq!(event_ids(&first_received), vec![stored.id, live[0].id]);
+        assert_eq!(
+            event_ids(&second_received),
+            vec![second_stored.id, live[1].id]
+        );
+    }
+}
@@ -1,17 +1,21 @@
 use std::{
     collections::HashSet,
     ops::Deref,
+    sync::Arc,
     time::{Duration, SystemTime},
 };
 
-use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
 use nostr::{
     event::{Kind, Tag},
     key::PublicKey,
 };
 
-use crate::protocol::model::{auth::SubkeyProof, event_kinds::SUBKEY_PROOF};
+use crate::protocol::{
+    model::{auth::SubkeyProof, event_kinds::SUBKEY_PROOF},
+    subkey::RevokedSubkeys,
+};
 
 use crate::router::{
     CleartextEvent, Conversation, ConversationError, ConversationMessage, Response,
@@ -39,11 +43,15 @@ pub trait MultiKeyListener: Sized + Send + 'static {
 ///   2. Potentially receive an encrypted message because it was sent to the main key or another subkey
 ///   3. Reply with a SUBKEY_PROOF message asking to include us in the conversation
 ///   4. Wait for the non-encrypted message
+#[derive(Serialize, Deserialize)]
 pub struct MultiKeyListenerAdapter<Inner> {
     pub user: Option<PublicKey>,
     pub subkey_proof: Option<SubkeyProof>,
     pub expires_at: Option<SystemTime>,
     pub inner: Inner,
+    /// Set by the router, proofs of the senders are checked against it
+    #[serde(skip)]
+    pub revoked_subkeys: Arc<RevokedSubkeys>,
 }
 
 impl<T: MultiKeyListener> Conversation for MultiKeyListenerAdapter<T>
@@ -103,6 +111,10 @@ where
             None => false,
         }
     }
+
+    fn set_revoked_subkeys(&mut self, revoked: Arc<RevokedSubkeys>) {
+        self.revoked_subkeys = revoked;
+    }
 }
 
 impl<Inner: MultiKeyListener> MultiKeyListenerAdapter<Inner> {
@@ -113,6 +125,7 @@ impl<Inner: MultiKeyListener> MultiKeyListenerAdapter<Inner> {
             expires_at: Inner::VALIDITY_SECONDS
                 .map(|seconds| SystemTime::now() + Duration::from_secs(seconds)),
             inner,
+            revoked_subkeys: Arc::default(),
         }
     }
 }
@@ -1,14 +1,18 @@
 use std::{
     collections::HashSet,
     ops::{Deref, DerefMut},
+    sync::Arc,
     time::{Duration, SystemTime},
 };
 
-use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
 use nostr::{event::Kind, filter::Filter, key::PublicKey};
 
-use crate::protocol::model::{auth::SubkeyProof, event_kinds::SUBKEY_PROOF};
+use crate::protocol::{
+    model::{auth::SubkeyProof, event_kinds::SUBKEY_PROOF},
+    subkey::{RevokedSubkeys, SubkeyError},
+};
 
 use crate::router::{
     CleartextEvent, Conversation, ConversationError, ConversationMessage, Response,
@@ -36,6 +40,16 @@ pub trait MultiKeySender: Sized + Send + 'static {
         _event: &CleartextEvent,
         _message: &Self::Message,
     ) -> Result<Response, Self::Error>;
+
+    /// Checks that a subkey of the user is allowed to reply, before the message is sent to it
+    ///
+    /// Any subkey with a valid proof is accepted by default.
+    fn authorize_subkey(
+        _state: &MultiKeySenderAdapter<Self>,
+        _proof: &SubkeyProof,
+    ) -> Result<(), SubkeyError> {
+        Ok(())
+    }
 }
 
 /// A conversation wrapper that handles key switching
@@ -45,11 +59,15 @@ pub trait MultiKeySender: Sized + Send + 'static {
 ///   2. Receive SUBKEY_PROOF messages asking to switch to a new key
 ///   3. Send out again the same message to the new key
 ///   4. Wait for the response
+#[derive(Serialize, Deserialize)]
 pub struct MultiKeySenderAdapter<Inner> {
     pub user: PublicKey,
     pub subkeys: HashSet<PublicKey>,
     pub expires_at: Option<SystemTime>,
     pub inner: Inner,
+    /// Set by the router, messages and proofs of revoked subkeys are ignored
+    #[serde(skip)]
+    pub revoked_subkeys: Arc<RevokedSubkeys>,
 }
 
 impl<T: MultiKeySender> Conversation for MultiKeySenderAdapter<T> {
@@ -73,6 +91,13 @@ impl<T: MultiKeySender> Conversation for MultiKeySenderAdapter<T> {
     fn on_message(&mut self, message: ConversationMessage) -> Result<Response, ConversationError> {
         match message {
             ConversationMessage::Cleartext(event) => {
+                if self.subkeys.contains(&event.pubkey)
+                    && self.revoked_subkeys.is_revoked(&self.user, &event.pubkey)
+                {
+                    log::warn!("Ignoring message from revoked subkey {:?}", event.pubkey);
+                    return Ok(Response::default());
+                }
+
                 if let Ok(content) = serde_json::from_value(event.content.clone()) {
                     let mut response = <T as MultiKeySender>::on_message(self, &event, &content)
                         .map_err(|e| ConversationError::Inner(Box::new(e)))?;
@@ -94,11 +119,16 @@ impl<T: MultiKeySender> Conversation for MultiKeySenderAdapter<T> {
                         return Ok(Response::default());
                     }
 
-                    if let Err(e) = proof.verify(&event.pubkey) {
+                    if let Err(e) = proof.verify_with(&event.pubkey, &self.revoked_subkeys) {
                         log::warn!("Invalid proof: {:?}", e);
                         return Ok(Response::default());
                     }
 
+                    if let Err(e) = <T as MultiKeySender>::authorize_subkey(self, &proof) {
+                        log::warn!("Subkey {:?} not allowed: {}", event.pubkey, e);
+                        return Ok(Response::default());
+                    }
+
                     let response_result = if event.pubkey == self.user {
                         // We only knew about a subkey and we thought it was the main key. Switching it now
                         log::debug!(
@@ -150,6 +180,10 @@ impl<T: MultiKeySender> Conversation for MultiKeySenderAdapter<T> {
             None => false,
         }
     }
+
+    fn set_revoked_subkeys(&mut self, revoked: Arc<RevokedSubkeys>) {
+        self.revoked_subkeys = revoked;
+    }
 }
 
 impl<Inner: MultiKeySender> MultiKeySenderAdapter<Inner> {
@@ -160,6 +194,7 @@ impl<Inner: MultiKeySender> MultiKeySenderAdapter<Inner> {
             expires_at: Inner::VALIDITY_SECONDS
                 .map(|seconds| SystemTime::now() + Duration::from_secs(seconds)),
             inner,
+            revoked_subkeys: Arc::default(),
         }
     }
 }
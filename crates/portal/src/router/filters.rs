@@ -1,41 +1,133 @@
+//! Merging of conversation filters
+//!
+//! The router serves the filters of compatible conversations with a single relay subscription
+//! (see [`super::multiplexer`]). A merged filter can match more events than the filters it was
+//! built from, e.g. the authors of one conversation with the tags of another: that's fine because
+//! incoming events are matched against the filter of each conversation before being dispatched.
+
 use nostr::filter::Filter;
 
+/// Whether two filters can be served by the same relay subscription
+///
+/// They must be for the same kinds and tag names, and constrain the same fields. Filters with a
+/// `limit` or a `search` are never merged, since they would apply to the merged result.
 pub fn can_be_merged(filter1: &Filter, filter2: &Filter) -> bool {
-    filter1.kinds == filter2.kinds && filter1.generic_tags == filter2.generic_tags
+    filter1.kinds == filter2.kinds
+        && filter1
+            .generic_tags
+            .keys()
+            .eq(filter2.generic_tags.keys())
+        && filter1.ids.is_some() == filter2.ids.is_some()
+        && filter1.authors.is_some() == filter2.authors.is_some()
+        && filter1.limit.is_none()
+        && filter2.limit.is_none()
+        && filter1.search.is_none()
+        && filter2.search.is_none()
 }
 
+/// Builds a filter matching every event matched by `filter1` or `filter2`
+///
+/// Only meaningful for filters that [`can_be_merged`].
 pub fn merge_filters(filter1: &Filter, filter2: &Filter) -> Filter {
     let mut cloned = filter1.clone();
 
     // Merge ids
     if let Some(ids) = &mut cloned.ids {
-        if let Some(ids2) = &filter2.ids {
-            ids.extend(ids2.iter().cloned());
+        match &filter2.ids {
+            Some(ids2) => ids.extend(ids2.iter().cloned()),
+            None => cloned.ids = None,
         }
-    } else if let Some(ids2) = &filter2.ids {
-        cloned.ids = Some(ids2.clone());
     }
     // Merge authors
     if let Some(authors) = &mut cloned.authors {
-        if let Some(authors2) = &filter2.authors {
-            authors.extend(authors2.iter().cloned());
+        match &filter2.authors {
+            Some(authors2) => authors.extend(authors2.iter().cloned()),
+            None => cloned.authors = None,
         }
-    } else if let Some(authors2) = &filter2.authors {
-        cloned.authors = Some(authors2.clone());
     }
-    // Merge since (take the minimum if both are Some)
-    match (cloned.since, filter2.since) {
-        (Some(s1), Some(s2)) => cloned.since = Some(std::cmp::min(s1, s2)),
-        (None, Some(s2)) => cloned.since = Some(s2),
-        _ => {}
+    // Merge tag values
+    for (tag, values2) in filter2.generic_tags.iter() {
+        cloned
+            .generic_tags
+            .entry(*tag)
+            .or_default()
+            .extend(values2.iter().cloned());
     }
 
-    // Merge until (take the maximum if both are Some)
-    match (cloned.until, filter2.until) {
-        (Some(u1), Some(u2)) => cloned.until = Some(std::cmp::max(u1, u2)),
-        (None, Some(u2)) => cloned.until = Some(u2),
-        _ => {}
-    }
+    // Merge since (take the minimum, unbounded if either is)
+    cloned.since = match (cloned.since, filter2.since) {
+        (Some(s1), Some(s2)) => Some(std::cmp::min(s1, s2)),
+        _ => None,
+    };
+
+    // Merge until (take the maximum, unbounded if either is)
+    cloned.until = match (cloned.until, filter2.until) {
+        (Some(u1), Some(u2)) => Some(std::cmp::max(u1, u2)),
+        _ => None,
+    };
 
     cloned
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nostr::{
+        event::{EventBuilder, Kind},
+        filter::MatchEventOptions,
+        key::Keys,
+        types::Timestamp,
+    };
+
+    #[test]
+    fn test_can_be_merged() {
+        let service = Keys::generate().public_key();
+        let base = Filter::new().kind(Kind::Custom(27001)).pubkey(service);
+
+        assert!(can_be_merged(
+            &base.clone().author(Keys::generate().public_key()),
+            &base.clone().author(Keys::generate().public_key()),
+        ));
+        assert!(can_be_merged(
+            &base,
+            &base.clone().pubkey(Keys::generate().public_key())
+        ));
+        assert!(!can_be_merged(&base, &base.clone().kind(Kind::Custom(27002))));
+        assert!(!can_be_merged(
+            &base,
+            &base.clone().author(Keys::generate().public_key())
+        ));
+        assert!(!can_be_merged(&base, &base.clone().limit(1)));
+    }
+
+    #[test]
+    fn test_merge_filters() {
+        let user1 = Keys::generate();
+        let user2 = Keys::generate();
+        let service = Keys::generate().public_key();
+
+        let filter1 = Filter::new()
+            .kind(Kind::Custom(27001))
+            .author(user1.public_key())
+            .pubkey(service)
+            .since(Timestamp::from(100));
+        let filter2 = Filter::new()
+            .kind(Kind::Custom(27001))
+            .author(user2.public_key())
+            .pubkey(service);
+        let merged = merge_filters(&filter1, &filter2);
+
+        assert_eq!(merged.authors.as_ref().map(|a| a.len()), Some(2));
+        assert_eq!(merged.since, None);
+
+        for user in [&user1, &user2] {
+            let event = EventBuilder::new(Kind::Custom(27001), "")
+                .tag(nostr::event::Tag::public_key(service))
+                .custom_created_at(Timestamp::from(50))
+                .sign_with_keys(user)
+                .unwrap();
+            assert!(merged.match_event(&event, MatchEventOptions::default()));
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     collections::HashSet,
     ops::{Deref, DerefMut},
+    sync::Arc,
 };
 
 use futures::Stream;
@@ -12,18 +13,28 @@ use nostr::{
     key::PublicKey,
 };
 
+use crate::protocol::subkey::RevokedSubkeys;
+
 pub mod actor;
 pub mod adapters;
 pub mod channel;
 pub mod filters;
 pub mod ids;
+pub mod multiplexer;
+pub mod outbox;
+pub mod store;
 
 pub use adapters::multi_key_listener::{MultiKeyListener, MultiKeyListenerAdapter};
 pub use adapters::multi_key_sender::{MultiKeySender, MultiKeySenderAdapter};
 pub use ids::{PortalConversationId, PortalSubscriptionId};
+pub use outbox::{EventOutbox, InMemoryOutbox, OutboxEntry, OutboxError};
+pub use store::{
+    ConversationRecord, ConversationRegistry, ConversationStore, ConversationStoreError,
+    InMemoryConversationStore, PersistentConversation, PersistentState, ResumedConversation,
+};
 
 // Re-export MessageRouterActor as MessageRouter for backward compatibility
-pub use actor::{MessageRouterActor as MessageRouter, MessageRouterActorError, SendOutcome, EventSendResult};
+pub use actor::{MessageRouterActor as MessageRouter, MessageRouterActorError, RouterStorage, SendOutcome, EventSendResult};
 
 #[derive(Debug)]
 struct ResponseEntry {
@@ -197,6 +208,12 @@ pub enum ConversationError {
 
     #[error("Conversation not found")]
     ConversationNotFound,
+
+    #[error("Conversation store error: {0}")]
+    Store(#[from] ConversationStoreError),
+
+    #[error("Outbox error: {0}")]
+    Outbox(#[from] OutboxError),
 }
 
 pub trait Conversation {
@@ -205,6 +222,10 @@ pub trait Conversation {
     fn init(&mut self) -> Result<Response, ConversationError> {
         Ok(Response::default())
     }
+
+    /// Called by the router before the conversation is initialized or resumed, with the
+    /// subkeys revoked so far. Conversations that check subkey proofs keep it.
+    fn set_revoked_subkeys(&mut self, _revoked: Arc<RevokedSubkeys>) {}
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,487 @@
+//! Shared relay subscriptions
+//!
+//! Opening a relay subscription per conversation quickly hits the subscription limits of public
+//! relays. Instead, the router adds the filter of each conversation to a [`SubscriptionMultiplexer`],
+//! which groups compatible filters (see [`can_be_merged`]) targeting the same relays into shared
+//! subscriptions of up to [`MAX_CONVERSATIONS_PER_SUBSCRIPTION`] conversations. The merged filter
+//! of a subscription is recomputed whenever a conversation joins or leaves it.
+//!
+//! Sending the merged filter again makes the relays replay their stored events to all the
+//! members, so shared subscriptions are only sent for live events (see [`LIVE_SINCE_MARGIN_SECS`]).
+//! Each conversation that joins gets the stored events matching its own filter, and the EOSE it
+//! is waiting for, from a backfill subscription closed once all of its relays sent their EOSE.
+//!
+//! Events received on a shared subscription are dispatched by the router to the conversations
+//! whose own filter matches them.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use nostr::{filter::Filter, types::Timestamp};
+
+use crate::router::{
+    PortalConversationId, PortalSubscriptionId,
+    filters::{can_be_merged, merge_filters},
+};
+
+/// Max conversations sharing a relay subscription, so merged filters stay within the size
+/// relays accept
+pub const MAX_CONVERSATIONS_PER_SUBSCRIPTION: usize = 256;
+
+/// How far back a shared subscription asks for events when it is sent, so events published
+/// right before it, or by a client whose clock is slightly late, are not missed. Events replayed
+/// within this margin are recent enough to be deduplicated by the router.
+pub const LIVE_SINCE_MARGIN_SECS: u64 = 60;
+
+/// A change to apply to the relay subscriptions
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubscriptionUpdate {
+    /// Subscribe `id` with `filter`, replacing its previous filter if already subscribed
+    Subscribe {
+        id: PortalSubscriptionId,
+        filter: Filter,
+        /// Relays to subscribe to, `None` for all of them
+        relays: Option<Vec<String>>,
+    },
+    Unsubscribe {
+        id: PortalSubscriptionId,
+    },
+}
+
+fn relay_list(relays: &Option<BTreeSet<String>>) -> Option<Vec<String>> {
+    relays
+        .as_ref()
+        .map(|relays| relays.iter().cloned().collect())
+}
+
+#[derive(Debug)]
+struct SharedSubscription {
+    /// `None` if the subscription is on all relays
+    relays: Option<BTreeSet<String>>,
+    /// Merge of the filters of all the members
+    filter: Filter,
+    /// Merged filter last sent to the relays, restricted to live events
+    live_filter: Filter,
+    members: HashMap<PortalConversationId, Filter>,
+}
+
+impl SharedSubscription {
+    /// Restricts the merged filter to live events and returns the update sending it
+    fn update(&mut self, id: &PortalSubscriptionId) -> SubscriptionUpdate {
+        let since = Timestamp::from(
+            Timestamp::now()
+                .as_u64()
+                .saturating_sub(LIVE_SINCE_MARGIN_SECS),
+        );
+        let mut live_filter = self.filter.clone();
+        live_filter.since = Some(live_filter.since.map_or(since, |s| s.max(since)));
+        self.live_filter = live_filter;
+
+        SubscriptionUpdate::Subscribe {
+            id: id.clone(),
+            filter: self.live_filter.clone(),
+            relays: relay_list(&self.relays),
+        }
+    }
+}
+
+/// Subscription fetching the stored events of a conversation that joined a shared subscription
+#[derive(Debug)]
+struct Backfill {
+    conversation: PortalConversationId,
+    /// `None` if the subscription is on all relays
+    relays: Option<BTreeSet<String>>,
+    filter: Filter,
+}
+
+/// Groups the filters of the conversations into shared relay subscriptions
+#[derive(Debug)]
+pub struct SubscriptionMultiplexer {
+    subscriptions: HashMap<PortalSubscriptionId, SharedSubscription>,
+    /// Subscription each conversation is part of
+    conversations: HashMap<PortalConversationId, PortalSubscriptionId>,
+    /// Backfill subscriptions still waiting for their EOSE
+    backfills: HashMap<PortalSubscriptionId, Backfill>,
+    max_members: usize,
+}
+
+impl SubscriptionMultiplexer {
+    pub fn new() -> Self {
+        Self::with_max_members(MAX_CONVERSATIONS_PER_SUBSCRIPTION)
+    }
+
+    pub fn with_max_members(max_members: usize) -> Self {
+        Self {
+            subscriptions: HashMap::new(),
+            conversations: HashMap::new(),
+            backfills: HashMap::new(),
+            max_members: max_members.max(1),
+        }
+    }
+
+    /// Adds the filter of a conversation, replacing its previous one
+    ///
+    /// `relays` are the relays the conversation is restricted to, `None` for all of them.
+    /// Returns the subscription the conversation is now part of and the updates to apply, the
+    /// last one being the backfill subscription of the conversation.
+    pub fn insert(
+        &mut self,
+        conversation: PortalConversationId,
+        filter: Filter,
+        relays: Option<&HashSet<String>>,
+    ) -> (PortalSubscriptionId, Vec<SubscriptionUpdate>) {
+        let mut updates = self.remove(&conversation);
+
+        let relays: Option<BTreeSet<String>> =
+            relays.map(|relays| relays.iter().cloned().collect());
+        let max_members = self.max_members;
+        let shared = self.subscriptions.iter_mut().find(|(_, subscription)| {
+            subscription.relays == relays
+                && subscription.members.len() < max_members
+                && can_be_merged(&subscription.filter, &filter)
+        });
+
+        let id = match shared {
+            Some((id, subscription)) => {
+                let merged = merge_filters(&subscription.filter, &filter);
+                subscription
+                    .members
+                    .insert(conversation.clone(), filter.clone());
+                // The members already receive the live events of an unchanged filter
+                if merged != subscription.filter {
+                    subscription.filter = merged;
+                    updates.push(subscription.update(id));
+                }
+                id.clone()
+            }
+            None => {
+                let id = PortalSubscriptionId::generate();
+                let mut subscription = SharedSubscription {
+                    relays: relays.clone(),
+                    filter: filter.clone(),
+                    live_filter: filter.clone(),
+                    members: HashMap::from([(conversation.clone(), filter.clone())]),
+                };
+                updates.push(subscription.update(&id));
+                self.subscriptions.insert(id.clone(), subscription);
+                id
+            }
+        };
+        self.conversations.insert(conversation.clone(), id.clone());
+
+        let backfill_id = PortalSubscriptionId::generate();
+        updates.push(SubscriptionUpdate::Subscribe {
+            id: backfill_id.clone(),
+            filter: filter.clone(),
+            relays: relay_list(&relays),
+        });
+        self.backfills.insert(
+            backfill_id,
+            Backfill {
+                conversation,
+                relays,
+                filter,
+            },
+        );
+
+        (id, updates)
+    }
+
+    /// Removes the filter of a conversation, returning the updates to apply
+    pub fn remove(&mut self, conversation: &PortalConversationId) -> Vec<SubscriptionUpdate> {
+        let mut updates: Vec<_> = self
+            .backfills
+            .iter()
+            .filter(|(_, backfill)| &backfill.conversation == conversation)
+            .map(|(id, _)| SubscriptionUpdate::Unsubscribe { id: id.clone() })
+            .collect();
+        self.backfills
+            .retain(|_, backfill| &backfill.conversation != conversation);
+
+        let Some(id) = self.conversations.remove(conversation) else {
+            return updates;
+        };
+        let Some(subscription) = self.subscriptions.get_mut(&id) else {
+            return updates;
+        };
+        subscription.members.remove(conversation);
+
+        let Some(filter) = subscription
+            .members
+            .values()
+            .cloned()
+            .reduce(|merged, filter| merge_filters(&merged, &filter))
+        else {
+            self.subscriptions.remove(&id);
+            updates.push(SubscriptionUpdate::Unsubscribe { id });
+            return updates;
+        };
+
+        if filter != subscription.filter {
+            subscription.filter = filter;
+            updates.push(subscription.update(&id));
+        }
+        updates
+    }
+
+    /// The conversation a backfill subscription fetches the stored events of
+    pub fn backfill_conversation(&self, id: &PortalSubscriptionId) -> Option<PortalConversationId> {
+        self.backfills
+            .get(id)
+            .map(|backfill| backfill.conversation.clone())
+    }
+
+    /// Forgets a backfill subscription that received all of its EOSE, returning the update
+    /// closing it
+    pub fn end_backfill(&mut self, id: &PortalSubscriptionId) -> Option<SubscriptionUpdate> {
+        self.backfills
+            .remove(id)
+            .map(|_| SubscriptionUpdate::Unsubscribe { id: id.clone() })
+    }
+
+    /// Whether `id` is one of the current subscriptions, backfills included
+    pub fn contains(&self, id: &PortalSubscriptionId) -> bool {
+        self.subscriptions.contains_key(id) || self.backfills.contains_key(id)
+    }
+
+    /// The conversations that are part of a subscription
+    pub fn members(&self, id: &PortalSubscriptionId) -> Vec<PortalConversationId> {
+        self.subscriptions
+            .get(id)
+            .map(|subscription| subscription.members.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The subscriptions on all relays, backfills included, with the filter last sent
+    pub fn global_subscriptions(&self) -> Vec<(PortalSubscriptionId, Filter)> {
+        let shared = self
+            .subscriptions
+            .iter()
+            .filter(|(_, subscription)| subscription.relays.is_none())
+            .map(|(id, subscription)| (id.clone(), subscription.live_filter.clone()));
+        let backfills = self
+            .backfills
+            .iter()
+            .filter(|(_, backfill)| backfill.relays.is_none())
+            .map(|(id, backfill)| (id.clone(), backfill.filter.clone()));
+        shared.chain(backfills).collect()
+    }
+
+    /// Forgets a relay removed from the pool
+    pub fn remove_relay(&mut self, url: &str) {
+        let relays = self
+            .subscriptions
+            .values_mut()
+            .map(|subscription| &mut subscription.relays)
+            .chain(
+                self.backfills
+                    .values_mut()
+                    .map(|backfill| &mut backfill.relays),
+            );
+        for relays in relays.flatten() {
+            relays.remove(url);
+        }
+    }
+
+    /// Number of shared relay subscriptions
+    pub fn len(&self) -> usize {
+        self.subscriptions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.subscriptions.clear();
+        self.conversations.clear();
+        self.backfills.clear();
+    }
+}
+
+impl Default for SubscriptionMultiplexer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nostr::{event::Kind, key::Keys};
+
+    fn response_filter(kind: u16) -> Filter {
+        Filter::new()
+            .kind(Kind::Custom(kind))
+            .author(Keys::generate().public_key())
+    }
+
+    fn subscribed_filter(update: &SubscriptionUpdate) -> &Filter {
+        match update {
+            SubscriptionUpdate::Subscribe { filter, .. } => filter,
+            SubscriptionUpdate::Unsubscribe { .. } => panic!("expected a subscription"),
+        }
+    }
+
+    fn subscription_id(update: &SubscriptionUpdate) -> &PortalSubscriptionId {
+        match update {
+            SubscriptionUpdate::Subscribe { id, .. } | SubscriptionUpdate::Unsubscribe { id } => id,
+        }
+    }
+
+    #[test]
+    fn test_compatible_filters_share_a_subscription() {
+        let mut multiplexer = SubscriptionMultiplexer::new();
+        let first = PortalConversationId::new_conversation();
+        let second = PortalConversationId::new_conversation();
+        let other = PortalConversationId::new_conversation();
+
+        let (id, _) = multiplexer.insert(first.clone(), response_filter(27001), None);
+        let (shared_id, updates) = multiplexer.insert(second.clone(), response_filter(27001), None);
+        assert_eq!(id, shared_id);
+        assert_eq!(updates.len(), 2);
+        assert_eq!(subscription_id(&updates[0]), &id);
+        assert_eq!(
+            subscribed_filter(&updates[0])
+                .authors
+                .as_ref()
+                .map(|a| a.len()),
+            Some(2)
+        );
+
+        let (other_id, _) = multiplexer.insert(other.clone(), response_filter(27002), None);
+        assert_ne!(id, other_id);
+
+        // Same filter, restricted to other relays
+        let relays = HashSet::from(["wss://relay.example".to_string()]);
+        let (pinned_id, _) = multiplexer.insert(
+            PortalConversationId::new_conversation(),
+            response_filter(27001),
+            Some(&relays),
+        );
+        assert_ne!(id, pinned_id);
+
+        assert_eq!(multiplexer.len(), 3);
+        let mut members = multiplexer.members(&id);
+        members.sort_by_key(|member| member.to_string());
+        let mut expected = vec![first, second];
+        expected.sort_by_key(|member| member.to_string());
+        assert_eq!(members, expected);
+    }
+
+    #[test]
+    fn test_shared_subscriptions_only_ask_for_live_events() {
+        let mut multiplexer = SubscriptionMultiplexer::new();
+        let first = PortalConversationId::new_conversation();
+        let filter = response_filter(27001);
+
+        let (id, updates) = multiplexer.insert(first.clone(), filter.clone(), None);
+        assert_eq!(updates.len(), 2);
+        assert_eq!(subscription_id(&updates[0]), &id);
+        let since = subscribed_filter(&updates[0]).since.unwrap();
+        assert!(since.as_u64() + LIVE_SINCE_MARGIN_SECS >= Timestamp::now().as_u64());
+
+        // The stored events come from the backfill, with the filter of the conversation
+        let backfill = subscription_id(&updates[1]).clone();
+        assert_ne!(backfill, id);
+        assert_eq!(subscribed_filter(&updates[1]), &filter);
+        assert_eq!(multiplexer.backfill_conversation(&backfill), Some(first));
+        assert!(multiplexer.contains(&backfill));
+        assert_eq!(multiplexer.global_subscriptions().len(), 2);
+
+        assert_eq!(
+            multiplexer.end_backfill(&backfill),
+            Some(SubscriptionUpdate::Unsubscribe {
+                id: backfill.clone()
+            })
+        );
+        assert!(!multiplexer.contains(&backfill));
+        assert_eq!(multiplexer.end_backfill(&backfill), None);
+        assert_eq!(multiplexer.global_subscriptions().len(), 1);
+    }
+
+    #[test]
+    fn test_unchanged_filter_is_not_sent_again() {
+        let mut multiplexer = SubscriptionMultiplexer::new();
+        let filter = response_filter(27001);
+
+        let (id, _) = multiplexer.insert(
+            PortalConversationId::new_conversation(),
+            filter.clone(),
+            None,
+        );
+        let (shared_id, updates) = multiplexer.insert(
+            PortalConversationId::new_conversation(),
+            filter.clone(),
+            None,
+        );
+
+        // Only the backfill of the new conversation
+        assert_eq!(id, shared_id);
+        assert_eq!(updates.len(), 1);
+        assert_ne!(subscription_id(&updates[0]), &id);
+        assert_eq!(subscribed_filter(&updates[0]), &filter);
+    }
+
+    #[test]
+    fn test_filters_are_recomputed_on_removal() {
+        let mut multiplexer = SubscriptionMultiplexer::new();
+        let first = PortalConversationId::new_conversation();
+        let second = PortalConversationId::new_conversation();
+        let first_filter = response_filter(27001);
+
+        let (id, _) = multiplexer.insert(first.clone(), first_filter.clone(), None);
+        let (_, updates) = multiplexer.insert(second.clone(), response_filter(27001), None);
+        let second_backfill = subscription_id(updates.last().unwrap()).clone();
+
+        let updates = multiplexer.remove(&second);
+        assert_eq!(updates.len(), 2);
+        assert_eq!(
+            updates[0],
+            SubscriptionUpdate::Unsubscribe {
+                id: second_backfill
+            }
+        );
+        assert_eq!(subscription_id(&updates[1]), &id);
+        assert_eq!(subscribed_filter(&updates[1]).authors, first_filter.authors);
+        assert!(multiplexer.remove(&second).is_empty());
+
+        let updates = multiplexer.remove(&first);
+        assert_eq!(
+            updates.last(),
+            Some(&SubscriptionUpdate::Unsubscribe { id: id.clone() })
+        );
+        assert!(!multiplexer.contains(&id));
+        assert!(multiplexer.is_empty());
+        assert!(multiplexer.global_subscriptions().is_empty());
+    }
+
+    #[test]
+    fn test_subscriptions_are_bounded() {
+        let mut multiplexer = SubscriptionMultiplexer::with_max_members(2);
+        for _ in 0..5 {
+            multiplexer.insert(
+                PortalConversationId::new_conversation(),
+                response_filter(27001),
+                None,
+            );
+        }
+        assert_eq!(multiplexer.len(), 3);
+    }
+
+    #[test]
+    fn test_replacing_a_filter_moves_the_conversation() {
+        let mut multiplexer = SubscriptionMultiplexer::new();
+        let conversation = PortalConversationId::new_conversation();
+
+        let (id, updates) = multiplexer.insert(conversation.clone(), response_filter(27001), None);
+        let backfill = subscription_id(&updates[1]).clone();
+        let (new_id, updates) =
+            multiplexer.insert(conversation.clone(), response_filter(27002), None);
+
+        assert_ne!(id, new_id);
+        assert_eq!(updates[0], SubscriptionUpdate::Unsubscribe { id: backfill });
+        assert_eq!(updates[1], SubscriptionUpdate::Unsubscribe { id });
+        assert_eq!(multiplexer.len(), 1);
+    }
+}
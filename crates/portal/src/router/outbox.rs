@@ -0,0 +1,296 @@
+//! Outbound event queue
+//!
+//! Events that were not accepted by every target relay are parked in an [`EventOutbox`] and
+//! retried by the router whenever it hears from one of the missing relays. Each entry tracks
+//! which relays already accepted the event, so retries only go to the others, and backs off
+//! exponentially between attempts so a relay that stays down isn't hammered.
+//!
+//! With a persistent outbox, events that were still queued when the process stopped are picked
+//! up again at the next start.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use nostr::event::{Event, EventId};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::model::Timestamp;
+
+/// Max entries of an [`InMemoryOutbox`] created with [`InMemoryOutbox::new`]
+pub const DEFAULT_OUTBOX_CAPACITY: usize = 512;
+
+/// Delay before the first retry, doubled after every failed attempt
+const RETRY_BASE_DELAY_SECS: u64 = 5;
+/// Upper bound of the delay between two attempts
+const RETRY_MAX_DELAY_SECS: u64 = 600;
+
+/// An event waiting to be delivered to some relays
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub event: Event,
+    /// Relays that still have to accept the event
+    pub pending_relays: HashSet<String>,
+    /// Relays that already accepted the event
+    pub delivered_relays: HashSet<String>,
+    /// Number of send attempts, including the first one
+    pub attempts: u32,
+    /// Unix timestamp of the first attempt
+    pub created_at: u64,
+    /// Unix timestamp of the last attempt
+    pub last_attempt_at: u64,
+    /// Unix timestamp before which the event is not retried
+    pub next_attempt_at: u64,
+    /// Why the last attempt failed
+    pub last_error: Option<String>,
+}
+
+impl OutboxEntry {
+    /// Creates the entry of an event after its first send attempt
+    pub fn new(event: Event, failed: HashSet<String>, succeeded: Vec<String>) -> Self {
+        let now = Timestamp::now().as_u64();
+        Self {
+            event,
+            last_error: Some(Self::failure_message(&failed)),
+            pending_relays: failed,
+            delivered_relays: succeeded.into_iter().collect(),
+            attempts: 1,
+            created_at: now,
+            last_attempt_at: now,
+            next_attempt_at: now + Self::backoff(1),
+        }
+    }
+
+    pub fn event_id(&self) -> EventId {
+        self.event.id
+    }
+
+    /// Whether every target relay accepted the event
+    pub fn is_delivered(&self) -> bool {
+        self.pending_relays.is_empty()
+    }
+
+    /// Whether the backoff since the last attempt has elapsed
+    pub fn is_due(&self, now: u64) -> bool {
+        self.next_attempt_at <= now
+    }
+
+    /// Records the result of a retry to [`Self::pending_relays`]
+    pub fn record_attempt(&mut self, result: Result<(HashSet<String>, Vec<String>), String>) {
+        let now = Timestamp::now().as_u64();
+        self.attempts += 1;
+        self.last_attempt_at = now;
+
+        match result {
+            Ok((failed, succeeded)) => {
+                self.delivered_relays.extend(succeeded);
+                self.last_error = (!failed.is_empty()).then(|| Self::failure_message(&failed));
+                self.pending_relays = failed;
+            }
+            Err(e) => self.last_error = Some(e),
+        }
+
+        self.next_attempt_at = now + Self::backoff(self.attempts);
+    }
+
+    /// Makes the event due for retry immediately
+    pub fn reset_backoff(&mut self) {
+        self.next_attempt_at = Timestamp::now().as_u64();
+    }
+
+    fn backoff(attempts: u32) -> u64 {
+        RETRY_BASE_DELAY_SECS
+            .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+            .min(RETRY_MAX_DELAY_SECS)
+    }
+
+    fn failure_message(failed: &HashSet<String>) -> String {
+        let mut relays = failed.iter().map(String::as_str).collect::<Vec<_>>();
+        relays.sort();
+        format!("Not accepted by {}", relays.join(", "))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum OutboxError {
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Storage error: {0}")]
+    Storage(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Storage backend for the outbound event queue
+///
+/// Entries are keyed by event ID: saving an entry for an event that is already queued
+/// replaces it.
+pub trait EventOutbox: Send + Sync {
+    fn save(&self, entry: OutboxEntry) -> Result<(), OutboxError>;
+    fn get(&self, event_id: &EventId) -> Result<Option<OutboxEntry>, OutboxError>;
+    fn remove(&self, event_id: &EventId) -> Result<(), OutboxError>;
+    /// All the queued entries, oldest first
+    fn load_all(&self) -> Result<Vec<OutboxEntry>, OutboxError>;
+    fn count(&self) -> Result<usize, OutboxError>;
+
+    /// Max number of queued events, `None` if unbounded
+    ///
+    /// When the outbox is full new events that fail to send are dropped.
+    fn capacity(&self) -> Option<usize>;
+}
+
+/// Outbox that keeps events in memory, so they are lost on restart
+///
+/// This is the default outbox of the router.
+#[derive(Debug)]
+pub struct InMemoryOutbox {
+    entries: Mutex<HashMap<EventId, OutboxEntry>>,
+    capacity: Option<usize>,
+}
+
+impl InMemoryOutbox {
+    /// Creates an outbox that holds up to [`DEFAULT_OUTBOX_CAPACITY`] events
+    pub fn new() -> Self {
+        Self::with_capacity(Some(DEFAULT_OUTBOX_CAPACITY))
+    }
+
+    pub fn with_capacity(capacity: Option<usize>) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+}
+
+impl Default for InMemoryOutbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventOutbox for InMemoryOutbox {
+    fn save(&self, entry: OutboxEntry) -> Result<(), OutboxError> {
+        self.entries.lock().unwrap().insert(entry.event_id(), entry);
+        Ok(())
+    }
+
+    fn get(&self, event_id: &EventId) -> Result<Option<OutboxEntry>, OutboxError> {
+        Ok(self.entries.lock().unwrap().get(event_id).cloned())
+    }
+
+    fn remove(&self, event_id: &EventId) -> Result<(), OutboxError> {
+        self.entries.lock().unwrap().remove(event_id);
+        Ok(())
+    }
+
+    fn load_all(&self) -> Result<Vec<OutboxEntry>, OutboxError> {
+        let mut entries: Vec<_> = self.entries.lock().unwrap().values().cloned().collect();
+        entries.sort_by_key(|entry| entry.created_at);
+        Ok(entries)
+    }
+
+    fn count(&self) -> Result<usize, OutboxError> {
+        Ok(self.entries.lock().unwrap().len())
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nostr::{event::EventBuilder, key::Keys};
+
+    fn relays(urls: &[&str]) -> HashSet<String> {
+        urls.iter().map(|url| url.to_string()).collect()
+    }
+
+    #[test]
+    fn test_record_attempt() {
+        let event = EventBuilder::text_note("hello")
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+        let mut entry = OutboxEntry::new(
+            event,
+            relays(&["wss://a", "wss://b"]),
+            vec!["wss://c".to_string()],
+        );
+        assert_eq!(entry.attempts, 1);
+        assert!(!entry.is_due(entry.created_at));
+        assert!(entry.is_due(entry.created_at + RETRY_BASE_DELAY_SECS));
+
+        entry.record_attempt(Err("Relay pool error".to_string()));
+        assert_eq!(entry.pending_relays, relays(&["wss://a", "wss://b"]));
+        assert_eq!(entry.last_error.as_deref(), Some("Relay pool error"));
+        assert_eq!(
+            entry.next_attempt_at - entry.last_attempt_at,
+            2 * RETRY_BASE_DELAY_SECS
+        );
+
+        entry.record_attempt(Ok((relays(&["wss://b"]), vec!["wss://a".to_string()])));
+        assert_eq!(entry.pending_relays, relays(&["wss://b"]));
+        assert_eq!(entry.delivered_relays, relays(&["wss://a", "wss://c"]));
+        assert_eq!(entry.last_error.as_deref(), Some("Not accepted by wss://b"));
+        assert!(!entry.is_delivered());
+
+        entry.reset_backoff();
+        assert!(entry.is_due(Timestamp::now().as_u64()));
+
+        entry.record_attempt(Ok((HashSet::new(), vec!["wss://b".to_string()])));
+        assert!(entry.is_delivered());
+        assert_eq!(entry.last_error, None);
+        assert_eq!(entry.attempts, 4);
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        assert_eq!(OutboxEntry::backoff(1), RETRY_BASE_DELAY_SECS);
+        assert_eq!(OutboxEntry::backoff(3), 4 * RETRY_BASE_DELAY_SECS);
+        assert_eq!(OutboxEntry::backoff(100), RETRY_MAX_DELAY_SECS);
+    }
+
+    #[test]
+    fn test_in_memory_outbox() {
+        let outbox = InMemoryOutbox::new();
+        assert_eq!(outbox.capacity(), Some(DEFAULT_OUTBOX_CAPACITY));
+
+        let keys = Keys::generate();
+        let first = OutboxEntry::new(
+            EventBuilder::text_note("first")
+                .sign_with_keys(&keys)
+                .unwrap(),
+            relays(&["wss://a"]),
+            vec![],
+        );
+        let second = OutboxEntry {
+            created_at: first.created_at + 1,
+            ..OutboxEntry::new(
+                EventBuilder::text_note("second")
+                    .sign_with_keys(&keys)
+                    .unwrap(),
+                relays(&["wss://a"]),
+                vec![],
+            )
+        };
+
+        outbox.save(second.clone()).unwrap();
+        outbox.save(first.clone()).unwrap();
+        outbox.save(first.clone()).unwrap();
+        assert_eq!(outbox.count().unwrap(), 2);
+
+        let ids: Vec<_> = outbox
+            .load_all()
+            .unwrap()
+            .iter()
+            .map(OutboxEntry::event_id)
+            .collect();
+        assert_eq!(ids, vec![first.event_id(), second.event_id()]);
+
+        outbox.remove(&first.event_id()).unwrap();
+        assert!(outbox.get(&first.event_id()).unwrap().is_none());
+        assert!(outbox.get(&second.event_id()).unwrap().is_some());
+    }
+}
@@ -0,0 +1,419 @@
+//! Persistent conversation state
+//!
+//! Conversations added with [`crate::router::MessageRouter::add_persistent_and_subscribe`] are
+//! saved in a [`ConversationStore`] every time they process a message, and removed once they
+//! finish or expire. Saves and removals run on a thread of their own, so the store may block.
+//! After a restart [`crate::router::MessageRouter::resume_conversations`] loads them back,
+//! re-subscribes their filters and hands their notification streams to the caller.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, mpsc},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use nostr::filter::Filter;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio_stream::StreamExt;
+
+use crate::router::{
+    Conversation, MultiKeyListener, MultiKeyListenerAdapter, MultiKeySender, MultiKeySenderAdapter,
+    NotificationStream,
+};
+
+/// A conversation whose state can be saved and restored later
+pub trait PersistentConversation: Conversation {
+    /// Name of the conversation type, used to pick the right type when restoring it
+    fn conversation_type(&self) -> &'static str;
+
+    /// Serialized state of the conversation
+    fn snapshot(&self) -> Result<serde_json::Value, serde_json::Error>;
+}
+
+/// State of a [`MultiKeySender`] or [`MultiKeyListener`] that can be persisted
+///
+/// Implementing this trait makes the corresponding adapter a [`PersistentConversation`].
+pub trait PersistentState: Serialize + DeserializeOwned {
+    /// Unique name of the conversation type
+    const CONVERSATION_TYPE: &'static str;
+}
+
+impl<T> PersistentConversation for MultiKeySenderAdapter<T>
+where
+    T: MultiKeySender + PersistentState,
+{
+    fn conversation_type(&self) -> &'static str {
+        T::CONVERSATION_TYPE
+    }
+
+    fn snapshot(&self) -> Result<serde_json::Value, serde_json::Error> {
+        serde_json::to_value(self)
+    }
+}
+
+impl<T> PersistentConversation for MultiKeyListenerAdapter<T>
+where
+    T: MultiKeyListener + PersistentState,
+    T::Message: core::fmt::Debug,
+{
+    fn conversation_type(&self) -> &'static str {
+        T::CONVERSATION_TYPE
+    }
+
+    fn snapshot(&self) -> Result<serde_json::Value, serde_json::Error> {
+        serde_json::to_value(self)
+    }
+}
+
+pub type PersistentConversationBox = Box<dyn PersistentConversation + Send + Sync>;
+
+impl std::fmt::Debug for PersistentConversationBox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PersistentConversation")
+            .field("type", &self.conversation_type())
+            .finish()
+    }
+}
+
+/// A saved conversation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConversationRecord {
+    /// Identifier chosen by the owner of the conversation, unique within the store
+    pub tag: String,
+    /// See [`PersistentConversation::conversation_type`]
+    pub conversation_type: String,
+    /// See [`PersistentConversation::snapshot`]
+    pub state: serde_json::Value,
+    /// Filter the conversation is subscribed with
+    pub filter: Option<Filter>,
+    /// Filters of the subkey proof subscriptions opened by the conversation
+    #[serde(default)]
+    pub alias_filters: Vec<Filter>,
+    /// Relays the conversation is bound to, `None` if it uses all of them
+    pub relays: Option<Vec<String>>,
+    /// Unix timestamp of the last update
+    pub updated_at: u64,
+}
+
+impl ConversationRecord {
+    pub(crate) fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConversationStoreError {
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[error("Storage error: {0}")]
+    Storage(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Storage backend for persistent conversations
+///
+/// Records are keyed by [`ConversationRecord::tag`]: saving a record with an existing tag
+/// replaces it.
+pub trait ConversationStore: Send + Sync {
+    fn save(&self, record: ConversationRecord) -> Result<(), ConversationStoreError>;
+    fn remove(&self, tag: &str) -> Result<(), ConversationStoreError>;
+    fn load_all(&self) -> Result<Vec<ConversationRecord>, ConversationStoreError>;
+}
+
+/// Store that keeps conversations in memory, so they are lost on restart
+///
+/// This is the default store of the router.
+#[derive(Debug, Default)]
+pub struct InMemoryConversationStore {
+    records: Mutex<HashMap<String, ConversationRecord>>,
+}
+
+impl InMemoryConversationStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ConversationStore for InMemoryConversationStore {
+    fn save(&self, record: ConversationRecord) -> Result<(), ConversationStoreError> {
+        self.records
+            .lock()
+            .unwrap()
+            .insert(record.tag.clone(), record);
+        Ok(())
+    }
+
+    fn remove(&self, tag: &str) -> Result<(), ConversationStoreError> {
+        self.records.lock().unwrap().remove(tag);
+        Ok(())
+    }
+
+    fn load_all(&self) -> Result<Vec<ConversationRecord>, ConversationStoreError> {
+        Ok(self.records.lock().unwrap().values().cloned().collect())
+    }
+}
+
+enum StoreWrite {
+    Save(ConversationRecord),
+    Remove(String),
+}
+
+/// Applies saves and removals to a [`ConversationStore`] on a thread of its own, in the order
+/// they were made, so that a slow store (e.g. a database on disk) doesn't block the router
+///
+/// Errors are only logged: the conversation goes on, it just may not survive a restart.
+#[derive(Clone)]
+pub(crate) struct ConversationStoreWriter {
+    sender: mpsc::Sender<StoreWrite>,
+}
+
+impl ConversationStoreWriter {
+    /// Starts the writer thread, which stops once every writer is dropped
+    pub(crate) fn new(store: Arc<dyn ConversationStore>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("conversation-store".to_string())
+            .spawn(move || {
+                for write in receiver {
+                    match write {
+                        StoreWrite::Save(record) => {
+                            let tag = record.tag.clone();
+                            if let Err(e) = store.save(record) {
+                                log::error!("Failed to save conversation {}: {:?}", tag, e);
+                            }
+                        }
+                        StoreWrite::Remove(tag) => {
+                            if let Err(e) = store.remove(&tag) {
+                                log::error!(
+                                    "Failed to remove conversation {} from store: {:?}",
+                                    tag,
+                                    e
+                                );
+                            }
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn the conversation store thread");
+        Self { sender }
+    }
+
+    pub(crate) fn save(&self, record: ConversationRecord) {
+        let _ = self.sender.send(StoreWrite::Save(record));
+    }
+
+    pub(crate) fn remove(&self, tag: String) {
+        let _ = self.sender.send(StoreWrite::Remove(tag));
+    }
+}
+
+type RestoreFn = fn(serde_json::Value) -> Result<PersistentConversationBox, serde_json::Error>;
+
+fn restore<C>(state: serde_json::Value) -> Result<PersistentConversationBox, serde_json::Error>
+where
+    C: PersistentConversation + DeserializeOwned + Send + Sync + 'static,
+{
+    Ok(Box::new(serde_json::from_value::<C>(state)?))
+}
+
+/// The conversation types that can be restored from a [`ConversationStore`]
+#[derive(Debug, Default, Clone)]
+pub struct ConversationRegistry {
+    types: HashMap<&'static str, RestoreFn>,
+}
+
+impl ConversationRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a [`MultiKeySender`] conversation type
+    pub fn register_sender<T>(mut self) -> Self
+    where
+        T: MultiKeySender + PersistentState + Sync,
+    {
+        self.types
+            .insert(T::CONVERSATION_TYPE, restore::<MultiKeySenderAdapter<T>>);
+        self
+    }
+
+    /// Registers a [`MultiKeyListener`] conversation type
+    pub fn register_listener<T>(mut self) -> Self
+    where
+        T: MultiKeyListener + PersistentState + Sync,
+        T::Message: core::fmt::Debug,
+    {
+        self.types
+            .insert(T::CONVERSATION_TYPE, restore::<MultiKeyListenerAdapter<T>>);
+        self
+    }
+
+    /// Rebuilds the conversation saved in `record`
+    ///
+    /// Returns `None` if the conversation type is not registered.
+    pub fn restore(
+        &self,
+        record: &ConversationRecord,
+    ) -> Option<Result<PersistentConversationBox, serde_json::Error>> {
+        let restore = self.types.get(record.conversation_type.as_str())?;
+        Some(restore(record.state.clone()))
+    }
+}
+
+/// A conversation restored by [`crate::router::MessageRouter::resume_conversations`]
+#[derive(Debug)]
+pub struct ResumedConversation {
+    pub tag: String,
+    pub conversation_type: String,
+    pub stream: NotificationStream<serde_json::Value>,
+}
+
+impl ResumedConversation {
+    /// Converts the notification stream to the notification type of the conversation
+    pub fn into_stream<T: DeserializeOwned + Serialize>(self) -> NotificationStream<T> {
+        let NotificationStream { stream } = self.stream;
+        NotificationStream::new(
+            stream.map(|result| result.and_then(|value| serde_json::from_value(value))),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nostr::key::Keys;
+
+    use crate::{
+        conversation::sdk::auth::AuthChallengeSenderConversation, router::ConversationMessage,
+    };
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let local = Keys::generate();
+        let user = Keys::generate().public_key();
+        let subkey = Keys::generate().public_key();
+
+        let conversation = MultiKeySenderAdapter::new_with_user(
+            user,
+            vec![subkey],
+            AuthChallengeSenderConversation::new(local.public_key(), None, vec![]),
+        );
+        let record = ConversationRecord {
+            tag: "stream".to_string(),
+            conversation_type: conversation.conversation_type().to_string(),
+            state: conversation.snapshot().unwrap(),
+            filter: None,
+            alias_filters: vec![],
+            relays: None,
+            updated_at: ConversationRecord::now(),
+        };
+
+        let registry =
+            ConversationRegistry::new().register_sender::<AuthChallengeSenderConversation>();
+        let mut restored = registry.restore(&record).unwrap().unwrap();
+        assert_eq!(restored.snapshot().unwrap(), record.state);
+        assert!(!restored.is_expired());
+        assert!(
+            restored
+                .on_message(ConversationMessage::EndOfStoredEvents)
+                .is_ok()
+        );
+
+        let unknown = ConversationRecord {
+            conversation_type: "unknown".to_string(),
+            ..record
+        };
+        assert!(registry.restore(&unknown).is_none());
+    }
+
+    #[test]
+    fn test_in_memory_store() {
+        let store = InMemoryConversationStore::new();
+        let record = ConversationRecord {
+            tag: "stream".to_string(),
+            conversation_type: "test".to_string(),
+            state: serde_json::json!({ "step": 1 }),
+            filter: None,
+            alias_filters: vec![],
+            relays: Some(vec!["wss://relay.example.com".to_string()]),
+            updated_at: ConversationRecord::now(),
+        };
+
+        store.save(record.clone()).unwrap();
+        store
+            .save(ConversationRecord {
+                state: serde_json::json!({ "step": 2 }),
+                ..record
+            })
+            .unwrap();
+
+        let records = store.load_all().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].state["step"], 2);
+
+        store.remove("stream").unwrap();
+        assert!(store.load_all().unwrap().is_empty());
+    }
+
+    /// Store whose saves wait for `gate`, reporting every write on `writes`
+    struct SlowStore {
+        gate: Mutex<mpsc::Receiver<()>>,
+        writes: Mutex<mpsc::Sender<String>>,
+    }
+
+    impl ConversationStore for SlowStore {
+        fn save(&self, record: ConversationRecord) -> Result<(), ConversationStoreError> {
+            self.gate.lock().unwrap().recv().unwrap();
+            let write = format!("save {} {}", record.tag, record.state["step"]);
+            self.writes.lock().unwrap().send(write).unwrap();
+            Ok(())
+        }
+
+        fn remove(&self, tag: &str) -> Result<(), ConversationStoreError> {
+            let write = format!("remove {}", tag);
+            self.writes.lock().unwrap().send(write).unwrap();
+            Ok(())
+        }
+
+        fn load_all(&self) -> Result<Vec<ConversationRecord>, ConversationStoreError> {
+            Ok(vec![])
+        }
+    }
+
+    #[test]
+    fn test_store_writer_does_not_wait_for_the_store() {
+        let (open, gate) = mpsc::channel();
+        let (writes_tx, writes) = mpsc::channel();
+        let writer = ConversationStoreWriter::new(Arc::new(SlowStore {
+            gate: Mutex::new(gate),
+            writes: Mutex::new(writes_tx),
+        }));
+
+        let record = |step: u32| ConversationRecord {
+            tag: "stream".to_string(),
+            conversation_type: "test".to_string(),
+            state: serde_json::json!({ "step": step }),
+            filter: None,
+            alias_filters: vec![],
+            relays: None,
+            updated_at: ConversationRecord::now(),
+        };
+        // Returns while the store is still blocked
+        writer.save(record(1));
+        writer.save(record(2));
+        writer.remove("stream".to_string());
+        assert!(writes.try_recv().is_err());
+
+        open.send(()).unwrap();
+        open.send(()).unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        let applied: Vec<_> = (0..3)
+            .map(|_| writes.recv_timeout(timeout).unwrap())
+            .collect();
+        assert_eq!(applied, ["save stream 1", "save stream 2", "remove stream"]);
+    }
+}
@@ -98,7 +98,7 @@ async fn test_auth_flow() {
         .add_and_subscribe(Box::new(MultiKeySenderAdapter::new_with_user(
             key_handshake_event.main_key,
             vec![],
-            AuthChallengeSenderConversation::new(service_keys.public_key(), None),
+            AuthChallengeSenderConversation::new(service_keys.public_key(), None, vec![]),
         )))
         .await
         .unwrap();
@@ -121,7 +121,7 @@ async fn test_auth_flow() {
         None,
         AuthResponseStatus::Approved {
             granted_permissions: vec![],
-            session_token: "ABC".to_string(),
+            session_token: String::new(),
         },
     );
     client_router
@@ -157,6 +157,8 @@ async fn test_auth_with_subkey_client() {
             expires_at: Timestamp::now_plus_seconds(3600),
             permissions: vec![],
             version: 1,
+            max_payment_msat: None,
+            allowed_counterparties: vec![],
         })
         .unwrap();
 
@@ -200,7 +202,7 @@ async fn test_auth_with_subkey_client() {
         .add_and_subscribe(Box::new(MultiKeySenderAdapter::new_with_user(
             client_keys_master.public_key(),
             vec![],
-            AuthChallengeSenderConversation::new(service_keys.public_key(), None),
+            AuthChallengeSenderConversation::new(service_keys.public_key(), None, vec![]),
         )))
         .await
         .unwrap();
@@ -223,7 +225,7 @@ async fn test_auth_with_subkey_client() {
         Some(client_subkey_proof),
         AuthResponseStatus::Approved {
             granted_permissions: vec![],
-            session_token: "ABC".to_string(),
+            session_token: String::new(),
         },
     );
     client_router
@@ -262,6 +264,8 @@ async fn test_auth_with_subkey_service() {
             expires_at: Timestamp::now_plus_seconds(3600),
             permissions: vec![],
             version: 1,
+            max_payment_msat: None,
+            allowed_counterparties: vec![],
         })
         .unwrap();
 
@@ -308,6 +312,7 @@ async fn test_auth_with_subkey_service() {
             AuthChallengeSenderConversation::new(
                 service_keys.public_key(),
                 Some(service_subkey_proof.clone()),
+                vec![],
             ),
         )))
         .await
@@ -331,7 +336,7 @@ async fn test_auth_with_subkey_service() {
         None,
         AuthResponseStatus::Approved {
             granted_permissions: vec![],
-            session_token: "ABC".to_string(),
+            session_token: String::new(),
         },
     );
     client_router